@@ -0,0 +1,206 @@
+//! List the records of WAL segments, or their statistics, without a server.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use pg_waldecoder_core::{
+    lsn::{filename_to_startptr, format_lsn, parse_lsn},
+    page::SIZE_OF_XLOG_LONG_PHD,
+    reader::{Record, StreamReader},
+    record::{rmgr_name, SIZE_OF_XLOG_RECORD},
+    segment::validate_segment,
+};
+
+const USAGE: &str = "Usage: pg-waldecode [--stats] [--start LSN] [--end LSN] SEGMENT...";
+
+struct Args {
+    stats: bool,
+    start: Option<u64>,
+    end: Option<u64>,
+    segments: Vec<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        stats: false,
+        start: None,
+        end: None,
+        segments: Vec::new(),
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--stats" => args.stats = true,
+            "--start" | "--end" => {
+                let lsn = iter.next().ok_or(format!("{arg} needs an LSN"))?;
+                let lsn = parse_lsn(&lsn).map_err(|e| e.to_string())?;
+                if arg == "--start" {
+                    args.start = Some(lsn);
+                } else {
+                    args.end = Some(lsn);
+                }
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => args.segments.push(PathBuf::from(arg)),
+        }
+    }
+    if args.segments.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    args.segments
+        .sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(args)
+}
+
+/// Validate the segments from their first page, grouping consecutive ones
+/// so records spanning two segments can be read
+fn group_segments(paths: &[PathBuf]) -> Result<Vec<Vec<PathBuf>>, String> {
+    let mut runs: Vec<Vec<PathBuf>> = Vec::new();
+    let mut last: Option<(u64, u64)> = None;
+    for path in paths {
+        let file_name = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .ok_or(format!("Invalid segment path {}", path.display()))?;
+        let mut first_page = Vec::with_capacity(SIZE_OF_XLOG_LONG_PHD);
+        File::open(path)
+            .and_then(|file| {
+                file.take(u64::try_from(SIZE_OF_XLOG_LONG_PHD).unwrap())
+                    .read_to_end(&mut first_page)
+            })
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        let header =
+            validate_segment(file_name, &first_page).map_err(|e| format!("{file_name}: {e}"))?;
+        let (tli, segno) = filename_to_startptr(file_name, u64::from(header.seg_size))
+            .map_err(|e| e.to_string())?;
+        let follows = segno
+            .checked_sub(1)
+            .is_some_and(|prev| last == Some((tli, prev)));
+        match runs.last_mut() {
+            Some(run) if follows => run.push(path.clone()),
+            _ => runs.push(vec![path.clone()]),
+        }
+        last = Some((tli, segno));
+    }
+    Ok(runs)
+}
+
+/// The segments of a run, opened one after the other as they're read
+struct RunReader {
+    paths: std::vec::IntoIter<PathBuf>,
+    current: Option<File>,
+}
+
+impl Read for RunReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.current {
+                let read = file.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            match self.paths.next() {
+                Some(path) => self.current = Some(File::open(path)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Block references of a record, as printed by `pg_waldump`
+fn block_refs(record: &Record) -> Result<String, String> {
+    let decoded = record
+        .decode()
+        .map_err(|e| format!("Invalid record at {}: {e}", format_lsn(record.lsn)))?;
+    let blocks = decoded.blocks.iter().map(|block| {
+        let rel = block.rlocator;
+        let fpw = if block.image.is_some() { " FPW" } else { "" };
+        format!(
+            ", blkref #{}: rel {}/{}/{} fork {} blk {}{fpw}",
+            block.id, rel.spc_oid, rel.db_oid, rel.rel_number, block.forknum, block.blkno
+        )
+    });
+    Ok(blocks.collect())
+}
+
+fn print_record(record: &Record) -> Result<(), String> {
+    let header = &record.header;
+    println!(
+        "rmgr: {:<11} len (rec/tot): {:>6}/{:>6}, tx: {:>10}, lsn: {}, prev {}, info: 0x{:02X}{}",
+        rmgr_name(header.rmid),
+        header.tot_len - u32::try_from(SIZE_OF_XLOG_RECORD).unwrap(),
+        header.tot_len,
+        header.xid,
+        format_lsn(record.lsn),
+        format_lsn(header.prev),
+        header.info,
+        block_refs(record)?
+    );
+    Ok(())
+}
+
+/// Count and size of the records of a resource manager
+#[derive(Default)]
+struct RmgrStats {
+    count: u64,
+    bytes: u64,
+}
+
+fn print_stats(stats: &BTreeMap<String, RmgrStats>) {
+    let total_count: u64 = stats.values().map(|s| s.count).sum();
+    let total_bytes: u64 = stats.values().map(|s| s.bytes).sum();
+    println!("{:<20} {:>10} {:>14}", "Type", "N", "Combined size");
+    for (rmgr, s) in stats {
+        println!("{rmgr:<20} {:>10} {:>14}", s.count, s.bytes);
+    }
+    println!("{:<20} {total_count:>10} {total_bytes:>14}", "Total");
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let mut stats = BTreeMap::<String, RmgrStats>::new();
+    for run in group_segments(&args.segments)? {
+        let run = RunReader {
+            paths: run.into_iter(),
+            current: None,
+        };
+        let reader = StreamReader::new(run).map_err(|e| e.to_string())?;
+        for record in reader {
+            let record = record.map_err(|e| e.to_string())?;
+            if args.start.is_some_and(|start| record.lsn < start) {
+                continue;
+            }
+            if args.end.is_some_and(|end| record.lsn >= end) {
+                break;
+            }
+            if args.stats {
+                let s = stats.entry(rmgr_name(record.header.rmid)).or_default();
+                s.count += 1;
+                s.bytes += u64::from(record.header.tot_len);
+            } else {
+                print_record(&record)?;
+            }
+        }
+    }
+    if args.stats {
+        print_stats(&stats);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = parse_args().and_then(|args| run(&args));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("pg-waldecode: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,61 @@
+/// Reflected Castagnoli polynomial used by `pg_crc32c`
+const CRC32C_POLY: u32 = 0x82F63B78;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// Initial value of a CRC, `INIT_CRC32C`
+pub const CRC32C_INIT: u32 = 0xFFFFFFFF;
+
+/// Add bytes to a CRC, `COMP_CRC32C`
+#[must_use]
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, b| {
+        CRC32C_TABLE[usize::from(crc.to_le_bytes()[0] ^ b)] ^ (crc >> 8)
+    })
+}
+
+/// Final value of a CRC, `FIN_CRC32C`
+#[must_use]
+pub fn crc32c_finish(crc: u32) -> u32 {
+    crc ^ 0xFFFFFFFF
+}
+
+/// CRC-32C of the bytes
+#[must_use]
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_finish(crc32c_update(CRC32C_INIT, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crc::{crc32c, crc32c_finish, crc32c_update, CRC32C_INIT};
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE3069283);
+        let split = crc32c_update(crc32c_update(CRC32C_INIT, b"1234"), b"56789");
+        assert_eq!(crc32c_finish(split), 0xE3069283);
+    }
+}
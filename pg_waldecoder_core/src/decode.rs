@@ -0,0 +1,423 @@
+use thiserror::Error;
+
+use crate::{
+    page::ByteOrder,
+    record::{RecordHeader, SIZE_OF_XLOG_RECORD},
+    version::WalVersion,
+};
+
+/// Size of a relation page, `BLCKSZ`
+pub const BLCKSZ: u32 = 8192;
+/// Highest block reference id, `XLR_MAX_BLOCK_ID`
+pub const XLR_MAX_BLOCK_ID: u8 = 32;
+/// Main data of less than 256 bytes, `XLR_BLOCK_ID_DATA_SHORT`
+pub const XLR_BLOCK_ID_DATA_SHORT: u8 = 255;
+/// Main data of 256 bytes or more, `XLR_BLOCK_ID_DATA_LONG`
+pub const XLR_BLOCK_ID_DATA_LONG: u8 = 254;
+/// Replication origin of the record, `XLR_BLOCK_ID_ORIGIN`
+pub const XLR_BLOCK_ID_ORIGIN: u8 = 253;
+/// Top-level xid of a subtransaction's record, `XLR_BLOCK_ID_TOPLEVEL_XID`
+pub const XLR_BLOCK_ID_TOPLEVEL_XID: u8 = 252;
+
+pub const BKPBLOCK_FORK_MASK: u8 = 0x0F;
+pub const BKPBLOCK_HAS_IMAGE: u8 = 0x10;
+pub const BKPBLOCK_HAS_DATA: u8 = 0x20;
+/// The page is reinitialized by the redo, no image is needed
+pub const BKPBLOCK_WILL_INIT: u8 = 0x40;
+/// The relation is the one of the previous block reference
+pub const BKPBLOCK_SAME_REL: u8 = 0x80;
+
+pub const BKPIMAGE_HAS_HOLE: u8 = 0x01;
+/// The image is restored at redo, not only logged for consistency checks
+pub const BKPIMAGE_APPLY: u8 = 0x02;
+pub const BKPIMAGE_COMPRESS_PGLZ: u8 = 0x04;
+pub const BKPIMAGE_COMPRESS_LZ4: u8 = 0x08;
+pub const BKPIMAGE_COMPRESS_ZSTD: u8 = 0x10;
+const BKPIMAGE_COMPRESSED: u8 =
+    BKPIMAGE_COMPRESS_PGLZ | BKPIMAGE_COMPRESS_LZ4 | BKPIMAGE_COMPRESS_ZSTD;
+/// Image flags before 15, only pglz compression was available
+const BKPIMAGE_IS_COMPRESSED_V13: u8 = 0x02;
+const BKPIMAGE_APPLY_V13: u8 = 0x04;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidBlockRef {
+    #[error("Record is too short for its block references")]
+    Truncated,
+    #[error("Out-of-order block_id {0}")]
+    OutOfOrder(u8),
+    #[error("Invalid block_id {0}")]
+    InvalidBlockId(u8),
+    #[error("BKPBLOCK_SAME_REL set but no previous rel for block {0}")]
+    NoPreviousRel(u8),
+    #[error("BKPBLOCK_HAS_DATA and data length don't match for block {0}")]
+    InvalidDataLength(u8),
+    #[error("Invalid image length {1}, hole offset {2} and hole length {3} for block {0}")]
+    InvalidImage(u8, u16, u16, u16),
+    #[error("Record length {0} doesn't match its block references and main data, {1} bytes")]
+    InvalidLength(u32, usize),
+}
+
+/// Relation of a block reference, `RelFileLocator`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelFileLocator {
+    pub spc_oid: u32,
+    pub db_oid: u32,
+    pub rel_number: u32,
+}
+
+/// Full page image of a block reference, with its hole removed and possibly
+/// compressed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockImage {
+    pub hole_offset: u16,
+    pub hole_length: u16,
+    pub bimg_info: u8,
+    pub data: Vec<u8>,
+}
+
+impl BlockImage {
+    #[must_use]
+    pub fn is_compressed(&self) -> bool {
+        self.bimg_info & BKPIMAGE_COMPRESSED != 0
+    }
+
+    #[must_use]
+    pub fn apply(&self) -> bool {
+        self.bimg_info & BKPIMAGE_APPLY != 0
+    }
+}
+
+/// Block reference of a record, `DecodedBkpBlock`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockRef {
+    pub id: u8,
+    pub rlocator: RelFileLocator,
+    pub forknum: u8,
+    pub blkno: u32,
+    pub flags: u8,
+    pub image: Option<BlockImage>,
+    pub data: Vec<u8>,
+}
+
+impl BlockRef {
+    #[must_use]
+    pub fn will_init(&self) -> bool {
+        self.flags & BKPBLOCK_WILL_INIT != 0
+    }
+}
+
+/// A record split in its block references and main data, like
+/// `DecodeXLogRecord` does
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedRecord {
+    pub header: RecordHeader,
+    pub origin: Option<u16>,
+    pub toplevel_xid: Option<u32>,
+    pub blocks: Vec<BlockRef>,
+    pub main_data: Vec<u8>,
+}
+
+/// Reads the headers of a record, failing instead of reading past its end
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    order: ByteOrder,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], InvalidBlockRef> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(InvalidBlockRef::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, InvalidBlockRef> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, InvalidBlockRef> {
+        Ok(self.order.read_u16(self.take(2)?, 0))
+    }
+
+    fn u32(&mut self) -> Result<u32, InvalidBlockRef> {
+        Ok(self.order.read_u32(self.take(4)?, 0))
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+}
+
+/// Image flags in the layout used since 15
+fn normalize_bimg_info(bimg_info: u8, version: WalVersion) -> u8 {
+    if version >= WalVersion::V15 {
+        return bimg_info;
+    }
+    let mut normalized = bimg_info & BKPIMAGE_HAS_HOLE;
+    if bimg_info & BKPIMAGE_IS_COMPRESSED_V13 != 0 {
+        normalized |= BKPIMAGE_COMPRESS_PGLZ;
+    }
+    if bimg_info & BKPIMAGE_APPLY_V13 != 0 {
+        normalized |= BKPIMAGE_APPLY;
+    }
+    normalized
+}
+
+/// Check the hole and length of an image the way `DecodeXLogRecord` does
+fn validate_image(
+    block_id: u8,
+    bimg_len: u16,
+    hole_offset: u16,
+    hole_length: u16,
+    bimg_info: u8,
+) -> Result<(), InvalidBlockRef> {
+    let has_hole = bimg_info & BKPIMAGE_HAS_HOLE != 0;
+    let compressed = bimg_info & BKPIMAGE_COMPRESSED != 0;
+    let full_page = u32::from(bimg_len) == BLCKSZ;
+    let invalid_hole = if has_hole {
+        hole_offset == 0 || hole_length == 0 || full_page
+    } else {
+        hole_offset != 0 || hole_length != 0
+    };
+    // Only compressed images or images with a hole are shorter than a page
+    let invalid_length = full_page == (compressed || has_hole);
+    if invalid_hole || invalid_length {
+        return Err(InvalidBlockRef::InvalidImage(
+            block_id,
+            bimg_len,
+            hole_offset,
+            hole_length,
+        ));
+    }
+    Ok(())
+}
+
+/// Header of block reference `id`, with the length of its image and data.
+/// `previous` is the relation of the previous block reference.
+fn parse_block_header(
+    cursor: &mut Cursor,
+    id: u8,
+    version: WalVersion,
+    previous: Option<RelFileLocator>,
+) -> Result<(BlockRef, usize, usize), InvalidBlockRef> {
+    let flags = cursor.u8()?;
+    let data_len = cursor.u16()?;
+    if (flags & BKPBLOCK_HAS_DATA != 0) != (data_len != 0) {
+        return Err(InvalidBlockRef::InvalidDataLength(id));
+    }
+    let mut image = None;
+    let mut bimg_len = 0;
+    if flags & BKPBLOCK_HAS_IMAGE != 0 {
+        bimg_len = cursor.u16()?;
+        let hole_offset = cursor.u16()?;
+        let bimg_info = normalize_bimg_info(cursor.u8()?, version);
+        let hole_length = if bimg_info & BKPIMAGE_COMPRESSED == 0 {
+            // The hole is what's missing from the page
+            BLCKSZ
+                .checked_sub(u32::from(bimg_len))
+                .and_then(|len| u16::try_from(len).ok())
+                .ok_or(InvalidBlockRef::InvalidImage(id, bimg_len, hole_offset, 0))?
+        } else if bimg_info & BKPIMAGE_HAS_HOLE != 0 {
+            cursor.u16()?
+        } else {
+            0
+        };
+        validate_image(id, bimg_len, hole_offset, hole_length, bimg_info)?;
+        image = Some(BlockImage {
+            hole_offset,
+            hole_length,
+            bimg_info,
+            data: Vec::new(),
+        });
+    }
+    let rlocator = if flags & BKPBLOCK_SAME_REL == 0 {
+        RelFileLocator {
+            spc_oid: cursor.u32()?,
+            db_oid: cursor.u32()?,
+            rel_number: cursor.u32()?,
+        }
+    } else {
+        previous.ok_or(InvalidBlockRef::NoPreviousRel(id))?
+    };
+    let blkno = cursor.u32()?;
+    let block = BlockRef {
+        id,
+        rlocator,
+        forknum: flags & BKPBLOCK_FORK_MASK,
+        blkno,
+        flags,
+        image,
+        data: Vec::new(),
+    };
+    Ok((block, usize::from(bimg_len), usize::from(data_len)))
+}
+
+/// Split a complete record, header included, in its block references and
+/// main data. Image flags of older versions are converted to the current
+/// layout.
+pub fn decode_record(
+    record: &[u8],
+    version: WalVersion,
+    order: ByteOrder,
+) -> Result<DecodedRecord, InvalidBlockRef> {
+    let header = RecordHeader::parse(record, order).map_err(|_| InvalidBlockRef::Truncated)?;
+    let mut cursor = Cursor {
+        buf: record,
+        pos: SIZE_OF_XLOG_RECORD,
+        order,
+    };
+    let mut origin = None;
+    let mut toplevel_xid = None;
+    let mut main_data_len = 0;
+    // Block references with the length of their image and data
+    let mut blocks: Vec<(BlockRef, usize, usize)> = Vec::new();
+    let mut datatotal = 0;
+    while cursor.remaining() > datatotal {
+        let block_id = cursor.u8()?;
+        match block_id {
+            XLR_BLOCK_ID_DATA_SHORT => {
+                main_data_len = usize::from(cursor.u8()?);
+                datatotal += main_data_len;
+                break;
+            }
+            XLR_BLOCK_ID_DATA_LONG => {
+                main_data_len =
+                    usize::try_from(cursor.u32()?).map_err(|_| InvalidBlockRef::Truncated)?;
+                datatotal += main_data_len;
+                break;
+            }
+            XLR_BLOCK_ID_ORIGIN => origin = Some(cursor.u16()?),
+            XLR_BLOCK_ID_TOPLEVEL_XID => toplevel_xid = Some(cursor.u32()?),
+            id if id <= XLR_MAX_BLOCK_ID => {
+                if blocks.last().is_some_and(|(last, _, _)| last.id >= id) {
+                    return Err(InvalidBlockRef::OutOfOrder(id));
+                }
+                let previous = blocks.last().map(|(last, _, _)| last.rlocator);
+                let block = parse_block_header(&mut cursor, id, version, previous)?;
+                datatotal += block.1 + block.2;
+                blocks.push(block);
+            }
+            id => return Err(InvalidBlockRef::InvalidBlockId(id)),
+        }
+    }
+    if cursor.remaining() != datatotal {
+        return Err(InvalidBlockRef::InvalidLength(header.tot_len, datatotal));
+    }
+
+    // The images and data of the blocks follow the headers, in order
+    let mut decoded_blocks = Vec::with_capacity(blocks.len());
+    for (mut block, bimg_len, data_len) in blocks {
+        if let Some(image) = block.image.as_mut() {
+            image.data = cursor.take(bimg_len)?.to_vec();
+        }
+        block.data = cursor.take(data_len)?.to_vec();
+        decoded_blocks.push(block);
+    }
+    let main_data = cursor.take(main_data_len)?.to_vec();
+    Ok(DecodedRecord {
+        header,
+        origin,
+        toplevel_xid,
+        blocks: decoded_blocks,
+        main_data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        decode::{
+            decode_record, normalize_bimg_info, validate_image, InvalidBlockRef, RelFileLocator,
+            BKPIMAGE_APPLY, BKPIMAGE_COMPRESS_PGLZ, BKPIMAGE_HAS_HOLE,
+        },
+        page::{ByteOrder, SIZE_OF_XLOG_LONG_PHD},
+        version::WalVersion,
+    };
+
+    fn test_segment() -> Vec<u8> {
+        std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap()
+    }
+
+    fn record_at(segment: &[u8], offset: usize) -> &[u8] {
+        let tot_len = usize::try_from(ByteOrder::Little.read_u32(segment, offset)).unwrap();
+        &segment[offset..offset + tot_len]
+    }
+
+    #[test]
+    fn test_decode_record() {
+        let segment = test_segment();
+        let rel = RelFileLocator {
+            spc_oid: 1663,
+            db_oid: 5,
+            rel_number: 16399,
+        };
+
+        // Full page image of a hint bit change
+        let fpi = decode_record(
+            record_at(&segment, SIZE_OF_XLOG_LONG_PHD),
+            WalVersion::V18,
+            ByteOrder::Little,
+        )
+        .unwrap();
+        assert_eq!(fpi.blocks.len(), 1);
+        assert_eq!(fpi.blocks[0].rlocator, rel);
+        assert_eq!(fpi.blocks[0].blkno, 1639);
+        let image = fpi.blocks[0].image.as_ref().unwrap();
+        assert_eq!(image.bimg_info, BKPIMAGE_HAS_HOLE | BKPIMAGE_APPLY);
+        assert_eq!((image.hole_offset, image.hole_length), (116, 5132));
+        assert_eq!(image.data.len(), 3060);
+        assert!(fpi.main_data.is_empty());
+
+        // Heap update of the same page
+        let update = decode_record(
+            record_at(&segment, 0xc50),
+            WalVersion::V18,
+            ByteOrder::Little,
+        )
+        .unwrap();
+        assert_eq!(update.header.rmid, 10);
+        assert_eq!(update.blocks[0].rlocator, rel);
+        assert_eq!(update.blocks[0].image, None);
+        assert_eq!(update.blocks[0].data.len(), 103);
+        assert_eq!(update.main_data.len(), 14);
+        assert_eq!(update.origin, None);
+
+        let mut truncated = record_at(&segment, 0xc50).to_vec();
+        truncated.truncate(40);
+        assert_eq!(
+            decode_record(&truncated, WalVersion::V18, ByteOrder::Little),
+            Err(InvalidBlockRef::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_normalize_bimg_info() {
+        // HAS_HOLE | IS_COMPRESSED | APPLY before 15
+        assert_eq!(
+            normalize_bimg_info(0x07, WalVersion::V14),
+            BKPIMAGE_HAS_HOLE | BKPIMAGE_COMPRESS_PGLZ | BKPIMAGE_APPLY
+        );
+        assert_eq!(normalize_bimg_info(0x03, WalVersion::V15), 0x03);
+        assert_eq!(
+            normalize_bimg_info(0x05, WalVersion::V13),
+            BKPIMAGE_HAS_HOLE | BKPIMAGE_APPLY
+        );
+    }
+
+    #[test]
+    fn test_validate_image() {
+        assert!(validate_image(0, 8192, 0, 0, BKPIMAGE_APPLY).is_ok());
+        assert!(validate_image(0, 3060, 116, 5132, BKPIMAGE_HAS_HOLE).is_ok());
+        assert_eq!(
+            validate_image(1, 3060, 0, 0, 0),
+            Err(InvalidBlockRef::InvalidImage(1, 3060, 0, 0))
+        );
+    }
+}
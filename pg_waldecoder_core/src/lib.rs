@@ -0,0 +1,22 @@
+//! WAL parsing that doesn't need a running server: LSN and segment file name
+//! math, page headers, records with their CRC and block references. The
+//! records are read by `pg-waldecode`, the extension reads them with the
+//! server's `XLogReadRecord` as its decoders work on `DecodedXLogRecord`.
+
+pub mod crc;
+pub mod decode;
+pub mod lsn;
+pub mod page;
+pub mod reader;
+pub mod record;
+pub mod segment;
+pub mod version;
+
+/// Size of a WAL page, `XLOG_BLCKSZ`
+pub const XLOG_BLCKSZ: u32 = 8192;
+
+/// Round up to the 8 bytes alignment used for WAL records, `MAXALIGN`
+#[must_use]
+pub fn maxalign(len: u64) -> u64 {
+    (len + 7) & !7
+}
@@ -0,0 +1,143 @@
+use std::path;
+
+use thiserror::Error;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidLSN {
+    #[error("Invalid LSN Format '{0}'")]
+    Format(String),
+    #[error("Invalid filename: '{0}'")]
+    FileName(String),
+    #[error("Invalid hex value in '{0}': `{1}`")]
+    HexValue(String, String),
+}
+
+/// Parse a `ffffffff/ffffffff` LSN
+pub fn parse_lsn(lsn: &str) -> Result<u64, InvalidLSN> {
+    let Some((xlogid_str, xrecoff_str)) = lsn.split_once('/') else {
+        return Err(InvalidLSN::Format(lsn.to_string()));
+    };
+    let xlogid = match u64::from_str_radix(xlogid_str, 16) {
+        Ok(xlogid) => xlogid,
+        Err(e) => return Err(InvalidLSN::HexValue(lsn.to_string(), e.to_string())),
+    };
+    let xrecoff = match u64::from_str_radix(xrecoff_str, 16) {
+        Ok(xrecoff) => xrecoff,
+        Err(e) => return Err(InvalidLSN::HexValue(lsn.to_string(), e.to_string())),
+    };
+    Ok(xlogid << 32 | xrecoff)
+}
+
+/// Format an LSN as a `ffffffff/ffffffff` string
+#[must_use]
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{0:X}/{1:08X}", lsn >> 32, lsn & 0xffffffff)
+}
+
+/// Segment number containing the LSN
+#[must_use]
+pub fn lsn_to_segno(lsn: u64, wal_segsz_bytes: u32) -> u64 {
+    lsn / u64::from(wal_segsz_bytes)
+}
+
+/// LSN of the start of a segment
+#[must_use]
+pub fn segno_to_lsn(segno: u64, wal_segsz_bytes: u32) -> u64 {
+    segno * u64::from(wal_segsz_bytes)
+}
+
+/// Returns file name for a provided timeline and segment number
+#[must_use]
+pub fn xlog_file_name(tli: u32, log_seg_no: u64, wal_segsz_bytes: u32) -> String {
+    let segments_per_xlog_id = 0x100000000u64 / u64::from(wal_segsz_bytes);
+    let up = log_seg_no / segments_per_xlog_id;
+    let rest = log_seg_no % segments_per_xlog_id;
+    format!("{tli:08X}{up:08X}{rest:08X}")
+}
+
+/// Convert a filename to a timeline and a segment number
+pub fn filename_to_startptr(
+    filename: &str,
+    wal_segsz_bytes: u64,
+) -> Result<(u64, u64), InvalidLSN> {
+    let Some(filename) = path::Path::new(filename)
+        .file_name()
+        .and_then(|s| s.to_str())
+    else {
+        return Err(InvalidLSN::FileName(filename.to_string()));
+    };
+    if filename.len() < 24 || !filename.is_char_boundary(24) {
+        return Err(InvalidLSN::FileName(filename.to_string()));
+    }
+
+    let tli_str = &filename[0..8];
+    let tli = match u64::from_str_radix(tli_str, 16) {
+        Ok(tli) => tli,
+        Err(e) => return Err(InvalidLSN::HexValue(tli_str.to_string(), e.to_string())),
+    };
+
+    let log_str = &filename[8..16];
+    let log = match u64::from_str_radix(log_str, 16) {
+        Ok(log) => log,
+        Err(e) => return Err(InvalidLSN::HexValue(log_str.to_string(), e.to_string())),
+    };
+
+    let seg_str = &filename[16..24];
+    let seg = match u64::from_str_radix(seg_str, 16) {
+        Ok(seg) => seg,
+        Err(e) => return Err(InvalidLSN::HexValue(seg_str.to_string(), e.to_string())),
+    };
+    Ok((tli, log * (0x100000000 / wal_segsz_bytes) + seg))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsn::{
+        filename_to_startptr, format_lsn, lsn_to_segno, parse_lsn, segno_to_lsn, xlog_file_name,
+        InvalidLSN,
+    };
+
+    #[test]
+    fn test_parse_lsn() {
+        assert_eq!(parse_lsn("0/01800C50"), Ok(0x1800c50));
+        assert_eq!(parse_lsn("2/01800C50"), Ok(0x201800c50));
+        assert_eq!(
+            parse_lsn("01800C50"),
+            Err(InvalidLSN::Format("01800C50".to_string()))
+        );
+        assert!(matches!(parse_lsn("0/zz"), Err(InvalidLSN::HexValue(..))));
+    }
+
+    #[test]
+    fn test_format_lsn() {
+        assert_eq!(format_lsn(0x201800c50), "2/01800C50");
+        assert_eq!(parse_lsn(&format_lsn(0x1800028)), Ok(0x1800028));
+    }
+
+    #[test]
+    fn test_segno() {
+        assert_eq!(lsn_to_segno(0x1800c50, 1024 * 1024), 0x18);
+        assert_eq!(segno_to_lsn(0x18, 1024 * 1024), 0x1800000);
+    }
+
+    #[test]
+    fn test_xlog_file_name() {
+        assert_eq!(
+            xlog_file_name(1, 0x18, 1024 * 1024),
+            "000000010000000000000018"
+        );
+        assert_eq!(
+            xlog_file_name(1, 2 * 256 + 24, 16 * 1024 * 1024),
+            "000000010000000200000018"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_startptr() {
+        let res = filename_to_startptr("000000010000000000000018", 1024 * 1024);
+        assert_eq!(res, Ok((1, 24)));
+        let res = filename_to_startptr("/archive/000000010000000200000018", 16 * 1024 * 1024);
+        assert_eq!(res, Ok((1, 2 * 256 + 24)));
+        assert!(filename_to_startptr("00000002.history", 1024 * 1024).is_err());
+    }
+}
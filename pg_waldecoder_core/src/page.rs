@@ -0,0 +1,297 @@
+use thiserror::Error;
+
+use crate::{segment::is_wal_segsz_valid, version::WalVersion, XLOG_BLCKSZ};
+
+/// `XLOG_PAGE_MAGIC` of the WAL format of the server
+pub const XLOG_PAGE_MAGIC: u16 = 0xD118;
+/// The page starts with the remaining part of a record, `XLP_FIRST_IS_CONTRECORD`
+pub const XLP_FIRST_IS_CONTRECORD: u16 = 0x0001;
+/// The page has a long header, `XLP_LONG_HEADER`
+pub const XLP_LONG_HEADER: u16 = 0x0002;
+/// Full page images can be removed, `XLP_BKP_REMOVABLE`
+pub const XLP_BKP_REMOVABLE: u16 = 0x0004;
+/// The contrecord was overwritten, `XLP_FIRST_IS_OVERWRITE_CONTRECORD`
+pub const XLP_FIRST_IS_OVERWRITE_CONTRECORD: u16 = 0x0008;
+
+/// `SizeOfXLogShortPHD`
+pub const SIZE_OF_XLOG_SHORT_PHD: usize = 24;
+/// `SizeOfXLogLongPHD`
+pub const SIZE_OF_XLOG_LONG_PHD: usize = 40;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidPage {
+    #[error("Page is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("Invalid magic number {0:04X} at {1:X}")]
+    InvalidMagic(u16, u64),
+    #[error("Unexpected page address {0:X}, expected {1:X}")]
+    UnexpectedPageAddr(u64, u64),
+    #[error("Missing long header at the start of the segment")]
+    MissingLongHeader,
+    #[error("Invalid WAL segment size {0}. The WAL segment size must be a power of two between 1MB and 1GB.")]
+    InvalidWalSegSz(u32),
+    #[error("Invalid WAL block size {0}, expected {XLOG_BLCKSZ}")]
+    InvalidBlckSz(u32),
+    #[error("WAL written in {} byte order, expected {}", .0.name(), .1.name())]
+    UnexpectedByteOrder(ByteOrder, ByteOrder),
+}
+
+/// Byte order of the fields of the WAL, the native one of the server that
+/// wrote it
+#[derive(Clone, Copy, Debug, Default, Hash, Ord, PartialOrd, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// Byte order of the platform
+    pub const NATIVE: ByteOrder = if cfg!(target_endian = "big") {
+        ByteOrder::Big
+    } else {
+        ByteOrder::Little
+    };
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            ByteOrder::Little => "little-endian",
+            ByteOrder::Big => "big-endian",
+        }
+    }
+
+    pub(crate) fn read_u16(self, buf: &[u8], offset: usize) -> u16 {
+        let bytes = buf[offset..offset + 2].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub(crate) fn read_u32(self, buf: &[u8], offset: usize) -> u32 {
+        let bytes = buf[offset..offset + 4].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    pub(crate) fn read_u64(self, buf: &[u8], offset: usize) -> u64 {
+        let bytes = buf[offset..offset + 8].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u64::from_le_bytes(bytes),
+            ByteOrder::Big => u64::from_be_bytes(bytes),
+        }
+    }
+}
+
+/// Header present at the start of every WAL page, `XLogPageHeaderData`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageHeader {
+    pub magic: u16,
+    pub info: u16,
+    pub tli: u32,
+    pub pageaddr: u64,
+    /// Length of the record continued from the previous page
+    pub rem_len: u32,
+    /// Detected from the magic
+    pub byte_order: ByteOrder,
+}
+
+/// Additional fields of the first page of a segment, `XLogLongPageHeaderData`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LongPageHeader {
+    pub std: PageHeader,
+    pub sysid: u64,
+    pub seg_size: u32,
+    pub xlog_blcksz: u32,
+}
+
+impl PageHeader {
+    /// Parse the header in the byte order giving a known magic, little-endian
+    /// if none does
+    pub fn parse(buf: &[u8]) -> Result<PageHeader, InvalidPage> {
+        if buf.len() < SIZE_OF_XLOG_SHORT_PHD {
+            return Err(InvalidPage::TooShort(buf.len()));
+        }
+        let order = [ByteOrder::Little, ByteOrder::Big]
+            .into_iter()
+            .find(|order| WalVersion::from_magic(order.read_u16(buf, 0)).is_some())
+            .unwrap_or_default();
+        Ok(PageHeader {
+            magic: order.read_u16(buf, 0),
+            info: order.read_u16(buf, 2),
+            tli: order.read_u32(buf, 4),
+            pageaddr: order.read_u64(buf, 8),
+            rem_len: order.read_u32(buf, 16),
+            byte_order: order,
+        })
+    }
+
+    #[must_use]
+    pub fn is_long(&self) -> bool {
+        self.info & XLP_LONG_HEADER != 0
+    }
+
+    /// Size of the header, records start after it
+    #[must_use]
+    pub fn size(&self) -> usize {
+        if self.is_long() {
+            SIZE_OF_XLOG_LONG_PHD
+        } else {
+            SIZE_OF_XLOG_SHORT_PHD
+        }
+    }
+
+    /// Major version of the server that wrote the page
+    #[must_use]
+    pub fn version(&self) -> Option<WalVersion> {
+        WalVersion::from_magic(self.magic)
+    }
+
+    /// Check that the page can be read by the server: its magic, byte order
+    /// and that it is at the expected address
+    pub fn validate(&self, expected_pageaddr: u64) -> Result<(), InvalidPage> {
+        self.validate_byte_order(ByteOrder::NATIVE)?;
+        self.validate_version(expected_pageaddr, WalVersion::CURRENT)
+    }
+
+    pub fn validate_byte_order(&self, expected: ByteOrder) -> Result<(), InvalidPage> {
+        if self.byte_order != expected {
+            return Err(InvalidPage::UnexpectedByteOrder(self.byte_order, expected));
+        }
+        Ok(())
+    }
+
+    /// Check that the page was written by a server of the version, at the
+    /// expected address
+    pub fn validate_version(
+        &self,
+        expected_pageaddr: u64,
+        version: WalVersion,
+    ) -> Result<(), InvalidPage> {
+        if self.magic != version.magic() {
+            return Err(InvalidPage::InvalidMagic(self.magic, expected_pageaddr));
+        }
+        if self.pageaddr != expected_pageaddr {
+            return Err(InvalidPage::UnexpectedPageAddr(
+                self.pageaddr,
+                expected_pageaddr,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl LongPageHeader {
+    /// Parse and validate the header of the first page of a segment
+    pub fn parse(buf: &[u8]) -> Result<LongPageHeader, InvalidPage> {
+        let std = PageHeader::parse(buf)?;
+        if !std.is_long() {
+            return Err(InvalidPage::MissingLongHeader);
+        }
+        if buf.len() < SIZE_OF_XLOG_LONG_PHD {
+            return Err(InvalidPage::TooShort(buf.len()));
+        }
+        let order = std.byte_order;
+        let header = LongPageHeader {
+            std,
+            sysid: order.read_u64(buf, 24),
+            seg_size: order.read_u32(buf, 32),
+            xlog_blcksz: order.read_u32(buf, 36),
+        };
+        if !is_wal_segsz_valid(header.seg_size) {
+            return Err(InvalidPage::InvalidWalSegSz(header.seg_size));
+        }
+        if header.xlog_blcksz != XLOG_BLCKSZ {
+            return Err(InvalidPage::InvalidBlckSz(header.xlog_blcksz));
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::page::{
+        ByteOrder, InvalidPage, LongPageHeader, PageHeader, XLOG_PAGE_MAGIC, XLP_BKP_REMOVABLE,
+        XLP_FIRST_IS_CONTRECORD, XLP_LONG_HEADER,
+    };
+
+    fn test_segment() -> Vec<u8> {
+        std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_long_page_header() {
+        let segment = test_segment();
+        let header = LongPageHeader::parse(&segment).unwrap();
+        assert_eq!(header.std.info, XLP_LONG_HEADER | XLP_BKP_REMOVABLE);
+        assert_eq!(header.std.tli, 1);
+        assert_eq!(header.std.rem_len, 0);
+        assert_eq!(header.seg_size, 1024 * 1024);
+        assert_eq!(header.xlog_blcksz, 8192);
+        assert!(header.std.validate(0x1800000).is_ok());
+        assert_eq!(
+            header.std.validate(0x1900000),
+            Err(InvalidPage::UnexpectedPageAddr(0x1800000, 0x1900000))
+        );
+    }
+
+    #[test]
+    fn test_short_page_header() {
+        let mut page = Vec::new();
+        page.extend(XLOG_PAGE_MAGIC.to_le_bytes());
+        page.extend(XLP_FIRST_IS_CONTRECORD.to_le_bytes());
+        page.extend(1u32.to_le_bytes());
+        page.extend(0x1802000u64.to_le_bytes());
+        page.extend(12u32.to_le_bytes());
+        page.extend([0; 4]);
+        let header = PageHeader::parse(&page).unwrap();
+        assert!(!header.is_long());
+        assert_eq!(header.size(), 24);
+        assert_eq!(header.rem_len, 12);
+        assert!(header.validate(0x1802000).is_ok());
+        assert_eq!(
+            LongPageHeader::parse(&page),
+            Err(InvalidPage::MissingLongHeader)
+        );
+        assert_eq!(
+            PageHeader::parse(&[0; 24]).unwrap().validate(0x1802000),
+            Err(InvalidPage::InvalidMagic(0, 0x1802000))
+        );
+        assert_eq!(PageHeader::parse(&[0; 4]), Err(InvalidPage::TooShort(4)));
+    }
+
+    #[test]
+    fn test_big_endian_page_header() {
+        let mut page = Vec::new();
+        page.extend(XLOG_PAGE_MAGIC.to_be_bytes());
+        page.extend(XLP_FIRST_IS_CONTRECORD.to_be_bytes());
+        page.extend(1u32.to_be_bytes());
+        page.extend(0x1802000u64.to_be_bytes());
+        page.extend(12u32.to_be_bytes());
+        page.extend([0; 4]);
+        let header = PageHeader::parse(&page).unwrap();
+        assert_eq!(header.byte_order, ByteOrder::Big);
+        assert_eq!(header.magic, XLOG_PAGE_MAGIC);
+        assert_eq!(header.tli, 1);
+        assert_eq!(header.pageaddr, 0x1802000);
+        assert_eq!(header.rem_len, 12);
+        assert_eq!(
+            header.validate_byte_order(ByteOrder::Little),
+            Err(InvalidPage::UnexpectedByteOrder(
+                ByteOrder::Big,
+                ByteOrder::Little
+            ))
+        );
+        assert_eq!(
+            InvalidPage::UnexpectedByteOrder(ByteOrder::Big, ByteOrder::Little).to_string(),
+            "WAL written in big-endian byte order, expected little-endian"
+        );
+    }
+}
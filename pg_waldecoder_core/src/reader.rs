@@ -0,0 +1,847 @@
+use std::io::{self, Read};
+
+use thiserror::Error;
+
+use crate::{
+    decode::{decode_record, DecodedRecord, InvalidBlockRef},
+    lsn::format_lsn,
+    maxalign,
+    page::{ByteOrder, InvalidPage, LongPageHeader, PageHeader},
+    record::{verify_record, InvalidRecord, RecordHeader, SIZE_OF_XLOG_RECORD},
+    version::WalVersion,
+    XLOG_BLCKSZ,
+};
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum ReadError {
+    #[error("Invalid page at {}: {}", format_lsn(*.0), .1)]
+    Page(u64, InvalidPage),
+    #[error("Invalid record at {}: {}", format_lsn(*.0), .1)]
+    Record(u64, InvalidRecord),
+    #[error("Record at {} continues past the end of the provided WAL", format_lsn(*.0))]
+    Incomplete(u64),
+    #[error("Couldn't read the WAL at {}: {}", format_lsn(*.0), .1)]
+    Io(u64, String),
+}
+
+impl ReadError {
+    /// Where the WAL couldn't be read
+    #[must_use]
+    pub fn lsn(&self) -> u64 {
+        match self {
+            ReadError::Page(lsn, _)
+            | ReadError::Record(lsn, _)
+            | ReadError::Incomplete(lsn)
+            | ReadError::Io(lsn, _) => *lsn,
+        }
+    }
+}
+
+/// A complete WAL record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub lsn: u64,
+    pub header: RecordHeader,
+    /// The whole record, header included
+    pub data: Vec<u8>,
+    /// Version of the server that wrote the record
+    pub version: WalVersion,
+    /// Byte order of the platform that wrote the record
+    pub byte_order: ByteOrder,
+}
+
+impl Record {
+    /// Block references and main data of the record
+    pub fn decode(&self) -> Result<DecodedRecord, InvalidBlockRef> {
+        decode_record(&self.data, self.version, self.byte_order)
+    }
+}
+
+/// Reads the records of consecutive WAL segments loaded in memory, without
+/// a server. Iteration stops at the end of the WAL, or at the first error.
+/// Reading continues in the next segment after a `XLOG_SWITCH` record.
+pub struct WalReader<'a> {
+    wal: &'a [u8],
+    /// LSN of the first byte of `wal`
+    base: u64,
+    seg_size: u32,
+    /// Version of the server that wrote the segments, from their magic
+    version: WalVersion,
+    byte_order: ByteOrder,
+    /// Position of the next record in `wal`
+    pos: usize,
+    /// Position after the last record read, before the padding of a switch
+    record_end: usize,
+    prev_lsn: Option<u64>,
+    done: bool,
+}
+
+/// Offset of a position inside its page
+fn page_offset(pos: usize) -> usize {
+    pos % usize::try_from(XLOG_BLCKSZ).unwrap()
+}
+
+impl<'a> WalReader<'a> {
+    /// Start reading at the first record beginning in the segments, `wal`
+    /// must start at a segment boundary. Segments written by servers from 13
+    /// are supported, on platforms of either byte order, only their records
+    /// and block references are decoded. Records are expected
+    /// aligned on 8 bytes, WAL of platforms with a smaller MAXALIGN fails with
+    /// an invalid record.
+    pub fn new(wal: &'a [u8]) -> Result<WalReader<'a>, ReadError> {
+        let header = LongPageHeader::parse(wal).map_err(|e| ReadError::Page(0, e))?;
+        let base = header.std.pageaddr;
+        let version = header.std.version().ok_or(ReadError::Page(
+            base,
+            InvalidPage::InvalidMagic(header.std.magic, base),
+        ))?;
+        let mut reader = WalReader {
+            wal,
+            base,
+            seg_size: header.seg_size,
+            version,
+            byte_order: header.std.byte_order,
+            pos: 0,
+            record_end: 0,
+            prev_lsn: None,
+            done: false,
+        };
+        // Skip the end of a record started in the previous segment
+        let rem_len = usize::try_from(header.std.rem_len).unwrap();
+        let mut skipped = Vec::new();
+        let pos = reader.read(header.std.size(), rem_len, &mut skipped)?;
+        reader.pos = reader.align(pos);
+        reader.record_end = reader.pos;
+        Ok(reader)
+    }
+
+    #[must_use]
+    pub fn seg_size(&self) -> u32 {
+        self.seg_size
+    }
+
+    #[must_use]
+    pub fn version(&self) -> WalVersion {
+        self.version
+    }
+
+    fn lsn(&self, pos: usize) -> u64 {
+        self.base + u64::try_from(pos).unwrap()
+    }
+
+    fn align(&self, pos: usize) -> usize {
+        let aligned = maxalign(self.lsn(pos)) - self.base;
+        usize::try_from(aligned).unwrap()
+    }
+
+    /// Position after the page header if `pos` is at the start of a page,
+    /// None if the page was never written
+    fn skip_page_header(&self, pos: usize) -> Result<Option<usize>, ReadError> {
+        if page_offset(pos) != 0 {
+            return Ok(Some(pos));
+        }
+        let lsn = self.lsn(pos);
+        let page = &self.wal[pos.min(self.wal.len())..];
+        let header = PageHeader::parse(page).map_err(|_| ReadError::Incomplete(lsn))?;
+        if header == PageHeader::default() {
+            return Ok(None);
+        }
+        header
+            .validate_byte_order(self.byte_order)
+            .and_then(|()| header.validate_version(lsn, self.version))
+            .map_err(|e| ReadError::Page(lsn, e))?;
+        Ok(Some(pos + header.size()))
+    }
+
+    /// Copy `len` bytes of record data from `pos`, skipping the page headers,
+    /// returns the position after them
+    fn read(&self, mut pos: usize, mut len: usize, out: &mut Vec<u8>) -> Result<usize, ReadError> {
+        while len > 0 {
+            let Some(data_pos) = self.skip_page_header(pos)? else {
+                return Err(ReadError::Incomplete(self.lsn(pos)));
+            };
+            pos = data_pos;
+            let page_end = pos - page_offset(pos) + usize::try_from(XLOG_BLCKSZ).unwrap();
+            let end = page_end.min(pos + len);
+            if end > self.wal.len() {
+                return Err(ReadError::Incomplete(self.lsn(pos)));
+            }
+            out.extend_from_slice(&self.wal[pos..end]);
+            len -= end - pos;
+            pos = end;
+        }
+        Ok(pos)
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, ReadError> {
+        if self.pos >= self.wal.len() {
+            return Ok(None);
+        }
+        let Some(start) = self.skip_page_header(self.pos)? else {
+            return Ok(None);
+        };
+        let lsn = self.lsn(start);
+        let mut data = Vec::with_capacity(SIZE_OF_XLOG_RECORD);
+        let pos = self.read(start, SIZE_OF_XLOG_RECORD, &mut data)?;
+        if data.iter().all(|b| *b == 0) {
+            // Zeroed space after the last record
+            return Ok(None);
+        }
+        let header =
+            RecordHeader::parse(&data, self.byte_order).map_err(|e| ReadError::Record(lsn, e))?;
+        if let Some(prev_lsn) = self.prev_lsn {
+            header
+                .validate_prev(prev_lsn)
+                .map_err(|e| ReadError::Record(lsn, e))?;
+        }
+        let remaining = usize::try_from(header.tot_len).unwrap() - SIZE_OF_XLOG_RECORD;
+        let pos = self.read(pos, remaining, &mut data)?;
+        verify_record(&data, self.byte_order).map_err(|e| ReadError::Record(lsn, e))?;
+        self.record_end = self.align(pos);
+        self.pos = if header.is_switch() {
+            // Jump over the padding instead of reading its pages
+            let next_segment = self.lsn(pos).next_multiple_of(u64::from(self.seg_size));
+            usize::try_from(next_segment - self.base).unwrap()
+        } else {
+            self.record_end
+        };
+        self.prev_lsn = Some(lsn);
+        Ok(Some(Record {
+            lsn,
+            header,
+            data,
+            version: self.version,
+            byte_order: self.byte_order,
+        }))
+    }
+}
+
+impl Iterator for WalReader<'_> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.read_record().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+/// Reads the records of consecutive WAL segments from `source` page by page,
+/// keeping in memory only the pages of the next record. Records are read as
+/// with [`WalReader`], `source` must start at a segment boundary.
+pub struct StreamReader<R> {
+    source: R,
+    /// Pages read from `source` and not consumed yet
+    buf: Vec<u8>,
+    /// LSN of the first byte of `buf`, at a page boundary
+    base: u64,
+    seg_size: u32,
+    version: WalVersion,
+    byte_order: ByteOrder,
+    /// LSN of the next record
+    next_lsn: u64,
+    prev_lsn: Option<u64>,
+    eof: bool,
+    done: bool,
+}
+
+/// Append pages of `source` to `buf`, as many as it already holds to read
+/// long records in a few passes. Returns false at the end of `source`.
+fn read_pages<R: Read>(source: &mut R, buf: &mut Vec<u8>, base: u64) -> Result<bool, ReadError> {
+    let len = buf.len().max(usize::try_from(XLOG_BLCKSZ).unwrap());
+    let end = base + u64::try_from(buf.len()).unwrap();
+    let read = source
+        .by_ref()
+        .take(u64::try_from(len).unwrap())
+        .read_to_end(buf)
+        .map_err(|e| ReadError::Io(end, e.to_string()))?;
+    Ok(read > 0)
+}
+
+impl<R: Read> StreamReader<R> {
+    /// Start reading at the first record beginning in the first segment of
+    /// `source`
+    pub fn new(mut source: R) -> Result<StreamReader<R>, ReadError> {
+        let mut buf = Vec::new();
+        let mut eof = !read_pages(&mut source, &mut buf, 0)?;
+        let reader = loop {
+            match WalReader::new(&buf) {
+                Ok(reader) => break reader,
+                // The end of a record started in the previous segment
+                Err(ReadError::Incomplete(_)) if !eof => {
+                    eof = !read_pages(&mut source, &mut buf, 0)?;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let (base, seg_size, version, byte_order) = (
+            reader.base,
+            reader.seg_size,
+            reader.version,
+            reader.byte_order,
+        );
+        let next_lsn = reader.lsn(reader.pos);
+        let mut stream = StreamReader {
+            source,
+            buf,
+            base,
+            seg_size,
+            version,
+            byte_order,
+            next_lsn,
+            prev_lsn: None,
+            eof,
+            done: false,
+        };
+        stream.consume()?;
+        Ok(stream)
+    }
+
+    #[must_use]
+    pub fn seg_size(&self) -> u32 {
+        self.seg_size
+    }
+
+    #[must_use]
+    pub fn version(&self) -> WalVersion {
+        self.version
+    }
+
+    fn fill(&mut self) -> Result<(), ReadError> {
+        self.eof = !read_pages(&mut self.source, &mut self.buf, self.base)?;
+        Ok(())
+    }
+
+    /// Drop the pages before the next record, skipping the ones not read yet
+    /// after a switch
+    fn consume(&mut self) -> Result<(), ReadError> {
+        let page_size = u64::from(XLOG_BLCKSZ);
+        let consumed = (self.next_lsn - self.base) / page_size * page_size;
+        let buffered = u64::try_from(self.buf.len()).unwrap();
+        if consumed <= buffered {
+            self.buf.drain(..usize::try_from(consumed).unwrap());
+        } else {
+            let end = self.base + buffered;
+            let skipped = io::copy(
+                &mut self.source.by_ref().take(consumed - buffered),
+                &mut io::sink(),
+            )
+            .map_err(|e| ReadError::Io(end, e.to_string()))?;
+            self.eof = skipped < consumed - buffered;
+            self.buf.clear();
+        }
+        self.base += consumed;
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, ReadError> {
+        loop {
+            let pos = usize::try_from(self.next_lsn - self.base).unwrap();
+            if pos >= self.buf.len() && !self.eof {
+                self.fill()?;
+                continue;
+            }
+            let mut reader = WalReader {
+                wal: &self.buf,
+                base: self.base,
+                seg_size: self.seg_size,
+                version: self.version,
+                byte_order: self.byte_order,
+                pos,
+                record_end: pos,
+                prev_lsn: self.prev_lsn,
+                done: false,
+            };
+            match reader.read_record() {
+                Ok(record) => {
+                    self.next_lsn = reader.lsn(reader.pos);
+                    self.prev_lsn = reader.prev_lsn;
+                    self.consume()?;
+                    return Ok(record);
+                }
+                Err(ReadError::Incomplete(_)) if !self.eof => self.fill()?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamReader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.read_record().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+/// Records of a single segment
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SegmentRecords {
+    /// First record beginning in the segment
+    pub first_lsn: Option<u64>,
+    /// Last record complete in the segment
+    pub last_lsn: Option<u64>,
+    /// Why the segment couldn't be read to its end
+    pub error: Option<ReadError>,
+}
+
+/// Read the records of a segment. The record continuing in the next segment
+/// isn't an error, it's just not counted.
+#[must_use]
+pub fn segment_records(segment: &[u8]) -> SegmentRecords {
+    let reader = match WalReader::new(segment) {
+        Ok(reader) => reader,
+        Err(ReadError::Incomplete(_)) => return SegmentRecords::default(),
+        Err(e) => {
+            return SegmentRecords {
+                error: Some(e),
+                ..Default::default()
+            }
+        }
+    };
+    let mut records = SegmentRecords::default();
+    for record in reader {
+        match record {
+            Ok(record) => {
+                records.first_lsn.get_or_insert(record.lsn);
+                records.last_lsn = Some(record.lsn);
+            }
+            Err(ReadError::Incomplete(_)) => {}
+            Err(e) => records.error = Some(e),
+        }
+    }
+    records
+}
+
+/// Records beginning in a segment, chained to the previous segment's
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SegmentChain {
+    /// Last record beginning in the segment
+    pub last_lsn: Option<u64>,
+    /// Where the chain of records breaks, reading stops there
+    pub error: Option<ReadError>,
+}
+
+/// Page left from the previous use of a recycled segment, ending the WAL
+fn is_recycled_page(e: &ReadError) -> bool {
+    matches!(e, ReadError::Page(_, InvalidPage::UnexpectedPageAddr(pageaddr, expected)) if pageaddr < expected)
+}
+
+/// Walk the records beginning in the first segment of `wal`, starting at LSN
+/// `base`, checking their CRC and that each one points back to the previous
+/// one. The first record must point to `prev_lsn`, the last record of the
+/// previous segment, when known. `wal` may hold the next segment too, for the
+/// record crossing the boundary: without it, the record is the end of the WAL
+/// rather than a break. Zeroes and pages of an older address, left from the
+/// previous use of a recycled segment, end the WAL too.
+#[must_use]
+pub fn segment_chain(wal: &[u8], base: u64, prev_lsn: Option<u64>) -> SegmentChain {
+    let unwritten = PageHeader::parse(wal).is_ok_and(|header| {
+        header == PageHeader::default() || (header.version().is_some() && header.pageaddr < base)
+    });
+    if unwritten {
+        return SegmentChain::default();
+    }
+    let mut reader = match WalReader::new(wal) {
+        Ok(reader) if reader.base != base => {
+            return SegmentChain {
+                error: Some(ReadError::Page(
+                    base,
+                    InvalidPage::UnexpectedPageAddr(reader.base, base),
+                )),
+                ..Default::default()
+            }
+        }
+        Ok(reader) => reader,
+        Err(e) => {
+            return SegmentChain {
+                error: Some(e),
+                ..Default::default()
+            }
+        }
+    };
+    let seg_size = usize::try_from(reader.seg_size).unwrap();
+    let seg_end = reader.lsn(seg_size);
+    reader.prev_lsn = prev_lsn;
+    let mut chain = SegmentChain::default();
+    for record in reader {
+        match record {
+            Ok(record) if record.lsn < seg_end => chain.last_lsn = Some(record.lsn),
+            Ok(_) => break,
+            Err(ReadError::Incomplete(_)) if wal.len() <= seg_size => {}
+            Err(e) if is_recycled_page(&e) => {}
+            Err(e) => chain.error = Some(e),
+        }
+    }
+    chain
+}
+
+/// Content of a range of a segment file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Valid records, from the start of the segment
+    Records,
+    /// Padding following a `XLOG_SWITCH` record up to the end of the segment
+    SwitchPadding,
+    /// Zeroes, space never written since the segment was created
+    Zeroed,
+    /// Pages left from the previous use of a recycled segment, starting with
+    /// the page of this LSN
+    Recycled(u64),
+    /// Pages at the segment's address whose records can't be read
+    Invalid,
+}
+
+impl RegionKind {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionKind::Records => "records",
+            RegionKind::SwitchPadding => "switch_padding",
+            RegionKind::Zeroed => "zeroed",
+            RegionKind::Recycled(_) => "recycled",
+            RegionKind::Invalid => "invalid",
+        }
+    }
+}
+
+/// Range of LSNs of a segment with the same content
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+/// Add a range to the regions, extending the last region when it's of the
+/// same kind and follows it
+fn push_region(regions: &mut Vec<Region>, start: u64, end: u64, kind: RegionKind) {
+    if start >= end {
+        return;
+    }
+    if let Some(last) = regions.last_mut() {
+        let extends = match (last.kind, kind) {
+            (RegionKind::Recycled(last_addr), RegionKind::Recycled(addr)) => {
+                last_addr + (last.end - last.start) == addr
+            }
+            (last_kind, kind) => last_kind == kind,
+        };
+        if extends && last.end == start {
+            last.end = end;
+            return;
+        }
+    }
+    regions.push(Region { start, end, kind });
+}
+
+/// Split a segment starting at LSN `base` in regions of valid records,
+/// switch padding, zeroes, pages left from its previous use before being
+/// recycled, or invalid pages, telling an idle server from a segment
+/// recycled before being archived. A record continued in the next segment
+/// is counted as valid up to the end of the segment.
+#[must_use]
+pub fn segment_regions(segment: &[u8], base: u64) -> Vec<Region> {
+    let len = u64::try_from(segment.len()).unwrap();
+    let mut regions = Vec::new();
+    let mut records_end = 0;
+    let reader = match WalReader::new(segment) {
+        Ok(reader) => Some(reader).filter(|reader| reader.base == base),
+        // A record continued from the previous segment fills this one
+        Err(ReadError::Incomplete(lsn)) if lsn >= base + len => {
+            if LongPageHeader::parse(segment).is_ok_and(|header| header.std.pageaddr == base) {
+                push_region(&mut regions, base, base + len, RegionKind::Records);
+                return regions;
+            }
+            None
+        }
+        Err(_) => None,
+    };
+    if let Some(mut reader) = reader {
+        let mut switched = false;
+        let mut continued = false;
+        for record in reader.by_ref() {
+            match record {
+                Ok(record) => switched = record.header.is_switch(),
+                Err(ReadError::Incomplete(lsn)) => continued = lsn >= base + len,
+                Err(_) => {}
+            }
+        }
+        records_end = if continued {
+            len
+        } else {
+            u64::try_from(reader.record_end).unwrap().min(len)
+        };
+        push_region(&mut regions, base, base + records_end, RegionKind::Records);
+        if switched {
+            push_region(
+                &mut regions,
+                base + records_end,
+                base + len,
+                RegionKind::SwitchPadding,
+            );
+            return regions;
+        }
+    }
+
+    // The rest of the last page of records, then the following pages
+    let page_size = u64::from(XLOG_BLCKSZ);
+    let mut offset = records_end;
+    while offset < len {
+        let page_end = (offset - offset % page_size + page_size).min(len);
+        let range = &segment[usize::try_from(offset).unwrap()..usize::try_from(page_end).unwrap()];
+        let kind = if range.iter().all(|b| *b == 0) {
+            RegionKind::Zeroed
+        } else if offset % page_size != 0 {
+            // Bytes after the last record, written over what the page held
+            RegionKind::Invalid
+        } else {
+            match PageHeader::parse(range) {
+                Ok(header) if header.version().is_some() && header.pageaddr != base + offset => {
+                    RegionKind::Recycled(header.pageaddr)
+                }
+                _ => RegionKind::Invalid,
+            }
+        };
+        push_region(&mut regions, base + offset, base + page_end, kind);
+        offset = page_end;
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        page::{InvalidPage, XLOG_PAGE_MAGIC},
+        reader::{
+            is_recycled_page, segment_chain, segment_records, segment_regions, ReadError, Region,
+            RegionKind, SegmentChain, StreamReader, WalReader,
+        },
+        record::{record_crc, InvalidRecord, RM_XLOG_ID, XLOG_SWITCH},
+        version::WalVersion,
+        XLOG_BLCKSZ,
+    };
+
+    fn test_segment() -> Vec<u8> {
+        std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wal_reader() {
+        let segment = test_segment();
+        let records = WalReader::new(&segment)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records[0].lsn, 0x1800028);
+        assert_eq!(records[0].header.tot_len, 3109);
+        assert_eq!(records[1].lsn, 0x1800c50);
+        assert_eq!(records[1].header.prev, 0x1800028);
+        assert!(records.windows(2).all(|w| w[1].header.prev == w[0].lsn));
+        // Every record read from the segment can be split in its blocks
+        assert!(records.iter().all(|record| record.decode().is_ok()));
+    }
+
+    #[test]
+    fn test_wal_reader_version() {
+        let segment = test_segment();
+        // Same records with the page magic of 17
+        let mut older = segment.clone();
+        for page in older.chunks_mut(usize::try_from(XLOG_BLCKSZ).unwrap()) {
+            if page[..2] == XLOG_PAGE_MAGIC.to_le_bytes() {
+                page[..2].copy_from_slice(&WalVersion::V17.magic().to_le_bytes());
+            }
+        }
+        let reader = WalReader::new(&older).unwrap();
+        assert_eq!(reader.version(), WalVersion::V17);
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), WalReader::new(&segment).unwrap().count());
+        assert!(records.iter().all(|r| r.version == WalVersion::V17));
+
+        // Magic of an unsupported version
+        older[..2].copy_from_slice(&0xD000u16.to_le_bytes());
+        assert_eq!(
+            WalReader::new(&older).err(),
+            Some(ReadError::Page(
+                0x1800000,
+                InvalidPage::InvalidMagic(0xD000, 0x1800000)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_wal_reader_errors() {
+        let segment = test_segment();
+        // The second record is cut
+        let mut reader = WalReader::new(&segment[..0xc60]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next(), Some(Err(ReadError::Incomplete(0x1800c50))));
+        assert_eq!(reader.next(), None);
+
+        assert!(matches!(
+            WalReader::new(&segment[8192..]),
+            Err(ReadError::Page(0, InvalidPage::MissingLongHeader))
+        ));
+    }
+
+    #[test]
+    fn test_wal_reader_switch() {
+        let mut segment = test_segment();
+        let first = WalReader::new(&segment).unwrap().next().unwrap().unwrap();
+        // Turn the first record into a switch, the records after it are
+        // skipped with the padding
+        let start = usize::try_from(first.lsn - 0x1800000).unwrap();
+        let mut record = first.data.clone();
+        record[16] = XLOG_SWITCH;
+        record[17] = RM_XLOG_ID;
+        let crc = record_crc(&record);
+        record[20..24].copy_from_slice(&crc.to_le_bytes());
+        // The record spans pages, only its header is patched in place
+        segment[start..start + 24].copy_from_slice(&record[..24]);
+        let records = WalReader::new(&segment)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].header.is_switch());
+        // The padding is skipped without being kept
+        let records = StreamReader::new(&segment[..])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn test_stream_reader() {
+        let segment = test_segment();
+        let records = WalReader::new(&segment)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let stream = StreamReader::new(&segment[..]).unwrap();
+        assert_eq!(stream.seg_size(), u32::try_from(segment.len()).unwrap());
+        assert_eq!(stream.collect::<Result<Vec<_>, _>>().unwrap(), records);
+
+        // The second record is cut
+        let mut stream = StreamReader::new(&segment[..0xc60]).unwrap();
+        assert!(stream.next().unwrap().is_ok());
+        assert_eq!(stream.next(), Some(Err(ReadError::Incomplete(0x1800c50))));
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    fn test_segment_records() {
+        let mut segment = test_segment();
+        let last_lsn = WalReader::new(&segment)
+            .unwrap()
+            .last()
+            .unwrap()
+            .unwrap()
+            .lsn;
+        let records = segment_records(&segment);
+        assert_eq!(records.first_lsn, Some(0x1800028));
+        assert_eq!(records.last_lsn, Some(last_lsn));
+        assert_eq!(records.error, None);
+
+        // A cut record is left for the next segment
+        let records = segment_records(&segment[..0xc60]);
+        assert_eq!(records.last_lsn, Some(0x1800028));
+        assert_eq!(records.error, None);
+
+        // The CRC of the second record doesn't match
+        segment[0xc68] ^= 0xff;
+        let records = segment_records(&segment);
+        assert_eq!(records.last_lsn, Some(0x1800028));
+        assert!(matches!(
+            records.error,
+            Some(ReadError::Record(0x1800c50, _))
+        ));
+    }
+
+    #[test]
+    fn test_segment_regions() {
+        let base = 0x1800000;
+        let segment = test_segment();
+        let end = base + u64::try_from(segment.len()).unwrap();
+        let regions = segment_regions(&segment, base);
+        assert_eq!(regions[0].kind, RegionKind::Records);
+        assert_eq!(regions[0].start, base);
+        assert_eq!(regions.last().unwrap().end, end);
+        assert!(regions.windows(2).all(|w| w[0].end == w[1].start));
+
+        // Never written
+        let zeroed = vec![0; segment.len()];
+        assert_eq!(
+            segment_regions(&zeroed, base),
+            vec![Region {
+                start: base,
+                end,
+                kind: RegionKind::Zeroed
+            }]
+        );
+
+        // The segment recycled as the next one, before it was written
+        let regions = segment_regions(&segment, base + 0x100000);
+        assert_eq!(regions[0].kind, RegionKind::Recycled(base));
+        assert_eq!(regions[0].start, base + 0x100000);
+        assert_eq!(regions.last().unwrap().end, end + 0x100000);
+    }
+
+    #[test]
+    fn test_segment_chain() {
+        let mut segment = test_segment();
+        let records = WalReader::new(&segment)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let base = 0x1800000;
+        let chain = segment_chain(&segment, base, Some(records[0].header.prev));
+        assert_eq!(chain.last_lsn, records.last().map(|r| r.lsn));
+        assert_eq!(chain.error, None);
+
+        // Recycled as the next segment or zeroed, it holds no records yet
+        let next = base + 0x100000;
+        assert_eq!(segment_chain(&segment, next, None), SegmentChain::default());
+        let zeroed = vec![0; segment.len()];
+        assert_eq!(segment_chain(&zeroed, base, None), SegmentChain::default());
+
+        // A page of an older address ends the WAL
+        let recycled = ReadError::Page(next, InvalidPage::UnexpectedPageAddr(base, next));
+        assert!(is_recycled_page(&recycled));
+        let misplaced = ReadError::Page(base, InvalidPage::UnexpectedPageAddr(next, base));
+        assert!(!is_recycled_page(&misplaced));
+
+        // The first record doesn't follow the previous segment's last record
+        let chain = segment_chain(&segment, base, Some(0x17fffd8));
+        assert_eq!(chain.last_lsn, None);
+        assert_eq!(
+            chain.error,
+            Some(ReadError::Record(
+                0x1800028,
+                InvalidRecord::InvalidPrevLink(records[0].header.prev, 0x17fffd8)
+            ))
+        );
+
+        // A modified record breaks the chain
+        segment[0xc68] ^= 0xff;
+        let chain = segment_chain(&segment, base, None);
+        assert_eq!(chain.last_lsn, Some(0x1800028));
+        assert!(matches!(chain.error, Some(ReadError::Record(0x1800c50, _))));
+    }
+}
@@ -0,0 +1,187 @@
+use thiserror::Error;
+
+use crate::{
+    crc::{crc32c_finish, crc32c_update, CRC32C_INIT},
+    page::ByteOrder,
+};
+
+/// `SizeOfXLogRecord`
+pub const SIZE_OF_XLOG_RECORD: usize = 24;
+/// Offset of `xl_crc` in `XLogRecord`
+const XL_CRC_OFFSET: usize = 20;
+/// Resource manager bits of `xl_info`, `XLR_RMGR_INFO_MASK`
+pub const XLR_RMGR_INFO_MASK: u8 = 0xF0;
+/// `RM_XLOG_ID`
+pub const RM_XLOG_ID: u8 = 0;
+/// `XLOG_SWITCH`, the rest of the segment is padding
+pub const XLOG_SWITCH: u8 = 0x40;
+
+/// Names of the builtin resource managers, indexed by `RmgrId`
+const RMGR_NAMES: [&str; 22] = [
+    "XLOG",
+    "Transaction",
+    "Storage",
+    "CLOG",
+    "Database",
+    "Tablespace",
+    "MultiXact",
+    "RelMap",
+    "Standby",
+    "Heap2",
+    "Heap",
+    "Btree",
+    "Hash",
+    "Gin",
+    "Gist",
+    "Sequence",
+    "SPGist",
+    "BRIN",
+    "CommitTs",
+    "ReplicationOrigin",
+    "Generic",
+    "LogicalMessage",
+];
+
+/// Name of a resource manager, custom ones are reported by id
+#[must_use]
+pub fn rmgr_name(rmid: u8) -> String {
+    RMGR_NAMES
+        .get(usize::from(rmid))
+        .map_or_else(|| format!("custom{rmid:03}"), ToString::to_string)
+}
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidRecord {
+    #[error("Record is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("Invalid record length {0}")]
+    InvalidLength(u32),
+    #[error("Record with incorrect prev-link {0:X}, expected {1:X}")]
+    InvalidPrevLink(u64, u64),
+    #[error("Incorrect resource manager data checksum {0:08X}, expected {1:08X}")]
+    InvalidCrc(u32, u32),
+}
+
+/// Fixed part of a WAL record, `XLogRecord`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// Total length of the record, including the header
+    pub tot_len: u32,
+    pub xid: u32,
+    /// Start of the previous record
+    pub prev: u64,
+    pub info: u8,
+    pub rmid: u8,
+    pub crc: u32,
+}
+
+impl RecordHeader {
+    pub fn parse(buf: &[u8], order: ByteOrder) -> Result<RecordHeader, InvalidRecord> {
+        if buf.len() < SIZE_OF_XLOG_RECORD {
+            return Err(InvalidRecord::TooShort(buf.len()));
+        }
+        let header = RecordHeader {
+            tot_len: order.read_u32(buf, 0),
+            xid: order.read_u32(buf, 4),
+            prev: order.read_u64(buf, 8),
+            info: buf[16],
+            rmid: buf[17],
+            crc: order.read_u32(buf, XL_CRC_OFFSET),
+        };
+        if usize::try_from(header.tot_len).is_ok_and(|len| len < SIZE_OF_XLOG_RECORD) {
+            return Err(InvalidRecord::InvalidLength(header.tot_len));
+        }
+        Ok(header)
+    }
+
+    /// Check the prev-link against the start of the previous record
+    pub fn validate_prev(&self, expected_prev: u64) -> Result<(), InvalidRecord> {
+        if self.prev != expected_prev {
+            return Err(InvalidRecord::InvalidPrevLink(self.prev, expected_prev));
+        }
+        Ok(())
+    }
+
+    /// Operation bits of `xl_info` interpreted by the resource manager
+    #[must_use]
+    pub fn rmgr_info(&self) -> u8 {
+        self.info & XLR_RMGR_INFO_MASK
+    }
+
+    /// Whether the record ends its segment, `XLOG_SWITCH`
+    #[must_use]
+    pub fn is_switch(&self) -> bool {
+        self.rmid == RM_XLOG_ID && self.rmgr_info() == XLOG_SWITCH
+    }
+}
+
+/// CRC of a whole record: the data after the header, then the header up to
+/// `xl_crc`
+#[must_use]
+pub fn record_crc(record: &[u8]) -> u32 {
+    let crc = crc32c_update(CRC32C_INIT, &record[SIZE_OF_XLOG_RECORD..]);
+    crc32c_finish(crc32c_update(crc, &record[..XL_CRC_OFFSET]))
+}
+
+/// Parse a complete record and verify its CRC
+pub fn verify_record(record: &[u8], order: ByteOrder) -> Result<RecordHeader, InvalidRecord> {
+    let header = RecordHeader::parse(record, order)?;
+    if usize::try_from(header.tot_len).ok() != Some(record.len()) {
+        return Err(InvalidRecord::InvalidLength(header.tot_len));
+    }
+    let crc = record_crc(record);
+    if crc != header.crc {
+        return Err(InvalidRecord::InvalidCrc(crc, header.crc));
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        page::{ByteOrder, SIZE_OF_XLOG_LONG_PHD},
+        record::{rmgr_name, verify_record, InvalidRecord, RecordHeader},
+    };
+
+    #[test]
+    fn test_rmgr_name() {
+        assert_eq!(rmgr_name(10), "Heap");
+        assert_eq!(rmgr_name(21), "LogicalMessage");
+        assert_eq!(rmgr_name(130), "custom130");
+    }
+
+    #[test]
+    fn test_verify_record() {
+        let segment = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap();
+        let start = SIZE_OF_XLOG_LONG_PHD;
+        let header = RecordHeader::parse(&segment[start..], ByteOrder::Little).unwrap();
+        assert_eq!(header.tot_len, 3109);
+        assert_eq!(header.prev, 0x1704af8);
+        assert_eq!(header.rmid, 0);
+        assert!(header.validate_prev(0x1704af8).is_ok());
+
+        let mut record = segment[start..start + 3109].to_vec();
+        assert_eq!(verify_record(&record, ByteOrder::Little), Ok(header));
+        record[100] ^= 0xFF;
+        assert!(matches!(
+            verify_record(&record, ByteOrder::Little),
+            Err(InvalidRecord::InvalidCrc(_, 0x94c4e422))
+        ));
+        assert_eq!(
+            RecordHeader::parse(&[0; 24], ByteOrder::Little),
+            Err(InvalidRecord::InvalidLength(0))
+        );
+
+        let mut swapped = Vec::new();
+        swapped.extend(header.tot_len.to_be_bytes());
+        swapped.extend(header.xid.to_be_bytes());
+        swapped.extend(header.prev.to_be_bytes());
+        swapped.extend([header.info, header.rmid, 0, 0]);
+        swapped.extend(header.crc.to_be_bytes());
+        assert_eq!(RecordHeader::parse(&swapped, ByteOrder::Big), Ok(header));
+    }
+}
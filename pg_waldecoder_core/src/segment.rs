@@ -0,0 +1,125 @@
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{
+    lsn::{filename_to_startptr, segno_to_lsn, InvalidLSN},
+    page::{InvalidPage, LongPageHeader},
+};
+
+pub const XLOG_FNAME_LEN: usize = 24;
+pub const XLOG_PARTIAL_SUFFIX: &str = ".partial";
+pub const WAL_SEG_MIN_SIZE: u32 = 1024 * 1024;
+pub const WAL_SEG_MAX_SIZE: u32 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidSegment {
+    #[error("Invalid WAL file name {0}")]
+    InvalidFileName(String),
+    #[error("{0}")]
+    Name(#[from] InvalidLSN),
+    #[error("{0}")]
+    Page(#[from] InvalidPage),
+    #[error("Segment {0} is on timeline {1}")]
+    UnexpectedTimeline(String, u32),
+}
+
+/// Returns true if the file name is a WAL segment name, optionally with the
+/// `.partial` suffix left by `pg_receivewal`
+#[must_use]
+pub fn is_xlog_file_name(file_name: &str) -> bool {
+    let file_name = file_name
+        .strip_suffix(XLOG_PARTIAL_SUFFIX)
+        .unwrap_or(file_name);
+    // We should have 24 characters with only hexadecimal characters
+    file_name.len() == XLOG_FNAME_LEN && file_name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns true if the path is a `.partial` segment
+#[must_use]
+pub fn is_partial_segment(wal_path: &Path) -> bool {
+    wal_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .is_some_and(|f| f.ends_with(XLOG_PARTIAL_SUFFIX))
+}
+
+/// Returns true if WAL seg size is correct
+#[must_use]
+pub fn is_wal_segsz_valid(wal_seg_size: u32) -> bool {
+    wal_seg_size.is_power_of_two() && (WAL_SEG_MIN_SIZE..=WAL_SEG_MAX_SIZE).contains(&wal_seg_size)
+}
+
+/// Check that the first page of a segment matches its file name, returns
+/// its long header
+pub fn validate_segment(
+    file_name: &str,
+    first_page: &[u8],
+) -> Result<LongPageHeader, InvalidSegment> {
+    if !is_xlog_file_name(file_name) {
+        return Err(InvalidSegment::InvalidFileName(file_name.to_string()));
+    }
+    let header = LongPageHeader::parse(first_page)?;
+    let (tli, segno) = filename_to_startptr(file_name, u64::from(header.seg_size))?;
+    if u64::from(header.std.tli) != tli {
+        return Err(InvalidSegment::UnexpectedTimeline(
+            file_name.to_string(),
+            header.std.tli,
+        ));
+    }
+    let expected_pageaddr = segno_to_lsn(segno, header.seg_size);
+    // Segments of older servers can be read without a server
+    let version = header.std.version().ok_or(InvalidPage::InvalidMagic(
+        header.std.magic,
+        expected_pageaddr,
+    ))?;
+    header.std.validate_version(expected_pageaddr, version)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        page::InvalidPage,
+        segment::{is_wal_segsz_valid, is_xlog_file_name, validate_segment, InvalidSegment},
+    };
+
+    #[test]
+    fn test_is_xlog_file_name() {
+        assert!(is_xlog_file_name("000000010000000000000018"));
+        assert!(is_xlog_file_name("000000010000000000000018.partial"));
+        assert!(!is_xlog_file_name("000000010000000000000018.backup"));
+        assert!(!is_xlog_file_name("00000002.history"));
+    }
+
+    #[test]
+    fn test_is_wal_segsz_valid() {
+        assert!(is_wal_segsz_valid(16 * 1024 * 1024));
+        assert!(!is_wal_segsz_valid(512 * 1024));
+        assert!(!is_wal_segsz_valid(3 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_validate_segment() {
+        let segment = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap();
+        let header = validate_segment("000000010000000000000018", &segment).unwrap();
+        assert_eq!(header.seg_size, 1024 * 1024);
+        assert_eq!(
+            validate_segment("000000010000000000000019", &segment),
+            Err(InvalidSegment::Page(InvalidPage::UnexpectedPageAddr(
+                0x1800000, 0x1900000
+            )))
+        );
+        assert_eq!(
+            validate_segment("000000020000000000000018", &segment),
+            Err(InvalidSegment::UnexpectedTimeline(
+                "000000020000000000000018".to_string(),
+                1
+            ))
+        );
+    }
+}
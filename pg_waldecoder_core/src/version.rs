@@ -0,0 +1,73 @@
+/// Major version of the server that wrote the WAL, identified by the magic
+/// of its pages. Records and block references keep the same layout since 13,
+/// `RelFileNode` and `RelFileLocator` included, only the flags of full page
+/// images changed in 15. Only this framing is versioned: the main data of the
+/// records, like the `xl_heap_*` structs, isn't decoded here and the
+/// extension only decodes the WAL of the version it's built for.
+#[derive(Clone, Copy, Debug, Hash, Ord, PartialOrd, PartialEq, Eq)]
+pub enum WalVersion {
+    V13,
+    V14,
+    V15,
+    V16,
+    V17,
+    V18,
+}
+
+/// `XLOG_PAGE_MAGIC` of each major version
+const PAGE_MAGICS: [(WalVersion, u16); 6] = [
+    (WalVersion::V13, 0xD106),
+    (WalVersion::V14, 0xD10D),
+    (WalVersion::V15, 0xD110),
+    (WalVersion::V16, 0xD113),
+    (WalVersion::V17, 0xD116),
+    (WalVersion::V18, 0xD118),
+];
+
+impl WalVersion {
+    /// Version of the server the extension is built for
+    pub const CURRENT: WalVersion = WalVersion::V18;
+
+    #[must_use]
+    pub fn from_magic(magic: u16) -> Option<WalVersion> {
+        PAGE_MAGICS
+            .iter()
+            .find(|(_, m)| *m == magic)
+            .map(|(version, _)| *version)
+    }
+
+    #[must_use]
+    pub fn magic(self) -> u16 {
+        PAGE_MAGICS
+            .iter()
+            .find(|(version, _)| *version == self)
+            .map(|(_, magic)| *magic)
+            .unwrap()
+    }
+
+    #[must_use]
+    pub fn major(self) -> u32 {
+        match self {
+            WalVersion::V13 => 13,
+            WalVersion::V14 => 14,
+            WalVersion::V15 => 15,
+            WalVersion::V16 => 16,
+            WalVersion::V17 => 17,
+            WalVersion::V18 => 18,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{page::XLOG_PAGE_MAGIC, version::WalVersion};
+
+    #[test]
+    fn test_wal_version() {
+        assert_eq!(WalVersion::from_magic(0xD106), Some(WalVersion::V13));
+        assert_eq!(WalVersion::from_magic(0xD105), None);
+        assert_eq!(WalVersion::CURRENT.magic(), XLOG_PAGE_MAGIC);
+        assert_eq!(WalVersion::V16.major(), 16);
+        assert!(WalVersion::V14 < WalVersion::V15);
+    }
+}
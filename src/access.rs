@@ -0,0 +1,170 @@
+use std::{
+    ffi::CStr,
+    path::{Path, PathBuf},
+};
+
+use pgrx::{extension_sql, pg_sys, prelude::*};
+
+use crate::{guc::RESTRICT_WAL_DIR, s3::is_s3_url};
+
+/// Role allowed to decode the WAL, names starting with `pg_` being reserved
+/// to builtin roles
+const READER_ROLE: &CStr = c"waldecoder_reader";
+
+extension_sql!(
+    r"
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_catalog.pg_roles WHERE rolname = 'waldecoder_reader') THEN
+        CREATE ROLE waldecoder_reader NOLOGIN;
+    END IF;
+END
+$$;
+",
+    name = "reader_role",
+);
+
+fn insufficient_privilege(message: &str, hint: &str) -> ! {
+    ErrorReport::new(
+        PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+        message,
+        function_name!(),
+    )
+    .set_hint(hint)
+    .report(PgLogLevel::ERROR);
+    unreachable!()
+}
+
+/// Returns true if the current user can read the decoded rows: superusers,
+/// members of `pg_read_server_files` and of the reader role
+fn has_decoder_access() -> bool {
+    unsafe {
+        if pg_sys::superuser() {
+            return true;
+        }
+        let user = pg_sys::GetUserId();
+        if pg_sys::has_privs_of_role(user, pg_sys::Oid::from(pg_sys::ROLE_PG_READ_SERVER_FILES)) {
+            return true;
+        }
+        let reader_role = pg_sys::get_role_oid(READER_ROLE.as_ptr(), true);
+        reader_role != pg_sys::InvalidOid && pg_sys::has_privs_of_role(user, reader_role)
+    }
+}
+
+/// Returns true if the path is the directory or inside it, once symlinks and
+/// `..` are resolved
+fn is_under_dir(path: &Path, dir: &Path) -> bool {
+    let (Ok(path), Ok(dir)) = (path.canonicalize(), dir.canonicalize()) else {
+        return false;
+    };
+    path.starts_with(dir)
+}
+
+fn data_directory() -> PathBuf {
+    PathBuf::from(
+        unsafe { CStr::from_ptr(pg_sys::DataDir) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Check that local WAL dirs, possibly a colon-separated list, are under the
+/// data directory unless superuser. S3 archives are cached under
+/// `pg_waldecoder.s3_cache_dir`, their URL can't leave it.
+pub fn check_wal_dir_access(wal_dir: &str) {
+    if !RESTRICT_WAL_DIR.get() || unsafe { pg_sys::superuser() } || is_s3_url(wal_dir) {
+        return;
+    }
+    let data_directory = data_directory();
+    for dir in wal_dir.split(':').filter(|d| !d.is_empty()) {
+        if !is_under_dir(Path::new(dir), &data_directory) {
+            insufficient_privilege(
+                &format!("permission denied to read WAL from \"{dir}\""),
+                "Only superusers can read WAL outside of the data directory.",
+            );
+        }
+    }
+}
+
+/// Raise an error if the current user isn't allowed to decode the WAL in
+/// the provided directory
+pub fn check_decoder_access(wal_dir: Option<&str>) {
+    if !has_decoder_access() {
+        insufficient_privilege(
+            "permission denied to decode WAL",
+            "Decoding the WAL exposes the rows of every table. Only superusers and members of pg_read_server_files or waldecoder_reader can decode it.",
+        );
+    }
+    if let Some(wal_dir) = wal_dir {
+        check_wal_dir_access(wal_dir);
+    }
+}
+
+/// Raise an error if the current user isn't allowed to write files on the
+/// server
+pub fn check_write_access() {
+    let allowed = unsafe {
+        pg_sys::superuser()
+            || pg_sys::has_privs_of_role(
+                pg_sys::GetUserId(),
+                pg_sys::Oid::from(pg_sys::ROLE_PG_WRITE_SERVER_FILES),
+            )
+    };
+    if !allowed {
+        insufficient_privilege(
+            "permission denied to write server files",
+            "Only superusers and members of pg_write_server_files can write files on the server.",
+        );
+    }
+}
+
+/// Raise an error if the current user isn't allowed to run programs on the
+/// server
+pub fn check_execute_access() {
+    let allowed = unsafe {
+        pg_sys::superuser()
+            || pg_sys::has_privs_of_role(
+                pg_sys::GetUserId(),
+                pg_sys::Oid::from(pg_sys::ROLE_PG_EXECUTE_SERVER_PROGRAM),
+            )
+    };
+    if !allowed {
+        insufficient_privilege(
+            "permission denied to execute programs on the server",
+            "Only superusers and members of pg_execute_server_program can run commands on the server.",
+        );
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::path::Path;
+
+    use crate::access::is_under_dir;
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_is_under_dir() {
+        let resources = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/resources"));
+        let wal_dir = resources.join("test/18_single_upgrade");
+        assert!(is_under_dir(&wal_dir, resources));
+        assert!(is_under_dir(resources, resources));
+        assert!(!is_under_dir(&wal_dir.join("../../../src"), resources));
+        assert!(!is_under_dir(&resources.join("missing"), resources));
+    }
+
+    #[pg_test(error = "permission denied to decode WAL")]
+    fn test_decoder_access_denied() {
+        Spi::run("CREATE ROLE test_no_wal_access").unwrap();
+        Spi::run("SET ROLE test_no_wal_access").unwrap();
+        Spi::run("SELECT * FROM pg_waldecoder('0/0')").unwrap();
+    }
+
+    #[pg_test(error = "permission denied to read WAL from \"/tmp\"")]
+    fn test_wal_dir_access_denied() {
+        Spi::run("CREATE ROLE test_wal_reader IN ROLE waldecoder_reader").unwrap();
+        Spi::run("SET ROLE test_wal_reader").unwrap();
+        Spi::run("SELECT * FROM pg_waldecoder('0/0', wal_dir => '/tmp')").unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use pgrx::{pg_sys, PgBox};
+
+use crate::xid::{xact_end, SubxactTree};
+
+/// Prefix and payload of a logical message record, emitted by
+/// `pg_logical_emit_message`
+fn logical_message(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<(&[u8], &[u8])> {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_LOGICALMSG_ID
+        || info != pg_sys::XLOG_LOGICAL_MESSAGE
+        || record.main_data.is_null()
+    {
+        return None;
+    }
+    let main_data = unsafe {
+        std::slice::from_raw_parts(
+            record.main_data.cast::<u8>(),
+            usize::try_from(record.main_data_len).ok()?,
+        )
+    };
+    let header_size = std::mem::offset_of!(pg_sys::xl_logical_message, message);
+    if main_data.len() < header_size {
+        return None;
+    }
+    let xlrec = unsafe {
+        std::ptr::read_unaligned(main_data.as_ptr().cast::<pg_sys::xl_logical_message>())
+    };
+    let body = &main_data[header_size..];
+    let prefix_size = xlrec.prefix_size;
+    let message_size = xlrec.message_size;
+    if body.len() < prefix_size.checked_add(message_size)? {
+        return None;
+    }
+    // The prefix is stored with its terminating NUL
+    let prefix = &body[..prefix_size.checked_sub(1)?];
+    Some((prefix, &body[prefix_size..prefix_size + message_size]))
+}
+
+/// Payloads of the logical messages with the annotation prefix, attached to
+/// the following changes of their transaction
+pub struct Annotations {
+    prefix: String,
+    /// Latest payload by top level xid
+    by_xid: HashMap<u32, String>,
+}
+
+impl Annotations {
+    pub fn new(prefix: &str) -> Annotations {
+        Annotations {
+            prefix: prefix.to_string(),
+            by_xid: HashMap::new(),
+        }
+    }
+
+    /// Keep the payload of an annotation message, forget the annotation of a
+    /// transaction at its end
+    pub fn observe(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>, subxacts: &SubxactTree) {
+        if let Some(xact_end) = xact_end(record) {
+            self.by_xid.remove(&xact_end.xid.into_inner());
+            return;
+        }
+        // Messages outside of a transaction come without xid
+        let xid = record.header.xl_xid;
+        if xid.into_inner() == 0 {
+            return;
+        }
+        let Some((prefix, payload)) = logical_message(record) else {
+            return;
+        };
+        if prefix == self.prefix.as_bytes() {
+            let toplevel = subxacts.toplevel(xid);
+            self.by_xid.insert(
+                toplevel.into_inner(),
+                String::from_utf8_lossy(payload).into_owned(),
+            );
+        }
+    }
+
+    /// Annotation of a transaction, from its latest annotation message
+    pub fn get(&self, toplevel_xid: pg_sys::TransactionId) -> Option<String> {
+        self.by_xid.get(&toplevel_xid.into_inner()).cloned()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::pg_lsn::PgLSN;
+
+    #[pg_test]
+    fn test_pg_waldecoder_annotation() {
+        unsafe {
+            Spi::run("CREATE TABLE test_annotation (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_annotation VALUES (1)");
+            Spi::run("SELECT pg_logical_emit_message(true, 'audit', 'user=alice request=1')");
+            Spi::run("SELECT pg_logical_emit_message(true, 'other', 'ignored')");
+            Spi::run("INSERT INTO test_annotation VALUES (2)");
+            Spi::run("SELECT pg_logical_emit_message(true, 'audit', 'user=bob request=2')");
+            Spi::run("INSERT INTO test_annotation VALUES (3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let annotations = |annotation_prefix: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(coalesce(annotation, '-'), ', ' ORDER BY lsn)
+                 FROM pg_waldecoder('{startptr}', timeline => 1, annotation_prefix => {annotation_prefix})
+                 WHERE relid = 'test_annotation'::regclass"
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            annotations("'audit'").as_deref(),
+            Some("-, user=alice request=1, user=bob request=2")
+        );
+        assert_eq!(annotations("NULL").as_deref(), Some("-, -, -"));
+    }
+}
@@ -0,0 +1,164 @@
+use pgrx::{pg_sys, prelude::*};
+
+use crate::{
+    decoder::{DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+    sink::try_in_subtransaction,
+};
+
+/// Which of the generated queries are applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// Replay the changes in LSN order
+    Redo,
+    /// Undo the changes in reverse LSN order
+    Revert,
+}
+
+impl TryFrom<&str> for Direction {
+    type Error = String;
+
+    fn try_from(direction: &str) -> Result<Self, Self::Error> {
+        match direction {
+            "redo" => Ok(Direction::Redo),
+            "revert" => Ok(Direction::Revert),
+            _ => Err(format!(
+                "Unknown direction '{direction}', expected redo or revert"
+            )),
+        }
+    }
+}
+
+/// Status of a statement and the error it raised
+fn apply_statement(statement: Option<&str>, dry_run: bool) -> (&'static str, Option<String>) {
+    match statement {
+        // Changes without a query and commented out conflicting reverts
+        None => ("skipped", None),
+        Some(statement) if statement.starts_with("--") => ("skipped", None),
+        Some(_) if dry_run => ("planned", None),
+        Some(statement) => match try_in_subtransaction(|| {
+            Spi::run(statement).unwrap();
+        }) {
+            Ok(()) => ("applied", None),
+            Err(e) => ("failed", Some(e)),
+        },
+    }
+}
+
+/// Execute the redo or revert queries of the changes decoded between
+/// `start_lsn` and `end_lsn` in the current transaction, each statement in
+/// its own subtransaction so a failing one doesn't abort the others. Reverts
+/// are applied in reverse LSN order. With `dry_run`, the statements are only
+/// listed. `check_revert_conflicts` skips the reverts conflicting with the
+/// live rows, for when only some of the later changes are reverted. Changes
+/// rolled back, with their transaction or to a savepoint, are left out.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_apply(
+    start_lsn: &str,
+    end_lsn: &str,
+    direction: default!(&str, "'redo'"),
+    dry_run: default!(bool, true),
+    check_revert_conflicts: default!(bool, false),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(xid, pg_sys::TransactionId),
+        name!(statement, Option<String>),
+        name!(status, String),
+        name!(error, Option<String>),
+    ),
+> {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let direction = match Direction::try_from(direction) {
+        Ok(direction) => direction,
+        Err(e) => error!("Error: {e}"),
+    };
+    let options = DecoderOptions {
+        end_lsn: Some(end_lsn),
+        timeline,
+        wal_dir,
+        check_revert_conflicts: check_revert_conflicts && direction == Direction::Revert,
+        ..Default::default()
+    };
+    // Every change is decoded before applying anything, the statements
+    // mustn't change what's left to decode
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    let mut changes: Vec<_> = wal_decoder.by_ref().collect();
+    changes.retain(|change| !wal_decoder.is_aborted(change.xid));
+    if direction == Direction::Revert {
+        changes.reverse();
+    }
+    let rows: Vec<_> = changes
+        .into_iter()
+        .map(|change| {
+            let statement = match direction {
+                Direction::Redo => change.redo_query,
+                Direction::Revert => change.revert_query,
+            };
+            let (status, error) = apply_statement(statement.as_deref(), dry_run);
+            (change.lsn, change.xid, statement, status.to_string(), error)
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        apply::{apply_statement, Direction},
+        pg_lsn::PgLSN,
+    };
+
+    #[test]
+    fn test_direction() {
+        assert_eq!(Direction::try_from("revert"), Ok(Direction::Revert));
+        assert!(Direction::try_from("undo").is_err());
+        assert_eq!(apply_statement(None, false), ("skipped", None));
+        assert_eq!(
+            apply_statement(Some("-- Conflict, the row was changed since"), false),
+            ("skipped", None)
+        );
+        assert_eq!(
+            apply_statement(Some("DELETE FROM t;"), true),
+            ("planned", None)
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_apply() {
+        unsafe {
+            Spi::run("CREATE TABLE test_apply (id int primary key, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_apply VALUES (1, 'a'), (2, 'b')");
+            Spi::run("UPDATE test_apply SET data = 'c' WHERE id = 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let planned = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_apply('{startptr}', '{endptr}', 'revert', timeline => 1)
+             WHERE status = 'planned'"
+        ));
+        assert_eq!(planned, Ok(Some(3)));
+        let applied = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_apply('{startptr}', '{endptr}', 'revert', false, timeline => 1)
+             WHERE status = 'applied'"
+        ));
+        assert_eq!(applied, Ok(Some(3)));
+        let remaining = Spi::get_one::<i64>("SELECT count(*) FROM test_apply");
+        assert_eq!(remaining, Ok(Some(0)));
+    }
+}
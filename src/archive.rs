@@ -0,0 +1,161 @@
+use pgrx::{datum::DatumWithOid, prelude::*, JsonB};
+
+use crate::{
+    access::{check_decoder_access, check_wal_dir_access},
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    pg_lsn::PgLSN,
+    s3::{is_s3_url, parse_s3_url},
+};
+
+extension_sql!(
+    r"
+CREATE TABLE pg_waldecoder_archive (
+    id serial,
+    name text PRIMARY KEY,
+    location text NOT NULL,
+    options jsonb NOT NULL DEFAULT '{}'
+);
+SELECT pg_catalog.pg_extension_config_dump('pg_waldecoder_archive', '');
+",
+    name = "archive_catalog",
+);
+
+/// Split archive locations in the order they are searched into a list of
+/// local WAL dirs and the S3 archives
+fn split_locations(locations: &[String]) -> (Option<String>, Vec<&str>) {
+    let (s3, local): (Vec<&str>, Vec<&str>) = locations
+        .iter()
+        .map(String::as_str)
+        .partition(|location| is_s3_url(location));
+    let wal_dirs = (!local.is_empty()).then(|| local.join(":"));
+    (wal_dirs, s3)
+}
+
+/// Register a WAL location, a local directory or an S3 URL, in the archive
+/// catalog scanned by `pg_waldecoder_scan_archives`. Archives are searched
+/// in registration order, the local directories before the S3 archives.
+#[pg_extern]
+fn pg_waldecoder_register_archive(name: &str, location: &str, options: default!(JsonB, "'{}'")) {
+    check_decoder_access(None);
+    if is_s3_url(location) {
+        if let Err(e) = parse_s3_url(location) {
+            error!("Error: {}", e.to_string());
+        }
+    } else if location.contains(':') {
+        error!("Archive location \"{location}\" can't contain ':'");
+    } else {
+        check_wal_dir_access(location);
+    }
+    let args: [DatumWithOid; 3] = [name.into(), location.into(), options.into()];
+    Spi::run_with_args(
+        "INSERT INTO pg_waldecoder_archive (name, location, options) VALUES ($1, $2, $3)
+         ON CONFLICT (name) DO UPDATE SET location = EXCLUDED.location, options = EXCLUDED.options",
+        &args,
+    )
+    .unwrap();
+}
+
+/// Remove an archive from the catalog, returns false if it wasn't registered
+#[pg_extern]
+fn pg_waldecoder_unregister_archive(name: &str) -> bool {
+    let args: [DatumWithOid; 1] = [name.into()];
+    Spi::get_one_with_args::<bool>(
+        "WITH deleted AS (DELETE FROM pg_waldecoder_archive WHERE name = $1 RETURNING 1)
+         SELECT count(*) > 0 FROM deleted",
+        &args,
+    )
+    .unwrap()
+    .unwrap_or(false)
+}
+
+/// Locations of the registered archives in search order
+fn archive_locations() -> Vec<String> {
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT location FROM pg_waldecoder_archive ORDER BY id",
+                None,
+                &[],
+            )?
+            .filter_map(|row| row.get::<String>(1).transpose())
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .unwrap()
+}
+
+/// Decode the WAL from `start_lsn` across all the registered archives,
+/// segments are taken from the first archive having them
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_scan_archives(
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let locations = archive_locations();
+    if locations.is_empty() {
+        error!("No archive registered, use pg_waldecoder_register_archive");
+    }
+    verbose!(Verbosity::Normal, "Scanning archives: {locations:?}");
+    let (wal_dirs, s3_archives) = split_locations(&locations);
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir: wal_dirs.as_deref(),
+        s3_archives: &s3_archives,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{archive::split_locations, pg_lsn::PgLSN};
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_split_locations() {
+        let locations = [
+            "/archive/hot".to_string(),
+            "s3://bucket/wal".to_string(),
+            "/archive/cold".to_string(),
+        ];
+        let (wal_dirs, s3) = split_locations(&locations);
+        assert_eq!(wal_dirs.as_deref(), Some("/archive/hot:/archive/cold"));
+        assert_eq!(s3, vec!["s3://bucket/wal"]);
+        assert_eq!(split_locations(&[]), (None, vec![]));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_scan_archives() {
+        unsafe {
+            Spi::run("CREATE TABLE test_archive (id int, data text);");
+            Spi::run(
+                "SELECT pg_waldecoder_register_archive('local', current_setting('data_directory') || '/pg_wal')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_archive (id) SELECT generate_series(1, 2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_scan_archives('{startptr}', '{endptr}', 1) WHERE op = 'INSERT'"
+        ))
+        .unwrap();
+        assert_eq!(count, Some(2));
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT pg_waldecoder_unregister_archive('local')"),
+            Ok(Some(true))
+        );
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::HashSet,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use pgrx::{
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags},
+    datum::DatumWithOid,
+    extension_sql, pg_guard, pg_shmem_init, pg_sys,
+    prelude::*,
+    shmem::PGRXSharedMemory,
+    PgLwLock, TimestampWithTimeZone,
+};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, OnError, WalDecoder},
+    guc::{
+        verbose, Verbosity, AUDIT_DATABASE, AUDIT_NAPTIME, AUDIT_NOTIFY_CHANNEL, AUDIT_RELATIONS,
+        AUDIT_START_LSN, AUDIT_TABLE,
+    },
+    notify::NotifySink,
+    output::OutputSink,
+    pg_lsn::PgLSN,
+    sink::{quote_table_name, try_in_subtransaction},
+};
+
+/// State of the audit worker as seen by other backends
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AuditStatus {
+    /// 0 when the worker isn't running
+    pid: i32,
+    /// LSN from which the next run decodes
+    current_lsn: u64,
+    /// Flush position decoded up to by the latest run
+    flush_lsn: u64,
+    rows_written: u64,
+    /// Runs that failed and were retried
+    errors: u64,
+    /// End of the latest run, 0 before the first one
+    last_run: pg_sys::TimestampTz,
+}
+
+unsafe impl PGRXSharedMemory for AuditStatus {}
+
+static AUDIT_STATUS: PgLwLock<AuditStatus> =
+    unsafe { PgLwLock::new(c"pg_waldecoder_audit_status") };
+
+/// The shared memory is only available when preloaded
+static SHMEM_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+extension_sql!(
+    r"
+CREATE VIEW pg_waldecoder_status AS
+    SELECT * FROM pg_waldecoder_audit_status();
+",
+    name = "audit_status_view",
+    requires = [pg_waldecoder_audit_status],
+);
+
+/// Request the shared memory of the worker's status and register the audit
+/// worker when the library is preloaded and an audit table and its database
+/// are configured
+pub fn register() {
+    if !unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(AUDIT_STATUS);
+    SHMEM_INITIALIZED.store(true, Ordering::Relaxed);
+    if AUDIT_TABLE.get().is_none() {
+        return;
+    }
+    match guc_string(&AUDIT_DATABASE) {
+        None => {
+            warning!("pg_waldecoder.audit_database isn't set, the audit worker isn't started");
+            return;
+        }
+        Some(database) if database.len() >= pg_sys::NAMEDATALEN as usize => {
+            warning!("pg_waldecoder.audit_database {database} is too long, the audit worker isn't started");
+            return;
+        }
+        Some(_) => {}
+    }
+    BackgroundWorkerBuilder::new("pg_waldecoder audit")
+        .set_function("pg_waldecoder_audit_main")
+        .set_library("pg_waldecoder")
+        .enable_spi_access()
+        .set_restart_time(Some(Duration::from_secs(10)))
+        .load();
+}
+
+fn guc_string(setting: &pgrx::GucSetting<Option<std::ffi::CString>>) -> Option<String> {
+    setting
+        .get()
+        .map(|s| s.to_string_lossy().into_owned())
+        .filter(|s| !s.is_empty())
+}
+
+/// Create the audit table and the progress bookkeeping if needed
+fn create_tables(audit_table: &str) {
+    Spi::run(&format!(
+        "CREATE TABLE IF NOT EXISTS {audit_table} (
+            lsn pg_lsn NOT NULL,
+            dboid oid NOT NULL,
+            relid oid,
+            spcoid oid NOT NULL,
+            relfilenumber oid NOT NULL,
+            xid xid8,
+            op text NOT NULL,
+            redo_query text,
+            revert_query text,
+            row_before text,
+            row_after text,
+            raw_xid xid NOT NULL,
+            commit_time timestamptz,
+            schema_name text,
+            relation_name text,
+            origin_id oid,
+            origin_name text,
+            raw_record bytea,
+            toplevel_xid xid,
+            changes jsonb,
+            error text
+        )"
+    ))
+    .unwrap();
+    // Added after the first versions of the table
+    Spi::run(&format!(
+        "ALTER TABLE {audit_table} ADD COLUMN IF NOT EXISTS error text"
+    ))
+    .unwrap();
+    Spi::run(
+        "CREATE TABLE IF NOT EXISTS pg_waldecoder_audit_progress (
+            audit_table text PRIMARY KEY,
+            next_lsn pg_lsn NOT NULL
+        )",
+    )
+    .unwrap();
+}
+
+/// LSN from which decoding resumes, the configured start LSN or the current
+/// flush position on the first run
+fn next_lsn(audit_table: &str) -> PgLSN {
+    let args: [DatumWithOid; 1] = [audit_table.into()];
+    let next_lsn = Spi::get_one_with_args::<String>(
+        "SELECT next_lsn::text FROM pg_waldecoder_audit_progress WHERE audit_table = $1",
+        &args,
+    )
+    .unwrap();
+    let configured = next_lsn.or_else(|| guc_string(&AUDIT_START_LSN));
+    match configured.map(|lsn| PgLSN::try_from(lsn.as_str())) {
+        Some(Ok(lsn)) => lsn,
+        Some(Err(e)) => error!("Error: {}", e.to_string()),
+        None => PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) }),
+    }
+}
+
+fn save_next_lsn(audit_table: &str, next_lsn: PgLSN) {
+    let args: [DatumWithOid; 2] = [audit_table.into(), next_lsn.to_string().into()];
+    Spi::run_with_args(
+        "INSERT INTO pg_waldecoder_audit_progress (audit_table, next_lsn) VALUES ($1, $2::pg_lsn)
+         ON CONFLICT (audit_table) DO UPDATE SET next_lsn = EXCLUDED.next_lsn",
+        &args,
+    )
+    .unwrap();
+}
+
+/// Relations the worker writes to, with their toast tables, whose changes
+/// aren't audited
+fn own_relids(audit_table: &str) -> HashSet<pg_sys::Oid> {
+    let args: [DatumWithOid; 1] = [audit_table.into()];
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT unnest(ARRAY[c.oid, c.reltoastrelid]) FROM pg_class c
+                 WHERE c.oid IN ($1::regclass, 'pg_waldecoder_audit_progress'::regclass)",
+                None,
+                &args,
+            )?
+            .filter_map(|row| row.get::<pg_sys::Oid>(1).transpose())
+            .collect::<Result<HashSet<_>, pgrx::spi::Error>>()
+    })
+    .unwrap()
+}
+
+/// Relids of the audited relations, None to audit all of them
+fn audited_relids() -> Option<HashSet<pg_sys::Oid>> {
+    let relations = guc_string(&AUDIT_RELATIONS)?;
+    let mut relids = HashSet::new();
+    for relation in relations
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+    {
+        let args: [DatumWithOid; 1] = [relation.into()];
+        match Spi::get_one_with_args::<pg_sys::Oid>("SELECT to_regclass($1)::oid", &args) {
+            Ok(Some(relid)) => {
+                relids.insert(relid);
+            }
+            _ => warning!("Audited relation {relation} doesn't exist"),
+        }
+    }
+    Some(relids)
+}
+
+fn insert_change(audit_table: &str, change: DecodedResult) {
+    let args: [DatumWithOid; 21] = [
+        change.lsn.into(),
+        change.dboid.into(),
+        change.relid.into(),
+        change.spcoid.into(),
+        change.relfilenumber.into(),
+        change.full_xid.into(),
+        change.op.into(),
+        change.redo_query.into(),
+        change.revert_query.into(),
+        change.row_before.into(),
+        change.row_after.into(),
+        change.xid.into(),
+        change.commit_time.into(),
+        change.schema_name.into(),
+        change.relation_name.into(),
+        change.origin_id.into(),
+        change.origin_name.into(),
+        change.raw_record.into(),
+        change.toplevel_xid.into(),
+        change.changes.into(),
+        change.error.into(),
+    ];
+    Spi::run_with_args(
+        &format!(
+            "INSERT INTO {audit_table} (lsn, dboid, relid, spcoid, relfilenumber, xid, op,
+                redo_query, revert_query, row_before, row_after, raw_xid,
+                commit_time, schema_name, relation_name, origin_id, origin_name, raw_record,
+                toplevel_xid, changes, error)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                $19, $20, $21)"
+        ),
+        &args,
+    )
+    .unwrap();
+}
+
+/// Account for a run that decoded up to `flushptr`, the next one starts at
+/// `next_lsn`
+fn publish_status(next_lsn: PgLSN, flushptr: PgLSN, rows_written: u64) {
+    if !SHMEM_INITIALIZED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut status = AUDIT_STATUS.exclusive();
+    status.current_lsn = next_lsn.into();
+    status.flush_lsn = flushptr.into();
+    status.rows_written += rows_written;
+}
+
+/// Decode the WAL written since the previous run into the audit table,
+/// returning the number of changes audited. The changes of the worker's own
+/// tables are left out. A record that can't be decoded, e.g. after a schema
+/// change of its table, is audited with its error instead of failing the run.
+fn audit_new_wal(audit_table: &str) -> u64 {
+    // `pg_waldecoder.audit_table` is an optionally qualified name
+    let quoted_table = quote_table_name(audit_table);
+    create_tables(&quoted_table);
+    let startptr = next_lsn(audit_table);
+    let flushptr = PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) });
+    if startptr >= flushptr {
+        publish_status(startptr, flushptr, 0);
+        return 0;
+    }
+
+    let relids = audited_relids();
+    let own_relids = own_relids(&quoted_table);
+    let mut notify_sink =
+        guc_string(&AUDIT_NOTIFY_CHANNEL).map(|channel| NotifySink::new(&channel));
+    let options = DecoderOptions {
+        live: true,
+        on_error: Some(OnError::Emit),
+        ..Default::default()
+    };
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    let mut count = 0;
+    for change in wal_decoder.by_ref() {
+        let audited = match (&relids, change.relid) {
+            (_, Some(relid)) if own_relids.contains(&relid) => false,
+            (None, _) => true,
+            (Some(relids), Some(relid)) => relids.contains(&relid),
+            (Some(_), None) => false,
+        };
+        if audited {
+            if let Some(notify_sink) = &mut notify_sink {
+                notify_sink.write(change.clone());
+            }
+            insert_change(&quoted_table, change);
+            count += 1;
+        }
+    }
+    let next_lsn = wal_decoder.end_lsn();
+    save_next_lsn(audit_table, next_lsn);
+    publish_status(next_lsn, flushptr, count);
+    verbose!(
+        Verbosity::Debug,
+        "Audited {count} changes from {startptr} to {next_lsn}"
+    );
+    count
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn pg_waldecoder_audit_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let Some(database) = guc_string(&AUDIT_DATABASE) else {
+        error!("pg_waldecoder.audit_database must be set to run the audit worker");
+    };
+    BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+
+    AUDIT_STATUS.exclusive().pid = unsafe { pg_sys::MyProcPid };
+    unsafe { pg_sys::before_shmem_exit(Some(clear_audit_pid), pg_sys::Datum::from(0)) };
+
+    let naptime = || Duration::from_secs(AUDIT_NAPTIME.get().cast_unsigned().into());
+    while BackgroundWorker::wait_latch(Some(naptime())) {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+        }
+        let Some(audit_table) = guc_string(&AUDIT_TABLE) else {
+            continue;
+        };
+        // A failed run is retried from the saved LSN after the naptime
+        BackgroundWorker::transaction(|| {
+            if let Err(e) = try_in_subtransaction(|| {
+                audit_new_wal(&audit_table);
+            }) {
+                warning!("Couldn't audit the new WAL: {e}");
+                AUDIT_STATUS.exclusive().errors += 1;
+            }
+        });
+        AUDIT_STATUS.exclusive().last_run = unsafe { pg_sys::GetCurrentTimestamp() };
+    }
+}
+
+/// Clear the worker's pid when it exits, after an error too
+#[pg_guard]
+unsafe extern "C-unwind" fn clear_audit_pid(_code: c_int, _arg: pg_sys::Datum) {
+    AUDIT_STATUS.exclusive().pid = 0;
+}
+
+/// The worker's pid, None if it's not a live backend anymore, e.g. after a
+/// crash
+fn live_pid(pid: i32) -> Option<i32> {
+    (pid != 0 && !unsafe { pg_sys::BackendPidGetProc(pid) }.is_null()).then_some(pid)
+}
+
+/// State of the audit worker: where it's decoding from, how far behind the
+/// flushed WAL it is, and how many changes and failed runs it had since the
+/// server started. `pid` is NULL when the worker isn't running.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_audit_status() -> TableIterator<
+    'static,
+    (
+        name!(pid, Option<i32>),
+        name!(current_lsn, Option<PgLSN>),
+        name!(flush_lsn, Option<PgLSN>),
+        name!(lag_bytes, Option<i64>),
+        name!(rows_written, i64),
+        name!(errors, i64),
+        name!(last_run, Option<TimestampWithTimeZone>),
+    ),
+> {
+    if !SHMEM_INITIALIZED.load(Ordering::Relaxed) {
+        error!(
+            "pg_waldecoder must be in shared_preload_libraries to report the audit worker's status"
+        );
+    }
+    let status = *AUDIT_STATUS.share();
+    let flushed = unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) };
+    let current_lsn = (status.current_lsn != 0).then_some(status.current_lsn);
+    TableIterator::once((
+        live_pid(status.pid),
+        current_lsn.map(PgLSN::from),
+        (status.flush_lsn != 0).then(|| PgLSN::from(status.flush_lsn)),
+        current_lsn.map(|lsn| i64::try_from(flushed.saturating_sub(lsn)).unwrap_or(i64::MAX)),
+        i64::try_from(status.rows_written).unwrap_or(i64::MAX),
+        i64::try_from(status.errors).unwrap_or(i64::MAX),
+        (status.last_run != 0)
+            .then(|| TimestampWithTimeZone::try_from(status.last_run).ok())
+            .flatten(),
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        audit_worker::{audit_new_wal, create_tables, save_next_lsn, AUDIT_STATUS},
+        pg_lsn::PgLSN,
+        sink::quote_table_name,
+    };
+
+    #[pg_test]
+    fn test_audit_new_wal() {
+        let audit_table = "public.\"test audit\"";
+        assert_eq!(quote_table_name(audit_table), audit_table);
+        unsafe {
+            Spi::run("CREATE TABLE test_audited (id int);").unwrap();
+            create_tables(audit_table);
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        save_next_lsn(audit_table, startptr);
+        unsafe {
+            Spi::run("INSERT INTO test_audited VALUES (1), (2)").unwrap();
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        audit_new_wal(audit_table);
+        let audited = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM {audit_table} WHERE relid = 'test_audited'::regclass AND op = 'INSERT'"
+        ));
+        assert_eq!(audited, Ok(Some(2)));
+
+        // The next run doesn't audit the rows written by the previous one
+        unsafe { pg_sys::XLogFlush(pg_sys::XactLastRecEnd) };
+        audit_new_wal(audit_table);
+        let own = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM {audit_table}
+             WHERE relid IN ('{audit_table}'::regclass, 'pg_waldecoder_audit_progress'::regclass)"
+        ));
+        assert_eq!(own, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_audit_status() {
+        let audit_table = "test_audit_status";
+        unsafe {
+            Spi::run("CREATE TABLE test_status_audited (id int);").unwrap();
+            create_tables(audit_table);
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        save_next_lsn(audit_table, startptr);
+        unsafe {
+            Spi::run("INSERT INTO test_status_audited VALUES (1)").unwrap();
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let rows_before = Spi::get_one::<i64>("SELECT rows_written FROM pg_waldecoder_status")
+            .unwrap()
+            .unwrap();
+        let audited = audit_new_wal(audit_table);
+
+        let Ok((Some(current_lsn), Some(rows_written))) = Spi::get_two::<bool, i64>(&format!(
+            "SELECT current_lsn > '{startptr}', rows_written FROM pg_waldecoder_status"
+        )) else {
+            panic!("Couldn't get the audit status")
+        };
+        assert!(current_lsn);
+        // Other tests may audit concurrently
+        assert!(rows_written >= rows_before + i64::try_from(audited).unwrap());
+
+        // The pid of a live backend is reported, a stale one isn't
+        let pid = || Spi::get_one::<i32>("SELECT pid FROM pg_waldecoder_status");
+        AUDIT_STATUS.exclusive().pid = unsafe { pg_sys::MyProcPid };
+        assert_eq!(pid(), Ok(Some(unsafe { pg_sys::MyProcPid })));
+        AUDIT_STATUS.exclusive().pid = i32::MAX;
+        assert_eq!(pid(), Ok(None));
+        AUDIT_STATUS.exclusive().pid = 0;
+    }
+}
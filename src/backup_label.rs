@@ -0,0 +1,270 @@
+use std::{fs, path::Path};
+
+use pg_waldecoder_core::lsn::xlog_file_name;
+use pgrx::{pg_sys::TimeLineID, prelude::*};
+use thiserror::Error;
+
+use crate::{
+    access::check_decoder_access,
+    pg_lsn::PgLSN,
+    segments::segments_dir,
+    wal::{find_segment_file, WalSource},
+};
+
+const BACKUP_LABEL_FILE: &str = "backup_label";
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidBackupLabel {
+    #[error("Could not read backup label {0}: {1}")]
+    ReadError(String, String),
+    #[error("Missing {0} in backup label")]
+    MissingField(String),
+    #[error("Invalid {0} in backup label: '{1}'")]
+    InvalidField(String, String),
+}
+
+/// Content of a backup_label file needed to replay a base backup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupLabel {
+    /// Redo pointer of the backup's checkpoint, where replay starts
+    pub start_lsn: PgLSN,
+    pub checkpoint_lsn: PgLSN,
+    /// End of the backup, only in the backup history file written when the
+    /// backup stops
+    pub stop_lsn: Option<PgLSN>,
+    pub start_tli: TimeLineID,
+    pub label: Option<String>,
+}
+
+/// Returns the value of a "KEY: value" line
+fn field<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .find_map(|l| l.strip_prefix(key)?.strip_prefix(':'))
+        .map(str::trim)
+}
+
+fn lsn_field(content: &str, key: &str) -> Result<PgLSN, InvalidBackupLabel> {
+    let Some(value) = field(content, key) else {
+        return Err(InvalidBackupLabel::MissingField(key.to_string()));
+    };
+    // START WAL LOCATION is followed by the segment name
+    let lsn = value.split_whitespace().next().unwrap_or_default();
+    if !lsn.contains('/') {
+        return Err(InvalidBackupLabel::InvalidField(
+            key.to_string(),
+            value.to_string(),
+        ));
+    }
+    PgLSN::try_from(lsn)
+        .map_err(|_| InvalidBackupLabel::InvalidField(key.to_string(), value.to_string()))
+}
+
+/// Parse the content of a backup_label file
+pub fn parse_backup_label(content: &str) -> Result<BackupLabel, InvalidBackupLabel> {
+    let start_lsn = lsn_field(content, "START WAL LOCATION")?;
+    let checkpoint_lsn = lsn_field(content, "CHECKPOINT LOCATION")?;
+    // START TIMELINE was added in 11, older labels only have the segment name
+    let start_tli = match field(content, "START TIMELINE") {
+        Some(tli) => match tli.parse::<TimeLineID>() {
+            Ok(tli) => tli,
+            Err(_) => {
+                return Err(InvalidBackupLabel::InvalidField(
+                    "START TIMELINE".to_string(),
+                    tli.to_string(),
+                ))
+            }
+        },
+        None => 1,
+    };
+    let stop_lsn = match field(content, "STOP WAL LOCATION") {
+        Some(_) => Some(lsn_field(content, "STOP WAL LOCATION")?),
+        None => None,
+    };
+    Ok(BackupLabel {
+        start_lsn,
+        checkpoint_lsn,
+        stop_lsn,
+        start_tli,
+        label: field(content, "LABEL").map(str::to_string),
+    })
+}
+
+/// Read the backup_label of a base backup directory
+pub fn read_backup_label(backup_dir: &Path) -> Result<BackupLabel, InvalidBackupLabel> {
+    let path = backup_dir.join(BACKUP_LABEL_FILE);
+    match fs::read_to_string(&path) {
+        Ok(content) => parse_backup_label(&content),
+        Err(e) => Err(InvalidBackupLabel::ReadError(
+            path.display().to_string(),
+            e.to_string(),
+        )),
+    }
+}
+
+/// Backup label given as its content, the path of the file or of the backup
+/// directory holding it
+fn load_backup_label(backup_label: &str) -> Result<BackupLabel, InvalidBackupLabel> {
+    if field(backup_label, "START WAL LOCATION").is_some() {
+        return parse_backup_label(backup_label);
+    }
+    check_decoder_access(Some(backup_label));
+    let path = Path::new(backup_label);
+    if path.is_dir() {
+        return read_backup_label(path);
+    }
+    match fs::read_to_string(path) {
+        Ok(content) => parse_backup_label(&content),
+        Err(e) => Err(InvalidBackupLabel::ReadError(
+            backup_label.to_string(),
+            e.to_string(),
+        )),
+    }
+}
+
+/// WAL needed to restore a base backup from its backup_label, given as its
+/// content or as the path of the file or of the backup directory, and the
+/// segments of it missing from `wal_dir`, or the server's WAL without it.
+/// Replay starts at `start_lsn` and must reach `stop_lsn`, the end of the
+/// backup, for the backup to be consistent. The stop is only known from a
+/// backup history file, a backup_label only requires the segments up to its
+/// checkpoint and is never `complete`.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_backup_wal(
+    backup_label: &str,
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(start_lsn, PgLSN),
+        name!(checkpoint_lsn, PgLSN),
+        name!(stop_lsn, Option<PgLSN>),
+        name!(timeline, i64),
+        name!(first_segment, String),
+        name!(last_segment, String),
+        name!(missing_segments, Vec<String>),
+        name!(complete, bool),
+    ),
+> {
+    let backup_label = match load_backup_label(backup_label) {
+        Ok(backup_label) => backup_label,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let (dir, segsz) = segments_dir(wal_dir);
+    let segno = |lsn: u64| lsn / u64::from(segsz);
+    let first = segno(backup_label.start_lsn.into());
+    // The stop is the end of the last record, its segment is the one before
+    // it at a boundary
+    let last = backup_label
+        .stop_lsn
+        .map_or(segno(backup_label.checkpoint_lsn.into()), |stop_lsn| {
+            segno(u64::from(stop_lsn).saturating_sub(1))
+        })
+        .max(first);
+    let file_name = |segno| xlog_file_name(backup_label.start_tli, segno, segsz);
+    let source = WalSource::from_dirs(vec![dir]);
+    let missing_segments: Vec<_> = (first..=last)
+        .map(file_name)
+        .filter(|fname| find_segment_file(&source, fname).is_none())
+        .collect();
+    let complete = backup_label.stop_lsn.is_some() && missing_segments.is_empty();
+    TableIterator::once((
+        backup_label.start_lsn,
+        backup_label.checkpoint_lsn,
+        backup_label.stop_lsn,
+        i64::from(backup_label.start_tli),
+        file_name(first),
+        file_name(last),
+        missing_segments,
+        complete,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        backup_label::{parse_backup_label, BackupLabel, InvalidBackupLabel},
+        pg_lsn::PgLSN,
+    };
+
+    const TEST_WAL_DIR: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/test/18_single_upgrade"
+    );
+
+    #[test]
+    fn test_parse_backup_label() {
+        let content = "START WAL LOCATION: 0/2000028 (file 000000010000000000000002)
+CHECKPOINT LOCATION: 0/2000060
+BACKUP METHOD: streamed
+BACKUP FROM: primary
+START TIME: 2025-01-01 00:00:00 UTC
+LABEL: pg_basebackup base backup
+START TIMELINE: 3
+";
+        assert_eq!(
+            parse_backup_label(content).unwrap(),
+            BackupLabel {
+                start_lsn: PgLSN::from(0x2000028u64),
+                checkpoint_lsn: PgLSN::from(0x2000060u64),
+                stop_lsn: None,
+                start_tli: 3,
+                label: Some("pg_basebackup base backup".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_backup_label() {
+        assert_eq!(
+            parse_backup_label("CHECKPOINT LOCATION: 0/2000060\n").unwrap_err(),
+            InvalidBackupLabel::MissingField("START WAL LOCATION".to_string())
+        );
+        assert!(parse_backup_label(
+            "START WAL LOCATION: garbage\nCHECKPOINT LOCATION: 0/2000060\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_backup_history() {
+        let content = "START WAL LOCATION: 0/2000028 (file 000000010000000000000002)
+STOP WAL LOCATION: 0/2000138 (file 000000010000000000000002)
+CHECKPOINT LOCATION: 0/2000060
+";
+        let backup_label = parse_backup_label(content).unwrap();
+        assert_eq!(backup_label.stop_lsn, Some(PgLSN::from(0x2000138u64)));
+        assert_eq!(backup_label.start_tli, 1);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_backup_wal() {
+        let query = |stop: &str| {
+            let backup_label = format!(
+                "START WAL LOCATION: 0/1800028 (file 000000010000000000000018)
+STOP WAL LOCATION: {stop}
+CHECKPOINT LOCATION: 0/1800C50
+"
+            );
+            Spi::get_two::<Vec<String>, bool>(&format!(
+                "SELECT missing_segments, complete
+                 FROM pg_waldecoder_backup_wal('{backup_label}', '{TEST_WAL_DIR}')"
+            ))
+        };
+        assert_eq!(query("0/1800D00"), Ok((Some(vec![]), Some(true))));
+        assert_eq!(
+            query("0/1A00010"),
+            Ok((
+                Some(vec![
+                    "000000010000000000000019".to_string(),
+                    "00000001000000000000001A".to_string()
+                ]),
+                Some(false)
+            ))
+        );
+    }
+}
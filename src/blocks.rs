@@ -0,0 +1,327 @@
+use std::collections::{BTreeSet, HashMap};
+
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*};
+
+use crate::{
+    decoder::WalDecoder,
+    pg_lsn::PgLSN,
+    relation::{classify_database, RecordDatabase, RelidCache},
+    walinspect::{lsn_bounds, wal_decoder},
+    xlog_reader::get_block_tag_extended,
+};
+
+/// Block of a relation file, as tablespace, database, relfilenumber, fork
+/// and block number
+pub type BlockKey = (u32, u32, u32, i32, pg_sys::BlockNumber);
+
+/// Fork of a relation file, as tablespace, database, relfilenumber and fork
+type ForkKey = (u32, u32, u32, i32);
+
+/// Blocks referenced by the records read until the end of the decoder's
+/// range, in relation file and block order
+pub fn touched_blocks(wal_decoder: &mut WalDecoder) -> BTreeSet<BlockKey> {
+    let mut blocks = BTreeSet::new();
+    while let Some(record) = wal_decoder.read_record() {
+        let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+            continue;
+        };
+        for block_id in 0..=max_block_id {
+            if let Some((rlocator, forknum, blknum)) =
+                get_block_tag_extended(wal_decoder.xlog_reader(), block_id)
+            {
+                blocks.insert((
+                    rlocator.spcOid.to_u32(),
+                    rlocator.dbOid.to_u32(),
+                    rlocator.relNumber.to_u32(),
+                    forknum,
+                    blknum,
+                ));
+            }
+        }
+    }
+    blocks
+}
+
+/// Distinct blocks modified between `start_lsn` and `end_lsn`, from the block
+/// references of the records, to cross-check incremental backup manifests
+/// and WAL summaries. Truncations and relation creations, tracked by WAL
+/// summaries as limit blocks, aren't listed. `relid` is only resolved for the
+/// current database and shared catalogs.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_blocks_touched(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(spcoid, pg_sys::Oid),
+        name!(dboid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(forknum, i16),
+        name!(blkno, i64),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let blocks = touched_blocks(&mut wal_decoder);
+    let mut relids = RelidCache::new();
+    TableIterator::new(blocks.into_iter().map(
+        move |(spcoid, dboid, relfilenumber, forknum, blkno)| {
+            let rlocator = pg_sys::RelFileLocator {
+                spcOid: spcoid.into(),
+                dbOid: dboid.into(),
+                relNumber: relfilenumber.into(),
+            };
+            let relid = (classify_database(&rlocator) != RecordDatabase::Other)
+                .then(|| relids.get(&rlocator))
+                .flatten();
+            (
+                rlocator.spcOid,
+                rlocator.dbOid,
+                rlocator.relNumber,
+                relid,
+                i16::try_from(forknum).unwrap_or(-1),
+                i64::from(blkno),
+            )
+        },
+    ))
+}
+
+/// WAL summaries of the timeline overlapping the range, as their start and
+/// end LSNs in order
+fn wal_summaries(timeline: i64, start_lsn: PgLSN, end_lsn: PgLSN) -> Vec<(PgLSN, PgLSN)> {
+    let args: [DatumWithOid; 3] = [
+        timeline.into(),
+        start_lsn.to_string().into(),
+        end_lsn.to_string().into(),
+    ];
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT start_lsn, end_lsn FROM pg_available_wal_summaries()
+                 WHERE tli = $1 AND start_lsn < $3::pg_lsn AND end_lsn > $2::pg_lsn
+                 ORDER BY start_lsn",
+                None,
+                &args,
+            )?
+            .map(|row| Ok((row.get::<PgLSN>(1)?.unwrap(), row.get::<PgLSN>(2)?.unwrap())))
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap()
+}
+
+/// Blocks of a WAL summary, and the limit blocks of the forks truncated or
+/// created in it
+fn summary_blocks(
+    timeline: i64,
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    blocks: &mut BTreeSet<BlockKey>,
+    limits: &mut HashMap<ForkKey, pg_sys::BlockNumber>,
+) {
+    let args: [DatumWithOid; 3] = [
+        timeline.into(),
+        start_lsn.to_string().into(),
+        end_lsn.to_string().into(),
+    ];
+    let rows = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT reltablespace, reldatabase, relfilenode, relforknumber, relblocknumber,
+                        is_limit_block
+                 FROM pg_wal_summary_contents($1, $2::pg_lsn, $3::pg_lsn)",
+                None,
+                &args,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<pg_sys::Oid>(1)?.unwrap_or(pg_sys::InvalidOid),
+                    row.get::<pg_sys::Oid>(2)?.unwrap_or(pg_sys::InvalidOid),
+                    row.get::<pg_sys::Oid>(3)?.unwrap_or(pg_sys::InvalidOid),
+                    row.get::<i16>(4)?.unwrap_or_default(),
+                    row.get::<i64>(5)?.unwrap_or_default(),
+                    row.get::<bool>(6)?.unwrap_or_default(),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap();
+    for (spcoid, dboid, relfilenumber, forknum, blkno, is_limit_block) in rows {
+        let fork = (
+            spcoid.to_u32(),
+            dboid.to_u32(),
+            relfilenumber.to_u32(),
+            i32::from(forknum),
+        );
+        let blkno = pg_sys::BlockNumber::try_from(blkno).unwrap_or(pg_sys::BlockNumber::MAX);
+        if is_limit_block {
+            let limit = limits.entry(fork).or_insert(blkno);
+            *limit = (*limit).min(blkno);
+        } else {
+            blocks.insert((fork.0, fork.1, fork.2, fork.3, blkno));
+        }
+    }
+}
+
+/// Blocks found only in the decoded WAL, `wal`, or only in the summaries,
+/// `summary`. Decoded blocks past the limit block of their fork may have
+/// been dropped from the summary by a later truncation and aren't reported.
+fn summary_diff(
+    decoded: &BTreeSet<BlockKey>,
+    summarized: &BTreeSet<BlockKey>,
+    limits: &HashMap<ForkKey, pg_sys::BlockNumber>,
+) -> Vec<(&'static str, BlockKey)> {
+    let truncated = |block: &BlockKey| {
+        limits
+            .get(&(block.0, block.1, block.2, block.3))
+            .is_some_and(|limit| block.4 >= *limit)
+    };
+    let wal = decoded
+        .difference(summarized)
+        .filter(|block| !truncated(block))
+        .map(|block| ("wal", *block));
+    let summary = summarized
+        .difference(decoded)
+        .map(|block| ("summary", *block));
+    wal.chain(summary).collect()
+}
+
+/// Decode the range covered by the server's WAL summaries overlapping
+/// `start_lsn` to `end_lsn` and compare the blocks it modifies with the
+/// summaries', a consistency check of the data used by incremental backups.
+/// Each row is a block only found in the decoded WAL, `source` is `wal`, or
+/// only in the summaries, `summary`. The range is widened to the bounds of
+/// the summaries, a warning tells when they don't cover all of it. Without
+/// `timeline`, the summaries of the current timeline are used.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_summary_diff(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(source, String),
+        name!(spcoid, pg_sys::Oid),
+        name!(dboid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(forknum, i16),
+        name!(blkno, i64),
+    ),
+> {
+    let timeline = match timeline {
+        Some(timeline) => timeline,
+        None => Spi::get_one::<i32>("SELECT timeline_id FROM pg_control_checkpoint()")
+            .ok()
+            .flatten()
+            .unwrap_or(1),
+    };
+    let summaries = wal_summaries(i64::from(timeline), start_lsn, end_lsn);
+    let (Some((first, _)), Some((_, last))) = (summaries.first(), summaries.last()) else {
+        error!(
+            "No WAL summaries of timeline {timeline} cover {start_lsn} to {end_lsn}, is summarize_wal enabled?"
+        );
+    };
+    let (first, last) = (*first, *last);
+    if first > start_lsn || last < end_lsn {
+        warning!("The WAL summaries only cover {first} to {last}");
+    }
+    let mut summarized = BTreeSet::new();
+    let mut limits = HashMap::new();
+    let mut covered = first;
+    for (summary_start, summary_end) in summaries {
+        if summary_start > covered {
+            warning!("The WAL summaries don't cover {covered} to {summary_start}");
+        }
+        covered = covered.max(summary_end);
+        summary_blocks(
+            i64::from(timeline),
+            summary_start,
+            summary_end,
+            &mut summarized,
+            &mut limits,
+        );
+    }
+
+    let end_lsn = lsn_bounds(first, last);
+    let mut wal_decoder = wal_decoder(first, Some(&end_lsn), Some(timeline), wal_dir);
+    let decoded = touched_blocks(&mut wal_decoder);
+    TableIterator::new(
+        summary_diff(&decoded, &summarized, &limits)
+            .into_iter()
+            .map(|(source, (spcoid, dboid, relfilenumber, forknum, blkno))| {
+                (
+                    source.to_string(),
+                    spcoid.into(),
+                    dboid.into(),
+                    relfilenumber.into(),
+                    i16::try_from(forknum).unwrap_or(-1),
+                    i64::from(blkno),
+                )
+            }),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use pgrx::prelude::*;
+
+    use crate::{blocks::summary_diff, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_summary_diff() {
+        let decoded = BTreeSet::from([(1663, 5, 16384, 0, 0), (1663, 5, 16384, 0, 1)]);
+        let summarized = BTreeSet::from([(1663, 5, 16384, 0, 0), (1663, 5, 16385, 0, 0)]);
+        assert_eq!(
+            summary_diff(&decoded, &summarized, &HashMap::new()),
+            [
+                ("wal", (1663, 5, 16384, 0, 1)),
+                ("summary", (1663, 5, 16385, 0, 0))
+            ]
+        );
+        // The block was dropped by a truncation
+        let limits = HashMap::from([((1663, 5, 16384, 0), 1)]);
+        assert_eq!(
+            summary_diff(&decoded, &summarized, &limits),
+            [("summary", (1663, 5, 16385, 0, 0))]
+        );
+    }
+
+    #[pg_test(
+        error = "No WAL summaries of timeline 1 cover 0/01000000 to 0/02000000, is summarize_wal enabled?"
+    )]
+    fn test_pg_waldecoder_summary_diff_disabled() {
+        Spi::run("SELECT * FROM pg_waldecoder_summary_diff('0/1000000', '0/2000000', 1)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_blocks_touched() {
+        unsafe {
+            Spi::run("CREATE TABLE test_blocks_touched (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_blocks_touched VALUES (1)");
+            Spi::run("INSERT INTO test_blocks_touched VALUES (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Both inserts modify the first block of the main fork
+        let blocks = Spi::get_one::<Vec<i64>>(&format!(
+            "SELECT array_agg(blkno) FROM pg_waldecoder_blocks_touched('{startptr}', '{endptr}', 1)
+             WHERE relid = 'test_blocks_touched'::regclass AND forknum = 0"
+        ));
+        assert_eq!(blocks, Ok(Some(vec![0])));
+    }
+}
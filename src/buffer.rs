@@ -0,0 +1,142 @@
+use pg_waldecoder_core::page::{InvalidPage, LongPageHeader, PageHeader};
+use pgrx::pg_sys::{self, XLOG_BLCKSZ};
+use thiserror::Error;
+
+use crate::pg_lsn::PgLSN;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum BufferError {
+    #[error("Invalid WAL page at the start of the data: {0}")]
+    InvalidPage(InvalidPage),
+    #[error("WAL data must start at a page boundary, got page address {0:X}")]
+    UnalignedPage(u64),
+}
+
+/// WAL held in memory, starting at a page boundary. The position of the data
+/// is taken from the address of its first page, the segment size from its
+/// long header when it starts a segment.
+pub struct BufferSource {
+    start: PgLSN,
+    data: Vec<u8>,
+    segsz: Option<u32>,
+    pub tli: pg_sys::TimeLineID,
+}
+
+impl BufferSource {
+    pub fn new(data: Vec<u8>) -> Result<BufferSource, BufferError> {
+        let header = PageHeader::parse(&data).map_err(BufferError::InvalidPage)?;
+        header
+            .validate(header.pageaddr)
+            .map_err(BufferError::InvalidPage)?;
+        if header.pageaddr % u64::from(XLOG_BLCKSZ) != 0 {
+            return Err(BufferError::UnalignedPage(header.pageaddr));
+        }
+        let segsz = if header.is_long() {
+            let long_header = LongPageHeader::parse(&data).map_err(BufferError::InvalidPage)?;
+            Some(long_header.seg_size)
+        } else {
+            None
+        };
+        Ok(BufferSource {
+            start: PgLSN::from(header.pageaddr),
+            data,
+            segsz,
+            tli: header.tli,
+        })
+    }
+
+    /// LSN of the first byte of the data
+    pub fn start(&self) -> PgLSN {
+        self.start
+    }
+
+    /// Segment size from the long header, None when the data starts in the
+    /// middle of a segment
+    pub fn wal_segment_size(&self) -> Option<u32> {
+        self.segsz
+    }
+
+    /// Fill buf with the WAL starting at ptr, returns the number of bytes
+    /// copied, fewer than requested at the end of the data
+    pub fn read(&self, ptr: PgLSN, buf: &mut [u8]) -> usize {
+        if ptr < self.start {
+            return 0;
+        }
+        let Ok(offset) = usize::try_from(u64::from(ptr - self.start)) else {
+            return 0;
+        };
+        let Some(available) = self.data.get(offset..) else {
+            return 0;
+        };
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        len
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        buffer::{BufferError, BufferSource},
+        pg_lsn::PgLSN,
+    };
+
+    fn test_segment() -> Vec<u8> {
+        std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_buffer_source() {
+        let segment = test_segment();
+        let source = BufferSource::new(segment.clone()).unwrap();
+        assert_eq!(source.start(), PgLSN::from(0x0180_0000u64));
+        assert_eq!(source.wal_segment_size(), Some(1024 * 1024));
+        assert_eq!(source.tli, 1);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(source.read(PgLSN::from(0x0180_0010u64), &mut buf), 16);
+        assert_eq!(buf[..], segment[0x10..0x20]);
+        let end = PgLSN::from(0x0180_0000u64 + segment.len() as u64 - 4);
+        assert_eq!(source.read(end, &mut buf), 4);
+        assert_eq!(source.read(PgLSN::from(0x10u64), &mut buf), 0);
+
+        // A page in the middle of the segment has a short header
+        let source = BufferSource::new(segment[8192..].to_vec()).unwrap();
+        assert_eq!(source.start(), PgLSN::from(0x0180_2000u64));
+        assert_eq!(source.wal_segment_size(), None);
+
+        assert!(matches!(
+            BufferSource::new(vec![0; 8192]),
+            Err(BufferError::InvalidPage(_))
+        ));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_bytes() {
+        unsafe {
+            Spi::run("CREATE TABLE test_bytes (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_bytes VALUES (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let inserts = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_bytes(
+                 pg_read_binary_file('pg_wal/' || pg_walfile_name('{startptr}')),
+                 '{startptr}', '{endptr}')
+             WHERE relation_name = 'test_bytes' AND op LIKE 'INSERT%'"
+        ));
+        assert_eq!(inserts, Ok(Some(2)));
+    }
+}
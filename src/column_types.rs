@@ -0,0 +1,269 @@
+use std::{collections::HashMap, ffi::CString};
+
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*, JsonB, PgTupleDesc};
+
+use crate::dblink::OtherRelation;
+
+/// Columns of a relation as they were when the WAL was written, from a
+/// `[schema.]table(column type, ...)` definition. Tuples of the relation are
+/// deformed with these columns instead of the current catalog's, for a table
+/// altered since.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelationColumns {
+    relation: String,
+    columns: Vec<(String, String)>,
+}
+
+/// Split on the commas outside of parentheses, keeping the typmods of types
+/// like `numeric(10,2)` whole
+fn split_columns(list: &str) -> Vec<&str> {
+    let mut columns = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                columns.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    columns.push(&list[start..]);
+    columns
+}
+
+impl TryFrom<&str> for RelationColumns {
+    type Error = String;
+
+    fn try_from(definition: &str) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "Invalid column types '{definition}', expected [schema.]table(column type, ...)"
+            )
+        };
+        let (relation, list) = definition.split_once('(').ok_or_else(invalid)?;
+        let list = list.trim_end().strip_suffix(')').ok_or_else(invalid)?;
+        let relation = relation.trim();
+        if relation.is_empty() {
+            return Err(invalid());
+        }
+        let columns = split_columns(list)
+            .into_iter()
+            .map(|column| {
+                let (name, typ) = column.trim().split_once(char::is_whitespace)?;
+                let typ = typ.trim();
+                (!typ.is_empty()).then(|| (name.to_string(), typ.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(RelationColumns {
+            relation: relation.to_string(),
+            columns,
+        })
+    }
+}
+
+impl RelationColumns {
+    pub fn new(relation: String, columns: Vec<(String, String)>) -> RelationColumns {
+        RelationColumns { relation, columns }
+    }
+
+    /// Schema and name of the relation, `public` without schema
+    fn schema_and_name(&self) -> (&str, &str) {
+        self.relation
+            .split_once('.')
+            .unwrap_or(("public", &self.relation))
+    }
+
+    /// Tuple descriptor of the columns, the types are resolved with the
+    /// current catalog
+    pub fn tuple_desc(&self) -> PgTupleDesc<'static> {
+        let natts = i32::try_from(self.columns.len()).unwrap();
+        unsafe {
+            let tupdesc = pg_sys::CreateTemplateTupleDesc(natts);
+            for (attnum, (name, typ)) in (1..).zip(&self.columns) {
+                let typ = CString::new(typ.as_str()).unwrap();
+                let name = CString::new(name.as_str()).unwrap();
+                let mut typid = pg_sys::InvalidOid;
+                let mut typmod = -1;
+                pg_sys::parseTypeString(
+                    typ.as_ptr(),
+                    &raw mut typid,
+                    &raw mut typmod,
+                    std::ptr::null_mut(),
+                );
+                pg_sys::TupleDescInitEntry(tupdesc, attnum, name.as_ptr(), typid, typmod, 0);
+            }
+            PgTupleDesc::from_pg(tupdesc)
+        }
+    }
+}
+
+/// Definitions of the relations of a foreign cluster by relfilenumber, from
+/// a `{"relfilenode": "[schema.]table(column type, ...)", ...}` object
+pub fn foreign_definitions(map: JsonB) -> Vec<(pg_sys::Oid, String)> {
+    let args: [DatumWithOid; 1] = [map.into()];
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT key::oid, value FROM jsonb_each_text($1) ORDER BY key",
+                None,
+                &args,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<pg_sys::Oid>(1)?.unwrap(),
+                    row.get::<String>(2)?.unwrap_or_default(),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap()
+}
+
+/// Tuple descriptors of the relations given column types, used in place of
+/// their current descriptor. Historical descriptors aren't rebuilt from the
+/// catalog changes of the WAL, the columns must be given.
+#[derive(Default)]
+pub struct TupleDescCache {
+    descs: HashMap<pg_sys::Oid, PgTupleDesc<'static>>,
+    /// Relations missing from the local catalog, by relfilenumber
+    foreign: HashMap<pg_sys::Oid, OtherRelation>,
+}
+
+impl TupleDescCache {
+    pub fn new(
+        definitions: &[RelationColumns],
+        foreign_definitions: &[(pg_sys::Oid, RelationColumns)],
+    ) -> TupleDescCache {
+        let mut descs = HashMap::new();
+        for definition in definitions {
+            let args: [DatumWithOid; 1] = [definition.relation.as_str().into()];
+            let Ok(Some(relid)) =
+                Spi::get_one_with_args::<pg_sys::Oid>("SELECT to_regclass($1)::oid", &args)
+            else {
+                error!(
+                    "Relation {} of the column types doesn't exist",
+                    definition.relation
+                );
+            };
+            descs.insert(relid, definition.tuple_desc());
+        }
+        let foreign = foreign_definitions
+            .iter()
+            .map(|(relfilenumber, definition)| {
+                let (schema_name, relation_name) = definition.schema_and_name();
+                let relation = OtherRelation {
+                    // Foreign relations have no local relid
+                    relid: pg_sys::InvalidOid,
+                    schema_name: schema_name.to_string(),
+                    relation_name: relation_name.to_string(),
+                    relpersistence: pg_sys::RELPERSISTENCE_PERMANENT,
+                    tuple_desc: definition.tuple_desc(),
+                    encoding: None,
+                    masks: Vec::new(),
+                };
+                (*relfilenumber, relation)
+            })
+            .collect();
+        TupleDescCache { descs, foreign }
+    }
+
+    /// Descriptor given for the relation
+    pub fn get(&self, relid: pg_sys::Oid) -> Option<&PgTupleDesc<'static>> {
+        self.descs.get(&relid)
+    }
+
+    /// Foreign relation given for a relfilenumber
+    pub fn foreign(&self, relfilenumber: pg_sys::Oid) -> Option<&OtherRelation> {
+        self.foreign.get(&relfilenumber)
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{column_types::RelationColumns, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_relation_columns() {
+        let definition =
+            RelationColumns::try_from("public.t(id int, amount numeric(10, 2), data text)")
+                .unwrap();
+        assert_eq!(definition.relation, "public.t");
+        assert_eq!(
+            definition.columns,
+            [
+                ("id".to_string(), "int".to_string()),
+                ("amount".to_string(), "numeric(10, 2)".to_string()),
+                ("data".to_string(), "text".to_string()),
+            ]
+        );
+        assert!(RelationColumns::try_from("t").is_err());
+        assert!(RelationColumns::try_from("t(id)").is_err());
+        assert!(RelationColumns::try_from("(id int)").is_err());
+        assert_eq!(definition.schema_and_name(), ("public", "t"));
+        let definition = RelationColumns::try_from("t(id int)").unwrap();
+        assert_eq!(definition.schema_and_name(), ("public", "t"));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_column_types() {
+        unsafe {
+            Spi::run("CREATE TABLE test_column_types (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_column_types VALUES (1, 'a')");
+            Spi::run("ALTER TABLE test_column_types DROP COLUMN data");
+            Spi::run("ALTER TABLE test_column_types ADD COLUMN amount int");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let query = |column_types: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                     column_types => {column_types})
+                 WHERE op = 'INSERT' AND relid = 'test_column_types'::regclass"
+            ))
+        };
+        assert_eq!(query("NULL"), Ok(Some("(1,)".to_string())));
+        assert_eq!(
+            query("ARRAY['test_column_types(id int, data text)']"),
+            Ok(Some("(1,a)".to_string()))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_foreign_relations() {
+        unsafe {
+            Spi::run("CREATE TABLE test_foreign_relations (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_foreign_relations VALUES (1, 'a')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let (relation, redo_query) = Spi::get_two::<String, String>(&format!(
+            "SELECT schema_name || '.' || relation_name, redo_query FROM pg_waldecoder('{startptr}', timeline => 1,
+                 foreign_relations => jsonb_build_object(
+                     pg_relation_filenode('test_foreign_relations'), 'legacy.orders(order_id int, label text)'))
+             WHERE op = 'INSERT' AND relid IS NULL"
+        ))
+        .unwrap();
+        assert_eq!(relation.as_deref(), Some("legacy.orders"));
+        assert_eq!(
+            redo_query.as_deref(),
+            Some("INSERT INTO legacy.orders (order_id, label) VALUES ('1', 'a');")
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*};
+
+use crate::tuple_str::{quote_identifier, quote_literal, ColumnValue};
+
+/// Columns of the relation's primary key
+fn primary_key_columns(relid: pg_sys::Oid) -> Result<Vec<String>, pgrx::spi::Error> {
+    let args: [DatumWithOid; 1] = [relid.into()];
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT a.attname::text FROM pg_index i
+                 JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+                 WHERE i.indrelid = $1 AND i.indisprimary",
+                None,
+                &args,
+            )?
+            .filter_map(|row| row.get::<String>(1).transpose())
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+}
+
+/// Condition matching a row with the values, compared as text as types like
+/// json or point have no equality operator
+fn text_condition(columns: &[ColumnValue]) -> String {
+    columns
+        .iter()
+        .map(|c| {
+            format!(
+                "{}::text IS NOT DISTINCT FROM {}",
+                quote_identifier(&c.name),
+                quote_literal(c.value.as_deref())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+fn row_exists(relname: &str, columns: &[ColumnValue]) -> Result<bool, pgrx::spi::Error> {
+    Spi::get_one::<bool>(&format!(
+        "SELECT EXISTS (SELECT 1 FROM {relname} WHERE {})",
+        text_condition(columns)
+    ))
+    .map(|exists| exists.unwrap_or(false))
+}
+
+/// Why reverting a change would overwrite a later change of the row, None
+/// when the live row still matches the change. The row written by an insert
+/// or an update must still exist with the same values, and no row with the
+/// primary key of a deleted row must have been inserted since.
+pub fn revert_conflict(
+    relname: &str,
+    relid: pg_sys::Oid,
+    old: Option<&[ColumnValue]>,
+    new: Option<&[ColumnValue]>,
+) -> Result<Option<&'static str>, pgrx::spi::Error> {
+    match (old, new) {
+        (_, Some(new)) => Ok((!row_exists(relname, new)?).then_some("the row was changed since")),
+        (Some(old), None) => {
+            let key_columns = primary_key_columns(relid)?;
+            let key = old
+                .iter()
+                .filter(|c| key_columns.contains(&c.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            // Without a primary key, a row with the same values can't be told
+            // apart from the deleted one
+            if key.is_empty() || key.len() != key_columns.len() {
+                return Ok(None);
+            }
+            Ok(row_exists(relname, &key)?
+                .then_some("a row with the same primary key was inserted since"))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Revert query commented out with the reason of the conflict, so running it
+/// doesn't clobber the later change
+pub fn conflicting_revert(revert_query: &str, reason: &str) -> String {
+    format!("-- Conflict, {reason}: {revert_query}")
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{conflict::conflicting_revert, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_conflicting_revert() {
+        assert_eq!(
+            conflicting_revert("DELETE FROM t WHERE id = '1';", "the row was changed since"),
+            "-- Conflict, the row was changed since: DELETE FROM t WHERE id = '1';"
+        );
+    }
+
+    #[pg_test]
+    fn test_revert_conflicts() {
+        unsafe {
+            Spi::run("CREATE TABLE test_revert_conflicts (id int primary key, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_revert_conflicts VALUES (1, 'a'), (2, 'b')");
+            Spi::run("UPDATE test_revert_conflicts SET data = 'c' WHERE id = 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let conflicts = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1, check_revert_conflicts => true)
+             WHERE revert_query LIKE '-- Conflict%'"
+        ));
+        // The insert of the row updated since conflicts
+        assert_eq!(conflicts, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_revert_conflicts_without_equality() {
+        unsafe {
+            Spi::run("CREATE TABLE test_revert_no_equality (id int, j json, p point, t text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run(
+                r#"INSERT INTO test_revert_no_equality VALUES (1, '{"a": 1}', '(1,2)', NULL)"#,
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // json and point have no = operator, the unchanged row doesn't conflict
+        let conflicts = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1, check_revert_conflicts => true)
+             WHERE relid = 'test_revert_no_equality'::regclass AND revert_query LIKE '-- Conflict%'"
+        ));
+        assert_eq!(conflicts, Ok(Some(0)));
+    }
+}
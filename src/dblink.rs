@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*, PgMemoryContexts, PgTupleDesc};
+
+use crate::{
+    column_types::RelationColumns,
+    masking::{masks_query, MaskMethod, EXTENSION_SCHEMA_QUERY},
+    tuple_str::quote_identifier,
+};
+
+/// Columns and names of the relation of a relfilenumber, queried in its own
+/// database. Relations with dropped columns or types that aren't built in
+/// can't be deformed with a descriptor of the calling database.
+const RELATION_QUERY: &str =
+    "SELECT c.oid, n.nspname::text, c.relname::text, c.relpersistence::text,
+       array_agg(a.attname::text ORDER BY a.attnum),
+       array_agg(format_type(a.atttypid, a.atttypmod) ORDER BY a.attnum),
+       bool_or(a.attisdropped OR a.atttypid >= 16384)
+FROM pg_class c
+JOIN pg_namespace n ON n.oid = c.relnamespace
+JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0
+WHERE c.oid = pg_filenode_relation({spcoid}, {relfilenumber}) AND c.oid >= 16384
+GROUP BY c.oid, n.nspname, c.relname, c.relpersistence";
+
+/// Connection string to a database, `conninfo` with its `dbname` set
+fn database_conninfo(conninfo: &str, datname: &str) -> String {
+    let datname = datname.replace('\\', "\\\\").replace('\'', "\\'");
+    format!("{conninfo} dbname='{datname}'")
+}
+
+/// Relation of another database, with the columns it has there
+pub struct OtherRelation {
+    pub relid: pg_sys::Oid,
+    pub schema_name: String,
+    pub relation_name: String,
+    pub relpersistence: u8,
+    pub tuple_desc: PgTupleDesc<'static>,
+    /// Encoding of the database when it differs from the current one's,
+    /// text values are converted from it
+    pub encoding: Option<i32>,
+    /// Masked columns configured in the relation's database
+    pub masks: Vec<(String, MaskMethod)>,
+}
+
+impl OtherRelation {
+    /// Schema qualified and quoted name of the relation
+    pub fn qualified_name(&self) -> String {
+        format!(
+            "{}.{}",
+            quote_identifier(&self.schema_name),
+            quote_identifier(&self.relation_name)
+        )
+    }
+}
+
+/// Catalogs of the other databases of the cluster, queried through dblink
+/// to decode their tuples. Relations are cached by relfilenumber, a rewrite
+/// gives them a new one.
+pub struct OtherDatabases {
+    conninfo: String,
+    /// Context outliving the records, the tuple descriptors are built there
+    parent_ctx: PgMemoryContexts,
+    /// Name and encoding of each database
+    databases: HashMap<pg_sys::Oid, Option<(String, i32)>>,
+    relations: HashMap<(pg_sys::Oid, pg_sys::Oid, pg_sys::RelFileNumber), Option<OtherRelation>>,
+}
+
+impl OtherDatabases {
+    pub fn new(conninfo: &str, parent_ctx: PgMemoryContexts) -> OtherDatabases {
+        let dblink =
+            Spi::get_one::<bool>("SELECT to_regprocedure('dblink(text, text)') IS NOT NULL");
+        if dblink != Ok(Some(true)) {
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_UNDEFINED_FUNCTION,
+                "The catalogs of other databases are queried with dblink",
+                function_name!(),
+            )
+            .set_hint("Run CREATE EXTENSION dblink.")
+            .report(PgLogLevel::ERROR);
+        }
+        OtherDatabases {
+            conninfo: conninfo.to_string(),
+            parent_ctx,
+            databases: HashMap::new(),
+            relations: HashMap::new(),
+        }
+    }
+
+    fn database(&mut self, dboid: pg_sys::Oid) -> Option<(String, i32)> {
+        self.databases
+            .entry(dboid)
+            .or_insert_with(|| {
+                let args: [DatumWithOid; 1] = [dboid.into()];
+                match Spi::get_two_with_args::<String, i32>(
+                    "SELECT datname::text, encoding FROM pg_database WHERE oid = $1",
+                    &args,
+                ) {
+                    Ok((Some(datname), Some(encoding))) => Some((datname, encoding)),
+                    _ => None,
+                }
+            })
+            .clone()
+    }
+
+    /// Relation of a relfilenumber in its database, None when it doesn't
+    /// exist anymore or its tuples can't be deformed here
+    pub fn get(&mut self, rlocator: &pg_sys::RelFileLocator) -> Option<&OtherRelation> {
+        let key = (rlocator.dbOid, rlocator.spcOid, rlocator.relNumber);
+        if !self.relations.contains_key(&key) {
+            let relation = self.lookup(rlocator);
+            self.relations.insert(key, relation);
+        }
+        self.relations.get(&key).and_then(Option::as_ref)
+    }
+
+    fn lookup(&mut self, rlocator: &pg_sys::RelFileLocator) -> Option<OtherRelation> {
+        let (datname, encoding) = self.database(rlocator.dbOid)?;
+        let query = RELATION_QUERY
+            .replace("{spcoid}", &rlocator.spcOid.to_u32().to_string())
+            .replace("{relfilenumber}", &rlocator.relNumber.to_u32().to_string());
+        let args: [DatumWithOid; 2] = [
+            database_conninfo(&self.conninfo, &datname).into(),
+            query.into(),
+        ];
+        let row = Spi::connect(|client| {
+            let mut rows = client.select(
+                "SELECT * FROM dblink($1, $2) AS t(relid oid, schema_name text, relation_name text,
+                     relpersistence text, column_names text[], column_types text[], unsupported boolean)",
+                None,
+                &args,
+            )?;
+            let Some(row) = rows.next() else {
+                return Ok(None);
+            };
+            Ok::<_, pgrx::spi::Error>(Some((
+                row.get::<pg_sys::Oid>(1)?.unwrap(),
+                row.get::<String>(2)?.unwrap_or_default(),
+                row.get::<String>(3)?.unwrap_or_default(),
+                row.get::<String>(4)?.unwrap_or_default(),
+                row.get::<Vec<String>>(5)?.unwrap_or_default(),
+                row.get::<Vec<String>>(6)?.unwrap_or_default(),
+                row.get::<bool>(7)?.unwrap_or(true),
+            )))
+        })
+        .unwrap();
+        let (relid, schema_name, relation_name, relpersistence, names, types, unsupported) = row?;
+        let masks = remote_masks(&database_conninfo(&self.conninfo, &datname), relid);
+        if unsupported {
+            warning!(
+                "{schema_name}.{relation_name} of database {datname} has dropped columns or custom types, its changes are reported without rows"
+            );
+            return None;
+        }
+        let columns = RelationColumns::new(
+            format!("{schema_name}.{relation_name}"),
+            names.into_iter().zip(types).collect(),
+        );
+        let tuple_desc = unsafe { self.parent_ctx.switch_to(|_| columns.tuple_desc()) };
+        Some(OtherRelation {
+            relid,
+            schema_name,
+            relation_name,
+            relpersistence: relpersistence
+                .bytes()
+                .next()
+                .unwrap_or(pg_sys::RELPERSISTENCE_PERMANENT),
+            tuple_desc,
+            encoding: (encoding != unsafe { pg_sys::GetDatabaseEncoding() }).then_some(encoding),
+            masks,
+        })
+    }
+}
+
+/// Masked columns of a relation, read from the `pg_waldecoder_masked_column`
+/// of its database. A database without the extension has none.
+fn remote_masks(conninfo: &str, relid: pg_sys::Oid) -> Vec<(String, MaskMethod)> {
+    Spi::connect(|client| {
+        let args: [DatumWithOid; 2] = [conninfo.into(), EXTENSION_SCHEMA_QUERY.into()];
+        let schema = client
+            .select(
+                "SELECT * FROM dblink($1, $2) AS t(schema_name text)",
+                None,
+                &args,
+            )?
+            .next()
+            .and_then(|row| row.get::<String>(1).transpose());
+        let Some(schema) = schema.transpose()? else {
+            return Ok(Vec::new());
+        };
+        let args: [DatumWithOid; 2] = [conninfo.into(), masks_query(&schema, relid).into()];
+        client
+            .select(
+                "SELECT * FROM dblink($1, $2) AS t(column_name text, method text)",
+                None,
+                &args,
+            )?
+            .map(|row| {
+                let name = row.get::<String>(1)?.unwrap_or_default();
+                let method = row.get::<String>(2)?.unwrap_or_default();
+                Ok((
+                    name,
+                    MaskMethod::try_from(method.as_str()).unwrap_or(MaskMethod::Mask),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{dblink::database_conninfo, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_database_conninfo() {
+        assert_eq!(
+            database_conninfo("host=/tmp port=5432", "it's"),
+            r"host=/tmp port=5432 dbname='it\'s'"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_other_database_masks() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'))",
+        )
+        .unwrap()
+        .unwrap();
+        // Committed by dblink's own sessions in the postgres database
+        let exec = |sql: &str| {
+            Spi::run(&format!(
+                "SELECT dblink_exec('{}', $sql${sql}$sql$)",
+                database_conninfo(&conninfo, "postgres")
+            ))
+            .unwrap();
+        };
+        exec(
+            "CREATE EXTENSION IF NOT EXISTS pg_waldecoder;
+             DROP TABLE IF EXISTS test_other_masked;
+             CREATE TABLE test_other_masked (id int, email text);
+             INSERT INTO pg_waldecoder_masked_column (relid, column_name)
+                 VALUES ('test_other_masked', 'email')",
+        );
+        let startptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        exec("INSERT INTO test_other_masked VALUES (1, 'a@example.com')");
+        let endptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+
+        let row_after = Spi::get_one::<String>(&format!(
+            "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 other_databases_conninfo => '{conninfo}')
+             WHERE relation_name = 'test_other_masked' AND op = 'INSERT'"
+        ));
+        exec("DROP TABLE test_other_masked");
+        assert_eq!(row_after, Ok(Some("(1,***)".to_string())));
+    }
+}
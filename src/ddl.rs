@@ -0,0 +1,259 @@
+use std::ffi::CStr;
+
+use pgrx::{pg_sys, prelude::*};
+
+use crate::tuple_str::{quote_identifier, ColumnValue};
+
+/// Value of a column of a catalog row
+fn value<'a>(columns: &'a [ColumnValue], name: &str) -> Option<&'a str> {
+    columns
+        .iter()
+        .find(|c| c.name == name)
+        .and_then(|c| c.value.as_deref())
+}
+
+fn oid_value(columns: &[ColumnValue], name: &str) -> Option<pg_sys::Oid> {
+    value(columns, name)?
+        .parse::<u32>()
+        .ok()
+        .map(pg_sys::Oid::from)
+}
+
+/// Changed value of a column between the old and new rows
+fn changed<'a>(
+    old: &'a [ColumnValue],
+    new: &'a [ColumnValue],
+    name: &str,
+) -> Option<(&'a str, &'a str)> {
+    let (old, new) = (value(old, name)?, value(new, name)?);
+    (old != new).then_some((old, new))
+}
+
+fn namespace_name(nspoid: pg_sys::Oid) -> Option<String> {
+    let nspname = unsafe { pg_sys::get_namespace_name(nspoid) };
+    (!nspname.is_null()).then(|| {
+        unsafe { CStr::from_ptr(nspname) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+/// Namespaces whose objects are created and dropped implicitly
+fn is_internal_namespace(nspname: &str) -> bool {
+    nspname == "pg_toast"
+        || nspname.starts_with("pg_temp_")
+        || nspname.starts_with("pg_toast_temp_")
+}
+
+/// Qualified name of a relation, from the catalogs as they are now
+fn relation_display_name(relid: pg_sys::Oid) -> String {
+    let relname = unsafe { pg_sys::get_rel_name(relid) };
+    if relname.is_null() {
+        return relid.to_u32().to_string();
+    }
+    let relname = unsafe { CStr::from_ptr(relname) }.to_string_lossy();
+    let nspname = namespace_name(unsafe { pg_sys::get_rel_namespace(relid) });
+    match nspname {
+        Some(nspname) => format!(
+            "{}.{}",
+            quote_identifier(&nspname),
+            quote_identifier(&relname)
+        ),
+        None => quote_identifier(&relname),
+    }
+}
+
+fn type_name(typid: pg_sys::Oid, typmod: i32) -> String {
+    unsafe { CStr::from_ptr(pg_sys::format_type_with_typemod(typid, typmod)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Object type of a `relkind`
+fn relkind_object(relkind: &str) -> Option<&'static str> {
+    match relkind {
+        "r" | "p" => Some("TABLE"),
+        "i" | "I" => Some("INDEX"),
+        "S" => Some("SEQUENCE"),
+        "v" => Some("VIEW"),
+        "m" => Some("MATERIALIZED VIEW"),
+        "f" => Some("FOREIGN TABLE"),
+        _ => None,
+    }
+}
+
+fn pg_class_ddl(old: Option<&[ColumnValue]>, new: Option<&[ColumnValue]>) -> Option<String> {
+    let row = new.or(old)?;
+    let object = relkind_object(value(row, "relkind")?)?;
+    let nspname = namespace_name(oid_value(row, "relnamespace")?)?;
+    if is_internal_namespace(&nspname) {
+        return None;
+    }
+    let qualified = |relname: &str| {
+        format!(
+            "{}.{}",
+            quote_identifier(&nspname),
+            quote_identifier(relname)
+        )
+    };
+    match (old, new) {
+        (None, Some(new)) => Some(format!(
+            "CREATE {object} {};",
+            qualified(value(new, "relname")?)
+        )),
+        (Some(old), None) => Some(format!(
+            "DROP {object} {};",
+            qualified(value(old, "relname")?)
+        )),
+        (Some(old), Some(new)) => {
+            if let Some((old_name, new_name)) = changed(old, new, "relname") {
+                return Some(format!(
+                    "ALTER {object} {} RENAME TO {};",
+                    qualified(old_name),
+                    quote_identifier(new_name)
+                ));
+            }
+            let (old_nsp, _) = changed(old, new, "relnamespace")?;
+            let old_nsp = namespace_name(old_nsp.parse::<u32>().ok()?.into())?;
+            Some(format!(
+                "ALTER {object} {}.{} SET SCHEMA {};",
+                quote_identifier(&old_nsp),
+                quote_identifier(value(new, "relname")?),
+                quote_identifier(&nspname)
+            ))
+        }
+        (None, None) => None,
+    }
+}
+
+fn pg_namespace_ddl(old: Option<&[ColumnValue]>, new: Option<&[ColumnValue]>) -> Option<String> {
+    let nspname = value(new.or(old)?, "nspname")?;
+    if is_internal_namespace(nspname) {
+        return None;
+    }
+    match (old, new) {
+        (None, Some(_)) => Some(format!("CREATE SCHEMA {};", quote_identifier(nspname))),
+        (Some(_), None) => Some(format!("DROP SCHEMA {};", quote_identifier(nspname))),
+        (Some(old), Some(new)) => {
+            let (old_name, new_name) = changed(old, new, "nspname")?;
+            Some(format!(
+                "ALTER SCHEMA {} RENAME TO {};",
+                quote_identifier(old_name),
+                quote_identifier(new_name)
+            ))
+        }
+        (None, None) => None,
+    }
+}
+
+fn pg_attribute_ddl(old: Option<&[ColumnValue]>, new: Option<&[ColumnValue]>) -> Option<String> {
+    let row = new.or(old)?;
+    // System columns are created and dropped with their relation
+    if value(row, "attnum")?.parse::<i32>().ok()? <= 0 {
+        return None;
+    }
+    let relation = relation_display_name(oid_value(row, "attrelid")?);
+    let column_type = |row: &[ColumnValue]| {
+        let typmod = value(row, "atttypmod")
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(-1);
+        oid_value(row, "atttypid").map(|typid| type_name(typid, typmod))
+    };
+    match (old, new) {
+        (None, Some(new)) => Some(format!(
+            "ALTER TABLE {relation} ADD COLUMN {} {};",
+            quote_identifier(value(new, "attname")?),
+            column_type(new)?
+        )),
+        (Some(old), Some(new)) => {
+            let attname = quote_identifier(value(old, "attname")?);
+            if changed(old, new, "attisdropped").is_some() {
+                return Some(format!("ALTER TABLE {relation} DROP COLUMN {attname};"));
+            }
+            if let Some((_, new_name)) = changed(old, new, "attname") {
+                return Some(format!(
+                    "ALTER TABLE {relation} RENAME COLUMN {attname} TO {};",
+                    quote_identifier(new_name)
+                ));
+            }
+            if changed(old, new, "atttypid").is_some() || changed(old, new, "atttypmod").is_some() {
+                return Some(format!(
+                    "ALTER TABLE {relation} ALTER COLUMN {attname} TYPE {};",
+                    column_type(new)?
+                ));
+            }
+            let (_, notnull) = changed(old, new, "attnotnull")?;
+            let action = if notnull == "t" { "SET" } else { "DROP" };
+            Some(format!(
+                "ALTER TABLE {relation} ALTER COLUMN {attname} {action} NOT NULL;"
+            ))
+        }
+        // Columns are deleted when their relation is dropped
+        _ => None,
+    }
+}
+
+/// DDL matching a change of a `pg_class`, `pg_namespace` or `pg_attribute`
+/// row. None for other relations and for catalog changes without a DDL
+/// equivalent, like statistics updates.
+pub fn catalog_ddl(
+    relid: pg_sys::Oid,
+    old: Option<&[ColumnValue]>,
+    new: Option<&[ColumnValue]>,
+) -> Option<String> {
+    match relid {
+        pg_sys::RelationRelationId => pg_class_ddl(old, new),
+        pg_sys::NamespaceRelationId => pg_namespace_ddl(old, new),
+        pg_sys::AttributeRelationId => pg_attribute_ddl(old, new),
+        _ => None,
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::pg_lsn::PgLSN;
+    use pgrx::prelude::*;
+
+    /// DDL decoded from the statements
+    fn decoded_ddl(statements: &[&str]) -> Vec<String> {
+        unsafe {
+            // Catalog pages are logged as full page images after a checkpoint
+            Spi::run("CHECKPOINT").unwrap();
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        for statement in statements {
+            Spi::run(statement).unwrap();
+        }
+        unsafe { pg_sys::XLogFlush(pg_sys::XactLastRecEnd) };
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(redo_query ORDER BY lsn) FROM pg_waldecoder('{startptr}', '{endptr}', 1)
+             WHERE redo_query NOT LIKE 'INSERT %' AND redo_query NOT LIKE 'UPDATE %' AND redo_query NOT LIKE 'DELETE %'"
+        ))
+        .unwrap()
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_catalog_ddl() {
+        Spi::run("CREATE TABLE test_ddl (id int, data text)").unwrap();
+        let ddl = decoded_ddl(&[
+            "CREATE SCHEMA test_ddl_schema",
+            "ALTER TABLE test_ddl RENAME TO test_ddl_renamed",
+            "ALTER TABLE test_ddl_renamed RENAME COLUMN data TO payload",
+            "ALTER TABLE test_ddl_renamed DROP COLUMN payload",
+        ]);
+        assert!(ddl.contains(&"CREATE SCHEMA test_ddl_schema;".to_string()));
+        assert!(
+            ddl.contains(&"ALTER TABLE public.test_ddl RENAME TO test_ddl_renamed;".to_string())
+        );
+        assert!(ddl.contains(
+            &"ALTER TABLE public.test_ddl_renamed RENAME COLUMN data TO payload;".to_string()
+        ));
+        assert!(
+            ddl.contains(&"ALTER TABLE public.test_ddl_renamed DROP COLUMN payload;".to_string())
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    ffi::CStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*};
+
+use crate::{decoder::DecodedResult, notify::json_string, tuple_str::quote_identifier};
+
+/// Microseconds between the Unix epoch and the Postgres epoch, 2000-01-01
+const POSTGRES_EPOCH_UNIX_USECS: i64 = 946_684_800_000_000;
+
+/// Fields of the `source` block of an event
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct EventSource {
+    db: Option<String>,
+    schema: Option<String>,
+    table: Option<String>,
+    tx_id: u64,
+    lsn: u64,
+    /// Commit time of the transaction, when known
+    ts_ms: Option<i64>,
+}
+
+/// Debezium operation of a heap operation, None for operations that don't
+/// change rows
+fn debezium_op(op: &str) -> Option<&'static str> {
+    match op.trim_end_matches("+INIT") {
+        "INSERT" | "MULTI_INSERT" => Some("c"),
+        "UPDATE" | "HOT_UPDATE" => Some("u"),
+        "DELETE" => Some("d"),
+        "TRUNCATE" => Some("t"),
+        _ => None,
+    }
+}
+
+/// Envelope of a change event, `before` and `after` are JSON objects
+fn envelope(
+    op: &str,
+    before: Option<&str>,
+    after: Option<&str>,
+    source: &EventSource,
+    ts_ms: i64,
+) -> String {
+    let source = format!(
+        r#"{{"version":{},"connector":"postgresql","name":"pg_waldecoder","ts_ms":{},"snapshot":"false","db":{},"schema":{},"table":{},"txId":{},"lsn":{},"xmin":null}}"#,
+        json_string(Some(env!("CARGO_PKG_VERSION"))),
+        source
+            .ts_ms
+            .map_or("null".to_string(), |ts_ms| ts_ms.to_string()),
+        json_string(source.db.as_deref()),
+        json_string(source.schema.as_deref()),
+        json_string(source.table.as_deref()),
+        source.tx_id,
+        source.lsn,
+    );
+    format!(
+        r#"{{"payload":{{"before":{},"after":{},"source":{source},"op":{},"ts_ms":{ts_ms}}}}}"#,
+        before.unwrap_or("null"),
+        after.unwrap_or("null"),
+        json_string(Some(op)),
+    )
+}
+
+/// Row in `record_out` format as a JSON object of its columns, read with the
+/// current definition of the relation
+fn row_json(relation: &str, row: Option<&str>) -> Option<String> {
+    let args: [DatumWithOid; 1] = [row?.into()];
+    Spi::get_one_with_args::<String>(
+        &format!("SELECT to_json(CAST($1 AS {relation}))::text"),
+        &args,
+    )
+    .ok()
+    .flatten()
+}
+
+fn database_name(dboid: pg_sys::Oid) -> Option<String> {
+    let name = unsafe { pg_sys::get_database_name(dboid) };
+    (!name.is_null()).then(|| {
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+/// Debezium change event of a row change, None for other changes
+pub fn debezium_event(change: &DecodedResult) -> Option<String> {
+    let op = debezium_op(&change.op)?;
+    let relation = change
+        .schema_name
+        .as_deref()
+        .zip(change.relation_name.as_deref())
+        .map(|(schema_name, relation_name)| {
+            format!(
+                "{}.{}",
+                quote_identifier(schema_name),
+                quote_identifier(relation_name)
+            )
+        });
+    let rows = relation.as_deref().map(|relation| {
+        (
+            row_json(relation, change.row_before.as_deref()),
+            row_json(relation, change.row_after.as_deref()),
+        )
+    });
+    let (before, after) = rows.unwrap_or_default();
+    let source = EventSource {
+        db: database_name(change.dboid),
+        schema: change.schema_name.clone(),
+        table: change.relation_name.clone(),
+        tx_id: change
+            .full_xid
+            .map_or(u64::from(change.xid.into_inner()), |xid| xid.0),
+        lsn: u64::from(change.lsn),
+        ts_ms: change.commit_time.map(|commit_time| {
+            (pg_sys::TimestampTz::from(commit_time) + POSTGRES_EPOCH_UNIX_USECS) / 1000
+        }),
+    };
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|now| i64::try_from(now.as_millis()).ok())
+        .unwrap_or(0);
+    Some(envelope(
+        op,
+        before.as_deref(),
+        after.as_deref(),
+        &source,
+        ts_ms,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::debezium::{debezium_op, envelope, EventSource};
+
+    #[test]
+    fn test_envelope() {
+        assert_eq!(debezium_op("INSERT+INIT"), Some("c"));
+        assert_eq!(debezium_op("HOT_UPDATE"), Some("u"));
+        assert_eq!(debezium_op("LOCK"), None);
+
+        let source = EventSource {
+            db: Some("postgres".to_string()),
+            schema: Some("public".to_string()),
+            table: Some("t".to_string()),
+            tx_id: 750,
+            lsn: 42,
+            ts_ms: None,
+        };
+        assert_eq!(
+            envelope("u", Some(r#"{"id":1}"#), Some(r#"{"id":2}"#), &source, 1000),
+            format!(
+                r#"{{"payload":{{"before":{{"id":1}},"after":{{"id":2}},"source":{{"version":"{}","connector":"postgresql","name":"pg_waldecoder","ts_ms":null,"snapshot":"false","db":"postgres","schema":"public","table":"t","txId":750,"lsn":42,"xmin":null}},"op":"u","ts_ms":1000}}}}"#,
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+}
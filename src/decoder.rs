@@ -1,24 +1,74 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ffi::{c_void, CStr, CString};
 use std::fs::File;
 use std::io;
 use std::os::fd::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use memmap2::Mmap;
+use pg_waldecoder_core::page::{PageHeader, XLOG_PAGE_MAGIC};
+use pg_waldecoder_core::version::WalVersion;
 use pgrx::iter::TableIterator;
 use pgrx::pg_sys::InvalidXLogRecPtr;
 use pgrx::spi::Error;
+use pgrx::{
+    datum::TryFromDatumError, extension_sql, function_name, name, pg_guard, warning,
+    AllocatedByRust, ErrorReport, PgHeapTuple, PgLogLevel, PgMemoryContexts, PgSqlErrorCode,
+    TimestampWithTimeZone,
+};
 use pgrx::{
     error,
     ffi::c_char,
-    pg_sys::{self, RmgrIds::RM_HEAP_ID, XLogRecord},
+    pg_sys::{
+        self,
+        RmgrIds::{RM_GENERIC_ID, RM_HEAP2_ID, RM_HEAP_ID, RM_NEXT_ID, RM_XACT_ID, RM_XLOG_ID},
+        XLogRecord,
+    },
     PgBox,
 };
-use pgrx::{info, name, pg_guard, warning, PgMemoryContexts};
 
+use crate::access::{check_decoder_access, check_write_access};
+use crate::annotation::Annotations;
+use crate::buffer::BufferSource;
+use crate::column_types::{RelationColumns, TupleDescCache};
+use crate::dblink::OtherDatabases;
+use crate::fpi_check::{check_record_fpis, FpiMismatch};
+use crate::guc::{self, verbose, UnsupportedRecords, Verbosity};
+use crate::masking::{ColumnExclusion, MaskCache};
+use crate::memory::{
+    context_allocated_bytes, create_record_context, publish_memory_stats, MemoryStats,
+};
+use crate::origin::{record_origin, OriginNameCache};
+use crate::page_cache::{saved_cache_path, PageCache};
 use crate::pg_lsn::{xlog_file_name, PgLSN};
-use crate::wal::detect_wal_dir;
-use crate::xlog_heap::decode_heap_record;
+use crate::progress::Progress;
+use crate::relation::{RelationFilter, RelationNameCache, RelidCache};
+use crate::remote::RemoteSource;
+use crate::rmgr::{describe_record, rmgr_decoder, DecodeContext};
+use crate::s3::{is_s3_url, S3Source};
+use crate::segments::no_record_diagnostics;
+use crate::sink::try_in_subtransaction;
+use crate::summary::{publish_scan_summary, ScanSummary};
+use crate::template::QueryTemplate;
+use crate::throttle::{CostDelay, PAGE_READ_COST, RECORD_COST};
+use crate::timeline::{
+    check_page_timeline, find_latest_timeline, missing_segment_mismatch, read_timeline_history,
+    segment_timeline, tli_of_point, wal_file_timelines, TimelineHistoryEntry,
+};
+use crate::timing::{record_read, reset_timings, start_read};
+use crate::toast::ToastChunks;
+use crate::tuple_str::JsonbText;
+use crate::wal::{
+    detect_wal_source, find_segment_file, format_rejections, is_partial_segment, live_wal_dir,
+    next_available_segment, validate_segment_size, WalLocation, WalSource,
+};
+use crate::wal_settings::WalSettings;
+use crate::walinspect::rmgr_display_name;
+use crate::xid::{commit_timestamp, xact_commit_time, xact_end, FullXid, SubxactTree, XidEpoch};
+use crate::xlog_heap::{heap_op, record_page_ids, replayed_lsn_mismatches, SkipReason};
+use crate::xlog_reader::{get_block, get_block_tag_extended};
 use thiserror::Error;
 
 #[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
@@ -27,60 +77,720 @@ pub enum WalError {
     ReadRecordError(pg_sys::XLogRecPtr, String),
 }
 
+/// What is done with a record that can't be read or decoded
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum OnError {
+    /// Raise an error, aborting the scan
+    Stop,
+    /// Skip the record and carry on with the next one
+    Skip,
+    /// Report the failure as a change and carry on with the next record
+    Emit,
+}
+
+impl TryFrom<&str> for OnError {
+    type Error = String;
+
+    fn try_from(on_error: &str) -> Result<Self, Self::Error> {
+        match on_error {
+            "stop" => Ok(OnError::Stop),
+            "skip" => Ok(OnError::Skip),
+            "emit" => Ok(OnError::Emit),
+            _ => Err(format!(
+                "Unknown on_error '{on_error}', expected stop, skip or emit"
+            )),
+        }
+    }
+}
+
+/// How `end_lsn` bounds the records decoded
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum EndBound {
+    /// Records ending by `end_lsn`, a record crossing it is left out
+    #[default]
+    RecordEnd,
+    /// Records starting before `end_lsn`, a record crossing it is read whole
+    RecordStart,
+}
+
+impl TryFrom<&str> for EndBound {
+    type Error = String;
+
+    fn try_from(end_bound: &str) -> Result<Self, Self::Error> {
+        match end_bound {
+            "record_end" => Ok(EndBound::RecordEnd),
+            "record_start" => Ok(EndBound::RecordStart),
+            _ => Err(format!(
+                "Unknown end_bound '{end_bound}', expected record_end or record_start"
+            )),
+        }
+    }
+}
+
+/// A change decoded from a heap record, one row of `pg_waldecoder`
+#[derive(Clone, Debug)]
 pub struct DecodedResult {
-    pub lsn: i64,
+    /// Start of the record
+    pub lsn: PgLSN,
     pub dboid: pg_sys::Oid,
-    pub relid: pg_sys::Oid,
+    /// None when the relation can't be found, e.g. it was dropped since
+    pub relid: Option<pg_sys::Oid>,
+    pub spcoid: pg_sys::Oid,
+    pub relfilenumber: pg_sys::RelFileNumber,
+    /// Fork and block of the modified page, they locate the change even
+    /// after the relation was rewritten or dropped
+    pub forknum: Option<i32>,
+    pub blkno: Option<i64>,
+    /// The relfilenode doesn't match any relation, only the metadata is set
+    pub relation_missing: bool,
+    /// The tuples don't fit the columns they were deformed with, the table
+    /// was altered since the WAL was written. None without tuples.
+    pub schema_mismatch: Option<bool>,
+    /// How the row before the change was obtained, or the row after without
+    /// one: `record`, `fpi`, `replay`, `current` or `logged`
+    pub provenance: Option<String>,
+    /// Xid as stored in the record
     pub xid: pg_sys::TransactionId,
-    pub redo_query: Option<&'static str>,
-    pub revert_query: Option<&'static str>,
-    pub row_before: Option<&'static str>,
-    pub row_after: Option<&'static str>,
-}
-
-impl
-    From<DecodedResult> for (
-        i64,
-        pg_sys::Oid,
-        pg_sys::Oid,
-        pg_sys::TransactionId,
-        Option<&'static str>,
-        Option<&'static str>,
-        Option<&'static str>,
-        Option<&'static str>,
+    /// Xid with its epoch, None until the epoch is known
+    pub full_xid: Option<FullXid>,
+    /// Commit time of the transaction, when the server tracks commit
+    /// timestamps
+    pub commit_time: Option<TimestampWithTimeZone>,
+    /// None when the relation doesn't exist anymore
+    pub schema_name: Option<String>,
+    pub relation_name: Option<String>,
+    /// Replication origin of changes applied by logical replication
+    pub origin_id: Option<pg_sys::Oid>,
+    pub origin_name: Option<String>,
+    /// Header and main data of the record, when requested
+    pub raw_record: Option<Vec<u8>>,
+    /// Top level transaction of `xid`, `xid` itself unless the WAL read so far
+    /// tells it's a subtransaction
+    pub toplevel_xid: pg_sys::TransactionId,
+    /// Old and new values of the columns modified by an update
+    pub changes: Option<JsonbText>,
+    /// Why the record couldn't be read or decoded, for the failures reported
+    /// with `on_error => 'emit'`
+    pub error: Option<String>,
+    /// Item pointer of the tuple before the change, for updates and deletes
+    pub old_ctid: Option<pg_sys::ItemPointerData>,
+    /// Item pointer of the tuple after the change, for inserts and updates
+    pub new_ctid: Option<pg_sys::ItemPointerData>,
+    /// Name of the heap operation, e.g. `INSERT` or `HOT_UPDATE`
+    pub op: String,
+    /// Query applying the change
+    pub redo_query: Option<String>,
+    /// Query undoing the change
+    pub revert_query: Option<String>,
+    /// Row before the change, in `record_out` format
+    pub row_before: Option<String>,
+    /// Row after the change, in `record_out` format
+    pub row_after: Option<String>,
+    /// Tuple after the change, or the deleted one, header included, when
+    /// requested
+    pub raw_tuple: Option<Vec<u8>>,
+    /// Names of the flags set in `t_infomask` and `t_infomask2`
+    pub infomask: Option<Vec<String>>,
+    pub xmin: Option<pg_sys::TransactionId>,
+    pub xmax: Option<pg_sys::TransactionId>,
+    /// Persistence of the relation: `permanent`, `unlogged` or `temporary`.
+    /// The queries of the changes to the other ones are usually meaningless,
+    /// their contents aren't recovered.
+    pub persistence: Option<String>,
+    /// Command id of the tuple header, its cmin or cmax, or a combo cid with
+    /// `HEAP_COMBOCID`. Only the pages of the images keep the id, tuples
+    /// rebuilt from a record have the first command id.
+    pub cid: Option<i64>,
+    /// Generated redo query with its literals replaced by parameters, the
+    /// same for the changes of a statement shape
+    pub query_fingerprint: Option<String>,
+    /// Start of the previous record, from the record's header
+    pub prev_lsn: Option<PgLSN>,
+    /// Total length of the record, its full page images included
+    pub record_length: Option<i32>,
+    /// Length of the full page images of the record
+    pub fpi_length: Option<i32>,
+    /// Payload of the latest annotation message of the transaction
+    pub annotation: Option<String>,
+}
+
+extension_sql!(
+    r"
+CREATE TYPE pg_waldecoder_change AS (
+    lsn pg_lsn,
+    dboid oid,
+    relid oid,
+    spcoid oid,
+    relfilenumber oid,
+    relation_missing boolean,
+    xid xid8,
+    op text,
+    redo_query text,
+    revert_query text,
+    row_before text,
+    row_after text,
+    raw_xid xid,
+    commit_time timestamptz,
+    schema_name text,
+    relation_name text,
+    origin_id oid,
+    origin_name text,
+    raw_record bytea,
+    toplevel_xid xid,
+    changes jsonb,
+    error text,
+    old_ctid tid,
+    new_ctid tid,
+    forknum int,
+    blkno bigint,
+    schema_mismatch boolean,
+    provenance text,
+    raw_tuple bytea,
+    infomask text[],
+    xmin xid,
+    xmax xid,
+    persistence text,
+    cid bigint,
+    query_fingerprint text,
+    prev_lsn pg_lsn,
+    record_length int,
+    fpi_length int,
+    annotation text
+);
+",
+    name = "change_type",
+);
+
+/// Composite type of the rows returned by the decoding functions
+pub const CHANGE_TYPE: &str = "pg_waldecoder_change";
+
+impl DecodedResult {
+    /// Build the `pg_waldecoder_change` row of the change
+    pub fn into_change(self) -> PgHeapTuple<'static, AllocatedByRust> {
+        let mut change = match PgHeapTuple::new_composite_type(CHANGE_TYPE) {
+            Ok(change) => change,
+            Err(e) => error!("Error: {}", e.to_string()),
+        };
+        if let Err(e) = self.set_change_fields(&mut change) {
+            error!("Error: {}", e.to_string());
+        }
+        change
+    }
+
+    fn set_change_fields(
+        self,
+        change: &mut PgHeapTuple<'static, AllocatedByRust>,
+    ) -> Result<(), TryFromDatumError> {
+        change.set_by_name("lsn", self.lsn)?;
+        change.set_by_name("dboid", self.dboid)?;
+        change.set_by_name("relid", self.relid)?;
+        change.set_by_name("spcoid", self.spcoid)?;
+        change.set_by_name("relfilenumber", self.relfilenumber)?;
+        change.set_by_name("relation_missing", self.relation_missing)?;
+        change.set_by_name("xid", self.full_xid)?;
+        change.set_by_name("op", self.op)?;
+        change.set_by_name("redo_query", self.redo_query)?;
+        change.set_by_name("revert_query", self.revert_query)?;
+        change.set_by_name("row_before", self.row_before)?;
+        change.set_by_name("row_after", self.row_after)?;
+        change.set_by_name("raw_xid", self.xid)?;
+        change.set_by_name("commit_time", self.commit_time)?;
+        change.set_by_name("schema_name", self.schema_name)?;
+        change.set_by_name("relation_name", self.relation_name)?;
+        change.set_by_name("origin_id", self.origin_id)?;
+        change.set_by_name("origin_name", self.origin_name)?;
+        change.set_by_name("raw_record", self.raw_record)?;
+        change.set_by_name("toplevel_xid", self.toplevel_xid)?;
+        change.set_by_name("changes", self.changes)?;
+        change.set_by_name("error", self.error)?;
+        change.set_by_name("old_ctid", self.old_ctid)?;
+        change.set_by_name("new_ctid", self.new_ctid)?;
+        change.set_by_name("forknum", self.forknum)?;
+        change.set_by_name("blkno", self.blkno)?;
+        change.set_by_name("schema_mismatch", self.schema_mismatch)?;
+        change.set_by_name("provenance", self.provenance)?;
+        change.set_by_name("raw_tuple", self.raw_tuple)?;
+        change.set_by_name("infomask", self.infomask)?;
+        change.set_by_name("xmin", self.xmin)?;
+        change.set_by_name("xmax", self.xmax)?;
+        change.set_by_name("persistence", self.persistence)?;
+        change.set_by_name("cid", self.cid)?;
+        change.set_by_name("query_fingerprint", self.query_fingerprint)?;
+        change.set_by_name("prev_lsn", self.prev_lsn)?;
+        change.set_by_name("record_length", self.record_length)?;
+        change.set_by_name("fpi_length", self.fpi_length)?;
+        change.set_by_name("annotation", self.annotation)?;
+        Ok(())
+    }
+}
+
+/// A row without any column set, the record specific constructors fill the
+/// columns they know
+impl Default for DecodedResult {
+    fn default() -> Self {
+        DecodedResult {
+            lsn: PgLSN::from(0u64),
+            dboid: pg_sys::InvalidOid,
+            relid: None,
+            spcoid: pg_sys::InvalidOid,
+            relfilenumber: pg_sys::InvalidOid,
+            forknum: None,
+            blkno: None,
+            relation_missing: false,
+            schema_mismatch: None,
+            provenance: None,
+            xid: pg_sys::InvalidTransactionId,
+            full_xid: None,
+            commit_time: None,
+            schema_name: None,
+            relation_name: None,
+            origin_id: None,
+            origin_name: None,
+            raw_record: None,
+            toplevel_xid: pg_sys::InvalidTransactionId,
+            changes: None,
+            error: None,
+            old_ctid: None,
+            new_ctid: None,
+            op: String::new(),
+            redo_query: None,
+            revert_query: None,
+            row_before: None,
+            row_after: None,
+            raw_tuple: None,
+            infomask: None,
+            xmin: None,
+            xmax: None,
+            persistence: None,
+            cid: None,
+            query_fingerprint: None,
+            prev_lsn: None,
+            record_length: None,
+            fpi_length: None,
+            annotation: None,
+        }
+    }
+}
+
+impl DecodedResult {
+    /// Row reporting a record that was aborted by a crash before being fully
+    /// written
+    fn aborted_record(lsn: PgLSN) -> DecodedResult {
+        DecodedResult {
+            lsn,
+            op: "ABORTED_CONTRECORD".to_string(),
+            ..DecodedResult::default()
+        }
+    }
+
+    /// Fill the columns describing the record of the change
+    fn set_record_info(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>) {
+        self.prev_lsn = Some(PgLSN::from(record.header.xl_prev));
+        self.record_length = Some(i32::try_from(record.header.xl_tot_len).unwrap_or(i32::MAX));
+        self.fpi_length = Some(i32::try_from(record_fpi_length(record)).unwrap_or(i32::MAX));
+    }
+
+    /// Row framing a transaction in the changes, `BEGIN` before its first
+    /// change, `COMMIT` or `ABORT` at its end record
+    fn transaction_row(op: &str, lsn: PgLSN, xid: pg_sys::TransactionId) -> DecodedResult {
+        DecodedResult {
+            lsn,
+            op: op.to_string(),
+            xid,
+            toplevel_xid: xid,
+            ..DecodedResult::default()
+        }
+    }
+
+    /// Change reporting a record that can't be decoded, with the relation of
+    /// its first block
+    fn unsupported_record(
+        xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> DecodedResult {
+        let mut decoded = DecodedResult {
+            lsn: PgLSN::from(record.lsn),
+            ..DecodedResult::default()
+        };
+        if let Some((rlocator, forknum, blknum)) = get_block_tag_extended(xlog_reader, 0) {
+            decoded.dboid = rlocator.dbOid;
+            decoded.spcoid = rlocator.spcOid;
+            decoded.relfilenumber = rlocator.relNumber;
+            decoded.forknum = Some(forknum);
+            decoded.blkno = Some(i64::from(blknum));
+        }
+        decoded.xid = record.header.xl_xid;
+        decoded.toplevel_xid = record.header.xl_xid;
+        decoded.op = describe_record(record);
+        decoded.set_record_info(record);
+        decoded
+    }
+
+    /// Change reporting a record that couldn't be read or decoded
+    fn failed_record(lsn: PgLSN, reason: &str) -> DecodedResult {
+        DecodedResult {
+            lsn,
+            op: "ERROR".to_string(),
+            error: Some(reason.to_string()),
+            ..DecodedResult::default()
+        }
+    }
+
+    /// Append a comment telling where the change comes from to its queries,
+    /// like `/* lsn=0/01800C50 xid=739 */`, with the commit time when it's
+    /// known
+    fn annotate_queries(&mut self) {
+        let commit_time = self
+            .commit_time
+            .map(|commit_time| format!(" commit_time='{commit_time}'"))
+            .unwrap_or_default();
+        let comment = format!(
+            "/* lsn={} xid={}{commit_time} */",
+            self.lsn,
+            self.xid.into_inner()
+        );
+        for query in self
+            .redo_query
+            .iter_mut()
+            .chain(self.revert_query.iter_mut())
+        {
+            query.push(' ');
+            query.push_str(&comment);
+        }
+    }
+}
+
+/// Whether the record may change rows without being decoded: generic WAL,
+/// custom resource managers and the heap2 inserts of COPY. Other builtin
+/// resource managers only log indexes, transactions and server state.
+fn is_unsupported_record(record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+    let rmid = u32::from(record.header.xl_rmid);
+    rmid == RM_GENERIC_ID
+        || rmid >= RM_NEXT_ID
+        || (rmid == RM_HEAP2_ID && heap_op(record) == pg_sys::XLOG_HEAP2_MULTI_INSERT)
+}
+
+/// Time left to the statement before `statement_timeout`, None without
+/// timeout
+fn statement_time_left() -> Option<Duration> {
+    let timeout_ms = u64::try_from(unsafe { pg_sys::StatementTimeout })
+        .ok()
+        .filter(|timeout_ms| *timeout_ms > 0)?;
+    let elapsed =
+        unsafe { pg_sys::GetCurrentTimestamp() - pg_sys::GetCurrentStatementStartTimestamp() };
+    let timeout = Duration::from_millis(timeout_ms);
+    // Stop early enough to return the rows, 1s or a tenth of the timeout
+    let margin = (timeout / 10).min(Duration::from_secs(1));
+    Some(
+        timeout
+            .saturating_sub(Duration::from_micros(u64::try_from(elapsed).unwrap_or(0)))
+            .saturating_sub(margin),
     )
-{
-    fn from(val: DecodedResult) -> Self {
-        (
-            val.lsn,
-            val.dboid,
-            val.relid,
-            val.xid,
-            val.redo_query,
-            val.revert_query,
-            val.row_before,
-            val.row_after,
+}
+
+/// When decoding stops, after `max_runtime` microseconds or before the
+/// statement times out
+fn decoding_deadline(max_runtime: Option<i64>, stop_before_timeout: bool) -> Option<Instant> {
+    let max_runtime =
+        max_runtime.map(|micros| Duration::from_micros(micros.max(0).cast_unsigned()));
+    let time_left = stop_before_timeout.then(statement_time_left).flatten();
+    let runtime = match (max_runtime, time_left) {
+        (Some(max_runtime), Some(time_left)) => max_runtime.min(time_left),
+        (runtime, None) | (None, runtime) => runtime?,
+    };
+    Some(Instant::now() + runtime)
+}
+
+/// Returns the LSN of the aborted record if this is an OVERWRITE_CONTRECORD
+/// record
+fn overwritten_contrecord(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<PgLSN> {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if info != pg_sys::XLOG_OVERWRITE_CONTRECORD || record.main_data.is_null() {
+        return None;
+    }
+    let xlrec = unsafe {
+        std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_overwrite_contrecord>())
+    };
+    Some(PgLSN::from(xlrec.overwritten_lsn))
+}
+
+/// Total length of the full page images of a record
+fn record_fpi_length(record: &PgBox<pg_sys::DecodedXLogRecord>) -> u32 {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return 0;
+    };
+    (0..=max_block_id)
+        .filter_map(|block_id| get_block(record, block_id))
+        .filter(|block| block.has_image)
+        .map(|block| u32::from(block.bimg_len))
+        .sum()
+}
+
+/// Header of a record followed by its main data
+fn raw_record_bytes(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Vec<u8> {
+    let header = unsafe {
+        std::slice::from_raw_parts(
+            (&raw const record.header).cast::<u8>(),
+            std::mem::size_of::<XLogRecord>(),
         )
+    };
+    let mut bytes = header.to_vec();
+    if !record.main_data.is_null() {
+        let main_data_len = usize::try_from(record.main_data_len).unwrap();
+        bytes.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(record.main_data.cast::<u8>(), main_data_len)
+        });
     }
+    bytes
+}
+
+/// Options controlling where WAL is read from and how decoding proceeds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecoderOptions<'a> {
+    pub end_lsn: Option<&'a str>,
+    pub timeline: Option<i32>,
+    /// Timelines the range spans, for WAL without history files. Each
+    /// segment is read from the latest of them having it.
+    pub timelines: &'a [i32],
+    /// Colon-separated WAL directories and files, a segment file is decoded
+    /// with the other listed ones only
+    pub wal_dir: Option<&'a str>,
+    pub live: bool,
+    pub conninfo: Option<&'a str>,
+    /// Decode this WAL instead of reading it from files
+    pub wal_data: Option<&'a [u8]>,
+    pub skip_missing: bool,
+    /// Stop once the end of the base backup started at this LSN is reached
+    pub backup_start: Option<PgLSN>,
+    /// Read pages never seen in a full page image from the current relation
+    pub read_current_pages: bool,
+    /// Report metadata of changes to other databases' relations
+    pub include_other_databases: bool,
+    /// Read the next segment in the background while decoding the current one
+    pub read_ahead: bool,
+    /// Report the `NEW_CID` records logged for catalog tuples with
+    /// `wal_level=logical`
+    pub include_new_cid: bool,
+    /// Report the visibility map changes of `VISIBLE` records
+    pub include_visible: bool,
+    /// Report the whole pages logged by `FPI` records, for table rewrites
+    /// and init forks
+    pub include_newpages: bool,
+    /// Report the pages logged by `FPI_FOR_HINT` records, when hint bits are
+    /// set with checksums or `wal_log_hints`
+    pub include_hint_fpis: bool,
+    /// Stop at the first commit more than this many microseconds after the first
+    /// decoded commit
+    pub for_interval: Option<i64>,
+    /// Stop after reading this many records, whether they're decoded or not
+    pub record_count: Option<u64>,
+    /// Stop reading after this many microseconds, returning the changes
+    /// decoded so far
+    pub max_runtime: Option<i64>,
+    /// Stop reading shortly before `statement_timeout` cancels the query
+    pub stop_before_timeout: bool,
+    /// Fill `raw_record` with the record's header and main data
+    pub include_raw_record: bool,
+    /// Fill `raw_tuple`, `infomask`, `xmin`, `xmax` and `cid` from the tuple
+    /// of the change
+    pub include_raw_tuple: bool,
+    /// Comment out the revert queries that would overwrite a later change of
+    /// the live row
+    pub check_revert_conflicts: bool,
+    /// Compare full page images with the blocks on disk, see
+    /// `take_fpi_mismatches`
+    pub check_fpis: bool,
+    /// Check that the pages replayed for each heap record end with the
+    /// record's LSN, flagging the changes of the mismatching ones in `error`
+    pub strict_redo: bool,
+    /// S3 archives searched after the WAL dirs, in order
+    pub s3_archives: &'a [&'a str],
+    /// `[schema.]table.column` patterns of the columns rendered as NULL in
+    /// the rows, after the queries were generated
+    pub exclude_columns: &'a [&'a str],
+    /// `[schema.]table(column type, ...)` definitions of the columns of the
+    /// relations altered since the WAL was written, used instead of their
+    /// current columns to deform the tuples
+    pub column_types: &'a [&'a str],
+    /// `[schema.]table(column type, ...)` definitions of the relations of a
+    /// foreign cluster by relfilenumber, decoded without the local catalog
+    pub foreign_relations: &'a [(pg_sys::Oid, &'a str)],
+    /// Only decode the changes of these relations, the records of other
+    /// relations are skipped before their tuples are rebuilt
+    pub relations: &'a [&'a str],
+    /// Statement rendered for each change in place of the generated redo
+    /// query, the revert query is left out
+    pub query_template: Option<&'a str>,
+    /// Leave the schema out of the relation names of the generated queries,
+    /// they then rely on the `search_path`
+    pub unqualified_names: bool,
+    /// Append the LSN, xid and commit time of the change as a comment to its
+    /// queries
+    pub annotate_queries: bool,
+    /// Connection string completed by the database name to query the
+    /// catalogs of other databases with dblink, their tuples are then
+    /// decoded too
+    pub other_databases_conninfo: Option<&'a str>,
+    /// Frame the changes of each transaction with `BEGIN` and `COMMIT` or
+    /// `ABORT` rows
+    pub include_transactions: bool,
+    /// Report the chunks inserted in toast tables, they're otherwise only
+    /// used to rebuild the toasted values of their table's rows
+    pub include_toast_chunks: bool,
+    /// Name of a saved page cache loaded before decoding and written back
+    /// once the scan ends, so a chunked scan resumes with its pages
+    pub page_cache_file: Option<&'a str>,
+    /// What is done with records that can't be decoded, defaults to
+    /// `pg_waldecoder.unsupported_records`
+    pub unsupported_records: Option<UnsupportedRecords>,
+    /// What is done with records that can't be read or decoded. Without it,
+    /// a read error ends the decoding with a warning, an unresolved relation
+    /// is reported with `relation_missing` and other failures raise an error.
+    pub on_error: Option<OnError>,
+    /// Whether the records crossing `end_lsn` are decoded
+    pub end_bound: EndBound,
+    /// Prefix of the logical messages whose payload is attached to the
+    /// following changes of their transaction as `annotation`
+    pub annotation_prefix: Option<&'a str>,
 }
 
+/// Identifies a cached page, the same way a buffer tag does
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct PageId {
-    rlocator: pg_sys::RelFileLocator,
+    spc_oid: pg_sys::Oid,
+    db_oid: pg_sys::Oid,
+    rel_number: pg_sys::RelFileNumber,
+    forknum: i32,
     blknum: pg_sys::BlockNumber,
 }
 
+impl PageId {
+    pub fn new(
+        rlocator: &pg_sys::RelFileLocator,
+        forknum: i32,
+        blknum: pg_sys::BlockNumber,
+    ) -> PageId {
+        PageId {
+            spc_oid: rlocator.spcOid,
+            db_oid: rlocator.dbOid,
+            rel_number: rlocator.relNumber,
+            forknum,
+            blknum,
+        }
+    }
+
+    pub fn rlocator(&self) -> pg_sys::RelFileLocator {
+        pg_sys::RelFileLocator {
+            spcOid: self.spc_oid,
+            dbOid: self.db_oid,
+            relNumber: self.rel_number,
+        }
+    }
+
+    pub fn forknum(&self) -> i32 {
+        self.forknum
+    }
+
+    pub fn blknum(&self) -> pg_sys::BlockNumber {
+        self.blknum
+    }
+}
+
+/// Iterator over the changes decoded from the WAL. It must be used in a
+/// backend, inside a transaction.
 pub struct WalDecoder {
     xlog_reader: PgBox<pg_sys::XLogReaderState>,
+    reader_release: *mut ReaderRelease,
     startptr: PgLSN,
     per_record_ctx: PgMemoryContexts,
-    page_hash: HashMap<PageId, pg_sys::Page>,
+    peak_record_bytes: usize,
+    page_cache: PageCache,
+    relid_cache: RelidCache,
+    relation_names: RelationNameCache,
+    origin_names: OriginNameCache,
+    mask_cache: MaskCache,
+    tuple_descs: TupleDescCache,
+    relation_filter: Option<RelationFilter>,
+    query_template: Option<QueryTemplate>,
+    progress: Progress,
+    summary: ScanSummary,
+    xid_epoch: XidEpoch,
+    wal_settings: WalSettings,
+    subxacts: SubxactTree,
+    /// Commit times are read from the server's commit timestamps
+    track_commit_time: bool,
+    backup_start: Option<PgLSN>,
+    /// Microseconds of commits decoded, counted from the first commit
+    interval: Option<i64>,
+    interval_end: Option<pg_sys::TimestampTz>,
+    record_count: Option<u64>,
+    /// Changes returned before stopping, from `pg_waldecoder.max_rows`
+    max_rows: Option<u64>,
+    /// When reading stops, from `max_runtime` or `statement_timeout`
+    deadline: Option<Instant>,
+    rows_returned: u64,
+    check_fpis: bool,
+    strict_redo: bool,
+    include_raw_record: bool,
+    include_raw_tuple: bool,
+    check_revert_conflicts: bool,
+    fpi_mismatches: Vec<FpiMismatch>,
+    /// The end of the backup or of the interval was reached
+    stopped: bool,
+    include_other_databases: bool,
+    include_new_cid: bool,
+    include_visible: bool,
+    include_newpages: bool,
+    include_hint_fpis: bool,
+    unqualified_names: bool,
+    annotate_queries: bool,
+    other_databases: Option<OtherDatabases>,
+    include_transactions: bool,
+    /// Top level xids whose `BEGIN` row was returned, until their end
+    open_transactions: HashSet<u32>,
+    /// Change returned after the `BEGIN` row of its transaction
+    pending_change: Option<DecodedResult>,
+    include_toast_chunks: bool,
+    toast_chunks: ToastChunks,
+    annotations: Option<Annotations>,
+    /// Where the page cache is saved when the scan ends
+    page_cache_file: Option<PathBuf>,
+    unsupported_records: UnsupportedRecords,
+    on_error: Option<OnError>,
+    /// Record that couldn't be read, reported before resuming
+    read_failure: Option<(PgLSN, String)>,
 }
 
 struct XLogReaderPrivate {
-    timeline: u32,
+    timeline_history: Vec<TimelineHistoryEntry>,
+    timelines: Vec<pg_sys::TimeLineID>,
+    current_timeline: Option<pg_sys::TimeLineID>,
     endptr: Option<PgLSN>,
     endptr_reached: bool,
+    end_bound: EndBound,
+    /// End of the WAL written, the pages of a record crossing `endptr` are
+    /// only read up to it
+    read_limit: Option<PgLSN>,
     opened_segment: Option<File>,
+    partial_segment: bool,
+    live: bool,
+    skip_missing: bool,
+    missing_segment: Option<(String, pg_sys::XLogSegNo)>,
+    remote: Option<RemoteSource>,
+    buffer: Option<BufferSource>,
+    /// Archives segments are fetched from, in order, when missing locally
+    s3: Vec<S3Source>,
+    source: WalSource,
+    read_ahead: bool,
+    /// Next segment being read in the background
+    pending_read_ahead: Option<JoinHandle<()>>,
+    /// Pages are copied from the mapping of the opened segment
+    mmap_segments: bool,
+    mapped_segment: Option<Mmap>,
+    /// The pages of a segment are dropped from the OS cache when it's closed
+    drop_wal_cache: bool,
+    /// Delay of the scan for the pages read and the records decoded
+    cost_delay: CostDelay,
 }
 
 #[pg_guard]
@@ -93,11 +803,20 @@ unsafe extern "C-unwind" fn pg_waldecoder_read_page(
 ) -> i32 {
     let target_page_ptr = PgLSN::from(target_page_ptr);
     let target_ptr = PgLSN::from(target_ptr);
-    info!("Reading page {}", target_page_ptr);
+    verbose!(Verbosity::Debug, "Reading page {}", target_page_ptr);
     let xlog_reader = unsafe { PgBox::from_pg(state) };
     let mut private = unsafe { PgBox::from_pg((*state).private_data.cast::<XLogReaderPrivate>()) };
     let blcksz = pg_sys::XLOG_BLCKSZ;
-    let count = match private.endptr {
+    // The record being read started before the end, its pages past the end
+    // are read too. A contrecord continued in the next segment asks for the
+    // pages of that segment with the same target.
+    let limit = match private.endptr {
+        Some(endptr) if private.end_bound == EndBound::RecordStart && target_ptr < endptr => {
+            private.read_limit
+        }
+        endptr => endptr,
+    };
+    let count = match limit {
         Some(endptr) => {
             if target_page_ptr + blcksz <= endptr {
                 blcksz
@@ -111,35 +830,158 @@ unsafe extern "C-unwind" fn pg_waldecoder_read_page(
         None => blcksz,
     };
 
-    let errinfo = Box::into_raw(Box::new(pg_sys::WALReadError::default()));
-    if !pg_sys::WALRead(
-        state,
-        read_buff,
-        target_page_ptr.into(),
-        usize::try_from(count).unwrap(),
-        private.timeline,
-        errinfo,
-    ) {
-        let errinfo = Box::from_raw(errinfo);
-        let seg = errinfo.wre_seg;
-        let fname = xlog_file_name(seg.ws_tli, seg.ws_segno, xlog_reader.segcxt.ws_segsize);
-
-        if errinfo.wre_errno != 0 {
-            let error = io::Error::from_raw_os_error(errinfo.wre_errno);
-            error!(
-                "could not read from file {0}, offset {1}: {2}",
-                fname, errinfo.wre_off, error
-            );
-        } else {
-            error!(
-                "could not read from file {0}, offset {1}: read {2} of {3}",
-                fname, errinfo.wre_off, errinfo.wre_read, errinfo.wre_req
-            );
+    if private.remote.is_some() {
+        // Without an end, stop at the end of WAL reported by the primary
+        let stop_at_server_end = limit.is_none();
+        let remote = private.remote.as_mut().unwrap();
+        let buf = std::slice::from_raw_parts_mut(read_buff.cast::<u8>(), count as usize);
+        return match remote.read(target_page_ptr, buf, stop_at_server_end) {
+            Ok(read) if read >= usize::try_from(req_len).unwrap() => i32::try_from(read).unwrap(),
+            Ok(_) => {
+                private.endptr_reached = true;
+                -1
+            }
+            Err(e) => error!("Error: {}", e.to_string()),
+        };
+    }
+
+    if let Some(buffer) = &private.buffer {
+        let buf = std::slice::from_raw_parts_mut(read_buff.cast::<u8>(), count as usize);
+        let read = buffer.read(target_page_ptr, buf);
+        if read < usize::try_from(req_len).unwrap() {
+            private.endptr_reached = true;
+            return -1;
+        }
+        return i32::try_from(read).unwrap();
+    }
+
+    // Read the segment from the timeline owning its last byte. A segment
+    // containing a switch point is complete only on the child timeline.
+    let segsz = u64::from(xlog_reader.segcxt.ws_segsize.cast_unsigned());
+    let page_ptr = u64::from(target_page_ptr);
+    let seg_last_byte = PgLSN::from(page_ptr - page_ptr % segsz + segsz - 1);
+    let tli = segment_timeline(
+        &private.timelines,
+        &private.source,
+        page_ptr / segsz,
+        xlog_reader.segcxt.ws_segsize,
+    )
+    .unwrap_or_else(|| tli_of_point(&private.timeline_history, seg_last_byte));
+    if private
+        .current_timeline
+        .is_some_and(|current| current != tli)
+    {
+        verbose!(
+            Verbosity::Normal,
+            "Switching to timeline {} at {}",
+            tli,
+            target_page_ptr
+        );
+    }
+    private.current_timeline = Some(tli);
+
+    if private.live || private.skip_missing {
+        // The server may have removed or recycled the segment since the scan
+        // started, or the archive may have a gap. Report it instead of failing
+        // in segment_open.
+        let segno = page_ptr / segsz;
+        let fname = xlog_file_name(tli, segno, xlog_reader.segcxt.ws_segsize);
+        if find_segment_file(&private.source, &fname).is_none() && private.s3.is_empty() {
+            private.missing_segment = Some((fname, segno));
+            return -1;
+        }
+    }
+
+    private.cost_delay.charge(PAGE_READ_COST);
+    let len = usize::try_from(count).unwrap();
+    let buf = std::slice::from_raw_parts_mut(read_buff.cast::<u8>(), len);
+    if !(private.mmap_segments && read_mapped_page(state, page_ptr / segsz, tli, page_ptr, buf)) {
+        let errinfo = Box::into_raw(Box::new(pg_sys::WALReadError::default()));
+        if !pg_sys::WALRead(state, read_buff, target_page_ptr.into(), len, tli, errinfo) {
+            let errinfo = Box::from_raw(errinfo);
+            let seg = errinfo.wre_seg;
+            let fname = xlog_file_name(seg.ws_tli, seg.ws_segno, xlog_reader.segcxt.ws_segsize);
+
+            if errinfo.wre_errno != 0 {
+                let error = io::Error::from_raw_os_error(errinfo.wre_errno);
+                error!(
+                    "could not read from file {0}, offset {1}: {2}",
+                    fname, errinfo.wre_off, error
+                );
+            } else {
+                error!(
+                    "could not read from file {0}, offset {1}: read {2} of {3}",
+                    fname, errinfo.wre_off, errinfo.wre_read, errinfo.wre_req
+                );
+            }
+        }
+    }
+    // A page written on a later timeline means the segment file was renamed
+    // or copied from another timeline, its records can't be trusted
+    let page = std::slice::from_raw_parts(read_buff.cast::<u8>(), count as usize);
+    if let Ok(header) = PageHeader::parse(page) {
+        let fname = xlog_file_name(tli, page_ptr / segsz, xlog_reader.segcxt.ws_segsize);
+        if header.magic == XLOG_PAGE_MAGIC {
+            if let Err(e) = check_page_timeline(&fname, header.tli, tli) {
+                error!("Error: {}", e.to_string());
+            }
+        } else if let Some(version) = header.version() {
+            // Older WAL is only framed by the core reader, the records are
+            // decoded with the layouts of the server
+            ErrorReport::new(
+                PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                format!(
+                    "Segment {fname} was written by PostgreSQL {}, only the WAL of PostgreSQL {} can be decoded",
+                    version.major(),
+                    WalVersion::CURRENT.major()
+                ),
+                function_name!(),
+            )
+            .set_hint("pg-waldecode lists the records of older WAL.")
+            .report(PgLogLevel::ERROR);
         }
     }
     i32::try_from(count).unwrap()
 }
 
+/// Copy the WAL at `page_ptr` from the mapping of its segment, opening the
+/// segment as `WALRead` would when another one is opened.
+///
+/// Returns false when the segment couldn't be mapped, the page is then read
+/// from the file.
+unsafe fn read_mapped_page(
+    state: *mut pg_sys::XLogReaderState,
+    segno: pg_sys::XLogSegNo,
+    tli: pg_sys::TimeLineID,
+    page_ptr: u64,
+    buf: &mut [u8],
+) -> bool {
+    let seg = unsafe { (*state).seg };
+    if seg.ws_file < 0 || seg.ws_segno != segno || seg.ws_tli != tli {
+        if seg.ws_file >= 0 {
+            unsafe { pg_waldecoder_segment_close(state) };
+        }
+        let mut open_tli = tli;
+        unsafe {
+            pg_waldecoder_segment_open(state, segno, &raw mut open_tli);
+            (*state).seg.ws_tli = open_tli;
+            (*state).seg.ws_segno = segno;
+        }
+    }
+    let private = unsafe { PgBox::from_pg((*state).private_data.cast::<XLogReaderPrivate>()) };
+    let segsz = u64::from(unsafe { (*state).segcxt.ws_segsize }.cast_unsigned());
+    let offset = usize::try_from(page_ptr % segsz).unwrap();
+    let Some(page) = private
+        .mapped_segment
+        .as_ref()
+        .and_then(|mapping| mapping.get(offset..offset + buf.len()))
+    else {
+        return false;
+    };
+    buf.copy_from_slice(page);
+    true
+}
+
 #[pg_guard]
 unsafe extern "C-unwind" fn pg_waldecoder_segment_open(
     state: *mut pg_sys::XLogReaderState,
@@ -150,67 +992,364 @@ unsafe extern "C-unwind" fn pg_waldecoder_segment_open(
     let mut private =
         unsafe { PgBox::from_pg(xlog_reader.private_data.cast::<XLogReaderPrivate>()) };
     let fname = xlog_file_name(*tli_ptr, next_seg_no, xlog_reader.segcxt.ws_segsize);
-    let wal_dir = CStr::from_ptr(xlog_reader.segcxt.ws_dir.as_ptr())
-        .to_str()
-        .expect("Error converting wal_dir to cstr");
-    let path = Path::new(wal_dir).join(&fname);
+    // Don't race with the read-ahead of this segment
+    if let Some(handle) = private.pending_read_ahead.take() {
+        let _ = handle.join();
+    }
+    let path = match (find_segment_file(&private.source, &fname), &private.s3[..]) {
+        (Some(path), _) => path,
+        (None, [first, others @ ..]) => {
+            // The error of the last archive is reported
+            let fetched = others.iter().fold(first.fetch(&fname), |fetched, s3| {
+                fetched.or_else(|_| s3.fetch(&fname))
+            });
+            match fetched {
+                Ok(path) => path,
+                Err(e) => error!("Error: {}", e.to_string()),
+            }
+        }
+        (None, []) => {
+            if let Some(e) = missing_segment_mismatch(
+                &private.source,
+                *tli_ptr,
+                next_seg_no,
+                xlog_reader.segcxt.ws_segsize,
+            ) {
+                error!("Error: {}", e.to_string());
+            }
+            error!(
+                "Could not find segment \"{}\" in \"{}\"",
+                fname, private.source
+            )
+        }
+    };
     let Ok(f) = File::open(&path) else {
         error!("Could not open file \"{}\"", path.display());
     };
-    info!("Opening segment {}", path.display());
+    // Segments of a different size would silently misalign every read
+    if let Err(e) = validate_segment_size(&path, xlog_reader.segcxt.ws_segsize.cast_unsigned()) {
+        error!("Error: {}", e.to_string());
+    }
+    verbose!(Verbosity::Normal, "Opening segment {}", path.display());
+    if private.mmap_segments {
+        // The file may be truncated while it's mapped by pg_receivewal or an
+        // archiver, partial segments are read instead
+        private.mapped_segment = (!is_partial_segment(&path))
+            .then(|| unsafe { Mmap::map(&f) }.ok())
+            .flatten();
+    }
     xlog_reader.seg.ws_file = f.as_raw_fd();
     private.opened_segment = Some(f);
+    private.partial_segment = is_partial_segment(&path);
+    if private.read_ahead {
+        let segsz = xlog_reader.segcxt.ws_segsize;
+        private.pending_read_ahead = start_read_ahead(&private, *tli_ptr, next_seg_no + 1, segsz);
+    }
+}
+
+/// Read the next segment in a background thread so it's in the OS cache, or
+/// downloaded from S3, by the time it's opened. The thread must not call into
+/// postgres.
+fn start_read_ahead(
+    private: &XLogReaderPrivate,
+    tli: pg_sys::TimeLineID,
+    segno: pg_sys::XLogSegNo,
+    segsz: i32,
+) -> Option<JoinHandle<()>> {
+    let seg_start = PgLSN::from(segno * u64::from(segsz.cast_unsigned()));
+    if private.endptr.is_some_and(|endptr| seg_start > endptr) {
+        return None;
+    }
+    let fname = xlog_file_name(tli, segno, segsz);
+    if let Some(path) = find_segment_file(&private.source, &fname) {
+        return Some(thread::spawn(move || {
+            let _ = File::open(path).and_then(|mut f| io::copy(&mut f, &mut io::sink()));
+        }));
+    }
+    if private.s3.is_empty() {
+        return None;
+    }
+    let s3 = private.s3.clone();
+    Some(thread::spawn(move || {
+        // Errors are reported when the segment is fetched again on open
+        let _ = s3.iter().find(|s3| s3.download(&fname).is_ok());
+    }))
 }
 
 #[pg_guard]
 unsafe extern "C-unwind" fn pg_waldecoder_segment_close(state: *mut pg_sys::XLogReaderState) {
     let mut private = unsafe { PgBox::from_pg((*state).private_data.cast::<XLogReaderPrivate>()) };
-    private.opened_segment = None;
+    private.mapped_segment = None;
+    if let Some(f) = private.opened_segment.take() {
+        if private.drop_wal_cache {
+            drop_file_cache(&f);
+        }
+    }
+}
+
+/// Tell the OS the pages of a file won't be needed again
+fn drop_file_cache(f: &File) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+/// Connect to an S3 archive and prefetch what WAL dir detection needs,
+/// returns the local cache dir of the archive
+fn open_s3_archive(url: &str, sources: &mut Vec<S3Source>) -> String {
+    let source = match S3Source::new(url) {
+        Ok(source) => source,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    if let Err(e) = source.prefetch() {
+        error!("Error: {}", e.to_string());
+    }
+    let cache_dir = source.cache_dir().to_string_lossy().to_string();
+    sources.push(source);
+    cache_dir
 }
 
-fn build_xlog_reader(
-    start_lsn: PgLSN,
-    end_lsn: Option<&str>,
-    timeline: i32,
-    wal_dir: Option<&str>,
-) -> PgBox<pg_sys::XLogReaderState> {
+fn build_xlog_reader(start_lsn: PgLSN, options: &DecoderOptions) -> PgBox<pg_sys::XLogReaderState> {
+    let DecoderOptions {
+        end_lsn,
+        timeline,
+        timelines,
+        wal_dir,
+        live,
+        conninfo,
+        wal_data,
+        skip_missing,
+        read_ahead,
+        s3_archives,
+        end_bound,
+        ..
+    } = *options;
     // Parse end ptr
-    let endptr = match end_lsn.map(PgLSN::try_from) {
+    let mut endptr = match end_lsn.map(PgLSN::try_from) {
         Some(Ok(endptr)) => Some(endptr),
         Some(Err(e)) => error!("Error: {}", e.to_string()),
         None => None,
     };
 
+    let mut read_limit = None;
+    let mut remote = None;
+    let mut buffer = None;
+    let mut s3 = Vec::new();
+    let mut detected_timeline = false;
+    let (source, segsz, timeline) = if let Some(data) = wal_data {
+        if wal_dir.is_some() || live || conninfo.is_some() {
+            error!("WAL data can't be used with wal_dir, live mode or conninfo");
+        }
+        let source = match BufferSource::new(data.to_vec()) {
+            Ok(source) => source,
+            Err(e) => error!("Error: {}", e.to_string()),
+        };
+        // Data starting in the middle of a segment is assumed to use the
+        // server's segment size
+        let segsz = source
+            .wal_segment_size()
+            .unwrap_or_else(|| unsafe { pg_sys::wal_segment_size }.cast_unsigned());
+        let timeline = timeline.map_or(source.tli, i32::cast_unsigned);
+        verbose!(
+            Verbosity::Normal,
+            "Decoding {} bytes of WAL from {}, segsz: {}",
+            data.len(),
+            source.start(),
+            segsz
+        );
+        buffer = Some(source);
+        (WalSource::default(), segsz, timeline)
+    } else if let Some(conninfo) = conninfo {
+        if wal_dir.is_some() || live {
+            error!("conninfo can't be used with wal_dir or live mode");
+        }
+        let mut source = match RemoteSource::connect(conninfo) {
+            Ok(source) => source,
+            Err(e) => error!("Error: {}", e.to_string()),
+        };
+        let segsz = source.wal_segment_size();
+        let timeline = timeline.map_or(source.primary_tli, i32::cast_unsigned);
+        if let Err(e) = source.start_streaming(start_lsn, timeline) {
+            error!("Error: {}", e.to_string());
+        }
+        verbose!(
+            Verbosity::Normal,
+            "Streaming from primary on timeline {}, segsz: {}",
+            timeline,
+            segsz
+        );
+        remote = Some(source);
+        (WalSource::default(), segsz, timeline)
+    } else if live {
+        if wal_dir.is_some() {
+            error!("wal_dir can't be used with live mode");
+        }
+        let (wal_dir, segsz) = live_wal_dir();
+        // Only read what was flushed, the rest of the segment may not be
+        // written yet
+        let mut insert_tli: pg_sys::TimeLineID = 0;
+        let flushptr = PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(&raw mut insert_tli) });
+        endptr = Some(endptr.map_or(flushptr, |endptr| endptr.min(flushptr)));
+        read_limit = Some(flushptr);
+        let timeline = timeline.map_or(insert_tli, i32::cast_unsigned);
+        verbose!(
+            Verbosity::Normal,
+            "Live mode using Wal dir: {}, segsz: {}, flushed up to {}",
+            wal_dir.display(),
+            segsz,
+            flushptr
+        );
+        (WalSource::from_dirs(vec![wal_dir]), segsz, timeline)
+    } else {
+        // The server's WAL can be read up to what was flushed when the scan
+        // started, ignoring what is written during the scan
+        if endptr.is_none()
+            && wal_dir.is_none()
+            && s3_archives.is_empty()
+            && guc::END_AT_FLUSH.get()
+        {
+            let flushptr = PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) });
+            verbose!(
+                Verbosity::Normal,
+                "Decoding up to the flushed WAL at {}",
+                flushptr
+            );
+            endptr = Some(flushptr);
+            read_limit = Some(flushptr);
+        }
+        // Segments of an S3 archive are fetched in a local cache used as
+        // WAL dir
+        let mut wal_dir = match wal_dir.filter(|d| is_s3_url(d)) {
+            Some(url) => Some(open_s3_archive(url, &mut s3)),
+            None => wal_dir.map(str::to_string),
+        };
+        for url in s3_archives {
+            let cache_dir = open_s3_archive(url, &mut s3);
+            wal_dir = Some(match wal_dir {
+                Some(wal_dir) => format!("{wal_dir}:{cache_dir}"),
+                None => cache_dir,
+            });
+        }
+        let (source, segsz) = match detect_wal_source(wal_dir.as_deref()) {
+            Ok(detected) => detected,
+            Err(rejections) => {
+                ErrorReport::new(
+                    PgSqlErrorCode::ERRCODE_UNDEFINED_FILE,
+                    "No valid WAL files found in wal dir",
+                    function_name!(),
+                )
+                .set_detail(format_rejections(&rejections))
+                .set_hint(
+                    "Set wal_dir to a directory containing WAL segments readable by the server.",
+                )
+                .report(PgLogLevel::ERROR);
+                unreachable!()
+            }
+        };
+        for location in source.locations() {
+            match location {
+                WalLocation::Dir(wal_dir) => verbose!(
+                    Verbosity::Normal,
+                    "Detected Wal dir: {}, segsz: {}",
+                    wal_dir.display(),
+                    segsz
+                ),
+                WalLocation::File(wal_file) => verbose!(
+                    Verbosity::Normal,
+                    "Using Wal file: {}, segsz: {}",
+                    wal_file.display(),
+                    segsz
+                ),
+            }
+        }
+
+        // Without an explicit timeline, decode up to the latest listed or
+        // known timeline
+        let timeline = match timeline.or_else(|| timelines.iter().max().copied()) {
+            Some(timeline) => timeline.cast_unsigned(),
+            None => {
+                detected_timeline = true;
+                find_latest_timeline(&source)
+            }
+        };
+        (source, segsz, timeline)
+    };
+    let timeline_history = match read_timeline_history(&source, timeline) {
+        Ok(timeline_history) => timeline_history,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    // Without history file for the detected timeline, its ancestors are only
+    // known from the segment names
+    let timelines = if detected_timeline && timeline_history.len() == 1 {
+        wal_file_timelines(&source)
+    } else {
+        timelines.iter().map(|tli| tli.cast_unsigned()).collect()
+    };
+    verbose!(
+        Verbosity::Normal,
+        "Decoding up to timeline {}, known timelines: {:?}",
+        timeline,
+        timelines
+    );
+
+    // The WAL of the foreign relations comes from another cluster
+    let check_system = guc::CHECK_SYSTEM_IDENTIFIER.get() && options.foreign_relations.is_empty();
+    let system_identifier = check_system.then(|| unsafe { pg_sys::GetSystemIdentifier() });
     let private_data = Box::new(XLogReaderPrivate {
-        timeline: timeline.cast_unsigned(),
+        timeline_history,
+        timelines,
+        current_timeline: None,
         endptr,
         endptr_reached: false,
+        end_bound,
+        read_limit,
         opened_segment: None,
+        partial_segment: false,
+        live,
+        skip_missing,
+        missing_segment: None,
+        remote,
+        buffer,
+        s3,
+        source: source.clone(),
+        read_ahead,
+        pending_read_ahead: None,
+        mmap_segments: guc::MMAP_SEGMENTS.get(),
+        mapped_segment: None,
+        drop_wal_cache: guc::DROP_WAL_CACHE.get(),
+        cost_delay: CostDelay::new(),
     });
 
-    let xl_routine = Box::new(pg_sys::XLogReaderRoutine {
+    // The routine is copied by XLogReaderAllocate
+    let mut xl_routine = pg_sys::XLogReaderRoutine {
         page_read: Some(pg_waldecoder_read_page),
         segment_open: Some(pg_waldecoder_segment_open),
         segment_close: Some(pg_waldecoder_segment_close),
-    });
-
-    let Some((wal_dir, segsz)) = detect_wal_dir(wal_dir) else {
-        error!("No valid WAL files found in wal dir")
     };
-    info!("Detected Wal dir: {}, segsz: {}", wal_dir.display(), segsz);
 
-    let wal_dir_cstr = CString::new(wal_dir.to_str().expect("wal_dir conversion error"))
-        .expect("WAL dir cstring conversion failed");
+    // Segments are searched in all WAL dirs, the first one is only kept as
+    // the reader's default directory
+    let wal_dir_cstr = CString::new(
+        source
+            .default_dir()
+            .to_str()
+            .expect("wal_dir conversion error"),
+    )
+    .expect("WAL dir cstring conversion failed");
     let wal_dir_ptr = wal_dir_cstr.as_c_str().as_ptr();
 
     let xlog_reader = unsafe {
         pg_sys::XLogReaderAllocate(
             segsz.cast_signed(),
             wal_dir_ptr,
-            Box::into_raw(xl_routine),
+            &raw mut xl_routine,
             Box::into_raw(private_data).cast::<c_void>(),
         )
     };
+    // Compared by XLogReaderValidatePageHeader with the long header of each
+    // segment, whatever the source of its pages
+    unsafe { (*xlog_reader).system_identifier = system_identifier.unwrap_or(0) };
     unsafe { PgBox::from_pg(xlog_reader) }
 }
 
@@ -218,78 +1357,978 @@ impl Iterator for WalDecoder {
     type Item = DecodedResult;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let _error_context = ErrorContextGuard::push(&self.xlog_reader);
+        // The change of a returned BEGIN row isn't left behind
+        if self.pending_change.is_none()
+            && self
+                .max_rows
+                .is_some_and(|max_rows| self.rows_returned >= max_rows)
+        {
+            if !self.stopped {
+                warning!(
+                    "Reached pg_waldecoder.max_rows, decoding stopped at {}",
+                    PgLSN::from(self.xlog_reader.EndRecPtr)
+                );
+                self.stopped = true;
+                self.release();
+            }
+            return None;
+        }
+        let decoded_record = self.decode_next();
+        if decoded_record.is_some() {
+            self.rows_returned += 1;
+        }
+        publish_memory_stats(self.memory_stats());
+        publish_scan_summary(self.scan_summary());
+        if decoded_record.is_none() {
+            if self.rows_returned == 0 {
+                self.report_no_changes();
+            }
+            let skipped = self
+                .summary
+                .skip_counts()
+                .iter()
+                .map(|(reason, count)| format!("{count} {reason}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            verbose!(Verbosity::Normal, "Skipped records: {skipped}");
+            let stats = self.page_cache.stats;
+            verbose!(
+                Verbosity::Normal,
+                "Page cache: {} hits, {} misses, {} evictions, {} allocations, {} spilled, {} reloaded",
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.allocations,
+                stats.spilled,
+                stats.reloaded
+            );
+            self.release();
+        }
+        decoded_record
+    }
+}
+
+/// Free an xlog reader and its private state
+unsafe fn free_xlog_reader(xlog_reader: *mut pg_sys::XLogReaderState) {
+    if xlog_reader.is_null() {
+        return;
+    }
+    unsafe {
+        let private_data = (*xlog_reader).private_data.cast::<XLogReaderPrivate>();
+        // Closes the opened segment through the segment_close callback
+        pg_sys::XLogReaderFree(xlog_reader);
+        if !private_data.is_null() {
+            drop(Box::from_raw(private_data));
+        }
+    }
+}
+
+/// Reader of a decoder, freed with the memory context the decoder was built
+/// in unless the decoder was dropped first
+struct ReaderRelease {
+    xlog_reader: *mut pg_sys::XLogReaderState,
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_release_reader(arg: *mut c_void) {
+    let release = arg.cast::<ReaderRelease>();
+    unsafe {
+        free_xlog_reader((*release).xlog_reader);
+        (*release).xlog_reader = std::ptr::null_mut();
+    }
+}
+
+/// Free the reader when the current memory context is reset or deleted: at
+/// the end of the scan, when the query fails or is cancelled, or when a
+/// rescan calls the function again
+fn register_reader_release(xlog_reader: *mut pg_sys::XLogReaderState) -> *mut ReaderRelease {
+    unsafe {
+        let release = pg_sys::palloc0(size_of::<ReaderRelease>()).cast::<ReaderRelease>();
+        (*release).xlog_reader = xlog_reader;
+        let callback = pg_sys::palloc0(size_of::<pg_sys::MemoryContextCallback>())
+            .cast::<pg_sys::MemoryContextCallback>();
+        (*callback).func = Some(pg_waldecoder_release_reader);
+        (*callback).arg = release.cast();
+        pg_sys::MemoryContextRegisterResetCallback(pg_sys::CurrentMemoryContext, callback);
+        release
+    }
+}
+
+/// Releases the xlog reader and its private state, unless the reset of the
+/// memory context did first
+impl Drop for WalDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            free_xlog_reader((*self.reader_release).xlog_reader);
+            (*self.reader_release).xlog_reader = std::ptr::null_mut();
+        }
+    }
+}
+
+/// Context of an error raised while the reader is at a record
+unsafe fn error_context(xlog_reader: *const pg_sys::XLogReaderState) -> CString {
+    let read_rec_ptr = PgLSN::from(unsafe { (*xlog_reader).ReadRecPtr });
+    let record = unsafe { (*xlog_reader).record };
+    let context = if record.is_null() {
+        format!("while reading WAL at {read_rec_ptr}")
+    } else {
+        let header = unsafe { (*record).header };
+        format!(
+            "while decoding WAL record at {read_rec_ptr}, rmgr {} info {:#X}",
+            header.xl_rmid, header.xl_info
+        )
+    };
+    CString::new(context).unwrap()
+}
+
+/// Error context callback reporting the record being decoded
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_error_callback(arg: *mut c_void) {
+    let context = unsafe { error_context(arg.cast()) };
+    unsafe {
+        pg_sys::errcontext_msg(c"%s".as_ptr(), context.as_ptr());
+    }
+}
+
+/// Error context callback reporting the context saved when the decoder
+/// raised a Rust error
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_saved_error_callback(arg: *mut c_void) {
+    unsafe {
+        pg_sys::errcontext_msg(c"%s".as_ptr(), arg.cast::<c_char>());
+    }
+}
+
+/// Keeps the decoder's error context callback installed while it is alive
+struct ErrorContextGuard {
+    callback: Box<pg_sys::ErrorContextCallback>,
+}
+
+impl ErrorContextGuard {
+    fn push(xlog_reader: &PgBox<pg_sys::XLogReaderState>) -> ErrorContextGuard {
+        let mut callback = Box::new(pg_sys::ErrorContextCallback {
+            previous: unsafe { pg_sys::error_context_stack },
+            callback: Some(pg_waldecoder_error_callback),
+            arg: xlog_reader.as_ptr().cast(),
+        });
+        unsafe { pg_sys::error_context_stack = &raw mut *callback };
+        ErrorContextGuard { callback }
+    }
+}
+
+impl Drop for ErrorContextGuard {
+    fn drop(&mut self) {
+        unsafe { pg_sys::error_context_stack = self.callback.previous };
+        if !std::thread::panicking() {
+            return;
+        }
+        // A Rust error is reported once its panic reaches the pg_guard
+        // boundary, after this guard and maybe the reader are dropped. A copy
+        // of the context stays installed until the error is caught, which
+        // restores the error context stack, ErrorContext is reset after.
+        unsafe {
+            let context = error_context(self.callback.arg.cast());
+            let saved = pg_sys::MemoryContextAlloc(
+                pg_sys::ErrorContext,
+                std::mem::size_of::<pg_sys::ErrorContextCallback>(),
+            )
+            .cast::<pg_sys::ErrorContextCallback>();
+            saved.write(pg_sys::ErrorContextCallback {
+                previous: self.callback.previous,
+                callback: Some(pg_waldecoder_saved_error_callback),
+                arg: pg_sys::MemoryContextStrdup(pg_sys::ErrorContext, context.as_ptr()).cast(),
+            });
+            pg_sys::error_context_stack = saved;
+        }
+    }
+}
+
+impl WalDecoder {
+    /// Close the segment and free the cached pages once the decoding ends,
+    /// without waiting for the end of the query. The reader is kept for
+    /// `end_lsn`, a segment is opened again if reading resumes.
+    fn release(&mut self) {
+        let state = self.xlog_reader.as_ptr();
+        unsafe {
+            if (*state).seg.ws_file >= 0 {
+                pg_waldecoder_segment_close(state);
+                (*state).seg.ws_file = -1;
+            }
+        }
+        if let Some(path) = self.page_cache_file.take() {
+            let resume_lsn = self.summary.last_lsn.unwrap_or(self.startptr);
+            if let Err(e) = self.page_cache.save(&path, resume_lsn.into()) {
+                error!("Could not save the page cache to {path:?}: {e}");
+            }
+        }
+        self.page_cache.clear();
+    }
+
+    /// End of the last decoded record, where decoding would resume
+    pub fn end_lsn(&self) -> PgLSN {
+        PgLSN::from(self.xlog_reader.EndRecPtr)
+    }
+
+    /// Reader of the decoded WAL, positioned on the latest record read
+    pub fn xlog_reader(&self) -> &PgBox<pg_sys::XLogReaderState> {
+        &self.xlog_reader
+    }
+
+    /// Keep the decoder across transactions, its page cache spilling to a
+    /// file released with the decoder rather than at the end of the
+    /// transaction
+    pub fn keep_across_transactions(&mut self) {
+        self.page_cache.spill_across_transactions();
+    }
+
+    /// Full page images found to differ from their block on disk since the
+    /// last call
+    pub fn take_fpi_mismatches(&mut self) -> Vec<FpiMismatch> {
+        std::mem::take(&mut self.fpi_mismatches)
+    }
+
+    /// Whether the changes of the transaction were rolled back, by the abort
+    /// of the transaction or of a savepoint, as far as decoded
+    pub fn is_aborted(&self, xid: pg_sys::TransactionId) -> bool {
+        self.subxacts.is_aborted(xid)
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            peak_record_bytes: self.peak_record_bytes,
+            page_cache_bytes: self.page_cache.bytes(),
+            page_allocations: self.page_cache.stats.allocations,
+            toast_chunk_bytes: self.toast_chunks.bytes(),
+        }
+    }
+
+    /// Whether the record starts at or after the end, when the pages read
+    /// for the record crossing it past the end hold the next records
+    fn is_past_end(&self, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        let private =
+            unsafe { PgBox::from_pg(self.xlog_reader.private_data.cast::<XLogReaderPrivate>()) };
+        private.end_bound == EndBound::RecordStart
+            && private
+                .endptr
+                .is_some_and(|endptr| PgLSN::from(record.lsn) >= endptr)
+    }
+
+    /// Totals of the decoding so far
+    pub fn scan_summary(&self) -> ScanSummary {
+        ScanSummary {
+            fpw_restored: self.page_cache.stats.restored_images,
+            resume_lsn: self.summary.last_lsn.or(Some(self.startptr)),
+            ..self.summary
+        }
+    }
+
+    /// Read the next record, None once decoding stops
+    pub fn read_record(&mut self) -> Option<PgBox<pg_sys::DecodedXLogRecord>> {
+        if self.stopped {
+            return None;
+        }
         loop {
-            // Move to the next record
+            if self
+                .record_count
+                .is_some_and(|count| self.summary.records_read >= count)
+            {
+                return None;
+            }
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                warning!(
+                    "Reached the maximum runtime, decoding stopped at {}",
+                    PgLSN::from(self.xlog_reader.EndRecPtr)
+                );
+                self.stopped = true;
+                return None;
+            }
+
+            // Move to the next record, read by the server's reader as the
+            // decoders use its DecodedXLogRecord, not the core WalReader
             let mut errormsg: *mut c_char = std::ptr::null_mut();
+            let read_start = start_read();
             let record =
                 unsafe { pg_sys::XLogReadRecord(self.xlog_reader.as_ptr(), &raw mut errormsg) };
             if record.is_null() {
-                let private = unsafe {
+                let mut private = unsafe {
                     PgBox::from_pg(self.xlog_reader.private_data.cast::<XLogReaderPrivate>())
                 };
                 if private.endptr_reached {
+                    self.check_backup_end_missing();
+                    return None;
+                }
+                if let Some((fname, segno)) = private.missing_segment.take() {
+                    let stopped_at = PgLSN::from(self.xlog_reader.EndRecPtr);
+                    if private.skip_missing && self.skip_to_next_segment(&private, segno) {
+                        warning!("Segment {fname} is missing, skipped WAL from {stopped_at}");
+                        continue;
+                    }
+                    warning!("Segment {fname} is missing, decoding stopped at {stopped_at}");
+                    return None;
+                }
+                if self.xlog_reader.abortedRecPtr != InvalidXLogRecPtr {
+                    // The WAL ends in the middle of a record, it will be
+                    // overwritten with an OVERWRITE_CONTRECORD after recovery
+                    verbose!(
+                        Verbosity::Normal,
+                        "Record at {} is incomplete, its continuation at {} is missing",
+                        PgLSN::from(self.xlog_reader.abortedRecPtr),
+                        PgLSN::from(self.xlog_reader.missingContrecPtr)
+                    );
                     return None;
                 }
                 if !errormsg.is_null() {
                     let msg = unsafe { CStr::from_ptr(errormsg).to_string_lossy().into_owned() };
-                    warning!("Error getting next wal record: {msg}");
-                    // return Err(WalError::ReadRecordError(self.xlog_reader.EndRecPtr, msg));
+                    let stopped_at = PgLSN::from(self.xlog_reader.EndRecPtr);
+                    if private.partial_segment {
+                        // A partial segment ends with the last record streamed by
+                        // pg_receivewal, reaching its end is expected
+                        verbose!(Verbosity::Normal, "Reached end of partial segment, decoding stopped at {stopped_at}: {msg}");
+                        return None;
+                    }
+                    // Invalid records followed by valid ones are corrupted,
+                    // otherwise the end of the WAL was reached
+                    if self.on_error.is_some() && self.skip_to_next_page(stopped_at) {
+                        self.summary.failed_records += 1;
+                        match self.on_error {
+                            Some(OnError::Stop) => {
+                                error!("Could not read WAL at {stopped_at}: {msg}")
+                            }
+                            Some(OnError::Emit) => {
+                                self.read_failure = Some((stopped_at, msg));
+                                return None;
+                            }
+                            _ => continue,
+                        }
+                    }
+                    warning!(
+                        "Error getting next wal record, decoding stopped at {stopped_at}: {msg}"
+                    );
+                    self.check_backup_end_missing();
                     return None;
                 }
             }
 
             // Get the latest decoded record from xlog reader
             let record = unsafe { PgBox::from_pg(self.xlog_reader.record) };
+            if self.is_past_end(&record) {
+                self.stopped = true;
+                return None;
+            }
             let rmid = u32::from(record.header.xl_rmid);
+            record_read(record.header.xl_rmid, read_start);
+            self.progress.record_read(PgLSN::from(record.lsn));
+            let end_rec_ptr = self.xlog_reader.EndRecPtr;
+            self.summary.records_read += 1;
+            self.summary.bytes_scanned = end_rec_ptr.saturating_sub(self.startptr.into());
+            self.summary.last_lsn = Some(PgLSN::from(end_rec_ptr));
+
+            if rmid == RM_XLOG_ID && self.is_backup_end(&record) {
+                verbose!(
+                    Verbosity::Normal,
+                    "Reached the end of the backup started at {}, WAL is consistent at {}",
+                    self.backup_start.unwrap(),
+                    PgLSN::from(self.xlog_reader.EndRecPtr)
+                );
+                self.stopped = true;
+                return None;
+            }
+
+            if rmid == RM_XACT_ID && self.is_past_interval(&record) {
+                verbose!(
+                    Verbosity::Normal,
+                    "Reached a commit past the decoded interval, decoding stopped at {}",
+                    PgLSN::from(record.lsn)
+                );
+                self.stopped = true;
+                return None;
+            }
 
-            if rmid != RM_HEAP_ID {
+            return Some(record);
+        }
+    }
+
+    /// Read records until one can be decoded
+    fn decode_next(&mut self) -> Option<DecodedResult> {
+        if let Some(change) = self.pending_change.take() {
+            return Some(change);
+        }
+        loop {
+            let Some(record) = self.read_record() else {
+                // Reading resumes after the failure on the next call
+                let (lsn, msg) = self.read_failure.take()?;
+                return Some(DecodedResult::failed_record(lsn, &msg));
+            };
+            unsafe {
+                (*self.xlog_reader.private_data.cast::<XLogReaderPrivate>())
+                    .cost_delay
+                    .charge(RECORD_COST);
+            }
+            let rmid = u32::from(record.header.xl_rmid);
+            if rmid == RM_XLOG_ID {
+                self.xid_epoch.observe(&record);
+                self.wal_settings.observe(&record);
+                if let Some(aborted) = overwritten_contrecord(&record) {
+                    verbose!(
+                        Verbosity::Normal,
+                        "Record at {aborted} was aborted, overwritten at {}",
+                        PgLSN::from(record.lsn)
+                    );
+                    return Some(DecodedResult::aborted_record(aborted));
+                }
+            }
+
+            self.relid_cache.invalidate_for(&self.xlog_reader, &record);
+            self.subxacts.observe(&record);
+            if let Some(annotations) = &mut self.annotations {
+                annotations.observe(&record, &self.subxacts);
+            }
+            if self.include_transactions {
+                if let Some(xact_end) = xact_end(&record)
+                    .filter(|xact_end| self.open_transactions.remove(&xact_end.xid.into_inner()))
+                {
+                    let op = if xact_end.committed {
+                        "COMMIT"
+                    } else {
+                        "ABORT"
+                    };
+                    let mut row =
+                        DecodedResult::transaction_row(op, PgLSN::from(record.lsn), xact_end.xid);
+                    row.full_xid = self.xid_epoch.full_xid(xact_end.xid);
+                    row.commit_time = TimestampWithTimeZone::try_from(xact_end.time).ok();
+                    row.set_record_info(&record);
+                    return Some(row);
+                }
+            }
+            if self.check_fpis {
+                let mismatches = check_record_fpis(&self.xlog_reader, &record);
+                self.fpi_mismatches.extend(mismatches);
+            }
+
+            let mut ctx = DecodeContext {
+                xlog_reader: &self.xlog_reader,
+                page_cache: &mut self.page_cache,
+                relid_cache: &mut self.relid_cache,
+                mask_cache: &mut self.mask_cache,
+                tuple_descs: &self.tuple_descs,
+                include_other_databases: self.include_other_databases,
+                check_revert_conflicts: self.check_revert_conflicts,
+                include_new_cid: self.include_new_cid,
+                include_visible: self.include_visible,
+                include_raw_tuple: self.include_raw_tuple,
+                include_newpages: self.include_newpages,
+                include_hint_fpis: self.include_hint_fpis,
+                unqualified_names: self.unqualified_names,
+                include_toast_chunks: self.include_toast_chunks,
+                toast_chunks: &mut self.toast_chunks,
+                other_databases: self.other_databases.as_mut(),
+                on_error: self.on_error,
+            };
+            let Some(rmgr) = rmgr_decoder(rmid).filter(|rmgr| rmgr.decodes(&ctx, &record)) else {
+                if is_unsupported_record(&record) {
+                    match self.unsupported_records {
+                        UnsupportedRecords::Skip => {}
+                        UnsupportedRecords::Report => {
+                            return Some(DecodedResult::unsupported_record(
+                                &self.xlog_reader,
+                                &record,
+                            ));
+                        }
+                        UnsupportedRecords::Error => error!(
+                            "Record {} at {} of resource manager {} can't be decoded",
+                            describe_record(&record),
+                            PgLSN::from(record.lsn),
+                            rmgr_display_name(record.header.xl_rmid)
+                        ),
+                    }
+                }
                 // Move to the next record
+                self.summary.skipped_non_heap += 1;
+                continue;
+            };
+            if self
+                .relation_filter
+                .as_ref()
+                .is_some_and(|filter| !filter.matches(&self.xlog_reader, &record))
+            {
+                rmgr.stats(&mut self.summary, SkipReason::Filtered);
                 continue;
             }
+            verbose!(
+                Verbosity::Debug,
+                "Decoding {} record at {}",
+                rmgr.describe(&record),
+                PgLSN::from(record.lsn)
+            );
 
             // Switch to per record memory context
             let mut old_ctx = unsafe { self.per_record_ctx.set_as_current() };
-
-            let decoded_record = match rmid {
-                RM_HEAP_ID => decode_heap_record(&self.xlog_reader, &record, &self.page_hash),
-                _ => panic!("Unexpected record type"),
+            let decoded_record = if matches!(self.on_error, Some(OnError::Skip | OnError::Emit)) {
+                // Errors raised while decoding are caught in a subtransaction
+                let mut decoded = None;
+                try_in_subtransaction(|| decoded = Some(rmgr.decode(&mut ctx, &record)))
+                    .map(|()| decoded.unwrap())
+            } else {
+                Ok(rmgr.decode(&mut ctx, &record))
             };
+            let redo_error = (self.strict_redo && rmid == RM_HEAP_ID)
+                .then(|| self.check_replayed_pages(&record))
+                .flatten();
 
             // Clean up
             unsafe { old_ctx.set_as_current() };
+            self.peak_record_bytes = self
+                .peak_record_bytes
+                .max(context_allocated_bytes(&self.per_record_ctx));
             unsafe { self.per_record_ctx.reset() };
             pg_sys::check_for_interrupts!();
 
-            return decoded_record;
+            // Records that can't be decoded are skipped
+            match decoded_record {
+                Ok(Ok(mut decoded_record)) => {
+                    if redo_error.is_some() {
+                        decoded_record.error = redo_error;
+                    }
+                    decoded_record.full_xid = self.xid_epoch.full_xid(decoded_record.xid);
+                    decoded_record.toplevel_xid = self.subxacts.toplevel(decoded_record.xid);
+                    decoded_record.annotation = self
+                        .annotations
+                        .as_ref()
+                        .and_then(|annotations| annotations.get(decoded_record.toplevel_xid));
+                    if self.track_commit_time {
+                        decoded_record.commit_time = commit_timestamp(decoded_record.xid);
+                    }
+                    // Changes of other databases come with their names
+                    if let Some((schema_name, relation_name)) = decoded_record
+                        .relid
+                        .filter(|_| decoded_record.schema_name.is_none())
+                        .and_then(|relid| self.relation_names.get(relid))
+                    {
+                        decoded_record.schema_name = Some(schema_name);
+                        decoded_record.relation_name = Some(relation_name);
+                    }
+                    if let Some(origin) = record_origin(&record) {
+                        decoded_record.origin_id = Some(pg_sys::Oid::from(u32::from(origin)));
+                        decoded_record.origin_name = self.origin_names.get(origin);
+                    }
+                    decoded_record.set_record_info(&record);
+                    if self.include_raw_record {
+                        decoded_record.raw_record = Some(raw_record_bytes(&record));
+                    }
+                    if let Some(query_template) = &self.query_template {
+                        decoded_record.redo_query = Some(query_template.render(&decoded_record));
+                        decoded_record.revert_query = None;
+                    }
+                    if self.annotate_queries {
+                        decoded_record.annotate_queries();
+                    }
+                    if decoded_record.relation_missing {
+                        self.summary.unresolved_relids += 1;
+                    }
+                    let toplevel_xid = decoded_record.toplevel_xid;
+                    if self.include_transactions
+                        && toplevel_xid.into_inner() != 0
+                        && self.open_transactions.insert(toplevel_xid.into_inner())
+                    {
+                        let mut row = DecodedResult::transaction_row(
+                            "BEGIN",
+                            decoded_record.lsn,
+                            toplevel_xid,
+                        );
+                        row.full_xid = self.xid_epoch.full_xid(toplevel_xid);
+                        row.commit_time = decoded_record.commit_time;
+                        self.pending_change = Some(decoded_record);
+                        return Some(row);
+                    }
+                    return Some(decoded_record);
+                }
+                Ok(Err(reason)) => {
+                    // No change can go unnoticed in strict mode
+                    if self.unsupported_records == UnsupportedRecords::Error
+                        && reason.loses_change()
+                    {
+                        error!(
+                            "Record {} at {} can't be decoded: {reason:?}",
+                            rmgr.describe(&record),
+                            PgLSN::from(record.lsn)
+                        );
+                    }
+                    rmgr.stats(&mut self.summary, reason);
+                }
+                Err(e) => {
+                    // The record may be partly replayed on its pages, the
+                    // next records of their blocks wait for a full page image
+                    for page_id in record_page_ids(&self.xlog_reader, &record) {
+                        self.page_cache.remove(&page_id);
+                    }
+                    self.summary.failed_records += 1;
+                    let lsn = PgLSN::from(record.lsn);
+                    if self.on_error == Some(OnError::Emit) {
+                        return Some(DecodedResult::failed_record(lsn, &e));
+                    }
+                    verbose!(Verbosity::Normal, "Skipped record at {lsn}: {e}");
+                }
+            }
         }
-        None
     }
 }
 
 impl WalDecoder {
-    pub fn new(
-        startptr: PgLSN,
-        end_lsn: Option<&str>,
-        timeline: i32,
-        wal_dir: Option<&str>,
+    /// Compare the LSN of the pages replayed for a heap record with its end,
+    /// the mismatching pages are dropped from the cache. Returns the error
+    /// reported with the change.
+    fn check_replayed_pages(
+        &mut self,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> Option<String> {
+        let mismatches = replayed_lsn_mismatches(&self.xlog_reader, record, &self.page_cache);
+        if mismatches.is_empty() {
+            return None;
+        }
+        let end_lsn = PgLSN::from(self.xlog_reader.EndRecPtr);
+        let blocks = mismatches
+            .iter()
+            .map(|(page_id, page_lsn)| format!("block {} has LSN {page_lsn}", page_id.blknum()))
+            .collect::<Vec<_>>();
+        let message = format!(
+            "Replayed pages don't end with the record's LSN {end_lsn}: {}",
+            blocks.join(", ")
+        );
+        warning!("{message} at {}", PgLSN::from(record.lsn));
+        for (page_id, _) in &mismatches {
+            self.page_cache.remove(page_id);
+        }
+        self.summary.redo_mismatches += u64::try_from(mismatches.len()).unwrap();
+        Some(message)
+    }
+
+    /// Decode the WAL of a directory, or of a single segment file, from
+    /// `start` up to `end`, or the end of the available WAL. Without a
+    /// timeline, the latest one found in the directory is followed.
+    pub fn from_path(
+        dir: &Path,
+        start: PgLSN,
+        end: Option<PgLSN>,
+        timeline: Option<pg_sys::TimeLineID>,
     ) -> WalDecoder {
+        let Some(wal_dir) = dir.to_str() else {
+            error!("Invalid WAL path \"{}\"", dir.display());
+        };
+        let end_lsn = end.map(|end| end.to_string());
+        let options = DecoderOptions {
+            end_lsn: end_lsn.as_deref(),
+            timeline: timeline.map(pg_sys::TimeLineID::cast_signed),
+            wal_dir: Some(wal_dir),
+            ..Default::default()
+        };
+        WalDecoder::new(start, &options)
+    }
+
+    pub fn new(startptr: PgLSN, options: &DecoderOptions) -> WalDecoder {
+        check_decoder_access(options.wal_dir);
+        let exclusions = match options
+            .exclude_columns
+            .iter()
+            .map(|pattern| ColumnExclusion::try_from(*pattern))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(exclusions) => exclusions,
+            Err(e) => error!("Error: {e}"),
+        };
+        let column_types = match options
+            .column_types
+            .iter()
+            .map(|definition| RelationColumns::try_from(*definition))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(column_types) => column_types,
+            Err(e) => error!("Error: {e}"),
+        };
+        let foreign_relations = match options
+            .foreign_relations
+            .iter()
+            .map(|(relfilenumber, definition)| {
+                RelationColumns::try_from(*definition).map(|columns| (*relfilenumber, columns))
+            })
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(foreign_relations) => foreign_relations,
+            Err(e) => error!("Error: {e}"),
+        };
+        let tuple_descs = TupleDescCache::new(&column_types, &foreign_relations);
+        let relation_filter =
+            (!options.relations.is_empty()).then(|| RelationFilter::new(options.relations));
+        let query_template = match options.query_template.map(QueryTemplate::try_from) {
+            Some(Ok(query_template)) => Some(query_template),
+            Some(Err(e)) => error!("Error: {e}"),
+            None => None,
+        };
+        let other_databases = options.other_databases_conninfo.map(|conninfo| {
+            OtherDatabases::new(
+                conninfo,
+                PgMemoryContexts::For(unsafe { pg_sys::CurrentMemoryContext }),
+            )
+        });
         // Build the xlog reader
-        let xlog_reader = build_xlog_reader(startptr, end_lsn, timeline, wal_dir);
-        let mut per_record_ctx = PgMemoryContexts::new("Per decoded record");
+        let xlog_reader = build_xlog_reader(startptr, options);
+        let reader_release = register_reader_release(xlog_reader.as_ptr());
+        let endptr = unsafe { (*xlog_reader.private_data.cast::<XLogReaderPrivate>()).endptr };
+        let parent_ctx = PgMemoryContexts::For(unsafe { pg_sys::CurrentMemoryContext });
+        let per_record_ctx = create_record_context();
+
+        let page_cache_size = usize::try_from(guc::PAGE_CACHE_SIZE.get()).unwrap_or(1);
+        let page_cache = PageCache::new(
+            page_cache_size,
+            parent_ctx,
+            options.read_current_pages,
+            guc::PAGE_CACHE_SPILL.get(),
+        );
+        // The server's WAL is recent enough to share its epoch, other sources
+        // wait for a checkpoint record
+        let server_next_xid = (options.wal_dir.is_none()
+            && options.conninfo.is_none()
+            && options.wal_data.is_none()
+            && options.s3_archives.is_empty())
+        .then(|| unsafe { pg_sys::ReadNextFullTransactionId() }.value);
+        let page_cache_file = match options.page_cache_file.map(saved_cache_path) {
+            Some(Ok(path)) => {
+                check_write_access();
+                Some(path)
+            }
+            Some(Err(e)) => error!("Error: {e}"),
+            None => None,
+        };
+        // Built before any error can be raised so the reader is released
+        // when unwinding
+        reset_timings();
+        let mut wal_decoder = WalDecoder {
+            xlog_reader,
+            reader_release,
+            startptr,
+            per_record_ctx,
+            peak_record_bytes: 0,
+            page_cache,
+            relid_cache: RelidCache::new(),
+            relation_names: RelationNameCache::default(),
+            origin_names: OriginNameCache::default(),
+            mask_cache: MaskCache::new(exclusions),
+            tuple_descs,
+            relation_filter,
+            query_template,
+            progress: Progress::new(startptr, endptr),
+            summary: ScanSummary::default(),
+            xid_epoch: XidEpoch::new(server_next_xid),
+            wal_settings: WalSettings::default(),
+            subxacts: SubxactTree::default(),
+            track_commit_time: server_next_xid.is_some()
+                && unsafe { pg_sys::track_commit_timestamp },
+            backup_start: options.backup_start,
+            interval: options.for_interval,
+            interval_end: None,
+            record_count: options.record_count,
+            max_rows: u64::try_from(guc::MAX_ROWS.get())
+                .ok()
+                .filter(|max_rows| *max_rows > 0),
+            rows_returned: 0,
+            deadline: decoding_deadline(options.max_runtime, options.stop_before_timeout),
+            check_fpis: options.check_fpis,
+            strict_redo: options.strict_redo,
+            include_raw_record: options.include_raw_record,
+            include_raw_tuple: options.include_raw_tuple,
+            check_revert_conflicts: options.check_revert_conflicts,
+            fpi_mismatches: Vec::new(),
+            stopped: false,
+            include_other_databases: options.include_other_databases
+                || options.other_databases_conninfo.is_some(),
+            include_new_cid: options.include_new_cid,
+            include_visible: options.include_visible,
+            include_newpages: options.include_newpages,
+            include_hint_fpis: options.include_hint_fpis,
+            unqualified_names: options.unqualified_names,
+            annotate_queries: options.annotate_queries,
+            other_databases,
+            include_transactions: options.include_transactions,
+            open_transactions: HashSet::new(),
+            pending_change: None,
+            include_toast_chunks: options.include_toast_chunks,
+            toast_chunks: ToastChunks::default(),
+            annotations: options.annotation_prefix.map(Annotations::new),
+            page_cache_file,
+            unsupported_records: options
+                .unsupported_records
+                .unwrap_or_else(|| guc::UNSUPPORTED_RECORDS.get()),
+            on_error: options.on_error,
+            read_failure: None,
+        };
+
+        if let Some(path) = &wal_decoder.page_cache_file {
+            match wal_decoder.page_cache.load(path, startptr.into()) {
+                Ok(pages) => verbose!(Verbosity::Normal, "Loaded {pages} pages from {path:?}"),
+                Err(e) => error!("Could not load the page cache from {path:?}: {e}"),
+            }
+        }
 
         // Check we have can find valid wal files
-        let first_record =
-            unsafe { pg_sys::XLogFindNextRecord(xlog_reader.as_ptr(), startptr.into()) };
+        let first_record = unsafe {
+            pg_sys::XLogFindNextRecord(wal_decoder.xlog_reader.as_ptr(), startptr.into())
+        };
         if first_record == u64::from(InvalidXLogRecPtr) {
-            error!("could not find a valid record after {}", startptr);
+            wal_decoder.report_no_record(startptr);
         }
+        wal_decoder
+    }
 
-        let page_hash = HashMap::new();
-        WalDecoder {
-            xlog_reader,
-            startptr,
-            per_record_ctx,
-            page_hash,
+    /// Raise the error of a start without a valid record after it, with the
+    /// segments inspected and the nearest record of the WAL dirs
+    fn report_no_record(&self, startptr: PgLSN) -> ! {
+        let mut message = format!("Could not find a valid record after {startptr}");
+        // Why the reader rejected the pages, like the WAL of another system
+        let errormsg = unsafe { CStr::from_ptr(self.xlog_reader.errormsg_buf) };
+        if !errormsg.is_empty() {
+            message = format!("{message}: {}", errormsg.to_string_lossy());
+        }
+        let private =
+            unsafe { PgBox::from_pg(self.xlog_reader.private_data.cast::<XLogReaderPrivate>()) };
+        if private.remote.is_some() || private.buffer.is_some() {
+            error!("{message}");
+        }
+        let segsz = self.xlog_reader.segcxt.ws_segsize.cast_unsigned();
+        let timelines: Vec<_> = private
+            .timeline_history
+            .iter()
+            .map(|entry| entry.tli)
+            .chain(private.timelines.iter().copied())
+            .map(u64::from)
+            .collect();
+        let (detail, hint) = no_record_diagnostics(&private.source, segsz, &timelines, startptr);
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_UNDEFINED_FILE,
+            message,
+            function_name!(),
+        )
+        .set_detail(detail)
+        .set_hint(hint)
+        .report(PgLogLevel::ERROR);
+        unreachable!()
+    }
+
+    /// Notice of a scan that returned no changes, with what was skipped
+    fn report_no_changes(&self) {
+        let summary = &self.summary;
+        ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+            format!(
+                "No changes decoded from {} to {}",
+                self.startptr,
+                self.end_lsn()
+            ),
+            function_name!(),
+        )
+        .set_detail(format!(
+            "{} records read, {} of other resource managers, {} without a page, {} without a row change or filtered out, {} failed.",
+            summary.records_read,
+            summary.skipped_non_heap,
+            summary.skipped_no_page,
+            summary.skipped_other,
+            summary.failed_records
+        ))
+        .set_hint(summary.no_changes_hint())
+        .report(PgLogLevel::NOTICE);
+    }
+
+    /// Returns true if the record marks the end of the decoded base backup
+    fn is_backup_end(&self, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        let Some(backup_start) = self.backup_start else {
+            return false;
+        };
+        let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+        if info != pg_sys::XLOG_BACKUP_END || record.main_data.is_null() {
+            return false;
+        }
+        // The record's main data is the start of the backup it ends
+        let startpoint =
+            unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::XLogRecPtr>()) };
+        PgLSN::from(startpoint) == backup_start
+    }
+
+    /// Whether a commit record is past the decoded interval, which starts at
+    /// the first commit
+    fn is_past_interval(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        let (Some(interval), Some(commit_time)) = (self.interval, xact_commit_time(record)) else {
+            return false;
+        };
+        let interval_end = *self
+            .interval_end
+            .get_or_insert(commit_time.saturating_add(interval));
+        commit_time > interval_end
+    }
+
+    /// Warn when the WAL ended before the decoded base backup was consistent
+    fn check_backup_end_missing(&self) {
+        if let Some(backup_start) = self.backup_start {
+            warning!(
+                "WAL ended at {} before the end of the backup started at {}, the backup's WAL is incomplete",
+                PgLSN::from(self.xlog_reader.EndRecPtr),
+                backup_start
+            );
+        }
+    }
+
+    /// Move the reader to the first record starting after the page of an
+    /// invalid record.
+    ///
+    /// Returns false if no valid record follows, at the end of the WAL.
+    fn skip_to_next_page(&mut self, invalid_at: PgLSN) -> bool {
+        let blcksz = u64::from(pg_sys::XLOG_BLCKSZ);
+        let next_page = (u64::from(invalid_at) / blcksz + 1) * blcksz;
+        let next_record =
+            unsafe { pg_sys::XLogFindNextRecord(self.xlog_reader.as_ptr(), next_page) };
+        if next_record == u64::from(InvalidXLogRecPtr) {
+            return false;
+        }
+        verbose!(
+            Verbosity::Normal,
+            "Skipped invalid record at {invalid_at}, resuming decoding at {}",
+            PgLSN::from(next_record)
+        );
+        true
+    }
+
+    /// Move the reader to the first record of the next available segment
+    /// after a gap.
+    ///
+    /// Returns false if no later segment could be found.
+    fn skip_to_next_segment(
+        &mut self,
+        private: &XLogReaderPrivate,
+        missing_segno: pg_sys::XLogSegNo,
+    ) -> bool {
+        let segsz = self.xlog_reader.segcxt.ws_segsize.cast_unsigned();
+        let timelines = private
+            .timeline_history
+            .iter()
+            .map(|e| e.tli)
+            .chain(private.timelines.iter().copied())
+            .collect::<Vec<_>>();
+        let Some(segno) = next_available_segment(&private.source, segsz, &timelines, missing_segno)
+        else {
+            return false;
+        };
+        let segment_start = segno * u64::from(segsz);
+        let next_record =
+            unsafe { pg_sys::XLogFindNextRecord(self.xlog_reader.as_ptr(), segment_start) };
+        if next_record == u64::from(InvalidXLogRecPtr) {
+            return false;
         }
+        verbose!(
+            Verbosity::Normal,
+            "Resuming decoding at {}",
+            PgLSN::from(next_record)
+        );
+        true
     }
 }
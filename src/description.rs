@@ -0,0 +1,342 @@
+use std::mem::offset_of;
+
+use pgrx::{pg_sys, prelude::*, JsonString, PgBox, TimestampWithTimeZone};
+
+use crate::{
+    decoder::{DecoderOptions, WalDecoder},
+    horizon::record_conflict_horizon,
+    notify::json_string,
+    pg_lsn::PgLSN,
+    walinspect::{record_description, record_type_name, rmgr_display_name},
+    xlog_heap::heap_op,
+};
+
+extension_sql!(
+    r"
+CREATE FUNCTION pg_waldecoder_describe(
+    start_lsn text,
+    end_lsn text DEFAULT NULL,
+    timeline int DEFAULT NULL,
+    wal_dir text DEFAULT NULL
+) RETURNS TABLE (
+    start_lsn pg_lsn,
+    resource_manager text,
+    record_type text,
+    description jsonb
+)
+LANGUAGE sql
+AS $$
+    SELECT start_lsn, resource_manager, record_type, description::jsonb
+    FROM pg_waldecoder_describe_json(start_lsn, end_lsn, timeline, wal_dir)
+$$;
+",
+    name = "pg_waldecoder_describe",
+    requires = [pg_waldecoder_describe_json],
+);
+
+/// Fields of a JSON object, in insertion order
+#[derive(Default)]
+struct JsonObject {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl JsonObject {
+    fn number(mut self, name: &'static str, value: impl Into<u64>) -> Self {
+        self.fields.push((name, value.into().to_string()));
+        self
+    }
+
+    fn boolean(mut self, name: &'static str, value: bool) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    fn string(mut self, name: &'static str, value: &str) -> Self {
+        self.fields.push((name, json_string(Some(value))));
+        self
+    }
+
+    fn numbers(mut self, name: &'static str, values: &[u32]) -> Self {
+        let values = values.iter().map(u32::to_string).collect::<Vec<_>>();
+        self.fields.push((name, format!("[{}]", values.join(","))));
+        self
+    }
+
+    fn render(&self) -> String {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(name, value)| format!("{}:{value}", json_string(Some(name))))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// Main data of a record read as `T`, None if it's too short
+fn main_data<T>(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<T> {
+    if record.main_data.is_null() || (record.main_data_len as usize) < size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(record.main_data.cast::<T>()) })
+}
+
+fn describe_heap(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<JsonObject> {
+    let json = JsonObject::default();
+    match heap_op(record) {
+        pg_sys::XLOG_HEAP_INSERT => {
+            let xlrec = main_data::<pg_sys::xl_heap_insert>(record)?;
+            Some(
+                json.number("off", xlrec.offnum)
+                    .number("flags", xlrec.flags),
+            )
+        }
+        pg_sys::XLOG_HEAP_DELETE => {
+            let xlrec = main_data::<pg_sys::xl_heap_delete>(record)?;
+            Some(
+                json.number("xmax", xlrec.xmax.into_inner())
+                    .number("off", xlrec.offnum)
+                    .number("infobits", xlrec.infobits_set)
+                    .number("flags", xlrec.flags),
+            )
+        }
+        pg_sys::XLOG_HEAP_UPDATE | pg_sys::XLOG_HEAP_HOT_UPDATE => {
+            let xlrec = main_data::<pg_sys::xl_heap_update>(record)?;
+            Some(
+                json.number("old_xmax", xlrec.old_xmax.into_inner())
+                    .number("old_off", xlrec.old_offnum)
+                    .number("old_infobits", xlrec.old_infobits_set)
+                    .number("flags", xlrec.flags)
+                    .number("new_xmax", xlrec.new_xmax.into_inner())
+                    .number("new_off", xlrec.new_offnum),
+            )
+        }
+        pg_sys::XLOG_HEAP_LOCK => {
+            let xlrec = main_data::<pg_sys::xl_heap_lock>(record)?;
+            Some(
+                json.number("xmax", xlrec.xmax.into_inner())
+                    .number("off", xlrec.offnum)
+                    .number("infobits", xlrec.infobits_set)
+                    .number("flags", xlrec.flags),
+            )
+        }
+        _ => None,
+    }
+}
+
+fn describe_xlog(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<JsonObject> {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if info != pg_sys::XLOG_CHECKPOINT_SHUTDOWN && info != pg_sys::XLOG_CHECKPOINT_ONLINE {
+        return None;
+    }
+    let checkpoint = main_data::<pg_sys::CheckPoint>(record)?;
+    Some(
+        JsonObject::default()
+            .string("redo", &PgLSN::from(checkpoint.redo).to_string())
+            .number("tli", checkpoint.ThisTimeLineID)
+            .number("prev_tli", checkpoint.PrevTimeLineID)
+            .boolean("fpw", checkpoint.fullPageWrites)
+            .number("wal_level", checkpoint.wal_level.cast_unsigned())
+            .number("next_xid", checkpoint.nextXid.value)
+            .number("next_oid", checkpoint.nextOid.to_u32())
+            .number("next_multi", checkpoint.nextMulti)
+            .number("next_multi_offset", checkpoint.nextMultiOffset)
+            .number("oldest_xid", checkpoint.oldestXid.into_inner())
+            .number("oldest_xid_db", checkpoint.oldestXidDB.to_u32())
+            .number("oldest_multi", checkpoint.oldestMulti)
+            .number("oldest_multi_db", checkpoint.oldestMultiDB.to_u32())
+            .number(
+                "oldest_commit_ts_xid",
+                checkpoint.oldestCommitTsXid.into_inner(),
+            )
+            .number(
+                "newest_commit_ts_xid",
+                checkpoint.newestCommitTsXid.into_inner(),
+            )
+            .number(
+                "oldest_running_xid",
+                checkpoint.oldestActiveXid.into_inner(),
+            )
+            .boolean("online", info == pg_sys::XLOG_CHECKPOINT_ONLINE),
+    )
+}
+
+fn describe_standby(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<JsonObject> {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if info != pg_sys::XLOG_RUNNING_XACTS {
+        return None;
+    }
+    let xlrec = main_data::<pg_sys::xl_running_xacts>(record)?;
+    // The xids of the running transactions follow the fixed fields,
+    // subtransactions last
+    let xcnt = usize::try_from(xlrec.xcnt).ok()?;
+    let subxcnt = usize::try_from(xlrec.subxcnt).ok()?;
+    let xids_offset = offset_of!(pg_sys::xl_running_xacts, xids);
+    if (record.main_data_len as usize) < xids_offset + (xcnt + subxcnt) * size_of::<u32>() {
+        return None;
+    }
+    let xids: Vec<u32> = (0..xcnt + subxcnt)
+        .map(|i| unsafe {
+            std::ptr::read_unaligned(
+                record
+                    .main_data
+                    .add(xids_offset + i * size_of::<u32>())
+                    .cast::<u32>(),
+            )
+        })
+        .collect();
+    Some(
+        JsonObject::default()
+            .number("next_xid", xlrec.nextXid.into_inner())
+            .number(
+                "latest_completed_xid",
+                xlrec.latestCompletedXid.into_inner(),
+            )
+            .number("oldest_running_xid", xlrec.oldestRunningXid.into_inner())
+            .numbers("xids", &xids[..xcnt])
+            .numbers("subxids", &xids[xcnt..])
+            .boolean("subxid_overflow", xlrec.subxid_overflow),
+    )
+}
+
+fn describe_xact(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<JsonObject> {
+    let info = u32::from(record.header.xl_info) & pg_sys::XLOG_XACT_OPMASK;
+    // Commit and abort records both start with the transaction's time
+    if !matches!(
+        info,
+        pg_sys::XLOG_XACT_COMMIT
+            | pg_sys::XLOG_XACT_ABORT
+            | pg_sys::XLOG_XACT_COMMIT_PREPARED
+            | pg_sys::XLOG_XACT_ABORT_PREPARED
+    ) {
+        return None;
+    }
+    let xlrec = main_data::<pg_sys::xl_xact_commit>(record)?;
+    let xact_time = TimestampWithTimeZone::try_from(xlrec.xact_time).ok()?;
+    Some(JsonObject::default().string("xact_time", &xact_time.to_string()))
+}
+
+/// Fields of a record as a JSON object. Only the heap changes and locks,
+/// checkpoints, running transactions and transaction ends have structured
+/// fields, the records of the other resource managers have their `rm_desc`
+/// text as `desc` and nothing else but their conflict horizon.
+fn describe_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> String {
+    let described = match u32::from(record.header.xl_rmid) {
+        pg_sys::RmgrIds::RM_HEAP_ID => describe_heap(record),
+        pg_sys::RmgrIds::RM_XLOG_ID => describe_xlog(record),
+        pg_sys::RmgrIds::RM_STANDBY_ID => describe_standby(record),
+        pg_sys::RmgrIds::RM_XACT_ID => describe_xact(record),
+        _ => None,
+    };
+    let described = described.unwrap_or_else(|| {
+        let desc = record_description(xlog_reader, record.header.xl_rmid);
+        JsonObject::default().string("desc", desc.as_deref().unwrap_or_default())
+    });
+    // Explains the cancellations of standby queries replaying the record
+    match record_conflict_horizon(record) {
+        Some(horizon) => described
+            .number("snapshot_conflict_horizon", horizon.xid)
+            .boolean("is_catalog_rel", horizon.catalog)
+            .render(),
+        None => described.render(),
+    }
+}
+
+/// Structured description of each record from `start_lsn`, wrapped by
+/// `pg_waldecoder_describe` returning it as jsonb. Records of resource
+/// managers other than Heap, XLOG, Standby and Transaction are described by
+/// their `rm_desc` text only.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_describe_json(
+    start_lsn: &str,
+    end_lsn: Option<&str>,
+    timeline: Option<i32>,
+    wal_dir: Option<&str>,
+) -> TableIterator<
+    'static,
+    (
+        name!(start_lsn, PgLSN),
+        name!(resource_manager, String),
+        name!(record_type, String),
+        name!(description, JsonString),
+    ),
+> {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    TableIterator::new(std::iter::from_fn(move || {
+        let record = wal_decoder.read_record()?;
+        Some((
+            PgLSN::from(record.lsn),
+            rmgr_display_name(record.header.xl_rmid),
+            record_type_name(record.header.xl_rmid, record.header.xl_info),
+            JsonString(describe_record(wal_decoder.xlog_reader(), &record)),
+        ))
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{description::JsonObject, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_json_object() {
+        let json = JsonObject::default()
+            .number("off", 12u16)
+            .boolean("fpw", true)
+            .string("redo", "0/1000028")
+            .numbers("xids", &[750, 751]);
+        assert_eq!(
+            json.render(),
+            r#"{"off":12,"fpw":true,"redo":"0/1000028","xids":[750,751]}"#
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_describe() {
+        let create_start = unsafe {
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+            PgLSN::from(pg_sys::GetXLogWriteRecPtr())
+        };
+        unsafe {
+            Spi::run("CREATE TABLE test_describe (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_describe values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let off = Spi::get_one::<i32>(&format!(
+            "SELECT (description->>'off')::int FROM pg_waldecoder_describe('{startptr}', '{endptr}', 1)
+             WHERE resource_manager = 'Heap'"
+        ));
+        assert_eq!(off, Ok(Some(1)));
+
+        // The catalog updates of the creation log Storage and Btree records,
+        // which only have their text description
+        let keys = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(DISTINCT k, ',') FROM pg_waldecoder_describe('{create_start}', '{endptr}', 1),
+                 jsonb_object_keys(description) k
+             WHERE resource_manager NOT IN ('Heap', 'XLOG', 'Standby', 'Transaction')
+                 AND description->>'snapshot_conflict_horizon' IS NULL"
+        ));
+        assert_eq!(keys, Ok(Some("desc".to_string())));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use pgrx::prelude::*;
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    pg_lsn::PgLSN,
+};
+
+/// What makes two changes of different timelines the same change
+type ChangeKey = (
+    Option<String>,
+    Option<String>,
+    String,
+    Option<String>,
+    Option<String>,
+);
+
+fn change_key(change: &DecodedResult) -> ChangeKey {
+    (
+        change.schema_name.clone(),
+        change.relation_name.clone(),
+        change.op.trim_end_matches("+INIT").to_string(),
+        change.row_before.clone(),
+        change.row_after.clone(),
+    )
+}
+
+/// Changes of a timeline, counted to match the same change written on both
+/// timelines only once
+#[derive(Default)]
+struct ChangeSet {
+    counts: HashMap<ChangeKey, usize>,
+}
+
+impl ChangeSet {
+    fn insert(&mut self, key: ChangeKey) {
+        *self.counts.entry(key).or_default() += 1;
+    }
+
+    /// Remove one occurrence of the change, false if there's none left
+    fn take(&mut self, key: &ChangeKey) -> bool {
+        match self.counts.get_mut(key) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Changes written past `switchpoint` on an abandoned timeline, e.g. by an old
+/// primary after a failover, that weren't written again on the surviving
+/// timeline. Both timelines are decoded from the switchpoint, the abandoned
+/// one is read from `abandoned_wal_dir` and the surviving one from the
+/// server's WAL unless `surviving_wal_dir` is given. Without a timeline, the
+/// latest found in each WAL dir is used.
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_timeline_diff(
+    switchpoint: &str,
+    abandoned_wal_dir: &str,
+    abandoned_timeline: default!(Option<i32>, "NULL"),
+    surviving_wal_dir: default!(Option<&str>, "NULL"),
+    surviving_timeline: default!(Option<i32>, "NULL"),
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    let startptr = match PgLSN::try_from(switchpoint) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+
+    let surviving_options = DecoderOptions {
+        timeline: surviving_timeline,
+        wal_dir: surviving_wal_dir,
+        ..Default::default()
+    };
+    let mut surviving = ChangeSet::default();
+    for change in WalDecoder::new(startptr, &surviving_options) {
+        surviving.insert(change_key(&change));
+    }
+    verbose!(
+        Verbosity::Normal,
+        "Found {} distinct changes on the surviving timeline",
+        surviving.counts.len()
+    );
+
+    let abandoned_options = DecoderOptions {
+        timeline: abandoned_timeline,
+        wal_dir: Some(abandoned_wal_dir),
+        ..Default::default()
+    };
+    let abandoned = WalDecoder::new(startptr, &abandoned_options);
+    SetOfIterator::new(
+        abandoned
+            .filter(move |change| !surviving.take(&change_key(change)))
+            .map(DecodedResult::into_change),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::divergence::ChangeSet;
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_change_set() {
+        let key = (
+            Some("public".to_string()),
+            Some("t".to_string()),
+            "INSERT".to_string(),
+            None,
+            Some("(1)".to_string()),
+        );
+        let mut changes = ChangeSet::default();
+        changes.insert(key.clone());
+        assert!(changes.take(&key));
+        // A change of the surviving timeline matches a single abandoned change
+        assert!(!changes.take(&key));
+    }
+}
@@ -0,0 +1,637 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+};
+
+use pgrx::prelude::*;
+
+use crate::{
+    access::{check_execute_access, check_write_access},
+    debezium::debezium_event,
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    notify::json_string,
+    output::{write_changes, OutputSink},
+    pg_lsn::PgLSN,
+    s3::hex_encode,
+    sink::SINK_COLUMNS,
+};
+
+/// Format of the exported file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    /// A JSON object per line
+    Ndjson,
+    /// A header line followed by a line per change
+    Csv,
+    /// A Debezium change event per line, for the row changes only
+    Debezium,
+}
+
+impl TryFrom<&str> for ExportFormat {
+    type Error = String;
+
+    fn try_from(format: &str) -> Result<Self, Self::Error> {
+        match format {
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "csv" => Ok(ExportFormat::Csv),
+            "debezium" => Ok(ExportFormat::Debezium),
+            _ => Err(format!("Unknown export format '{format}'")),
+        }
+    }
+}
+
+/// Value of an exported column
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Field {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Text(String),
+}
+
+impl<T: Into<Field>> From<Option<T>> for Field {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Field::Null, Into::into)
+    }
+}
+
+impl From<String> for Field {
+    fn from(value: String) -> Self {
+        Field::Text(value)
+    }
+}
+
+impl From<pg_sys::Oid> for Field {
+    fn from(value: pg_sys::Oid) -> Self {
+        Field::Number(u64::from(value.to_u32()))
+    }
+}
+
+/// Item pointer in `tid` format, `(block,offset)`
+fn tid_text(tid: pg_sys::ItemPointerData) -> String {
+    let blknum = (u32::from(tid.ip_blkid.bi_hi) << 16) | u32::from(tid.ip_blkid.bi_lo);
+    format!("({blknum},{})", tid.ip_posid)
+}
+
+/// Columns of a change, in the order of `SINK_COLUMNS`
+fn change_fields(change: DecodedResult) -> [(&'static str, Field); 39] {
+    [
+        ("lsn", change.lsn.to_string().into()),
+        ("dboid", change.dboid.into()),
+        ("relid", change.relid.into()),
+        ("spcoid", change.spcoid.into()),
+        ("relfilenumber", change.relfilenumber.into()),
+        ("relation_missing", Field::Bool(change.relation_missing)),
+        (
+            "xid",
+            change.full_xid.map(|xid| Field::Number(xid.0)).into(),
+        ),
+        ("op", change.op.into()),
+        ("redo_query", change.redo_query.into()),
+        ("revert_query", change.revert_query.into()),
+        ("row_before", change.row_before.into()),
+        ("row_after", change.row_after.into()),
+        ("raw_xid", Field::Number(u64::from(change.xid.into_inner()))),
+        (
+            "commit_time",
+            change.commit_time.map(|t| t.to_string()).into(),
+        ),
+        ("schema_name", change.schema_name.into()),
+        ("relation_name", change.relation_name.into()),
+        ("origin_id", change.origin_id.into()),
+        ("origin_name", change.origin_name.into()),
+        (
+            "raw_record",
+            change
+                .raw_record
+                .map(|bytes| format!("\\x{}", hex_encode(&bytes)))
+                .into(),
+        ),
+        (
+            "toplevel_xid",
+            Field::Number(u64::from(change.toplevel_xid.into_inner())),
+        ),
+        ("changes", change.changes.map(|changes| changes.0).into()),
+        ("error", change.error.into()),
+        ("old_ctid", change.old_ctid.map(tid_text).into()),
+        ("new_ctid", change.new_ctid.map(tid_text).into()),
+        (
+            "forknum",
+            change
+                .forknum
+                .map(|forknum| Field::Number(u64::from(forknum.cast_unsigned())))
+                .into(),
+        ),
+        (
+            "blkno",
+            change
+                .blkno
+                .map(|blkno| Field::Number(blkno.cast_unsigned()))
+                .into(),
+        ),
+        (
+            "schema_mismatch",
+            change.schema_mismatch.map(Field::Bool).into(),
+        ),
+        ("provenance", change.provenance.into()),
+        (
+            "raw_tuple",
+            change
+                .raw_tuple
+                .map(|bytes| format!("\\x{}", hex_encode(&bytes)))
+                .into(),
+        ),
+        (
+            "infomask",
+            change
+                .infomask
+                .map(|flags| format!("{{{}}}", flags.join(",")))
+                .into(),
+        ),
+        (
+            "xmin",
+            change
+                .xmin
+                .map(|xmin| Field::Number(u64::from(xmin.into_inner())))
+                .into(),
+        ),
+        (
+            "xmax",
+            change
+                .xmax
+                .map(|xmax| Field::Number(u64::from(xmax.into_inner())))
+                .into(),
+        ),
+        ("persistence", change.persistence.into()),
+        (
+            "cid",
+            change
+                .cid
+                .map(|cid| Field::Number(cid.cast_unsigned()))
+                .into(),
+        ),
+        ("query_fingerprint", change.query_fingerprint.into()),
+        (
+            "prev_lsn",
+            change.prev_lsn.map(|prev_lsn| prev_lsn.to_string()).into(),
+        ),
+        (
+            "record_length",
+            change
+                .record_length
+                .map(|length| Field::Number(u64::from(length.cast_unsigned())))
+                .into(),
+        ),
+        (
+            "fpi_length",
+            change
+                .fpi_length
+                .map(|length| Field::Number(u64::from(length.cast_unsigned())))
+                .into(),
+        ),
+        ("annotation", change.annotation.into()),
+    ]
+}
+
+fn json_line(fields: &[(&str, Field)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(name, field)| {
+            let value = match field {
+                Field::Null => "null".to_string(),
+                Field::Bool(value) => value.to_string(),
+                Field::Number(value) => value.to_string(),
+                Field::Text(value) => json_string(Some(value)),
+            };
+            format!("{}:{value}", json_string(Some(name)))
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Render a CSV value like COPY does, NULLs are left empty and empty strings
+/// are quoted
+fn csv_value(value: &str) -> String {
+    if value.is_empty() || value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_line(fields: &[(&str, Field)]) -> String {
+    fields
+        .iter()
+        .map(|(_, field)| match field {
+            Field::Null => String::new(),
+            Field::Bool(value) => if *value { "t" } else { "f" }.to_string(),
+            Field::Number(value) => value.to_string(),
+            Field::Text(value) => csv_value(value),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Line of a change in the format, None when the format skips the change
+fn format_line(format: ExportFormat, change: DecodedResult) -> Option<String> {
+    match format {
+        ExportFormat::Ndjson => Some(json_line(&change_fields(change))),
+        ExportFormat::Csv => Some(csv_line(&change_fields(change))),
+        ExportFormat::Debezium => debezium_event(&change),
+    }
+}
+
+/// Replace the placeholders of an `archive_command`-like template: `%s` and
+/// `%e` by the LSNs of the first and last changes of the batch, `%n` by the
+/// number of changes and `%%` by a `%`
+fn batch_command(template: &str, first: PgLSN, last: PgLSN, count: usize) -> String {
+    let mut command = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            command.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => command.push_str(&first.to_string()),
+            Some('e') => command.push_str(&last.to_string()),
+            Some('n') => command.push_str(&count.to_string()),
+            Some('%') => command.push('%'),
+            Some(other) => {
+                command.push('%');
+                command.push(other);
+            }
+            None => command.push('%'),
+        }
+    }
+    command
+}
+
+/// Wait for the command to exit, killing it when the query is cancelled
+fn wait_child(child: &mut Child) -> std::io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        unsafe {
+            pg_sys::WaitLatch(
+                pg_sys::MyLatch,
+                (pg_sys::WL_EXIT_ON_PM_DEATH | pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT)
+                    .cast_signed(),
+                10,
+                pg_sys::PG_WAIT_EXTENSION,
+            );
+            pg_sys::ResetLatch(pg_sys::MyLatch);
+            if std::ptr::read_volatile(&raw const pg_sys::QueryCancelPending) != 0
+                || std::ptr::read_volatile(&raw const pg_sys::ProcDiePending) != 0
+            {
+                // The error raised by the interrupt doesn't drop the child
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+        pg_sys::check_for_interrupts!();
+    }
+}
+
+/// Run the command with a shell, writing the lines to its stdin
+fn run_command(command: &str, lines: &[String]) -> Result<(), String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let mut stdin = BufWriter::new(child.stdin.take().unwrap());
+    let written = lines
+        .iter()
+        .try_for_each(|line| writeln!(stdin, "{line}"))
+        .and_then(|()| stdin.flush());
+    // Close stdin so the command sees the end of the batch
+    drop(stdin);
+    let status = wait_child(&mut child).map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("command exited with {status}"));
+    }
+    written.map_err(|e| e.to_string())
+}
+
+/// Write a line per change in a file of the server
+struct FileSink<'a> {
+    path: &'a str,
+    format: ExportFormat,
+    writer: BufWriter<File>,
+    written: usize,
+}
+
+impl<'a> FileSink<'a> {
+    fn create(path: &'a str, format: ExportFormat) -> FileSink<'a> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(e) => error!("Could not create file \"{path}\": {e}"),
+        };
+        let mut sink = FileSink {
+            path,
+            format,
+            writer: BufWriter::new(file),
+            written: 0,
+        };
+        if format == ExportFormat::Csv {
+            sink.write_line(&SINK_COLUMNS.join(","));
+        }
+        sink
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.writer, "{line}") {
+            error!("Could not write to file \"{}\": {e}", self.path);
+        }
+    }
+}
+
+impl OutputSink for FileSink<'_> {
+    fn write(&mut self, change: DecodedResult) {
+        if let Some(line) = format_line(self.format, change) {
+            self.write_line(&line);
+            self.written += 1;
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Could not write to file \"{}\": {e}", self.path);
+        }
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+}
+
+/// Pipe the lines of the changes to a command, a run per batch
+struct CommandSink<'a> {
+    template: &'a str,
+    format: ExportFormat,
+    batch_size: usize,
+    batch: Vec<(PgLSN, String)>,
+    written: usize,
+}
+
+impl CommandSink<'_> {
+    fn run_batch(&mut self) {
+        let (Some((first, _)), Some((last, _))) = (self.batch.first(), self.batch.last()) else {
+            return;
+        };
+        let count = self.batch.len();
+        let command = batch_command(self.template, *first, *last, count);
+        // Each run gets a complete CSV file
+        let header = (self.format == ExportFormat::Csv).then(|| SINK_COLUMNS.join(","));
+        let lines: Vec<_> = header
+            .into_iter()
+            .chain(self.batch.drain(..).map(|(_, line)| line))
+            .collect();
+        if let Err(e) = run_command(&command, &lines) {
+            error!("Could not pipe changes to \"{command}\": {e}");
+        }
+        self.written += count;
+    }
+}
+
+impl OutputSink for CommandSink<'_> {
+    fn write(&mut self, change: DecodedResult) {
+        let lsn = change.lsn;
+        if let Some(line) = format_line(self.format, change) {
+            self.batch.push((lsn, line));
+        }
+        if self.batch.len() == self.batch_size {
+            self.run_batch();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.run_batch();
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+}
+
+/// Write the changes decoded from `start_lsn` to a file of the server, as
+/// `ndjson`, `csv` or `debezium`, instead of returning them. Returns the
+/// number of changes written.
+#[pg_extern]
+fn pg_waldecoder_to_file(
+    start_lsn: &str,
+    end_lsn: Option<&str>,
+    path: &str,
+    format: default!(&str, "'ndjson'"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> i64 {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let format = match ExportFormat::try_from(format) {
+        Ok(format) => format,
+        Err(e) => error!("Error: {e}"),
+    };
+    check_write_access();
+
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    let written = write_changes(wal_decoder, &mut FileSink::create(path, format));
+    verbose!(Verbosity::Normal, "Wrote {written} changes in {path}");
+    i64::try_from(written).unwrap_or(i64::MAX)
+}
+
+/// Pipe the changes decoded from `start_lsn` to a command run by a shell, a
+/// run per batch of `batch_size` changes, e.g. to hand them to a log shipper
+/// without going through a file. The command is a template like
+/// `archive_command`, `%s` and `%e` are replaced by the LSNs of the first and
+/// last changes of the batch and `%n` by their number. Returns the number of
+/// changes written.
+#[pg_extern]
+fn pg_waldecoder_to_command(
+    start_lsn: &str,
+    end_lsn: Option<&str>,
+    command: &str,
+    format: default!(&str, "'ndjson'"),
+    batch_size: default!(i32, 1000),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> i64 {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let format = match ExportFormat::try_from(format) {
+        Ok(format) => format,
+        Err(e) => error!("Error: {e}"),
+    };
+    let Some(batch_size) = usize::try_from(batch_size).ok().filter(|size| *size > 0) else {
+        error!("Error: batch_size must be positive");
+    };
+    check_execute_access();
+
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    let mut sink = CommandSink {
+        template: command,
+        format,
+        batch_size,
+        batch: Vec::with_capacity(batch_size),
+        written: 0,
+    };
+    let written = write_changes(wal_decoder, &mut sink);
+    verbose!(Verbosity::Normal, "Piped {written} changes to {command}");
+    i64::try_from(written).unwrap_or(i64::MAX)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        export::{batch_command, csv_line, csv_value, json_line, tid_text, ExportFormat, Field},
+        pg_lsn::PgLSN,
+    };
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_export_lines() {
+        let fields = [
+            ("lsn", Field::Text("0/2A".to_string())),
+            ("relid", Field::Null),
+            ("relation_missing", Field::Bool(false)),
+            ("xid", Field::Number(750)),
+            ("row_after", Field::Text("(1,\"a\")".to_string())),
+        ];
+        assert_eq!(
+            json_line(&fields),
+            r#"{"lsn":"0/2A","relid":null,"relation_missing":false,"xid":750,"row_after":"(1,\"a\")"}"#
+        );
+        assert_eq!(csv_line(&fields), r#"0/2A,,f,750,"(1,""a"")""#);
+        assert_eq!(csv_value(""), r#""""#);
+        assert_eq!(ExportFormat::try_from("csv"), Ok(ExportFormat::Csv));
+        assert!(ExportFormat::try_from("parquet").is_err());
+        let tid = pg_sys::ItemPointerData {
+            ip_blkid: pg_sys::BlockIdData { bi_hi: 1, bi_lo: 2 },
+            ip_posid: 3,
+        };
+        assert_eq!(tid_text(tid), "(65538,3)");
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_to_file() {
+        unsafe {
+            Spi::run("CREATE TABLE test_to_file (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_to_file (id) SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let path = std::env::temp_dir().join("pg_waldecoder_to_file.csv");
+        let written = Spi::get_one::<i64>(&format!(
+            "SELECT pg_waldecoder_to_file('{startptr}', '{endptr}', '{}', 'csv', 1)",
+            path.display()
+        ))
+        .unwrap();
+        assert_eq!(written, Some(3));
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content.lines().count(), 4);
+        assert!(content.starts_with("lsn,dboid,relid,"));
+    }
+
+    #[test]
+    fn test_batch_command() {
+        assert_eq!(
+            batch_command(
+                "ship --from %s --to %e --count %n 100%% %x%",
+                PgLSN::from(0x10u64),
+                PgLSN::from(0x40u64),
+                3
+            ),
+            "ship --from 0/00000010 --to 0/00000040 --count 3 100% %x%"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_to_command() {
+        unsafe {
+            Spi::run("CREATE TABLE test_to_command (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_to_command SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let path = std::env::temp_dir().join(format!(
+            "pg_waldecoder_to_command_{}.json",
+            std::process::id()
+        ));
+        let written = Spi::get_one::<i64>(&format!(
+            "SELECT pg_waldecoder_to_command('{startptr}', '{endptr}', 'cat >> {} && echo %n >> {0}', batch_size => 2, timeline => 1)",
+            path.display()
+        ))
+        .unwrap();
+        assert_eq!(written, Some(3));
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Two batches, each followed by its size
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], "2");
+        assert_eq!(lines[4], "1");
+        assert!(lines[0].starts_with(r#"{"lsn":"#));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_to_file_debezium() {
+        unsafe {
+            Spi::run("CREATE TABLE test_to_debezium (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_to_debezium VALUES (1, 'a')");
+            Spi::run("DELETE FROM test_to_debezium");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let path = std::env::temp_dir().join("pg_waldecoder_to_file.json");
+        let written = Spi::get_one::<i64>(&format!(
+            "SELECT pg_waldecoder_to_file('{startptr}', '{endptr}', '{}', 'debezium', 1)",
+            path.display()
+        ))
+        .unwrap();
+        assert_eq!(written, Some(2));
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert!(lines[0].contains(r#""op":"c""#));
+        assert!(lines[1].contains(r#""op":"d""#));
+        assert!(content.contains(r#""after":{"id":1,"data":"a"}"#));
+    }
+}
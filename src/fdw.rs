@@ -0,0 +1,674 @@
+use std::ffi::{c_int, c_void, CStr, CString};
+
+use pgrx::{
+    error, extension_sql, pg_guard, pg_sys, prelude::*, FromDatum, PgBox, PgList, PgMemoryContexts,
+    PgTupleDesc,
+};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    materialize::Row,
+    pg_lsn::PgLSN,
+};
+
+extension_sql!(
+    r"
+CREATE FUNCTION pg_waldecoder_fdw_handler() RETURNS fdw_handler
+    LANGUAGE c STRICT
+    AS 'MODULE_PATHNAME', 'pg_waldecoder_fdw_handler';
+
+CREATE FUNCTION pg_waldecoder_fdw_validator(text[], oid) RETURNS void
+    LANGUAGE c STRICT
+    AS 'MODULE_PATHNAME', 'pg_waldecoder_fdw_validator';
+
+CREATE FOREIGN DATA WRAPPER pg_waldecoder_fdw
+    HANDLER pg_waldecoder_fdw_handler
+    VALIDATOR pg_waldecoder_fdw_validator;
+",
+    name = "pg_waldecoder_fdw",
+);
+
+/// Columns of `pg_waldecoder_change` with their types, in the order of the
+/// rows built from the changes
+const CHANGE_COLUMNS_QUERY: &str = "SELECT attname::text, atttypid FROM pg_attribute
+     WHERE attrelid = (SELECT typrelid FROM pg_type WHERE oid = 'pg_waldecoder_change'::regtype)
+       AND attnum > 0 AND NOT attisdropped ORDER BY attnum";
+
+/// Rows planned for a scan of a foreign table
+const ESTIMATED_ROWS: f64 = 1000.0;
+
+/// Options of a foreign table, merged with the ones of its server
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct ScanOptions {
+    start_lsn: Option<String>,
+    end_lsn: Option<String>,
+    timeline: Option<i32>,
+    wal_dir: Option<String>,
+    /// Comma separated `[schema.]table` names of the relations decoded
+    relations: Vec<String>,
+}
+
+impl ScanOptions {
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "start_lsn" | "end_lsn" => {
+                PgLSN::try_from(value).map_err(|e| e.to_string())?;
+                if name == "start_lsn" {
+                    self.start_lsn = Some(value.to_string());
+                } else {
+                    self.end_lsn = Some(value.to_string());
+                }
+            }
+            "timeline" => {
+                let timeline = value
+                    .parse()
+                    .map_err(|_| format!("Invalid timeline '{value}'"))?;
+                self.timeline = Some(timeline);
+            }
+            "wal_dir" => self.wal_dir = Some(value.to_string()),
+            "relations" => {
+                self.relations = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|relation| !relation.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            _ => return Err(format!("Unknown option '{name}' of pg_waldecoder_fdw")),
+        }
+        Ok(())
+    }
+
+    /// Apply a list of `DefElem` options
+    unsafe fn add(&mut self, options: *mut pg_sys::List) {
+        let options = unsafe { PgList::<pg_sys::DefElem>::from_pg(options) };
+        for elem in options.iter_ptr() {
+            let name = unsafe { CStr::from_ptr((*elem).defname) }.to_string_lossy();
+            let value = unsafe { CStr::from_ptr(pg_sys::defGetString(elem)) }.to_string_lossy();
+            if let Err(e) = self.set(&name, &value) {
+                error!("Error: {e}");
+            }
+        }
+    }
+
+    /// Options of the server of a foreign table, overridden by the table's
+    fn of_table(foreigntableid: pg_sys::Oid) -> ScanOptions {
+        let mut options = ScanOptions::default();
+        unsafe {
+            let table = pg_sys::GetForeignTable(foreigntableid);
+            let server = pg_sys::GetForeignServer((*table).serverid);
+            options.add((*server).options);
+            options.add((*table).options);
+        }
+        options
+    }
+}
+
+/// Equality quals of a scan pushed down to the decoder, the changes not
+/// matching them are skipped before building their tuple. The executor
+/// still checks all the quals.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Quals {
+    relid: Option<pg_sys::Oid>,
+    xid: Option<u64>,
+    op: Option<String>,
+}
+
+impl Quals {
+    fn matches(&self, change: &DecodedResult) -> bool {
+        self.relid.is_none_or(|relid| change.relid == Some(relid))
+            && self
+                .xid
+                .is_none_or(|xid| change.full_xid.is_some_and(|full_xid| full_xid.0 == xid))
+            && self.op.as_ref().is_none_or(|op| change.op == *op)
+    }
+
+    /// Quals as name and value pairs
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(relid) = self.relid {
+            fields.push(("relid", relid.to_u32().to_string()));
+        }
+        if let Some(xid) = self.xid {
+            fields.push(("xid", xid.to_string()));
+        }
+        if let Some(op) = &self.op {
+            fields.push(("op", op.clone()));
+        }
+        fields
+    }
+
+    fn from_fields<'a>(fields: impl Iterator<Item = (&'a str, &'a str)>) -> Quals {
+        let mut quals = Quals::default();
+        for (name, value) in fields {
+            match name {
+                "relid" => quals.relid = value.parse::<u32>().ok().map(pg_sys::Oid::from),
+                "xid" => quals.xid = value.parse().ok(),
+                "op" => quals.op = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        quals
+    }
+
+    /// Quals kept in the plan's `fdw_private`, as a list of `DefElem`
+    fn to_list(&self) -> *mut pg_sys::List {
+        let mut list = PgList::<pg_sys::DefElem>::new();
+        for (name, value) in self.fields() {
+            let name = CString::new(name).unwrap();
+            let value = CString::new(value).unwrap();
+            unsafe {
+                list.push(pg_sys::makeDefElem(
+                    pg_sys::pstrdup(name.as_ptr()),
+                    pg_sys::makeString(pg_sys::pstrdup(value.as_ptr())).cast(),
+                    -1,
+                ));
+            }
+        }
+        list.into_pg()
+    }
+
+    unsafe fn from_list(list: *mut pg_sys::List) -> Quals {
+        let list = unsafe { PgList::<pg_sys::DefElem>::from_pg(list) };
+        let fields = list
+            .iter_ptr()
+            .map(|elem| unsafe {
+                (
+                    CStr::from_ptr((*elem).defname)
+                        .to_string_lossy()
+                        .into_owned(),
+                    CStr::from_ptr(pg_sys::defGetString(elem))
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        Quals::from_fields(
+            fields
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    }
+
+    /// Column and constant of a `column = constant` clause on the scanned
+    /// relation
+    unsafe fn equality_qual(
+        foreigntableid: pg_sys::Oid,
+        scanrelid: pg_sys::Index,
+        clause: *mut pg_sys::Node,
+    ) -> Option<(String, *mut pg_sys::Const)> {
+        if clause.is_null() || unsafe { (*clause).type_ } != pg_sys::NodeTag::T_OpExpr {
+            return None;
+        }
+        let opexpr = clause.cast::<pg_sys::OpExpr>();
+        let opname = unsafe { pg_sys::get_opname((*opexpr).opno) };
+        if opname.is_null() || unsafe { CStr::from_ptr(opname) }.to_bytes() != b"=" {
+            return None;
+        }
+        let args = unsafe { PgList::<pg_sys::Node>::from_pg((*opexpr).args) };
+        let (left, right) = (args.get_ptr(0)?, args.get_ptr(1)?);
+        let (var, constant) = match unsafe { ((*left).type_, (*right).type_) } {
+            (pg_sys::NodeTag::T_Var, pg_sys::NodeTag::T_Const) => (left, right),
+            (pg_sys::NodeTag::T_Const, pg_sys::NodeTag::T_Var) => (right, left),
+            _ => return None,
+        };
+        let var = var.cast::<pg_sys::Var>();
+        let constant = constant.cast::<pg_sys::Const>();
+        if u32::try_from(unsafe { (*var).varno }).ok() != Some(scanrelid)
+            || unsafe { (*var).varattno } <= 0
+            || unsafe { (*constant).constisnull }
+        {
+            return None;
+        }
+        let attname = unsafe { pg_sys::get_attname(foreigntableid, (*var).varattno, false) };
+        let attname = unsafe { CStr::from_ptr(attname) }
+            .to_string_lossy()
+            .into_owned();
+        Some((attname, constant))
+    }
+
+    /// Quals on `relid`, `xid` and `op` among the restrictions of a scan
+    unsafe fn pushed_down(
+        foreigntableid: pg_sys::Oid,
+        scanrelid: pg_sys::Index,
+        scan_clauses: *mut pg_sys::List,
+    ) -> Quals {
+        let mut quals = Quals::default();
+        let scan_clauses = unsafe { PgList::<pg_sys::RestrictInfo>::from_pg(scan_clauses) };
+        for rinfo in scan_clauses.iter_ptr() {
+            let Some((column, constant)) = (unsafe {
+                Quals::equality_qual(foreigntableid, scanrelid, (*rinfo).clause.cast())
+            }) else {
+                continue;
+            };
+            let (value, consttype) = unsafe { ((*constant).constvalue, (*constant).consttype) };
+            match (column.as_str(), consttype) {
+                ("relid", pg_sys::OIDOID) => {
+                    quals.relid = unsafe { pg_sys::Oid::from_datum(value, false) };
+                }
+                ("xid", pg_sys::XID8OID) => quals.xid = u64::try_from(value.value()).ok(),
+                ("op", pg_sys::TEXTOID) => quals.op = unsafe { String::from_datum(value, false) },
+                _ => {}
+            }
+        }
+        quals
+    }
+}
+
+/// Decoding of a foreign table scan
+struct ScanState {
+    options: ScanOptions,
+    quals: Quals,
+    decoder: Option<WalDecoder>,
+    /// Position in the rows of the changes of each column of the foreign
+    /// table, None for the dropped ones
+    columns: Vec<Option<usize>>,
+    /// Context of the decoder, outliving the per tuple context of the scan
+    scan_ctx: pg_sys::MemoryContext,
+}
+
+impl ScanState {
+    /// Start decoding, again from `start_lsn` on a rescan
+    fn start(&mut self) {
+        drop(self.decoder.take());
+        let Some(start_lsn) = &self.options.start_lsn else {
+            error!("The start_lsn option of the foreign table is required");
+        };
+        let startptr = match PgLSN::try_from(start_lsn.as_str()) {
+            Ok(startptr) => startptr,
+            Err(e) => error!("Error: {}", e.to_string()),
+        };
+        // A relid qual only decodes the records of its relation, unless the
+        // table already restricts the relations
+        let relid = self
+            .quals
+            .relid
+            .filter(|relid| !unsafe { pg_sys::get_rel_name(*relid) }.is_null())
+            .map(|relid| relid.to_u32().to_string());
+        let relations: Vec<&str> = match &relid {
+            Some(relid) if self.options.relations.is_empty() => vec![relid.as_str()],
+            _ => self.options.relations.iter().map(String::as_str).collect(),
+        };
+        let options = DecoderOptions {
+            end_lsn: self.options.end_lsn.as_deref(),
+            timeline: self.options.timeline,
+            wal_dir: self.options.wal_dir.as_deref(),
+            relations: &relations,
+            ..Default::default()
+        };
+        let decoder = unsafe {
+            PgMemoryContexts::For(self.scan_ctx).switch_to(|_| WalDecoder::new(startptr, &options))
+        };
+        self.decoder = Some(decoder);
+    }
+
+    /// Next change matching the pushed down quals
+    fn next_change(&mut self) -> Option<DecodedResult> {
+        let decoder = self.decoder.as_mut()?;
+        let quals = &self.quals;
+        unsafe {
+            PgMemoryContexts::For(self.scan_ctx)
+                .switch_to(|_| decoder.find(|change| quals.matches(change)))
+        }
+    }
+}
+
+/// Position of each column of a foreign table in the rows of the changes,
+/// its columns must be columns of `pg_waldecoder_change`
+fn scan_columns(tupdesc: &PgTupleDesc) -> Vec<Option<usize>> {
+    let change_columns = Spi::connect(|client| {
+        client
+            .select(CHANGE_COLUMNS_QUERY, None, &[])?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.unwrap(),
+                    row.get::<pg_sys::Oid>(2)?.unwrap(),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap();
+    tupdesc
+        .iter()
+        .map(|attr| {
+            if attr.is_dropped() {
+                return None;
+            }
+            let name = attr.name();
+            let Some(position) = change_columns.iter().position(|(column, _)| column == name)
+            else {
+                error!("Column {name} of the foreign table isn't a column of pg_waldecoder_change");
+            };
+            let typid = change_columns[position].1;
+            if attr.type_oid().value() != typid {
+                let typname = unsafe { CStr::from_ptr(pg_sys::format_type_be(typid)) };
+                error!(
+                    "Column {name} of the foreign table must be of type {}",
+                    typname.to_string_lossy()
+                );
+            }
+            Some(position)
+        })
+        .collect()
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_get_rel_size(
+    _root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    _foreigntableid: pg_sys::Oid,
+) {
+    unsafe { (*baserel).rows = ESTIMATED_ROWS };
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_get_paths(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    _foreigntableid: pg_sys::Oid,
+) {
+    unsafe {
+        // Every record of the range is read, whatever the quals
+        let path = pg_sys::create_foreignscan_path(
+            root,
+            baserel,
+            std::ptr::null_mut(),
+            (*baserel).rows,
+            0,
+            10.0,
+            10000.0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        pg_sys::add_path(baserel, path.cast());
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_get_plan(
+    _root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+    _best_path: *mut pg_sys::ForeignPath,
+    tlist: *mut pg_sys::List,
+    scan_clauses: *mut pg_sys::List,
+    outer_plan: *mut pg_sys::Plan,
+) -> *mut pg_sys::ForeignScan {
+    unsafe {
+        let scanrelid = (*baserel).relid;
+        let quals = Quals::pushed_down(foreigntableid, scanrelid, scan_clauses);
+        pg_sys::make_foreignscan(
+            tlist,
+            pg_sys::extract_actual_clauses(scan_clauses, false),
+            scanrelid,
+            std::ptr::null_mut(),
+            quals.to_list(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            outer_plan,
+        )
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_explain(
+    node: *mut pg_sys::ForeignScanState,
+    es: *mut pg_sys::ExplainState,
+) {
+    let plan = unsafe { (*node).ss.ps.plan.cast::<pg_sys::ForeignScan>() };
+    let quals = unsafe { Quals::from_list((*plan).fdw_private) };
+    let fields = quals.fields();
+    if fields.is_empty() {
+        return;
+    }
+    let pushed = fields
+        .iter()
+        .map(|(name, value)| format!("{name} = {value}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let pushed = CString::new(pushed).unwrap();
+    unsafe { pg_sys::ExplainPropertyText(c"Decoder Filter".as_ptr(), pushed.as_ptr(), es) };
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_begin(node: *mut pg_sys::ForeignScanState, eflags: c_int) {
+    if eflags.cast_unsigned() & pg_sys::EXEC_FLAG_EXPLAIN_ONLY != 0 {
+        return;
+    }
+    let mut state = unsafe {
+        let plan = (*node).ss.ps.plan.cast::<pg_sys::ForeignScan>();
+        let relation = (*node).ss.ss_currentRelation;
+        ScanState {
+            options: ScanOptions::of_table((*relation).rd_id),
+            quals: Quals::from_list((*plan).fdw_private),
+            decoder: None,
+            columns: scan_columns(&PgTupleDesc::from_pg_unchecked((*relation).rd_att)),
+            scan_ctx: pg_sys::CurrentMemoryContext,
+        }
+    };
+    state.start();
+    unsafe {
+        (*node).fdw_state = Box::into_raw(Box::new(state)).cast();
+        // The scan isn't ended when the query fails, the state is then
+        // dropped with the query's memory
+        let callback = pg_sys::palloc0(size_of::<pg_sys::MemoryContextCallback>())
+            .cast::<pg_sys::MemoryContextCallback>();
+        (*callback).func = Some(fdw_release);
+        (*callback).arg = node.cast();
+        pg_sys::MemoryContextRegisterResetCallback(pg_sys::CurrentMemoryContext, callback);
+    }
+}
+
+/// Drop the state of a scan not ended, when its memory context goes away
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_release(arg: *mut c_void) {
+    unsafe { fdw_end(arg.cast()) };
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_iterate(
+    node: *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::TupleTableSlot {
+    let state = unsafe { &mut *(*node).fdw_state.cast::<ScanState>() };
+    let slot = unsafe { (*node).ss.ss_ScanTupleSlot };
+    unsafe { (*(*slot).tts_ops).clear.unwrap()(slot) };
+    let Some(change) = state.next_change() else {
+        return slot;
+    };
+    // The values live in the per tuple context of the scan
+    let row = Row::from(change);
+    for (attnum, position) in state.columns.iter().enumerate() {
+        let (value, isnull) = position.map_or((pg_sys::Datum::from(0), true), |position| {
+            (row.values[position], row.nulls[position])
+        });
+        unsafe {
+            *(*slot).tts_values.add(attnum) = value;
+            *(*slot).tts_isnull.add(attnum) = isnull;
+        }
+    }
+    unsafe { pg_sys::ExecStoreVirtualTuple(slot) }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_rescan(node: *mut pg_sys::ForeignScanState) {
+    let state = unsafe { &mut *(*node).fdw_state.cast::<ScanState>() };
+    state.start();
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn fdw_end(node: *mut pg_sys::ForeignScanState) {
+    let state = unsafe { (*node).fdw_state };
+    if state.is_null() {
+        return;
+    }
+    drop(unsafe { Box::from_raw(state.cast::<ScanState>()) });
+    unsafe { (*node).fdw_state = std::ptr::null_mut() };
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_pg_waldecoder_fdw_handler() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// Handler of `pg_waldecoder_fdw`. A foreign table's options give the range
+/// decoded, its columns are columns of `pg_waldecoder_change`, and equality
+/// quals on `relid`, `xid` and `op` are pushed down to the decoder.
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C-unwind" fn pg_waldecoder_fdw_handler(
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::Datum {
+    let mut routine =
+        unsafe { PgBox::<pg_sys::FdwRoutine>::alloc_node(pg_sys::NodeTag::T_FdwRoutine) };
+    routine.GetForeignRelSize = Some(fdw_get_rel_size);
+    routine.GetForeignPaths = Some(fdw_get_paths);
+    routine.GetForeignPlan = Some(fdw_get_plan);
+    routine.ExplainForeignScan = Some(fdw_explain);
+    routine.BeginForeignScan = Some(fdw_begin);
+    routine.IterateForeignScan = Some(fdw_iterate);
+    routine.ReScanForeignScan = Some(fdw_rescan);
+    routine.EndForeignScan = Some(fdw_end);
+    pg_sys::Datum::from(routine.into_pg())
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_pg_waldecoder_fdw_validator() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// Check the options of the servers and foreign tables of
+/// `pg_waldecoder_fdw`: `start_lsn`, `end_lsn`, `timeline`, `wal_dir` and
+/// `relations`
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C-unwind" fn pg_waldecoder_fdw_validator(
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::Datum {
+    unsafe {
+        let options = pg_sys::untransformRelOptions(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0));
+        ScanOptions::default().add(options);
+    }
+    pg_sys::Datum::from(0)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        fdw::{Quals, ScanOptions},
+        pg_lsn::PgLSN,
+        sink::try_in_subtransaction,
+    };
+
+    #[test]
+    fn test_fdw_options() {
+        let mut options = ScanOptions::default();
+        options.set("start_lsn", "0/1000028").unwrap();
+        options.set("timeline", "2").unwrap();
+        options.set("relations", "public.a, b,").unwrap();
+        assert_eq!(options.start_lsn.as_deref(), Some("0/1000028"));
+        assert_eq!(options.timeline, Some(2));
+        assert_eq!(options.relations, ["public.a", "b"]);
+        assert!(options.set("start_lsn", "nope").is_err());
+        assert!(options.set("timeline", "two").is_err());
+        assert!(options.set("live", "true").is_err());
+
+        let quals = Quals {
+            relid: Some(pg_sys::Oid::from(16384)),
+            xid: None,
+            op: Some("INSERT".to_string()),
+        };
+        let fields = quals.fields();
+        assert_eq!(
+            fields,
+            [("relid", "16384".to_string()), ("op", "INSERT".to_string())]
+        );
+        assert_eq!(
+            Quals::from_fields(fields.iter().map(|(name, value)| (*name, value.as_str()))),
+            quals
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_fdw() {
+        unsafe {
+            Spi::run("CREATE TABLE test_fdw_source (id int);");
+            Spi::run("CREATE TABLE test_fdw_other (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_fdw_source VALUES (1), (2)");
+            Spi::run("INSERT INTO test_fdw_other VALUES (3)");
+            Spi::run("DELETE FROM test_fdw_source WHERE id = 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        Spi::run("CREATE SERVER test_fdw_server FOREIGN DATA WRAPPER pg_waldecoder_fdw").unwrap();
+        Spi::run(&format!(
+            "CREATE FOREIGN TABLE test_fdw_changes (lsn pg_lsn, relid oid, op text, row_after text)
+             SERVER test_fdw_server OPTIONS (start_lsn '{startptr}', timeline '1')"
+        ))
+        .unwrap();
+
+        let inserts = Spi::get_one::<String>(
+            "SELECT string_agg(row_after, ', ' ORDER BY lsn) FROM test_fdw_changes
+             WHERE relid = 'test_fdw_source'::regclass AND op = 'INSERT'",
+        );
+        assert_eq!(inserts, Ok(Some("(1), (2)".to_string())));
+        let plan = Spi::explain("SELECT * FROM test_fdw_changes WHERE op = 'DELETE'").unwrap();
+        assert!(plan
+            .0
+            .to_string()
+            .contains(r#""Decoder Filter":"op = DELETE""#));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_fdw_failed_scan() {
+        unsafe {
+            Spi::run("CREATE TABLE test_fdw_failed (id int, email text);");
+            // Hashing without a key fails the scan
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_fdw_failed', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_fdw_failed VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        Spi::run("CREATE SERVER test_fdw_failed FOREIGN DATA WRAPPER pg_waldecoder_fdw").unwrap();
+        Spi::run(&format!(
+            "CREATE FOREIGN TABLE test_fdw_failed_changes (lsn pg_lsn, row_after text)
+             SERVER test_fdw_failed OPTIONS (start_lsn '{startptr}', end_lsn '{endptr}', timeline '1')"
+        ))
+        .unwrap();
+
+        // The state of the failed scans and their segments are released
+        let open_files = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let before = open_files();
+        for _ in 0..3 {
+            try_in_subtransaction(|| {
+                Spi::run("SELECT count(*) FROM test_fdw_failed_changes").unwrap();
+            })
+            .unwrap_err();
+        }
+        assert_eq!(open_files(), before);
+    }
+
+    #[pg_test(error = "Error: Unknown option 'live' of pg_waldecoder_fdw")]
+    fn test_pg_waldecoder_fdw_invalid_option() {
+        Spi::run("CREATE SERVER test_fdw_invalid FOREIGN DATA WRAPPER pg_waldecoder_fdw OPTIONS (live 'true')")
+            .unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use pgrx::{pg_sys, prelude::*};
+
+use crate::{
+    pg_lsn::{xlog_file_name, PgLSN},
+    wal::live_wal_dir,
+};
+
+/// Directory of the fixtures shipped with the repository
+pub const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test");
+
+/// Step of the script of a fixture
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Sql(String),
+    Checkpoint,
+    SwitchSegment,
+}
+
+impl Step {
+    fn sql(&self) -> &str {
+        match self {
+            Step::Sql(statement) => statement,
+            Step::Checkpoint => "CHECKPOINT",
+            Step::SwitchSegment => "SELECT pg_switch_wal()",
+        }
+    }
+}
+
+/// Script of a WAL fixture: statements, checkpoints and segment switches run
+/// on the test server, whose WAL is copied to test the decoding of the
+/// records it covers
+#[derive(Clone, Debug, Default)]
+pub struct WalFixture {
+    name: String,
+    steps: Vec<Step>,
+}
+
+/// Segments of a generated fixture and the range of its WAL
+#[derive(Clone, Debug)]
+pub struct Fixture {
+    pub dir: PathBuf,
+    pub timeline: pg_sys::TimeLineID,
+    pub start_lsn: PgLSN,
+    pub end_lsn: PgLSN,
+}
+
+impl WalFixture {
+    pub fn new(name: &str) -> WalFixture {
+        WalFixture {
+            name: name.to_string(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Run a statement
+    pub fn sql(mut self, statement: &str) -> Self {
+        self.steps.push(Step::Sql(statement.to_string()));
+        self
+    }
+
+    /// Run a checkpoint, the next change of each page logs its image
+    pub fn checkpoint(mut self) -> Self {
+        self.steps.push(Step::Checkpoint);
+        self
+    }
+
+    /// Switch to a new segment, the WAL that follows starts the next one
+    pub fn switch_segment(mut self) -> Self {
+        self.steps.push(Step::SwitchSegment);
+        self
+    }
+
+    /// Script of the fixture as SQL, saved with its segments
+    fn script(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| format!("{};\n", step.sql()))
+            .collect()
+    }
+
+    /// Run the script and copy the segments of its WAL in `<dir>/<name>`, a
+    /// temporary directory of the backend by default, replacing the previous
+    /// ones, `FIXTURES_DIR` updates the fixtures of the repository. The
+    /// script and the LSN range are saved in `script.sql` and `lsn_range`.
+    /// The last segment is copied as written so far. The changes of the test's
+    /// transaction are flushed without being committed.
+    pub fn generate(&self, dir: Option<&Path>) -> io::Result<Fixture> {
+        let startptr = flush();
+        for step in &self.steps {
+            if let Err(e) = Spi::run(step.sql()) {
+                error!("Fixture {} failed running {}: {e}", self.name, step.sql());
+            }
+        }
+        let endptr = flush();
+
+        let mut timeline: pg_sys::TimeLineID = 0;
+        unsafe { pg_sys::GetFlushRecPtr(&raw mut timeline) };
+        let (wal_dir, segsz) = live_wal_dir();
+        let fixture_dir = dir
+            .map_or_else(default_dir, Path::to_path_buf)
+            .join(&self.name);
+        if fixture_dir.exists() {
+            fs::remove_dir_all(&fixture_dir)?;
+        }
+        fs::create_dir_all(&fixture_dir)?;
+        let segno = |lsn: PgLSN| u64::from(lsn) / u64::from(segsz);
+        for segno in segno(startptr)..=segno(endptr) {
+            let fname = xlog_file_name(timeline, segno, segsz.cast_signed());
+            fs::copy(wal_dir.join(&fname), fixture_dir.join(&fname))?;
+        }
+        fs::write(fixture_dir.join("script.sql"), self.script())?;
+        fs::write(
+            fixture_dir.join("lsn_range"),
+            format!("{startptr} {endptr}\n"),
+        )?;
+        Ok(Fixture {
+            dir: fixture_dir,
+            timeline,
+            start_lsn: startptr,
+            end_lsn: endptr,
+        })
+    }
+}
+
+/// Directory of the fixtures generated without one
+fn default_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("pg_waldecoder_fixtures_{}", std::process::id()))
+}
+
+/// Flush the WAL written so far, returning where it ends
+fn flush() -> PgLSN {
+    unsafe {
+        pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        PgLSN::from(pg_sys::GetXLogWriteRecPtr())
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::fs;
+
+    use pgrx::prelude::*;
+
+    use crate::fixtures::{Step, WalFixture};
+
+    #[test]
+    fn test_fixture_script() {
+        let fixture = WalFixture::new("updates")
+            .sql("CREATE TABLE t (id int)")
+            .checkpoint()
+            .sql("UPDATE t SET id = 2")
+            .switch_segment();
+        assert_eq!(fixture.steps[1], Step::Checkpoint);
+        assert_eq!(
+            fixture.script(),
+            "CREATE TABLE t (id int);\nCHECKPOINT;\nUPDATE t SET id = 2;\nSELECT pg_switch_wal();\n"
+        );
+    }
+
+    #[pg_test]
+    fn test_wal_fixture() {
+        let fixture = WalFixture::new("inserts")
+            .sql("CREATE TABLE test_fixture (id int)")
+            .sql("INSERT INTO test_fixture VALUES (1), (2)")
+            .switch_segment()
+            .checkpoint()
+            .sql("INSERT INTO test_fixture VALUES (3)")
+            .generate(None)
+            .unwrap();
+        assert!(fixture.dir.starts_with(std::env::temp_dir()));
+        // The switch starts a second segment
+        let segments = fs::read_dir(&fixture.dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().len() == 24)
+            .count();
+        assert_eq!(segments, 2);
+        assert_eq!(
+            fs::read_to_string(fixture.dir.join("lsn_range")).unwrap(),
+            format!("{} {}\n", fixture.start_lsn, fixture.end_lsn)
+        );
+
+        let ids = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after, ',' ORDER BY lsn) FROM pg_waldecoder('{}', '{}', {},
+                 wal_dir => '{}')
+             WHERE relid = 'test_fixture'::regclass AND op = 'INSERT'",
+            fixture.start_lsn,
+            fixture.end_lsn,
+            fixture.timeline,
+            fixture.dir.display()
+        ));
+        assert_eq!(ids, Ok(Some("(1),(2),(3)".to_string())));
+    }
+}
@@ -0,0 +1,311 @@
+use pg_waldecoder_core::record::rmgr_name;
+use pgrx::{pg_sys, prelude::*, PgBox};
+
+use crate::{
+    decoder::{DecoderOptions, WalDecoder},
+    page::{page_clear_checksum, page_get_lsn, page_set_lsn},
+    pg_lsn::PgLSN,
+    relation::{classify_database, read_current_block, RecordDatabase, RelidCache},
+    walinspect::{lsn_bounds, restore_block_image, wal_decoder},
+    xlog_reader::{get_block, get_block_tag_extended, rmgr_data},
+};
+
+/// Full page image differing from the current version of its block
+#[derive(Clone, Copy, Debug)]
+pub struct FpiMismatch {
+    pub lsn: PgLSN,
+    pub rmid: u8,
+    pub rlocator: pg_sys::RelFileLocator,
+    pub forknum: i32,
+    pub blknum: pg_sys::BlockNumber,
+}
+
+/// Page sized buffer aligned like the pages of the buffer manager
+fn page_buffer() -> Vec<u64> {
+    vec![0; pg_sys::BLCKSZ as usize / size_of::<u64>()]
+}
+
+/// Mask the parts of a page that can change without WAL-logging, with the
+/// masking used by `wal_consistency_checking`
+unsafe fn mask_page(rmid: u8, page: pg_sys::Page, blknum: pg_sys::BlockNumber) {
+    match rmgr_data(rmid).rm_mask {
+        Some(rm_mask) => unsafe { rm_mask(page, blknum) },
+        None => unsafe { page_clear_checksum(page) },
+    }
+}
+
+/// Compare the full page image of a block reference with the current block.
+/// Blocks modified after the record can't be compared and are ignored.
+fn check_block(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    block_id: u8,
+) -> Option<FpiMismatch> {
+    if !get_block(record, block_id)?.has_image {
+        return None;
+    }
+    let (rlocator, forknum, blknum) = get_block_tag_extended(xlog_reader, block_id)?;
+    if rlocator.dbOid != unsafe { pg_sys::MyDatabaseId } {
+        return None;
+    }
+
+    let mut current = page_buffer();
+    let current_page = current.as_mut_ptr().cast::<std::ffi::c_char>();
+    if !read_current_block(&rlocator, forknum, blknum, current_page) {
+        return None;
+    }
+    let end_lsn = xlog_reader.EndRecPtr;
+    if unsafe { page_get_lsn(current_page) } != end_lsn {
+        return None;
+    }
+
+    let mut image = page_buffer();
+    let image_page = image.as_mut_ptr().cast::<std::ffi::c_char>();
+    if !unsafe { pg_sys::RestoreBlockImage(xlog_reader.as_ptr(), block_id, image_page) } {
+        return None;
+    }
+    unsafe { page_set_lsn(image_page, end_lsn) };
+
+    let rmid = record.header.xl_rmid;
+    unsafe {
+        mask_page(rmid, current_page, blknum);
+        mask_page(rmid, image_page, blknum);
+    }
+    (current != image).then_some(FpiMismatch {
+        lsn: PgLSN::from(record.lsn),
+        rmid,
+        rlocator,
+        forknum,
+        blknum,
+    })
+}
+
+/// Compare the full page images of a record with the current blocks
+pub fn check_record_fpis(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Vec<FpiMismatch> {
+    // max_block_id is -1 without block references
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    (0..=max_block_id)
+        .filter_map(|block_id| check_block(xlog_reader, record, block_id))
+        .collect()
+}
+
+/// Compare the full page images written from `start_lsn` with the blocks of
+/// the current database as they are now, after masking the hint bits
+/// and unused space like `wal_consistency_checking` does. A mismatch on a
+/// block left untouched since the image was written points to a torn or
+/// corrupted page.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_check_fpis(
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(rmgr, String),
+        name!(spcoid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(forknum, i32),
+        name!(blknum, i64),
+    ),
+> {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let options = DecoderOptions {
+        end_lsn,
+        live: true,
+        check_fpis: true,
+        ..Default::default()
+    };
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    let mut mismatches = Vec::new().into_iter();
+    let mut done = false;
+    // Mismatches are collected while the decoder reads records
+    let mismatches = std::iter::from_fn(move || loop {
+        if let Some(mismatch) = mismatches.next() {
+            return Some(mismatch);
+        }
+        if done {
+            return None;
+        }
+        done = wal_decoder.next().is_none();
+        mismatches = wal_decoder.take_fpi_mismatches().into_iter();
+    });
+    TableIterator::new(mismatches.map(|mismatch| {
+        (
+            mismatch.lsn,
+            rmgr_name(mismatch.rmid),
+            mismatch.rlocator.spcOid,
+            mismatch.rlocator.relNumber,
+            mismatch.forknum,
+            i64::from(mismatch.blknum),
+        )
+    }))
+}
+
+/// Full page images written between `start_lsn` and `end_lsn`, of the forks
+/// of `relid` only when given, with the page as it was logged. The pages can
+/// be inspected with pageinspect, like `heap_page_items(page)`. The relid of
+/// the images of other databases is NULL.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_fpi(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    relid: default!(Option<pg_sys::Oid>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(spcoid, pg_sys::Oid),
+        name!(dboid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(forknum, i32),
+        name!(blkno, i64),
+        name!(page, Vec<u8>),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut relids = RelidCache::new();
+    let mut rows = Vec::new().into_iter();
+    TableIterator::new(std::iter::from_fn(move || loop {
+        if let Some(row) = rows.next() {
+            return Some(row);
+        }
+        let record = wal_decoder.read_record()?;
+        let xlog_reader = wal_decoder.xlog_reader();
+        relids.invalidate_for(xlog_reader, &record);
+        let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+            continue;
+        };
+        let mut images = Vec::new();
+        for block_id in 0..=max_block_id {
+            if !get_block(&record, block_id).is_some_and(|block| block.has_image) {
+                continue;
+            }
+            let Some((rlocator, forknum, blknum)) = get_block_tag_extended(xlog_reader, block_id)
+            else {
+                continue;
+            };
+            let block_relid = match classify_database(&rlocator) {
+                RecordDatabase::Other => None,
+                _ => relids.get(&rlocator),
+            };
+            if relid.is_some_and(|relid| block_relid != Some(relid)) {
+                continue;
+            }
+            images.push((
+                PgLSN::from(record.lsn),
+                block_relid,
+                rlocator.spcOid,
+                rlocator.dbOid,
+                rlocator.relNumber,
+                forknum,
+                i64::from(blknum),
+                restore_block_image(xlog_reader, &record, block_id),
+            ));
+        }
+        rows = images.into_iter();
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::pg_lsn::PgLSN;
+
+    #[pg_test]
+    fn test_pg_waldecoder_check_fpis() {
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("CREATE TABLE test_check_fpis (id int);");
+            Spi::run("INSERT INTO test_check_fpis SELECT generate_series(1, 100)");
+            Spi::run("CHECKPOINT");
+            Spi::run("UPDATE test_check_fpis SET id = id + 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let mismatches = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_check_fpis('{startptr}')"
+        ));
+        assert_eq!(mismatches, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_check_fpis_mismatch() {
+        unsafe {
+            Spi::run("CREATE TABLE test_check_fpis_mismatch (id int);");
+            Spi::run("INSERT INTO test_check_fpis_mismatch VALUES (1)");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The image of the update is the last change of the page
+            Spi::run("UPDATE test_check_fpis_mismatch SET id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let check = || {
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM pg_waldecoder_check_fpis('{startptr}')
+                 WHERE relfilenumber = pg_relation_filenode('test_check_fpis_mismatch')"
+            ))
+        };
+        assert_eq!(check(), Ok(Some(0)));
+
+        // Flip a byte of the first tuple's data in the buffer without
+        // logging it, the masking leaves the tuple data untouched
+        let rel = PgRelation::open_with_name("test_check_fpis_mismatch").unwrap();
+        let flip = || unsafe {
+            let buffer = pg_sys::ReadBuffer(rel.as_ptr(), 0);
+            pg_sys::LockBuffer(buffer, pg_sys::BUFFER_LOCK_EXCLUSIVE.cast_signed());
+            let page = pg_sys::BufferGetPage(buffer);
+            let tuple = pg_sys::PageGetItem(page, pg_sys::PageGetItemId(page, 1))
+                .cast::<pg_sys::HeapTupleHeaderData>();
+            let data = tuple.cast::<u8>().add(usize::from((*tuple).t_hoff));
+            *data ^= 0xFF;
+            pg_sys::UnlockReleaseBuffer(buffer);
+        };
+        flip();
+        let mismatches = check();
+        flip();
+        assert_eq!(mismatches, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_fpi() {
+        unsafe {
+            Spi::run("CREATE TABLE test_fpi (id int);");
+            Spi::run("INSERT INTO test_fpi VALUES (1)");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The first change after the checkpoint logs the page
+            Spi::run("UPDATE test_fpi SET id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let pages = Spi::get_two::<i64, i32>(&format!(
+            "SELECT count(*), min(length(page))
+             FROM pg_waldecoder_fpi('{startptr}', '{endptr}', 'test_fpi'::regclass, 1)
+             WHERE forknum = 0 AND blkno = 0"
+        ));
+        assert_eq!(pages, Ok((Some(1), Some(8192))));
+    }
+}
@@ -0,0 +1,327 @@
+use std::ffi::CString;
+
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
+
+/// Amount of INFO messages reported while decoding
+#[derive(PostgresGucEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only warnings and errors
+    Quiet,
+    /// Messages about the decoding setup, segments and stop points
+    Normal,
+    /// Messages for every page and record
+    Debug,
+}
+
+/// What is done with the records of resource managers that can't be decoded
+#[derive(PostgresGucEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsupportedRecords {
+    /// Skip them silently
+    #[default]
+    Skip,
+    /// Report them as changes without rows
+    Report,
+    /// Raise an error, no change can go unnoticed
+    Error,
+}
+
+impl TryFrom<&str> for UnsupportedRecords {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "skip" => Ok(UnsupportedRecords::Skip),
+            "report" => Ok(UnsupportedRecords::Report),
+            "error" => Ok(UnsupportedRecords::Error),
+            _ => Err(format!(
+                "Unknown unsupported_records '{value}', expected skip, report or error"
+            )),
+        }
+    }
+}
+
+/// Log at INFO level if the verbosity is at least the provided one
+macro_rules! verbose {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::guc::VERBOSITY.get() >= $level {
+            pgrx::info!($($arg)*);
+        }
+    };
+}
+pub(crate) use verbose;
+
+pub static S3_ENDPOINT: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static S3_REGION: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static S3_ACCESS_KEY_ID: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static S3_SECRET_ACCESS_KEY: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub static S3_CACHE_DIR: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static VERBOSITY: GucSetting<Verbosity> = GucSetting::<Verbosity>::new(Verbosity::Normal);
+pub static RECORD_CONTEXT_INIT_BLOCK_SIZE: GucSetting<i32> = GucSetting::<i32>::new(8);
+pub static RECORD_CONTEXT_MAX_BLOCK_SIZE: GucSetting<i32> = GucSetting::<i32>::new(8192);
+pub static AUDIT_DATABASE: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static AUDIT_TABLE: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static AUDIT_START_LSN: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static AUDIT_RELATIONS: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static AUDIT_NOTIFY_CHANNEL: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub static AUDIT_NAPTIME: GucSetting<i32> = GucSetting::<i32>::new(10);
+pub static RESTRICT_WAL_DIR: GucSetting<bool> = GucSetting::<bool>::new(true);
+pub static PROGRESS_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static TRACK_TIMING: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static PAGE_CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(16384);
+pub static PAGE_CACHE_SPILL: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static MMAP_SEGMENTS: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static DROP_WAL_CACHE: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static COST_DELAY: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static COST_LIMIT: GucSetting<i32> = GucSetting::<i32>::new(200);
+pub static MAX_ROWS: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static END_AT_FLUSH: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static CHECK_SYSTEM_IDENTIFIER: GucSetting<bool> = GucSetting::<bool>::new(true);
+pub static MASK_HASH_KEY: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+pub static UNSUPPORTED_RECORDS: GucSetting<UnsupportedRecords> =
+    GucSetting::<UnsupportedRecords>::new(UnsupportedRecords::Skip);
+
+/// Register the extension's GUCs
+pub fn init() {
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.s3_endpoint",
+        c"Endpoint of the S3-compatible object storage.",
+        c"Defaults to AWS_ENDPOINT_URL, then to the AWS endpoint of the region.",
+        &S3_ENDPOINT,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.s3_region",
+        c"Region of the S3-compatible object storage.",
+        c"Defaults to AWS_REGION, then to AWS_DEFAULT_REGION.",
+        &S3_REGION,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.s3_access_key_id",
+        c"Access key used to fetch WAL from object storage.",
+        c"Defaults to AWS_ACCESS_KEY_ID.",
+        &S3_ACCESS_KEY_ID,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.s3_secret_access_key",
+        c"Secret key used to fetch WAL from object storage.",
+        c"Defaults to AWS_SECRET_ACCESS_KEY.",
+        &S3_SECRET_ACCESS_KEY,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY | GucFlags::NO_SHOW_ALL,
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.s3_cache_dir",
+        c"Local directory where fetched WAL segments are cached.",
+        c"Relative paths are relative to the data directory.",
+        &S3_CACHE_DIR,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.page_cache_size",
+        c"Maximum number of pages rebuilt from the WAL kept in memory.",
+        c"Least recently used pages are evicted once the limit is reached.",
+        &PAGE_CACHE_SIZE,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_BLOCKS,
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.page_cache_spill",
+        c"Write the pages evicted from the page cache to a temporary file.",
+        c"They're read back when needed again instead of being lost, trading I/O for decoding huge ranges. The file counts in temp_file_limit.",
+        &PAGE_CACHE_SPILL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.mmap_segments",
+        c"Map the WAL segments in memory instead of reading their pages.",
+        c"Saves a system call per page for the scans reading every page of large archives.",
+        &MMAP_SEGMENTS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.drop_wal_cache",
+        c"Drop the pages of the WAL segments from the OS cache once they're read.",
+        c"Keeps the decoding of large archives from evicting the pages of the live workload.",
+        &DROP_WAL_CACHE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.record_context_init_block_size",
+        c"Initial block size of the per record memory context.",
+        c"Raising it avoids growing the context for every record of wide tables.",
+        &RECORD_CONTEXT_INIT_BLOCK_SIZE,
+        1,
+        1024 * 1024,
+        GucContext::Userset,
+        GucFlags::UNIT_KB,
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.record_context_max_block_size",
+        c"Maximum block size of the per record memory context.",
+        c"Raised to the initial block size if lower.",
+        &RECORD_CONTEXT_MAX_BLOCK_SIZE,
+        1,
+        1024 * 1024,
+        GucContext::Userset,
+        GucFlags::UNIT_KB,
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.audit_database",
+        c"Database the audit worker connects to.",
+        c"Required to start the worker.",
+        &AUDIT_DATABASE,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.audit_table",
+        c"Table the audit worker inserts decoded changes into.",
+        c"The worker is only started when set and pg_waldecoder is preloaded.",
+        &AUDIT_TABLE,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.audit_start_lsn",
+        c"LSN the audit worker starts decoding from on its first run.",
+        c"Defaults to the current flush position.",
+        &AUDIT_START_LSN,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.audit_relations",
+        c"Comma-separated list of relations audited by the worker.",
+        c"All relations are audited when empty.",
+        &AUDIT_RELATIONS,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.audit_notify_channel",
+        c"Channel the audit worker notifies with each audited change.",
+        c"No notification is sent when empty.",
+        &AUDIT_NOTIFY_CHANNEL,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.audit_naptime",
+        c"Time between two runs of the audit worker.",
+        c"",
+        &AUDIT_NAPTIME,
+        1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.restrict_wal_dir",
+        c"Only let superusers read WAL outside of the data directory.",
+        c"",
+        &RESTRICT_WAL_DIR,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.progress_interval",
+        c"Time between two NOTICEs reporting the progress of a scan.",
+        c"Progress isn't reported when 0.",
+        &PROGRESS_INTERVAL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.track_timing",
+        c"Time the steps of decoding for each resource manager.",
+        c"Reported by pg_waldecoder_timing().",
+        &TRACK_TIMING,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.cost_delay",
+        c"Sleep of a scan once its cost reached pg_waldecoder.cost_limit.",
+        c"Reading a WAL page costs 2 and decoding a record 1, like vacuum's cost delay. 0 doesn't delay scans.",
+        &COST_DELAY,
+        0,
+        100,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.cost_limit",
+        c"Cost of the pages read and records decoded after which a scan sleeps.",
+        c"Only used when pg_waldecoder.cost_delay is set.",
+        &COST_LIMIT,
+        1,
+        10000,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_waldecoder.max_rows",
+        c"Maximum number of changes decoded by a scan.",
+        c"Decoding stops with a warning once reached, 0 doesn't limit the changes.",
+        &MAX_ROWS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.end_at_flush",
+        c"Stop scans of the server's WAL without end at the WAL flushed when they start.",
+        c"When off, such scans read the WAL written while decoding up to its end.",
+        &END_AT_FLUSH,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_waldecoder.check_system_identifier",
+        c"Check that the WAL segments read belong to the local database system.",
+        c"Turn it off to decode the WAL of another cluster, implied by foreign_relations.",
+        &CHECK_SYSTEM_IDENTIFIER,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pg_waldecoder.mask_hash_key",
+        c"Secret key of the HMAC replacing the values of the columns masked by hash.",
+        c"Required to decode the relations with such columns.",
+        &MASK_HASH_KEY,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY | GucFlags::NO_SHOW_ALL,
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_waldecoder.unsupported_records",
+        c"What is done with records of resource managers that can't be decoded.",
+        c"Custom, generic WAL and COPY multi-insert records are skipped, reported as changes or raise an error.",
+        &UNSUPPORTED_RECORDS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_waldecoder.verbosity",
+        c"Amount of messages reported while decoding.",
+        c"quiet only reports warnings, debug reports every page and record.",
+        &VERBOSITY,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
@@ -0,0 +1,265 @@
+use pgrx::{
+    pg_sys::{
+        self,
+        RmgrIds::{RM_BTREE_ID, RM_GIST_ID, RM_HASH_ID, RM_HEAP2_ID, RM_SPGIST_ID},
+    },
+    prelude::*,
+    PgBox,
+};
+
+use crate::{
+    pg_lsn::PgLSN,
+    relation::{classify_database, RecordDatabase, RelidCache},
+    walinspect::{lsn_bounds, record_type_name, rmgr_display_name, wal_decoder},
+    xlog_reader::get_block_tag_extended,
+};
+
+/// Record types of hash_xlog.h, gistxlog.h and spgxlog.h carrying a horizon
+const XLOG_HASH_VACUUM_ONE_PAGE: u32 = 0xB0;
+const XLOG_GIST_DELETE: u32 = 0x10;
+const XLOG_GIST_PAGE_REUSE: u32 = 0x20;
+const XLOG_SPGIST_VACUUM_REDIRECT: u32 = 0x80;
+
+/// Offset of the horizon in the page reuse records, after the locator and
+/// the block number
+const PAGE_REUSE_HORIZON_OFFSET: usize = 16;
+
+/// Xid used by a standby to cancel the queries whose snapshot may still see
+/// the rows removed or frozen by a record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConflictHorizon {
+    pub xid: u32,
+    /// Only the queries of the record's database can conflict, except for
+    /// catalog relations
+    pub catalog: bool,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + size_of::<u32>())?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn read_bool(data: &[u8], offset: usize) -> Option<bool> {
+    data.get(offset).map(|byte| *byte != 0)
+}
+
+/// Snapshot conflict horizon of a record from its main data, None for the
+/// records without one or with an invalid one
+pub fn conflict_horizon(rmid: u32, info: u32, main_data: &[u8]) -> Option<ConflictHorizon> {
+    let (xid, catalog) = match (rmid, info) {
+        (RM_HEAP2_ID, pg_sys::XLOG_HEAP2_VISIBLE) => {
+            // snapshotConflictHorizon and flags
+            let flags = u32::from(*main_data.get(size_of::<u32>())?);
+            (
+                read_u32(main_data, 0)?,
+                flags & pg_sys::VISIBILITYMAP_XLOG_CATALOG_REL != 0,
+            )
+        }
+        (
+            RM_HEAP2_ID,
+            pg_sys::XLOG_HEAP2_PRUNE_ON_ACCESS
+            | pg_sys::XLOG_HEAP2_PRUNE_VACUUM_SCAN
+            | pg_sys::XLOG_HEAP2_PRUNE_VACUUM_CLEANUP,
+        ) => {
+            // reason and flags, followed by the horizon when there's one
+            let flags = u32::from(*main_data.get(1)?);
+            if flags & pg_sys::XLHP_HAS_CONFLICT_HORIZON == 0 {
+                return None;
+            }
+            (
+                read_u32(main_data, 2)?,
+                flags & pg_sys::XLHP_IS_CATALOG_REL != 0,
+            )
+        }
+        // snapshotConflictHorizon, ndeleted, nupdated and isCatalogRel
+        (RM_BTREE_ID, pg_sys::XLOG_BTREE_DELETE) => {
+            (read_u32(main_data, 0)?, read_bool(main_data, 8)?)
+        }
+        // The full xid of the page reuse records is reduced to its xid
+        (RM_BTREE_ID, pg_sys::XLOG_BTREE_REUSE_PAGE) | (RM_GIST_ID, XLOG_GIST_PAGE_REUSE) => (
+            read_u32(main_data, PAGE_REUSE_HORIZON_OFFSET)?,
+            read_bool(main_data, PAGE_REUSE_HORIZON_OFFSET + size_of::<u64>())?,
+        ),
+        // snapshotConflictHorizon, ntuples and isCatalogRel
+        (RM_HASH_ID, XLOG_HASH_VACUUM_ONE_PAGE) | (RM_GIST_ID, XLOG_GIST_DELETE) => {
+            (read_u32(main_data, 0)?, read_bool(main_data, 6)?)
+        }
+        // nToPlaceholder, firstPlaceholder, snapshotConflictHorizon and
+        // isCatalogRel
+        (RM_SPGIST_ID, XLOG_SPGIST_VACUUM_REDIRECT) => {
+            (read_u32(main_data, 4)?, read_bool(main_data, 8)?)
+        }
+        _ => return None,
+    };
+    (xid != pg_sys::InvalidTransactionId.into_inner()).then_some(ConflictHorizon { xid, catalog })
+}
+
+/// Snapshot conflict horizon of a decoded record
+pub fn record_conflict_horizon(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Option<ConflictHorizon> {
+    if record.main_data.is_null() {
+        return None;
+    }
+    let main_data = unsafe {
+        std::slice::from_raw_parts(
+            record.main_data.cast::<u8>(),
+            usize::try_from(record.main_data_len).ok()?,
+        )
+    };
+    conflict_horizon(
+        u32::from(record.header.xl_rmid),
+        u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK,
+        main_data,
+    )
+}
+
+/// Relation of a record with a horizon, from its first block or from the
+/// locator of the page reuse records without blocks
+fn horizon_relation(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Option<pg_sys::RelFileLocator> {
+    if let Some((rlocator, _, _)) = get_block_tag_extended(xlog_reader, 0) {
+        return Some(rlocator);
+    }
+    if (record.main_data_len as usize) < size_of::<pg_sys::RelFileLocator>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::RelFileLocator>()) })
+}
+
+/// Records between `start_lsn` and `end_lsn` carrying a snapshot conflict
+/// horizon: prune, visible, and the index deletion and page reuse records.
+/// Replayed on a standby, they cancel the queries whose snapshot xmin
+/// precedes the horizon, in the record's database or in all of them for a
+/// catalog relation. `relid` is NULL for another database's relations.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_conflict_horizons(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(resource_manager, String),
+        name!(record_type, String),
+        name!(database, Option<pg_sys::Oid>),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(snapshot_conflict_horizon, pg_sys::TransactionId),
+        name!(is_catalog_rel, bool),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut relids = RelidCache::new();
+    TableIterator::new(std::iter::from_fn(move || loop {
+        let record = wal_decoder.read_record()?;
+        let xlog_reader = wal_decoder.xlog_reader();
+        relids.invalidate_for(xlog_reader, &record);
+        let Some(horizon) = record_conflict_horizon(&record) else {
+            continue;
+        };
+        let rlocator = horizon_relation(xlog_reader, &record);
+        let relid = rlocator
+            .filter(|rlocator| classify_database(rlocator) != RecordDatabase::Other)
+            .and_then(|rlocator| relids.get(&rlocator));
+        let rmid = record.header.xl_rmid;
+        return Some((
+            PgLSN::from(record.lsn),
+            rmgr_display_name(rmid),
+            record_type_name(rmid, record.header.xl_info),
+            rlocator.map(|rlocator| rlocator.dbOid),
+            relid,
+            pg_sys::TransactionId::from(horizon.xid),
+            horizon.catalog,
+        ));
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        horizon::{conflict_horizon, ConflictHorizon},
+        pg_lsn::PgLSN,
+    };
+
+    #[test]
+    fn test_conflict_horizon() {
+        use pgrx::pg_sys::RmgrIds::{RM_BTREE_ID, RM_HASH_ID, RM_HEAP2_ID, RM_SPGIST_ID};
+
+        let xid = 750u32.to_ne_bytes();
+        let horizon = |catalog| Some(ConflictHorizon { xid: 750, catalog });
+
+        // Prune with a horizon on a catalog relation, then without a horizon
+        let flags =
+            u8::try_from(pg_sys::XLHP_HAS_CONFLICT_HORIZON | pg_sys::XLHP_IS_CATALOG_REL).unwrap();
+        let prune = [&[0, flags][..], &xid].concat();
+        assert_eq!(
+            conflict_horizon(RM_HEAP2_ID, pg_sys::XLOG_HEAP2_PRUNE_ON_ACCESS, &prune),
+            horizon(true)
+        );
+        assert_eq!(
+            conflict_horizon(RM_HEAP2_ID, pg_sys::XLOG_HEAP2_PRUNE_VACUUM_SCAN, &[0, 0]),
+            None
+        );
+
+        let btree_delete = [&xid[..], &[1, 0, 0, 0, 0]].concat();
+        assert_eq!(
+            conflict_horizon(RM_BTREE_ID, pg_sys::XLOG_BTREE_DELETE, &btree_delete),
+            horizon(true)
+        );
+        let reuse = [&[0; 16][..], &xid, &[0; 4], &[0]].concat();
+        assert_eq!(
+            conflict_horizon(RM_BTREE_ID, pg_sys::XLOG_BTREE_REUSE_PAGE, &reuse),
+            horizon(false)
+        );
+        let redirect = [&[0; 4][..], &xid, &[0]].concat();
+        assert_eq!(
+            conflict_horizon(RM_SPGIST_ID, 0x80, &redirect),
+            horizon(false)
+        );
+
+        // Truncated records, an invalid horizon and other records
+        assert_eq!(
+            conflict_horizon(RM_BTREE_ID, pg_sys::XLOG_BTREE_DELETE, &xid),
+            None
+        );
+        assert_eq!(conflict_horizon(RM_HASH_ID, 0xB0, &[0; 8]), None);
+        assert_eq!(
+            conflict_horizon(RM_BTREE_ID, pg_sys::XLOG_BTREE_INSERT_LEAF, &btree_delete),
+            None
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_conflict_horizons() {
+        unsafe {
+            Spi::run("CREATE TABLE test_horizons (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_horizons SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Inserts remove nothing, no query can conflict with them
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_conflict_horizons('{startptr}', '{endptr}', 1)"
+        ));
+        assert_eq!(count, Ok(Some(0)));
+        let described = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_describe('{startptr}', '{endptr}', 1)
+             WHERE description ? 'snapshot_conflict_horizon'"
+        ));
+        assert_eq!(described, Ok(Some(0)));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use pgrx::{
+    pg_sys::{
+        self,
+        RmgrIds::{RM_BTREE_ID, RM_HEAP_ID},
+    },
+    prelude::*,
+};
+
+use crate::{
+    pg_lsn::PgLSN,
+    relation::{classify_database, RecordDatabase, RelidCache},
+    walinspect::{lsn_bounds, wal_decoder},
+    xlog_heap::{heap_op, item_pointer},
+    xlog_reader::{get_block_data, get_block_tag_extended},
+};
+
+/// Heap tid as block and offset
+type Tid = (pg_sys::BlockNumber, pg_sys::OffsetNumber);
+
+/// A heap change and the indexes it inserted entries in
+struct HeapWrite {
+    lsn: PgLSN,
+    xid: pg_sys::TransactionId,
+    relid: pg_sys::Oid,
+    op: &'static str,
+    tid: Tid,
+    indexes: Vec<pg_sys::Oid>,
+}
+
+/// Heap tid of the tuple inserted by a btree leaf insert, from the data of
+/// its leaf block. The tuple of a posting list split follows the offset in
+/// the posting list.
+fn inserted_heap_tid(info: u32, data: &[u8]) -> Option<Tid> {
+    let tuple = match info {
+        pg_sys::XLOG_BTREE_INSERT_LEAF => data,
+        pg_sys::XLOG_BTREE_INSERT_POST => data.get(size_of::<u16>()..)?,
+        _ => return None,
+    };
+    let tid = tuple.get(..size_of::<pg_sys::ItemPointerData>())?;
+    let word = |i: usize| u16::from_ne_bytes([tid[i], tid[i + 1]]);
+    Some(((u32::from(word(0)) << 16) | u32::from(word(2)), word(4)))
+}
+
+/// Row changes between `start_lsn` and `end_lsn` with the btree indexes they
+/// inserted entries in, matched on the transaction and heap tid of the
+/// entries. HOT updates have no index entries. Entries inserted by a page
+/// split and other index types aren't counted.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_index_writes(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(xid, pg_sys::TransactionId),
+        name!(relid, pg_sys::Oid),
+        name!(op, String),
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(indexes, Vec<pg_sys::Oid>),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut relids = RelidCache::new();
+    let mut writes: Vec<HeapWrite> = Vec::new();
+    let mut by_tid: HashMap<(pg_sys::TransactionId, Tid), usize> = HashMap::new();
+    while let Some(record) = wal_decoder.read_record() {
+        let xlog_reader = wal_decoder.xlog_reader();
+        let Some((rlocator, _, blknum)) = get_block_tag_extended(xlog_reader, 0) else {
+            continue;
+        };
+        if classify_database(&rlocator) == RecordDatabase::Other {
+            continue;
+        }
+        let xid = record.header.xl_xid;
+        match u32::from(record.header.xl_rmid) {
+            RM_HEAP_ID => {
+                let main_data = record.main_data;
+                let (op, offnum) = match heap_op(&record) {
+                    pg_sys::XLOG_HEAP_INSERT => {
+                        let xlrec = unsafe {
+                            std::ptr::read_unaligned(main_data.cast::<pg_sys::xl_heap_insert>())
+                        };
+                        ("INSERT", xlrec.offnum)
+                    }
+                    op @ (pg_sys::XLOG_HEAP_UPDATE | pg_sys::XLOG_HEAP_HOT_UPDATE) => {
+                        let xlrec = unsafe {
+                            std::ptr::read_unaligned(main_data.cast::<pg_sys::xl_heap_update>())
+                        };
+                        let name = if op == pg_sys::XLOG_HEAP_UPDATE {
+                            "UPDATE"
+                        } else {
+                            "HOT_UPDATE"
+                        };
+                        (name, xlrec.new_offnum)
+                    }
+                    _ => continue,
+                };
+                let Some(relid) = relids.get(&rlocator) else {
+                    continue;
+                };
+                by_tid.insert((xid, (blknum, offnum)), writes.len());
+                writes.push(HeapWrite {
+                    lsn: PgLSN::from(record.lsn),
+                    xid,
+                    relid,
+                    op,
+                    tid: (blknum, offnum),
+                    indexes: Vec::new(),
+                });
+            }
+            RM_BTREE_ID => {
+                let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+                let Some(tid) =
+                    get_block_data(xlog_reader, 0).and_then(|data| inserted_heap_tid(info, data))
+                else {
+                    continue;
+                };
+                let Some(&i) = by_tid.get(&(xid, tid)) else {
+                    continue;
+                };
+                let Some(index) = relids.get(&rlocator) else {
+                    continue;
+                };
+                // The same tid may be used by another table of the transaction
+                let heap = unsafe { pg_sys::IndexGetRelation(index, true) };
+                if heap == writes[i].relid {
+                    writes[i].indexes.push(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    TableIterator::new(writes.into_iter().map(|write| {
+        (
+            write.lsn,
+            write.xid,
+            write.relid,
+            write.op.to_string(),
+            item_pointer(write.tid.0, write.tid.1),
+            write.indexes,
+        )
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{index_writes::inserted_heap_tid, pg_lsn::PgLSN};
+
+    #[test]
+    fn test_inserted_heap_tid() {
+        let mut tuple = Vec::new();
+        for word in [1u16, 2, 3, 16] {
+            tuple.extend_from_slice(&word.to_ne_bytes());
+        }
+        assert_eq!(
+            inserted_heap_tid(pg_sys::XLOG_BTREE_INSERT_LEAF, &tuple),
+            Some(((1 << 16) | 2, 3))
+        );
+        let mut posting = 7u16.to_ne_bytes().to_vec();
+        posting.extend_from_slice(&tuple);
+        assert_eq!(
+            inserted_heap_tid(pg_sys::XLOG_BTREE_INSERT_POST, &posting),
+            Some(((1 << 16) | 2, 3))
+        );
+        assert_eq!(
+            inserted_heap_tid(pg_sys::XLOG_BTREE_INSERT_LEAF, &tuple[..4]),
+            None
+        );
+        assert_eq!(inserted_heap_tid(pg_sys::XLOG_BTREE_SPLIT_L, &tuple), None);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_index_writes() {
+        unsafe {
+            Spi::run("CREATE TABLE test_index_writes (id int primary key, data text, other int);");
+            Spi::run("CREATE INDEX test_index_writes_other ON test_index_writes (other)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_index_writes VALUES (1, 'a', 1)");
+            Spi::run("UPDATE test_index_writes SET data = 'b'");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let indexes = Spi::get_one::<Vec<i32>>(&format!(
+            "SELECT array_agg(cardinality(indexes) ORDER BY lsn)
+             FROM pg_waldecoder_index_writes('{startptr}', '{endptr}', 1)
+             WHERE relid = 'test_index_writes'::regclass"
+        ));
+        assert_eq!(indexes, Ok(Some(vec![2, 0])));
+    }
+}
@@ -1,9 +1,66 @@
+mod access;
+mod annotation;
+mod apply;
+mod archive;
+mod audit_worker;
+mod backup_label;
+mod blocks;
+mod buffer;
+mod column_types;
+mod conflict;
+mod dblink;
+mod ddl;
+mod debezium;
 mod decoder;
+mod description;
+mod divergence;
+mod export;
+mod fdw;
+#[cfg(any(test, feature = "pg_test"))]
+mod fixtures;
+mod fpi_check;
+mod guc;
+mod horizon;
+mod index_writes;
+mod maintenance;
+mod masking;
+mod materialize;
+mod memory;
+mod notify;
+mod origin;
+mod output;
+mod output_plugin;
+mod page;
+mod page_cache;
 mod pg_lsn;
+mod planner;
+mod progress;
 mod relation;
+mod remote;
+mod rmgr;
+mod s3;
+mod script;
+mod segments;
+mod session;
+mod shadow;
+mod sink;
+mod slot;
+mod stats;
+mod summary;
+mod template;
+mod throttle;
+mod time_travel;
+mod timeline;
+mod timing;
+mod toast;
 mod tuple_str;
+mod undo;
 mod wal;
+mod wal_settings;
+mod walinspect;
+mod xid;
 mod xlog_heap;
+mod xlog_newpage;
 mod xlog_reader;
 
 use std::{
@@ -16,57 +73,515 @@ use std::{
 };
 
 use pgrx::{
+    datum::Interval,
     pg_sys::{TimeLineID, WALRead, XLogReaderState, XLogSegNo, XLOG_BLCKSZ},
     prelude::*,
+    JsonB,
 };
 
 use crate::{
-    decoder::WalDecoder,
-    pg_lsn::{xlog_file_name, PgLSN},
+    access::check_decoder_access,
+    backup_label::read_backup_label,
+    column_types::foreign_definitions,
+    decoder::{EndBound, OnError},
+    guc::{verbose, UnsupportedRecords, Verbosity},
+    memory::last_memory_stats,
+    notify::NotifySink,
+    output::{OutputSink, SinkRows},
+    pg_lsn::xlog_file_name,
+    summary::last_scan_summary,
     wal::detect_wal_dir,
 };
 
+pub use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+};
+
 ::pgrx::pg_module_magic!(name, version);
 
-#[allow(clippy::type_complexity)]
-#[pg_extern]
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    guc::init();
+    progress::init();
+    audit_worker::register();
+}
+
+/// Decode the WAL from `start_lsn`. Rows are produced one call at a time as
+/// records are decoded, so a LIMIT or a cursor stops reading the WAL early. The
+/// segments read must belong to the local database system, checked by the WAL
+/// reader on their first page, unless `pg_waldecoder.check_system_identifier`
+/// is off.
+///
+/// A range spanning a promotion follows the history of the timeline, or the
+/// listed `timelines` when the history files are missing. Without `timeline`,
+/// the latest timeline of the history files and segment names is decoded.
+///
+/// `wal_dir` lists WAL directories and files separated by colons, listing
+/// segment files decodes only these segments.
+///
+/// `end_bound` decides what happens to the record crossing `end_lsn`:
+/// `record_end` only decodes the records ending by `end_lsn`, `record_start`
+/// decodes the records starting before it, reading the WAL past `end_lsn`,
+/// across segments, to complete the last one.
+///
+/// `unsupported_records` handles the records of custom resource managers,
+/// generic WAL and the multi-inserts of COPY, which can't be decoded: it skips
+/// them, reports them as changes without rows or raises an error, defaulting to
+/// `pg_waldecoder.unsupported_records`. `error` also fails on the heap records
+/// whose page or block is missing.
+///
+/// `on_error` handles the records that can't be read or decoded, a corrupted
+/// record or a relation that can't be found: `stop` raises an error, `skip`
+/// carries on with the next record and `emit` also returns a change with the op
+/// `ERROR` and the reason of the failure in `error`, keeping a complete trail
+/// of the WAL. The change of a relation that can't be found keeps its op, with
+/// the reason in `error`.
+///
+/// `prev_lsn`, `record_length` and `fpi_length` describe the record of each
+/// change, to spot chain breaks and the space taken by full page images.
+///
+/// `column_types` gives the columns of the relations altered since the WAL was
+/// written as `[schema.]table(column type, ...)` definitions, tuples are
+/// otherwise deformed with the current columns of their relation.
+///
+/// `relations` only decodes the changes of the listed relations and their toast
+/// tables, the records of other relations are skipped from their block
+/// references.
+///
+/// `foreign_relations` decodes the WAL of another cluster whose relations
+/// aren't in the local catalog, with a `{"relfilenode": "[schema.]table(column
+/// type, ...)"}` object. Their changes come without relid.
+///
+/// `include_newpages` reports the `FPI` records logging whole pages, written by
+/// table rewrites and init forks, as `NEWPAGE` changes listing the blocks.
+///
+/// `include_hint_fpis` reports the `FPI_FOR_HINT` records, logging a page when
+/// its hint bits are first set after a checkpoint with checksums or
+/// `wal_log_hints`, as `FPI` changes. Their images always refresh the cached
+/// pages.
+///
+/// `include_raw_tuple` fills `raw_tuple` with the tuple after the change, or
+/// the deleted one, header included, and `infomask`, `xmin`, `xmax` and `cid`
+/// with the flags, transactions and command id of its header.
+///
+/// `include_transactions` frames the changes of each transaction with a `BEGIN`
+/// row before the first one and a `COMMIT` or `ABORT` row at its end record,
+/// with the transaction's xid and `commit_time`.
+///
+/// `include_toast_chunks` also reports the changes of the toast tables. The
+/// toasted values of the rows are otherwise rebuilt from the chunks inserted in
+/// their toast table earlier in the scan, or rendered as NULL.
+///
+/// `query_template` is a statement rendered for each change in `redo_query`
+/// instead of the generated one, with placeholders like `{lsn}`, `{xid}`,
+/// `{table}` or `{row_after}` replaced by quoted literals, `revert_query` is
+/// then NULL.
+///
+/// `qualify_names => false` leaves the schema out of the relations of the
+/// generated queries, for queries run with a `search_path`. Identifiers are
+/// quoted when needed either way.
+///
+/// `annotate_queries` appends a comment with the LSN, xid and commit time of
+/// the change to its queries, keeping copied statements traceable.
+///
+/// `annotation_prefix` attaches the payload of the transactional logical
+/// messages with this prefix, like `pg_logical_emit_message(true, 'audit',
+/// 'user=alice request=42')`, to the following changes of their transaction as
+/// `annotation`, the latest message replacing the previous one.
+///
+/// `other_databases_conninfo` decodes the tuples of the other databases too,
+/// their catalogs are queried through dblink with the connection string
+/// completed by the database's name, as are their masked columns. Relations
+/// with dropped columns or custom types are still reported without rows. Their
+/// text values are converted from the database's encoding when it differs from
+/// the current one, or rendered as bytea when they can't be converted.
+///
+/// `strict_redo` checks that the pages replayed for each heap record end with
+/// the record's LSN, as real redo sets it. The changes of mismatching pages
+/// come with an `error`, the pages are dropped from the cache and counted in
+/// the `redo_mismatches` of the scan summary.
+///
+/// `max_runtime` stops reading once it has run for this long, and
+/// `stop_before_timeout` shortly before `statement_timeout` would cancel the
+/// query, returning the changes decoded so far.
+///
+/// A scan is continued from the `resume_lsn` of
+/// `pg_waldecoder_last_scan_summary()`, e.g. once `pg_waldecoder.max_rows` or
+/// `max_runtime` stopped it.
+///
+/// `page_cache_file` names a temporary file the cached pages are loaded from
+/// and saved to when the scan ends, the next chunk then decodes the pages the
+/// previous ones restored. The file is saved with the `resume_lsn`, loading it
+/// fails when the scan starts elsewhere. Writing it requires the privileges of
+/// pg_write_server_files.
+///
+/// `rows_hint` is the number of rows the planner expects from the call, 1000 by
+/// default, to plan the queries joining or streaming the changes.
+#[pg_extern(requires = ["change_type"])]
 fn pg_waldecoder(
     start_lsn: &str,
     end_lsn: default!(Option<&str>, "NULL"),
-    timeline: default!(i32, 1),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+    live: default!(bool, false),
+    conninfo: default!(Option<&str>, "NULL"),
+    skip_missing: default!(bool, false),
+    read_current_pages: default!(bool, false),
+    include_other_databases: default!(bool, false),
+    read_ahead: default!(bool, false),
+    include_new_cid: default!(bool, false),
+    include_visible: default!(bool, false),
+    include_newpages: default!(bool, false),
+    include_raw_record: default!(bool, false),
+    include_raw_tuple: default!(bool, false),
+    check_revert_conflicts: default!(bool, false),
+    notify_channel: default!(Option<&str>, "NULL"),
+    exclude_columns: default!(Option<Vec<String>>, "NULL"),
+    timelines: default!(Option<Vec<i32>>, "NULL"),
+    unsupported_records: default!(Option<&str>, "NULL"),
+    on_error: default!(Option<&str>, "NULL"),
+    column_types: default!(Option<Vec<String>>, "NULL"),
+    relations: default!(Option<Vec<String>>, "NULL"),
+    query_template: default!(Option<&str>, "NULL"),
+    qualify_names: default!(bool, true),
+    annotate_queries: default!(bool, false),
+    page_cache_file: default!(Option<&str>, "NULL"),
+    other_databases_conninfo: default!(Option<&str>, "NULL"),
+    include_transactions: default!(bool, false),
+    include_toast_chunks: default!(bool, false),
+    strict_redo: default!(bool, false),
+    end_bound: default!(&str, "'record_end'"),
+    annotation_prefix: default!(Option<&str>, "NULL"),
+    max_runtime: default!(Option<Interval>, "NULL"),
+    stop_before_timeout: default!(bool, false),
+    foreign_relations: default!(Option<JsonB>, "NULL"),
+    include_hint_fpis: default!(bool, false),
+    rows_hint: default!(Option<i32>, "NULL"),
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    // Parse start ptr
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+
+    let exclude_columns: Vec<&str> = exclude_columns
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    let column_types: Vec<&str> = column_types.iter().flatten().map(String::as_str).collect();
+    let foreign_relations = foreign_relations
+        .map(foreign_definitions)
+        .unwrap_or_default();
+    let foreign_relations: Vec<(pg_sys::Oid, &str)> = foreign_relations
+        .iter()
+        .map(|(relfilenumber, definition)| (*relfilenumber, definition.as_str()))
+        .collect();
+    let relations: Vec<&str> = relations.iter().flatten().map(String::as_str).collect();
+    let timelines = timelines.unwrap_or_default();
+    let unsupported_records = match unsupported_records.map(UnsupportedRecords::try_from) {
+        Some(Ok(unsupported_records)) => Some(unsupported_records),
+        Some(Err(e)) => error!("Error: {e}"),
+        None => None,
+    };
+    let on_error = match on_error.map(OnError::try_from) {
+        Some(Ok(on_error)) => Some(on_error),
+        Some(Err(e)) => error!("Error: {e}"),
+        None => None,
+    };
+    let end_bound = match EndBound::try_from(end_bound) {
+        Ok(end_bound) => end_bound,
+        Err(e) => error!("Error: {e}"),
+    };
+    let max_runtime = max_runtime.map(|max_runtime| match interval_micros(max_runtime) {
+        Some(micros) if micros > 0 => micros,
+        _ => error!("max_runtime must be a positive interval"),
+    });
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        timelines: &timelines,
+        wal_dir,
+        live,
+        conninfo,
+        skip_missing,
+        read_current_pages,
+        include_other_databases,
+        read_ahead,
+        include_new_cid,
+        include_visible,
+        include_newpages,
+        include_hint_fpis,
+        include_raw_record,
+        include_raw_tuple,
+        check_revert_conflicts,
+        exclude_columns: &exclude_columns,
+        column_types: &column_types,
+        foreign_relations: &foreign_relations,
+        relations: &relations,
+        query_template,
+        unqualified_names: !qualify_names,
+        annotate_queries,
+        page_cache_file,
+        other_databases_conninfo,
+        include_transactions,
+        include_toast_chunks,
+        strict_redo,
+        end_bound,
+        annotation_prefix,
+        max_runtime,
+        stop_before_timeout,
+        unsupported_records,
+        on_error,
+        ..Default::default()
+    };
+    verbose!(
+        Verbosity::Normal,
+        "Called with: {start_lsn:?}, {options:?}, {notify_channel:?}, {rows_hint:?}"
+    );
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    let sinks: Vec<Box<dyn OutputSink>> = notify_channel
+        .map(|channel| Box::new(NotifySink::new(channel)) as Box<dyn OutputSink>)
+        .into_iter()
+        .collect();
+    SetOfIterator::new(SinkRows::new(wal_decoder, sinks).map(DecodedResult::into_change))
+}
+
+/// Decode the changes of the next `count` records from `start_lsn`, like
+/// `pg_waldump -n`. Records that don't produce a change still count, so the
+/// next page starts at the `last_lsn` of `pg_waldecoder_last_scan_summary()`.
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_n(
+    start_lsn: &str,
+    count: i64,
+    timeline: default!(Option<i32>, "NULL"),
     wal_dir: default!(Option<&str>, "NULL"),
-) -> TableIterator<
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    verbose!(
+        Verbosity::Normal,
+        "Called with: {start_lsn:?}, {count:?}, {timeline:?}, {wal_dir:?}"
+    );
+
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let Ok(count) = u64::try_from(count) else {
+        error!("count must not be negative")
+    };
+
+    let options = DecoderOptions {
+        timeline,
+        wal_dir,
+        record_count: Some(count),
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
+}
+
+/// Decode the WAL passed in `data` from `start_lsn`, e.g. a segment stored in
+/// a table or fetched through a foreign table, without reading any file. The
+/// data must start at a page boundary, its position is taken from the
+/// address of its first page.
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_bytes(
+    data: &[u8],
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    verbose!(
+        Verbosity::Normal,
+        "Called with {} bytes: {start_lsn:?}, {end_lsn:?}, {timeline:?}",
+        data.len()
+    );
+
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_data: Some(data),
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
+}
+
+/// Decode what happened on the server since its latest checkpoint, from the
+/// checkpoint's redo point up to the flushed WAL
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_since_checkpoint(
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    let redoptr = PgLSN::from(unsafe { pg_sys::GetRedoRecPtr() });
+    verbose!(
+        Verbosity::Normal,
+        "Decoding since the checkpoint with redo at {redoptr}"
+    );
+    let options = DecoderOptions {
+        live: true,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(redoptr, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
+}
+
+const USECS_PER_DAY: i64 = 86_400_000_000;
+
+/// Microseconds of an interval, None when it overflows. Months are counted as
+/// 30 days, like interval comparisons do.
+pub(crate) fn interval_micros(interval: Interval) -> Option<i64> {
+    i64::from(interval.months())
+        .checked_mul(30)
+        .and_then(|days| days.checked_add(i64::from(interval.days())))
+        .and_then(|days| days.checked_mul(USECS_PER_DAY))
+        .and_then(|micros| micros.checked_add(interval.micros()))
+}
+
+/// Decode the WAL from `start_lsn` until the first commit more than
+/// `for_interval` after the first decoded commit. The interval needs an
+/// explicit cast, e.g. `pg_waldecoder('0/1000028', '5 minutes'::interval)`, to
+/// be told apart from an end LSN.
+#[pg_extern(name = "pg_waldecoder", requires = ["change_type"])]
+fn pg_waldecoder_for_interval(
+    start_lsn: &str,
+    for_interval: Interval,
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    verbose!(
+        Verbosity::Normal,
+        "Called with: {start_lsn:?}, {for_interval:?}"
+    );
+
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let for_interval = match interval_micros(for_interval) {
+        Some(micros) if micros > 0 => micros,
+        _ => error!("for_interval must be a positive interval"),
+    };
+
+    let options = DecoderOptions {
+        for_interval: Some(for_interval),
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
+}
+
+/// Memory used by the latest decoding of the session
+#[pg_extern(parallel_restricted)]
+fn pg_waldecoder_memory() -> TableIterator<
     'static,
     (
-        name!(lsn, i64),
-        name!(dboid, pg_sys::Oid),
-        name!(relid, pg_sys::Oid),
-        name!(xid, pg_sys::TransactionId),
-        name!(redo_query, Option<&'static str>),
-        name!(revert_query, Option<&'static str>),
-        name!(row_before, Option<&'static str>),
-        name!(row_after, Option<&'static str>),
+        name!(peak_record_bytes, i64),
+        name!(page_cache_bytes, i64),
+        name!(page_allocations, i64),
+        name!(toast_chunk_bytes, i64),
     ),
 > {
-    info!("Called with: {start_lsn:?}, {end_lsn:?}, {timeline:?}, {wal_dir:?}");
+    let stats = last_memory_stats();
+    TableIterator::once((
+        i64::try_from(stats.peak_record_bytes).unwrap_or(i64::MAX),
+        i64::try_from(stats.page_cache_bytes).unwrap_or(i64::MAX),
+        i64::try_from(stats.page_allocations).unwrap_or(i64::MAX),
+        i64::try_from(stats.toast_chunk_bytes).unwrap_or(i64::MAX),
+    ))
+}
 
-    // Parse start ptr
-    let startptr = match PgLSN::try_from(start_lsn) {
-        Ok(startptr) => startptr,
+/// Totals of the latest decoding of the session
+#[allow(clippy::type_complexity)]
+#[pg_extern(parallel_restricted)]
+fn pg_waldecoder_last_scan_summary() -> TableIterator<
+    'static,
+    (
+        name!(records_read, i64),
+        name!(skipped_non_heap, i64),
+        name!(skipped_no_page, i64),
+        name!(skipped_other, i64),
+        name!(unresolved_relids, i64),
+        name!(failed_records, i64),
+        name!(fpw_restored, i64),
+        name!(redo_mismatches, i64),
+        name!(bytes_scanned, i64),
+        name!(last_lsn, Option<PgLSN>),
+        name!(resume_lsn, Option<PgLSN>),
+    ),
+> {
+    let summary = last_scan_summary();
+    let to_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::once((
+        to_i64(summary.records_read),
+        to_i64(summary.skipped_non_heap),
+        to_i64(summary.skipped_no_page),
+        to_i64(summary.skipped_other),
+        to_i64(summary.unresolved_relids),
+        to_i64(summary.failed_records),
+        to_i64(summary.fpw_restored),
+        to_i64(summary.redo_mismatches),
+        to_i64(summary.bytes_scanned),
+        summary.last_lsn,
+        summary.resume_lsn,
+    ))
+}
+
+/// Records skipped by the latest decoding of the session, by reason: of
+/// other resource managers than heap, without a block reference, whose page
+/// was never seen, with an unresolved relid, not in the relations decoded,
+/// in another database, without a row change or writing toast chunks
+#[pg_extern(parallel_restricted)]
+fn pg_waldecoder_skip_stats() -> TableIterator<'static, (name!(reason, String), name!(records, i64))>
+{
+    let summary = last_scan_summary();
+    TableIterator::new(
+        summary
+            .skip_counts()
+            .map(|(reason, count)| (reason.to_string(), i64::try_from(count).unwrap_or(i64::MAX))),
+    )
+}
+
+/// Decode the WAL shipped with a base backup, from the backup's start up to
+/// the point where it reaches consistency
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_backup(
+    backup_dir: &str,
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    // The backup label is read before the decoder checks the access
+    check_decoder_access(Some(backup_dir));
+    let backup_label = match read_backup_label(Path::new(backup_dir)) {
+        Ok(backup_label) => backup_label,
         Err(e) => error!("Error: {}", e.to_string()),
     };
+    verbose!(
+        Verbosity::Normal,
+        "Decoding backup {:?} from {} on timeline {}",
+        backup_label.label,
+        backup_label.start_lsn,
+        backup_label.start_tli
+    );
 
-    let wal_decoder = WalDecoder::new(startptr, end_lsn, timeline, wal_dir);
-    //    let (results, err) = decode_wal_records(&xlog_reader, startptr);
-    TableIterator::new(wal_decoder.map(std::convert::Into::into))
+    let options = DecoderOptions {
+        timeline: Some(backup_label.start_tli.cast_signed()),
+        wal_dir: Some(backup_dir),
+        backup_start: Some(backup_label.start_lsn),
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(backup_label.start_lsn, &options);
+    SetOfIterator::new(wal_decoder.map(DecodedResult::into_change))
 }
 
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
     use crate::{
-        decoder::{DecodedResult, WalDecoder},
+        decoder::{DecodedResult, DecoderOptions, EndBound, OnError, WalDecoder},
+        guc::UnsupportedRecords,
         pg_lsn::PgLSN,
+        sink::try_in_subtransaction,
     };
     use pgrx::{pg_sys::XLogRecPtr, prelude::*};
     use std::ffi::{CStr, CString};
@@ -86,12 +601,1437 @@ mod tests {
             pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
         }
 
-        let wal_decoder = WalDecoder::new(startptr, None, 1, None);
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let wal_decoder = WalDecoder::new(startptr, &options);
         let results = wal_decoder.take(4).collect::<Vec<DecodedResult>>();
         assert_eq!(results.len(), 1);
         let decoded_record = &results[0];
         assert!(decoded_record.redo_query.is_some());
     }
+
+    #[test]
+    fn test_unsupported_records() {
+        assert_eq!(
+            UnsupportedRecords::try_from("error"),
+            Ok(UnsupportedRecords::Error)
+        );
+        assert!(UnsupportedRecords::try_from("strict").is_err());
+        assert_eq!(OnError::try_from("emit"), Ok(OnError::Emit));
+        assert!(OnError::try_from("ignore").is_err());
+        assert_eq!(
+            EndBound::try_from("record_start"),
+            Ok(EndBound::RecordStart)
+        );
+        assert!(EndBound::try_from("record").is_err());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_unsupported_multi_insert() {
+        unsafe {
+            Spi::run("CREATE TABLE test_multi_insert (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // COPY logs its rows as a heap2 multi-insert
+            Spi::run("COPY test_multi_insert FROM PROGRAM 'seq 1 3'");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let query = |unsupported_records: &str| {
+            format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                     unsupported_records => '{unsupported_records}')
+                 WHERE op LIKE 'Heap2/MULTI_INSERT%'"
+            )
+        };
+        assert_eq!(Spi::get_one::<i64>(&query("skip")), Ok(Some(0)));
+        assert_eq!(Spi::get_one::<i64>(&query("report")), Ok(Some(1)));
+        let error = try_in_subtransaction(|| {
+            Spi::run(&query("error")).unwrap();
+        })
+        .unwrap_err();
+        assert!(
+            error.contains("Record Heap2/MULTI_INSERT at"),
+            "unexpected error {error}"
+        );
+
+        // The record is in the context of the Rust error
+        Spi::run(&format!(
+            "CREATE FUNCTION test_error_context() RETURNS text LANGUAGE plpgsql AS $f$
+             DECLARE
+                 context text;
+             BEGIN
+                 PERFORM ({});
+                 RETURN NULL;
+             EXCEPTION WHEN OTHERS THEN
+                 GET STACKED DIAGNOSTICS context = PG_EXCEPTION_CONTEXT;
+                 RETURN context;
+             END
+             $f$",
+            query("error")
+        ))
+        .unwrap();
+        let context = Spi::get_one::<String>("SELECT test_error_context()")
+            .unwrap()
+            .unwrap();
+        assert!(
+            context.contains("while decoding WAL record at "),
+            "unexpected context {context}"
+        );
+    }
+
+    #[pg_test(
+        error = "Segment 000000010000000000000018 was written by PostgreSQL 17, only the WAL of PostgreSQL 18 can be decoded"
+    )]
+    fn test_pg_waldecoder_older_version() {
+        let dir = std::env::temp_dir().join("pg_waldecoder_older_version");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut segment = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/test/18_single_upgrade/000000010000000000000018"
+        ))
+        .unwrap();
+        // Magic of the pages written by 17
+        for page in segment.chunks_mut(8192) {
+            page[..2].copy_from_slice(&0xD116u16.to_le_bytes());
+        }
+        std::fs::write(dir.join("000000010000000000000018"), segment).unwrap();
+
+        Spi::run(&format!(
+            "SELECT * FROM pg_waldecoder('0/1800028', '0/1800D40', 1, wal_dir => '{}')",
+            dir.display()
+        ))
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_mmap_segments() {
+        let records = || {
+            Spi::get_one::<String>(concat!(
+                "SELECT string_agg(start_lsn::text, ',') FROM pg_waldecoder_describe('0/1800028', '0/1800D40', 1, wal_dir => '",
+                env!("CARGO_MANIFEST_DIR"),
+                "/resources/test/18_single_upgrade')"
+            ))
+        };
+        let read = records();
+        assert_eq!(
+            read,
+            Ok(Some("0/1800028,0/1800C50,0/1800CF8,0/1800D28".to_string()))
+        );
+        // The pages are copied from the mapped segment instead of read
+        Spi::run("SET LOCAL pg_waldecoder.mmap_segments = on").unwrap();
+        assert_eq!(records(), read);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_other_system() {
+        Spi::run("SET LOCAL pg_waldecoder.check_system_identifier = on").unwrap();
+        let error = try_in_subtransaction(|| {
+            Spi::run(concat!(
+                "SELECT * FROM pg_waldecoder('0/1800028', '0/1800D40', 1, wal_dir => '",
+                env!("CARGO_MANIFEST_DIR"),
+                "/resources/test/18_single_upgrade')"
+            ))
+            .unwrap();
+        })
+        .unwrap_err();
+        assert!(
+            error.contains("different database system"),
+            "unexpected error {error}"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_end_bound() {
+        unsafe {
+            Spi::run("CREATE TABLE test_end_bound (id int, data text);");
+            Spi::run("ALTER TABLE test_end_bound ALTER data SET STORAGE PLAIN;");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The scan needs a first record ending before the end
+            Spi::run("INSERT INTO test_end_bound VALUES (0, 'a')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let large_insert = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The insert spans several WAL pages
+            Spi::run("INSERT INTO test_end_bound SELECT 1, repeat('x', 6000)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = large_insert + 64u64;
+
+        let query = |end_bound: &str| {
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1, end_bound => '{end_bound}')
+                 WHERE relid = 'test_end_bound'::regclass"
+            ))
+        };
+        assert_eq!(query("record_end"), Ok(Some(1)));
+        assert_eq!(query("record_start"), Ok(Some(2)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_end_bound_contrecord() {
+        let segsz = Spi::get_one::<i64>(
+            "SELECT setting::bigint FROM pg_settings WHERE name = 'wal_segment_size'",
+        )
+        .unwrap()
+        .unwrap();
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogInsertRecPtr()) };
+        unsafe {
+            Spi::run("SELECT pg_logical_emit_message(false, 'test_end_bound', 'first')");
+        }
+        let message = unsafe { PgLSN::from(pg_sys::GetXLogInsertRecPtr()) };
+        let segment_end =
+            u64::from(message) - u64::from(message) % segsz.cast_unsigned() + segsz.cast_unsigned();
+        unsafe {
+            // The message is continued in the next segment
+            Spi::run(&format!(
+                "SELECT pg_logical_emit_message(false, 'test_end_bound', repeat('x', {}))",
+                segment_end - u64::from(message) + 8192
+            ));
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = message + 64u64;
+
+        let last_lsn = |end_bound: &str| {
+            Spi::run(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1, end_bound => '{end_bound}')"
+            ))
+            .unwrap();
+            Spi::get_one::<PgLSN>("SELECT last_lsn FROM pg_waldecoder_last_scan_summary()")
+                .unwrap()
+                .map(u64::from)
+        };
+        assert!(last_lsn("record_end").is_none_or(|last_lsn| last_lsn <= u64::from(endptr)));
+        assert!(last_lsn("record_start").is_some_and(|last_lsn| last_lsn > segment_end));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_rescan() {
+        unsafe {
+            Spi::run("CREATE TABLE test_rescan (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_rescan SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The function is called again for each row of the outer side
+        let counts = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(changes::text, ',') FROM generate_series(1, 3) g,
+             LATERAL (SELECT count(*) AS changes FROM pg_waldecoder('{startptr}', '{endptr}', g / g)
+                      WHERE relid = 'test_rescan'::regclass) s"
+        ));
+        assert_eq!(counts, Ok(Some("3,3,3".to_string())));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_release_on_error() {
+        unsafe {
+            Spi::run("CREATE TABLE test_release (id int, email text);");
+            // Hashing without a key fails the scan
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_release', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_release VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The segments opened by the failed scans are closed
+        let open_files = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let before = open_files();
+        for _ in 0..3 {
+            try_in_subtransaction(|| {
+                Spi::run(&format!(
+                    "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1)"
+                ))
+                .unwrap();
+            })
+            .unwrap_err();
+        }
+        // Nor are they left open by a scan stopped early
+        Spi::run("SET pg_waldecoder.mask_hash_key = 'secret'").unwrap();
+        Spi::run(&format!(
+            "SELECT * FROM pg_waldecoder('{startptr}', '{endptr}', 1) LIMIT 1"
+        ))
+        .unwrap();
+        assert_eq!(open_files(), before);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_on_error_skip() {
+        unsafe {
+            Spi::run("CREATE TABLE test_on_error (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let relfilenode = Spi::get_one::<pg_sys::Oid>(
+            "SELECT relfilenode FROM pg_class WHERE relname = 'test_on_error'",
+        )
+        .unwrap()
+        .unwrap();
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_on_error VALUES (1)");
+            Spi::run("DROP TABLE test_on_error");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The change of the dropped relation is only reported without on_error
+        let query = |on_error: &str| {
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1, on_error => {on_error})
+                 WHERE relfilenumber = {}",
+                relfilenode.to_u32()
+            ))
+        };
+        assert_eq!(query("NULL"), Ok(Some(1)));
+        assert_eq!(query("'skip'"), Ok(Some(0)));
+
+        // Emitted with the reason it's missing its row
+        let error = Spi::get_one::<String>(&format!(
+            "SELECT error FROM pg_waldecoder('{startptr}', timeline => 1, on_error => 'emit')
+             WHERE relfilenumber = {}",
+            relfilenode.to_u32()
+        ))
+        .unwrap();
+        assert!(
+            error.is_some_and(|error| error.starts_with("Couldn't find oid for rlocator")),
+            "missing error"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_on_error_drops_pages() {
+        unsafe {
+            Spi::run("CREATE TABLE test_on_error_pages (id int, email text);");
+            // Hashing without a key fails once the insert is replayed
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_on_error_pages', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_on_error_pages VALUES (1, 'a@example.com')");
+            Spi::run("UPDATE test_on_error_pages SET id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The page of the failed insert is dropped, the update can't be
+        // decoded without it
+        let (failed, error) = Spi::get_two::<i64, String>(&format!(
+            "SELECT count(*), min(error) FROM pg_waldecoder('{startptr}', '{endptr}', 1, on_error => 'emit')
+             WHERE op = 'ERROR'"
+        ))
+        .unwrap();
+        assert_eq!(failed, Some(1));
+        assert!(error.is_some_and(|error| error.contains("mask_hash_key must be set")));
+        let (failed_records, skipped_no_page) = Spi::get_two::<i64, i64>(
+            "SELECT failed_records, skipped_no_page FROM pg_waldecoder_last_scan_summary()",
+        )
+        .unwrap();
+        assert_eq!(failed_records, Some(1));
+        assert_eq!(skipped_no_page, Some(1));
+
+        // Skipped the same way
+        Spi::run(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1, on_error => 'skip')"
+        ))
+        .unwrap();
+        let failed_records =
+            Spi::get_one::<i64>("SELECT failed_records FROM pg_waldecoder_last_scan_summary()");
+        assert_eq!(failed_records, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_max_rows() {
+        unsafe {
+            Spi::run("CREATE TABLE test_max_rows (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_max_rows SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        Spi::run("SET pg_waldecoder.max_rows = 2").unwrap();
+        let rows = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1)"
+        ));
+        assert_eq!(rows, Ok(Some(2)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_include_transactions() {
+        unsafe {
+            Spi::run("CREATE TABLE test_include_transactions (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_include_transactions VALUES (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The test's transaction is still open, only its BEGIN row is returned
+        let ops = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(op, ',') FROM pg_waldecoder('{startptr}', timeline => 1,
+                 include_transactions => true)
+             WHERE op = 'BEGIN' OR relid = 'test_include_transactions'::regclass"
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(ops.starts_with("BEGIN,INSERT"), "{ops}");
+        assert_eq!(ops.matches("BEGIN").count(), 1);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_resume() {
+        unsafe {
+            Spi::run("CREATE TABLE test_resume (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_resume SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        Spi::run("SET pg_waldecoder.max_rows = 2").unwrap();
+        let rows = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after, ',') FROM pg_waldecoder('{startptr}', timeline => 1,
+                 page_cache_file => 'test_resume')"
+        ));
+        assert_eq!(rows, Ok(Some("(1),(2)".to_string())));
+        Spi::run("RESET pg_waldecoder.max_rows").unwrap();
+        let resume_lsn =
+            Spi::get_one::<PgLSN>("SELECT resume_lsn FROM pg_waldecoder_last_scan_summary()")
+                .unwrap()
+                .unwrap();
+
+        // The page of the previous inserts comes from the saved page cache
+        let rows = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after, ',') FROM pg_waldecoder('{resume_lsn}', timeline => 1,
+                 page_cache_file => 'test_resume')
+             WHERE relid = 'test_resume'::regclass"
+        ));
+        assert_eq!(rows, Ok(Some("(3)".to_string())));
+
+        // The saved pages don't match another start
+        let error = try_in_subtransaction(|| {
+            Spi::run(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1,
+                     page_cache_file => 'test_resume')"
+            ))
+            .unwrap();
+        })
+        .unwrap_err();
+        assert!(
+            error.contains("decoding must resume there"),
+            "unexpected error {error}"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_update_missing_tuple() {
+        unsafe {
+            Spi::run("CREATE TABLE test_update_missing (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_update_missing VALUES (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        Spi::run(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 page_cache_file => 'test_update_missing')"
+        ))
+        .unwrap();
+
+        // The saved page misses the second tuple, skipped by the next scan
+        unsafe {
+            Spi::run("INSERT INTO test_update_missing VALUES (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("UPDATE test_update_missing SET id = 3 WHERE id = 2");
+            Spi::run("INSERT INTO test_update_missing VALUES (4)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        // Pass the saved page off as saved where the next scan starts
+        let path = crate::page_cache::saved_cache_path("test_update_missing").unwrap();
+        let mut saved = std::fs::read(&path).unwrap();
+        saved[..8].copy_from_slice(&u64::from(startptr).to_le_bytes());
+        std::fs::write(&path, saved).unwrap();
+
+        // The page is dropped and the changes that follow still decode
+        let ops = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(op, ',' ORDER BY lsn) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 page_cache_file => 'test_update_missing')
+             WHERE relid = 'test_update_missing'::regclass"
+        ));
+        assert_eq!(ops, Ok(Some("HOT_UPDATE,INSERT".to_string())));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_record_info() {
+        unsafe {
+            Spi::run("CREATE TABLE test_record_info (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_record_info VALUES (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let consistent = Spi::get_one::<bool>(&format!(
+            "SELECT prev_lsn < lsn AND record_length > fpi_length FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_record_info'::regclass"
+        ));
+        assert_eq!(consistent, Ok(Some(true)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_strict_redo() {
+        unsafe {
+            Spi::run("CREATE TABLE test_strict_redo (id int, data text);");
+            Spi::run("CHECKPOINT");
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The first insert logs the page whole, the next records are
+            // replayed on it
+            Spi::run("INSERT INTO test_strict_redo VALUES (1, 'a')");
+            Spi::run("UPDATE test_strict_redo SET data = 'b'");
+            Spi::run("DELETE FROM test_strict_redo");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let errors = Spi::get_two::<i64, i64>(&format!(
+            "SELECT count(*), count(error) FROM pg_waldecoder('{startptr}', timeline => 1, strict_redo => true)
+             WHERE relid = 'test_strict_redo'::regclass"
+        ));
+        assert_eq!(errors, Ok((Some(3), Some(0))));
+        let mismatches =
+            Spi::get_one::<i64>("SELECT redo_mismatches FROM pg_waldecoder_last_scan_summary()");
+        assert_eq!(mismatches, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_wal_decoder_from_path() {
+        unsafe {
+            Spi::run("CREATE TABLE test_from_path (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_from_path (id, data) values (1, 'a')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let data_dir = Spi::get_one::<String>("SELECT current_setting('data_directory')")
+            .unwrap()
+            .unwrap();
+        let wal_dir = std::path::Path::new(&data_dir).join("pg_wal");
+        let results = crate::WalDecoder::from_path(&wal_dir, startptr, Some(endptr), Some(1))
+            .collect::<Vec<crate::DecodedResult>>();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].op, "INSERT");
+        assert_eq!(results[0].row_after.as_deref(), Some("(1,a)"));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_segment_file() {
+        unsafe {
+            Spi::run("CREATE TABLE test_segment_file (id int);");
+            // The changes are written in a new segment
+            Spi::run("SELECT pg_switch_wal()");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogInsertRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_segment_file VALUES (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Only the listed segment is read
+        let segment = Spi::get_one::<String>(&format!(
+            "SELECT current_setting('data_directory') || '/pg_wal/' || pg_walfile_name('{startptr}')"
+        ))
+        .unwrap()
+        .unwrap();
+        let row_after = Spi::get_one::<String>(&format!(
+            "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1, wal_dir => '{segment}')
+             WHERE relid = 'test_segment_file'::regclass"
+        ));
+        assert_eq!(row_after, Ok(Some("(1)".to_string())));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_change_type() {
+        unsafe {
+            Spi::run("CREATE TABLE test_change_type (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_change_type values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Changes can be stored as a whole
+        Spi::run(&format!(
+            "CREATE TABLE test_changes AS SELECT c AS change FROM pg_waldecoder('{startptr}', '{endptr}', 1) c"
+        ))
+        .unwrap();
+        let row_after = Spi::get_one::<String>("SELECT (change).row_after FROM test_changes");
+        assert_eq!(row_after, Ok(Some("(1)".to_string())));
+        // The epoch of the server's WAL is known from the start
+        let same_xid =
+            Spi::get_one::<bool>("SELECT xid((change).xid) = (change).raw_xid FROM test_changes");
+        assert_eq!(same_xid, Ok(Some(true)));
+        // Only the failures reported with on_error have an error
+        let error = Spi::get_one::<String>("SELECT (change).error FROM test_changes");
+        assert_eq!(error, Ok(None));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_ctid() {
+        unsafe {
+            Spi::run("CREATE TABLE test_ctid (id int);");
+            Spi::run("INSERT INTO test_ctid VALUES (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("UPDATE test_ctid SET id = 3 WHERE id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let (old_ctid, new_ctid) = Spi::get_two::<String, String>(&format!(
+            "SELECT old_ctid::text, new_ctid::text FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE op LIKE '%UPDATE'"
+        ))
+        .unwrap();
+        let current = Spi::get_one::<String>("SELECT ctid::text FROM test_ctid WHERE id = 3");
+        assert_eq!(old_ctid.as_deref(), Some("(0,2)"));
+        assert_eq!(new_ctid, current.unwrap());
+        let location = Spi::get_two::<i32, i64>(&format!(
+            "SELECT forknum, blkno FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE op LIKE '%UPDATE'"
+        ));
+        assert_eq!(location, Ok((Some(0), Some(0))));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_schema_mismatch() {
+        unsafe {
+            Spi::run("CREATE TABLE test_schema_mismatch (id int, data int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_schema_mismatch VALUES (1, 1)");
+            Spi::run("ALTER TABLE test_schema_mismatch ADD COLUMN other bigint NOT NULL DEFAULT 0");
+            Spi::run("INSERT INTO test_schema_mismatch VALUES (2, 2, 2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let mismatches = Spi::get_one::<Vec<bool>>(&format!(
+            "SELECT array_agg(schema_mismatch ORDER BY lsn) FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE op = 'INSERT' AND relid = 'test_schema_mismatch'::regclass"
+        ));
+        assert_eq!(mismatches, Ok(Some(vec![true, false])));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_relations() {
+        unsafe {
+            Spi::run("CREATE TABLE test_relations_a (id int);");
+            Spi::run("CREATE TABLE test_relations_b (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_relations_a VALUES (1)");
+            Spi::run("INSERT INTO test_relations_b VALUES (2)");
+            Spi::run("UPDATE test_relations_a SET id = 3");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let relids = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(relid::regclass::text ORDER BY lsn)
+             FROM pg_waldecoder('{startptr}', timeline => 1,
+                 relations => ARRAY['test_relations_a'])"
+        ));
+        assert_eq!(
+            relids,
+            Ok(Some(vec![
+                "test_relations_a".to_string(),
+                "test_relations_a".to_string()
+            ]))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_provenance() {
+        unsafe {
+            Spi::run("CREATE TABLE test_provenance (id int, data text);");
+            Spi::run("INSERT INTO test_provenance VALUES (1, 'a')");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The first change after the checkpoint logs the page
+            Spi::run("UPDATE test_provenance SET data = 'b'");
+            Spi::run("UPDATE test_provenance SET data = 'c'");
+            Spi::run("INSERT INTO test_provenance VALUES (2, 'd')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let provenance = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(provenance ORDER BY lsn) FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_provenance'::regclass"
+        ));
+        assert_eq!(
+            provenance,
+            Ok(Some(vec![
+                "fpi".to_string(),
+                "replay".to_string(),
+                "record".to_string()
+            ]))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_replay() {
+        unsafe {
+            Spi::run("CREATE TABLE test_replay (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // Only the insert initialises the page, following records need the
+            // cached page to be replayed
+            Spi::run("INSERT INTO test_replay (id, data) values (1, 'a')");
+            Spi::run("UPDATE test_replay SET id = 1000000");
+            Spi::run("DELETE FROM test_replay");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let wal_decoder = WalDecoder::new(startptr, &options);
+        let results = wal_decoder.take(3).collect::<Vec<DecodedResult>>();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].row_after.as_deref(), Some("(1,a)"));
+        assert_eq!(results[1].row_before.as_deref(), Some("(1,a)"));
+        assert_eq!(results[1].row_after.as_deref(), Some("(1000000,a)"));
+        assert_eq!(results[2].row_before.as_deref(), Some("(1000000,a)"));
+        assert_eq!(
+            results[2].revert_query.as_deref(),
+            Some("INSERT INTO public.test_replay (id, data) VALUES ('1000000', 'a');")
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_update_prefix_suffix() {
+        unsafe {
+            Spi::run("CREATE TABLE test_prefix (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // Only the last byte changes, the update logs the old tuple's prefix length
+            Spi::run("INSERT INTO test_prefix (id, data) values (1, 'aaaa')");
+            Spi::run("UPDATE test_prefix SET data = 'aaab'");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let wal_decoder = WalDecoder::new(startptr, &options);
+        let results = wal_decoder.take(2).collect::<Vec<DecodedResult>>();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].row_after.as_deref(), Some("(1,aaab)"));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_dropped_relation() {
+        unsafe {
+            Spi::run("CREATE TABLE test_dropped (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let relfilenode = Spi::get_one::<pg_sys::Oid>(
+            "SELECT relfilenode FROM pg_class WHERE relname = 'test_dropped'",
+        )
+        .unwrap()
+        .unwrap();
+
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_dropped (id) values (1)");
+            Spi::run("DROP TABLE test_dropped");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let wal_decoder = WalDecoder::new(startptr, &options);
+        let decoded_record = wal_decoder.take(1).next().unwrap();
+        assert!(decoded_record.relation_missing);
+        assert_eq!(decoded_record.relid, None);
+        assert_eq!(decoded_record.relfilenumber, relfilenode);
+        assert!(decoded_record.redo_query.is_none());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_read_current_pages() {
+        unsafe {
+            Spi::run("CREATE TABLE test_current (id int, data text);");
+            Spi::run("INSERT INTO test_current (id, data) values (1, 'a')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The page of the updated row was initialised before the decoded range
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("UPDATE test_current SET id = 1000000");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let results = WalDecoder::new(startptr, &options).collect::<Vec<DecodedResult>>();
+        assert!(results.is_empty());
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            read_current_pages: true,
+            ..Default::default()
+        };
+        let results = WalDecoder::new(startptr, &options).collect::<Vec<DecodedResult>>();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].row_after.is_some());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_insert_without_page() {
+        unsafe {
+            Spi::run("CREATE TABLE test_insert_record (id int, data text);");
+            Spi::run("INSERT INTO test_insert_record (id, data) values (1, 'a')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The page was initialised before the decoded range, the inserted
+        // tuple is taken from the record
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_insert_record (id, data) values (2, 'b')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let results = WalDecoder::new(startptr, &options).collect::<Vec<DecodedResult>>();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].row_after.as_deref(), Some("(2,b)"));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_limit() {
+        unsafe {
+            Spi::run("CREATE TABLE test_limit (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_limit (id) SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        let second_row = Spi::get_one::<PgLSN>(&format!(
+            "SELECT lsn FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_limit'::regclass ORDER BY lsn OFFSET 1 LIMIT 1"
+        ))
+        .unwrap()
+        .unwrap();
+
+        // Decoding stops after the first row instead of reading up to the end of WAL
+        let lsn = Spi::get_one::<PgLSN>(&format!(
+            "SELECT lsn FROM pg_waldecoder('{startptr}', timeline => 1) LIMIT 1"
+        ))
+        .unwrap();
+        assert!(lsn.is_some_and(|lsn| lsn >= startptr && lsn < second_row));
+        let (records_read, last_lsn) = Spi::get_two::<i64, PgLSN>(
+            "SELECT records_read, last_lsn FROM pg_waldecoder_last_scan_summary()",
+        )
+        .unwrap();
+        assert!(last_lsn.is_some_and(|last_lsn| last_lsn < second_row && last_lsn < endptr));
+        assert!(records_read.is_some_and(|records_read| records_read < 10));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_new_cid() {
+        unsafe { pg_sys::XLogFlush(pg_sys::XactLastRecEnd) };
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The catalog tuples of the table are logged with their command ids
+            Spi::run("CREATE TABLE test_new_cid (id int)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let query = |include_new_cid: bool| {
+            Spi::get_two::<i64, bool>(&format!(
+                "SELECT count(*), bool_and(raw_xid = pg_current_xact_id()::xid AND row_after LIKE '(%,%,%,\"(%,%)\")')
+                 FROM pg_waldecoder('{startptr}', timeline => 1, include_new_cid => {include_new_cid})
+                 WHERE op = 'NEW_CID' AND relid = 'pg_class'::regclass"
+            ))
+        };
+        let Ok((Some(new_cids), Some(matching))) = query(true) else {
+            panic!("Couldn't get NEW_CID records")
+        };
+        assert!(new_cids >= 1);
+        assert!(matching);
+        assert_eq!(query(false), Ok((Some(0), None)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_visible() {
+        // VACUUM can't run in the test's transaction
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s dbname=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'), current_database())",
+        )
+        .unwrap()
+        .unwrap();
+        let exec = |sql: &str| {
+            Spi::run(&format!(
+                "SELECT dblink_exec('{conninfo}', $sql${sql}$sql$)"
+            ))
+            .unwrap();
+        };
+        exec(
+            "DROP TABLE IF EXISTS test_visible;
+             CREATE TABLE test_visible (id int);
+             INSERT INTO test_visible VALUES (1)",
+        );
+        let startptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        exec("VACUUM (FREEZE) test_visible");
+        let endptr = unsafe {
+            pg_sys::XLogFlush(pg_sys::GetXLogInsertRecPtr());
+            PgLSN::from(pg_sys::GetXLogWriteRecPtr())
+        };
+
+        let query = |include_visible: bool| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(row_after, ',') FROM pg_waldecoder('{startptr}', '{endptr}', 1, include_visible => {include_visible})
+                 WHERE op = 'VISIBLE' AND relid = 'test_visible'::regclass"
+            ))
+        };
+        // The frozen block is all visible and all frozen
+        assert_eq!(query(true), Ok(Some("(0,t,t)".to_string())));
+        assert_eq!(query(false), Ok(None));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_origin() {
+        unsafe {
+            Spi::run("CREATE TABLE test_origin (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // Changes applied with a session origin, like a subscription's
+            Spi::run("INSERT INTO test_origin VALUES (1)");
+            Spi::run("SELECT pg_replication_origin_create('test_origin')");
+            Spi::run("SELECT pg_replication_origin_session_setup('test_origin')");
+            Spi::run("INSERT INTO test_origin VALUES (2)");
+            Spi::run("SELECT pg_replication_origin_session_reset()");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let origins = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after || ':' || coalesce(origin_name, '-'), ',' ORDER BY lsn)
+             FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_origin'::regclass
+                 AND origin_id IS NOT DISTINCT FROM pg_replication_origin_oid(origin_name)"
+        ));
+        assert_eq!(origins, Ok(Some("(1):-,(2):test_origin".to_string())));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_for_interval() {
+        // The interval is measured between commit records
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s dbname=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'), current_database())",
+        )
+        .unwrap()
+        .unwrap();
+        let exec = |sql: &str| {
+            Spi::run(&format!(
+                "SELECT dblink_exec('{conninfo}', $sql${sql}$sql$)"
+            ))
+            .unwrap();
+        };
+        exec("DROP TABLE IF EXISTS test_for_interval; CREATE TABLE test_for_interval (id int)");
+        let startptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        exec("INSERT INTO test_for_interval VALUES (1)");
+        exec("INSERT INTO test_for_interval VALUES (2)");
+        Spi::run("SELECT pg_sleep(1.5)").unwrap();
+        // Its commit is past the interval, the changes written after aren't
+        // decoded
+        exec("INSERT INTO test_for_interval VALUES (3)");
+        exec("INSERT INTO test_for_interval VALUES (4)");
+
+        let ids = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after, ',' ORDER BY lsn) FROM pg_waldecoder('{startptr}', '1 second'::interval)
+             WHERE relid = 'test_for_interval'::regclass"
+        ));
+        assert_eq!(ids, Ok(Some("(1),(2),(3)".to_string())));
+    }
+
+    #[pg_test(error = "for_interval must be a positive interval")]
+    fn test_pg_waldecoder_negative_interval() {
+        Spi::run("SELECT * FROM pg_waldecoder('0/1000028', '-5 minutes'::interval)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_memory() {
+        unsafe {
+            Spi::run("CREATE TABLE test_memory (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_memory (id) values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        let results = WalDecoder::new(startptr, &options).take(1).count();
+        assert_eq!(results, 1);
+        let Ok((Some(peak_record_bytes), Some(page_cache_bytes))) = Spi::get_two::<i64, i64>(
+            "SELECT peak_record_bytes, page_cache_bytes FROM pg_waldecoder_memory()",
+        ) else {
+            panic!("Couldn't get memory stats")
+        };
+        assert!(peak_record_bytes > 0);
+        assert_eq!(page_cache_bytes, i64::from(pg_sys::BLCKSZ));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_last_scan_summary() {
+        unsafe {
+            Spi::run("CREATE TABLE test_summary (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_summary values (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(WalDecoder::new(startptr, &options).take(2).count(), 2);
+        let Ok((Some(records_read), Some(skipped_no_page), Some(bytes_scanned))) =
+            Spi::get_three::<i64, i64, i64>(
+                "SELECT records_read, skipped_no_page, bytes_scanned FROM pg_waldecoder_last_scan_summary()",
+            )
+        else {
+            panic!("Couldn't get scan summary")
+        };
+        assert!(records_read >= 2);
+        assert_eq!(skipped_no_page, 0);
+        assert!(bytes_scanned > 0);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_max_runtime() {
+        unsafe {
+            Spi::run("CREATE TABLE test_max_runtime (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_max_runtime SELECT generate_series(1, 2000)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Decoding stops before reaching the end
+        let changes = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 max_runtime => '1 microsecond')"
+        ));
+        assert!(changes.unwrap().unwrap() < 2000);
+        let resume_lsn =
+            Spi::get_one::<PgLSN>("SELECT resume_lsn FROM pg_waldecoder_last_scan_summary()")
+                .unwrap()
+                .unwrap();
+        assert!(resume_lsn < endptr);
+
+        let changes = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 max_runtime => '1 hour', stop_before_timeout => true)"
+        ));
+        assert_eq!(changes, Ok(Some(2000)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_skip_stats() {
+        unsafe {
+            Spi::run("CREATE TABLE test_skip_kept (id int);");
+            Spi::run("CREATE TABLE test_skip_filtered (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_skip_kept values (1)");
+            Spi::run("INSERT INTO test_skip_filtered values (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let changes = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1,
+                 relations => ARRAY['test_skip_kept'])"
+        ));
+        assert_eq!(changes, Ok(Some(1)));
+        let skipped = |reason: &str| {
+            Spi::get_one::<i64>(&format!(
+                "SELECT records FROM pg_waldecoder_skip_stats() WHERE reason = '{reason}'"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert!(skipped("filtered") >= 2);
+        assert_eq!(skipped("no_page"), 0);
+        assert!(skipped("non_heap") > 0);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_raw_record() {
+        unsafe {
+            Spi::run("CREATE TABLE test_raw_record (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_raw_record values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // xl_rmid is at offset 17 of the record header
+        let Ok((Some(length), Some(rmid))) = Spi::get_two::<i32, i32>(&format!(
+            "SELECT length(raw_record), get_byte(raw_record, 17) FROM pg_waldecoder('{startptr}', timeline => 1, include_raw_record => true) LIMIT 1"
+        )) else {
+            panic!("Couldn't get raw record")
+        };
+        assert!(length > 24);
+        assert_eq!(rmid, i32::try_from(pg_sys::RmgrIds::RM_HEAP_ID).unwrap());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_persistence() {
+        unsafe {
+            Spi::run("CREATE TABLE test_persistence (id int);");
+            Spi::run("CREATE UNLOGGED TABLE test_persistence_unlogged (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_persistence VALUES (1)");
+            Spi::run("INSERT INTO test_persistence_unlogged VALUES (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The rows of unlogged tables aren't logged
+        let persistence = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(persistence) FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE op = 'INSERT'
+               AND relid IN ('test_persistence'::regclass, 'test_persistence_unlogged'::regclass)"
+        ));
+        assert_eq!(persistence, Ok(Some(vec!["permanent".to_string()])));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_qualify_names() {
+        unsafe {
+            Spi::run(r#"CREATE TABLE "Test Qualify" (id int);"#);
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run(r#"INSERT INTO "Test Qualify" values (1)"#);
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let query = |qualify_names: bool| {
+            Spi::get_one::<String>(&format!(
+                "SELECT redo_query FROM pg_waldecoder('{startptr}', timeline => 1,
+                     qualify_names => {qualify_names})
+                 WHERE relid = '\"Test Qualify\"'::regclass"
+            ))
+        };
+        assert_eq!(
+            query(true),
+            Ok(Some(
+                r#"INSERT INTO public."Test Qualify" (id) VALUES ('1');"#.to_string()
+            ))
+        );
+        assert_eq!(
+            query(false),
+            Ok(Some(
+                r#"INSERT INTO "Test Qualify" (id) VALUES ('1');"#.to_string()
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_annotate_queries() {
+        unsafe {
+            Spi::run("CREATE TABLE test_annotate_queries (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_annotate_queries values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let query = |column: &str| {
+            Spi::get_three::<PgLSN, i64, String>(&format!(
+                "SELECT lsn, raw_xid::text::bigint, {column}
+                 FROM pg_waldecoder('{startptr}', timeline => 1, annotate_queries => true)
+                 WHERE relid = 'test_annotate_queries'::regclass"
+            ))
+            .unwrap()
+        };
+        let (Some(lsn), Some(raw_xid), redo_query) = query("redo_query") else {
+            panic!("Couldn't get the insert")
+        };
+        let comment = format!("/* lsn={lsn} xid={raw_xid} */");
+        assert_eq!(
+            redo_query,
+            Some(format!(
+                "INSERT INTO public.test_annotate_queries (id) VALUES ('1'); {comment}"
+            ))
+        );
+        assert!(query("revert_query").2.unwrap().ends_with(&comment));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_raw_tuple() {
+        unsafe {
+            Spi::run("CREATE TABLE test_raw_tuple (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_raw_tuple values (1)");
+            Spi::run("DELETE FROM test_raw_tuple");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let query = |op: &str| {
+            Spi::get_two::<i32, bool>(&format!(
+                "SELECT length(raw_tuple), xmin = raw_xid OR xmax = raw_xid
+                 FROM pg_waldecoder('{startptr}', timeline => 1, include_raw_tuple => true)
+                 WHERE op = '{op}' AND relid = 'test_raw_tuple'::regclass"
+            ))
+        };
+        // The header and the int column
+        let (Ok((Some(insert_length), Some(true))), Ok((Some(_), Some(true)))) =
+            (query("INSERT"), query("DELETE"))
+        else {
+            panic!("Couldn't get raw tuples")
+        };
+        assert!(insert_length >= 28);
+        let flags = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT infomask FROM pg_waldecoder('{startptr}', timeline => 1, include_raw_tuple => true)
+             WHERE op = 'INSERT' AND relid = 'test_raw_tuple'::regclass"
+        ));
+        assert_eq!(flags, Ok(Some(vec!["HEAP_XMAX_INVALID".to_string()])));
+        // The inserted tuple is rebuilt from the record
+        let cid = Spi::get_one::<i64>(&format!(
+            "SELECT cid FROM pg_waldecoder('{startptr}', timeline => 1, include_raw_tuple => true)
+             WHERE op = 'INSERT' AND relid = 'test_raw_tuple'::regclass"
+        ));
+        assert_eq!(cid, Ok(Some(0)));
+        let raw_tuple = Spi::get_one::<Vec<u8>>(&format!(
+            "SELECT raw_tuple FROM pg_waldecoder('{startptr}', timeline => 1) LIMIT 1"
+        ));
+        assert_eq!(raw_tuple, Ok(None));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_toplevel_xid() {
+        unsafe {
+            Spi::run("CREATE TABLE test_toplevel_xid (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // Each exception block runs its insert in a subtransaction, the
+            // 64th subtransaction logs the assignment of the previous ones
+            Spi::run(
+                "DO $$ BEGIN
+                    FOR i IN 1..64 LOOP
+                        BEGIN
+                            INSERT INTO test_toplevel_xid VALUES (i);
+                        EXCEPTION WHEN others THEN NULL;
+                        END;
+                    END LOOP;
+                END $$",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let Ok((Some(subxact), Some(toplevel))) = Spi::get_two::<bool, bool>(&format!(
+            "SELECT raw_xid <> toplevel_xid, toplevel_xid = pg_current_xact_id()::xid FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_toplevel_xid'::regclass AND row_after = '(64)'"
+        )) else {
+            panic!("Couldn't get toplevel xid")
+        };
+        assert!(subxact);
+        assert!(toplevel);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_changes() {
+        unsafe {
+            Spi::run("CREATE TABLE test_changes (id int, a text, b text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_changes VALUES (1, 'x', 'y')");
+            Spi::run("UPDATE test_changes SET b = 'z'");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let changes = Spi::get_one::<String>(&format!(
+            "SELECT changes::text FROM pg_waldecoder('{startptr}', timeline => 1) WHERE op = 'HOT_UPDATE'"
+        ));
+        assert_eq!(
+            changes,
+            Ok(Some(r#"{"b": {"new": "z", "old": "y"}}"#.to_string()))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_record_count() {
+        unsafe {
+            Spi::run("CREATE TABLE test_record_count (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_record_count SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let options = DecoderOptions {
+            timeline: Some(1),
+            record_count: Some(3),
+            ..Default::default()
+        };
+        let mut wal_decoder = WalDecoder::new(startptr, &options);
+        assert!(wal_decoder.by_ref().count() <= 3);
+        assert_eq!(wal_decoder.scan_summary().records_read, 3);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_since_checkpoint() {
+        Spi::run("CHECKPOINT").unwrap();
+        unsafe {
+            Spi::run("CREATE TABLE test_since_checkpoint (id int);");
+            Spi::run("INSERT INTO test_since_checkpoint values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let inserts = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_waldecoder_since_checkpoint() WHERE op = 'INSERT'",
+        );
+        assert!(inserts.unwrap().is_some_and(|inserts| inserts >= 1));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_live() {
+        unsafe {
+            Spi::run("CREATE TABLE test_live (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("Insert INTO test_live (id) values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // Live mode stops at the flushed LSN instead of hitting the end of WAL
+        let options = DecoderOptions {
+            live: true,
+            ..Default::default()
+        };
+        let wal_decoder = WalDecoder::new(startptr, &options);
+        let results = wal_decoder.collect::<Vec<DecodedResult>>();
+        assert_eq!(results.len(), 1);
+    }
 }
 
 /// This module is required by `cargo pgrx test` invocations.
@@ -105,6 +2045,13 @@ pub mod pg_test {
     #[must_use]
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        // NEW_CID records and logical slots need a logical wal_level, the
+        // status views need the shared memory of a preloaded library
+        // The fixtures were written by other clusters
+        vec![
+            "wal_level = 'logical'",
+            "shared_preload_libraries = 'pg_waldecoder'",
+            "pg_waldecoder.check_system_identifier = off",
+        ]
     }
 }
@@ -0,0 +1,292 @@
+use pgrx::{pg_sys, prelude::*, PgBox, TimestampWithTimeZone};
+
+use crate::{
+    pg_lsn::PgLSN,
+    relation::RelationFilter,
+    walinspect::{lsn_bounds, record_type_name, wal_decoder},
+    xid::xact_end,
+    xlog_heap::heap_op,
+    xlog_reader::{get_block_data, get_block_tag_extended},
+};
+
+/// Size of a `xlhp_freeze_plan`: xmax, t_infomask2, t_infomask, frzflags and
+/// ntuples
+const FREEZE_PLAN_SIZE: usize = 12;
+
+/// Offset of the plans of `xlhp_freeze_plans`, aligned after nplans
+const FREEZE_PLANS_OFFSET: usize = 4;
+
+/// Offset of the ntuples of a freeze plan
+const FREEZE_PLAN_NTUPLES_OFFSET: usize = 10;
+
+/// Items changed by a prune and freeze record on its heap page
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct PruneCounts {
+    redirected: u16,
+    now_dead: u16,
+    now_unused: u16,
+    frozen: u16,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + size_of::<u16>())?;
+    Some(u16::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// Counts of the items of a prune and freeze record from its `flags` and the
+/// data of its heap block, laid out as the freeze plans, the redirected
+/// pairs, the dead items and the unused items
+fn prune_counts(flags: u32, data: &[u8]) -> Option<PruneCounts> {
+    let mut counts = PruneCounts::default();
+    let mut cursor = 0;
+    if flags & pg_sys::XLHP_HAS_FREEZE_PLANS != 0 {
+        let nplans = usize::from(read_u16(data, cursor)?);
+        cursor += FREEZE_PLANS_OFFSET;
+        for _ in 0..nplans {
+            let ntuples = read_u16(data, cursor + FREEZE_PLAN_NTUPLES_OFFSET)?;
+            counts.frozen = counts.frozen.checked_add(ntuples)?;
+            cursor += FREEZE_PLAN_SIZE;
+        }
+    }
+    // ntargets followed by their offsets, pairs of offsets for redirections
+    let mut items = |flag: u32, offsets_per_target: usize| -> Option<u16> {
+        if flags & flag == 0 {
+            return Some(0);
+        }
+        let ntargets = read_u16(data, cursor)?;
+        cursor += size_of::<u16>() * (1 + offsets_per_target * usize::from(ntargets));
+        (cursor <= data.len()).then_some(ntargets)
+    };
+    counts.redirected = items(pg_sys::XLHP_HAS_REDIRECTIONS, 2)?;
+    counts.now_dead = items(pg_sys::XLHP_HAS_DEAD_ITEMS, 1)?;
+    counts.now_unused = items(pg_sys::XLHP_HAS_NOW_UNUSED_ITEMS, 1)?;
+    Some(counts)
+}
+
+/// Maintenance records of `relation` between `start_lsn` and `end_lsn`: the
+/// pruning and freezing of its heap blocks, by page accesses and the scan and
+/// cleanup passes of vacuum, and the setting of their visibility map bits.
+/// `tuples_removed` counts the tuples whose storage was reclaimed, the
+/// redirected, dead and unused items of a prune. A vacuum cleanup only marks
+/// unused the items its scan already counted as dead. The counts are NULL for
+/// the records logged with a full page image, which drops the block data.
+/// `xact_time` is the time of the latest transaction end read before the
+/// record, to place it in time. The relation is matched by its current
+/// relfilenode.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_maintenance(
+    relation: &str,
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(xact_time, Option<TimestampWithTimeZone>),
+        name!(record_type, String),
+        name!(blkno, i64),
+        name!(tuples_removed, Option<i32>),
+        name!(redirected, Option<i32>),
+        name!(now_dead, Option<i32>),
+        name!(now_unused, Option<i32>),
+        name!(frozen, Option<i32>),
+        name!(all_visible, Option<bool>),
+        name!(all_frozen, Option<bool>),
+    ),
+> {
+    let filter = RelationFilter::new(&[relation]);
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut xact_time = None;
+    TableIterator::new(std::iter::from_fn(move || loop {
+        let record = wal_decoder.read_record()?;
+        if let Some(xact_end) = xact_end(&record) {
+            xact_time = TimestampWithTimeZone::try_from(xact_end.time).ok();
+            continue;
+        }
+        let xlog_reader = wal_decoder.xlog_reader();
+        if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_HEAP2_ID
+            || !filter.matches(xlog_reader, &record)
+        {
+            continue;
+        }
+        let record_type = record_type_name(record.header.xl_rmid, record.header.xl_info);
+        let row = match heap_op(&record) {
+            pg_sys::XLOG_HEAP2_PRUNE_ON_ACCESS
+            | pg_sys::XLOG_HEAP2_PRUNE_VACUUM_SCAN
+            | pg_sys::XLOG_HEAP2_PRUNE_VACUUM_CLEANUP => {
+                prune_row(xlog_reader, &record, xact_time, record_type)
+            }
+            pg_sys::XLOG_HEAP2_VISIBLE => visible_row(xlog_reader, &record, xact_time, record_type),
+            _ => None,
+        };
+        if row.is_some() {
+            return row;
+        }
+    }))
+}
+
+type MaintenanceRow = (
+    PgLSN,
+    Option<TimestampWithTimeZone>,
+    String,
+    i64,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<bool>,
+    Option<bool>,
+);
+
+fn prune_row(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    xact_time: Option<TimestampWithTimeZone>,
+    record_type: String,
+) -> Option<MaintenanceRow> {
+    let (_, _, blknum) = get_block_tag_extended(xlog_reader, 0)?;
+    if record.main_data.is_null() || record.main_data_len < 2 {
+        return None;
+    }
+    // reason and flags of xl_heap_prune
+    let flags = u32::from(unsafe { *record.main_data.cast::<u8>().add(1) });
+    let counts = get_block_data(xlog_reader, 0).and_then(|data| prune_counts(flags, data));
+    let removed = counts.map(|counts| {
+        if heap_op(record) == pg_sys::XLOG_HEAP2_PRUNE_VACUUM_CLEANUP {
+            0
+        } else {
+            i32::from(counts.redirected) + i32::from(counts.now_dead) + i32::from(counts.now_unused)
+        }
+    });
+    Some((
+        PgLSN::from(record.lsn),
+        xact_time,
+        record_type,
+        i64::from(blknum),
+        removed,
+        counts.map(|counts| i32::from(counts.redirected)),
+        counts.map(|counts| i32::from(counts.now_dead)),
+        counts.map(|counts| i32::from(counts.now_unused)),
+        counts.map(|counts| i32::from(counts.frozen)),
+        None,
+        None,
+    ))
+}
+
+fn visible_row(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    xact_time: Option<TimestampWithTimeZone>,
+    record_type: String,
+) -> Option<MaintenanceRow> {
+    // Block 0 is the visibility map page, block 1 the heap page
+    let (_, _, blknum) = get_block_tag_extended(xlog_reader, 1)?;
+    if record.main_data.is_null() {
+        return None;
+    }
+    let xlrec =
+        unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_visible>()) };
+    let flags = u32::from(xlrec.flags);
+    Some((
+        PgLSN::from(record.lsn),
+        xact_time,
+        record_type,
+        i64::from(blknum),
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(flags & pg_sys::VISIBILITYMAP_ALL_VISIBLE != 0),
+        Some(flags & pg_sys::VISIBILITYMAP_ALL_FROZEN != 0),
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        maintenance::{prune_counts, PruneCounts},
+        pg_lsn::PgLSN,
+    };
+
+    #[test]
+    fn test_prune_counts() {
+        let u16s = |values: &[u16]| {
+            values
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect::<Vec<_>>()
+        };
+        // A plan freezing 3 tuples, a redirection, 2 dead items and their
+        // frozen offsets
+        let plan = [&[0u8; 10][..], &3u16.to_ne_bytes()].concat();
+        let data = [
+            &u16s(&[1, 0])[..],
+            &plan,
+            &u16s(&[1, 1, 2]),
+            &u16s(&[2, 3, 4]),
+            &u16s(&[5, 6, 7]),
+        ]
+        .concat();
+        let flags = pg_sys::XLHP_HAS_FREEZE_PLANS
+            | pg_sys::XLHP_HAS_REDIRECTIONS
+            | pg_sys::XLHP_HAS_DEAD_ITEMS;
+        assert_eq!(
+            prune_counts(flags, &data),
+            Some(PruneCounts {
+                redirected: 1,
+                now_dead: 2,
+                now_unused: 0,
+                frozen: 3,
+            })
+        );
+
+        let unused = u16s(&[3, 1, 2, 3]);
+        assert_eq!(
+            prune_counts(pg_sys::XLHP_HAS_NOW_UNUSED_ITEMS, &unused),
+            Some(PruneCounts {
+                now_unused: 3,
+                ..Default::default()
+            })
+        );
+        // Truncated data
+        assert_eq!(
+            prune_counts(pg_sys::XLHP_HAS_NOW_UNUSED_ITEMS, &unused[..4]),
+            None
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_maintenance() {
+        unsafe {
+            Spi::run("CREATE TABLE test_maintenance (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_maintenance SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // Inserts into a new table don't prune or freeze anything
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_maintenance('test_maintenance', '{startptr}', '{endptr}', 1)"
+        ));
+        assert_eq!(count, Ok(Some(0)));
+    }
+
+    #[pg_test(error = "Relation missing_maintenance of the filter doesn't exist")]
+    fn test_pg_waldecoder_maintenance_missing_relation() {
+        Spi::run("SELECT * FROM pg_waldecoder_maintenance('missing_maintenance', '0/0', '0/0')")
+            .unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+
+use pgrx::{extension_sql, pg_sys, prelude::*};
+
+use crate::{
+    guc::MASK_HASH_KEY,
+    s3::{hex_encode, hmac_sha256},
+    tuple_str::{quote_identifier, ColumnValue},
+};
+
+extension_sql!(
+    r"
+CREATE TABLE pg_waldecoder_masked_column (
+    relid regclass NOT NULL,
+    column_name name NOT NULL,
+    method text NOT NULL DEFAULT 'mask' CHECK (method IN ('mask', 'hash')),
+    PRIMARY KEY (relid, column_name)
+);
+SELECT pg_catalog.pg_extension_config_dump('pg_waldecoder_masked_column', '');
+",
+    name = "masked_column_catalog",
+);
+
+/// Replacement of a masked value
+const MASK: &str = "***";
+
+/// Schema of the extension, holding `pg_waldecoder_masked_column`
+pub(crate) const EXTENSION_SCHEMA_QUERY: &str = "SELECT n.nspname::text
+FROM pg_catalog.pg_extension e
+JOIN pg_catalog.pg_namespace n ON n.oid = e.extnamespace
+WHERE e.extname = 'pg_waldecoder'";
+
+/// Masks of a relation, from the catalog of the extension's `schema`
+pub(crate) fn masks_query(schema: &str, relid: pg_sys::Oid) -> String {
+    format!(
+        "SELECT column_name::text, method FROM {}.pg_waldecoder_masked_column WHERE relid = {}",
+        quote_identifier(schema),
+        relid.to_u32()
+    )
+}
+
+/// How the value of a masked column is rendered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskMethod {
+    /// Replaced by a fixed string
+    Mask,
+    /// Replaced by its HMAC-SHA256 keyed by `pg_waldecoder.mask_hash_key`,
+    /// so equal values can still be matched
+    Hash,
+}
+
+impl TryFrom<&str> for MaskMethod {
+    type Error = String;
+
+    fn try_from(method: &str) -> Result<Self, Self::Error> {
+        match method {
+            "mask" => Ok(MaskMethod::Mask),
+            "hash" => Ok(MaskMethod::Hash),
+            _ => Err(format!("Unknown mask method '{method}'")),
+        }
+    }
+}
+
+fn mask_value(value: &str, method: MaskMethod, hash_key: &[u8]) -> String {
+    match method {
+        MaskMethod::Mask => MASK.to_string(),
+        MaskMethod::Hash => hex_encode(&hmac_sha256(hash_key, value)),
+    }
+}
+
+/// Mask the configured columns, NULLs are kept as they are. Hashing without
+/// a key raises an error, an unkeyed hash of low entropy values is reversed
+/// by hashing their candidates.
+pub fn mask_columns(
+    columns: &mut [ColumnValue],
+    masks: &[(String, MaskMethod)],
+    hash_key: Option<&[u8]>,
+) {
+    for column in columns {
+        let Some((_, method)) = masks.iter().find(|(name, _)| *name == column.name) else {
+            continue;
+        };
+        let hash_key = match (method, hash_key) {
+            (MaskMethod::Hash, None) => error!(
+                "pg_waldecoder.mask_hash_key must be set to hash the masked column {}",
+                column.name
+            ),
+            (_, hash_key) => hash_key.unwrap_or_default(),
+        };
+        column.value = column
+            .value
+            .as_deref()
+            .map(|v| mask_value(v, *method, hash_key));
+    }
+}
+
+/// Column whose values are left out of the rows, from a
+/// `[schema.]table.column` pattern where any part can be `*`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnExclusion {
+    schema: Option<String>,
+    table: String,
+    column: String,
+}
+
+impl TryFrom<&str> for ColumnExclusion {
+    type Error = String;
+
+    fn try_from(pattern: &str) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = pattern.split('.').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(format!("Invalid column pattern '{pattern}'"));
+        }
+        let (schema, table, column) = match parts[..] {
+            [table, column] => (None, table, column),
+            [schema, table, column] => (Some(schema.to_string()), table, column),
+            _ => {
+                return Err(format!(
+                    "Invalid column pattern '{pattern}', expected [schema.]table.column"
+                ))
+            }
+        };
+        Ok(ColumnExclusion {
+            schema,
+            table: table.to_string(),
+            column: column.to_string(),
+        })
+    }
+}
+
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    pattern == "*" || pattern == name
+}
+
+impl ColumnExclusion {
+    fn matches(&self, schema: &str, table: &str, column: &str) -> bool {
+        self.schema
+            .as_deref()
+            .is_none_or(|pattern| pattern_matches(pattern, schema))
+            && pattern_matches(&self.table, table)
+            && pattern_matches(&self.column, column)
+    }
+}
+
+/// Render the excluded columns of the relation as NULL, the rows keep the
+/// relation's row type
+pub fn exclude_columns(
+    columns: &mut [ColumnValue],
+    exclusions: &[ColumnExclusion],
+    schema: &str,
+    table: &str,
+) {
+    for column in columns {
+        if exclusions
+            .iter()
+            .any(|exclusion| exclusion.matches(schema, table, &column.name))
+        {
+            column.value = None;
+        }
+    }
+}
+
+/// Masked columns of each relation, read from `pg_waldecoder_masked_column`
+/// the first time a relation is decoded, and the columns excluded from all
+/// relations
+#[derive(Default)]
+pub struct MaskCache {
+    masks: HashMap<pg_sys::Oid, Vec<(String, MaskMethod)>>,
+    exclusions: Vec<ColumnExclusion>,
+    hash_key: Option<Vec<u8>>,
+}
+
+fn load_masks(relid: pg_sys::Oid) -> Vec<(String, MaskMethod)> {
+    // The catalog is missing when the library is used without the extension,
+    // it's looked up in the extension's schema, not shadowed by the
+    // search_path
+    let Ok(Some(schema)) = Spi::get_one::<String>(EXTENSION_SCHEMA_QUERY) else {
+        return Vec::new();
+    };
+    Spi::connect(|client| {
+        client
+            .select(&masks_query(&schema, relid), None, &[])?
+            .map(|row| {
+                let name = row.get::<String>(1)?.unwrap_or_default();
+                let method = row.get::<String>(2)?.unwrap_or_default();
+                Ok((
+                    name,
+                    MaskMethod::try_from(method.as_str()).unwrap_or(MaskMethod::Mask),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap()
+}
+
+impl MaskCache {
+    pub fn new(exclusions: Vec<ColumnExclusion>) -> MaskCache {
+        MaskCache {
+            exclusions,
+            hash_key: MASK_HASH_KEY
+                .get()
+                .map(|key| key.to_bytes().to_vec())
+                .filter(|key| !key.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    /// Mask the configured columns of the relation
+    pub fn apply(&mut self, rel: &PgRelation, columns: &mut [ColumnValue]) {
+        let relid = rel.oid();
+        let masks = self.masks.entry(relid).or_insert_with(|| load_masks(relid));
+        mask_columns(columns, masks, self.hash_key.as_deref());
+    }
+
+    /// Mask the columns of a relation of another database with its masks
+    pub fn apply_masks(&self, columns: &mut [ColumnValue], masks: &[(String, MaskMethod)]) {
+        mask_columns(columns, masks, self.hash_key.as_deref());
+    }
+
+    /// Hide the excluded columns of the displayed rows, once the queries
+    /// were generated with all of them
+    pub fn exclude(&self, columns: &mut [ColumnValue], schema: &str, table: &str) {
+        if !self.exclusions.is_empty() {
+            exclude_columns(columns, &self.exclusions, schema, table);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        masking::{exclude_columns, mask_columns, ColumnExclusion, MaskMethod},
+        pg_lsn::PgLSN,
+        tuple_str::ColumnValue,
+    };
+    use pgrx::prelude::*;
+
+    fn column(name: &str, value: Option<&str>) -> ColumnValue {
+        ColumnValue {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            generated: false,
+            identity_always: false,
+        }
+    }
+
+    #[test]
+    fn test_mask_columns() {
+        let mut columns = vec![
+            column("id", Some("1")),
+            column("email", Some("a@example.com")),
+            column("ssn", Some("abc")),
+            column("phone", None),
+        ];
+        let masks = vec![
+            ("email".to_string(), MaskMethod::Mask),
+            ("ssn".to_string(), MaskMethod::Hash),
+            ("phone".to_string(), MaskMethod::Mask),
+        ];
+        mask_columns(&mut columns, &masks, Some(b"key"));
+        assert_eq!(columns[0].value.as_deref(), Some("1"));
+        assert_eq!(columns[1].value.as_deref(), Some("***"));
+        // HMAC-SHA256 of abc keyed by key
+        assert_eq!(
+            columns[2].value.as_deref(),
+            Some("9c196e32dc0175f86f4b1cb89289d6619de6bee699e4c378e68309ed97a1a6ab")
+        );
+        assert_eq!(columns[3].value, None);
+    }
+
+    #[test]
+    fn test_exclude_columns() {
+        let exclusions = ["users.password", "*.*.blob", "audit.*.payload"]
+            .into_iter()
+            .map(ColumnExclusion::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut columns = vec![
+            column("id", Some("1")),
+            column("password", Some("secret")),
+            column("blob", None),
+            column("payload", Some("{}")),
+        ];
+        exclude_columns(&mut columns, &exclusions, "public", "users");
+        let values: Vec<_> = columns.iter().map(|c| c.value.as_deref()).collect();
+        assert_eq!(values, [Some("1"), None, None, Some("{}")]);
+        exclude_columns(&mut columns, &exclusions, "audit", "events");
+        assert_eq!(columns[3].value, None);
+        assert_eq!(columns.len(), 4);
+
+        assert!(ColumnExclusion::try_from("password").is_err());
+        assert!(ColumnExclusion::try_from("users..password").is_err());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_excluded_columns() {
+        unsafe {
+            Spi::run("CREATE TABLE test_excluded (id int, password text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_excluded VALUES (1, 'secret')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let (row_after, redo_query) = Spi::get_two::<String, String>(&format!(
+            "SELECT row_after, redo_query FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 exclude_columns => ARRAY['test_excluded.password']) WHERE op = 'INSERT'"
+        ))
+        .unwrap();
+        // The row keeps its row type, the query sets every column
+        assert_eq!(row_after.as_deref(), Some("(1,)"));
+        assert_eq!(
+            redo_query.as_deref(),
+            Some("INSERT INTO public.test_excluded (id, password) VALUES ('1', 'secret');")
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_masked_columns() {
+        unsafe {
+            Spi::run("CREATE TABLE test_masked (id int, email text);");
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column (relid, column_name) VALUES ('test_masked', 'email')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_masked VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let row_after = Spi::get_one::<String>(&format!(
+            "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1) WHERE op = 'INSERT'"
+        ));
+        assert_eq!(row_after, Ok(Some("(1,***)".to_string())));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_hashed_columns() {
+        unsafe {
+            Spi::run("CREATE TABLE test_hashed (id int, email text);");
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_hashed', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_hashed VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        Spi::run("SET pg_waldecoder.mask_hash_key = 'secret'").unwrap();
+        let row_after = Spi::get_one::<String>(&format!(
+            "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1) WHERE op = 'INSERT'"
+        ));
+        assert_eq!(
+            row_after,
+            Ok(Some(
+                "(1,0607236cc2fc521ca815254262b7014cb54eb5488f266e4777158cc52a33cfe9)".to_string()
+            ))
+        );
+    }
+
+    #[pg_test(error = "pg_waldecoder.mask_hash_key must be set to hash the masked column email")]
+    fn test_pg_waldecoder_hashed_columns_without_key() {
+        unsafe {
+            Spi::run("CREATE TABLE test_hashed (id int, email text);");
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_hashed', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_hashed VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        Spi::run(&format!(
+            "SELECT row_after FROM pg_waldecoder('{startptr}', '{endptr}', 1)"
+        ))
+        .unwrap();
+    }
+}
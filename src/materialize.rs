@@ -0,0 +1,218 @@
+use pgrx::{
+    error, extension_sql,
+    fcinfo::{pg_arg_is_null, pg_getarg},
+    pg_guard, pg_sys, IntoDatum, PgMemoryContexts,
+};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+};
+
+extension_sql!(
+    r"
+CREATE FUNCTION pg_waldecoder_materialize(
+    start_lsn text,
+    end_lsn text DEFAULT NULL,
+    timeline int DEFAULT NULL,
+    wal_dir text DEFAULT NULL
+) RETURNS TABLE (
+    lsn pg_lsn,
+    dboid oid,
+    relid oid,
+    spcoid oid,
+    relfilenumber oid,
+    relation_missing boolean,
+    xid xid8,
+    op text,
+    redo_query text,
+    revert_query text,
+    row_before text,
+    row_after text,
+    raw_xid xid,
+    commit_time timestamptz,
+    schema_name text,
+    relation_name text,
+    origin_id oid,
+    origin_name text,
+    raw_record bytea,
+    toplevel_xid xid,
+    changes jsonb,
+    error text,
+    old_ctid tid,
+    new_ctid tid,
+    forknum int,
+    blkno bigint,
+    schema_mismatch boolean,
+    provenance text,
+    raw_tuple bytea,
+    infomask text[],
+    xmin xid,
+    xmax xid,
+    persistence text,
+    cid bigint,
+    query_fingerprint text,
+    prev_lsn pg_lsn,
+    record_length int,
+    fpi_length int,
+    annotation text
+)
+LANGUAGE c CALLED ON NULL INPUT
+AS 'MODULE_PATHNAME', 'pg_waldecoder_materialize';
+",
+    name = "pg_waldecoder_materialize",
+);
+
+/// Values and null flags of a row stored in the tuplestore
+#[derive(Default)]
+pub(crate) struct Row {
+    pub values: Vec<pg_sys::Datum>,
+    pub nulls: Vec<bool>,
+}
+
+impl Row {
+    fn push<T: IntoDatum>(&mut self, value: T) {
+        match value.into_datum() {
+            Some(datum) => {
+                self.values.push(datum);
+                self.nulls.push(false);
+            }
+            None => {
+                self.values.push(pg_sys::Datum::from(0));
+                self.nulls.push(true);
+            }
+        }
+    }
+}
+
+impl From<DecodedResult> for Row {
+    fn from(val: DecodedResult) -> Self {
+        let mut row = Row::default();
+        row.push(val.lsn);
+        row.push(val.dboid);
+        row.push(val.relid);
+        row.push(val.spcoid);
+        row.push(val.relfilenumber);
+        row.push(val.relation_missing);
+        row.push(val.full_xid);
+        row.push(val.op);
+        row.push(val.redo_query);
+        row.push(val.revert_query);
+        row.push(val.row_before);
+        row.push(val.row_after);
+        row.push(val.xid);
+        row.push(val.commit_time);
+        row.push(val.schema_name);
+        row.push(val.relation_name);
+        row.push(val.origin_id);
+        row.push(val.origin_name);
+        row.push(val.raw_record);
+        row.push(val.toplevel_xid);
+        row.push(val.changes);
+        row.push(val.error);
+        row.push(val.old_ctid);
+        row.push(val.new_ctid);
+        row.push(val.forknum);
+        row.push(val.blkno);
+        row.push(val.schema_mismatch);
+        row.push(val.provenance);
+        row.push(val.raw_tuple);
+        row.push(val.infomask);
+        row.push(val.xmin);
+        row.push(val.xmax);
+        row.push(val.persistence);
+        row.push(val.cid);
+        row.push(val.query_fingerprint);
+        row.push(val.prev_lsn);
+        row.push(val.record_length);
+        row.push(val.fpi_length);
+        row.push(val.annotation);
+        row
+    }
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_pg_waldecoder_materialize() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// Decode the WAL like `pg_waldecoder` but store all rows in a tuplestore,
+/// spilling to disk past `work_mem`. Meant for callers consuming the whole
+/// result, like `CREATE TABLE AS`.
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C-unwind" fn pg_waldecoder_materialize(
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::Datum {
+    let Some(start_lsn) = (unsafe { pg_getarg::<&str>(fcinfo, 0) }) else {
+        error!("start_lsn can't be NULL");
+    };
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let end_lsn = unsafe { pg_getarg::<&str>(fcinfo, 1) };
+    let timeline = unsafe { pg_getarg::<i32>(fcinfo, 2) };
+    let wal_dir = if unsafe { pg_arg_is_null(fcinfo, 3) } {
+        None
+    } else {
+        unsafe { pg_getarg::<&str>(fcinfo, 3) }
+    };
+
+    // Builds the tuplestore, bounded by work_mem, in the per-query context
+    unsafe { pg_sys::InitMaterializedSRF(fcinfo, 0) };
+    let rsinfo = unsafe { (*fcinfo).resultinfo.cast::<pg_sys::ReturnSetInfo>() };
+    let (tupstore, tupdesc) = unsafe { ((*rsinfo).setResult, (*rsinfo).setDesc) };
+
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let mut row_ctx = PgMemoryContexts::new("Materialized row");
+    for decoded_record in WalDecoder::new(startptr, &options) {
+        unsafe {
+            row_ctx.switch_to(|_| {
+                let mut row = Row::from(decoded_record);
+                pg_sys::tuplestore_putvalues(
+                    tupstore,
+                    tupdesc,
+                    row.values.as_mut_ptr(),
+                    row.nulls.as_mut_ptr(),
+                );
+            });
+            row_ctx.reset();
+        }
+    }
+    pg_sys::Datum::from(0)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::pg_lsn::PgLSN;
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_pg_waldecoder_materialize() {
+        unsafe {
+            Spi::run("CREATE TABLE test_materialize (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_materialize (id) SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let count = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_materialize('{startptr}', '{endptr}', 1) WHERE op = 'INSERT'"
+        ))
+        .unwrap();
+        assert_eq!(count, Some(3));
+    }
+}
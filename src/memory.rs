@@ -0,0 +1,54 @@
+use std::cell::Cell;
+
+use pgrx::{pg_sys, PgMemoryContexts};
+
+use crate::guc::{RECORD_CONTEXT_INIT_BLOCK_SIZE, RECORD_CONTEXT_MAX_BLOCK_SIZE};
+
+/// Memory used by a decoding
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Largest size reached by the per record context
+    pub peak_record_bytes: usize,
+    /// Size of the cached and released page buffers
+    pub page_cache_bytes: usize,
+    /// Page buffers allocated
+    pub page_allocations: u64,
+    /// Size of the toast chunks kept for the rows not decoded yet
+    pub toast_chunk_bytes: usize,
+}
+
+thread_local! {
+    /// Stats of the latest decoding of the backend
+    static LAST_MEMORY_STATS: Cell<MemoryStats> = Cell::new(MemoryStats::default());
+}
+
+pub fn publish_memory_stats(stats: MemoryStats) {
+    LAST_MEMORY_STATS.set(stats);
+}
+
+pub fn last_memory_stats() -> MemoryStats {
+    LAST_MEMORY_STATS.get()
+}
+
+/// Create the per record context as a child of the current one, with the
+/// block sizes set by the GUCs
+pub fn create_record_context() -> PgMemoryContexts {
+    let kb_to_bytes = |kb: i32| usize::try_from(kb).unwrap_or(1) * 1024;
+    let init_block_size = kb_to_bytes(RECORD_CONTEXT_INIT_BLOCK_SIZE.get());
+    let max_block_size = kb_to_bytes(RECORD_CONTEXT_MAX_BLOCK_SIZE.get()).max(init_block_size);
+    let context = unsafe {
+        pg_sys::AllocSetContextCreateInternal(
+            pg_sys::CurrentMemoryContext,
+            c"Per decoded record".as_ptr(),
+            0,
+            init_block_size,
+            max_block_size,
+        )
+    };
+    PgMemoryContexts::For(context)
+}
+
+/// Total size of the blocks allocated by a context and its children
+pub fn context_allocated_bytes(context: &PgMemoryContexts) -> usize {
+    unsafe { pg_sys::MemoryContextMemAllocated(context.value(), true) }
+}
@@ -0,0 +1,144 @@
+use std::{ffi::CString, fmt::Write};
+
+use pgrx::pg_sys;
+
+use crate::{decoder::DecodedResult, output::OutputSink};
+
+/// `NOTIFY_PAYLOAD_MAX_LENGTH`, payloads must be shorter than this
+const NOTIFY_PAYLOAD_MAX_LENGTH: usize = 8192 - 64 - 128;
+
+/// Render a string as a JSON value
+pub fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c < ' ' => write!(res, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// JSON payload describing the change. The queries and rows are left out
+/// when they would make the payload too large, with `truncated` set.
+fn change_payload(change: &DecodedResult) -> String {
+    let relid = change
+        .relid
+        .map_or("null".to_string(), |relid| relid.to_u32().to_string());
+    let full_xid = change
+        .full_xid
+        .map_or("null".to_string(), |full_xid| full_xid.0.to_string());
+    let header = format!(
+        r#"{{"lsn":{},"dboid":{},"relid":{relid},"schema_name":{},"relation_name":{},"relfilenumber":{},"xid":{full_xid},"raw_xid":{},"op":{}"#,
+        json_string(Some(&change.lsn.to_string())),
+        change.dboid.to_u32(),
+        json_string(change.schema_name.as_deref()),
+        json_string(change.relation_name.as_deref()),
+        change.relfilenumber.to_u32(),
+        change.xid.into_inner(),
+        json_string(Some(&change.op)),
+    );
+    let payload = format!(
+        r#"{header},"redo_query":{},"revert_query":{},"row_before":{},"row_after":{}}}"#,
+        json_string(change.redo_query.as_deref()),
+        json_string(change.revert_query.as_deref()),
+        json_string(change.row_before.as_deref()),
+        json_string(change.row_after.as_deref()),
+    );
+    if payload.len() < NOTIFY_PAYLOAD_MAX_LENGTH {
+        payload
+    } else {
+        format!(r#"{header},"truncated":true}}"#)
+    }
+}
+
+/// Queue a notification per change on a channel, sent when the transaction
+/// commits
+pub struct NotifySink {
+    channel: CString,
+    written: usize,
+}
+
+impl NotifySink {
+    pub fn new(channel: &str) -> NotifySink {
+        NotifySink {
+            channel: CString::new(channel).unwrap(),
+            written: 0,
+        }
+    }
+}
+
+impl OutputSink for NotifySink {
+    fn write(&mut self, change: DecodedResult) {
+        let payload = CString::new(change_payload(&change)).unwrap();
+        unsafe { pg_sys::Async_Notify(self.channel.as_ptr(), payload.as_ptr()) };
+        self.written += 1;
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        decoder::DecodedResult,
+        notify::{change_payload, json_string},
+        pg_lsn::PgLSN,
+        xid::FullXid,
+    };
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string(None), "null");
+        assert_eq!(json_string(Some("it's")), r#""it's""#);
+        assert_eq!(json_string(Some("a\"b\\c\n\u{1}")), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[test]
+    fn test_change_payload() {
+        let mut change = DecodedResult {
+            lsn: PgLSN::from(42u64),
+            dboid: pg_sys::Oid::from(5),
+            relid: Some(pg_sys::Oid::from(16384)),
+            spcoid: pg_sys::Oid::from(1663),
+            relfilenumber: pg_sys::Oid::from(16385),
+            forknum: Some(0),
+            blkno: Some(0),
+            schema_mismatch: Some(false),
+            provenance: Some("record".to_string()),
+            xid: pg_sys::TransactionId::from(750),
+            full_xid: Some(FullXid((1 << 32) + 750)),
+            schema_name: Some("public".to_string()),
+            relation_name: Some("t".to_string()),
+            toplevel_xid: pg_sys::TransactionId::from(750),
+            op: "INSERT".to_string(),
+            redo_query: Some("INSERT INTO t (id) VALUES ('1');".to_string()),
+            row_after: Some("(1)".to_string()),
+            ..DecodedResult::default()
+        };
+        assert_eq!(
+            change_payload(&change),
+            r#"{"lsn":"0/0000002A","dboid":5,"relid":16384,"schema_name":"public","relation_name":"t","relfilenumber":16385,"xid":4294968046,"raw_xid":750,"op":"INSERT","redo_query":"INSERT INTO t (id) VALUES ('1');","revert_query":null,"row_before":null,"row_after":"(1)"}"#
+        );
+
+        change.row_after = Some("x".repeat(8000));
+        assert_eq!(
+            change_payload(&change),
+            r#"{"lsn":"0/0000002A","dboid":5,"relid":16384,"schema_name":"public","relation_name":"t","relfilenumber":16385,"xid":4294968046,"raw_xid":750,"op":"INSERT","truncated":true}"#
+        );
+    }
+}
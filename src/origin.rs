@@ -0,0 +1,34 @@
+use std::{collections::HashMap, ffi::CStr};
+
+use pgrx::pg_sys;
+
+/// Replication origin of a record, None for locally generated changes
+pub fn record_origin(record: &pg_sys::DecodedXLogRecord) -> Option<pg_sys::RepOriginId> {
+    let origin = record.record_origin;
+    // InvalidRepOriginId
+    (origin != 0).then_some(origin)
+}
+
+/// Cache of the names of replication origins
+#[derive(Default)]
+pub struct OriginNameCache {
+    names: HashMap<pg_sys::RepOriginId, Option<String>>,
+}
+
+impl OriginNameCache {
+    /// Name of an origin, None when it was dropped since
+    pub fn get(&mut self, origin: pg_sys::RepOriginId) -> Option<String> {
+        self.names
+            .entry(origin)
+            .or_insert_with(|| {
+                let mut name: *mut std::ffi::c_char = std::ptr::null_mut();
+                let found = unsafe { pg_sys::replorigin_by_oid(origin, true, &raw mut name) };
+                (found && !name.is_null()).then(|| {
+                    unsafe { CStr::from_ptr(name) }
+                        .to_string_lossy()
+                        .into_owned()
+                })
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,75 @@
+use crate::decoder::{DecodedResult, WalDecoder};
+
+/// Destination of the decoded changes. Changes are written in LSN order and
+/// `finish` is called once the decoding ends, for the sinks writing batches.
+pub trait OutputSink {
+    /// Write the change, or keep it for the next batch
+    fn write(&mut self, change: DecodedResult);
+
+    /// Write what's left to write
+    fn finish(&mut self) {}
+
+    /// Number of changes written so far
+    fn written(&self) -> usize;
+}
+
+/// Write the changes decoded by `wal_decoder` to the sink, returns the number
+/// of changes written
+pub fn write_changes(wal_decoder: WalDecoder, sink: &mut dyn OutputSink) -> usize {
+    for change in wal_decoder {
+        sink.write(change);
+    }
+    sink.finish();
+    sink.written()
+}
+
+/// Changes returned by a set returning function, each one also written to
+/// the sinks as it's returned
+pub struct SinkRows {
+    wal_decoder: WalDecoder,
+    sinks: Vec<Box<dyn OutputSink>>,
+}
+
+impl SinkRows {
+    pub fn new(wal_decoder: WalDecoder, sinks: Vec<Box<dyn OutputSink>>) -> SinkRows {
+        SinkRows { wal_decoder, sinks }
+    }
+}
+
+impl OutputSink for SinkRows {
+    fn write(&mut self, change: DecodedResult) {
+        for sink in &mut self.sinks {
+            sink.write(change.clone());
+        }
+    }
+
+    fn finish(&mut self) {
+        for sink in &mut self.sinks {
+            sink.finish();
+        }
+    }
+
+    fn written(&self) -> usize {
+        self.sinks
+            .iter()
+            .map(|sink| sink.written())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Iterator for SinkRows {
+    type Item = DecodedResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(change) = self.wal_decoder.next() else {
+            self.finish();
+            self.sinks.clear();
+            return None;
+        };
+        if !self.sinks.is_empty() {
+            self.write(change.clone());
+        }
+        Some(change)
+    }
+}
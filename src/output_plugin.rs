@@ -0,0 +1,325 @@
+use std::ffi::{CStr, CString};
+
+use pgrx::{pg_guard, pg_sys, prelude::*, PgList, PgMemoryContexts, PgRelation};
+
+use crate::tuple_str::{
+    format_row, generate_key_query, generate_queries, relation_name, tuple_values, ColumnValue,
+};
+
+/// What is written for each change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// The query redoing the change
+    Redo,
+    /// The query reverting the change
+    Revert,
+    /// The operation with the rows before and after the change
+    Row,
+}
+
+impl TryFrom<&str> for OutputFormat {
+    type Error = String;
+
+    fn try_from(format: &str) -> Result<Self, Self::Error> {
+        match format {
+            "redo" => Ok(OutputFormat::Redo),
+            "revert" => Ok(OutputFormat::Revert),
+            "row" => Ok(OutputFormat::Row),
+            _ => Err(format!("Unknown output format '{format}'")),
+        }
+    }
+}
+
+/// State kept in the decoding context's `output_plugin_private`
+struct PluginState {
+    format: OutputFormat,
+    /// Reset after each change
+    change_ctx: pg_sys::MemoryContext,
+}
+
+/// Entry point of the output plugin, used when a slot is created with
+/// `pg_waldecoder` as plugin
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _PG_output_plugin_init(cb: *mut pg_sys::OutputPluginCallbacks) {
+    let cb = unsafe { &mut *cb };
+    cb.startup_cb = Some(pg_waldecoder_startup);
+    cb.begin_cb = Some(pg_waldecoder_begin);
+    cb.change_cb = Some(pg_waldecoder_change);
+    cb.commit_cb = Some(pg_waldecoder_commit);
+    cb.shutdown_cb = Some(pg_waldecoder_shutdown);
+}
+
+/// Parse the options passed to the slot functions
+fn parse_format(options: *mut pg_sys::List) -> OutputFormat {
+    let options = unsafe { PgList::<pg_sys::DefElem>::from_pg(options) };
+    let mut format = OutputFormat::Redo;
+    for elem in options.iter_ptr() {
+        let name = unsafe { CStr::from_ptr((*elem).defname) }.to_string_lossy();
+        if name != "format" {
+            error!("Unknown option '{name}'");
+        }
+        let value = unsafe { CStr::from_ptr(pg_sys::defGetString(elem)) }.to_string_lossy();
+        format = match OutputFormat::try_from(value.as_ref()) {
+            Ok(format) => format,
+            Err(e) => error!("Error: {e}"),
+        };
+    }
+    format
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_startup(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    options: *mut pg_sys::OutputPluginOptions,
+    _is_init: bool,
+) {
+    let ctx = unsafe { &mut *ctx };
+    unsafe {
+        (*options).output_type = pg_sys::OutputPluginOutputType::OUTPUT_PLUGIN_TEXTUAL_OUTPUT
+    };
+    let change_ctx = unsafe {
+        pg_sys::AllocSetContextCreateInternal(
+            ctx.context,
+            c"pg_waldecoder change".as_ptr(),
+            pg_sys::ALLOCSET_DEFAULT_MINSIZE.try_into().unwrap(),
+            pg_sys::ALLOCSET_DEFAULT_INITSIZE.try_into().unwrap(),
+            pg_sys::ALLOCSET_DEFAULT_MAXSIZE.try_into().unwrap(),
+        )
+    };
+    let state = PluginState {
+        format: parse_format(ctx.output_plugin_options),
+        change_ctx,
+    };
+    ctx.output_plugin_private = Box::into_raw(Box::new(state)).cast();
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_shutdown(ctx: *mut pg_sys::LogicalDecodingContext) {
+    let ctx = unsafe { &mut *ctx };
+    if ctx.output_plugin_private.is_null() {
+        return;
+    }
+    // The change context is a child of the decoding context and freed with it
+    drop(unsafe { Box::from_raw(ctx.output_plugin_private.cast::<PluginState>()) });
+    ctx.output_plugin_private = std::ptr::null_mut();
+}
+
+/// Write a line of output for the current transaction
+fn write_line(ctx: *mut pg_sys::LogicalDecodingContext, line: &str) {
+    let line = CString::new(line).unwrap();
+    unsafe {
+        pg_sys::OutputPluginPrepareWrite(ctx, true);
+        pg_sys::appendStringInfoString((*ctx).out, line.as_ptr());
+        pg_sys::OutputPluginWrite(ctx, true);
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_begin(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    txn: *mut pg_sys::ReorderBufferTXN,
+) {
+    let xid = unsafe { (*txn).xid };
+    write_line(ctx, &format!("BEGIN; -- xid {xid}"));
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_commit(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    txn: *mut pg_sys::ReorderBufferTXN,
+    _commit_lsn: pg_sys::XLogRecPtr,
+) {
+    let xid = unsafe { (*txn).xid };
+    write_line(ctx, &format!("COMMIT; -- xid {xid}"));
+}
+
+/// Names of the replica identity columns, the only ones of the old rows
+/// unless the relation's replica identity is FULL
+fn identity_columns(rel: &PgRelation) -> Vec<String> {
+    let identity = unsafe {
+        pg_sys::RelationGetIndexAttrBitmap(
+            rel.as_ptr(),
+            pg_sys::IndexAttrBitmapKind::INDEX_ATTR_BITMAP_IDENTITY_KEY,
+        )
+    };
+    let tupdesc = rel.tuple_desc();
+    tupdesc
+        .iter()
+        .zip(1..)
+        .filter(|(_, attnum)| unsafe {
+            pg_sys::bms_is_member(
+                attnum - pg_sys::FirstLowInvalidHeapAttributeNumber,
+                identity,
+            )
+        })
+        .map(|(attr, _)| attr.name().to_string())
+        .collect()
+}
+
+/// Query redoing an update or a delete from the replica identity of the old
+/// row, from the new row when the identity is unchanged and the old row isn't
+/// logged. None when the relation has no replica identity.
+fn key_query(
+    rel: &PgRelation,
+    relname: &str,
+    old: Option<&[ColumnValue]>,
+    new: Option<&[ColumnValue]>,
+) -> Option<String> {
+    // Replica identity columns are never NULL
+    let key = match (old, new) {
+        (Some(old), _) => old.iter().filter(|c| c.value.is_some()).cloned().collect(),
+        (None, Some(new)) => {
+            let identity = identity_columns(rel);
+            new.iter()
+                .filter(|c| identity.contains(&c.name))
+                .cloned()
+                .collect()
+        }
+        (None, None) => Vec::new(),
+    };
+    (!key.is_empty()).then(|| generate_key_query(relname, &key, new))
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_change(
+    ctx: *mut pg_sys::LogicalDecodingContext,
+    _txn: *mut pg_sys::ReorderBufferTXN,
+    relation: pg_sys::Relation,
+    change: *mut pg_sys::ReorderBufferChange,
+) {
+    let state = unsafe { &*(*ctx).output_plugin_private.cast::<PluginState>() };
+    let change = unsafe { &*change };
+    let (op, old_tuple, new_tuple) = unsafe {
+        let tp = &change.data.tp;
+        match change.action {
+            pg_sys::ReorderBufferChangeType::REORDER_BUFFER_CHANGE_INSERT => {
+                ("INSERT", None, Some(tp.newtuple))
+            }
+            pg_sys::ReorderBufferChangeType::REORDER_BUFFER_CHANGE_UPDATE => {
+                ("UPDATE", Some(tp.oldtuple), Some(tp.newtuple))
+            }
+            pg_sys::ReorderBufferChangeType::REORDER_BUFFER_CHANGE_DELETE => {
+                ("DELETE", Some(tp.oldtuple), None)
+            }
+            _ => return,
+        }
+    };
+
+    let mut change_ctx = PgMemoryContexts::For(state.change_ctx);
+    let line = unsafe {
+        change_ctx.switch_to(|_| {
+            let rel = PgRelation::from_pg(relation);
+            let tupdesc = rel.tuple_desc();
+            let relname = relation_name(&rel);
+            let values = |tuple: Option<pg_sys::HeapTuple>| {
+                tuple
+                    .filter(|t| !t.is_null())
+                    .map(|t| tuple_values(&tupdesc, t))
+            };
+            let old_values = values(old_tuple);
+            let new_values = values(new_tuple);
+            if state.format == OutputFormat::Row {
+                return Some(format!(
+                    "{op} {relname} old: {} new: {}",
+                    old_values.as_deref().map_or("NULL".into(), format_row),
+                    new_values.as_deref().map_or("NULL".into(), format_row),
+                ));
+            }
+            let identity_full = (*rel.rd_rel).relreplident.cast_unsigned()
+                == pg_sys::REPLICA_IDENTITY_FULL;
+            if op != "INSERT" && !identity_full {
+                // The old row only has its replica identity, if any
+                if state.format == OutputFormat::Revert {
+                    return Some(format!(
+                        "-- {op} on {relname} can't be reverted without the old row, REPLICA IDENTITY FULL is needed"
+                    ));
+                }
+                return Some(
+                    key_query(&rel, &relname, old_values.as_deref(), new_values.as_deref())
+                        .unwrap_or_else(|| format!(
+                            "-- {op} on {relname} without replica identity, REPLICA IDENTITY FULL or a primary key is needed"
+                        )),
+                );
+            }
+            let (redo_query, revert_query) =
+                generate_queries(&relname, old_values.as_deref(), new_values.as_deref())?;
+            Some(if state.format == OutputFormat::Redo {
+                redo_query
+            } else {
+                revert_query
+            })
+        })
+    };
+    change_ctx.reset();
+    if let Some(line) = line {
+        write_line(ctx, &line);
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{output_plugin::OutputFormat, pg_lsn::PgLSN};
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!(OutputFormat::try_from("redo"), Ok(OutputFormat::Redo));
+        assert_eq!(OutputFormat::try_from("revert"), Ok(OutputFormat::Revert));
+        assert_eq!(OutputFormat::try_from("row"), Ok(OutputFormat::Row));
+        assert!(OutputFormat::try_from("json").is_err());
+    }
+
+    #[pg_test]
+    fn test_output_plugin_replica_identity() {
+        // Created before the test's transaction writes
+        Spi::run("SELECT pg_create_logical_replication_slot('test_plugin', 'pg_waldecoder', true)")
+            .unwrap();
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s dbname=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'), current_database())",
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run(&format!(
+            "SELECT dblink_exec('{conninfo}', $sql$
+                DROP TABLE IF EXISTS test_plugin_key, test_plugin_no_key;
+                CREATE TABLE test_plugin_key (id int primary key, data text);
+                CREATE TABLE test_plugin_no_key (id int, data text);
+                INSERT INTO test_plugin_key VALUES (1, 'a');
+                UPDATE test_plugin_key SET data = 'b';
+                DELETE FROM test_plugin_key;
+                INSERT INTO test_plugin_no_key VALUES (1, 'a');
+                DELETE FROM test_plugin_no_key
+            $sql$)"
+        ))
+        .unwrap();
+        let endptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+
+        let changes = |format: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(data, E'\\n' ORDER BY lsn) FROM pg_logical_slot_peek_changes('test_plugin', '{endptr}', NULL, 'format', '{format}')
+                 WHERE data LIKE 'UPDATE%' OR data LIKE 'DELETE%' OR data LIKE '-- %'"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        // The old rows of the default replica identity only have the key
+        assert_eq!(
+            changes("redo"),
+            "UPDATE public.test_plugin_key SET id = '1', data = 'b' WHERE id = '1';
+DELETE FROM public.test_plugin_key WHERE id = '1';
+-- DELETE on public.test_plugin_no_key without replica identity, REPLICA IDENTITY FULL or a primary key is needed"
+        );
+        let not_reverted = changes("revert")
+            .lines()
+            .filter(|line| line.contains("can't be reverted without the old row"))
+            .count();
+        assert_eq!(not_reverted, 3);
+    }
+}
@@ -0,0 +1,75 @@
+use std::mem::{offset_of, size_of};
+
+use pgrx::pg_sys::{self, OffsetNumber, Page, PageHeaderData, XLogRecPtr};
+
+/// Size of the page header up to the line pointer array
+const SIZE_OF_PAGE_HEADER_DATA: usize = offset_of!(PageHeaderData, pd_linp);
+
+fn page_header(page: Page) -> *mut PageHeaderData {
+    page.cast::<PageHeaderData>()
+}
+
+/// Returns the number of line pointers of a page
+pub unsafe fn page_get_max_offset_number(page: Page) -> OffsetNumber {
+    let pd_lower = usize::from(unsafe { (*page_header(page)).pd_lower });
+    if pd_lower <= SIZE_OF_PAGE_HEADER_DATA {
+        return 0;
+    }
+    OffsetNumber::try_from((pd_lower - SIZE_OF_PAGE_HEADER_DATA) / size_of::<pg_sys::ItemIdData>())
+        .unwrap()
+}
+
+pub unsafe fn page_get_lsn(page: Page) -> XLogRecPtr {
+    let pd_lsn = unsafe { (*page_header(page)).pd_lsn };
+    (u64::from(pd_lsn.xlogid) << 32) | u64::from(pd_lsn.xrecoff)
+}
+
+pub unsafe fn page_set_lsn(page: Page, lsn: XLogRecPtr) {
+    unsafe {
+        (*page_header(page)).pd_lsn = pg_sys::PageXLogRecPtr {
+            xlogid: (lsn >> 32) as u32,
+            xrecoff: (lsn & 0xffffffff) as u32,
+        };
+    }
+}
+
+/// Clear the checksum, which changes each time the page is written
+pub unsafe fn page_clear_checksum(page: Page) {
+    unsafe { (*page_header(page)).pd_checksum = 0 };
+}
+
+pub unsafe fn page_set_prunable(page: Page, xid: pg_sys::TransactionId) {
+    unsafe {
+        let header = page_header(page);
+        if (*header).pd_prune_xid == pg_sys::InvalidTransactionId
+            || pg_sys::TransactionIdPrecedes(xid, (*header).pd_prune_xid)
+        {
+            (*header).pd_prune_xid = xid;
+        }
+    }
+}
+
+pub unsafe fn page_clear_all_visible(page: Page) {
+    unsafe {
+        (*page_header(page)).pd_flags &= !u16::try_from(pg_sys::PD_ALL_VISIBLE).unwrap();
+    }
+}
+
+pub unsafe fn page_set_all_visible(page: Page) {
+    unsafe {
+        (*page_header(page)).pd_flags |= u16::try_from(pg_sys::PD_ALL_VISIBLE).unwrap();
+    }
+}
+
+/// Returns the line pointer of an offset if it points to a normal tuple
+pub unsafe fn page_get_normal_item_id(page: Page, offnum: OffsetNumber) -> Option<pg_sys::ItemId> {
+    if offnum == pg_sys::InvalidOffsetNumber || offnum > unsafe { page_get_max_offset_number(page) }
+    {
+        return None;
+    }
+    let item_id = unsafe { pg_sys::PageGetItemId(page, offnum) };
+    if unsafe { (*item_id).lp_flags() } != pg_sys::LP_NORMAL {
+        return None;
+    }
+    Some(item_id)
+}
@@ -0,0 +1,619 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use pgrx::{pg_sys, warning, PgMemoryContexts};
+
+use crate::{decoder::PageId, pg_lsn::PgLSN, relation::read_current_block};
+
+/// Number of released page buffers kept for reuse
+const MAX_FREE_PAGES: usize = 16;
+/// Temporary files directory of the default tablespace, relative to the data
+/// directory
+const TEMP_FILES_DIR: &str = "base/pgsql_tmp";
+
+/// Bytes of a page id in a saved page cache
+const SAVED_PAGE_ID_SIZE: usize = 5 * size_of::<u32>();
+/// Bytes of the header of a saved page cache, the LSN its pages are at
+const SAVED_HEADER_SIZE: usize = size_of::<u64>();
+
+/// Counters of the page cache usage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Page buffers allocated, released buffers are reused
+    pub allocations: u64,
+    /// Pages read from the current relation after a miss
+    pub current_reads: u64,
+    /// Full page images restored from the WAL
+    pub restored_images: u64,
+    /// Evicted pages written to the spill file
+    pub spilled: u64,
+    /// Pages read back from the spill file
+    pub reloaded: u64,
+}
+
+struct CachedPage {
+    page: pg_sys::Page,
+    last_used: u64,
+}
+
+/// Temporary file the evicted pages are written to, in slots of a block. The
+/// file is a `BufFile`, counted in `temp_file_limit` and deleted at the end
+/// of the transaction, even when it fails, or with the decoder when it's
+/// kept across transactions.
+struct SpillFile {
+    file: *mut pg_sys::BufFile,
+    /// Slot of each spilled page
+    slots: HashMap<PageId, u64>,
+    /// Slots of the pages read back, reused by the next spills
+    free_slots: Vec<u64>,
+    next_slot: u64,
+}
+
+impl SpillFile {
+    /// Create the file, owned by `owner` unless it's kept across
+    /// transactions. It's allocated in the current memory context.
+    fn create(owner: pg_sys::ResourceOwner, across_transactions: bool) -> SpillFile {
+        let file = unsafe {
+            // The per record subtransactions would release it
+            let current_owner = pg_sys::CurrentResourceOwner;
+            pg_sys::CurrentResourceOwner = owner;
+            let file = pg_sys::BufFileCreateTemp(across_transactions);
+            pg_sys::CurrentResourceOwner = current_owner;
+            file
+        };
+        SpillFile {
+            file,
+            slots: HashMap::new(),
+            free_slots: Vec::new(),
+            next_slot: 0,
+        }
+    }
+
+    /// Move to a slot, returns false past the end of the file
+    fn seek(&self, slot: u64) -> bool {
+        let Ok(blknum) = i64::try_from(slot) else {
+            return false;
+        };
+        unsafe { pg_sys::BufFileSeekBlock(self.file, blknum) == 0 }
+    }
+
+    /// Read the page of a slot, raises an error if the file can't be read
+    fn read_slot(&self, slot: u64, page: *mut u8) -> bool {
+        if !self.seek(slot) {
+            return false;
+        }
+        unsafe { pg_sys::BufFileReadExact(self.file, page.cast(), pg_sys::BLCKSZ as usize) };
+        true
+    }
+
+    /// Write a page, raises an error if the file can't be written, like once
+    /// `temp_file_limit` is reached
+    fn write(&mut self, page_id: PageId, page: pg_sys::Page) -> bool {
+        let slot = self.slots.get(&page_id).copied().unwrap_or_else(|| {
+            self.free_slots.pop().unwrap_or_else(|| {
+                self.next_slot += 1;
+                self.next_slot - 1
+            })
+        });
+        if !self.seek(slot) {
+            return false;
+        }
+        unsafe { pg_sys::BufFileWrite(self.file, page.cast(), pg_sys::BLCKSZ as usize) };
+        self.slots.insert(page_id, slot);
+        true
+    }
+
+    /// Read a spilled page back in `page`, freeing its slot
+    fn read(&mut self, page_id: &PageId, page: pg_sys::Page) -> bool {
+        let Some(slot) = self.slots.remove(page_id) else {
+            return false;
+        };
+        self.free_slots.push(slot);
+        self.read_slot(slot, page.cast())
+    }
+
+    /// Drop the spilled version of a page, replaced by a newer one
+    fn forget(&mut self, page_id: &PageId) {
+        if let Some(slot) = self.slots.remove(page_id) {
+            self.free_slots.push(slot);
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        unsafe { pg_sys::BufFileClose(self.file) };
+    }
+}
+
+/// File a page cache is saved to under a name, a temporary file removed
+/// when the server restarts. Names are made of letters, digits and `_`.
+pub fn saved_cache_path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "Invalid page cache file name \"{name}\", only letters, digits and _ are allowed"
+        ));
+    }
+    Ok(Path::new(TEMP_FILES_DIR).join(format!("pgsql_tmp.waldecoder_cache_{name}")))
+}
+
+fn encode_page_id(page_id: &PageId) -> [u8; SAVED_PAGE_ID_SIZE] {
+    let rlocator = page_id.rlocator();
+    let mut bytes = [0; SAVED_PAGE_ID_SIZE];
+    for (chunk, value) in bytes.chunks_exact_mut(size_of::<u32>()).zip([
+        rlocator.spcOid.to_u32(),
+        rlocator.dbOid.to_u32(),
+        rlocator.relNumber.to_u32(),
+        page_id.forknum().cast_unsigned(),
+        page_id.blknum(),
+    ]) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+fn decode_page_id(bytes: &[u8]) -> PageId {
+    let values: Vec<u32> = bytes
+        .chunks_exact(size_of::<u32>())
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    let rlocator = pg_sys::RelFileLocator {
+        spcOid: values[0].into(),
+        dbOid: values[1].into(),
+        relNumber: values[2].into(),
+    };
+    PageId::new(&rlocator, values[3].cast_signed(), values[4])
+}
+
+/// Pages rebuilt from the WAL, bounded to a number of pages with the least
+/// recently used ones evicted first
+pub struct PageCache {
+    pages: HashMap<PageId, CachedPage>,
+    /// Pages ordered by last use
+    lru: BTreeMap<u64, PageId>,
+    tick: u64,
+    capacity: usize,
+    /// Context outliving the records, cached pages are allocated there
+    parent_ctx: PgMemoryContexts,
+    /// Released page buffers ready for reuse
+    free_pages: Vec<pg_sys::Page>,
+    /// Read missing pages from the current relation
+    read_current: bool,
+    /// Pages read from the current relation, until a full page image or an
+    /// initialization replaces them
+    current_pages: HashSet<PageId>,
+    /// Write evicted pages to a spill file, created on the first eviction
+    spill: bool,
+    spill_file: Option<SpillFile>,
+    /// Resource owner of the spill file, the one of the query
+    spill_owner: pg_sys::ResourceOwner,
+    /// Keep the spill file until the cache is dropped rather than the end of
+    /// the transaction
+    spill_across_transactions: bool,
+    pub stats: PageCacheStats,
+}
+
+impl PageCache {
+    pub fn new(
+        capacity: usize,
+        parent_ctx: PgMemoryContexts,
+        read_current: bool,
+        spill: bool,
+    ) -> PageCache {
+        PageCache {
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity: capacity.max(1),
+            parent_ctx,
+            free_pages: Vec::new(),
+            read_current,
+            current_pages: HashSet::new(),
+            spill,
+            spill_file: None,
+            spill_owner: unsafe { pg_sys::CurrentResourceOwner },
+            spill_across_transactions: false,
+            stats: PageCacheStats::default(),
+        }
+    }
+
+    /// Keep the spill file of a cache used across transactions
+    pub fn spill_across_transactions(&mut self) {
+        self.spill_across_transactions = true;
+    }
+
+    /// Get a page buffer, reusing a released one when possible. Buffers are
+    /// allocated in the parent context.
+    pub fn alloc_page(&mut self) -> pg_sys::Page {
+        if let Some(page) = self.free_pages.pop() {
+            return page;
+        }
+        self.stats.allocations += 1;
+        self.parent_ctx.palloc(pg_sys::BLCKSZ as usize).cast()
+    }
+
+    /// Give back a page buffer that isn't cached anymore
+    pub fn release_page(&mut self, page: pg_sys::Page) {
+        if self.free_pages.len() < MAX_FREE_PAGES {
+            self.free_pages.push(page);
+        } else {
+            unsafe { pg_sys::pfree(page.cast()) };
+        }
+    }
+
+    fn touch(&mut self, page_id: PageId) {
+        self.tick += 1;
+        if let Some(cached) = self.pages.get_mut(&page_id) {
+            self.lru.remove(&cached.last_used);
+            cached.last_used = self.tick;
+            self.lru.insert(self.tick, page_id);
+        }
+    }
+
+    /// Look up a page needed for redo, counting hits and misses.
+    ///
+    /// A page evicted to the spill file is read back. On a miss, the page may
+    /// be read from the current relation. It then reflects the relation's
+    /// latest state rather than the one at the record.
+    pub fn get(&mut self, page_id: &PageId) -> Option<pg_sys::Page> {
+        let Some(page) = self.peek(page_id) else {
+            if let Some(page) = self.read_spilled_page(page_id) {
+                return Some(page);
+            }
+            self.stats.misses += 1;
+            return self.read_current_page(page_id);
+        };
+        self.stats.hits += 1;
+        self.touch(*page_id);
+        Some(page)
+    }
+
+    fn read_spilled_page(&mut self, page_id: &PageId) -> Option<pg_sys::Page> {
+        if !self.spill_file.as_ref()?.slots.contains_key(page_id) {
+            return None;
+        }
+        let page = self.alloc_page();
+        if !self.spill_file.as_mut()?.read(page_id, page) {
+            self.release_page(page);
+            return None;
+        }
+        self.stats.reloaded += 1;
+        let current = self.is_current(page_id);
+        self.insert(*page_id, page);
+        if current {
+            self.current_pages.insert(*page_id);
+        }
+        Some(page)
+    }
+
+    /// Write an evicted page to the spill file
+    fn spill_page(&mut self, page_id: PageId, page: pg_sys::Page) {
+        if !self.spill {
+            return;
+        }
+        if self.spill_file.is_none() {
+            let (owner, across_transactions) = (self.spill_owner, self.spill_across_transactions);
+            let spill_file = unsafe {
+                self.parent_ctx
+                    .switch_to(|_| SpillFile::create(owner, across_transactions))
+            };
+            self.spill_file = Some(spill_file);
+        }
+        if let Some(spill_file) = &mut self.spill_file {
+            if spill_file.write(page_id, page) {
+                self.stats.spilled += 1;
+            }
+        }
+    }
+
+    fn read_current_page(&mut self, page_id: &PageId) -> Option<pg_sys::Page> {
+        if !self.read_current {
+            return None;
+        }
+        let page = self.alloc_page();
+        let rlocator = page_id.rlocator();
+        if !read_current_block(&rlocator, page_id.forknum(), page_id.blknum(), page) {
+            self.release_page(page);
+            return None;
+        }
+        warning!(
+            "Block {} of {:?} read from the current relation, decoded rows may reflect a later state",
+            page_id.blknum(),
+            rlocator
+        );
+        self.stats.current_reads += 1;
+        self.insert(*page_id, page);
+        self.current_pages.insert(*page_id);
+        Some(page)
+    }
+
+    /// Whether the cached page was read from the current relation, its
+    /// tuples may be later versions than the ones of the record
+    pub fn is_current(&self, page_id: &PageId) -> bool {
+        self.current_pages.contains(page_id)
+    }
+
+    /// Memory used by the cached and released page buffers
+    pub fn bytes(&self) -> usize {
+        (self.pages.len() + self.free_pages.len()) * pg_sys::BLCKSZ as usize
+    }
+
+    /// Look up a page without updating the counters nor the LRU order
+    pub fn peek(&self, page_id: &PageId) -> Option<pg_sys::Page> {
+        self.pages.get(page_id).map(|cached| cached.page)
+    }
+
+    /// Cache a page allocated with `alloc_page`, replacing and freeing the
+    /// previous version
+    pub fn insert(&mut self, page_id: PageId, page: pg_sys::Page) {
+        self.current_pages.remove(&page_id);
+        if let Some(previous) = self.pages.get_mut(&page_id) {
+            if previous.page != page {
+                let previous_page = std::mem::replace(&mut previous.page, page);
+                self.release_page(previous_page);
+            }
+            self.touch(page_id);
+            return;
+        }
+        // The spilled version is outdated
+        if let Some(spill_file) = &mut self.spill_file {
+            spill_file.forget(&page_id);
+        }
+        while self.pages.len() >= self.capacity {
+            let Some((_, evicted)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(cached) = self.pages.remove(&evicted) {
+                if !self.spill {
+                    self.current_pages.remove(&evicted);
+                }
+                self.spill_page(evicted, cached.page);
+                self.release_page(cached.page);
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.pages.insert(
+            page_id,
+            CachedPage {
+                page,
+                last_used: self.tick,
+            },
+        );
+        self.lru.insert(self.tick, page_id);
+    }
+
+    /// Drop a page from the cache, returns true if it was cached
+    pub fn remove(&mut self, page_id: &PageId) -> bool {
+        self.current_pages.remove(page_id);
+        if let Some(spill_file) = &mut self.spill_file {
+            spill_file.forget(page_id);
+        }
+        let Some(cached) = self.pages.remove(page_id) else {
+            return false;
+        };
+        self.lru.remove(&cached.last_used);
+        self.release_page(cached.page);
+        true
+    }
+
+    /// Write the cached and spilled pages to a file, each page after its id,
+    /// following the LSN the pages are at. Pages read from the current
+    /// relation are left out.
+    pub fn save(&self, path: &Path, lsn: u64) -> io::Result<()> {
+        let mut content = lsn.to_le_bytes().to_vec();
+        for (page_id, cached) in &self.pages {
+            if self.current_pages.contains(page_id) {
+                continue;
+            }
+            content.extend_from_slice(&encode_page_id(page_id));
+            content.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(cached.page.cast::<u8>(), pg_sys::BLCKSZ as usize)
+            });
+        }
+        if let Some(spill_file) = &self.spill_file {
+            let mut page = vec![0; pg_sys::BLCKSZ as usize];
+            for (page_id, slot) in &spill_file.slots {
+                if !spill_file.read_slot(*slot, page.as_mut_ptr()) {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                content.extend_from_slice(&encode_page_id(page_id));
+                content.extend_from_slice(&page);
+            }
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Replaced at once so a failed save keeps the previous pages
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Cache the pages of a file written by `save`, returns the number of
+    /// pages loaded, 0 when there's no such file. The pages must have been
+    /// saved at `lsn`, where decoding resumes.
+    pub fn load(&mut self, path: &Path, lsn: u64) -> io::Result<usize> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let Some((header, content)) = content.split_at_checked(SAVED_HEADER_SIZE) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated page cache file",
+            ));
+        };
+        let saved_lsn = u64::from_le_bytes(header.try_into().unwrap());
+        if saved_lsn != lsn {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "its pages are at {}, decoding must resume there instead of {}",
+                    PgLSN::from(saved_lsn),
+                    PgLSN::from(lsn)
+                ),
+            ));
+        }
+        let entry_size = SAVED_PAGE_ID_SIZE + pg_sys::BLCKSZ as usize;
+        if content.len() % entry_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated page cache file",
+            ));
+        }
+        for entry in content.chunks_exact(entry_size) {
+            let (page_id, image) = entry.split_at(SAVED_PAGE_ID_SIZE);
+            let page = self.alloc_page();
+            unsafe {
+                std::ptr::copy_nonoverlapping(image.as_ptr(), page.cast::<u8>(), image.len());
+            }
+            self.insert(decode_page_id(page_id), page);
+        }
+        Ok(content.len() / entry_size)
+    }
+
+    /// Free every page and the spill file, the counters are kept
+    pub fn clear(&mut self) {
+        let pages = self.pages.drain().map(|(_, cached)| cached.page);
+        for page in pages.chain(self.free_pages.drain(..)) {
+            unsafe { pg_sys::pfree(page.cast()) };
+        }
+        self.lru.clear();
+        self.current_pages.clear();
+        self.spill_file = None;
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        decoder::PageId,
+        page_cache::{saved_cache_path, PageCache},
+    };
+    use pgrx::prelude::*;
+    use pgrx::PgMemoryContexts;
+
+    fn page_id(blknum: pg_sys::BlockNumber) -> PageId {
+        let rlocator = pg_sys::RelFileLocator {
+            spcOid: 1663.into(),
+            dbOid: 5.into(),
+            relNumber: 16384.into(),
+        };
+        PageId::new(&rlocator, 0, blknum)
+    }
+
+    #[pg_test]
+    fn test_page_cache_lru_eviction() {
+        let mut cache = PageCache::new(2, PgMemoryContexts::CurrentMemoryContext, false, false);
+        for blknum in 0..2 {
+            let page = cache.alloc_page();
+            cache.insert(page_id(blknum), page);
+        }
+        // Block 0 becomes the most recently used, block 1 is evicted
+        assert!(cache.get(&page_id(0)).is_some());
+        let page = cache.alloc_page();
+        cache.insert(page_id(2), page);
+
+        assert!(cache.get(&page_id(1)).is_none());
+        assert!(cache.peek(&page_id(0)).is_some());
+        assert!(cache.peek(&page_id(2)).is_some());
+        assert_eq!(cache.stats.hits, 1);
+        assert_eq!(cache.stats.misses, 1);
+        assert_eq!(cache.stats.evictions, 1);
+
+        // The evicted buffer is reused
+        assert_eq!(cache.stats.allocations, 3);
+        let page = cache.alloc_page();
+        cache.insert(page_id(3), page);
+        assert_eq!(cache.stats.allocations, 3);
+    }
+
+    #[pg_test]
+    fn test_page_cache_spill() {
+        let mut cache = PageCache::new(1, PgMemoryContexts::CurrentMemoryContext, false, true);
+        for blknum in 0..2 {
+            let page = cache.alloc_page();
+            unsafe {
+                page.cast::<u8>()
+                    .write_bytes(u8::try_from(blknum).unwrap() + 1, 8)
+            };
+            cache.insert(page_id(blknum), page);
+        }
+        assert_eq!(cache.stats.spilled, 1);
+        assert!(cache.peek(&page_id(0)).is_none());
+
+        // Block 0 is read back, spilling block 1
+        let page = cache.get(&page_id(0)).unwrap();
+        assert_eq!(unsafe { *page.cast::<u8>() }, 1);
+        assert_eq!(cache.stats.reloaded, 1);
+        assert_eq!(cache.stats.misses, 0);
+        assert_eq!(cache.stats.spilled, 2);
+
+        // Removed pages aren't read back
+        cache.remove(&page_id(1));
+        assert!(cache.get(&page_id(1)).is_none());
+        assert_eq!(cache.stats.misses, 1);
+    }
+
+    #[pg_test]
+    fn test_page_cache_save_load() {
+        let mut cache = PageCache::new(1, PgMemoryContexts::CurrentMemoryContext, false, true);
+        for blknum in 0..2 {
+            let page = cache.alloc_page();
+            unsafe {
+                page.cast::<u8>()
+                    .write_bytes(u8::try_from(blknum).unwrap() + 1, 8)
+            };
+            cache.insert(page_id(blknum), page);
+        }
+        let path = saved_cache_path("test_save_load").unwrap();
+        // Both the cached and the spilled page are saved
+        cache.save(&path, 0x1800000).unwrap();
+
+        let mut loaded = PageCache::new(2, PgMemoryContexts::CurrentMemoryContext, false, false);
+        // Saved at another LSN
+        assert!(loaded.load(&path, 0x1900000).is_err());
+        assert_eq!(loaded.load(&path, 0x1800000).unwrap(), 2);
+        for blknum in 0..2 {
+            let page = loaded.peek(&page_id(blknum)).unwrap();
+            assert_eq!(
+                unsafe { *page.cast::<u8>() },
+                u8::try_from(blknum).unwrap() + 1
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.load(&path, 0x1800000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_saved_cache_path() {
+        assert!(saved_cache_path("chunk_1").is_ok());
+        assert!(saved_cache_path("../chunk").is_err());
+        assert!(saved_cache_path("").is_err());
+    }
+
+    #[pg_test]
+    fn test_page_cache_clear() {
+        let mut cache = PageCache::new(1, PgMemoryContexts::CurrentMemoryContext, false, true);
+        for blknum in 0..2 {
+            let page = cache.alloc_page();
+            cache.insert(page_id(blknum), page);
+        }
+        cache.remove(&page_id(1));
+        assert!(cache.bytes() > 0);
+
+        cache.clear();
+        assert_eq!(cache.bytes(), 0);
+        assert!(cache.get(&page_id(0)).is_none());
+        assert_eq!(cache.stats.spilled, 1);
+    }
+}
@@ -1,3 +1,5 @@
+pub use pg_waldecoder_core::lsn::{filename_to_startptr, InvalidLSN};
+use pg_waldecoder_core::lsn::{format_lsn, parse_lsn};
 use pgrx::callconv::{ArgAbi, BoxRet};
 use pgrx::datum::Datum;
 use pgrx::pg_sys::Oid;
@@ -10,7 +12,6 @@ use std::ffi::CStr;
 use std::fmt::{Display, Formatter};
 use std::num::TryFromIntError;
 use std::ops::{Add, Sub};
-use std::path;
 
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
@@ -18,25 +19,10 @@ pub struct PgLSN {
     value: u64,
 }
 
-#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, thiserror::Error)]
-pub enum InvalidLSN {
-    #[error("Invalid LSN Format '{0}'")]
-    Format(String),
-    #[error("Invalid filename: '{0}'")]
-    FileName(String),
-    #[error("Invalid hex value in '{0}': `{1}`")]
-    HexValue(String, String),
-}
-
 impl Display for PgLSN {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         // format ourselves as a `ffffffff/ffffffff` string
-        write!(
-            f,
-            "{0:X}/{1:08X}",
-            self.value >> 32,
-            (self.value & 0xffffffff) as u32
-        )
+        write!(f, "{}", format_lsn(self.value))
     }
 }
 
@@ -95,21 +81,7 @@ impl TryFrom<&str> for PgLSN {
     type Error = InvalidLSN;
 
     fn try_from(lsn: &str) -> Result<Self, Self::Error> {
-        let mut iter = lsn.split('/');
-        let Some(xlogid_str) = iter.next() else {
-            return Err(InvalidLSN::Format(lsn.to_string()));
-        };
-        let xlogid = match u64::from_str_radix(xlogid_str, 16) {
-            Ok(xlogid) => xlogid,
-            Err(e) => return Err(InvalidLSN::HexValue(lsn.to_string(), e.to_string())),
-        };
-
-        let xrecoff_str = iter.next().unwrap();
-        let xrecoff = match u64::from_str_radix(xrecoff_str, 16) {
-            Ok(xrecoff) => xrecoff,
-            Err(e) => return Err(InvalidLSN::HexValue(lsn.to_string(), e.to_string())),
-        };
-        Ok(PgLSN::from(xlogid << 32 | xrecoff))
+        parse_lsn(lsn).map(PgLSN::from)
     }
 }
 
@@ -193,47 +165,7 @@ impl Sub<PgLSN> for PgLSN {
 
 /// Returns file name for a provided timeline and record pointer
 pub fn xlog_file_name(tli: pg_sys::TimeLineID, log_seg_no: pg_sys::XLogSegNo, wal_segsz_bytes: i32) -> String {
-    let segments_per_xlog_id = 0x100000000u64 / u64::from(wal_segsz_bytes.cast_unsigned());
-    let up = log_seg_no / segments_per_xlog_id;
-    let rest = log_seg_no % segments_per_xlog_id;
-    format!("{tli:08X}{up:08X}{rest:08X}")
-}
-
-/// Convert a filename to a start ptr and timeline
-pub fn filename_to_startptr(
-    filename: &str,
-    wal_segsz_bytes: u64,
-) -> Result<(u64, u64), InvalidLSN> {
-    let Some(filename) = path::Path::new(filename)
-        .file_name()
-        .and_then(|s| s.to_str())
-    else {
-        return Err(InvalidLSN::FileName(filename.to_string()));
-    };
-
-    let tli_str = &filename[0..8];
-    let tli = match u64::from_str_radix(tli_str, 16) {
-        Ok(tli) => tli,
-        Err(e) => {
-            return Err(InvalidLSN::HexValue(
-                filename[0..8].to_string(),
-                e.to_string(),
-            ))
-        }
-    };
-
-    let log_str = &filename[8..16];
-    let log = match u64::from_str_radix(log_str, 16) {
-        Ok(log) => log,
-        Err(e) => return Err(InvalidLSN::HexValue(log_str.to_string(), e.to_string())),
-    };
-
-    let seg_str = &filename[16..24];
-    let seg = match u64::from_str_radix(seg_str, 16) {
-        Ok(seg) => seg,
-        Err(e) => return Err(InvalidLSN::HexValue(seg_str.to_string(), e.to_string())),
-    };
-    Ok((tli, log * 0x100000000 * wal_segsz_bytes + seg))
+    pg_waldecoder_core::lsn::xlog_file_name(tli, log_seg_no, wal_segsz_bytes.cast_unsigned())
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -253,6 +185,8 @@ mod tests {
     fn test_filename_to_startptr() {
         let res = filename_to_startptr("000000010000000000000018", 1024 * 1024);
         assert_eq!(res.unwrap(), (1, 24));
+        let res = filename_to_startptr("000000010000000200000018", 16 * 1024 * 1024);
+        assert_eq!(res.unwrap(), (1, 2 * 256 + 24));
     }
 
     #[test]
@@ -0,0 +1,138 @@
+use std::ffi::CStr;
+
+use pgrx::{extension_sql, pg_guard, pg_sys, FromDatum, PgList};
+
+/// Rows planned for a scan without `rows_hint`
+const DEFAULT_ROWS: u32 = 1000;
+
+// pg_waldecoder reads WAL files and pages, runs subtransactions and keeps a
+// per-backend state (summary, caches, progress), it stays PARALLEL UNSAFE.
+// Its row estimate comes from the support function, the rows_hint argument
+// when it's a constant.
+extension_sql!(
+    r"
+CREATE FUNCTION pg_waldecoder_support(internal) RETURNS internal
+    LANGUAGE c STRICT
+    AS 'MODULE_PATHNAME', 'pg_waldecoder_support';
+
+DO $$
+BEGIN
+    EXECUTE format('ALTER FUNCTION %s COST 10000 ROWS 1000 SUPPORT pg_waldecoder_support',
+        (SELECT oid::regprocedure FROM pg_proc
+         WHERE proname = 'pg_waldecoder' AND 'rows_hint' = ANY (proargnames)));
+END
+$$;
+",
+    name = "pg_waldecoder_support",
+    finalize,
+);
+
+/// Position of the `rows_hint` argument among the input arguments of the
+/// function, found by name in `proargnames`
+unsafe fn rows_hint_position(funcid: pg_sys::Oid) -> Option<usize> {
+    let tuple = unsafe {
+        pg_sys::SearchSysCache1(
+            pg_sys::SysCacheIdentifier::PROCOID as i32,
+            pg_sys::Datum::from(funcid),
+        )
+    };
+    if tuple.is_null() {
+        return None;
+    }
+    let mut types = std::ptr::null_mut();
+    let mut names = std::ptr::null_mut();
+    let mut modes = std::ptr::null_mut();
+    let nargs =
+        unsafe { pg_sys::get_func_arg_info(tuple, &raw mut types, &raw mut names, &raw mut modes) };
+    let mut position = None;
+    if !names.is_null() {
+        let mut inputs = 0;
+        for i in 0..usize::try_from(nargs).unwrap_or(0) {
+            // Without modes, every argument is an input
+            let mode = if modes.is_null() {
+                b'i'
+            } else {
+                unsafe { *modes.add(i) }.cast_unsigned()
+            };
+            if !matches!(mode, b'i' | b'b' | b'v') {
+                continue;
+            }
+            let name = unsafe { *names.add(i) };
+            if !name.is_null() && unsafe { CStr::from_ptr(name) } == c"rows_hint" {
+                position = Some(inputs);
+                break;
+            }
+            inputs += 1;
+        }
+    }
+    unsafe { pg_sys::ReleaseSysCache(tuple) };
+    position
+}
+
+/// Rows given by the `rows_hint` argument of a call when it's a positive
+/// constant
+unsafe fn rows_hint(node: *mut pg_sys::Node) -> Option<f64> {
+    if node.is_null() || unsafe { (*node).type_ } != pg_sys::NodeTag::T_FuncExpr {
+        return None;
+    }
+    let expr = node.cast::<pg_sys::FuncExpr>();
+    let position = unsafe { rows_hint_position((*expr).funcid) }?;
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg((*expr).args) };
+    let arg = args.get_ptr(position)?;
+    if unsafe { (*arg).type_ } != pg_sys::NodeTag::T_Const {
+        return None;
+    }
+    let constant = arg.cast::<pg_sys::Const>();
+    if unsafe { (*constant).consttype } != pg_sys::INT4OID {
+        return None;
+    }
+    let (value, isnull) = unsafe { ((*constant).constvalue, (*constant).constisnull) };
+    let rows = unsafe { i32::from_datum(value, isnull) }?;
+    (rows > 0).then_some(f64::from(rows))
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_pg_waldecoder_support() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+/// Planner support function of `pg_waldecoder`, estimates its rows from
+/// `rows_hint`, falling back to the function's `ROWS` of 1000
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C-unwind" fn pg_waldecoder_support(
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::Datum {
+    let Some(request) = (unsafe { pgrx::fcinfo::pg_getarg_pointer::<pg_sys::Node>(fcinfo, 0) })
+    else {
+        return pg_sys::Datum::from(0);
+    };
+    if unsafe { (*request).type_ } != pg_sys::NodeTag::T_SupportRequestRows {
+        return pg_sys::Datum::from(0);
+    }
+    let request = request.cast::<pg_sys::SupportRequestRows>();
+    let rows = unsafe { rows_hint((*request).node) }.unwrap_or(f64::from(DEFAULT_ROWS));
+    unsafe { (*request).rows = rows };
+    pg_sys::Datum::from(request)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_pg_waldecoder_rows_hint() {
+        let plan_rows = |args: &str| {
+            Spi::get_one::<String>(&format!("EXPLAIN SELECT * FROM pg_waldecoder('0/0'{args})"))
+                .unwrap()
+                .unwrap()
+        };
+        assert!(plan_rows(", rows_hint => 42").contains(" rows=42 "));
+        assert!(plan_rows("").contains(" rows=1000 "));
+        // Only constants are used
+        assert!(plan_rows(", rows_hint => (random() * 10)::int").contains(" rows=1000 "));
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    cell::Cell,
+    ffi::c_int,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use pgrx::{
+    extension_sql, pg_guard, pg_shmem_init, pg_sys, prelude::*, shmem::PGRXSharedMemory, PgLwLock,
+};
+
+use crate::{guc::PROGRESS_INTERVAL, pg_lsn::PgLSN};
+
+/// Number of scans reported at the same time
+const MAX_REPORTED_SCANS: usize = 64;
+/// Records decoded between two updates of the shared progress
+const SHARED_UPDATE_RECORDS: u64 = 1024;
+
+/// Progress of a scan as seen by other backends
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanProgress {
+    /// 0 when the slot is free
+    pid: i32,
+    start_lsn: u64,
+    /// 0 when decoding up to the end of the available WAL
+    end_lsn: u64,
+    current_lsn: u64,
+    records: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressSlots {
+    slots: [ScanProgress; MAX_REPORTED_SCANS],
+}
+
+impl Default for ProgressSlots {
+    fn default() -> Self {
+        ProgressSlots {
+            slots: [ScanProgress::default(); MAX_REPORTED_SCANS],
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for ProgressSlots {}
+
+static PROGRESS: PgLwLock<ProgressSlots> = unsafe { PgLwLock::new(c"pg_waldecoder_progress") };
+
+/// The shared memory is only available when preloaded
+static SHMEM_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Whether the exit callback releasing the backend's slots is registered
+    static EXIT_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+extension_sql!(
+    r"
+CREATE VIEW pg_stat_progress_waldecoder AS
+    SELECT * FROM pg_waldecoder_progress();
+",
+    name = "progress_view",
+    requires = [pg_waldecoder_progress],
+);
+
+/// Request the shared memory holding the progress of the scans
+pub fn init() {
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        pg_shmem_init!(PROGRESS);
+        SHMEM_INITIALIZED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Release the slots of the backend's scans when it exits, a FATAL error
+/// doesn't drop them
+#[pg_guard]
+unsafe extern "C-unwind" fn release_backend_slots(_code: c_int, _arg: pg_sys::Datum) {
+    let pid = unsafe { pg_sys::MyProcPid };
+    for slot in PROGRESS
+        .exclusive()
+        .slots
+        .iter_mut()
+        .filter(|s| s.pid == pid)
+    {
+        *slot = ScanProgress::default();
+    }
+}
+
+/// Percent of the range between start and end reached by current
+fn percent_done(start: u64, end: u64, current: u64) -> Option<f64> {
+    if end <= start {
+        return None;
+    }
+    // Precision loss is fine for a percentage
+    #[allow(clippy::cast_precision_loss)]
+    let percent = (current.saturating_sub(start)) as f64 * 100.0 / (end - start) as f64;
+    Some(percent.min(100.0))
+}
+
+/// Progress of the current scan, published in shared memory when the library
+/// is preloaded and reported as NOTICEs every `pg_waldecoder.progress_interval`
+pub struct Progress {
+    start: PgLSN,
+    end: Option<PgLSN>,
+    records: u64,
+    slot: Option<usize>,
+    last_notice: Instant,
+}
+
+impl Progress {
+    pub fn new(start: PgLSN, end: Option<PgLSN>) -> Progress {
+        let slot = SHMEM_INITIALIZED
+            .load(Ordering::Relaxed)
+            .then(|| {
+                if !EXIT_CALLBACK.replace(true) {
+                    unsafe {
+                        pg_sys::before_shmem_exit(
+                            Some(release_backend_slots),
+                            pg_sys::Datum::from(0),
+                        );
+                    }
+                }
+                let mut slots = PROGRESS.exclusive();
+                let slot = slots.slots.iter().position(|s| s.pid == 0)?;
+                slots.slots[slot] = ScanProgress {
+                    pid: unsafe { pg_sys::MyProcPid },
+                    start_lsn: start.into(),
+                    end_lsn: end.map_or(0, u64::from),
+                    current_lsn: start.into(),
+                    records: 0,
+                };
+                Some(slot)
+            })
+            .flatten();
+        Progress {
+            start,
+            end,
+            records: 0,
+            slot,
+            last_notice: Instant::now(),
+        }
+    }
+
+    /// Account for a record read at `current`
+    pub fn record_read(&mut self, current: PgLSN) {
+        self.records += 1;
+        if let Some(slot) = self.slot {
+            if self.records % SHARED_UPDATE_RECORDS == 0 {
+                let mut slots = PROGRESS.exclusive();
+                slots.slots[slot].current_lsn = current.into();
+                slots.slots[slot].records = self.records;
+            }
+        }
+        let interval = PROGRESS_INTERVAL.get();
+        if interval <= 0 {
+            return;
+        }
+        let interval = Duration::from_secs(interval.cast_unsigned().into());
+        if self.last_notice.elapsed() < interval {
+            return;
+        }
+        self.last_notice = Instant::now();
+        let percent = self
+            .end
+            .and_then(|end| percent_done(self.start.into(), end.into(), current.into()))
+            .map_or(String::new(), |p| format!(" ({p:.1}%)"));
+        notice!(
+            "Decoded {} records, reached {current}{percent}",
+            self.records
+        );
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            PROGRESS.exclusive().slots[slot] = ScanProgress::default();
+        }
+    }
+}
+
+/// Progress of the scans running in all backends
+#[pg_extern]
+fn pg_waldecoder_progress() -> TableIterator<
+    'static,
+    (
+        name!(pid, i32),
+        name!(start_lsn, PgLSN),
+        name!(end_lsn, Option<PgLSN>),
+        name!(current_lsn, PgLSN),
+        name!(percent_done, Option<f64>),
+        name!(records, i64),
+    ),
+> {
+    if !SHMEM_INITIALIZED.load(Ordering::Relaxed) {
+        error!("pg_waldecoder must be in shared_preload_libraries to report progress");
+    }
+    let slots = PROGRESS.share().slots;
+    TableIterator::new(slots.into_iter().filter(|s| s.pid != 0).map(|s| {
+        (
+            s.pid,
+            PgLSN::from(s.start_lsn),
+            (s.end_lsn != 0).then(|| PgLSN::from(s.end_lsn)),
+            PgLSN::from(s.current_lsn),
+            percent_done(s.start_lsn, s.end_lsn, s.current_lsn),
+            i64::try_from(s.records).unwrap_or(i64::MAX),
+        )
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        pg_lsn::PgLSN,
+        progress::{percent_done, release_backend_slots, Progress},
+    };
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_percent_done() {
+        assert_eq!(percent_done(100, 300, 200), Some(50.0));
+        assert_eq!(percent_done(100, 300, 400), Some(100.0));
+        assert_eq!(percent_done(100, 300, 50), Some(0.0));
+        assert_eq!(percent_done(100, 0, 200), None);
+    }
+
+    #[pg_test]
+    fn test_release_backend_slots() {
+        let scans = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pg_stat_progress_waldecoder WHERE pid = pg_backend_pid()",
+            )
+        };
+        let start = PgLSN::from(0x0100_0028u64);
+        // A FATAL error exits without dropping the progress
+        std::mem::forget(Progress::new(start, None));
+        assert_eq!(scans(), Ok(Some(1)));
+        unsafe { release_backend_slots(1, pg_sys::Datum::from(0)) };
+        assert_eq!(scans(), Ok(Some(0)));
+    }
+}
@@ -1,9 +1,40 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+};
+
 use pgrx::{
-    pg_sys::{self, InvalidOid, Oid},
+    datum::DatumWithOid,
+    pg_sys::{
+        self, InvalidOid, Oid,
+        RmgrIds::{RM_HEAP_ID, RM_RELMAP_ID, RM_SMGR_ID},
+    },
     prelude::*,
-    Spi,
+    PgBox, Spi,
 };
 
+use crate::xlog_reader::{get_block_tag, get_block_tag_extended};
+
+/// Database of a record's relation, relative to the one we're connected to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordDatabase {
+    Current,
+    /// Shared catalogs, visible from every database
+    Shared,
+    /// Relids and tuple descriptors can't be resolved from here
+    Other,
+}
+
+pub fn classify_database(rlocator: &pg_sys::RelFileLocator) -> RecordDatabase {
+    if rlocator.dbOid == InvalidOid {
+        RecordDatabase::Shared
+    } else if rlocator.dbOid == unsafe { pg_sys::MyDatabaseId } {
+        RecordDatabase::Current
+    } else {
+        RecordDatabase::Other
+    }
+}
+
 /// Find the matching relid for the provided `RelFileLocator`
 pub fn get_relid_from_rlocator(rlocator: &pg_sys::RelFileLocator) -> Option<Oid> {
     unsafe {
@@ -14,12 +45,212 @@ pub fn get_relid_from_rlocator(rlocator: &pg_sys::RelFileLocator) -> Option<Oid>
     }
 }
 
+/// Read the current content of a block, returns false if the relation or the
+/// block doesn't exist anymore. The block is read through the shared
+/// buffers, the file lacks the changes not written by a checkpoint yet.
+pub fn read_current_block(
+    rlocator: &pg_sys::RelFileLocator,
+    forknum: i32,
+    blknum: pg_sys::BlockNumber,
+    page: pg_sys::Page,
+) -> bool {
+    unsafe {
+        // INVALID_PROC_NUMBER, WAL is only written for permanent relations
+        let smgr = pg_sys::smgropen(*rlocator, pg_sys::INVALID_PROC_NUMBER);
+        if !pg_sys::smgrexists(smgr, forknum) || blknum >= pg_sys::smgrnblocks(smgr, forknum) {
+            return false;
+        }
+        let buffer = pg_sys::ReadBufferWithoutRelcache(
+            *rlocator,
+            forknum,
+            blknum,
+            pg_sys::ReadBufferMode::RBM_NORMAL,
+            std::ptr::null_mut(),
+            true,
+        );
+        pg_sys::LockBuffer(buffer, pg_sys::BUFFER_LOCK_SHARE.cast_signed());
+        std::ptr::copy_nonoverlapping(
+            pg_sys::BufferGetPage(buffer).cast::<u8>(),
+            page.cast::<u8>(),
+            pg_sys::BLCKSZ as usize,
+        );
+        pg_sys::UnlockReleaseBuffer(buffer);
+    }
+    true
+}
+
+/// Cache of relfilenode to relid lookups, cleared when a record may have
+/// changed the mapping
+pub struct RelidCache {
+    relids: HashMap<(Oid, pg_sys::RelFileNumber), Option<Oid>>,
+    /// Relfilenode of pg_class, whose changes affect the mapping
+    pg_class_relnumber: pg_sys::RelFileNumber,
+}
+
+impl RelidCache {
+    pub fn new() -> RelidCache {
+        let pg_class_relnumber =
+            unsafe { pg_sys::RelationMapOidToFilenumber(pg_sys::RelationRelationId, false) };
+        RelidCache {
+            relids: HashMap::new(),
+            pg_class_relnumber,
+        }
+    }
+
+    /// Find the relid of a `RelFileLocator`, looking it up on a miss
+    pub fn get(&mut self, rlocator: &pg_sys::RelFileLocator) -> Option<Oid> {
+        *self
+            .relids
+            .entry((rlocator.spcOid, rlocator.relNumber))
+            .or_insert_with(|| get_relid_from_rlocator(rlocator))
+    }
+
+    /// Clear the cache if the record creates, truncates or remaps a relation
+    /// file, or modifies pg_class
+    pub fn invalidate_for(
+        &mut self,
+        xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) {
+        let invalidates = match u32::from(record.header.xl_rmid) {
+            RM_SMGR_ID | RM_RELMAP_ID => true,
+            RM_HEAP_ID if record.max_block_id >= 0 => {
+                get_block_tag(xlog_reader).0.relNumber == self.pg_class_relnumber
+            }
+            _ => false,
+        };
+        if invalidates {
+            self.relids.clear();
+        }
+    }
+}
+
+impl Default for RelidCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Relation files whose changes are decoded, the block references of a
+/// record are checked against them before any relid lookup, page restoration
+/// or tuple reconstruction. Relations are matched by their current
+/// relfilenode, the changes logged before a rewrite of the table aren't
+/// decoded. The toast table of a relation is matched with it, its chunks
+/// rebuild the relation's toasted values.
+pub struct RelationFilter {
+    relnumbers: HashSet<(Oid, pg_sys::RelFileNumber)>,
+}
+
+impl RelationFilter {
+    /// Resolve the relations, raises an error if one doesn't exist
+    pub fn new(relations: &[&str]) -> RelationFilter {
+        let relnumbers = relations
+            .iter()
+            .flat_map(|relation| {
+                let args: [DatumWithOid; 1] = [(*relation).into()];
+                let Ok((Some(relnumber), Some(shared), toast_relnumber)) =
+                    Spi::get_three_with_args::<Oid, bool, Oid>(
+                        "SELECT pg_relation_filenode(oid), relisshared,
+                             pg_relation_filenode(NULLIF(reltoastrelid, 0))
+                         FROM pg_class WHERE oid = to_regclass($1)",
+                        &args,
+                    )
+                else {
+                    error!("Relation {relation} of the filter doesn't exist");
+                };
+                let db_oid = if shared {
+                    InvalidOid
+                } else {
+                    unsafe { pg_sys::MyDatabaseId }
+                };
+                std::iter::once(relnumber)
+                    .chain(toast_relnumber)
+                    .map(move |relnumber| (db_oid, relnumber))
+            })
+            .collect();
+        RelationFilter { relnumbers }
+    }
+
+    /// Whether a block of the latest decoded record is in one of the relations
+    pub fn matches(
+        &self,
+        xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> bool {
+        (0..=record.max_block_id)
+            .filter_map(|block_id| u8::try_from(block_id).ok())
+            .filter_map(|block_id| get_block_tag_extended(xlog_reader, block_id))
+            .any(|(rlocator, _, _)| {
+                self.relnumbers
+                    .contains(&(rlocator.dbOid, rlocator.relNumber))
+            })
+    }
+}
+
+/// Schema and name of a relation, None when it doesn't exist anymore
+pub fn lookup_relation_name(relid: Oid) -> Option<(String, String)> {
+    unsafe {
+        let relname = pg_sys::get_rel_name(relid);
+        if relname.is_null() {
+            return None;
+        }
+        let nspname = pg_sys::get_namespace_name(pg_sys::get_rel_namespace(relid));
+        if nspname.is_null() {
+            return None;
+        }
+        Some((
+            CStr::from_ptr(nspname).to_string_lossy().into_owned(),
+            CStr::from_ptr(relname).to_string_lossy().into_owned(),
+        ))
+    }
+}
+
+/// Cache of the schema and name of relids
+#[derive(Default)]
+pub struct RelationNameCache {
+    names: HashMap<Oid, Option<(String, String)>>,
+}
+
+impl RelationNameCache {
+    pub fn get(&mut self, relid: Oid) -> Option<(String, String)> {
+        self.names
+            .entry(relid)
+            .or_insert_with(|| lookup_relation_name(relid))
+            .clone()
+    }
+}
+
+/// Name of a `relpersistence` value
+pub fn persistence_name(relpersistence: u8) -> &'static str {
+    match relpersistence {
+        pg_sys::RELPERSISTENCE_UNLOGGED => "unlogged",
+        pg_sys::RELPERSISTENCE_TEMP => "temporary",
+        _ => "permanent",
+    }
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
-    use crate::relation::get_relid_from_rlocator;
+    use crate::relation::{
+        classify_database, get_relid_from_rlocator, persistence_name, RecordDatabase,
+        RelationNameCache, RelidCache,
+    };
     use pgrx::prelude::*;
 
+    #[test]
+    fn test_persistence_name() {
+        assert_eq!(
+            persistence_name(pg_sys::RELPERSISTENCE_PERMANENT),
+            "permanent"
+        );
+        assert_eq!(
+            persistence_name(pg_sys::RELPERSISTENCE_UNLOGGED),
+            "unlogged"
+        );
+        assert_eq!(persistence_name(pg_sys::RELPERSISTENCE_TEMP), "temporary");
+    }
+
     #[pg_test]
     fn test_get_relid_from_rlocator() {
         let Ok((Some(expected_oid), Some(relfilenode), Some(tablespace))) =
@@ -38,4 +269,49 @@ mod tests {
         let relid = get_relid_from_rlocator(&rlocator).unwrap();
         assert_eq!(relid, expected_oid);
     }
+
+    #[pg_test]
+    fn test_classify_database() {
+        let mut rlocator = pg_sys::RelFileLocator {
+            spcOid: pg_sys::DEFAULTTABLESPACE_OID,
+            dbOid: unsafe { pg_sys::MyDatabaseId },
+            relNumber: 16384.into(),
+        };
+        assert_eq!(classify_database(&rlocator), RecordDatabase::Current);
+        rlocator.dbOid = pg_sys::InvalidOid;
+        assert_eq!(classify_database(&rlocator), RecordDatabase::Shared);
+        rlocator.dbOid = u32::MAX.into();
+        assert_eq!(classify_database(&rlocator), RecordDatabase::Other);
+    }
+
+    #[pg_test]
+    fn test_relid_cache() {
+        let Ok((Some(expected_oid), Some(relfilenode))) = Spi::get_two::<pg_sys::Oid, pg_sys::Oid>(
+            "SELECT oid, relfilenode FROM pg_class where relname='pg_statistic'",
+        ) else {
+            panic!("Couldn't get relfilenode")
+        };
+        let mut rlocator = pg_sys::RelFileLocator {
+            spcOid: pg_sys::InvalidOid,
+            dbOid: unsafe { pg_sys::MyDatabaseId },
+            relNumber: relfilenode,
+        };
+        let mut cache = RelidCache::new();
+        assert_eq!(cache.get(&rlocator), Some(expected_oid));
+        assert_eq!(cache.get(&rlocator), Some(expected_oid));
+        // Missing relations are cached too
+        rlocator.relNumber = u32::MAX.into();
+        assert_eq!(cache.get(&rlocator), None);
+        assert_eq!(cache.relids.len(), 2);
+    }
+
+    #[pg_test]
+    fn test_relation_name_cache() {
+        let mut cache = RelationNameCache::default();
+        assert_eq!(
+            cache.get(pg_sys::StatisticRelationId),
+            Some(("pg_catalog".to_string(), "pg_statistic".to_string()))
+        );
+        assert_eq!(cache.get(u32::MAX.into()), None);
+    }
 }
@@ -0,0 +1,395 @@
+use std::{
+    ffi::{c_char, CStr, CString},
+    ptr,
+};
+
+use pgrx::{
+    pg_sys::{self, TimeLineID},
+    prelude::*,
+};
+use thiserror::Error;
+
+use crate::pg_lsn::PgLSN;
+
+const APPLICATION_NAME: &CStr = c"pg_waldecoder";
+/// How long to wait for the primary before checking for interrupts
+const RECEIVE_TIMEOUT_MS: i64 = 1000;
+/// 'w' + dataStart + walEnd + sendTime
+const XLOG_DATA_HEADER_LEN: usize = 25;
+/// 'k' + walEnd + sendTime + replyRequested
+const KEEPALIVE_LEN: usize = 18;
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum RemoteError {
+    #[error("Could not load libpqwalreceiver")]
+    LoadError,
+    #[error("Could not connect to the primary server: {0}")]
+    ConnectError(String),
+    #[error("Could not run {0} on the primary server: {1}")]
+    CommandError(String, String),
+    #[error("Invalid wal_segment_size reported by the primary server: {0}")]
+    InvalidWalSegSz(String),
+    #[error("Replication stream ended at {0}")]
+    StreamEnded(PgLSN),
+    #[error("Unexpected replication message of type {0}")]
+    UnexpectedMessage(char),
+    #[error("Received WAL at {0} while expecting {1}")]
+    UnexpectedDataStart(PgLSN, PgLSN),
+    #[error("The primary server has no WAL to stream on timeline {0} from {1}")]
+    NothingToStream(TimeLineID, PgLSN),
+}
+
+/// WAL received from a primary server with the physical replication protocol
+pub struct RemoteSource {
+    conn: *mut pg_sys::WalReceiverConn,
+    pub primary_tli: TimeLineID,
+    segsz: u32,
+    /// LSN of the first byte in buffer
+    buffer_start: PgLSN,
+    buffer: Vec<u8>,
+    /// Latest end of WAL reported by the primary
+    server_wal_end: PgLSN,
+    /// Timeline being streamed
+    timeline: TimeLineID,
+    streaming: bool,
+}
+
+fn walrcv_functions() -> &'static pg_sys::WalReceiverFunctionsType {
+    unsafe { &*pg_sys::WalReceiverFunctions }
+}
+
+fn cstr_to_string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+}
+
+/// Parse a size reported by SHOW like "16MB"
+pub fn parse_size(size: &str) -> Option<u32> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let value = size[..unit_start].parse::<u32>().ok()?;
+    let multiplier = match size[unit_start..].trim() {
+        "" | "B" => 1,
+        "kB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    value.checked_mul(multiplier)
+}
+
+impl RemoteSource {
+    /// Open a physical replication connection to the primary
+    pub fn connect(conninfo: &str) -> Result<RemoteSource, RemoteError> {
+        unsafe { pg_sys::load_file(c"libpqwalreceiver".as_ptr(), false) };
+        if unsafe { pg_sys::WalReceiverFunctions.is_null() } {
+            return Err(RemoteError::LoadError);
+        }
+        let Ok(conninfo) = CString::new(conninfo) else {
+            return Err(RemoteError::ConnectError(
+                "connection string contains a null byte".to_string(),
+            ));
+        };
+
+        let mut err: *mut c_char = ptr::null_mut();
+        let conn = unsafe {
+            (walrcv_functions().walrcv_connect.unwrap())(
+                conninfo.as_ptr(),
+                true,
+                false,
+                false,
+                APPLICATION_NAME.as_ptr(),
+                &raw mut err,
+            )
+        };
+        if conn.is_null() {
+            return Err(RemoteError::ConnectError(cstr_to_string(err)));
+        }
+
+        let mut primary_tli: TimeLineID = 0;
+        unsafe { (walrcv_functions().walrcv_identify_system.unwrap())(conn, &raw mut primary_tli) };
+
+        let mut source = RemoteSource {
+            conn,
+            primary_tli,
+            segsz: 0,
+            buffer_start: PgLSN::from(0u64),
+            buffer: Vec::new(),
+            server_wal_end: PgLSN::from(0u64),
+            timeline: primary_tli,
+            streaming: false,
+        };
+        source.segsz = source.fetch_wal_segment_size()?;
+        Ok(source)
+    }
+
+    pub fn wal_segment_size(&self) -> u32 {
+        self.segsz
+    }
+
+    /// Ask the primary for its segment size, it may differ from ours
+    fn fetch_wal_segment_size(&self) -> Result<u32, RemoteError> {
+        let query = c"SHOW wal_segment_size";
+        let ret_types = [pg_sys::TEXTOID];
+        let res = unsafe {
+            (walrcv_functions().walrcv_exec.unwrap())(
+                self.conn,
+                query.as_ptr(),
+                1,
+                ret_types.as_ptr(),
+            )
+        };
+        let res = unsafe { PgBox::from_pg(res) };
+        if res.status != pg_sys::WalRcvExecStatus::WALRCV_OK_TUPLES {
+            return Err(RemoteError::CommandError(
+                query.to_string_lossy().to_string(),
+                cstr_to_string(res.err),
+            ));
+        }
+
+        let value = unsafe {
+            let slot = pg_sys::MakeSingleTupleTableSlot(
+                res.tupledesc,
+                &raw const pg_sys::TTSOpsMinimalTuple,
+            );
+            if !pg_sys::tuplestore_gettupleslot(res.tuplestore, true, false, slot) {
+                return Err(RemoteError::InvalidWalSegSz(String::new()));
+            }
+            pg_sys::slot_getsomeattrs_int(slot, 1);
+            let value = String::from_datum(*(*slot).tts_values, *(*slot).tts_isnull);
+            pg_sys::ExecDropSingleTupleTableSlot(slot);
+            value.unwrap_or_default()
+        };
+
+        match parse_size(&value) {
+            Some(segsz) => Ok(segsz),
+            None => Err(RemoteError::InvalidWalSegSz(value)),
+        }
+    }
+
+    /// Start streaming from the beginning of the segment containing startptr.
+    ///
+    /// The xlog reader validates the long header of each segment it reads so
+    /// the whole segment is needed. A stream already started is ended first.
+    pub fn start_streaming(&mut self, startptr: PgLSN, tli: TimeLineID) -> Result<(), RemoteError> {
+        self.end_streaming();
+        let startptr = u64::from(startptr);
+        let segment_start = startptr - startptr % u64::from(self.segsz);
+
+        let mut options: pg_sys::WalRcvStreamOptions = unsafe { std::mem::zeroed() };
+        options.logical = false;
+        options.slotname = ptr::null_mut();
+        options.startpoint = segment_start;
+        options.proto.physical.startpointTLI = tli;
+        let started = unsafe {
+            (walrcv_functions().walrcv_startstreaming.unwrap())(self.conn, &raw const options)
+        };
+        // The timeline ends at the start point, no WAL would ever come
+        if !started {
+            return Err(RemoteError::NothingToStream(
+                tli,
+                PgLSN::from(segment_start),
+            ));
+        }
+
+        self.streaming = true;
+        self.timeline = tli;
+        self.buffer_start = PgLSN::from(segment_start);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn end_streaming(&mut self) {
+        if self.streaming {
+            let mut next_tli: TimeLineID = 0;
+            unsafe {
+                (walrcv_functions().walrcv_endstreaming.unwrap())(self.conn, &raw mut next_tli)
+            };
+            self.streaming = false;
+        }
+    }
+
+    fn buffer_end(&self) -> PgLSN {
+        self.buffer_start + self.buffer.len() as u64
+    }
+
+    /// Fill buf with the WAL starting at ptr.
+    ///
+    /// Returns the number of bytes copied. When `stop_at_server_end` is set,
+    /// fewer bytes are returned once the end of WAL reported by the primary is
+    /// reached instead of waiting for new WAL.
+    pub fn read(
+        &mut self,
+        ptr: PgLSN,
+        buf: &mut [u8],
+        stop_at_server_end: bool,
+    ) -> Result<usize, RemoteError> {
+        // What was received and dropped is streamed again
+        if ptr < self.buffer_start {
+            self.start_streaming(ptr, self.timeline)?;
+        }
+        // Drop what was received before the current segment
+        let segment_start = u64::from(ptr) - u64::from(ptr) % u64::from(self.segsz);
+        if u64::from(self.buffer_start) < segment_start {
+            let drop_len =
+                (segment_start - u64::from(self.buffer_start)).min(self.buffer.len() as u64);
+            self.buffer.drain(..usize::try_from(drop_len).unwrap());
+            self.buffer_start = self.buffer_start + drop_len;
+        }
+
+        let end = ptr + buf.len() as u64;
+        while self.buffer_end() < end {
+            if stop_at_server_end
+                && u64::from(self.server_wal_end) != 0
+                && self.buffer_end() >= self.server_wal_end
+            {
+                break;
+            }
+            self.receive()?;
+        }
+
+        let offset = usize::try_from(u64::from(ptr - self.buffer_start)).unwrap();
+        let available = self.buffer.len().saturating_sub(offset).min(buf.len());
+        buf[..available].copy_from_slice(&self.buffer[offset..offset + available]);
+        Ok(available)
+    }
+
+    /// Wait for the next replication message and process it
+    fn receive(&mut self) -> Result<(), RemoteError> {
+        loop {
+            let mut msg_ptr: *mut c_char = ptr::null_mut();
+            let mut wait_fd: pg_sys::pgsocket = pg_sys::PGINVALID_SOCKET;
+            let len = unsafe {
+                (walrcv_functions().walrcv_receive.unwrap())(
+                    self.conn,
+                    &raw mut msg_ptr,
+                    &raw mut wait_fd,
+                )
+            };
+            if len == 0 {
+                unsafe {
+                    pg_sys::WaitLatchOrSocket(
+                        pg_sys::MyLatch,
+                        (pg_sys::WL_EXIT_ON_PM_DEATH
+                            | pg_sys::WL_SOCKET_READABLE
+                            | pg_sys::WL_LATCH_SET
+                            | pg_sys::WL_TIMEOUT)
+                            .cast_signed(),
+                        wait_fd,
+                        RECEIVE_TIMEOUT_MS,
+                        pg_sys::PG_WAIT_EXTENSION,
+                    );
+                    pg_sys::ResetLatch(pg_sys::MyLatch);
+                }
+                pg_sys::check_for_interrupts!();
+                continue;
+            }
+            if len < 0 {
+                self.streaming = false;
+                return Err(RemoteError::StreamEnded(self.buffer_end()));
+            }
+
+            let msg = unsafe {
+                std::slice::from_raw_parts(msg_ptr.cast::<u8>(), usize::try_from(len).unwrap())
+            };
+            match msg[0] {
+                b'w' if msg.len() >= XLOG_DATA_HEADER_LEN => {
+                    let data_start = PgLSN::from(u64::from_be_bytes(msg[1..9].try_into().unwrap()));
+                    let wal_end = PgLSN::from(u64::from_be_bytes(msg[9..17].try_into().unwrap()));
+                    if data_start != self.buffer_end() {
+                        return Err(RemoteError::UnexpectedDataStart(
+                            data_start,
+                            self.buffer_end(),
+                        ));
+                    }
+                    self.buffer.extend_from_slice(&msg[XLOG_DATA_HEADER_LEN..]);
+                    self.server_wal_end = wal_end.max(self.server_wal_end);
+                    return Ok(());
+                }
+                b'k' if msg.len() >= KEEPALIVE_LEN => {
+                    let wal_end = PgLSN::from(u64::from_be_bytes(msg[1..9].try_into().unwrap()));
+                    self.server_wal_end = wal_end.max(self.server_wal_end);
+                    if msg[17] != 0 {
+                        self.send_reply();
+                    }
+                    // A keepalive may tell us we already have everything
+                    return Ok(());
+                }
+                c => return Err(RemoteError::UnexpectedMessage(char::from(c))),
+            }
+        }
+    }
+
+    /// Send a standby status update so the primary doesn't time us out
+    fn send_reply(&self) {
+        let pos = u64::from(self.buffer_end()).to_be_bytes();
+        let now = unsafe { pg_sys::GetCurrentTimestamp() }.to_be_bytes();
+        let mut reply = Vec::with_capacity(34);
+        reply.push(b'r');
+        // Write, flush and apply positions
+        reply.extend_from_slice(&pos);
+        reply.extend_from_slice(&pos);
+        reply.extend_from_slice(&pos);
+        reply.extend_from_slice(&now);
+        reply.push(0);
+        unsafe {
+            (walrcv_functions().walrcv_send.unwrap())(
+                self.conn,
+                reply.as_ptr().cast::<c_char>(),
+                i32::try_from(reply.len()).unwrap(),
+            );
+        }
+    }
+}
+
+impl Drop for RemoteSource {
+    fn drop(&mut self) {
+        self.end_streaming();
+        unsafe { (walrcv_functions().walrcv_disconnect.unwrap())(self.conn) };
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{pg_lsn::PgLSN, remote::parse_size};
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("16MB"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_size("1GB"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("512kB"), Some(512 * 1024));
+        assert_eq!(parse_size("4GB"), None);
+        assert_eq!(parse_size("16 parsecs"), None);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_conninfo() {
+        unsafe {
+            Spi::run("CREATE TABLE test_conninfo (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_conninfo VALUES (1), (2)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The flushed WAL is streamed by a walsender of the test server
+        let rows = Spi::get_one::<String>(&format!(
+            "SELECT string_agg(row_after, ',' ORDER BY lsn) FROM pg_waldecoder('{startptr}', '{endptr}',
+                 conninfo => format('host=%s port=%s',
+                     split_part(current_setting('unix_socket_directories'), ',', 1),
+                     current_setting('port')))
+             WHERE relid = 'test_conninfo'::regclass AND op = 'INSERT'"
+        ));
+        assert_eq!(rows, Ok(Some("(1),(2)".to_string())));
+    }
+}
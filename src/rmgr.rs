@@ -0,0 +1,114 @@
+use pgrx::{
+    pg_sys::{
+        self,
+        RmgrIds::{RM_HEAP2_ID, RM_HEAP_ID, RM_XLOG_ID},
+    },
+    PgBox,
+};
+
+use crate::{
+    column_types::TupleDescCache,
+    dblink::OtherDatabases,
+    decoder::{DecodedResult, OnError},
+    masking::MaskCache,
+    page_cache::PageCache,
+    relation::RelidCache,
+    summary::ScanSummary,
+    toast::ToastChunks,
+    walinspect::{record_type_name, rmgr_display_name},
+    xlog_heap::{Heap2Decoder, HeapDecoder, SkipReason},
+    xlog_newpage::NewPageDecoder,
+};
+
+/// State of the decoder shared by the resource managers while decoding a
+/// record
+pub struct DecodeContext<'a> {
+    pub xlog_reader: &'a PgBox<pg_sys::XLogReaderState>,
+    pub page_cache: &'a mut PageCache,
+    pub relid_cache: &'a mut RelidCache,
+    pub mask_cache: &'a mut MaskCache,
+    pub tuple_descs: &'a TupleDescCache,
+    pub include_other_databases: bool,
+    pub check_revert_conflicts: bool,
+    pub include_new_cid: bool,
+    pub include_visible: bool,
+    pub include_raw_tuple: bool,
+    pub include_newpages: bool,
+    pub include_hint_fpis: bool,
+    pub unqualified_names: bool,
+    /// Report the chunks inserted in toast tables instead of only using them
+    /// for the rows of their table
+    pub include_toast_chunks: bool,
+    /// Chunks of the toasted values decoded so far
+    pub toast_chunks: &'a mut ToastChunks,
+    /// Catalogs of the other databases, to decode their tuples
+    pub other_databases: Option<&'a mut OtherDatabases>,
+    pub on_error: Option<OnError>,
+}
+
+/// Decoding of the records of a resource manager
+pub trait RmgrDecoder: Sync {
+    /// Whether the record may produce a change, other records are skipped
+    /// without being decoded
+    fn decodes(&self, ctx: &DecodeContext, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool;
+
+    /// Change of the record, or why it isn't reported
+    fn decode(
+        &self,
+        ctx: &mut DecodeContext,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> Result<DecodedResult, SkipReason>;
+
+    /// Name of the record, as `rmgr/record type`
+    fn describe(&self, record: &PgBox<pg_sys::DecodedXLogRecord>) -> String {
+        describe_record(record)
+    }
+
+    /// Count a record that wasn't reported in the totals of the scan
+    fn stats(&self, summary: &mut ScanSummary, reason: SkipReason) {
+        summary.record_skipped(reason);
+    }
+}
+
+/// Name of a record, as `rmgr/record type`
+pub fn describe_record(record: &PgBox<pg_sys::DecodedXLogRecord>) -> String {
+    format!(
+        "{}/{}",
+        rmgr_display_name(record.header.xl_rmid),
+        record_type_name(record.header.xl_rmid, record.header.xl_info)
+    )
+}
+
+/// Decoders of the supported resource managers, keyed by rmid
+static RMGR_DECODERS: [(u32, &dyn RmgrDecoder); 3] = [
+    (RM_HEAP_ID, &HeapDecoder),
+    (RM_HEAP2_ID, &Heap2Decoder),
+    (RM_XLOG_ID, &NewPageDecoder),
+];
+
+/// Decoder of the resource manager, None when its records can't be decoded
+pub fn rmgr_decoder(rmid: u32) -> Option<&'static dyn RmgrDecoder> {
+    RMGR_DECODERS
+        .iter()
+        .find(|(id, _)| *id == rmid)
+        .map(|(_, decoder)| *decoder)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::{
+        pg_sys::RmgrIds::{RM_BTREE_ID, RM_HEAP2_ID, RM_HEAP_ID, RM_XLOG_ID},
+        prelude::*,
+    };
+
+    use crate::rmgr::rmgr_decoder;
+
+    #[test]
+    fn test_rmgr_decoder() {
+        assert!(rmgr_decoder(RM_HEAP_ID).is_some());
+        assert!(rmgr_decoder(RM_HEAP2_ID).is_some());
+        assert!(rmgr_decoder(RM_XLOG_ID).is_some());
+        assert!(rmgr_decoder(RM_BTREE_ID).is_none());
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use pgrx::{prelude::*, GucSetting};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    guc::{
+        verbose, Verbosity, S3_ACCESS_KEY_ID, S3_CACHE_DIR, S3_ENDPOINT, S3_REGION,
+        S3_SECRET_ACCESS_KEY,
+    },
+    wal::is_xlog_file_name,
+};
+
+const S3_SCHEME: &str = "s3://";
+const DEFAULT_CACHE_DIR: &str = "pg_waldecoder_cache";
+const DEFAULT_REGION: &str = "us-east-1";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum S3Error {
+    #[error("Invalid S3 URL '{0}'")]
+    InvalidUrl(String),
+    #[error("Missing S3 credentials, set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or pg_waldecoder.s3_access_key_id and pg_waldecoder.s3_secret_access_key")]
+    MissingCredentials,
+    #[error("Request to {0} failed: {1}")]
+    RequestError(String, String),
+    #[error("Object {0} doesn't exist")]
+    NotFound(String),
+    #[error("Could not write {0} in cache: {1}")]
+    CacheError(String, String),
+}
+
+/// WAL archive stored in an S3-compatible bucket.
+///
+/// Objects are downloaded to a local cache directory which is then used as
+/// the WAL directory.
+#[derive(Clone)]
+pub struct S3Source {
+    endpoint: String,
+    host: String,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    cache_dir: PathBuf,
+}
+
+/// Returns true if the WAL dir is an S3 URL
+pub fn is_s3_url(wal_dir: &str) -> bool {
+    wal_dir.starts_with(S3_SCHEME)
+}
+
+/// Split an s3://bucket/prefix URL into its bucket and prefix. They're
+/// joined to the cache directory, empty, `.` and `..` parts are rejected so
+/// the cache stays under it.
+pub fn parse_s3_url(url: &str) -> Result<(String, String), S3Error> {
+    let Some(path) = url.strip_prefix(S3_SCHEME) else {
+        return Err(S3Error::InvalidUrl(url.to_string()));
+    };
+    let path = path.trim_end_matches('/');
+    if path
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        return Err(S3Error::InvalidUrl(url.to_string()));
+    }
+    let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+    let prefix = if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    };
+    Ok((bucket.to_string(), prefix))
+}
+
+/// Read a setting from its GUC, falling back to environment variables
+fn setting(guc: &GucSetting<Option<CString>>, env_vars: &[&str]) -> Option<String> {
+    if let Some(value) = guc.get() {
+        return Some(value.to_string_lossy().to_string());
+    }
+    env_vars.iter().find_map(|v| env::var(v).ok())
+}
+
+/// URI-encode a value as required by `SigV4`
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(char::from(b));
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Derive the `SigV4` signing key
+pub fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{secret_access_key}").as_bytes(), date);
+    let k_region = hmac_sha256(&k_date, region);
+    let k_service = hmac_sha256(&k_region, service);
+    hmac_sha256(&k_service, "aws4_request")
+}
+
+/// Format a unix timestamp as an ISO 8601 basic date time (20130524T000000Z)
+pub fn amz_date(unix_secs: u64) -> String {
+    let days = unix_secs / 86400;
+    let secs_of_day = unix_secs % 86400;
+    // Civil date from days since epoch
+    let z = days.cast_signed() + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+impl S3Source {
+    pub fn new(url: &str) -> Result<S3Source, S3Error> {
+        let (bucket, prefix) = parse_s3_url(url)?;
+        let region = setting(&S3_REGION, &["AWS_REGION", "AWS_DEFAULT_REGION"])
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = setting(&S3_ENDPOINT, &["AWS_ENDPOINT_URL_S3", "AWS_ENDPOINT_URL"])
+            .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, h)| h)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let (Some(access_key_id), Some(secret_access_key)) = (
+            setting(&S3_ACCESS_KEY_ID, &["AWS_ACCESS_KEY_ID"]),
+            setting(&S3_SECRET_ACCESS_KEY, &["AWS_SECRET_ACCESS_KEY"]),
+        ) else {
+            return Err(S3Error::MissingCredentials);
+        };
+        let session_token = env::var("AWS_SESSION_TOKEN").ok();
+
+        let cache_root =
+            setting(&S3_CACHE_DIR, &[]).unwrap_or_else(|| DEFAULT_CACHE_DIR.to_string());
+        let cache_dir = Path::new(&cache_root).join(&bucket).join(&prefix);
+        if let Err(e) = fs::create_dir_all(&cache_dir) {
+            return Err(S3Error::CacheError(
+                cache_dir.display().to_string(),
+                e.to_string(),
+            ));
+        }
+
+        Ok(S3Source {
+            endpoint,
+            host,
+            region,
+            bucket,
+            prefix,
+            access_key_id,
+            secret_access_key,
+            session_token,
+            cache_dir,
+        })
+    }
+
+    /// Local directory holding the fetched objects
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Build a signed GET request for the object path and query
+    fn signed_get(&self, path: &str, query: &[(&str, &str)]) -> ureq::Request {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+
+        let canonical_uri = uri_encode(&format!("/{}/{path}", self.bucket), false);
+        let mut query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let canonical_query = query.join("&");
+
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers = headers
+            .iter()
+            .map(|(k, v)| format!("{k}:{v}\n"))
+            .collect::<String>();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "GET\n{canonical_uri}\n{canonical_query}\n{canonical_headers}\n{signed_headers}\n{UNSIGNED_PAYLOAD}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex_encode(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, "s3");
+        let signature = hex_encode(&hmac_sha256(&key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        );
+
+        let mut url = format!("{}{canonical_uri}", self.endpoint);
+        if !canonical_query.is_empty() {
+            url = format!("{url}?{canonical_query}");
+        }
+        let mut request = ureq::get(&url).set("Authorization", &authorization);
+        for (k, v) in headers.iter().filter(|(k, _)| *k != "host") {
+            request = request.set(k, v);
+        }
+        request
+    }
+
+    /// List the WAL segment and history file names found under the prefix
+    pub fn list_wal_files(&self) -> Result<Vec<String>, S3Error> {
+        let mut names = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = match self.signed_get("", &query).call() {
+                Ok(response) => response.into_string(),
+                Err(e) => return Err(S3Error::RequestError(self.bucket.clone(), e.to_string())),
+            };
+            let body = match body {
+                Ok(body) => body,
+                Err(e) => return Err(S3Error::RequestError(self.bucket.clone(), e.to_string())),
+            };
+
+            for key in xml_values(&body, "Key") {
+                let Some(name) = key.strip_prefix(&self.prefix) else {
+                    continue;
+                };
+                if is_xlog_file_name(name) || name.ends_with(".history") {
+                    names.push(name.to_string());
+                }
+            }
+            continuation_token = xml_values(&body, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Download an object in the cache if it's not already there
+    pub fn fetch(&self, name: &str) -> Result<PathBuf, S3Error> {
+        let path = self.cache_dir.join(name);
+        if !path.exists() {
+            verbose!(
+                Verbosity::Normal,
+                "Fetching s3://{}/{}{name}",
+                self.bucket,
+                self.prefix
+            );
+        }
+        self.download(name)
+    }
+
+    /// Same as `fetch` without logging, it can be used outside of the
+    /// backend's main thread
+    pub fn download(&self, name: &str) -> Result<PathBuf, S3Error> {
+        let path = self.cache_dir.join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+        let key = format!("{}{name}", self.prefix);
+        let response = match self.signed_get(&key, &[]).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Err(S3Error::NotFound(key)),
+            Err(e) => return Err(S3Error::RequestError(key, e.to_string())),
+        };
+
+        // Download to a temporary file so an interrupted fetch doesn't leave
+        // a truncated segment in the cache
+        let tmp_path = self.cache_dir.join(format!("{name}.tmp"));
+        let res = File::create(&tmp_path)
+            .and_then(|mut f| io::copy(&mut response.into_reader(), &mut f))
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(S3Error::CacheError(
+                path.display().to_string(),
+                e.to_string(),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Fetch the history files and the first segment so the cache directory
+    /// can be used for WAL dir detection
+    pub fn prefetch(&self) -> Result<(), S3Error> {
+        let names = self.list_wal_files()?;
+        for name in names.iter().filter(|n| n.ends_with(".history")) {
+            self.fetch(name)?;
+        }
+        if let Some(first_segment) = names.iter().find(|n| is_xlog_file_name(n)) {
+            self.fetch(first_segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extract the values of an XML element, enough for S3 list responses
+fn xml_values(body: &str, element: &str) -> Vec<String> {
+    let open = format!("<{element}>");
+    let close = format!("</{element}>");
+    let mut values = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(rest[..end].replace("&amp;", "&"));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use crate::s3::{amz_date, hex_encode, parse_s3_url, signing_key, uri_encode, xml_values};
+
+    #[test]
+    fn test_parse_s3_url() {
+        assert_eq!(
+            parse_s3_url("s3://archive/cluster1/wal/").unwrap(),
+            ("archive".to_string(), "cluster1/wal/".to_string())
+        );
+        assert_eq!(
+            parse_s3_url("s3://archive").unwrap(),
+            ("archive".to_string(), String::new())
+        );
+        assert!(parse_s3_url("s3:///wal").is_err());
+        assert!(parse_s3_url("/var/lib/wal").is_err());
+        // Parts leaving the cache directory
+        assert!(parse_s3_url("s3://../../../data").is_err());
+        assert!(parse_s3_url("s3://archive/wal/../../..").is_err());
+        assert!(parse_s3_url("s3://archive//etc").is_err());
+        assert!(parse_s3_url("s3://archive/./wal").is_err());
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS SigV4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex_encode(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_amz_date() {
+        assert_eq!(amz_date(0), "19700101T000000Z");
+        assert_eq!(amz_date(1369353600), "20130524T000000Z");
+        assert_eq!(amz_date(1709251199), "20240229T235959Z");
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("/bucket/wal dir/a+b", false),
+            "/bucket/wal%20dir/a%2Bb"
+        );
+        assert_eq!(uri_encode("wal/", true), "wal%2F");
+    }
+
+    #[test]
+    fn test_xml_values() {
+        let body = "<ListBucketResult><Contents><Key>wal/000000010000000000000001</Key></Contents>\
+                    <Contents><Key>wal/00000002.history</Key></Contents></ListBucketResult>";
+        assert_eq!(
+            xml_values(body, "Key"),
+            vec!["wal/000000010000000000000001", "wal/00000002.history"]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use pgrx::prelude::*;
+
+use crate::{
+    access::check_write_access,
+    apply::Direction,
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+    tuple_str::quote_identifier,
+};
+
+/// Change of the script, with where it was decoded from
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ScriptStatement {
+    lsn: PgLSN,
+    xid: u32,
+    op: String,
+    relation: Option<String>,
+    query: Option<String>,
+}
+
+impl ScriptStatement {
+    fn new(change: DecodedResult, direction: Direction) -> Self {
+        let relation =
+            change
+                .schema_name
+                .zip(change.relation_name)
+                .map(|(schema_name, relation_name)| {
+                    format!(
+                        "{}.{}",
+                        quote_identifier(&schema_name),
+                        quote_identifier(&relation_name)
+                    )
+                });
+        ScriptStatement {
+            lsn: change.lsn,
+            xid: change.xid.into_inner(),
+            op: change.op,
+            relation,
+            query: match direction {
+                Direction::Redo => change.redo_query,
+                Direction::Revert => change.revert_query,
+            },
+        }
+    }
+}
+
+/// Text kept on a comment line, its line breaks escaped
+fn comment_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Script applying the statements of each transaction in its own transaction
+/// block, with triggers and foreign keys disabled as on a replica. Redo
+/// replays the transactions in the order of their commit record from
+/// `commits` and their changes in LSN order, revert undoes them from the
+/// latest. Transactions without a commit are left out. Changes without a
+/// query are left as comments.
+fn sql_script(
+    start: PgLSN,
+    end: PgLSN,
+    direction: Direction,
+    transactions: BTreeMap<u32, Vec<ScriptStatement>>,
+    commits: &BTreeMap<u32, PgLSN>,
+) -> String {
+    let mut transactions: Vec<_> = transactions
+        .into_iter()
+        .filter(|(_, statements)| !statements.is_empty())
+        .filter_map(|(xid, statements)| Some((*commits.get(&xid)?, xid, statements)))
+        .collect();
+    transactions.sort_by_key(|(commit_lsn, _, _)| *commit_lsn);
+    if direction == Direction::Revert {
+        transactions.reverse();
+        for (_, _, statements) in &mut transactions {
+            statements.reverse();
+        }
+    }
+
+    let direction_name = match direction {
+        Direction::Redo => "redo",
+        Direction::Revert => "revert",
+    };
+    let mut script = String::new();
+    writeln!(
+        script,
+        "-- pg_waldecoder {direction_name} script of the changes from {start} to {end}"
+    )
+    .unwrap();
+    script.push_str("SET session_replication_role = replica;\n");
+    for (_, toplevel_xid, statements) in transactions {
+        writeln!(script, "\n-- Transaction {toplevel_xid}").unwrap();
+        script.push_str("BEGIN;\n");
+        for statement in statements {
+            let relation = statement
+                .relation
+                .as_deref()
+                .map_or("unknown relation".to_string(), comment_text);
+            write!(
+                script,
+                "-- {} xid {} {} on {relation}",
+                statement.lsn,
+                statement.xid,
+                comment_text(&statement.op)
+            )
+            .unwrap();
+            match &statement.query {
+                Some(query) => writeln!(script, "\n{query}").unwrap(),
+                None => writeln!(script, " has no {direction_name} query").unwrap(),
+            }
+        }
+        script.push_str("COMMIT;\n");
+    }
+    script.push_str("\nRESET session_replication_role;\n");
+    script
+}
+
+/// A psql-ready script redoing or reverting the changes decoded between
+/// `start_lsn` and `end_lsn`, a transaction block per transaction with
+/// comments telling where each statement comes from. Changes rolled back,
+/// with their transaction or to a savepoint, are left out, as are the
+/// transactions not committed by `end_lsn`. With `path`, the script is also
+/// written to this file of the server.
+#[pg_extern]
+fn pg_waldecoder_script(
+    start_lsn: &str,
+    end_lsn: &str,
+    direction: default!(&str, "'redo'"),
+    path: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> String {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let direction = match Direction::try_from(direction) {
+        Ok(direction) => direction,
+        Err(e) => error!("Error: {e}"),
+    };
+    if path.is_some() {
+        check_write_access();
+    }
+    let options = DecoderOptions {
+        end_lsn: Some(end_lsn),
+        timeline,
+        wal_dir,
+        include_transactions: true,
+        ..Default::default()
+    };
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    let changes: Vec<_> = wal_decoder.by_ref().collect();
+    let mut transactions: BTreeMap<u32, Vec<ScriptStatement>> = BTreeMap::new();
+    let mut commits = BTreeMap::new();
+    for change in changes {
+        if change.op == "COMMIT" {
+            commits.insert(change.xid.into_inner(), change.lsn);
+            continue;
+        }
+        if wal_decoder.is_aborted(change.xid)
+            || !matches!(
+                change.op.trim_end_matches("+INIT"),
+                "INSERT" | "UPDATE" | "HOT_UPDATE" | "DELETE"
+            )
+        {
+            continue;
+        }
+        transactions
+            .entry(change.toplevel_xid.into_inner())
+            .or_default()
+            .push(ScriptStatement::new(change, direction));
+    }
+    let script = sql_script(
+        startptr,
+        wal_decoder.end_lsn(),
+        direction,
+        transactions,
+        &commits,
+    );
+    if let Some(path) = path {
+        if let Err(e) = std::fs::write(path, &script) {
+            error!("Could not write to file \"{path}\": {e}");
+        }
+    }
+    script
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use pgrx::prelude::*;
+
+    use crate::{
+        apply::Direction,
+        pg_lsn::PgLSN,
+        script::{sql_script, ScriptStatement},
+    };
+
+    fn statement(lsn: i32, xid: u32, query: Option<&str>) -> ScriptStatement {
+        ScriptStatement {
+            lsn: PgLSN::from(lsn),
+            xid,
+            op: "INSERT".to_string(),
+            relation: Some("public.t".to_string()),
+            query: query.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_sql_script() {
+        let transactions = || {
+            BTreeMap::from([
+                (
+                    750,
+                    vec![
+                        statement(0x10, 750, Some("INSERT INTO t VALUES ('1');")),
+                        statement(0x40, 751, None),
+                    ],
+                ),
+                (
+                    752,
+                    vec![statement(0x20, 752, Some("INSERT INTO t VALUES ('2');"))],
+                ),
+                (
+                    753,
+                    vec![statement(0x30, 753, Some("INSERT INTO t VALUES ('3');"))],
+                ),
+            ])
+        };
+        // 750 commits first despite its later change, 753 is still in progress
+        let commits = BTreeMap::from([(750, PgLSN::from(0x44)), (752, PgLSN::from(0x48))]);
+        assert_eq!(
+            sql_script(
+                PgLSN::from(0x10),
+                PgLSN::from(0x50),
+                Direction::Redo,
+                transactions(),
+                &commits
+            ),
+            "-- pg_waldecoder redo script of the changes from 0/00000010 to 0/00000050
+SET session_replication_role = replica;
+
+-- Transaction 750
+BEGIN;
+-- 0/00000010 xid 750 INSERT on public.t
+INSERT INTO t VALUES ('1');
+-- 0/00000040 xid 751 INSERT on public.t has no redo query
+COMMIT;
+
+-- Transaction 752
+BEGIN;
+-- 0/00000020 xid 752 INSERT on public.t
+INSERT INTO t VALUES ('2');
+COMMIT;
+
+RESET session_replication_role;
+"
+        );
+        let revert = sql_script(
+            PgLSN::from(0x10),
+            PgLSN::from(0x50),
+            Direction::Revert,
+            transactions(),
+            &commits,
+        );
+        assert!(revert.find("Transaction 752").unwrap() < revert.find("Transaction 750").unwrap());
+        assert!(revert.find("0/00000040").unwrap() < revert.rfind("0/00000010").unwrap());
+        assert!(!revert.contains("Transaction 753"));
+    }
+
+    #[test]
+    fn test_sql_script_comment_line_breaks() {
+        let mut change = statement(0x10, 750, None);
+        change.relation = Some("public.\"t\nDROP TABLE t; --\"".to_string());
+        let script = sql_script(
+            PgLSN::from(0x10),
+            PgLSN::from(0x20),
+            Direction::Redo,
+            BTreeMap::from([(750, vec![change])]),
+            &BTreeMap::from([(750, PgLSN::from(0x18))]),
+        );
+        assert!(script.contains("INSERT on public.\"t\\nDROP TABLE t; --\" has no redo query\n"));
+        assert!(!script.lines().any(|line| line.starts_with("DROP")));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_script() {
+        unsafe {
+            Spi::run("CREATE TABLE test_script (id int primary key, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_script VALUES (1, 'a')");
+            Spi::run("UPDATE test_script SET data = 'b' WHERE id = 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let script = Spi::get_one::<String>(&format!(
+            "SELECT pg_waldecoder_script('{startptr}', '{endptr}', 'revert', timeline => 1)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(script.contains("SET session_replication_role = replica;"));
+        // The test's transaction isn't committed yet
+        assert_eq!(script.matches("BEGIN;").count(), 0);
+        assert!(!script.contains("test_script"));
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use pg_waldecoder_core::{
+    lsn::segno_to_lsn,
+    page::LongPageHeader,
+    reader::{segment_chain, segment_records, segment_regions, RegionKind},
+};
+use pgrx::{pg_sys, prelude::*};
+
+use crate::{
+    access::check_decoder_access,
+    pg_lsn::{filename_to_startptr, PgLSN},
+    wal::{detect_wal_dir, format_rejections, is_xlog_file_name, validate_segment_size, WalSource},
+};
+
+/// A segment file of a WAL directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub timeline: u64,
+    pub segno: u64,
+}
+
+/// Segment files of the directory with the expected segment size, in
+/// timeline and segment order
+pub fn wal_segments(dir: &Path, segsz: u32) -> Vec<Segment> {
+    source_segments(&WalSource::from_dirs(vec![dir.to_path_buf()]), segsz)
+}
+
+/// Segment files of the WAL source with the expected segment size, in
+/// timeline and segment order
+pub fn source_segments(source: &WalSource, segsz: u32) -> Vec<Segment> {
+    let mut segments: Vec<_> = source
+        .files()
+        .into_iter()
+        .filter_map(|(file_name, path)| {
+            if !is_xlog_file_name(&file_name) || validate_segment_size(&path, segsz).is_err() {
+                return None;
+            }
+            let (timeline, segno) = filename_to_startptr(&file_name, u64::from(segsz)).ok()?;
+            Some(Segment {
+                path,
+                file_name,
+                timeline,
+                segno,
+            })
+        })
+        .collect();
+    segments.sort_by(|a, b| {
+        (a.timeline, a.segno, &a.file_name).cmp(&(b.timeline, b.segno, &b.file_name))
+    });
+    segments
+}
+
+/// First and last segments of the contiguous run starting at the first
+/// segment, and the next segment after the gap ending it. `segnos` must be
+/// sorted.
+fn contiguous_segments(segnos: &[u64]) -> Option<(u64, u64, Option<u64>)> {
+    let first = *segnos.first()?;
+    let mut last = first;
+    for &segno in segnos {
+        if segno > last + 1 {
+            return Some((first, last, Some(segno)));
+        }
+        last = last.max(segno);
+    }
+    Some((first, last, None))
+}
+
+/// LSN of `lsns` closest to `target`
+fn nearest_lsn(lsns: &[u64], target: u64) -> Option<u64> {
+    lsns.iter().copied().min_by_key(|lsn| lsn.abs_diff(target))
+}
+
+/// Detail and hint of the error raised when no valid record follows `start`:
+/// the segments holding it, the closest valid record found in the segments
+/// of the decoded timelines and what may not match
+pub fn no_record_diagnostics(
+    source: &WalSource,
+    segsz: u32,
+    timelines: &[u64],
+    start: PgLSN,
+) -> (String, String) {
+    let segments = source_segments(source, segsz);
+    let start_segno = u64::from(start) / u64::from(segsz);
+    let (decoded, others): (Vec<_>, Vec<_>) = segments
+        .iter()
+        .partition(|segment| timelines.contains(&segment.timeline));
+    let covering: Vec<_> = decoded
+        .iter()
+        .filter(|segment| segment.segno == start_segno)
+        .map(|segment| segment.file_name.as_str())
+        .collect();
+    let inspected = if covering.is_empty() {
+        format!("No segment of timelines {timelines:?} holds {start}.")
+    } else {
+        format!("Inspected segments: {}.", covering.join(", "))
+    };
+    // The records of the segments around the start are the closest
+    let before = decoded
+        .iter()
+        .filter(|segment| segment.segno <= start_segno)
+        .max_by_key(|segment| segment.segno);
+    let after = decoded
+        .iter()
+        .filter(|segment| segment.segno >= start_segno)
+        .min_by_key(|segment| segment.segno);
+    let lsns: Vec<_> = before
+        .into_iter()
+        .chain(after)
+        .flat_map(|segment| {
+            let records = fs::read(&segment.path)
+                .map(|wal| segment_records(&wal))
+                .unwrap_or_default();
+            records.first_lsn.into_iter().chain(records.last_lsn)
+        })
+        .collect();
+    let detail = match nearest_lsn(&lsns, u64::from(start)) {
+        Some(lsn) => format!(
+            "{inspected} The nearest valid record is at {}.",
+            PgLSN::from(lsn)
+        ),
+        None => format!("{inspected} No valid record was found around it."),
+    };
+
+    let other = others
+        .iter()
+        .find(|segment| segment.segno == start_segno)
+        .filter(|_| covering.is_empty());
+    let hint = if let Some(other) = other {
+        format!(
+            "Segment {} of timeline {} holds {start}, set timeline to {}.",
+            other.file_name, other.timeline, other.timeline
+        )
+    } else if let (Some(first), Some(last)) = (decoded.first(), decoded.last()) {
+        if covering.is_empty() {
+            format!(
+                "The WAL dirs hold the WAL of timelines {timelines:?} from {} to {}, check start_lsn and wal_dir.",
+                PgLSN::from(segno_to_lsn(first.segno, segsz)),
+                PgLSN::from(segno_to_lsn(last.segno + 1, segsz))
+            )
+        } else {
+            "The segment may be truncated or corrupted, pg_waldecoder_verify checks the records of the WAL dirs.".to_string()
+        }
+    } else if others.is_empty() {
+        "Set wal_dir to a directory containing the WAL segments of start_lsn.".to_string()
+    } else {
+        let mut other_timelines: Vec<_> = others.iter().map(|segment| segment.timeline).collect();
+        other_timelines.dedup();
+        format!(
+            "The WAL dirs only hold timelines {other_timelines:?}, set timeline to one of them."
+        )
+    };
+    (detail, hint)
+}
+
+/// Directory of `wal_dir` holding the segments, raises an error if there are
+/// none
+pub fn segments_dir(wal_dir: Option<&str>) -> (PathBuf, u32) {
+    check_decoder_access(wal_dir);
+    match detect_wal_dir(wal_dir) {
+        Ok(detected) => detected,
+        Err(rejections) => error!(
+            "No valid WAL files found in wal dir: {}",
+            format_rejections(&rejections)
+        ),
+    }
+}
+
+/// Read a segment file, raises an error if it can't be read
+fn read_segment(segment: &Segment) -> Vec<u8> {
+    match fs::read(&segment.path) {
+        Ok(wal) => wal,
+        Err(e) => error!("Could not read WAL file {}: {e}", segment.path.display()),
+    }
+}
+
+/// WAL segments found in `wal_dir`, or the server's WAL without it, with the
+/// LSN range they cover and the first and last records starting in them.
+/// `system_identifier` is the database system that wrote the segment, from
+/// its first page. `valid` is false when a page or a record of the segment
+/// can't be read, `error` tells why.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_ls(
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(file_name, String),
+        name!(timeline, i64),
+        name!(system_identifier, Option<i64>),
+        name!(size, i64),
+        name!(start_lsn, PgLSN),
+        name!(end_lsn, PgLSN),
+        name!(first_record_lsn, Option<PgLSN>),
+        name!(last_record_lsn, Option<PgLSN>),
+        name!(valid, bool),
+        name!(error, Option<String>),
+    ),
+> {
+    let (dir, segsz) = segments_dir(wal_dir);
+    let segments = wal_segments(&dir, segsz);
+    TableIterator::new(segments.into_iter().map(move |segment| {
+        let wal = read_segment(&segment);
+        let (size, records) = (wal.len(), segment_records(&wal));
+        let start_lsn = segno_to_lsn(segment.segno, segsz);
+        let sysid = LongPageHeader::parse(&wal).ok().map(|header| header.sysid);
+        (
+            segment.file_name,
+            i64::try_from(segment.timeline).unwrap(),
+            sysid.map(u64::cast_signed),
+            i64::try_from(size).unwrap(),
+            PgLSN::from(start_lsn),
+            PgLSN::from(start_lsn + u64::from(segsz)),
+            records.first_lsn.map(PgLSN::from),
+            records.last_lsn.map(PgLSN::from),
+            records.error.is_none(),
+            records.error.map(|e| e.to_string()),
+        )
+    }))
+}
+
+/// Regions of the WAL segments found in `wal_dir`, or the server's WAL without
+/// it: valid records, the padding after a switch, zeroed space, pages left
+/// from the previous use of a recycled segment or invalid pages. Without
+/// activity, the end of a segment is zeroed, a segment recycled before being
+/// archived holds pages of older LSNs, given by `recycled_lsn`.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_regions(
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(file_name, String),
+        name!(timeline, i64),
+        name!(start_lsn, PgLSN),
+        name!(end_lsn, PgLSN),
+        name!(kind, String),
+        name!(recycled_lsn, Option<PgLSN>),
+    ),
+> {
+    let (dir, segsz) = segments_dir(wal_dir);
+    let segments = wal_segments(&dir, segsz);
+    TableIterator::new(segments.into_iter().flat_map(move |segment| {
+        let wal = read_segment(&segment);
+        let regions = segment_regions(&wal, segno_to_lsn(segment.segno, segsz));
+        let timeline = i64::try_from(segment.timeline).unwrap();
+        regions.into_iter().map(move |region| {
+            let recycled_lsn = match region.kind {
+                RegionKind::Recycled(lsn) => Some(PgLSN::from(lsn)),
+                _ => None,
+            };
+            (
+                segment.file_name.clone(),
+                timeline,
+                PgLSN::from(region.start),
+                PgLSN::from(region.end),
+                region.kind.name().to_string(),
+                recycled_lsn,
+            )
+        })
+    }))
+}
+
+/// LSN range covered without interruption by the segments of each timeline
+/// found in `wal_dir`, from the start of the first segment to the end of the
+/// last one before a missing segment. The gap is the range of the missing
+/// segments, NULL when the segments are contiguous, a decode crossing it
+/// fails unless `skip_missing` is set.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_wal_range(
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(timeline, i64),
+        name!(start_lsn, PgLSN),
+        name!(end_lsn, PgLSN),
+        name!(gap_start_lsn, Option<PgLSN>),
+        name!(gap_end_lsn, Option<PgLSN>),
+    ),
+> {
+    let (dir, segsz) = segments_dir(wal_dir);
+    let segments = wal_segments(&dir, segsz);
+    let mut timelines: Vec<_> = segments.iter().map(|segment| segment.timeline).collect();
+    timelines.dedup();
+    let rows: Vec<_> = timelines
+        .into_iter()
+        .filter_map(|timeline| {
+            let segnos: Vec<_> = segments
+                .iter()
+                .filter(|segment| segment.timeline == timeline)
+                .map(|segment| segment.segno)
+                .collect();
+            let (first, last, after_gap) = contiguous_segments(&segnos)?;
+            let end_lsn = segno_to_lsn(last + 1, segsz);
+            Some((
+                i64::try_from(timeline).unwrap(),
+                PgLSN::from(segno_to_lsn(first, segsz)),
+                PgLSN::from(end_lsn),
+                after_gap.map(|_| PgLSN::from(end_lsn)),
+                after_gap.map(|segno| PgLSN::from(segno_to_lsn(segno, segsz))),
+            ))
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+/// Walk the chain of records of every segment found in `wal_dir`, or the
+/// server's WAL without it, checking the CRC of each record and that it
+/// points back to the previous one, across segment boundaries. Each row is a
+/// break of the chain: a modified, replaced or truncated segment, or missing
+/// segments. The chain restarts after a break, no rows means the WAL of each
+/// timeline is intact. Zeroes and pages left from the previous use of a
+/// recycled segment end the WAL of a segment, the server's segments past its
+/// insert location are preallocated and skipped.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_verify(
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(timeline, i64),
+        name!(file_name, String),
+        name!(lsn, PgLSN),
+        name!(error, String),
+    ),
+> {
+    let (dir, segsz) = segments_dir(wal_dir);
+    let mut segments = wal_segments(&dir, segsz);
+    // The .partial copy of an archived segment isn't checked
+    segments.dedup_by_key(|segment| (segment.timeline, segment.segno));
+    if wal_dir.is_none() {
+        let end_lsn = unsafe {
+            if pg_sys::RecoveryInProgress() {
+                pg_sys::GetXLogReplayRecPtr(std::ptr::null_mut())
+            } else {
+                pg_sys::GetXLogInsertRecPtr()
+            }
+        };
+        segments.retain(|segment| segno_to_lsn(segment.segno, segsz) <= end_lsn);
+    }
+    let mut breaks = Vec::new();
+    let mut prev_lsn = None;
+    let mut next_wal = None;
+    for (i, segment) in segments.iter().enumerate() {
+        let timeline = i64::try_from(segment.timeline).unwrap();
+        let after = segments
+            .get(i + 1)
+            .filter(|after| after.timeline == segment.timeline);
+        let next = after.filter(|after| after.segno == segment.segno + 1);
+        // The next segment holds the end of the record crossing the boundary
+        let mut wal = next_wal.take().unwrap_or_else(|| read_segment(segment));
+        let len = wal.len();
+        if let Some(next) = next {
+            wal.extend(read_segment(next));
+        }
+        let chain = segment_chain(&wal, segno_to_lsn(segment.segno, segsz), prev_lsn);
+        prev_lsn = chain.last_lsn.or(prev_lsn);
+        if let Some(e) = chain.error {
+            breaks.push((
+                timeline,
+                segment.file_name.clone(),
+                PgLSN::from(e.lsn()),
+                e.to_string(),
+            ));
+            prev_lsn = None;
+        }
+        if next.is_some() {
+            next_wal = Some(wal.split_off(len));
+            continue;
+        }
+        if let Some(after) = after {
+            breaks.push((
+                timeline,
+                segment.file_name.clone(),
+                PgLSN::from(segno_to_lsn(segment.segno + 1, segsz)),
+                format!("Segments are missing up to {}", after.file_name),
+            ));
+        }
+        // The first records of the next timeline follow the history
+        prev_lsn = None;
+    }
+    TableIterator::new(breaks)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::{fs, path::Path};
+
+    use pg_waldecoder_core::page::LongPageHeader;
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        segments::{contiguous_segments, nearest_lsn, no_record_diagnostics, wal_segments},
+        wal::WalSource,
+    };
+
+    const TEST_WAL_DIR: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/test/18_single_upgrade"
+    );
+
+    #[test]
+    fn test_wal_segments() {
+        let segments = wal_segments(Path::new(TEST_WAL_DIR), 1024 * 1024);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].file_name, "000000010000000000000018");
+        assert_eq!((segments[0].timeline, segments[0].segno), (1, 0x18));
+        assert!(wal_segments(Path::new(TEST_WAL_DIR), 16 * 1024 * 1024).is_empty());
+    }
+
+    #[test]
+    fn test_contiguous_segments() {
+        assert_eq!(contiguous_segments(&[]), None);
+        assert_eq!(contiguous_segments(&[3, 4, 5]), Some((3, 5, None)));
+        // A segment and its .partial
+        assert_eq!(contiguous_segments(&[3, 4, 4, 5]), Some((3, 5, None)));
+        assert_eq!(contiguous_segments(&[3, 4, 7, 8]), Some((3, 4, Some(7))));
+    }
+
+    #[test]
+    fn test_nearest_lsn() {
+        assert_eq!(nearest_lsn(&[], 0x40), None);
+        assert_eq!(nearest_lsn(&[0x28, 0x60, 0x90], 0x40), Some(0x28));
+        assert_eq!(nearest_lsn(&[0x28, 0x60, 0x90], 0x50), Some(0x60));
+    }
+
+    #[test]
+    fn test_no_record_diagnostics() {
+        let wal_dirs = WalSource::from_dirs(vec![Path::new(TEST_WAL_DIR).to_path_buf()]);
+        let segsz = 1024 * 1024;
+        let (detail, hint) =
+            no_record_diagnostics(&wal_dirs, segsz, &[2], PgLSN::from(0x1800100u64));
+        assert_eq!(
+            detail,
+            "No segment of timelines [2] holds 0/01800100. No valid record was found around it."
+        );
+        assert_eq!(
+            hint,
+            "Segment 000000010000000000000018 of timeline 1 holds 0/01800100, set timeline to 1."
+        );
+        let (detail, hint) =
+            no_record_diagnostics(&wal_dirs, segsz, &[1], PgLSN::from(0x2000000u64));
+        assert!(detail.starts_with(
+            "No segment of timelines [1] holds 0/02000000. The nearest valid record is at 0/018"
+        ));
+        assert_eq!(hint, "The WAL dirs hold the WAL of timelines [1] from 0/01800000 to 0/01900000, check start_lsn and wal_dir.");
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_wal_range() {
+        let range = Spi::get_two::<PgLSN, PgLSN>(&format!(
+            "SELECT start_lsn, end_lsn FROM pg_waldecoder_wal_range('{TEST_WAL_DIR}')
+             WHERE gap_start_lsn IS NULL"
+        ));
+        assert_eq!(
+            range,
+            Ok((
+                Some(PgLSN::from(0x1800000u64)),
+                Some(PgLSN::from(0x1900000u64))
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_verify() {
+        let breaks = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_verify('{TEST_WAL_DIR}')"
+        ));
+        assert_eq!(breaks, Ok(Some(0)));
+
+        // Modify the second record in a copy of the segment
+        let dir = std::env::temp_dir().join("pg_waldecoder_verify");
+        fs::create_dir_all(&dir).unwrap();
+        let mut segment =
+            fs::read(Path::new(TEST_WAL_DIR).join("000000010000000000000018")).unwrap();
+        segment[0xc68] ^= 0xff;
+        fs::write(dir.join("000000010000000000000018"), segment).unwrap();
+        let lsn = Spi::get_one::<PgLSN>(&format!(
+            "SELECT lsn FROM pg_waldecoder_verify('{}')",
+            dir.display()
+        ));
+        assert_eq!(lsn, Ok(Some(PgLSN::from(0x1800c50u64))));
+
+        // A recycled segment following the last one isn't a break
+        let dir = std::env::temp_dir().join("pg_waldecoder_verify_recycled");
+        fs::create_dir_all(&dir).unwrap();
+        let segment = Path::new(TEST_WAL_DIR).join("000000010000000000000018");
+        fs::copy(&segment, dir.join("000000010000000000000018")).unwrap();
+        fs::copy(&segment, dir.join("000000010000000000000019")).unwrap();
+        let breaks = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_verify('{}')",
+            dir.display()
+        ));
+        assert_eq!(breaks, Ok(Some(0)));
+
+        // Nor are the server's recycled and preallocated segments
+        let breaks = Spi::get_one::<i64>("SELECT count(*) FROM pg_waldecoder_verify()");
+        assert_eq!(breaks, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_regions() {
+        let (kind, start_lsn) = Spi::get_two::<String, PgLSN>(&format!(
+            "SELECT kind, start_lsn FROM pg_waldecoder_regions('{TEST_WAL_DIR}')
+             ORDER BY start_lsn LIMIT 1"
+        ))
+        .unwrap();
+        assert_eq!(kind.as_deref(), Some("records"));
+        assert_eq!(start_lsn, Some(PgLSN::from(0x1800000u64)));
+
+        // The segment recycled as the next one, before it was written
+        let dir = std::env::temp_dir().join("pg_waldecoder_regions");
+        fs::create_dir_all(&dir).unwrap();
+        let segment = Path::new(TEST_WAL_DIR).join("000000010000000000000018");
+        fs::copy(&segment, dir.join("000000010000000000000018")).unwrap();
+        fs::copy(&segment, dir.join("000000010000000000000019")).unwrap();
+        let recycled_lsn = Spi::get_one::<PgLSN>(&format!(
+            "SELECT recycled_lsn FROM pg_waldecoder_regions('{}') WHERE kind = 'recycled'
+             ORDER BY start_lsn LIMIT 1",
+            dir.display()
+        ));
+        assert_eq!(recycled_lsn, Ok(Some(PgLSN::from(0x1800000u64))));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_ls() {
+        let (start_lsn, first_record_lsn) = Spi::get_two::<PgLSN, PgLSN>(&format!(
+            "SELECT start_lsn, first_record_lsn FROM pg_waldecoder_ls('{TEST_WAL_DIR}') WHERE valid"
+        ))
+        .unwrap();
+        assert_eq!(start_lsn, Some(PgLSN::from(0x1800000u64)));
+        assert_eq!(first_record_lsn, Some(PgLSN::from(0x1800028u64)));
+
+        let segment = fs::read(Path::new(TEST_WAL_DIR).join("000000010000000000000018")).unwrap();
+        let sysid = LongPageHeader::parse(&segment).unwrap().sysid;
+        let system_identifier = Spi::get_one::<i64>(&format!(
+            "SELECT system_identifier FROM pg_waldecoder_ls('{TEST_WAL_DIR}')"
+        ));
+        assert_eq!(system_identifier, Ok(Some(sysid.cast_signed())));
+    }
+}
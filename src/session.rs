@@ -0,0 +1,281 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    ffi::c_void,
+};
+
+use pgrx::{pg_sys, prelude::*, PgMemoryContexts};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    pg_lsn::PgLSN,
+};
+
+/// Decoder kept open in the session between fetches. Its reader, page cache
+/// and per record context live in a context of its own, deleted on close.
+struct OpenDecoder {
+    wal_decoder: Option<WalDecoder>,
+    context: PgMemoryContexts,
+    /// Set during a fetch, a fetch interrupted by an error leaves the reader
+    /// in the middle of a record and the decoder is released
+    fetching: bool,
+}
+
+impl Drop for OpenDecoder {
+    fn drop(&mut self) {
+        // The decoder frees its reader before its memory goes away
+        drop(self.wal_decoder.take());
+        unsafe { pg_sys::MemoryContextDelete(self.context.value()) };
+    }
+}
+
+thread_local! {
+    /// Decoders opened by `pg_waldecoder_open` in the backend, by handle
+    static OPEN_DECODERS: RefCell<BTreeMap<i32, OpenDecoder>> = const { RefCell::new(BTreeMap::new()) };
+    /// Whether the abort callbacks are registered in the backend
+    static ABORT_CALLBACKS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Release the decoders of the fetches interrupted by an error, their
+/// handles stay open until closed but can't be fetched from anymore. The
+/// decoders are still borrowed when a subtransaction aborts during a fetch,
+/// the error was caught by the fetch itself.
+fn release_failed_fetches() {
+    OPEN_DECODERS.with(|open_decoders| {
+        let Ok(mut open_decoders) = open_decoders.try_borrow_mut() else {
+            return;
+        };
+        for open_decoder in open_decoders.values_mut().filter(|d| d.fetching) {
+            open_decoder.fetching = false;
+            drop(open_decoder.wal_decoder.take());
+        }
+    });
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_xact_callback(
+    event: pg_sys::XactEvent::Type,
+    _arg: *mut c_void,
+) {
+    if event == pg_sys::XactEvent::XACT_EVENT_ABORT
+        || event == pg_sys::XactEvent::XACT_EVENT_PARALLEL_ABORT
+    {
+        release_failed_fetches();
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn pg_waldecoder_subxact_callback(
+    event: pg_sys::SubXactEvent::Type,
+    _subxid: pg_sys::SubTransactionId,
+    _parent_subxid: pg_sys::SubTransactionId,
+    _arg: *mut c_void,
+) {
+    if event == pg_sys::SubXactEvent::SUBXACT_EVENT_ABORT_SUB {
+        release_failed_fetches();
+    }
+}
+
+/// Register the abort callbacks once per backend
+fn register_abort_callbacks() {
+    if ABORT_CALLBACKS.replace(true) {
+        return;
+    }
+    unsafe {
+        pg_sys::RegisterXactCallback(Some(pg_waldecoder_xact_callback), std::ptr::null_mut());
+        pg_sys::RegisterSubXactCallback(Some(pg_waldecoder_subxact_callback), std::ptr::null_mut());
+    }
+}
+
+/// Open a decoder on the WAL from `start_lsn`, kept in the session until
+/// `pg_waldecoder_close`. Its changes are read in pages by
+/// `pg_waldecoder_fetch` without restarting the scan, the cached pages
+/// carrying over from one fetch to the next.
+#[pg_extern]
+fn pg_waldecoder_open(
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+    read_current_pages: default!(bool, false),
+    include_other_databases: default!(bool, false),
+    relations: default!(Option<Vec<String>>, "NULL"),
+) -> i32 {
+    verbose!(Verbosity::Normal, "Called with: {start_lsn:?}, {end_lsn:?}, {timeline:?}, {wal_dir:?}, {read_current_pages:?}, {include_other_databases:?}, {relations:?}");
+
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let relations: Vec<&str> = relations.iter().flatten().map(String::as_str).collect();
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        read_current_pages,
+        include_other_databases,
+        relations: &relations,
+        ..Default::default()
+    };
+    let context = unsafe {
+        pg_sys::AllocSetContextCreateInternal(
+            pg_sys::TopMemoryContext,
+            c"Open decoder".as_ptr(),
+            pg_sys::ALLOCSET_DEFAULT_MINSIZE.try_into().unwrap(),
+            pg_sys::ALLOCSET_DEFAULT_INITSIZE.try_into().unwrap(),
+            pg_sys::ALLOCSET_DEFAULT_MAXSIZE.try_into().unwrap(),
+        )
+    };
+    let mut open_decoder = OpenDecoder {
+        wal_decoder: None,
+        context: PgMemoryContexts::For(context),
+        fetching: false,
+    };
+    let mut wal_decoder = unsafe {
+        open_decoder
+            .context
+            .switch_to(|_| WalDecoder::new(startptr, &options))
+    };
+    wal_decoder.keep_across_transactions();
+    open_decoder.wal_decoder = Some(wal_decoder);
+    register_abort_callbacks();
+    OPEN_DECODERS.with_borrow_mut(|open_decoders| {
+        let handle = open_decoders
+            .last_key_value()
+            .map_or(1, |(handle, _)| handle + 1);
+        open_decoders.insert(handle, open_decoder);
+        handle
+    })
+}
+
+/// Next `count` changes of an open decoder, no rows once its range is
+/// decoded. A fetch failing with an error releases the decoder, it then has to
+/// be closed.
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_fetch(
+    handle: i32,
+    count: i64,
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    let Ok(count) = usize::try_from(count) else {
+        error!("count must not be negative")
+    };
+    let changes: Vec<DecodedResult> = OPEN_DECODERS.with_borrow_mut(|open_decoders| {
+        let Some(open_decoder) = open_decoders.get_mut(&handle) else {
+            error!("No open decoder with handle {handle}")
+        };
+        let Some(wal_decoder) = open_decoder.wal_decoder.as_mut() else {
+            error!("Decoder with handle {handle} was released after a failed fetch, close it")
+        };
+        open_decoder.fetching = true;
+        // Allocations made while reading must outlive the statement
+        let changes = unsafe {
+            open_decoder
+                .context
+                .switch_to(|_| wal_decoder.by_ref().take(count).collect())
+        };
+        open_decoder.fetching = false;
+        changes
+    });
+    SetOfIterator::new(changes.into_iter().map(DecodedResult::into_change))
+}
+
+/// Close an open decoder, releasing its memory. Returns false when there's no
+/// decoder with this handle.
+#[pg_extern]
+fn pg_waldecoder_close(handle: i32) -> bool {
+    OPEN_DECODERS
+        .with_borrow_mut(|open_decoders| open_decoders.remove(&handle))
+        .is_some()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{pg_lsn::PgLSN, sink::try_in_subtransaction};
+
+    #[pg_test]
+    fn test_pg_waldecoder_open_fetch_close() {
+        unsafe {
+            Spi::run("CREATE TABLE test_open_fetch (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_open_fetch SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let handle = Spi::get_one::<i32>(&format!(
+            "SELECT pg_waldecoder_open('{startptr}', '{endptr}', 1)"
+        ))
+        .unwrap()
+        .unwrap();
+        let fetch = |count: i64| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(row_after, ',') FROM pg_waldecoder_fetch({handle}, {count})
+                 WHERE relid = 'test_open_fetch'::regclass"
+            ))
+            .unwrap()
+        };
+        assert_eq!(fetch(1).as_deref(), Some("(1)"));
+        assert_eq!(fetch(10).as_deref(), Some("(2),(3)"));
+        assert_eq!(fetch(10), None);
+        assert_eq!(
+            Spi::get_one::<bool>(&format!("SELECT pg_waldecoder_close({handle})")),
+            Ok(Some(true))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(&format!("SELECT pg_waldecoder_close({handle})")),
+            Ok(Some(false))
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_failed_fetch() {
+        unsafe {
+            Spi::run("CREATE TABLE test_failed_fetch (id int, email text);");
+            // Hashing without a key fails the fetch
+            Spi::run(
+                "INSERT INTO pg_waldecoder_masked_column VALUES ('test_failed_fetch', 'email', 'hash')",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_failed_fetch VALUES (1, 'a@example.com')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let handle = Spi::get_one::<i32>(&format!(
+            "SELECT pg_waldecoder_open('{startptr}', '{endptr}', 1)"
+        ))
+        .unwrap()
+        .unwrap();
+        let fetch = || {
+            try_in_subtransaction(|| {
+                Spi::run(&format!(
+                    "SELECT count(*) FROM pg_waldecoder_fetch({handle}, 10)"
+                ))
+                .unwrap();
+            })
+            .unwrap_err()
+        };
+        let error = fetch();
+        assert!(error.contains("mask_hash_key"), "unexpected error {error}");
+        // The decoder was released with the aborted subtransaction
+        let error = fetch();
+        assert!(
+            error.contains("was released after a failed fetch"),
+            "unexpected error {error}"
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(&format!("SELECT pg_waldecoder_close({handle})")),
+            Ok(Some(true))
+        );
+    }
+}
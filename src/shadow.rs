@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use pgrx::{datum::DatumWithOid, pg_sys, prelude::*};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    output::OutputSink,
+    pg_lsn::PgLSN,
+    slot::normalize_op,
+    tuple_str::quote_identifier,
+};
+
+/// Columns of a relation with their types, without constraints or defaults
+const COLUMNS_QUERY: &str = "SELECT string_agg(format('%I %s', attname, format_type(atttypid, atttypmod)), ', ' ORDER BY attnum)
+     FROM pg_attribute WHERE attrelid = $1 AND attnum > 0 AND NOT attisdropped";
+
+/// Part of a shadow table name, its dots and backslashes escaped so each
+/// relation gets its own name
+fn escape_name_part(part: &str) -> String {
+    part.replace('\\', "\\\\").replace('.', "\\.")
+}
+
+/// Shadow table of a relation, `<target_schema>."<schema>.<relation>"`, None
+/// when the name doesn't fit in an identifier
+fn shadow_table_name(
+    target_schema: &str,
+    schema_name: &str,
+    relation_name: &str,
+) -> Option<String> {
+    let name = format!(
+        "{}.{}",
+        escape_name_part(schema_name),
+        escape_name_part(relation_name)
+    );
+    (name.len() < pg_sys::NAMEDATALEN as usize).then(|| {
+        format!(
+            "{}.{}",
+            quote_identifier(target_schema),
+            quote_identifier(&name)
+        )
+    })
+}
+
+/// Insert of a change in a shadow table, the row is cast to the relation's
+/// row type to split it in columns
+fn shadow_insert_query(shadow_table: &str, relation: &str) -> String {
+    format!("INSERT INTO {shadow_table} SELECT $1, $2, $3, ($4::{relation}).*")
+}
+
+/// Shadow table and insert query of a relation
+struct ShadowTable {
+    relid: pg_sys::Oid,
+    name: String,
+    insert_query: String,
+}
+
+/// Insert each change in the shadow table of its relation, created on the
+/// first change
+struct ShadowSink<'a> {
+    target_schema: &'a str,
+    /// Shadow tables by relid, None when the relation doesn't exist anymore
+    tables: HashMap<pg_sys::Oid, Option<ShadowTable>>,
+    written: usize,
+}
+
+impl ShadowSink<'_> {
+    fn shadow_table(&mut self, change: &DecodedResult) -> Option<&ShadowTable> {
+        let relid = change.relid?;
+        let schema_name = change.schema_name.as_deref()?;
+        let relation_name = change.relation_name.as_deref()?;
+        let target_schema = self.target_schema;
+        self.tables
+            .entry(relid)
+            .or_insert_with(|| {
+                let args: [DatumWithOid; 1] = [relid.into()];
+                let Ok(Some(columns)) = Spi::get_one_with_args::<String>(COLUMNS_QUERY, &args)
+                else {
+                    warning!("{schema_name}.{relation_name} doesn't exist anymore, its changes aren't written in a shadow table");
+                    return None;
+                };
+                let Some(name) = shadow_table_name(target_schema, schema_name, relation_name)
+                else {
+                    warning!("The shadow table name of {schema_name}.{relation_name} is too long, its changes aren't written");
+                    return None;
+                };
+                Spi::run(&format!(
+                    "CREATE TABLE IF NOT EXISTS {name} (waldecoder_lsn pg_lsn, waldecoder_xid xid8, waldecoder_op text, {columns})"
+                ))
+                .unwrap();
+                let relation = format!(
+                    "{}.{}",
+                    quote_identifier(schema_name),
+                    quote_identifier(relation_name)
+                );
+                let insert_query = shadow_insert_query(&name, &relation);
+                let args: [DatumWithOid; 1] = [name.as_str().into()];
+                let relid = Spi::get_one_with_args::<pg_sys::Oid>("SELECT $1::regclass::oid", &args)
+                    .unwrap()
+                    .unwrap();
+                Some(ShadowTable {
+                    relid,
+                    name,
+                    insert_query,
+                })
+            })
+            .as_ref()
+    }
+}
+
+impl OutputSink for ShadowSink<'_> {
+    fn write(&mut self, change: DecodedResult) {
+        // The changes of the shadow tables themselves aren't shadowed
+        if self
+            .tables
+            .values()
+            .flatten()
+            .any(|table| Some(table.relid) == change.relid)
+        {
+            return;
+        }
+        let op = normalize_op(&change.op).to_string();
+        let row = match op.as_str() {
+            "INSERT" | "UPDATE" => change.row_after.clone(),
+            "DELETE" => change.row_before.clone(),
+            _ => None,
+        };
+        // The row doesn't fit the current columns of the relation
+        let Some(row) = row.filter(|_| change.schema_mismatch != Some(true)) else {
+            return;
+        };
+        let Some(shadow_table) = self.shadow_table(&change) else {
+            return;
+        };
+        let args: [DatumWithOid; 4] = [
+            change.lsn.into(),
+            change.full_xid.into(),
+            op.into(),
+            row.into(),
+        ];
+        verbose!(
+            Verbosity::Debug,
+            "Writing change at {} in {}",
+            change.lsn,
+            shadow_table.name
+        );
+        Spi::run_with_args(&shadow_table.insert_query, &args).unwrap();
+        self.written += 1;
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+}
+
+/// Decode the WAL from `start_lsn` and write each row change in the shadow
+/// table of its relation, `<target_schema>."<schema>.<relation>"` with the
+/// dots and backslashes of the names escaped by a backslash, created if
+/// needed with the relation's columns after `waldecoder_lsn`,
+/// `waldecoder_xid` and `waldecoder_op`. Inserts and updates, HOT or not,
+/// store the new row, deletes the old one. Without `end_lsn`, the WAL is
+/// decoded up to what was flushed when the call started. Returns the number
+/// of rows written.
+#[pg_extern]
+fn pg_waldecoder_shadow(
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+    target_schema: default!(&str, "'waldecoder'"),
+) -> i64 {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    Spi::run(&format!(
+        "CREATE SCHEMA IF NOT EXISTS {}",
+        quote_identifier(target_schema)
+    ))
+    .unwrap();
+
+    // Changes are written as they're decoded, the WAL of the shadow tables
+    // is left out by ending at the current flush
+    let flushptr = PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) }).to_string();
+    let options = DecoderOptions {
+        end_lsn: end_lsn.or(Some(&flushptr)),
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let mut sink = ShadowSink {
+        target_schema,
+        tables: HashMap::new(),
+        written: 0,
+    };
+    for change in WalDecoder::new(startptr, &options) {
+        sink.write(change);
+    }
+    sink.finish();
+    let written = sink.written();
+    verbose!(
+        Verbosity::Normal,
+        "Wrote {written} changes in {} shadow tables",
+        sink.tables.values().flatten().count()
+    );
+    i64::try_from(written).unwrap_or(i64::MAX)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        shadow::{shadow_insert_query, shadow_table_name},
+    };
+
+    #[test]
+    fn test_shadow_table_name() {
+        let name = shadow_table_name("waldecoder", "public", "Orders").unwrap();
+        assert_eq!(name, "waldecoder.\"public.Orders\"");
+        assert_eq!(
+            shadow_insert_query(&name, "public.\"Orders\""),
+            "INSERT INTO waldecoder.\"public.Orders\" SELECT $1, $2, $3, ($4::public.\"Orders\").*"
+        );
+        // Each relation has its own shadow table
+        assert_ne!(
+            shadow_table_name("waldecoder", "a_b", "c"),
+            shadow_table_name("waldecoder", "a", "b_c")
+        );
+        assert_eq!(
+            shadow_table_name("waldecoder", "a.b", "c").unwrap(),
+            "waldecoder.\"a\\.b.c\""
+        );
+        assert_ne!(
+            shadow_table_name("waldecoder", "a.b", "c"),
+            shadow_table_name("waldecoder", "a", "b.c")
+        );
+        assert_eq!(
+            shadow_table_name("waldecoder", "public", &"t".repeat(60)),
+            None
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_shadow() {
+        unsafe {
+            Spi::run("CREATE TABLE public.test_shadow (id int primary key, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO public.test_shadow VALUES (1, 'a'), (2, 'b')");
+            Spi::run("UPDATE public.test_shadow SET data = 'c' WHERE id = 1");
+            Spi::run("DELETE FROM public.test_shadow WHERE id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let written = Spi::get_one::<i64>(&format!(
+            "SELECT pg_waldecoder_shadow('{startptr}', '{endptr}', 1)"
+        ))
+        .unwrap();
+        assert_eq!(written, Some(4));
+        let history = Spi::get_one::<String>(
+            "SELECT string_agg(waldecoder_op || ' ' || id || ' ' || data, ', ' ORDER BY waldecoder_lsn)
+             FROM waldecoder.\"public.test_shadow\"",
+        )
+        .unwrap();
+        assert_eq!(
+            history.as_deref(),
+            Some("INSERT 1 a, INSERT 2 b, UPDATE 1 c, DELETE 2 b")
+        );
+    }
+}
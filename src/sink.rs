@@ -0,0 +1,383 @@
+use pgrx::{datum::DatumWithOid, pg_sys, pg_sys::panic::CaughtError, prelude::*, PgTryBuilder};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    output::{write_changes, OutputSink},
+    pg_lsn::PgLSN,
+};
+
+/// Columns of the sink table, in the order of `DecodedResult`
+pub const SINK_COLUMNS: [&str; 39] = [
+    "lsn",
+    "dboid",
+    "relid",
+    "spcoid",
+    "relfilenumber",
+    "relation_missing",
+    "xid",
+    "op",
+    "redo_query",
+    "revert_query",
+    "row_before",
+    "row_after",
+    "raw_xid",
+    "commit_time",
+    "schema_name",
+    "relation_name",
+    "origin_id",
+    "origin_name",
+    "raw_record",
+    "toplevel_xid",
+    "changes",
+    "error",
+    "old_ctid",
+    "new_ctid",
+    "forknum",
+    "blkno",
+    "schema_mismatch",
+    "provenance",
+    "raw_tuple",
+    "infomask",
+    "xmin",
+    "xmax",
+    "persistence",
+    "cid",
+    "query_fingerprint",
+    "prev_lsn",
+    "record_length",
+    "fpi_length",
+    "annotation",
+];
+
+/// What to do when a batch can't be written in the sink table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorPolicy {
+    /// Raise the error, aborting the whole call
+    Stop,
+    /// Warn and drop the failing batch
+    SkipBatch,
+    /// Retry the failing batch row by row, dropping the failing rows
+    SkipRow,
+}
+
+impl TryFrom<&str> for ErrorPolicy {
+    type Error = String;
+
+    fn try_from(policy: &str) -> Result<Self, Self::Error> {
+        match policy {
+            "stop" => Ok(ErrorPolicy::Stop),
+            "skip_batch" => Ok(ErrorPolicy::SkipBatch),
+            "skip_row" => Ok(ErrorPolicy::SkipRow),
+            _ => Err(format!("Unknown error policy '{policy}'")),
+        }
+    }
+}
+
+/// Quoted name of a table given as an optionally qualified name
+pub(crate) fn quote_table_name(name: &str) -> String {
+    let args: [DatumWithOid; 1] = [name.into()];
+    Spi::get_one_with_args::<String>(
+        "SELECT string_agg(quote_ident(part), '.' ORDER BY n) FROM unnest(parse_ident($1)) WITH ORDINALITY AS t(part, n)",
+        &args,
+    )
+    .unwrap()
+    .unwrap()
+}
+
+/// Multi-row insert of `rows` decoded changes into the target table
+fn batch_insert_query(target_table: &str, rows: usize) -> String {
+    let values = (0..rows)
+        .map(|row| {
+            let params = (1..=SINK_COLUMNS.len())
+                .map(|col| format!("${}", row * SINK_COLUMNS.len() + col))
+                .collect::<Vec<_>>();
+            format!("({})", params.join(", "))
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "INSERT INTO {target_table} ({}) VALUES {}",
+        SINK_COLUMNS.join(", "),
+        values.join(", ")
+    )
+}
+
+fn insert_batch(target_table: &str, batch: &[DecodedResult]) {
+    let mut args: Vec<DatumWithOid> = Vec::with_capacity(batch.len() * SINK_COLUMNS.len());
+    for change in batch {
+        let change = change.clone();
+        args.extend([
+            change.lsn.into(),
+            change.dboid.into(),
+            change.relid.into(),
+            change.spcoid.into(),
+            change.relfilenumber.into(),
+            change.relation_missing.into(),
+            change.full_xid.into(),
+            change.op.into(),
+            change.redo_query.into(),
+            change.revert_query.into(),
+            change.row_before.into(),
+            change.row_after.into(),
+            change.xid.into(),
+            change.commit_time.into(),
+            change.schema_name.into(),
+            change.relation_name.into(),
+            change.origin_id.into(),
+            change.origin_name.into(),
+            change.raw_record.into(),
+            change.toplevel_xid.into(),
+            change.changes.into(),
+            change.error.into(),
+            change.old_ctid.into(),
+            change.new_ctid.into(),
+            change.forknum.into(),
+            change.blkno.into(),
+            change.schema_mismatch.into(),
+            change.provenance.into(),
+            change.raw_tuple.into(),
+            change.infomask.into(),
+            change.xmin.into(),
+            change.xmax.into(),
+            change.persistence.into(),
+            change.cid.into(),
+            change.query_fingerprint.into(),
+            change.prev_lsn.into(),
+            change.record_length.into(),
+            change.fpi_length.into(),
+            change.annotation.into(),
+        ]);
+    }
+    Spi::run_with_args(&batch_insert_query(target_table, batch.len()), &args).unwrap();
+}
+
+/// Message of an error caught by `PgTryBuilder`
+fn caught_message(e: &CaughtError) -> String {
+    match e {
+        CaughtError::PostgresError(report)
+        | CaughtError::ErrorReport(report)
+        | CaughtError::RustPanic {
+            ereport: report, ..
+        } => report.message().to_string(),
+    }
+}
+
+/// Run `f` in a subtransaction, rolling it back and returning the error's
+/// message if it raises an error
+pub(crate) fn try_in_subtransaction(f: impl FnOnce()) -> Result<(), String> {
+    let old_context = unsafe { pg_sys::CurrentMemoryContext };
+    let old_owner = unsafe { pg_sys::CurrentResourceOwner };
+    // Restored like PG_CATCH does, a Rust error leaves its context installed
+    let old_error_context = unsafe { pg_sys::error_context_stack };
+    unsafe {
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(old_context);
+    }
+    let restore = || unsafe {
+        pg_sys::MemoryContextSwitchTo(old_context);
+        pg_sys::CurrentResourceOwner = old_owner;
+        pg_sys::error_context_stack = old_error_context;
+    };
+    PgTryBuilder::new(|| {
+        f();
+        unsafe { pg_sys::ReleaseCurrentSubTransaction() };
+        restore();
+        Ok(())
+    })
+    .catch_others(|e| {
+        unsafe { pg_sys::RollbackAndReleaseCurrentSubTransaction() };
+        restore();
+        Err(caught_message(&e))
+    })
+    .execute()
+}
+
+/// Insert a batch in a subtransaction, warning and returning false if it fails
+fn try_insert_batch(target_table: &str, batch: &[DecodedResult]) -> bool {
+    match try_in_subtransaction(|| insert_batch(target_table, batch)) {
+        Ok(()) => true,
+        Err(e) => {
+            warning!("Couldn't write in the sink table: {e}");
+            false
+        }
+    }
+}
+
+/// Write a batch according to the error policy, returns the number of rows
+/// written
+fn write_batch(target_table: &str, batch: &[DecodedResult], on_error: ErrorPolicy) -> usize {
+    if on_error == ErrorPolicy::Stop {
+        insert_batch(target_table, batch);
+        return batch.len();
+    }
+    if try_insert_batch(target_table, batch) {
+        return batch.len();
+    }
+    if on_error == ErrorPolicy::SkipBatch {
+        return 0;
+    }
+    batch
+        .iter()
+        .filter(|change| try_insert_batch(target_table, std::slice::from_ref(*change)))
+        .count()
+}
+
+/// Insert the changes by batches in a local or foreign table
+struct TableSink<'a> {
+    target_table: &'a str,
+    batch_size: usize,
+    on_error: ErrorPolicy,
+    batch: Vec<DecodedResult>,
+    written: usize,
+}
+
+impl OutputSink for TableSink<'_> {
+    fn write(&mut self, change: DecodedResult) {
+        self.batch.push(change);
+        if self.batch.len() == self.batch_size {
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.batch.is_empty() {
+            self.written += write_batch(self.target_table, &self.batch, self.on_error);
+            self.batch.clear();
+        }
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+}
+
+/// Decode the WAL from `start_lsn` and write the changes in `target_table`,
+/// an optionally qualified name of a local or a foreign table with the
+/// columns returned by `pg_waldecoder`. Without `end_lsn`, decoding stops at
+/// the WAL flushed when the call starts, the changes written in the table
+/// aren't decoded. Returns the number of rows written.
+#[allow(clippy::too_many_arguments)]
+#[pg_extern]
+fn pg_waldecoder_sink(
+    target_table: &str,
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+    batch_size: default!(i32, 100),
+    on_error: default!(&str, "'stop'"),
+) -> i64 {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let on_error = match ErrorPolicy::try_from(on_error) {
+        Ok(on_error) => on_error,
+        Err(e) => error!("Error: {e}"),
+    };
+    let Ok(batch_size) = usize::try_from(batch_size) else {
+        error!("Error: batch_size must be positive");
+    };
+    // Keep the number of parameters of a batch under the protocol's limit
+    let batch_size = batch_size.clamp(1, usize::from(u16::MAX) / SINK_COLUMNS.len());
+    let target_table = quote_table_name(target_table);
+    let flushed = end_lsn
+        .is_none()
+        .then(|| PgLSN::from(unsafe { pg_sys::GetFlushRecPtr(std::ptr::null_mut()) }).to_string());
+
+    let options = DecoderOptions {
+        end_lsn: end_lsn.or(flushed.as_deref()),
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    let mut sink = TableSink {
+        target_table: &target_table,
+        batch_size,
+        on_error,
+        batch: Vec::with_capacity(batch_size),
+        written: 0,
+    };
+    let written = write_changes(wal_decoder, &mut sink);
+    verbose!(
+        Verbosity::Normal,
+        "Wrote {written} changes in {target_table}"
+    );
+    i64::try_from(written).unwrap_or(i64::MAX)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::{
+        pg_lsn::PgLSN,
+        sink::{batch_insert_query, ErrorPolicy},
+    };
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_batch_insert_query() {
+        let query = batch_insert_query("sink", 2);
+        assert!(query.starts_with("INSERT INTO sink (lsn, dboid, relid,"));
+        assert!(query.ends_with("VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38, $39), ($40, $41, $42, $43, $44, $45, $46, $47, $48, $49, $50, $51, $52, $53, $54, $55, $56, $57, $58, $59, $60, $61, $62, $63, $64, $65, $66, $67, $68, $69, $70, $71, $72, $73, $74, $75, $76, $77, $78)"));
+        assert_eq!(ErrorPolicy::try_from("skip_row"), Ok(ErrorPolicy::SkipRow));
+        assert!(ErrorPolicy::try_from("ignore").is_err());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_sink() {
+        unsafe {
+            Spi::run("CREATE TABLE test_sink_source (id int, data text);");
+            Spi::run(
+                "CREATE TABLE test_sink AS SELECT * FROM pg_waldecoder('0/0', '0/0') WITH NO DATA;",
+            );
+            // Only the first inserted row fits
+            Spi::run("ALTER TABLE test_sink ADD CHECK (row_after <> '(2,)');");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_sink_source (id) SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let written = Spi::get_one::<i64>(&format!(
+            "SELECT pg_waldecoder_sink('test_sink', '{startptr}', '{endptr}', 1, batch_size => 2, on_error => 'skip_row')"
+        ))
+        .unwrap();
+        assert_eq!(written, Some(2));
+        let count =
+            Spi::get_one::<i64>("SELECT count(*) FROM test_sink WHERE op = 'INSERT'").unwrap();
+        assert_eq!(count, Some(2));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_sink_until_flushed() {
+        unsafe {
+            Spi::run("CREATE TABLE test_sink_flushed (id int);");
+            Spi::run(
+                r#"CREATE TABLE "Test Sink" AS SELECT * FROM pg_waldecoder('0/0', '0/0') WITH NO DATA;"#,
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_sink_flushed SELECT generate_series(1, 3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        // The inserts in the sink table come after the end
+        let written = Spi::get_one::<i64>(&format!(
+            r#"SELECT pg_waldecoder_sink('public."Test Sink"', '{startptr}', timeline => 1, batch_size => 1)"#
+        ))
+        .unwrap();
+        assert_eq!(written, Some(3));
+        let count = Spi::get_one::<i64>(
+            r#"SELECT count(*) FROM "Test Sink" WHERE relid = 'test_sink_flushed'::regclass"#,
+        )
+        .unwrap();
+        assert_eq!(count, Some(3));
+    }
+}
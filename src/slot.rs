@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashSet};
+
+use pgrx::{datum::DatumWithOid, prelude::*};
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    guc::{verbose, Verbosity},
+    pg_lsn::PgLSN,
+    tuple_str::quote_identifier,
+};
+
+/// restart_lsn and confirmed_flush_lsn of a replication slot
+fn slot_lsns(slot_name: &str) -> (Option<PgLSN>, Option<PgLSN>) {
+    let args: [DatumWithOid; 1] = [slot_name.into()];
+    let lsns = Spi::get_two_with_args::<PgLSN, PgLSN>(
+        "SELECT restart_lsn, confirmed_flush_lsn
+         FROM pg_catalog.pg_replication_slots WHERE slot_name = $1",
+        &args,
+    );
+    match lsns {
+        Ok(lsns) => lsns,
+        Err(pgrx::spi::Error::InvalidPosition) => {
+            error!("Replication slot \"{slot_name}\" doesn't exist")
+        }
+        Err(e) => error!("Error: {}", e.to_string()),
+    }
+}
+
+/// Decoder advancing a slot once all its records were consumed
+struct SlotDecoder {
+    wal_decoder: WalDecoder,
+    slot_name: String,
+    advance: bool,
+}
+
+impl Iterator for SlotDecoder {
+    type Item = DecodedResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let change = self.wal_decoder.next();
+        if change.is_none() && self.advance {
+            self.advance = false;
+            let end_lsn = self.wal_decoder.end_lsn();
+            verbose!(
+                Verbosity::Normal,
+                "Advancing slot {} to {end_lsn}",
+                self.slot_name
+            );
+            let args: [DatumWithOid; 2] =
+                [self.slot_name.as_str().into(), end_lsn.to_string().into()];
+            Spi::run_with_args(
+                "SELECT pg_catalog.pg_replication_slot_advance($1, $2::pg_lsn)",
+                &args,
+            )
+            .unwrap();
+        }
+        change
+    }
+}
+
+/// Decode the WAL retained by a replication slot, from its restart_lsn, to
+/// take over from a consumer that stopped. Changes below the slot's
+/// confirmed_flush_lsn were already acknowledged by a logical consumer. With
+/// `advance`, the slot is moved past the decoded WAL once all rows were read.
+#[pg_extern(requires = ["change_type"])]
+fn pg_waldecoder_from_slot(
+    slot_name: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    advance: default!(bool, false),
+) -> SetOfIterator<'static, pgrx::composite_type!('static, "pg_waldecoder_change")> {
+    let (restart_lsn, confirmed_flush_lsn) = slot_lsns(slot_name);
+    let Some(startptr) = restart_lsn else {
+        error!("Replication slot \"{slot_name}\" doesn't reserve WAL")
+    };
+    verbose!(
+        Verbosity::Normal,
+        "Decoding slot {slot_name} from {startptr}, confirmed flush: {confirmed_flush_lsn:?}"
+    );
+
+    let options = DecoderOptions {
+        end_lsn,
+        live: true,
+        ..Default::default()
+    };
+    let wal_decoder = WalDecoder::new(startptr, &options);
+    let slot_decoder = SlotDecoder {
+        wal_decoder,
+        slot_name: slot_name.to_string(),
+        advance,
+    };
+    SetOfIterator::new(slot_decoder.map(DecodedResult::into_change))
+}
+
+/// Changes of a transaction on a relation, by operation
+type ChangeKey = (u32, String, String);
+
+/// Operation of a decoded heap record as a logical decoding plugin names it
+pub(crate) fn normalize_op(op: &str) -> &str {
+    match op.trim_end_matches("+INIT") {
+        "HOT_UPDATE" => "UPDATE",
+        op => op,
+    }
+}
+
+/// Relation and operation of a change line written by the `pg_waldecoder`
+/// plugin in row format or by `test_decoding`
+fn parse_change_line(plugin: &str, line: &str) -> Option<(String, String)> {
+    match plugin {
+        // INSERT public.t old: NULL new: (1)
+        "pg_waldecoder" => {
+            let (op, rest) = line.split_once(' ')?;
+            let (relation, _) = rest.split_once(" old: ")?;
+            Some((relation.to_string(), op.to_string()))
+        }
+        // table public.t: INSERT: id[integer]:1
+        "test_decoding" => {
+            let rest = line.strip_prefix("table ")?;
+            let (relation, rest) = rest.split_once(": ")?;
+            let op = rest.split(':').next()?;
+            Some((relation.to_string(), op.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Changes the slot's plugin would emit up to `end_lsn`, without consuming
+/// them
+fn slot_changes(slot_name: &str, end_lsn: PgLSN) -> BTreeMap<ChangeKey, i64> {
+    let args: [DatumWithOid; 1] = [slot_name.into()];
+    let plugin = Spi::get_one_with_args::<String>(
+        "SELECT plugin::text FROM pg_catalog.pg_replication_slots WHERE slot_name = $1",
+        &args,
+    );
+    let plugin = match plugin {
+        Ok(Some(plugin)) => plugin,
+        Ok(None) => error!("Replication slot \"{slot_name}\" isn't a logical slot"),
+        Err(pgrx::spi::Error::InvalidPosition) => {
+            error!("Replication slot \"{slot_name}\" doesn't exist")
+        }
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let query = match plugin.as_str() {
+        "pg_waldecoder" => {
+            "SELECT xid::text::bigint, data FROM pg_catalog.pg_logical_slot_peek_changes($1, $2::pg_lsn, NULL, 'format', 'row')"
+        }
+        "test_decoding" => {
+            "SELECT xid::text::bigint, data FROM pg_catalog.pg_logical_slot_peek_changes($1, $2::pg_lsn, NULL)"
+        }
+        _ => error!("Comparing changes of plugin \"{plugin}\" isn't supported"),
+    };
+
+    let args: [DatumWithOid; 2] = [slot_name.into(), end_lsn.to_string().into()];
+    let lines = Spi::connect(|client| {
+        client
+            .select(query, None, &args)?
+            .map(|row| Ok((row.get::<i64>(1)?, row.get::<String>(2)?)))
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap();
+
+    let mut changes = BTreeMap::new();
+    for (xid, line) in lines {
+        let (Some(xid), Some(line)) = (xid.and_then(|xid| u32::try_from(xid).ok()), line) else {
+            continue;
+        };
+        if let Some((relation, op)) = parse_change_line(&plugin, &line) {
+            *changes.entry((xid, relation, op)).or_default() += 1;
+        }
+    }
+    changes
+}
+
+/// Row changes read by the file decoder of the transactions committed
+/// before `end_lsn`, like the slot would send them. Changes of
+/// subtransactions count for their top-level transaction.
+fn wal_changes(start_lsn: PgLSN, end_lsn: PgLSN) -> BTreeMap<ChangeKey, i64> {
+    let end_lsn = end_lsn.to_string();
+    let options = DecoderOptions {
+        end_lsn: Some(&end_lsn),
+        live: true,
+        include_transactions: true,
+        ..Default::default()
+    };
+    let mut changes = BTreeMap::new();
+    let mut committed = HashSet::new();
+    for change in WalDecoder::new(start_lsn, &options) {
+        let xid = change.toplevel_xid.into_inner();
+        if change.op == "COMMIT" {
+            committed.insert(xid);
+            continue;
+        }
+        let (Some(schema_name), Some(relation_name)) = (&change.schema_name, &change.relation_name)
+        else {
+            continue;
+        };
+        let op = normalize_op(&change.op);
+        // Slots don't send the changes of the catalogs
+        let user_relation = change
+            .relid
+            .is_some_and(|relid| relid.to_u32() >= pg_sys::FirstNormalObjectId);
+        if !matches!(op, "INSERT" | "UPDATE" | "DELETE") || !user_relation {
+            continue;
+        }
+        let relation = format!(
+            "{}.{}",
+            quote_identifier(schema_name),
+            quote_identifier(relation_name)
+        );
+        *changes.entry((xid, relation, op.to_string())).or_default() += 1;
+    }
+    changes.retain(|(xid, _, _), _| committed.contains(xid));
+    changes
+}
+
+/// Compare what a logical slot would emit up to `end_lsn` with the changes
+/// found in the WAL from `start_lsn`, which should be at or before the start
+/// of the first transaction the slot has to send. Only the relations,
+/// transactions and operations whose change counts differ are returned.
+/// Slots using the `pg_waldecoder` or `test_decoding` plugins are supported.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_check_slot(
+    slot_name: &str,
+    start_lsn: &str,
+    end_lsn: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(xid, i64),
+        name!(relation, String),
+        name!(op, String),
+        name!(slot_changes, i64),
+        name!(wal_changes, i64),
+    ),
+> {
+    let (startptr, endptr) = match (PgLSN::try_from(start_lsn), PgLSN::try_from(end_lsn)) {
+        (Ok(startptr), Ok(endptr)) => (startptr, endptr),
+        (Err(e), _) | (_, Err(e)) => error!("Error: {}", e.to_string()),
+    };
+    let slot_changes = slot_changes(slot_name, endptr);
+    let wal_changes = wal_changes(startptr, endptr);
+    verbose!(
+        Verbosity::Normal,
+        "Comparing {} slot change groups with {} WAL change groups",
+        slot_changes.len(),
+        wal_changes.len()
+    );
+
+    let mut counts: BTreeMap<ChangeKey, (i64, i64)> = BTreeMap::new();
+    for (key, count) in slot_changes {
+        counts.entry(key).or_default().0 = count;
+    }
+    for (key, count) in wal_changes {
+        counts.entry(key).or_default().1 = count;
+    }
+    TableIterator::new(
+        counts
+            .into_iter()
+            .filter(|(_, (slot, wal))| slot != wal)
+            .map(|((xid, relation, op), (slot, wal))| (i64::from(xid), relation, op, slot, wal)),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        slot::{normalize_op, parse_change_line},
+    };
+
+    #[test]
+    fn test_parse_change_line() {
+        assert_eq!(
+            parse_change_line("pg_waldecoder", "INSERT public.t old: NULL new: (1)"),
+            Some(("public.t".to_string(), "INSERT".to_string()))
+        );
+        assert_eq!(
+            parse_change_line("test_decoding", "table public.t: DELETE: id[integer]:1"),
+            Some(("public.t".to_string(), "DELETE".to_string()))
+        );
+        assert_eq!(parse_change_line("test_decoding", "BEGIN 735"), None);
+        assert_eq!(normalize_op("HOT_UPDATE"), "UPDATE");
+        assert_eq!(normalize_op("INSERT+INIT"), "INSERT");
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_from_slot() {
+        Spi::run(
+            "SELECT pg_create_physical_replication_slot('test_slot', immediately_reserve => true, temporary => true)",
+        )
+        .unwrap();
+        unsafe {
+            Spi::run("CREATE TABLE test_slot (id int);");
+            Spi::run("INSERT INTO test_slot values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let inserts = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_waldecoder_from_slot('test_slot', advance => true) WHERE op = 'INSERT'",
+        );
+        assert!(inserts.unwrap().is_some_and(|inserts| inserts >= 1));
+        let restart_lsn = Spi::get_one::<PgLSN>(
+            "SELECT restart_lsn FROM pg_replication_slots WHERE slot_name = 'test_slot'",
+        )
+        .unwrap();
+        let flushptr = unsafe { PgLSN::from(pg_sys::GetFlushRecPtr(std::ptr::null_mut())) };
+        assert!(restart_lsn.is_some_and(|lsn| lsn <= flushptr));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_check_slot() {
+        // Created before the test's transaction writes
+        Spi::run(
+            "SELECT pg_create_logical_replication_slot('test_check_slot', 'test_decoding', true)",
+        )
+        .unwrap();
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s dbname=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'), current_database())",
+        )
+        .unwrap()
+        .unwrap();
+        let exec = |connection: &str, sql: &str| {
+            Spi::run(&format!(
+                "SELECT dblink_exec({connection}, $sql${sql}$sql$)"
+            ))
+            .unwrap();
+        };
+        let session = format!("'{conninfo}'");
+        let startptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        exec(
+            &session,
+            "DROP TABLE IF EXISTS test_check_slot; CREATE TABLE test_check_slot (id int)",
+        );
+        // The insert of the savepoint belongs to a subtransaction
+        exec(
+            &session,
+            "BEGIN;
+             INSERT INTO test_check_slot VALUES (1);
+             SAVEPOINT s;
+             INSERT INTO test_check_slot VALUES (2);
+             RELEASE SAVEPOINT s;
+             COMMIT",
+        );
+        // Committed after the end, the slot doesn't send it yet
+        Spi::run(&format!(
+            "SELECT dblink_connect('test_check_slot', '{conninfo}')"
+        ))
+        .unwrap();
+        exec("'test_check_slot'", "BEGIN");
+        exec(
+            "'test_check_slot'",
+            "INSERT INTO test_check_slot VALUES (3)",
+        );
+        let endptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        exec("'test_check_slot'", "COMMIT");
+        Spi::run("SELECT dblink_disconnect('test_check_slot')").unwrap();
+
+        let mismatches = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_check_slot('test_check_slot', '{startptr}', '{endptr}')"
+        ));
+        exec(&session, "DROP TABLE test_check_slot");
+        assert_eq!(mismatches, Ok(Some(0)));
+    }
+
+    #[pg_test(error = "Replication slot \"missing_slot\" doesn't exist")]
+    fn test_pg_waldecoder_from_missing_slot() {
+        Spi::run("SELECT * FROM pg_waldecoder_from_slot('missing_slot')").unwrap();
+    }
+}
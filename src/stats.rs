@@ -0,0 +1,1015 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use pgrx::{datum::Interval, pg_sys, prelude::*, PgBox, TimestampWithTimeZone};
+
+use crate::{
+    decoder::WalDecoder,
+    interval_micros,
+    pg_lsn::PgLSN,
+    relation::{classify_database, RecordDatabase, RelationNameCache, RelidCache},
+    walinspect::{block_ref_info, lsn_bounds, wal_decoder},
+    xid::xact_end,
+    xlog_heap::heap_op,
+    xlog_reader::{get_block, get_block_tag, get_block_tag_extended},
+};
+
+/// Relation file of a block reference
+type RelationKey = (pg_sys::Oid, pg_sys::Oid, pg_sys::RelFileNumber);
+
+fn relation_key(rlocator: &pg_sys::RelFileLocator) -> RelationKey {
+    (rlocator.spcOid, rlocator.dbOid, rlocator.relNumber)
+}
+
+/// Relid, schema and name of relation files, only resolved for the current
+/// database and shared catalogs
+#[derive(Default)]
+struct RelationResolver {
+    relids: RelidCache,
+    names: RelationNameCache,
+}
+
+impl RelationResolver {
+    fn resolve(&mut self, key: RelationKey) -> (Option<pg_sys::Oid>, Option<(String, String)>) {
+        let rlocator = pg_sys::RelFileLocator {
+            spcOid: key.0,
+            dbOid: key.1,
+            relNumber: key.2,
+        };
+        if classify_database(&rlocator) == RecordDatabase::Other {
+            return (None, None);
+        }
+        let relid = self.relids.get(&rlocator);
+        (relid, relid.and_then(|relid| self.names.get(relid)))
+    }
+}
+
+/// WAL volume attributed to a relation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RelationVolume {
+    records: u64,
+    record_bytes: u64,
+    fpi_bytes: u64,
+}
+
+/// Attribute a record to the relations of its block references. The record
+/// itself is counted for the relation of its first block reference, the main
+/// one by convention, and each image for the relation of its block. Records
+/// without block references are attributed to no relation.
+fn add_record_volume(
+    volumes: &mut HashMap<Option<RelationKey>, RelationVolume>,
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) {
+    let (_, fpi_len) = block_ref_info(xlog_reader);
+    let mut main_relation = None;
+    if let Ok(max_block_id) = u8::try_from(record.max_block_id) {
+        for block_id in 0..=max_block_id {
+            let Some(block) = get_block(record, block_id) else {
+                continue;
+            };
+            let Some((rlocator, _, _)) = get_block_tag_extended(xlog_reader, block_id) else {
+                continue;
+            };
+            let key = relation_key(&rlocator);
+            main_relation.get_or_insert(key);
+            if block.has_image {
+                volumes.entry(Some(key)).or_default().fpi_bytes += u64::from(block.bimg_len);
+            }
+        }
+    }
+    let volume = volumes.entry(main_relation).or_default();
+    volume.records += 1;
+    volume.record_bytes += u64::from(record.header.xl_tot_len - fpi_len);
+}
+
+/// Number of records, record bytes and full page image bytes written for each
+/// relation between `start_lsn` and `end_lsn`, largest first. Record bytes
+/// exclude the images, like pg_walinspect's record_size.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_relation_stats(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(spcoid, Option<pg_sys::Oid>),
+        name!(dboid, Option<pg_sys::Oid>),
+        name!(relfilenumber, Option<pg_sys::Oid>),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(schema_name, Option<String>),
+        name!(relation_name, Option<String>),
+        name!(records, i64),
+        name!(record_bytes, i64),
+        name!(fpi_bytes, i64),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut volumes = HashMap::new();
+    while let Some(record) = wal_decoder.read_record() {
+        add_record_volume(&mut volumes, wal_decoder.xlog_reader(), &record);
+    }
+
+    let mut volumes: Vec<_> = volumes.into_iter().collect();
+    volumes.sort_by_key(|(_, volume)| std::cmp::Reverse(volume.record_bytes + volume.fpi_bytes));
+    let mut resolver = RelationResolver::default();
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::new(volumes.into_iter().map(move |(key, volume)| {
+        let (relid, name) = key.map_or((None, None), |key| resolver.resolve(key));
+        let (schema_name, relation_name) = name.unzip();
+        (
+            key.map(|(spcoid, _, _)| spcoid),
+            key.map(|(_, dboid, _)| dboid),
+            key.map(|(_, _, relnumber)| relnumber),
+            relid,
+            schema_name,
+            relation_name,
+            as_i64(volume.records),
+            as_i64(volume.record_bytes),
+            as_i64(volume.fpi_bytes),
+        )
+    }))
+}
+
+/// Start of the bucket of `bucket_micros` holding `time`
+fn bucket_start(time: pg_sys::TimestampTz, bucket_micros: i64) -> pg_sys::TimestampTz {
+    time - time.rem_euclid(bucket_micros)
+}
+
+/// WAL volume written between `start_lsn` and `end_lsn` per `bucket` of
+/// time, and per relation with `per_relation`, an after the fact graph of the
+/// WAL generation rate. WAL records aren't timestamped, a record belongs to
+/// the bucket of the latest commit, abort or checkpoint time read before it,
+/// the records before the first one have a NULL bucket.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_throughput(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    bucket: default!(Interval, "'1 minute'"),
+    per_relation: default!(bool, false),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(bucket_start, Option<TimestampWithTimeZone>),
+        name!(spcoid, Option<pg_sys::Oid>),
+        name!(dboid, Option<pg_sys::Oid>),
+        name!(relfilenumber, Option<pg_sys::Oid>),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(schema_name, Option<String>),
+        name!(relation_name, Option<String>),
+        name!(records, i64),
+        name!(record_bytes, i64),
+        name!(fpi_bytes, i64),
+    ),
+> {
+    let bucket_micros = match interval_micros(bucket) {
+        Some(micros) if micros > 0 => micros,
+        _ => error!("bucket must be a positive interval"),
+    };
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut buckets: BTreeMap<Option<pg_sys::TimestampTz>, HashMap<_, RelationVolume>> =
+        BTreeMap::new();
+    let mut last_time = None;
+    while let Some(record) = wal_decoder.read_record() {
+        let volumes = buckets
+            .entry(last_time.map(|time| bucket_start(time, bucket_micros)))
+            .or_default();
+        add_record_volume(volumes, wal_decoder.xlog_reader(), &record);
+        if let Some((checkpoint, _)) = checkpoint_record(&record) {
+            last_time = Some(unsafe { pg_sys::time_t_to_timestamptz(checkpoint.time) });
+        }
+        if let Some(xact_end) = xact_end(&record) {
+            last_time = Some(xact_end.time);
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (bucket, volumes) in buckets {
+        let mut volumes: Vec<_> = if per_relation {
+            volumes.into_iter().collect()
+        } else {
+            let total = volumes
+                .into_values()
+                .fold(RelationVolume::default(), |total, volume| RelationVolume {
+                    records: total.records + volume.records,
+                    record_bytes: total.record_bytes + volume.record_bytes,
+                    fpi_bytes: total.fpi_bytes + volume.fpi_bytes,
+                });
+            vec![(None, total)]
+        };
+        volumes
+            .sort_by_key(|(_, volume)| std::cmp::Reverse(volume.record_bytes + volume.fpi_bytes));
+        rows.extend(
+            volumes
+                .into_iter()
+                .map(|(key, volume)| (bucket, key, volume)),
+        );
+    }
+    let mut resolver = RelationResolver::default();
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::new(rows.into_iter().map(move |(bucket, key, volume)| {
+        let (relid, name) = key.map_or((None, None), |key| resolver.resolve(key));
+        let (schema_name, relation_name) = name.unzip();
+        (
+            bucket.and_then(|bucket| TimestampWithTimeZone::try_from(bucket).ok()),
+            key.map(|(spcoid, _, _)| spcoid),
+            key.map(|(_, dboid, _)| dboid),
+            key.map(|(_, _, relnumber)| relnumber),
+            relid,
+            schema_name,
+            relation_name,
+            as_i64(volume.records),
+            as_i64(volume.record_bytes),
+            as_i64(volume.fpi_bytes),
+        )
+    }))
+}
+
+/// Compression method of a full page image
+fn image_compression(bimg_info: u8) -> &'static str {
+    let bimg_info = u32::from(bimg_info);
+    if bimg_info & pg_sys::BKPIMAGE_COMPRESS_PGLZ != 0 {
+        "pglz"
+    } else if bimg_info & pg_sys::BKPIMAGE_COMPRESS_LZ4 != 0 {
+        "lz4"
+    } else if bimg_info & pg_sys::BKPIMAGE_COMPRESS_ZSTD != 0 {
+        "zstd"
+    } else {
+        "none"
+    }
+}
+
+/// Full page images of a relation fork with the same compression
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ImageVolume {
+    images: u64,
+    /// Bytes of the images as written in the WAL
+    image_bytes: u64,
+    /// Bytes of the pages without their hole, before compression
+    page_bytes: u64,
+}
+
+/// Images per relation, fork and compression method
+type ImageKey = (RelationKey, i32, &'static str);
+
+fn add_record_images(
+    images: &mut HashMap<ImageKey, ImageVolume>,
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return;
+    };
+    for block_id in 0..=max_block_id {
+        let Some(block) = get_block(record, block_id).filter(|block| block.has_image) else {
+            continue;
+        };
+        let Some((rlocator, forknum, _)) = get_block_tag_extended(xlog_reader, block_id) else {
+            continue;
+        };
+        let key = (
+            relation_key(&rlocator),
+            forknum,
+            image_compression(block.bimg_info),
+        );
+        let volume = images.entry(key).or_default();
+        volume.images += 1;
+        volume.image_bytes += u64::from(block.bimg_len);
+        volume.page_bytes += u64::from(pg_sys::BLCKSZ - u32::from(block.hole_length));
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn fraction(value: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        value as f64 / total as f64
+    }
+}
+
+/// Full page images written between `start_lsn` and `end_lsn`, per relation,
+/// fork and compression method, largest first. `wal_fraction` is the part of
+/// the WAL bytes of the range spent on these images, and `page_bytes` what
+/// they would take without `wal_compression`.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_fpi_stats(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(spcoid, pg_sys::Oid),
+        name!(dboid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(schema_name, Option<String>),
+        name!(relation_name, Option<String>),
+        name!(forknum, i16),
+        name!(compression, String),
+        name!(images, i64),
+        name!(image_bytes, i64),
+        name!(page_bytes, i64),
+        name!(wal_fraction, f64),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut images = HashMap::new();
+    let mut wal_bytes: u64 = 0;
+    while let Some(record) = wal_decoder.read_record() {
+        wal_bytes += u64::from(record.header.xl_tot_len);
+        add_record_images(&mut images, wal_decoder.xlog_reader(), &record);
+    }
+
+    let mut images: Vec<_> = images.into_iter().collect();
+    images.sort_by_key(|(_, volume)| std::cmp::Reverse(volume.image_bytes));
+    let mut resolver = RelationResolver::default();
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::new(
+        images
+            .into_iter()
+            .map(move |((key, forknum, compression), volume)| {
+                let (relid, name) = resolver.resolve(key);
+                let (schema_name, relation_name) = name.unzip();
+                (
+                    key.0,
+                    key.1,
+                    key.2,
+                    relid,
+                    schema_name,
+                    relation_name,
+                    i16::try_from(forknum).unwrap_or(-1),
+                    compression.to_string(),
+                    as_i64(volume.images),
+                    as_i64(volume.image_bytes),
+                    as_i64(volume.page_bytes),
+                    fraction(volume.image_bytes, wal_bytes),
+                )
+            }),
+    )
+}
+
+/// Checkpoint record, with whether it was written at shutdown
+fn checkpoint_record(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Option<(pg_sys::CheckPoint, bool)> {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_XLOG_ID
+        || (info != pg_sys::XLOG_CHECKPOINT_SHUTDOWN && info != pg_sys::XLOG_CHECKPOINT_ONLINE)
+        || record.main_data.is_null()
+    {
+        return None;
+    }
+    let checkpoint =
+        unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::CheckPoint>()) };
+    Some((checkpoint, info == pg_sys::XLOG_CHECKPOINT_SHUTDOWN))
+}
+
+/// Guess what triggered a checkpoint from its distance to the previous one.
+/// A checkpoint starting `timeout` seconds after the previous one was timed,
+/// one starting after the WAL grew past `max_distance` was requested to keep
+/// the WAL under max_wal_size, others were requested explicitly, e.g. by a
+/// CHECKPOINT command or a base backup.
+fn checkpoint_trigger(
+    shutdown: bool,
+    previous: Option<(i64, u64)>,
+    timeout: i64,
+    max_distance: u64,
+) -> Option<&'static str> {
+    if shutdown {
+        return Some("shutdown");
+    }
+    let (seconds, distance) = previous?;
+    Some(if seconds >= timeout {
+        "timeout"
+    } else if distance >= max_distance {
+        "max_wal_size"
+    } else {
+        "requested"
+    })
+}
+
+/// Checkpoints written between `start_lsn` and `end_lsn`, with the distance
+/// in bytes between their redo pointers and the seconds between their starts.
+/// The trigger is guessed from the current checkpoint_timeout and
+/// max_wal_size, it's NULL for the first online checkpoint of the range.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_checkpoints(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(lsn, PgLSN),
+        name!(redo_lsn, PgLSN),
+        name!(checkpoint_time, Option<TimestampWithTimeZone>),
+        name!(shutdown, bool),
+        name!(distance_bytes, Option<i64>),
+        name!(distance_seconds, Option<i64>),
+        name!(trigger, Option<String>),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let timeout = i64::from(unsafe { pg_sys::CheckPointTimeout });
+    // XLogCheckpointNeeded's threshold
+    let max_distance = u64::try_from(unsafe { pg_sys::CheckPointSegments } - 1).unwrap_or(0)
+        * u64::try_from(unsafe { pg_sys::wal_segment_size }).unwrap_or(0);
+    let mut previous: Option<pg_sys::CheckPoint> = None;
+    TableIterator::new(std::iter::from_fn(move || loop {
+        let record = wal_decoder.read_record()?;
+        let Some((checkpoint, shutdown)) = checkpoint_record(&record) else {
+            continue;
+        };
+        let distance = previous.map(|previous| {
+            (
+                checkpoint.time - previous.time,
+                checkpoint.redo.saturating_sub(previous.redo),
+            )
+        });
+        previous = Some(checkpoint);
+        let checkpoint_time = TimestampWithTimeZone::try_from(unsafe {
+            pg_sys::time_t_to_timestamptz(checkpoint.time)
+        })
+        .ok();
+        return Some((
+            PgLSN::from(record.lsn),
+            PgLSN::from(checkpoint.redo),
+            checkpoint_time,
+            shutdown,
+            distance.map(|(_, bytes)| i64::try_from(bytes).unwrap_or(i64::MAX)),
+            distance.map(|(seconds, _)| seconds),
+            checkpoint_trigger(shutdown, distance, timeout, max_distance).map(str::to_string),
+        ));
+    }))
+}
+
+/// Rows changed in a relation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RowChanges {
+    inserts: u64,
+    updates: u64,
+    deletes: u64,
+}
+
+impl RowChanges {
+    fn total(&self) -> u64 {
+        self.inserts + self.updates + self.deletes
+    }
+}
+
+/// Rows changed by a heap record, counted from the record alone without
+/// decoding its tuples
+fn record_row_changes(record: &PgBox<pg_sys::DecodedXLogRecord>) -> RowChanges {
+    let mut changes = RowChanges::default();
+    match (u32::from(record.header.xl_rmid), heap_op(record)) {
+        (pg_sys::RmgrIds::RM_HEAP_ID, pg_sys::XLOG_HEAP_INSERT) => changes.inserts = 1,
+        (pg_sys::RmgrIds::RM_HEAP_ID, pg_sys::XLOG_HEAP_UPDATE | pg_sys::XLOG_HEAP_HOT_UPDATE) => {
+            changes.updates = 1;
+        }
+        (pg_sys::RmgrIds::RM_HEAP_ID, pg_sys::XLOG_HEAP_DELETE) => changes.deletes = 1,
+        (pg_sys::RmgrIds::RM_HEAP2_ID, pg_sys::XLOG_HEAP2_MULTI_INSERT)
+            if !record.main_data.is_null() =>
+        {
+            let xlrec = unsafe {
+                std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_multi_insert>())
+            };
+            changes.inserts = u64::from(xlrec.ntuples);
+        }
+        _ => {}
+    }
+    changes
+}
+
+fn add_row_changes(
+    changes: &mut HashMap<RelationKey, RowChanges>,
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) {
+    let record_changes = record_row_changes(record);
+    if record.max_block_id < 0 || record_changes.total() == 0 {
+        return;
+    }
+    let (rlocator, _, _) = get_block_tag(xlog_reader);
+    let relation_changes = changes.entry(relation_key(&rlocator)).or_default();
+    relation_changes.inserts += record_changes.inserts;
+    relation_changes.updates += record_changes.updates;
+    relation_changes.deletes += record_changes.deletes;
+}
+
+/// The `limit` relations with the most rows inserted, updated or deleted
+/// between `start_lsn` and `end_lsn`. Rows are counted from the heap records,
+/// so relations of other databases are ranked too but without a name.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_top_relations(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    limit: default!(i64, 10),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(spcoid, pg_sys::Oid),
+        name!(dboid, pg_sys::Oid),
+        name!(relfilenumber, pg_sys::Oid),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(schema_name, Option<String>),
+        name!(relation_name, Option<String>),
+        name!(inserts, i64),
+        name!(updates, i64),
+        name!(deletes, i64),
+        name!(total, i64),
+    ),
+> {
+    let Ok(limit) = usize::try_from(limit) else {
+        error!("limit must not be negative");
+    };
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut changes = HashMap::new();
+    while let Some(record) = wal_decoder.read_record() {
+        add_row_changes(&mut changes, wal_decoder.xlog_reader(), &record);
+    }
+
+    let mut changes: Vec<_> = changes.into_iter().collect();
+    changes.sort_by_key(|(_, changes)| std::cmp::Reverse(changes.total()));
+    changes.truncate(limit);
+    let mut resolver = RelationResolver::default();
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::new(changes.into_iter().map(move |(key, changes)| {
+        let (relid, name) = resolver.resolve(key);
+        let (schema_name, relation_name) = name.unzip();
+        (
+            key.0,
+            key.1,
+            key.2,
+            relid,
+            schema_name,
+            relation_name,
+            as_i64(changes.inserts),
+            as_i64(changes.updates),
+            as_i64(changes.deletes),
+            as_i64(changes.total()),
+        )
+    }))
+}
+
+/// Activity of a transaction in the WAL
+#[derive(Clone, Debug)]
+struct XactActivity {
+    first_lsn: pg_sys::XLogRecPtr,
+    /// Latest timestamp read before the transaction's first record
+    first_time: Option<pg_sys::TimestampTz>,
+    wal_bytes: u64,
+    /// Blocks modified by the transaction's records
+    pages: HashSet<(RelationKey, i32, pg_sys::BlockNumber)>,
+    end: Option<(pg_sys::XLogRecPtr, pg_sys::TimestampTz, bool)>,
+}
+
+impl XactActivity {
+    fn new(first_lsn: pg_sys::XLogRecPtr, first_time: Option<pg_sys::TimestampTz>) -> Self {
+        XactActivity {
+            first_lsn,
+            first_time,
+            wal_bytes: 0,
+            pages: HashSet::new(),
+            end: None,
+        }
+    }
+
+    /// Fold the records of a subtransaction into its top level transaction
+    fn merge(&mut self, sub: XactActivity) {
+        if sub.first_lsn < self.first_lsn {
+            self.first_lsn = sub.first_lsn;
+            self.first_time = sub.first_time;
+        }
+        self.wal_bytes += sub.wal_bytes;
+        self.pages.extend(sub.pages);
+    }
+
+    fn relations(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|(key, _, _)| key)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+/// Read the records between the bounds of the decoder, collecting the
+/// activity of each top level transaction by xid
+fn collect_transactions(mut wal_decoder: WalDecoder) -> HashMap<u32, XactActivity> {
+    let mut xacts: HashMap<u32, XactActivity> = HashMap::new();
+    let mut last_time = None;
+    while let Some(record) = wal_decoder.read_record() {
+        let xid = record.header.xl_xid.into_inner();
+        if xid != 0 {
+            let activity = xacts
+                .entry(xid)
+                .or_insert_with(|| XactActivity::new(record.lsn, last_time));
+            activity.wal_bytes += u64::from(record.header.xl_tot_len);
+            if let Ok(max_block_id) = u8::try_from(record.max_block_id) {
+                let pages = (0..=max_block_id).filter_map(|block_id| {
+                    let (rlocator, forknum, blknum) =
+                        get_block_tag_extended(wal_decoder.xlog_reader(), block_id)?;
+                    Some((relation_key(&rlocator), forknum, blknum))
+                });
+                activity.pages.extend(pages);
+            }
+        }
+        if let Some((checkpoint, _)) = checkpoint_record(&record) {
+            last_time = Some(unsafe { pg_sys::time_t_to_timestamptz(checkpoint.time) });
+        }
+        let Some(xact_end) = xact_end(&record) else {
+            continue;
+        };
+        last_time = Some(xact_end.time);
+        let xid = xact_end.xid.into_inner();
+        let mut activity = xacts
+            .remove(&xid)
+            .unwrap_or_else(|| XactActivity::new(record.lsn, last_time));
+        for subxid in &xact_end.subxacts {
+            if let Some(sub) = xacts.remove(&subxid.into_inner()) {
+                activity.merge(sub);
+            }
+        }
+        activity.end = Some((record.lsn, xact_end.time, xact_end.committed));
+        xacts.insert(xid, activity);
+    }
+    xacts
+}
+
+/// Transactions with records between `start_lsn` and `end_lsn`, with their
+/// first record and their commit or abort, subtransactions included in their
+/// top level transaction. WAL records aren't timestamped, the time of the
+/// first record is the latest commit, abort or checkpoint time read before
+/// it, so the duration is an upper bound. Transactions still running at
+/// `end_lsn`, or whose first record is before `start_lsn`, are reported too.
+#[allow(clippy::type_complexity, clippy::cast_precision_loss)]
+#[pg_extern]
+fn pg_waldecoder_transactions(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(xid, pg_sys::TransactionId),
+        name!(first_lsn, PgLSN),
+        name!(first_time, Option<TimestampWithTimeZone>),
+        name!(end_lsn, Option<PgLSN>),
+        name!(end_time, Option<TimestampWithTimeZone>),
+        name!(status, Option<String>),
+        name!(duration_seconds, Option<f64>),
+        name!(wal_bytes, i64),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut xacts: Vec<_> = collect_transactions(wal_decoder).into_iter().collect();
+    xacts.sort_by_key(|(_, activity)| activity.first_lsn);
+    let timestamp = |t: pg_sys::TimestampTz| TimestampWithTimeZone::try_from(t).ok();
+    TableIterator::new(xacts.into_iter().map(move |(xid, activity)| {
+        let duration = activity
+            .end
+            .zip(activity.first_time)
+            .map(|((_, end_time, _), first_time)| (end_time - first_time) as f64 / 1_000_000.0);
+        (
+            pg_sys::TransactionId::from(xid),
+            PgLSN::from(activity.first_lsn),
+            activity.first_time.and_then(timestamp),
+            activity.end.map(|(lsn, _, _)| PgLSN::from(lsn)),
+            activity.end.and_then(|(_, time, _)| timestamp(time)),
+            activity.end.map(|(_, _, committed)| {
+                if committed { "committed" } else { "aborted" }.to_string()
+            }),
+            duration,
+            i64::try_from(activity.wal_bytes).unwrap_or(i64::MAX),
+        )
+    }))
+}
+
+/// What the largest transactions are ranked by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TransactionSize {
+    WalBytes,
+    Relations,
+    Pages,
+}
+
+impl TryFrom<&str> for TransactionSize {
+    type Error = String;
+
+    fn try_from(order_by: &str) -> Result<Self, Self::Error> {
+        match order_by {
+            "wal_bytes" => Ok(TransactionSize::WalBytes),
+            "relations" => Ok(TransactionSize::Relations),
+            "pages" => Ok(TransactionSize::Pages),
+            _ => Err(format!(
+                "Unknown order '{order_by}', expected wal_bytes, relations or pages"
+            )),
+        }
+    }
+}
+
+/// The `limit` transactions between `start_lsn` and `end_lsn` that wrote the
+/// most WAL bytes, or touched the most distinct relations or pages depending
+/// on `order_by`
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_largest_transactions(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    order_by: default!(&str, "'wal_bytes'"),
+    limit: default!(i64, 10),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(xid, pg_sys::TransactionId),
+        name!(first_lsn, PgLSN),
+        name!(end_lsn, Option<PgLSN>),
+        name!(status, Option<String>),
+        name!(wal_bytes, i64),
+        name!(relations, i64),
+        name!(pages, i64),
+    ),
+> {
+    let order_by = match TransactionSize::try_from(order_by) {
+        Ok(order_by) => order_by,
+        Err(e) => error!("Error: {e}"),
+    };
+    let Ok(limit) = usize::try_from(limit) else {
+        error!("limit must not be negative");
+    };
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    let mut xacts: Vec<_> = collect_transactions(wal_decoder)
+        .into_iter()
+        .map(|(xid, activity)| {
+            let relations = activity.relations() as u64;
+            let pages = activity.pages.len() as u64;
+            (xid, activity, relations, pages)
+        })
+        .collect();
+    xacts.sort_by_key(|(_, activity, relations, pages)| {
+        std::cmp::Reverse(match order_by {
+            TransactionSize::WalBytes => activity.wal_bytes,
+            TransactionSize::Relations => *relations,
+            TransactionSize::Pages => *pages,
+        })
+    });
+    xacts.truncate(limit);
+    TableIterator::new(
+        xacts
+            .into_iter()
+            .map(move |(xid, activity, relations, pages)| {
+                (
+                    pg_sys::TransactionId::from(xid),
+                    PgLSN::from(activity.first_lsn),
+                    activity.end.map(|(lsn, _, _)| PgLSN::from(lsn)),
+                    activity.end.map(|(_, _, committed)| {
+                        if committed { "committed" } else { "aborted" }.to_string()
+                    }),
+                    as_i64(activity.wal_bytes),
+                    as_i64(relations),
+                    as_i64(pages),
+                )
+            }),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        stats::{bucket_start, checkpoint_trigger, image_compression, TransactionSize},
+    };
+
+    #[test]
+    fn test_image_compression() {
+        assert_eq!(image_compression(0x03), "none");
+        assert_eq!(image_compression(0x08), "lz4");
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        let minute = 60_000_000;
+        assert_eq!(bucket_start(90_000_000, minute), 60_000_000);
+        assert_eq!(bucket_start(60_000_000, minute), 60_000_000);
+        // Times before 2000 are negative
+        assert_eq!(bucket_start(-30_000_000, minute), -60_000_000);
+    }
+
+    #[test]
+    fn test_checkpoint_trigger() {
+        let max_distance = 64 << 20;
+        assert_eq!(
+            checkpoint_trigger(true, None, 300, max_distance),
+            Some("shutdown")
+        );
+        assert_eq!(checkpoint_trigger(false, None, 300, max_distance), None);
+        assert_eq!(
+            checkpoint_trigger(false, Some((300, 1024)), 300, max_distance),
+            Some("timeout")
+        );
+        assert_eq!(
+            checkpoint_trigger(false, Some((42, max_distance)), 300, max_distance),
+            Some("max_wal_size")
+        );
+        assert_eq!(
+            checkpoint_trigger(false, Some((42, 1024)), 300, max_distance),
+            Some("requested")
+        );
+    }
+
+    #[test]
+    fn test_transaction_size() {
+        assert_eq!(
+            TransactionSize::try_from("pages"),
+            Ok(TransactionSize::Pages)
+        );
+        assert!(TransactionSize::try_from("rows").is_err());
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_relation_stats() {
+        unsafe {
+            Spi::run("CREATE TABLE test_relation_stats (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_relation_stats SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let records = Spi::get_one::<i64>(&format!(
+            "SELECT records FROM pg_waldecoder_relation_stats('{startptr}', '{endptr}', 1)
+             WHERE relation_name = 'test_relation_stats'"
+        ));
+        assert_eq!(records, Ok(Some(10)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_throughput() {
+        unsafe {
+            Spi::run("CREATE TABLE test_throughput (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_throughput SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let records = Spi::get_one::<i64>(&format!(
+            "SELECT sum(records)::bigint FROM pg_waldecoder_throughput('{startptr}', '{endptr}',
+                 per_relation => true, timeline => 1)
+             WHERE relation_name = 'test_throughput'"
+        ));
+        assert_eq!(records, Ok(Some(10)));
+        let buckets = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_throughput('{startptr}', '{endptr}', timeline => 1)
+             WHERE relid IS NOT NULL"
+        ));
+        assert_eq!(buckets, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_fpi_stats() {
+        unsafe {
+            Spi::run("CREATE TABLE test_fpi_stats (id int);");
+            Spi::run("INSERT INTO test_fpi_stats SELECT generate_series(1, 10)");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("UPDATE test_fpi_stats SET id = id + 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The first change after the checkpoint writes the page's image
+        let images = Spi::get_one::<i64>(&format!(
+            "SELECT sum(images)::bigint FROM pg_waldecoder_fpi_stats('{startptr}', '{endptr}', 1)
+             WHERE relation_name = 'test_fpi_stats' AND forknum = 0"
+        ));
+        assert_eq!(images, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_checkpoints() {
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("CHECKPOINT");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let checkpoints = Spi::get_two::<i64, i64>(&format!(
+            "SELECT count(*), count(trigger) FROM pg_waldecoder_checkpoints('{startptr}', '{endptr}', 1)"
+        ));
+        assert_eq!(checkpoints, Ok((Some(2), Some(1))));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_top_relations() {
+        unsafe {
+            Spi::run("CREATE TABLE test_top_quiet (id int);");
+            Spi::run("CREATE TABLE test_top_busy (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_top_quiet VALUES (1)");
+            Spi::run("INSERT INTO test_top_busy SELECT generate_series(1, 5)");
+            Spi::run("UPDATE test_top_busy SET id = id + 1 WHERE id < 3");
+            Spi::run("DELETE FROM test_top_busy WHERE id = 5");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let top = Spi::get_two::<String, i64>(&format!(
+            "SELECT relation_name, total FROM pg_waldecoder_top_relations('{startptr}', '{endptr}', 1, 1)"
+        ));
+        assert_eq!(top, Ok((Some("test_top_busy".to_string()), Some(8))));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_transactions() {
+        unsafe {
+            Spi::run("CREATE TABLE test_transactions (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_transactions SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        // The test's transaction is still running
+        let xact = Spi::get_two::<i64, bool>(&format!(
+            "SELECT wal_bytes, status IS NULL FROM pg_waldecoder_transactions('{startptr}', '{endptr}', 1)
+             WHERE xid = pg_current_xact_id()::xid"
+        ));
+        let Ok((Some(wal_bytes), Some(running))) = xact else {
+            panic!("Transaction not found: {xact:?}");
+        };
+        assert!(wal_bytes > 0);
+        assert!(running);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_largest_transactions() {
+        unsafe {
+            Spi::run("CREATE TABLE test_largest_transactions (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run(
+                "INSERT INTO test_largest_transactions SELECT i, repeat('a', 1000) FROM generate_series(1, 100) i",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let largest = Spi::get_two::<i64, i64>(&format!(
+            "SELECT relations, pages FROM pg_waldecoder_largest_transactions('{startptr}', '{endptr}', 'pages', 1, 1)"
+        ));
+        // Rows of 1kB fill several pages of a single relation
+        let Ok((Some(relations), Some(pages))) = largest else {
+            panic!("Transaction not found: {largest:?}");
+        };
+        assert_eq!(relations, 1);
+        assert!(pages > 10);
+    }
+}
@@ -0,0 +1,111 @@
+use std::cell::Cell;
+
+use crate::{pg_lsn::PgLSN, xlog_heap::SkipReason};
+
+/// Totals of a decoding, from the first record read up to where it stopped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    pub records_read: u64,
+    /// Records of other resource managers than heap
+    pub skipped_non_heap: u64,
+    /// Heap records whose page was never seen in a full page image
+    pub skipped_no_page: u64,
+    /// Heap records without a row change, in another database or not in the
+    /// relations decoded
+    pub skipped_other: u64,
+    /// Part of `skipped_other` without a block reference
+    pub skipped_no_block: u64,
+    /// Part of `skipped_other` whose operation doesn't change a row
+    pub skipped_no_row_change: u64,
+    /// Part of `skipped_other` in another database
+    pub skipped_other_database: u64,
+    /// Part of `skipped_other` not in the relations decoded
+    pub skipped_filtered: u64,
+    /// Part of `skipped_other` writing the chunks of toasted values
+    pub skipped_toast_chunks: u64,
+    /// Changes whose relid couldn't be found, reported without a relation
+    /// or skipped with `on_error`
+    pub unresolved_relids: u64,
+    /// Records that couldn't be read or decoded, skipped or reported with
+    /// `on_error`
+    pub failed_records: u64,
+    pub fpw_restored: u64,
+    /// Replayed pages whose LSN wasn't their record's end, with
+    /// `strict_redo`
+    pub redo_mismatches: u64,
+    pub bytes_scanned: u64,
+    /// End of the last record read
+    pub last_lsn: Option<PgLSN>,
+    /// Start of a scan continuing this one, the end of the last record read
+    /// or the start of the scan when none was
+    pub resume_lsn: Option<PgLSN>,
+}
+
+impl ScanSummary {
+    pub fn record_skipped(&mut self, reason: SkipReason) {
+        match reason {
+            SkipReason::NoPage => self.skipped_no_page += 1,
+            SkipReason::UnresolvedRelation => self.unresolved_relids += 1,
+            SkipReason::NoBlock => {
+                self.skipped_other += 1;
+                self.skipped_no_block += 1;
+            }
+            SkipReason::UnsupportedOperation => {
+                self.skipped_other += 1;
+                self.skipped_no_row_change += 1;
+            }
+            SkipReason::OtherDatabase => {
+                self.skipped_other += 1;
+                self.skipped_other_database += 1;
+            }
+            SkipReason::Filtered => {
+                self.skipped_other += 1;
+                self.skipped_filtered += 1;
+            }
+            SkipReason::ToastChunk => {
+                self.skipped_other += 1;
+                self.skipped_toast_chunks += 1;
+            }
+        }
+    }
+
+    /// Records skipped by reason, the unresolved relids included
+    pub fn skip_counts(&self) -> [(&'static str, u64); 8] {
+        [
+            ("non_heap", self.skipped_non_heap),
+            ("no_block", self.skipped_no_block),
+            ("no_page", self.skipped_no_page),
+            ("unresolved_relid", self.unresolved_relids),
+            ("filtered", self.skipped_filtered),
+            ("other_database", self.skipped_other_database),
+            ("no_row_change", self.skipped_no_row_change),
+            ("toast_chunk", self.skipped_toast_chunks),
+        ]
+    }
+
+    /// Why a scan may have returned no changes, from its most skipped records
+    pub fn no_changes_hint(&self) -> &'static str {
+        if self.records_read == 0 {
+            "No record was read, check end_lsn and the timeline."
+        } else if self.skipped_no_page > 0 {
+            "Records whose page was never seen in a full page image can't be decoded, start from a checkpoint or use read_current_pages."
+        } else if self.skipped_other > 0 {
+            "Changes of other databases are only decoded with include_other_databases, relations only keeps the listed ones."
+        } else {
+            "The range only holds records of other resource managers than heap."
+        }
+    }
+}
+
+thread_local! {
+    /// Summary of the latest decoding of the backend
+    static LAST_SCAN_SUMMARY: Cell<ScanSummary> = Cell::new(ScanSummary::default());
+}
+
+pub fn publish_scan_summary(summary: ScanSummary) {
+    LAST_SCAN_SUMMARY.set(summary);
+}
+
+pub fn last_scan_summary() -> ScanSummary {
+    LAST_SCAN_SUMMARY.get()
+}
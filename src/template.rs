@@ -0,0 +1,200 @@
+use crate::{decoder::DecodedResult, tuple_str::quote_literal};
+
+/// Field of a change substituted in a query template
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Lsn,
+    Xid,
+    RawXid,
+    Op,
+    Table,
+    SchemaName,
+    RelationName,
+    Relid,
+    RowBefore,
+    RowAfter,
+    Changes,
+    RedoQuery,
+    RevertQuery,
+    CommitTime,
+}
+
+impl TryFrom<&str> for Placeholder {
+    type Error = String;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "lsn" => Ok(Placeholder::Lsn),
+            "xid" => Ok(Placeholder::Xid),
+            "raw_xid" => Ok(Placeholder::RawXid),
+            "op" => Ok(Placeholder::Op),
+            "table" => Ok(Placeholder::Table),
+            "schema_name" => Ok(Placeholder::SchemaName),
+            "relation_name" => Ok(Placeholder::RelationName),
+            "relid" => Ok(Placeholder::Relid),
+            "row_before" => Ok(Placeholder::RowBefore),
+            "row_after" => Ok(Placeholder::RowAfter),
+            "changes" => Ok(Placeholder::Changes),
+            "redo_query" => Ok(Placeholder::RedoQuery),
+            "revert_query" => Ok(Placeholder::RevertQuery),
+            "commit_time" => Ok(Placeholder::CommitTime),
+            _ => Err(format!("Unknown placeholder {{{name}}} in query template")),
+        }
+    }
+}
+
+impl Placeholder {
+    /// Value of the field for a change, None when it's NULL
+    fn value(self, change: &DecodedResult) -> Option<String> {
+        match self {
+            Placeholder::Lsn => Some(change.lsn.to_string()),
+            Placeholder::Xid => change.full_xid.map(|xid| xid.0.to_string()),
+            Placeholder::RawXid => Some(change.xid.into_inner().to_string()),
+            Placeholder::Op => Some(change.op.clone()),
+            Placeholder::Table => change
+                .schema_name
+                .as_ref()
+                .zip(change.relation_name.as_ref())
+                .map(|(schema_name, relation_name)| format!("{schema_name}.{relation_name}")),
+            Placeholder::SchemaName => change.schema_name.clone(),
+            Placeholder::RelationName => change.relation_name.clone(),
+            Placeholder::Relid => change.relid.map(|relid| relid.to_u32().to_string()),
+            Placeholder::RowBefore => change.row_before.clone(),
+            Placeholder::RowAfter => change.row_after.clone(),
+            Placeholder::Changes => change.changes.as_ref().map(|changes| changes.0.clone()),
+            Placeholder::RedoQuery => change.redo_query.clone(),
+            Placeholder::RevertQuery => change.revert_query.clone(),
+            Placeholder::CommitTime => change.commit_time.map(|t| t.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Placeholder),
+}
+
+/// Statement rendered for each change in place of the generated queries,
+/// like `INSERT INTO audit.changes VALUES ({lsn}, {xid}, {table}, {row_after})`.
+/// Placeholders are replaced by the fields of the change as quoted literals,
+/// or NULL, `{{` and `}}` are literal braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryTemplate {
+    parts: Vec<Part>,
+}
+
+impl TryFrom<&str> for QueryTemplate {
+    type Error = String;
+
+    fn try_from(template: &str) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err("Unclosed placeholder in query template".to_string());
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(Placeholder::try_from(name.trim())?));
+                }
+                '}' => return Err("Unmatched } in query template".to_string()),
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(QueryTemplate { parts })
+    }
+}
+
+impl QueryTemplate {
+    /// Statement of a change
+    pub fn render(&self, change: &DecodedResult) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(placeholder) => quote_literal(placeholder.value(change).as_deref()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        template::{Part, Placeholder, QueryTemplate},
+    };
+
+    #[test]
+    fn test_query_template() {
+        let template = QueryTemplate::try_from("SELECT {{{lsn}}}, { op }").unwrap();
+        assert_eq!(
+            template.parts,
+            [
+                Part::Text("SELECT {".to_string()),
+                Part::Field(Placeholder::Lsn),
+                Part::Text("}, ".to_string()),
+                Part::Field(Placeholder::Op),
+            ]
+        );
+        assert!(QueryTemplate::try_from("SELECT {lsn").is_err());
+        assert!(QueryTemplate::try_from("SELECT lsn}").is_err());
+        assert_eq!(
+            QueryTemplate::try_from("SELECT {missing}"),
+            Err("Unknown placeholder {missing} in query template".to_string())
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_query_template() {
+        unsafe {
+            Spi::run("CREATE TABLE test_query_template (id int, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_query_template VALUES (1, 'it''s')");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        let (redo_query, revert_query) = Spi::get_two::<String, String>(&format!(
+            "SELECT redo_query, revert_query FROM pg_waldecoder('{startptr}', timeline => 1,
+                 query_template => 'INSERT INTO audit VALUES ({{op}}, {{relation_name}}, {{row_before}}, {{row_after}});')
+             WHERE relid = 'test_query_template'::regclass"
+        ))
+        .unwrap();
+        assert_eq!(
+            redo_query.as_deref(),
+            Some("INSERT INTO audit VALUES ('INSERT', 'test_query_template', NULL, '(1,it''s)');")
+        );
+        assert_eq!(revert_query, None);
+    }
+}
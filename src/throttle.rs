@@ -0,0 +1,97 @@
+use pgrx::pg_sys;
+
+use crate::guc;
+
+/// Cost of reading a WAL page, like a buffer miss for vacuum
+pub const PAGE_READ_COST: i32 = 2;
+/// Cost of decoding a record
+pub const RECORD_COST: i32 = 1;
+
+/// Balance after charging a cost, and whether the scan sleeps before going
+/// on
+fn charge_balance(balance: i32, cost: i32, limit: i32) -> (i32, bool) {
+    let balance = balance.saturating_add(cost);
+    if balance >= limit {
+        (0, true)
+    } else {
+        (balance, false)
+    }
+}
+
+/// Cost-based delay of a scan, like vacuum's. The pages read and the records
+/// decoded add up their cost, the scan sleeps `pg_waldecoder.cost_delay` once
+/// it reaches `pg_waldecoder.cost_limit`, keeping a large scan from
+/// saturating the disks of a busy server.
+pub struct CostDelay {
+    delay_ms: i32,
+    limit: i32,
+    balance: i32,
+}
+
+impl CostDelay {
+    pub fn new() -> CostDelay {
+        CostDelay {
+            delay_ms: guc::COST_DELAY.get(),
+            limit: guc::COST_LIMIT.get(),
+            balance: 0,
+        }
+    }
+
+    pub fn charge(&mut self, cost: i32) {
+        if self.delay_ms == 0 {
+            return;
+        }
+        let (balance, sleep) = charge_balance(self.balance, cost, self.limit);
+        self.balance = balance;
+        if !sleep {
+            return;
+        }
+        unsafe {
+            pg_sys::WaitLatch(
+                pg_sys::MyLatch,
+                (pg_sys::WL_EXIT_ON_PM_DEATH | pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT)
+                    .cast_signed(),
+                i64::from(self.delay_ms),
+                pg_sys::PG_WAIT_EXTENSION,
+            );
+            pg_sys::ResetLatch(pg_sys::MyLatch);
+        }
+        pg_sys::check_for_interrupts!();
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{pg_lsn::PgLSN, throttle::charge_balance};
+
+    #[test]
+    fn test_charge_balance() {
+        assert_eq!(charge_balance(0, 2, 200), (2, false));
+        assert_eq!(charge_balance(198, 2, 200), (0, true));
+        assert_eq!(charge_balance(i32::MAX, 2, 200), (0, true));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_cost_delay() {
+        unsafe {
+            Spi::run("CREATE TABLE test_cost_delay (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_cost_delay SELECT generate_series(1, 10)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        Spi::run("SET pg_waldecoder.cost_delay = 1").unwrap();
+        Spi::run("SET pg_waldecoder.cost_limit = 1").unwrap();
+        let rows = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder('{startptr}', timeline => 1)
+             WHERE relid = 'test_cost_delay'::regclass"
+        ));
+        assert_eq!(rows, Ok(Some(10)));
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use pg_waldecoder_core::lsn::segno_to_lsn;
+use pgrx::{
+    pg_sys::{
+        self,
+        RmgrIds::{RM_HEAP2_ID, RM_HEAP_ID},
+    },
+    prelude::*,
+    PgBox, PgMemoryContexts,
+};
+
+use crate::{
+    decoder::PageId,
+    guc,
+    page::{page_get_max_offset_number, page_set_all_visible},
+    page_cache::PageCache,
+    pg_lsn::PgLSN,
+    relation::{classify_database, RecordDatabase, RelidCache},
+    segments::{segments_dir, wal_segments},
+    tuple_str::{format_row, tuple_values},
+    walinspect::{lsn_bounds, wal_decoder},
+    xid::{xact_end, FIRST_NORMAL_TRANSACTION_ID},
+    xlog_heap::{apply_heap_record, get_heap_tuple, heap_op, restore_fpw},
+    xlog_reader::{get_block_tag_extended, has_block_image_to_apply},
+};
+
+/// Pages cached while replaying, the page followed and the other blocks of
+/// a record
+const REPLAY_CACHE_PAGES: usize = 4;
+
+/// Start of the oldest segment of `wal_dir`, on the timeline when given
+fn oldest_wal_lsn(timeline: Option<i32>, wal_dir: Option<&str>) -> PgLSN {
+    let (dir, segsz) = segments_dir(wal_dir);
+    let timeline = timeline.and_then(|timeline| u64::try_from(timeline).ok());
+    let Some(segment) = wal_segments(&dir, segsz)
+        .into_iter()
+        .filter(|segment| timeline.is_none_or(|timeline| segment.timeline == timeline))
+        .min_by_key(|segment| segment.segno)
+    else {
+        error!("No WAL segments found in wal dir {}", dir.display());
+    };
+    PgLSN::from(segno_to_lsn(segment.segno, segsz))
+}
+
+/// Block references of the latest decoded record, with their page
+fn record_blocks(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Vec<(u8, PageId, pg_sys::RelFileLocator, pg_sys::BlockNumber)> {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    (0..=max_block_id)
+        .filter_map(|block_id| {
+            let (rlocator, forknum, blknum) = get_block_tag_extended(xlog_reader, block_id)?;
+            let page_id = PageId::new(&rlocator, forknum, blknum);
+            Some((block_id, page_id, rlocator, blknum))
+        })
+        .collect()
+}
+
+/// Apply a record on the cached pages of the main fork blocks selected by
+/// `follows`, returns them with their block numbers. Heap records are
+/// replayed, the full page images of other records replace the pages. The
+/// other changes, like pruning, freezing or multi-inserts, aren't replayed
+/// and their pages are dropped rather than left stale, only the visibility
+/// flag set by `VISIBLE` records is applied. Only the followed pages are
+/// kept, the other blocks aren't replayed.
+fn replay_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+    mut follows: impl FnMut(&pg_sys::RelFileLocator, pg_sys::BlockNumber) -> bool,
+) -> Vec<(pg_sys::BlockNumber, PageId)> {
+    let blocks = record_blocks(xlog_reader, record);
+    let (followed, others): (Vec<_>, Vec<_>) =
+        blocks
+            .into_iter()
+            .partition(|(_, page_id, rlocator, blknum)| {
+                page_id.forknum() == pg_sys::ForkNumber::MAIN_FORKNUM
+                    && classify_database(rlocator) != RecordDatabase::Other
+                    && follows(rlocator, *blknum)
+            });
+    if followed.is_empty() {
+        return Vec::new();
+    }
+    if u32::from(record.header.xl_rmid) == RM_HEAP_ID {
+        apply_heap_record(xlog_reader, record, page_cache);
+    } else {
+        // Block 1 of a VISIBLE record is the heap page
+        let visible = u32::from(record.header.xl_rmid) == RM_HEAP2_ID
+            && heap_op(record) == pg_sys::XLOG_HEAP2_VISIBLE;
+        for (block_id, page_id, _, _) in &followed {
+            if has_block_image_to_apply(record, *block_id) {
+                let page = restore_fpw(xlog_reader, *block_id, page_id, page_cache);
+                page_cache.insert(*page_id, page);
+            } else if visible && *block_id == 1 {
+                if let Some(page) = page_cache.get(page_id) {
+                    unsafe { page_set_all_visible(page) };
+                }
+            } else {
+                page_cache.remove(page_id);
+            }
+        }
+    }
+    for (_, other, _, _) in &others {
+        page_cache.remove(other);
+    }
+    followed
+        .into_iter()
+        .map(|(_, page_id, _, blknum)| (blknum, page_id))
+        .collect()
+}
+
+/// Page of a relation's block as of `lsn`, rebuilt from the latest full page
+/// image written since `start_lsn`, or the oldest WAL of `wal_dir`, with the
+/// heap records up to `lsn` replayed on it. NULL when no full page image nor
+/// initialization of the page was found, or when a record that can't be
+/// replayed, like a prune or a multi-insert, changed it since without a full
+/// page image. The page can be inspected with
+/// pageinspect, like `heap_page_items(page)`.
+#[pg_extern]
+fn pg_waldecoder_page_at(
+    relid: pg_sys::Oid,
+    blkno: i64,
+    lsn: PgLSN,
+    start_lsn: default!(Option<PgLSN>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> Option<Vec<u8>> {
+    let Ok(blkno) = pg_sys::BlockNumber::try_from(blkno) else {
+        error!("Invalid block number {blkno}");
+    };
+    let start_lsn = start_lsn.unwrap_or_else(|| oldest_wal_lsn(timeline, wal_dir));
+    let end_lsn = lsn_bounds(start_lsn, lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut relids = RelidCache::new();
+    let mut page_cache = PageCache::new(
+        REPLAY_CACHE_PAGES,
+        PgMemoryContexts::CurrentMemoryContext,
+        false,
+        false,
+    );
+    let mut followed = None;
+    while let Some(record) = wal_decoder.read_record() {
+        let xlog_reader = wal_decoder.xlog_reader();
+        relids.invalidate_for(xlog_reader, &record);
+        let replayed = replay_record(xlog_reader, &record, &mut page_cache, |rlocator, blknum| {
+            blknum == blkno && relids.get(rlocator) == Some(relid)
+        });
+        if let Some(&(_, page_id)) = replayed.first() {
+            followed = Some(page_id);
+        }
+    }
+    let page = page_cache.peek(&followed?)?;
+    Some(unsafe { std::slice::from_raw_parts(page.cast::<u8>(), pg_sys::BLCKSZ as usize) }.to_vec())
+}
+
+/// Whether a transaction is committed as of the replayed LSN, from its commit
+/// or abort record. A transaction that wrote records without ending is in
+/// progress, the others ended before the scan and the hint bits, when set,
+/// tell whether they committed.
+fn xid_committed(
+    xid: pg_sys::TransactionId,
+    hint: Option<bool>,
+    ends: &HashMap<u32, bool>,
+    writers: &HashSet<u32>,
+) -> bool {
+    // The bootstrap and frozen xids are committed
+    if xid.into_inner() < FIRST_NORMAL_TRANSACTION_ID {
+        return xid != pg_sys::InvalidTransactionId;
+    }
+    if let Some(&committed) = ends.get(&xid.into_inner()) {
+        return committed;
+    }
+    !writers.contains(&xid.into_inner()) && hint.unwrap_or(true)
+}
+
+/// Whether a tuple is visible as of the replayed LSN: inserted by a committed
+/// transaction and not deleted nor updated by one. The updater of a tuple
+/// locked by several transactions isn't known, the tuple is kept.
+fn tuple_visible(
+    infomask: u16,
+    xmin: pg_sys::TransactionId,
+    xmax: pg_sys::TransactionId,
+    ends: &HashMap<u32, bool>,
+    writers: &HashSet<u32>,
+) -> bool {
+    let infomask = u32::from(infomask);
+    let hint = |committed: u32, invalid: u32| {
+        if infomask & committed != 0 {
+            Some(true)
+        } else if infomask & invalid != 0 {
+            Some(false)
+        } else {
+            None
+        }
+    };
+    let frozen = pg_sys::HEAP_XMIN_COMMITTED | pg_sys::HEAP_XMIN_INVALID;
+    if infomask & frozen != frozen {
+        let xmin_hint = hint(pg_sys::HEAP_XMIN_COMMITTED, pg_sys::HEAP_XMIN_INVALID);
+        if !xid_committed(xmin, xmin_hint, ends, writers) {
+            return false;
+        }
+    }
+    let lock_mask = pg_sys::HEAP_XMAX_EXCL_LOCK | pg_sys::HEAP_XMAX_KEYSHR_LOCK;
+    let locked_only = infomask & pg_sys::HEAP_XMAX_LOCK_ONLY != 0
+        || infomask & (pg_sys::HEAP_XMAX_IS_MULTI | lock_mask) == pg_sys::HEAP_XMAX_EXCL_LOCK;
+    if infomask & (pg_sys::HEAP_XMAX_INVALID | pg_sys::HEAP_XMAX_IS_MULTI) != 0
+        || locked_only
+        || xmax == pg_sys::InvalidTransactionId
+    {
+        return true;
+    }
+    let xmax_hint = hint(pg_sys::HEAP_XMAX_COMMITTED, pg_sys::HEAP_XMAX_INVALID);
+    !xid_committed(xmax, xmax_hint, ends, writers)
+}
+
+/// Rows of a relation visible as of `lsn`, from its pages rebuilt like
+/// `pg_waldecoder_page_at` and the transactions committed by `lsn`, in
+/// `record_out` format. The blocks of the relation without a full page image
+/// nor initialization since `start_lsn`, or the oldest WAL of `wal_dir`, and
+/// those changed since by records that can't be replayed, can't be rebuilt
+/// and their rows are missing, a warning counts them. Transactions ended before
+/// the scan are taken as committed unless the hint bits of the tuple tell
+/// otherwise.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_table_at(
+    relid: pg_sys::Oid,
+    lsn: PgLSN,
+    start_lsn: default!(Option<PgLSN>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(xmin, pg_sys::TransactionId),
+        name!(xmax, pg_sys::TransactionId),
+        name!(row, String),
+    ),
+> {
+    let start_lsn = start_lsn.unwrap_or_else(|| oldest_wal_lsn(timeline, wal_dir));
+    let end_lsn = lsn_bounds(start_lsn, lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut relids = RelidCache::new();
+    // Every page of the relation is kept, the evicted ones are spilled
+    let mut page_cache = PageCache::new(
+        usize::try_from(guc::PAGE_CACHE_SIZE.get()).unwrap_or(1),
+        PgMemoryContexts::CurrentMemoryContext,
+        false,
+        true,
+    );
+    let mut pages = BTreeMap::new();
+    let mut ends = HashMap::new();
+    let mut writers = HashSet::new();
+    while let Some(record) = wal_decoder.read_record() {
+        let xlog_reader = wal_decoder.xlog_reader();
+        writers.insert(record.header.xl_xid.into_inner());
+        if let Some(end) = xact_end(&record) {
+            for xid in std::iter::once(end.xid).chain(end.subxacts) {
+                ends.insert(xid.into_inner(), end.committed);
+            }
+            continue;
+        }
+        relids.invalidate_for(xlog_reader, &record);
+        let replayed = replay_record(xlog_reader, &record, &mut page_cache, |rlocator, _| {
+            relids.get(rlocator) == Some(relid)
+        });
+        pages.extend(replayed);
+    }
+
+    let rel = unsafe { PgRelation::with_lock(relid, pg_sys::AccessShareLock.cast_signed()) };
+    let tupdesc = rel.tuple_desc();
+    let mut rows = Vec::new();
+    let mut missing = 0;
+    // The blocks of the relation now, and the rebuilt ones past its end
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(rel.as_ptr(), pg_sys::ForkNumber::MAIN_FORKNUM)
+    };
+    let blknums: BTreeSet<_> = (0..nblocks).chain(pages.keys().copied()).collect();
+    for blknum in blknums {
+        let Some(page) = pages
+            .get(&blknum)
+            .and_then(|page_id| page_cache.get(page_id))
+        else {
+            missing += 1;
+            continue;
+        };
+        for offnum in 1..=unsafe { page_get_max_offset_number(page) } {
+            let Some(tuple) = get_heap_tuple(page, blknum, offnum, relid) else {
+                continue;
+            };
+            let (infomask, xmin, xmax) = unsafe {
+                let header = (*tuple).t_data;
+                let fields = (*header).t_choice.t_heap;
+                ((*header).t_infomask, fields.t_xmin, fields.t_xmax)
+            };
+            if tuple_visible(infomask, xmin, xmax, &ends, &writers) {
+                let row = format_row(&tuple_values(&tupdesc, tuple));
+                rows.push((unsafe { (*tuple).t_self }, xmin, xmax, row));
+            }
+        }
+    }
+    if missing > 0 {
+        warning!("{missing} blocks of the relation couldn't be rebuilt, their rows are missing");
+    }
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use pgrx::prelude::*;
+
+    use crate::{pg_lsn::PgLSN, time_travel::tuple_visible};
+
+    /// Number of line pointers of a page, from `pd_lower`
+    fn page_items(page: &[u8]) -> usize {
+        (usize::from(u16::from_ne_bytes([page[12], page[13]])) - 24) / 4
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_page_at() {
+        unsafe {
+            Spi::run("CREATE TABLE test_page_at (id int);");
+            Spi::run("INSERT INTO test_page_at VALUES (1)");
+            Spi::run("CHECKPOINT");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The first change after the checkpoint logs the page
+            Spi::run("UPDATE test_page_at SET id = 2");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let midptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_page_at VALUES (3)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let page_at = |lsn: PgLSN| {
+            Spi::get_one::<Vec<u8>>(&format!(
+                "SELECT pg_waldecoder_page_at('test_page_at'::regclass, 0, '{lsn}', '{startptr}', 1)"
+            ))
+            .unwrap()
+        };
+        assert_eq!(page_at(startptr), None);
+        assert_eq!(page_at(midptr).map(|page| page_items(&page)), Some(2));
+        assert_eq!(page_at(endptr).map(|page| page_items(&page)), Some(3));
+
+        // The rows copied are logged by a multi-insert, which isn't replayed
+        unsafe {
+            Spi::run("COPY test_page_at FROM PROGRAM 'printf \"4\\n5\\n\"'");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let copyptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        assert_eq!(page_at(copyptr), None);
+    }
+
+    #[test]
+    fn test_tuple_visible() {
+        let xid = pg_sys::TransactionId::from;
+        let ends = HashMap::from([(750, true), (751, false)]);
+        let writers = HashSet::from([750, 751, 752]);
+        let none = pg_sys::InvalidTransactionId;
+        let flag = |flag: u32| u16::try_from(flag).unwrap();
+        let live = flag(pg_sys::HEAP_XMAX_INVALID);
+        // Committed, aborted and in progress inserts
+        assert!(tuple_visible(live, xid(750), none, &ends, &writers));
+        assert!(!tuple_visible(live, xid(751), none, &ends, &writers));
+        assert!(!tuple_visible(live, xid(752), none, &ends, &writers));
+        // Inserted before the scan, unless the hint bits tell it aborted
+        assert!(tuple_visible(live, xid(700), none, &ends, &writers));
+        let aborted = live | flag(pg_sys::HEAP_XMIN_INVALID);
+        assert!(!tuple_visible(aborted, xid(700), none, &ends, &writers));
+        assert!(tuple_visible(live, xid(2), none, &ends, &writers));
+        // Deleted by a committed transaction, not by an aborted one nor a lock
+        assert!(!tuple_visible(0, xid(700), xid(750), &ends, &writers));
+        assert!(tuple_visible(0, xid(700), xid(751), &ends, &writers));
+        assert!(tuple_visible(0, xid(700), xid(752), &ends, &writers));
+        let locked = flag(pg_sys::HEAP_XMAX_LOCK_ONLY);
+        assert!(tuple_visible(locked, xid(700), xid(750), &ends, &writers));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_table_at() {
+        // The changes are committed by another session
+        Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").unwrap();
+        let conninfo = Spi::get_one::<String>(
+            "SELECT format('host=%s port=%s dbname=%s',
+                 split_part(current_setting('unix_socket_directories'), ',', 1),
+                 current_setting('port'), current_database())",
+        )
+        .unwrap()
+        .unwrap();
+        let exec = |sql: &str| {
+            Spi::run(&format!(
+                "SELECT dblink_exec('{conninfo}', $sql${sql}$sql$)"
+            ))
+            .unwrap();
+        };
+        // Two blocks of rows
+        exec(
+            "DROP TABLE IF EXISTS test_table_at;
+             CREATE TABLE test_table_at (id int);
+             INSERT INTO test_table_at SELECT generate_series(1, 300)",
+        );
+        Spi::run("CHECKPOINT").unwrap();
+        let startptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+        // Only the first block is logged
+        exec("DELETE FROM test_table_at WHERE id = 1");
+        let endptr = Spi::get_one::<PgLSN>("SELECT pg_current_wal_insert_lsn()")
+            .unwrap()
+            .unwrap();
+
+        let rows = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_waldecoder_table_at('test_table_at'::regclass, '{endptr}', '{startptr}', 1)"
+        ));
+        let (first_block, total) = Spi::get_two::<i64, i64>(
+            "SELECT count(*) FILTER (WHERE (ctid::text::point)[0] = 0), count(*)
+             FROM test_table_at",
+        )
+        .unwrap();
+        exec("DROP TABLE test_table_at");
+        // The rows of the second block, never logged, are missing
+        assert!(first_block < total);
+        assert_eq!(rows, Ok(first_block));
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs;
+
+use pgrx::pg_sys::{TimeLineID, XLogSegNo};
+use thiserror::Error;
+
+use crate::{
+    pg_lsn::{filename_to_startptr, xlog_file_name, PgLSN},
+    wal::{find_segment_file, is_xlog_file_name, WalSource},
+};
+
+const HISTORY_SUFFIX: &str = ".history";
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum InvalidHistory {
+    #[error("Could not read history file {0}: {1}")]
+    ReadError(String, String),
+    #[error("Syntax error in history file {0}: {1}")]
+    SyntaxError(String, String),
+    #[error("Invalid data in history file {0}: timeline IDs must be in increasing sequence")]
+    InvalidSequence(String),
+}
+
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
+pub enum TimelineMismatch {
+    #[error("Segment {0} belongs to timeline {1}, you asked for {2}")]
+    PageTimeline(String, TimeLineID, TimeLineID),
+    #[error("Segment {0} not found, segment {1} belongs to timeline {2}, you asked for {3}")]
+    OtherTimeline(String, String, TimeLineID, TimeLineID),
+}
+
+/// A timeline and the range of WAL it covers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimelineHistoryEntry {
+    pub tli: TimeLineID,
+    pub begin: PgLSN,
+    /// Switch point to the next timeline, None for the target timeline
+    pub end: Option<PgLSN>,
+}
+
+/// Returns the history file name of a timeline
+pub fn history_file_name(tli: TimeLineID) -> String {
+    format!("{tli:08X}{HISTORY_SUFFIX}")
+}
+
+/// Parse the content of a timeline history file.
+///
+/// Each line contains the parent timeline, the switch point and a free form
+/// reason. Entries are returned from the oldest timeline to the target one.
+pub fn parse_timeline_history(
+    fname: &str,
+    content: &str,
+    target_tli: TimeLineID,
+) -> Result<Vec<TimelineHistoryEntry>, InvalidHistory> {
+    let mut entries = Vec::new();
+    let mut prev_end = PgLSN::from(0u64);
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(tli_str), Some(switchpoint_str)) = (fields.next(), fields.next()) else {
+            return Err(InvalidHistory::SyntaxError(
+                fname.to_string(),
+                line.to_string(),
+            ));
+        };
+        let Ok(tli) = tli_str.parse::<TimeLineID>() else {
+            return Err(InvalidHistory::SyntaxError(
+                fname.to_string(),
+                line.to_string(),
+            ));
+        };
+        let Ok(switchpoint) = PgLSN::try_from(switchpoint_str) else {
+            return Err(InvalidHistory::SyntaxError(
+                fname.to_string(),
+                line.to_string(),
+            ));
+        };
+        if entries
+            .last()
+            .is_some_and(|e: &TimelineHistoryEntry| tli <= e.tli)
+        {
+            return Err(InvalidHistory::InvalidSequence(fname.to_string()));
+        }
+        entries.push(TimelineHistoryEntry {
+            tli,
+            begin: prev_end,
+            end: Some(switchpoint),
+        });
+        prev_end = switchpoint;
+    }
+    if entries.last().is_some_and(|e| target_tli <= e.tli) {
+        return Err(InvalidHistory::InvalidSequence(fname.to_string()));
+    }
+    entries.push(TimelineHistoryEntry {
+        tli: target_tli,
+        begin: prev_end,
+        end: None,
+    });
+    Ok(entries)
+}
+
+/// Read the history of the target timeline from the WAL directories.
+///
+/// Like the server, a missing history file means the target timeline has no
+/// parent.
+pub fn read_timeline_history(
+    source: &WalSource,
+    target_tli: TimeLineID,
+) -> Result<Vec<TimelineHistoryEntry>, InvalidHistory> {
+    let fname = history_file_name(target_tli);
+    let path = source.find(&fname);
+    let Some(path) = path.filter(|_| target_tli != 1) else {
+        return Ok(vec![TimelineHistoryEntry {
+            tli: target_tli,
+            begin: PgLSN::from(0u64),
+            end: None,
+        }]);
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Err(InvalidHistory::ReadError(
+                path.display().to_string(),
+                e.to_string(),
+            ))
+        }
+    };
+    parse_timeline_history(&fname, &content, target_tli)
+}
+
+/// Returns the latest timeline with a history file or a segment in the WAL
+/// source
+pub fn find_latest_timeline(source: &WalSource) -> TimeLineID {
+    source
+        .files()
+        .into_iter()
+        .filter_map(|(file_name, _)| {
+            let tli_str = file_name.strip_suffix(HISTORY_SUFFIX)?;
+            TimeLineID::from_str_radix(tli_str, 16).ok()
+        })
+        .chain(wal_file_timelines(source))
+        .max()
+        .unwrap_or(1)
+}
+
+/// Timelines of the segment files in the WAL source, in increasing order
+pub fn wal_file_timelines(source: &WalSource) -> Vec<TimeLineID> {
+    let mut timelines: Vec<TimeLineID> = source
+        .files()
+        .into_iter()
+        .filter_map(|(file_name, _)| {
+            if !is_xlog_file_name(&file_name) {
+                return None;
+            }
+            TimeLineID::from_str_radix(&file_name[..8], 16).ok()
+        })
+        .collect();
+    timelines.sort_unstable();
+    timelines.dedup();
+    timelines
+}
+
+/// Returns the timeline owning the provided LSN
+pub fn tli_of_point(history: &[TimelineHistoryEntry], ptr: PgLSN) -> TimeLineID {
+    for entry in history {
+        if entry.end.is_none_or(|end| ptr < end) {
+            return entry.tli;
+        }
+    }
+    // The last entry is always the target timeline with an open end
+    history.last().map_or(1, |e| e.tli)
+}
+
+/// Returns the latest of the provided timelines with the segment in the WAL
+/// source. Without history files, a segment containing a switch point
+/// is complete only on the child timeline, which comes first.
+pub fn segment_timeline(
+    timelines: &[TimeLineID],
+    source: &WalSource,
+    segno: XLogSegNo,
+    segsz: i32,
+) -> Option<TimeLineID> {
+    let mut timelines = timelines.to_vec();
+    timelines.sort_unstable_by(|a, b| b.cmp(a));
+    timelines
+        .into_iter()
+        .find(|tli| find_segment_file(source, &xlog_file_name(*tli, segno, segsz)).is_some())
+}
+
+/// Check that a page read from the segment of a timeline wasn't written on a
+/// later timeline. Pages copied from a parent timeline at a promotion keep
+/// their older timeline.
+pub fn check_page_timeline(
+    fname: &str,
+    page_tli: TimeLineID,
+    tli: TimeLineID,
+) -> Result<(), TimelineMismatch> {
+    if page_tli > tli {
+        return Err(TimelineMismatch::PageTimeline(
+            fname.to_string(),
+            page_tli,
+            tli,
+        ));
+    }
+    Ok(())
+}
+
+/// Timelines with the segment in the WAL source, in increasing order
+pub fn segment_timelines(source: &WalSource, segno: XLogSegNo, segsz: u32) -> Vec<TimeLineID> {
+    let mut timelines: Vec<TimeLineID> = source
+        .files()
+        .into_iter()
+        .filter_map(|(file_name, _)| {
+            if !is_xlog_file_name(&file_name) {
+                return None;
+            }
+            let (tli, file_segno) = filename_to_startptr(&file_name, u64::from(segsz)).ok()?;
+            (file_segno == segno).then_some(TimeLineID::try_from(tli).ok()?)
+        })
+        .collect();
+    timelines.sort_unstable();
+    timelines.dedup();
+    timelines
+}
+
+/// Error for a segment missing on the requested timeline but found on
+/// another one
+pub fn missing_segment_mismatch(
+    source: &WalSource,
+    tli: TimeLineID,
+    segno: XLogSegNo,
+    segsz: i32,
+) -> Option<TimelineMismatch> {
+    let other = *segment_timelines(source, segno, segsz.cast_unsigned()).last()?;
+    Some(TimelineMismatch::OtherTimeline(
+        xlog_file_name(tli, segno, segsz),
+        xlog_file_name(other, segno, segsz),
+        other,
+        tli,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        timeline::{
+            check_page_timeline, find_latest_timeline, missing_segment_mismatch,
+            parse_timeline_history, segment_timeline, segment_timelines, tli_of_point,
+            wal_file_timelines, TimelineHistoryEntry, TimelineMismatch,
+        },
+        wal::WalSource,
+    };
+
+    #[test]
+    fn test_parse_timeline_history() {
+        let content =
+            "1\t0/3000000\tno recovery target specified\n\n2\t0/5000A28\tbefore 2025-01-01\n";
+        let history = parse_timeline_history("00000003.history", content, 3).unwrap();
+        assert_eq!(
+            history,
+            vec![
+                TimelineHistoryEntry {
+                    tli: 1,
+                    begin: PgLSN::from(0u64),
+                    end: Some(PgLSN::from(0x3000000u64)),
+                },
+                TimelineHistoryEntry {
+                    tli: 2,
+                    begin: PgLSN::from(0x3000000u64),
+                    end: Some(PgLSN::from(0x5000A28u64)),
+                },
+                TimelineHistoryEntry {
+                    tli: 3,
+                    begin: PgLSN::from(0x5000A28u64),
+                    end: None,
+                },
+            ]
+        );
+
+        assert_eq!(tli_of_point(&history, PgLSN::from(0x2FFFFFFu64)), 1);
+        assert_eq!(tli_of_point(&history, PgLSN::from(0x3000000u64)), 2);
+        assert_eq!(tli_of_point(&history, PgLSN::from(0x6000000u64)), 3);
+    }
+
+    #[test]
+    fn test_segment_timeline() {
+        let wal_dirs = WalSource::from_dirs(vec![Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/test/18_single_upgrade"
+        ))
+        .to_path_buf()]);
+        let segsz = 1024 * 1024;
+        assert_eq!(segment_timeline(&[1, 3], &wal_dirs, 0x18, segsz), Some(1));
+        assert_eq!(segment_timeline(&[2, 3], &wal_dirs, 0x18, segsz), None);
+        assert_eq!(segment_timeline(&[1], &wal_dirs, 0x19, segsz), None);
+
+        assert_eq!(segment_timelines(&wal_dirs, 0x18, 1024 * 1024), [1]);
+        assert_eq!(
+            missing_segment_mismatch(&wal_dirs, 3, 0x18, segsz)
+                .unwrap()
+                .to_string(),
+            "Segment 000000030000000000000018 not found, segment 000000010000000000000018 belongs to timeline 1, you asked for 3"
+        );
+        assert!(missing_segment_mismatch(&wal_dirs, 3, 0x19, segsz).is_none());
+
+        // Without history file, the timeline comes from the segment names
+        assert_eq!(wal_file_timelines(&wal_dirs), [1]);
+        assert_eq!(find_latest_timeline(&wal_dirs), 1);
+    }
+
+    #[test]
+    fn test_check_page_timeline() {
+        assert!(check_page_timeline("000000010000000000000003", 1, 1).is_ok());
+        // Copied from the parent timeline at the promotion
+        assert!(check_page_timeline("000000020000000000000003", 1, 2).is_ok());
+        assert_eq!(
+            check_page_timeline("000000010000000000000003", 3, 1),
+            Err(TimelineMismatch::PageTimeline(
+                "000000010000000000000003".to_string(),
+                3,
+                1
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_timeline_history() {
+        assert!(parse_timeline_history("00000002.history", "1\tnot_a_lsn", 2).is_err());
+        assert!(parse_timeline_history("00000002.history", "2\t0/3000000", 2).is_err());
+    }
+}
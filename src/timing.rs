@@ -0,0 +1,153 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use pg_waldecoder_core::record::rmgr_name;
+use pgrx::prelude::*;
+
+use crate::guc::TRACK_TIMING;
+
+/// Step of the decoding of a record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the record and the pages it spans
+    ReadRecord,
+    RestoreFpi,
+    ResolveRelid,
+    /// Deforming tuples and rendering their values
+    BuildTuple,
+}
+
+/// Time spent decoding the records of a resource manager
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RmgrTiming {
+    pub records: u64,
+    pub read_record: Duration,
+    pub restore_fpi: Duration,
+    pub resolve_relid: Duration,
+    pub build_tuple: Duration,
+}
+
+impl RmgrTiming {
+    fn add(&mut self, phase: Phase, elapsed: Duration) {
+        let total = match phase {
+            Phase::ReadRecord => &mut self.read_record,
+            Phase::RestoreFpi => &mut self.restore_fpi,
+            Phase::ResolveRelid => &mut self.resolve_relid,
+            Phase::BuildTuple => &mut self.build_tuple,
+        };
+        *total += elapsed;
+    }
+}
+
+thread_local! {
+    /// Timings of the latest decoding of the backend, by rmgr id
+    static TIMINGS: RefCell<BTreeMap<u8, RmgrTiming>> = const { RefCell::new(BTreeMap::new()) };
+    /// Rmgr of the record being decoded
+    static CURRENT_RMGR: Cell<u8> = const { Cell::new(0) };
+}
+
+/// Forget the timings of the previous decoding
+pub fn reset_timings() {
+    TIMINGS.with_borrow_mut(BTreeMap::clear);
+}
+
+/// Start timing the read of a record, None when timing isn't tracked
+pub fn start_read() -> Option<Instant> {
+    TRACK_TIMING.get().then(Instant::now)
+}
+
+/// Account a record read since `start`, the following phases are attributed
+/// to its rmgr
+pub fn record_read(rmid: u8, start: Option<Instant>) {
+    CURRENT_RMGR.set(rmid);
+    let Some(start) = start else {
+        return;
+    };
+    TIMINGS.with_borrow_mut(|timings| {
+        let timing = timings.entry(rmid).or_default();
+        timing.records += 1;
+        timing.add(Phase::ReadRecord, start.elapsed());
+    });
+}
+
+/// Run `f`, adding its duration to the phase of the current record's rmgr
+pub fn timed<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !TRACK_TIMING.get() {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    TIMINGS.with_borrow_mut(|timings| {
+        timings
+            .entry(CURRENT_RMGR.get())
+            .or_default()
+            .add(phase, elapsed);
+    });
+    result
+}
+
+fn to_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Time spent in each step of the latest decoding of the session, by resource
+/// manager. Only tracked with `pg_waldecoder.track_timing` enabled.
+#[pg_extern(parallel_restricted)]
+fn pg_waldecoder_timing() -> TableIterator<
+    'static,
+    (
+        name!(rmgr, String),
+        name!(records, i64),
+        name!(read_record_ms, f64),
+        name!(restore_fpi_ms, f64),
+        name!(resolve_relid_ms, f64),
+        name!(build_tuple_ms, f64),
+    ),
+> {
+    let timings = TIMINGS.with_borrow(Clone::clone);
+    TableIterator::new(timings.into_iter().map(|(rmid, timing)| {
+        (
+            rmgr_name(rmid),
+            i64::try_from(timing.records).unwrap_or(i64::MAX),
+            to_ms(timing.read_record),
+            to_ms(timing.restore_fpi),
+            to_ms(timing.resolve_relid),
+            to_ms(timing.build_tuple),
+        )
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{DecoderOptions, PgLSN, WalDecoder};
+
+    #[pg_test]
+    fn test_pg_waldecoder_timing() {
+        unsafe {
+            Spi::run("CREATE TABLE test_timing (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_timing values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+
+        Spi::run("SET pg_waldecoder.track_timing = on").unwrap();
+        let options = DecoderOptions {
+            timeline: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(WalDecoder::new(startptr, &options).take(1).count(), 1);
+        let records =
+            Spi::get_one::<i64>("SELECT records FROM pg_waldecoder_timing() WHERE rmgr = 'Heap'");
+        assert_eq!(records, Ok(Some(1)));
+    }
+}
@@ -0,0 +1,171 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use pgrx::{
+    pg_sys::{self, HeapTuple},
+    FromDatum, PgTupleDesc,
+};
+
+/// Bits of `va_extinfo` holding the stored size, the others are the
+/// compression method
+const VARLENA_EXTSIZE_MASK: u32 = (1 << 30) - 1;
+
+/// `va_header` flag of an inline compressed varlena
+const VARATT_4B_C: u32 = 0x02;
+
+/// Chunks of the values written in toast tables by the decoded records, to
+/// rebuild the toasted values of the rows of their owning table. A value is
+/// dropped once the row using it was decoded.
+#[derive(Default)]
+pub struct ToastChunks {
+    /// Chunks by toast relation and value id, with their sequence number
+    values: HashMap<(pg_sys::Oid, pg_sys::Oid), Vec<(i32, Vec<u8>)>>,
+    /// Size of the chunks kept
+    bytes: usize,
+    /// Values fetched by the row being decoded
+    fetched: RefCell<Vec<(pg_sys::Oid, pg_sys::Oid)>>,
+}
+
+impl ToastChunks {
+    /// Keep the chunk of a tuple inserted in the toast table `toastrelid`
+    pub fn insert(&mut self, toastrelid: pg_sys::Oid, tupdesc: &PgTupleDesc, tuple: HeapTuple) {
+        // chunk_id, chunk_seq and chunk_data
+        let mut values = [pg_sys::Datum::from(0); 3];
+        let mut isnull = [true; 3];
+        if tupdesc.len() != values.len() {
+            return;
+        }
+        unsafe {
+            pg_sys::heap_deform_tuple(
+                tuple,
+                tupdesc.as_ptr(),
+                values.as_mut_ptr(),
+                isnull.as_mut_ptr(),
+            );
+        }
+        let chunk = unsafe {
+            (
+                pg_sys::Oid::from_datum(values[0], isnull[0]),
+                i32::from_datum(values[1], isnull[1]),
+                Vec::<u8>::from_datum(values[2], isnull[2]),
+            )
+        };
+        if let (Some(value_id), Some(chunk_seq), Some(chunk_data)) = chunk {
+            self.bytes += chunk_data.len();
+            self.values
+                .entry((toastrelid, value_id))
+                .or_default()
+                .push((chunk_seq, chunk_data));
+        }
+    }
+
+    /// Value of an on-disk toast pointer rebuilt from its chunks and
+    /// decompressed, None when some of its chunks weren't decoded
+    pub fn fetch(&self, pointer: pg_sys::Datum) -> Option<pg_sys::Datum> {
+        // The pointer follows the 1 byte header and the tag
+        let external = unsafe {
+            std::ptr::read_unaligned(
+                pointer
+                    .cast_mut_ptr::<u8>()
+                    .add(2)
+                    .cast::<pg_sys::varatt_external>(),
+            )
+        };
+        let key = (external.va_toastrelid, external.va_valueid);
+        let chunks = self.values.get(&key)?;
+        self.fetched.borrow_mut().push(key);
+        let extsize = usize::try_from(external.va_extinfo & VARLENA_EXTSIZE_MASK).unwrap();
+        let mut data = Vec::with_capacity(extsize);
+        let mut chunks = chunks.iter().collect::<Vec<_>>();
+        chunks.sort_by_key(|(chunk_seq, _)| *chunk_seq);
+        for (expected_seq, (chunk_seq, chunk_data)) in (0..).zip(chunks) {
+            if *chunk_seq != expected_seq {
+                return None;
+            }
+            data.extend_from_slice(chunk_data);
+        }
+        if data.len() != extsize {
+            return None;
+        }
+
+        // Inline varlena, compressed when less than the raw size was stored
+        let varhdrsz = std::mem::size_of::<u32>();
+        let compressed = extsize + varhdrsz < usize::try_from(external.va_rawsize).unwrap_or(0);
+        let total = u32::try_from(extsize + varhdrsz).ok()?;
+        let header = (total << 2) | if compressed { VARATT_4B_C } else { 0 };
+        unsafe {
+            let varlena = pg_sys::palloc(extsize + varhdrsz).cast::<u8>();
+            std::ptr::write_unaligned(varlena.cast::<u32>(), header);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), varlena.add(varhdrsz), extsize);
+            let value = pg_sys::pg_detoast_datum(varlena.cast());
+            Some(pg_sys::Datum::from(value))
+        }
+    }
+
+    /// Drop the values fetched by the row just decoded
+    pub fn evict_fetched(&mut self) {
+        for key in self.fetched.take() {
+            if let Some(chunks) = self.values.remove(&key) {
+                self.bytes -= chunks.iter().map(|(_, data)| data.len()).sum::<usize>();
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::pg_lsn::PgLSN;
+
+    #[pg_test]
+    fn test_pg_waldecoder_toast() {
+        unsafe {
+            Spi::run("CREATE TABLE test_toast (id int, data text, compressed text);");
+            Spi::run("ALTER TABLE test_toast ALTER data SET STORAGE EXTERNAL;");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // data is toasted as is, compressed once compressed
+            Spi::run(
+                "INSERT INTO test_toast SELECT 1, repeat('abc', 5000), string_agg(md5(i::text), '')
+                 FROM generate_series(1, 2000) i",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let consistent = Spi::get_one::<bool>(&format!(
+            "SELECT w.row_after = t::text FROM pg_waldecoder('{startptr}', '{endptr}', 1) w, test_toast t
+             WHERE w.relid = 'test_toast'::regclass"
+        ));
+        assert_eq!(consistent, Ok(Some(true)));
+        // The chunks are dropped once their row is decoded
+        let toast_chunk_bytes =
+            Spi::get_one::<i64>("SELECT toast_chunk_bytes FROM pg_waldecoder_memory()");
+        assert_eq!(toast_chunk_bytes, Ok(Some(0)));
+
+        // The toast table of a filtered relation is decoded with it
+        let consistent = Spi::get_one::<bool>(&format!(
+            "SELECT w.row_after = t::text FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                 relations => ARRAY['test_toast']) w, test_toast t
+             WHERE w.relid = 'test_toast'::regclass"
+        ));
+        assert_eq!(consistent, Ok(Some(true)));
+
+        let query = |include_toast_chunks: bool| {
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                     include_toast_chunks => {include_toast_chunks})
+                 WHERE schema_name = 'pg_toast'"
+            ))
+        };
+        assert!(query(true).unwrap().unwrap() > 0);
+        assert_eq!(query(false), Ok(Some(0)));
+    }
+}
@@ -1,42 +1,636 @@
-use std::num::NonZeroUsize;
+use std::{
+    ffi::{CStr, CString},
+    fmt::Write,
+};
 
 use pgrx::{
     pg_sys::{self, HeapTuple},
     prelude::*,
-    PgBox, PgTupleDesc,
+    IntoDatum, PgTryBuilder, PgTupleDesc,
 };
 
-pub fn append_values(
-    mut buffer: String,
-    new_tuple: &PgHeapTuple<AllocatedByRust>,
-    old_tuple: Option<&PgHeapTuple<AllocatedByRust>>,
-) {
-    for (i, attr) in new_tuple.attributes() {
+use crate::{notify::json_string, s3::hex_encode, toast::ToastChunks};
+
+/// On-disk toast pointer tag, `VARTAG_ONDISK`
+const VARTAG_ONDISK: u8 = 18;
+
+/// A column of a decoded tuple with its value rendered by the type's output
+/// function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnValue {
+    pub name: String,
+    pub value: Option<String>,
+    /// Generated columns can't be written
+    pub generated: bool,
+    /// `GENERATED ALWAYS AS IDENTITY` columns need `OVERRIDING SYSTEM VALUE`
+    pub identity_always: bool,
+}
+
+/// Returns true if the varlena is a pointer to a value stored in a toast table
+fn is_external_ondisk(datum: pg_sys::Datum) -> bool {
+    let ptr = datum.cast_mut_ptr::<u8>();
+    unsafe { *ptr == 0x01 && *ptr.add(1) == VARTAG_ONDISK }
+}
+
+/// Alignment in bytes of an `attalign` code
+fn type_alignment(attalign: u8) -> usize {
+    match attalign {
+        b'd' => 8,
+        b'i' => 4,
+        b's' => 2,
+        _ => 1,
+    }
+}
+
+/// Whether the tuple can have been written with the columns of the
+/// descriptor. Tuples of a page may lack the columns added after they were
+/// written, `complete` requires all the descriptor's columns. The length of
+/// tuples without NULLs nor varlenas must be the aligned width of their
+/// columns.
+pub fn tuple_fits_desc(tupdesc: &PgTupleDesc, tuple: HeapTuple, complete: bool) -> bool {
+    let (header, t_len) = unsafe { (&*(*tuple).t_data, (*tuple).t_len) };
+    let natts_mask = u16::try_from(pg_sys::HEAP_NATTS_MASK).unwrap();
+    let natts = usize::from(header.t_infomask2 & natts_mask);
+    if natts > tupdesc.len() || (complete && natts != tupdesc.len()) {
+        return false;
+    }
+    if u32::from(header.t_infomask) & (pg_sys::HEAP_HASNULL | pg_sys::HEAP_HASVARWIDTH) != 0 {
+        return true;
+    }
+    let mut width = 0;
+    for attr in tupdesc.iter().take(natts) {
+        // A varlena would have set HEAP_HASVARWIDTH
+        let Ok(attlen) = usize::try_from(attr.attlen) else {
+            return false;
+        };
+        width = width.next_multiple_of(type_alignment(attr.attalign.cast_unsigned())) + attlen;
+    }
+    usize::try_from(t_len).unwrap() == width + usize::from(header.t_hoff)
+}
+
+/// Convert a value from `encoding` to the current database's. A value that
+/// isn't valid in `encoding` or can't be represented in the current one is
+/// rendered as bytea, with a warning naming its encoding.
+fn convert_encoding(value: &CStr, encoding: i32, column: &str) -> String {
+    let len = i32::try_from(value.count_bytes()).unwrap();
+    let converted = PgTryBuilder::new(|| unsafe {
+        let out = pg_sys::pg_do_encoding_conversion(
+            value.as_ptr().cast_mut().cast(),
+            len,
+            encoding,
+            pg_sys::GetDatabaseEncoding(),
+        );
+        Some(CStr::from_ptr(out.cast()).to_string_lossy().into_owned())
+    })
+    .catch_others(|_| None)
+    .execute();
+    converted.unwrap_or_else(|| {
+        let name = unsafe { CStr::from_ptr(pg_sys::pg_encoding_to_char(encoding)) };
+        warning!(
+            "Column {column} can't be converted from {}, rendering it as bytea",
+            name.to_string_lossy()
+        );
+        format!("\\x{}", hex_encode(value.to_bytes()))
+    })
+}
+
+/// Deform a tuple and render each of its live columns as text
+pub fn tuple_values(tupdesc: &PgTupleDesc, tuple: HeapTuple) -> Vec<ColumnValue> {
+    tuple_values_in(tupdesc, tuple, None, None)
+}
+
+/// Deform a tuple of a database with another encoding, its text values are
+/// converted from `encoding` to the current database's. Toasted values are
+/// rebuilt from the chunks of `toast_chunks`.
+pub fn tuple_values_in(
+    tupdesc: &PgTupleDesc,
+    tuple: HeapTuple,
+    encoding: Option<i32>,
+    toast_chunks: Option<&ToastChunks>,
+) -> Vec<ColumnValue> {
+    let natts = tupdesc.len();
+    let mut values = vec![pg_sys::Datum::from(0); natts];
+    let mut isnull = vec![true; natts];
+    unsafe {
+        pg_sys::heap_deform_tuple(
+            tuple,
+            tupdesc.as_ptr(),
+            values.as_mut_ptr(),
+            isnull.as_mut_ptr(),
+        );
+    }
+
+    let mut columns = Vec::with_capacity(natts);
+    for (i, attr) in tupdesc.iter().enumerate() {
         if attr.is_dropped() {
             continue;
         }
+        let mut column = ColumnValue {
+            name: attr.name().to_string(),
+            value: None,
+            generated: attr.attgenerated != 0,
+            identity_always: attr.attidentity.cast_unsigned() == pg_sys::ATTRIBUTE_IDENTITY_ALWAYS,
+        };
+        if isnull[i] {
+            columns.push(column);
+            continue;
+        }
+        if attr.attlen == -1 && is_external_ondisk(values[i]) {
+            // The toast table may not hold this value anymore, only the
+            // decoded chunks are used
+            let Some(value) = toast_chunks.and_then(|toast_chunks| toast_chunks.fetch(values[i]))
+            else {
+                warning!(
+                    "Column {} is stored in a toast table, rendering it as NULL",
+                    column.name
+                );
+                columns.push(column);
+                continue;
+            };
+            values[i] = value;
+        }
+
+        let value = unsafe {
+            let mut foutoid = pg_sys::InvalidOid;
+            let mut typisvarlena = false;
+            pg_sys::getTypeOutputInfo(attr.atttypid, &raw mut foutoid, &raw mut typisvarlena);
+            let out = CStr::from_ptr(pg_sys::OidOutputFunctionCall(foutoid, values[i]));
+            match encoding {
+                Some(encoding) => convert_encoding(out, encoding, &column.name),
+                None => out.to_string_lossy().into_owned(),
+            }
+        };
+        column.value = Some(value);
+        columns.push(column);
+    }
+    columns
+}
+
+/// Format column values the way `record_out` does
+pub fn format_row(columns: &[ColumnValue]) -> String {
+    let mut buffer = String::from("(");
+    for (i, column) in columns.iter().enumerate() {
+        if i > 0 {
+            buffer.push(',');
+        }
+        let Some(value) = &column.value else {
+            continue;
+        };
+        let needs_quotes = value.is_empty()
+            || value
+                .chars()
+                .any(|c| matches!(c, '"' | '\\' | '(' | ')' | ',') || c.is_ascii_whitespace());
+        if !needs_quotes {
+            buffer.push_str(value);
+            continue;
+        }
+        buffer.push('"');
+        for c in value.chars() {
+            if c == '"' || c == '\\' {
+                buffer.push(c);
+            }
+            buffer.push(c);
+        }
+        buffer.push('"');
+    }
+    buffer.push(')');
+    buffer
+}
+
+pub(crate) fn quote_identifier(ident: &str) -> String {
+    let ident = CString::new(ident).unwrap();
+    unsafe { CStr::from_ptr(pg_sys::quote_identifier(ident.as_ptr())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub(crate) fn quote_literal(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "NULL".to_string();
+    };
+    let value = CString::new(value).unwrap();
+    unsafe { CStr::from_ptr(pg_sys::quote_literal_cstr(value.as_ptr())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Build a condition matching a row with the provided values
+pub(crate) fn where_clause(columns: &[ColumnValue]) -> String {
+    columns
+        .iter()
+        .map(|c| match &c.value {
+            Some(value) => format!(
+                "{} = {}",
+                quote_identifier(&c.name),
+                quote_literal(Some(value))
+            ),
+            None => format!("{} IS NULL", quote_identifier(&c.name)),
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+/// Build an insert of the row, generated columns are left to be computed
+pub fn generate_insert_query(relname: &str, columns: &[ColumnValue]) -> String {
+    let columns = columns.iter().filter(|c| !c.generated).collect::<Vec<_>>();
+    let names = columns
+        .iter()
+        .map(|c| quote_identifier(&c.name))
+        .collect::<Vec<_>>();
+    let values = columns
+        .iter()
+        .map(|c| quote_literal(c.value.as_deref()))
+        .collect::<Vec<_>>();
+    let overriding = if columns.iter().any(|c| c.identity_always) {
+        " OVERRIDING SYSTEM VALUE"
+    } else {
+        ""
+    };
+    format!(
+        "INSERT INTO {relname} ({}){overriding} VALUES ({});",
+        names.join(", "),
+        values.join(", ")
+    )
+}
+
+/// Schema qualified and quoted name of the relation
+pub fn relation_name(rel: &PgRelation) -> String {
+    unsafe {
+        let relname = pg_sys::quote_qualified_identifier(
+            pg_sys::get_namespace_name(rel.namespace_oid()),
+            rel.rd_rel.as_ref().unwrap().relname.data.as_ptr(),
+        );
+        CStr::from_ptr(relname).to_string_lossy().into_owned()
+    }
+}
+
+/// Build the queries redoing and reverting a change from the row values
+/// before and after it
+pub fn generate_queries(
+    relname: &str,
+    old: Option<&[ColumnValue]>,
+    new: Option<&[ColumnValue]>,
+) -> Option<(String, String)> {
+    match (old, new) {
+        (None, Some(new)) => Some((
+            generate_insert_query(relname, new),
+            generate_delete_query(relname, new),
+        )),
+        (Some(old), None) => Some((
+            generate_delete_query(relname, old),
+            generate_insert_query(relname, old),
+        )),
+        (Some(old), Some(new)) => Some((
+            generate_update_query(relname, old, new),
+            generate_update_query(relname, new, old),
+        )),
+        (None, None) => None,
+    }
+}
+
+/// Build the query redoing a change when only the replica identity of the old
+/// row is known. An update sets all the writable columns of the new row.
+pub fn generate_key_query(
+    relname: &str,
+    key: &[ColumnValue],
+    new: Option<&[ColumnValue]>,
+) -> String {
+    let Some(new) = new else {
+        return generate_delete_query(relname, key);
+    };
+    let set_clause = new
+        .iter()
+        .filter(|c| !c.generated && !c.identity_always)
+        .map(|c| {
+            format!(
+                "{} = {}",
+                quote_identifier(&c.name),
+                quote_literal(c.value.as_deref())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "UPDATE {relname} SET {set_clause} WHERE {};",
+        where_clause(key)
+    )
+}
+
+pub fn generate_delete_query(relname: &str, columns: &[ColumnValue]) -> String {
+    format!("DELETE FROM {relname} WHERE {};", where_clause(columns))
+}
+
+/// A column whose value differs between two versions of a row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnChange<'a> {
+    /// Value before the change, None when NULL or missing from the old row
+    pub old: Option<&'a str>,
+    /// The column in the new row
+    pub new: &'a ColumnValue,
+}
+
+/// Columns of the new row whose value differs from the old row. Columns are
+/// matched by name, those missing from the old row are reported as changed.
+pub fn changed_columns<'a>(
+    old: &'a [ColumnValue],
+    new: &'a [ColumnValue],
+) -> Vec<ColumnChange<'a>> {
+    new.iter()
+        .filter_map(|n| {
+            let o = old.iter().find(|o| o.name == n.name);
+            (o.map(|o| &o.value) != Some(&n.value)).then(|| ColumnChange {
+                old: o.and_then(|o| o.value.as_deref()),
+                new: n,
+            })
+        })
+        .collect()
+}
+
+/// JSON text returned as `jsonb`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonbText(pub String);
+
+impl IntoDatum for JsonbText {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        let text = CString::new(self.0).ok()?;
+        Some(unsafe {
+            pg_sys::DirectFunctionCall1Coll(
+                Some(pg_sys::jsonb_in),
+                pg_sys::InvalidOid,
+                pg_sys::Datum::from(text.as_ptr()),
+            )
+        })
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        pg_sys::JSONBOID
+    }
+}
+
+/// Old and new values of the changed columns, as
+/// `{"col": {"old": ..., "new": ...}}`
+pub fn changes_json(old: &[ColumnValue], new: &[ColumnValue]) -> String {
+    let changes = changed_columns(old, new)
+        .iter()
+        .map(|change| {
+            format!(
+                r#"{}:{{"old":{},"new":{}}}"#,
+                json_string(Some(&change.new.name)),
+                json_string(change.old),
+                json_string(change.new.value.as_deref())
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", changes.join(","))
+}
+
+/// Build an update moving a row from its old values to its new ones, only
+/// setting the modified columns. Generated and `GENERATED ALWAYS` identity
+/// columns can't be set by an update.
+pub fn generate_update_query(relname: &str, old: &[ColumnValue], new: &[ColumnValue]) -> String {
+    let writable = |c: &&ColumnValue| !c.generated && !c.identity_always;
+    let changed: Vec<&ColumnValue> = changed_columns(old, new)
+        .into_iter()
+        .map(|change| change.new)
+        .filter(writable)
+        .collect();
+    // An update not changing any value is redone as is, setting every column
+    let set_columns: Vec<&ColumnValue> = if changed.is_empty() {
+        new.iter().filter(writable).collect()
+    } else {
+        changed
+    };
+    let set_clause = set_columns
+        .iter()
+        .map(|c| {
+            format!(
+                "{} = {}",
+                quote_identifier(&c.name),
+                quote_literal(c.value.as_deref())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "UPDATE {relname} SET {set_clause} WHERE {};",
+        where_clause(old)
+    )
+}
+
+/// End of the string literal starting at `start`, quotes inside it are
+/// doubled
+fn literal_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == b'\'' {
+            if bytes.get(i + 1) != Some(&b'\'') {
+                return i + 1;
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
 
-        if i.get() > 1 {
-            buffer.push_str(", ");
+/// Shape of a generated query, its literals replaced by numbered parameters
+/// like `pg_stat_statements` normalizes queries. `NULL` values are
+/// parameters too, `IS NULL` conditions are kept.
+pub fn query_fingerprint(query: &str) -> String {
+    let bytes = query.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || !b.is_ascii();
+    let mut fingerprint = String::with_capacity(query.len());
+    let mut params = 0;
+    let mut previous_word = "";
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let mut param = false;
+        match bytes[i] {
+            b'"' => {
+                // Quoted identifier, copied as is
+                i = start + 1;
+                while i < bytes.len() {
+                    if bytes[i] == b'"' && bytes.get(i + 1) != Some(&b'"') {
+                        i += 1;
+                        break;
+                    }
+                    i += if bytes[i] == b'"' { 2 } else { 1 };
+                }
+                previous_word = "";
+            }
+            b'\'' => {
+                i = literal_end(bytes, start);
+                param = true;
+            }
+            b if is_word(b) => {
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+                let word = &query[start..i];
+                if (word == "E" || word == "e") && bytes.get(i) == Some(&b'\'') {
+                    i = literal_end(bytes, i);
+                    param = true;
+                } else if b.is_ascii_digit() {
+                    // Fractional part of a number
+                    while i < bytes.len() && (bytes[i] == b'.' || bytes[i].is_ascii_digit()) {
+                        i += 1;
+                    }
+                    param = true;
+                } else {
+                    param = word == "NULL" && previous_word != "IS" && previous_word != "NOT";
+                }
+                previous_word = word;
+            }
+            b => {
+                i += 1;
+                if !b.is_ascii_whitespace() {
+                    previous_word = "";
+                }
+            }
+        }
+        if param {
+            params += 1;
+            write!(fingerprint, "${params}").unwrap();
+        } else {
+            fingerprint.push_str(&query[start..i]);
         }
-        let old_val = old_tuple.map(|e| e.get_by_index::<String>(i));
     }
+    fingerprint
 }
 
-pub fn generate_insert_query(relname: &str, heap_tuple: &PgHeapTuple<AllocatedByRust>) -> String {
-    // let natts = tuple_desc.len();
-    // let mut is_null = (0..natts).map(|_| true).collect::<Vec<_>>();
-    //        let heap_tuple_data = pg_sys::heap_form_tuple(
-    //            tuple_desc.as_ptr(),
-    //            std::ptr::null_mut(),
-    //            is_null.as_mut_ptr(),
-    //        );
-    //    let heap_tuple = unsafe { PgHeapTuple::from_heap_tuple(tuple_desc, heap_tuple_data) };
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tuple_str::{
+        changed_columns, changes_json, convert_encoding, format_row, generate_delete_query,
+        generate_insert_query, generate_key_query, generate_update_query, query_fingerprint,
+        ColumnChange, ColumnValue,
+    };
+    use pgrx::prelude::*;
 
-    let attribute_names = heap_tuple
-        .attributes()
-        .filter(|(_, v)| v.is_dropped())
-        .map(|(_, v)| v.name()).collect::<Vec<_>>();
+    fn columns(values: &[(&str, Option<&str>)]) -> Vec<ColumnValue> {
+        values
+            .iter()
+            .map(|(name, value)| ColumnValue {
+                name: (*name).to_string(),
+                value: value.map(str::to_string),
+                generated: false,
+                identity_always: false,
+            })
+            .collect()
+    }
 
-    format!("INSERT INTO {relname} ({})", attribute_names.join(","))
+    #[pg_test]
+    fn test_convert_encoding() {
+        let latin1 = unsafe { pg_sys::pg_char_to_encoding(c"LATIN1".as_ptr()) };
+        assert_eq!(convert_encoding(c"caf\xe9", latin1, "t"), "café");
+        // A truncated multibyte character isn't valid EUC_JP
+        let euc_jp = unsafe { pg_sys::pg_char_to_encoding(c"EUC_JP".as_ptr()) };
+        assert_eq!(convert_encoding(c"ab\x8e", euc_jp, "t"), "\\x61628e");
+    }
+
+    #[test]
+    fn test_format_row() {
+        let row = columns(&[("id", Some("1")), ("data", None), ("t", Some("a \"b\""))]);
+        assert_eq!(format_row(&row), r#"(1,,"a ""b""")"#);
+        assert_eq!(format_row(&columns(&[("t", Some(""))])), r#"("")"#);
+    }
+
+    #[test]
+    fn test_changed_columns() {
+        let old = columns(&[("id", Some("1")), ("a", Some("x")), ("b", None)]);
+        let new = columns(&[
+            ("id", Some("1")),
+            ("b", Some("y")),
+            ("a", Some("x")),
+            ("c", None),
+        ]);
+        assert_eq!(
+            changed_columns(&old, &new),
+            [
+                ColumnChange {
+                    old: None,
+                    new: &new[1]
+                },
+                ColumnChange {
+                    old: None,
+                    new: &new[3]
+                },
+            ]
+        );
+        assert!(changed_columns(&old, &old).is_empty());
+        assert_eq!(
+            changes_json(&old, &new),
+            r#"{"b":{"old":null,"new":"y"},"c":{"old":null,"new":null}}"#
+        );
+    }
+
+    #[test]
+    fn test_query_fingerprint() {
+        assert_eq!(
+            query_fingerprint(
+                r#"UPDATE public."it's" SET "Data" = 'a''b', n = E'\\x' WHERE id = '1' AND d IS NULL;"#
+            ),
+            r#"UPDATE public."it's" SET "Data" = $1, n = $2 WHERE id = $3 AND d IS NULL;"#
+        );
+        assert_eq!(
+            query_fingerprint("INSERT INTO t1 (id, data) VALUES (12.5, NULL);"),
+            "INSERT INTO t1 (id, data) VALUES ($1, $2);"
+        );
+        assert_eq!(
+            query_fingerprint("DELETE FROM t WHERE id = '1';"),
+            query_fingerprint("DELETE FROM t WHERE id = '2';")
+        );
+    }
+
+    #[pg_test]
+    fn test_generate_queries() {
+        let old = columns(&[("id", Some("1")), ("Data", None)]);
+        let new = columns(&[("id", Some("1")), ("Data", Some("it's"))]);
+        assert_eq!(
+            generate_insert_query("public.test", &new),
+            r#"INSERT INTO public.test (id, "Data") VALUES ('1', 'it''s');"#
+        );
+        assert_eq!(
+            generate_delete_query("public.test", &old),
+            r#"DELETE FROM public.test WHERE id = '1' AND "Data" IS NULL;"#
+        );
+        assert_eq!(
+            generate_update_query("public.test", &old, &new),
+            r#"UPDATE public.test SET "Data" = 'it''s' WHERE id = '1' AND "Data" IS NULL;"#
+        );
+    }
+
+    #[pg_test]
+    fn test_generate_key_query() {
+        let key = columns(&[("id", Some("1"))]);
+        let new = columns(&[("id", Some("1")), ("data", Some("b"))]);
+        assert_eq!(
+            generate_key_query("public.test", &key, Some(&new)),
+            "UPDATE public.test SET id = '1', data = 'b' WHERE id = '1';"
+        );
+        assert_eq!(
+            generate_key_query("public.test", &key, None),
+            "DELETE FROM public.test WHERE id = '1';"
+        );
+    }
+
+    #[pg_test]
+    fn test_generate_queries_special_columns() {
+        let mut old = columns(&[("id", Some("1")), ("data", Some("a")), ("len", Some("1"))]);
+        old[0].identity_always = true;
+        old[2].generated = true;
+        let mut new = old.clone();
+        new[1].value = Some("bb".to_string());
+        new[2].value = Some("2".to_string());
+        assert_eq!(
+            generate_insert_query("public.test", &new),
+            "INSERT INTO public.test (id, data) OVERRIDING SYSTEM VALUE VALUES ('1', 'bb');"
+        );
+        assert_eq!(
+            generate_update_query("public.test", &old, &new),
+            "UPDATE public.test SET data = 'bb' WHERE id = '1' AND data = 'a' AND len = '1';"
+        );
+    }
 }
@@ -0,0 +1,241 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use pgrx::prelude::*;
+
+use crate::{
+    decoder::{DecodedResult, DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+};
+
+/// Change to undo, as written in the script
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct UndoStatement {
+    lsn: PgLSN,
+    op: String,
+    relation: Option<String>,
+    revert_query: Option<String>,
+}
+
+impl From<DecodedResult> for UndoStatement {
+    fn from(change: DecodedResult) -> Self {
+        let relation = change
+            .schema_name
+            .zip(change.relation_name)
+            .map(|(schema_name, relation_name)| format!("{schema_name}.{relation_name}"));
+        UndoStatement {
+            lsn: change.lsn,
+            op: change.op,
+            relation,
+            revert_query: change.revert_query,
+        }
+    }
+}
+
+/// Script reverting the changes of each transaction in its own transaction
+/// block, the latest transaction first and the changes of a transaction in
+/// reverse LSN order. Changes without a revert query are left as comments.
+fn undo_script(transactions: BTreeMap<u32, Vec<UndoStatement>>) -> String {
+    let mut transactions: Vec<_> = transactions
+        .into_iter()
+        .filter(|(_, statements)| !statements.is_empty())
+        .collect();
+    // Changes are decoded in LSN order, the last one is the latest
+    transactions.sort_by_key(|(_, statements)| std::cmp::Reverse(statements.last().map(|s| s.lsn)));
+
+    let mut script = String::new();
+    for (xid, statements) in transactions {
+        let first = &statements[0].lsn;
+        let last = &statements[statements.len() - 1].lsn;
+        writeln!(
+            script,
+            "-- Revert transaction {xid}, changes from {first} to {last}"
+        )
+        .unwrap();
+        script.push_str("BEGIN;\n");
+        for statement in statements.iter().rev() {
+            let relation = statement.relation.as_deref().unwrap_or("unknown relation");
+            match &statement.revert_query {
+                Some(revert_query) => {
+                    writeln!(
+                        script,
+                        "-- {} {} on {relation}\n{revert_query}",
+                        statement.lsn, statement.op
+                    )
+                    .unwrap();
+                }
+                None => {
+                    writeln!(
+                        script,
+                        "-- {} {} on {relation} can't be reverted",
+                        statement.lsn, statement.op
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        script.push_str("COMMIT;\n");
+    }
+    script
+}
+
+/// A single script undoing the changes decoded from `start_lsn`, of the
+/// transactions listed in `xids` or of all of them. Xids are matched against
+/// the top level xid of the changes, so changes made under savepoints are
+/// reverted with their transaction. Changes rolled back, with their
+/// transaction or to a savepoint, are left out.
+#[pg_extern]
+fn pg_waldecoder_undo_script(
+    start_lsn: &str,
+    end_lsn: default!(Option<&str>, "NULL"),
+    xids: default!(Option<Vec<i64>>, "NULL"),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> String {
+    let startptr = match PgLSN::try_from(start_lsn) {
+        Ok(startptr) => startptr,
+        Err(e) => error!("Error: {}", e.to_string()),
+    };
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    let mut wal_decoder = WalDecoder::new(startptr, &options);
+    let changes: Vec<_> = wal_decoder.by_ref().collect();
+    let mut transactions: BTreeMap<u32, Vec<UndoStatement>> = BTreeMap::new();
+    for change in changes {
+        // The abort of a savepoint is logged after its changes
+        if wal_decoder.is_aborted(change.xid) {
+            continue;
+        }
+        let xid = change.toplevel_xid.into_inner();
+        if xids
+            .as_ref()
+            .is_some_and(|xids| !xids.contains(&i64::from(xid)))
+        {
+            continue;
+        }
+        if !matches!(
+            change.op.trim_end_matches("+INIT"),
+            "INSERT" | "UPDATE" | "HOT_UPDATE" | "DELETE"
+        ) {
+            continue;
+        }
+        transactions
+            .entry(xid)
+            .or_default()
+            .push(UndoStatement::from(change));
+    }
+    undo_script(transactions)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        undo::{undo_script, UndoStatement},
+    };
+
+    fn statement(lsn: i32, revert_query: Option<&str>) -> UndoStatement {
+        UndoStatement {
+            lsn: PgLSN::from(lsn),
+            op: "INSERT".to_string(),
+            relation: Some("public.t".to_string()),
+            revert_query: revert_query.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_undo_script() {
+        let transactions = BTreeMap::from([
+            (
+                750,
+                vec![
+                    statement(0x10, Some("DELETE FROM t WHERE id = 1;")),
+                    statement(0x40, None),
+                ],
+            ),
+            (
+                751,
+                vec![statement(0x20, Some("DELETE FROM t WHERE id = 2;"))],
+            ),
+        ]);
+        assert_eq!(
+            undo_script(transactions),
+            "-- Revert transaction 750, changes from 0/00000010 to 0/00000040
+BEGIN;
+-- 0/00000040 INSERT on public.t can't be reverted
+-- 0/00000010 INSERT on public.t
+DELETE FROM t WHERE id = 1;
+COMMIT;
+-- Revert transaction 751, changes from 0/00000020 to 0/00000020
+BEGIN;
+-- 0/00000020 INSERT on public.t
+DELETE FROM t WHERE id = 2;
+COMMIT;
+"
+        );
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_undo_script() {
+        unsafe {
+            Spi::run("CREATE TABLE test_undo_script (id int primary key, data text);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_undo_script VALUES (1, 'a'), (2, 'b')");
+            Spi::run("UPDATE test_undo_script SET data = 'c' WHERE id = 1");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let script = Spi::get_one::<String>(&format!(
+            "SELECT pg_waldecoder_undo_script('{startptr}', '{endptr}', ARRAY[pg_current_xact_id()::xid::text::bigint], 1)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(script.starts_with("-- Revert transaction"));
+        assert_eq!(script.matches("BEGIN;").count(), 1);
+        // The update is reverted before the inserts
+        let update = script.find("HOT_UPDATE").unwrap();
+        assert!(script.rfind("INSERT").unwrap() > update);
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_undo_script_savepoint() {
+        unsafe {
+            Spi::run("CREATE TABLE test_undo_savepoint (id int primary key);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_undo_savepoint VALUES (1)");
+            // The second insert is rolled back with its subtransaction
+            Spi::run(
+                "DO $$ BEGIN
+                    INSERT INTO test_undo_savepoint VALUES (2);
+                    RAISE EXCEPTION 'rollback';
+                 EXCEPTION WHEN OTHERS THEN NULL;
+                 END $$",
+            );
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let script = Spi::get_one::<String>(&format!(
+            "SELECT pg_waldecoder_undo_script('{startptr}', '{endptr}', timeline => 1)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(script.matches("INSERT on").count(), 1);
+        assert!(!script.contains("'2'"));
+    }
+}
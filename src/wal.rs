@@ -1,15 +1,21 @@
-use pgrx::pg_sys::{XLogLongPageHeaderData, XLOGDIR, XLOG_BLCKSZ};
+use pg_waldecoder_core::page::{ByteOrder, InvalidPage, LongPageHeader};
+use pg_waldecoder_core::segment::XLOG_PARTIAL_SUFFIX;
+pub use pg_waldecoder_core::segment::{is_partial_segment, is_wal_segsz_valid, is_xlog_file_name};
+use pgrx::pg_sys::{self, XLOGDIR, XLOG_BLCKSZ};
 use std::{
     env,
+    ffi::CStr,
     fs::{self, File},
     io::{self, Read},
     path::{Path, PathBuf},
 };
 use thiserror::Error;
 
-const XLOG_FNAME_LEN: usize = 24;
-const WAL_SEG_MIN_SIZE: u32 = 1024 * 1024;
-const WAL_SEG_MAX_SIZE: u32 = 1024 * 1024 * 1024;
+use crate::pg_lsn::filename_to_startptr;
+
+const WAL_DIR_SEPARATOR: char = ':';
+/// Maximum number of rejected files reported per candidate directory
+const MAX_REPORTED_REASONS: usize = 5;
 
 #[derive(Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq, Error)]
 pub enum InvalidWalFile {
@@ -23,22 +29,158 @@ pub enum InvalidWalFile {
     NoFile(String),
     #[error("Invalid WAL segment size {0}. The WAL segment size must be a power of two between 1MB and 1GB.")]
     InvalidWalSegSz(u32),
+    #[error("WAL file {0} has a segment size of {1} bytes while {2} bytes were expected")]
+    MismatchedWalSegSz(String, u32, u32),
+}
+
+/// A candidate WAL directory or file and why its files were rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedCandidate {
+    pub dir: PathBuf,
+    pub reasons: Vec<InvalidWalFile>,
+}
+
+/// Where WAL files are read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WalLocation {
+    /// Every file of the directory is used
+    Dir(PathBuf),
+    /// A segment or history file, used alone
+    File(PathBuf),
+}
+
+/// Directories and files the WAL is read from. A file is taken from the first
+/// location holding it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalSource {
+    locations: Vec<WalLocation>,
+}
+
+impl WalSource {
+    pub fn new(locations: Vec<WalLocation>) -> WalSource {
+        WalSource { locations }
+    }
+
+    /// Source reading every file of the directories
+    pub fn from_dirs(dirs: Vec<PathBuf>) -> WalSource {
+        WalSource::new(dirs.into_iter().map(WalLocation::Dir).collect())
+    }
+
+    pub fn locations(&self) -> &[WalLocation] {
+        &self.locations
+    }
+
+    /// Names and paths of the files of the source
+    pub fn files(&self) -> Vec<(String, PathBuf)> {
+        let mut files = Vec::new();
+        for location in &self.locations {
+            match location {
+                WalLocation::Dir(dir) => {
+                    let Ok(entries) = fs::read_dir(dir) else {
+                        continue;
+                    };
+                    files.extend(
+                        entries
+                            .filter_map(Result::ok)
+                            .filter_map(|e| Some((e.file_name().to_str()?.to_string(), e.path()))),
+                    );
+                }
+                WalLocation::File(path) => {
+                    if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
+                        files.push((file_name.to_string(), path.clone()));
+                    }
+                }
+            }
+        }
+        files
+    }
+
+    /// Path of the file named `fname`, None when no location has it
+    pub fn find(&self, fname: &str) -> Option<PathBuf> {
+        self.locations.iter().find_map(|location| match location {
+            WalLocation::Dir(dir) => Some(dir.join(fname)).filter(|path| path.exists()),
+            WalLocation::File(path) => Some(path)
+                .filter(|path| path.file_name().is_some_and(|f| f == fname) && path.exists())
+                .cloned(),
+        })
+    }
+
+    /// Directory the server's reader defaults to, the first one of the
+    /// source or the one of its first file
+    pub fn default_dir(&self) -> PathBuf {
+        self.locations
+            .first()
+            .map(|location| match location {
+                WalLocation::Dir(dir) => dir.clone(),
+                WalLocation::File(path) => path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for WalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let locations = self
+            .locations
+            .iter()
+            .map(|location| match location {
+                WalLocation::Dir(path) | WalLocation::File(path) => path.display().to_string(),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", locations.join(":"))
+    }
 }
 
 /// Search if directory contains a valid WAL file.
 pub fn search_directory(dir: &PathBuf) -> Result<Option<(PathBuf, u32)>, io::Error> {
+    search_directory_with_reasons(dir, &mut Vec::new())
+}
+
+/// Search if directory contains a valid WAL file, collecting the reason each
+/// file was rejected.
+pub fn search_directory_with_reasons(
+    dir: &PathBuf,
+    reasons: &mut Vec<InvalidWalFile>,
+) -> Result<Option<(PathBuf, u32)>, io::Error> {
     let mut entries = fs::read_dir(dir)?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()?;
     entries.sort();
     for f in entries {
-        if let Ok(segsz) = validate_wal_file(&f) {
-            return Ok(Some((f, segsz)));
+        match validate_wal_file(&f) {
+            Ok(segsz) => return Ok(Some((f, segsz))),
+            Err(e) => reasons.push(e),
         }
     }
     Ok(None)
 }
 
+/// Format rejected candidates for an error detail
+pub fn format_rejections(rejections: &[RejectedCandidate]) -> String {
+    let mut lines = Vec::new();
+    for rejection in rejections {
+        let reasons = if rejection.reasons.is_empty() {
+            "no files found".to_string()
+        } else {
+            let mut reasons = rejection
+                .reasons
+                .iter()
+                .take(MAX_REPORTED_REASONS)
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            if rejection.reasons.len() > MAX_REPORTED_REASONS {
+                reasons.push(format!(
+                    "and {} more",
+                    rejection.reasons.len() - MAX_REPORTED_REASONS
+                ));
+            }
+            reasons.join("; ")
+        };
+        lines.push(format!("{}: {reasons}", rejection.dir.display()));
+    }
+    lines.join("\n")
+}
+
 /// Validate that the provided file is a valid WAL file
 pub fn validate_wal_file(wal_path: &PathBuf) -> Result<u32, InvalidWalFile> {
     let wal_str = wal_path.to_string_lossy().to_string();
@@ -49,23 +191,22 @@ pub fn validate_wal_file(wal_path: &PathBuf) -> Result<u32, InvalidWalFile> {
     let Some(file_name) = wal_path.file_name().and_then(|f| f.to_str()) else {
         return Err(InvalidWalFile::NoFile(wal_str));
     };
-    // We should have 24 characters
-    if file_name.len() != XLOG_FNAME_LEN {
+    if !is_xlog_file_name(file_name) {
         return Err(InvalidWalFile::InvalidFileName(file_name.to_string()));
     }
-    // With only hexadecimal characters
-    for c in file_name.chars() {
-        if !c.is_ascii_hexdigit() {
-            return Err(InvalidWalFile::InvalidFileName(file_name.to_string()));
-        }
-    }
     // Validate segsz from the WAL file
     get_wal_segsz(wal_path)
 }
 
-/// Returns true if WAL seg size is correct
-pub fn is_wal_segsz_valid(wal_seg_size: u32) -> bool {
-    wal_seg_size.is_power_of_two() && (WAL_SEG_MIN_SIZE..=WAL_SEG_MAX_SIZE).contains(&wal_seg_size)
+/// Find the file of a segment in the WAL source, searched in order.
+///
+/// A complete segment is preferred, a `.partial` segment is used as a
+/// fallback.
+pub fn find_segment_file(source: &WalSource, fname: &str) -> Option<PathBuf> {
+    let partial_fname = format!("{fname}{XLOG_PARTIAL_SUFFIX}");
+    [fname, partial_fname.as_str()]
+        .iter()
+        .find_map(|f| source.find(f))
 }
 
 /// Identify the target directory.
@@ -78,7 +219,7 @@ pub fn is_wal_segsz_valid(wal_seg_size: u32) -> bool {
 ///  .
 ///  XLOGDIR /
 ///  $PGDATA / XLOGDIR /
-pub fn detect_wal_dir(wal_dir: Option<&str>) -> Option<(PathBuf, u32)> {
+pub fn detect_wal_dir(wal_dir: Option<&str>) -> Result<(PathBuf, u32), Vec<RejectedCandidate>> {
     let xlog_dir = XLOGDIR.to_string_lossy().to_string();
     let wal_dir_candidates = if let Some(d) = wal_dir {
         let d = Path::new(d).to_path_buf();
@@ -98,13 +239,88 @@ pub fn detect_wal_dir(wal_dir: Option<&str>) -> Option<(PathBuf, u32)> {
         r
     };
 
+    let mut rejections = Vec::new();
     for d in wal_dir_candidates {
-        let f = search_directory(&d);
-        if let Ok(Some((_, segsz))) = f {
-            return Some((d, segsz));
+        let mut reasons = Vec::new();
+        match search_directory_with_reasons(&d, &mut reasons) {
+            Ok(Some((_, segsz))) => return Ok((d, segsz)),
+            Ok(None) => {}
+            Err(e) => reasons.push(InvalidWalFile::IoError(e.to_string())),
+        }
+        rejections.push(RejectedCandidate { dir: d, reasons });
+    }
+    Err(rejections)
+}
+
+/// Identify the WAL source from a colon-separated list of directories and
+/// files.
+///
+/// A directory entry is resolved like a single directory and entries without
+/// WAL files are skipped. A file entry is a segment, only decoded with the
+/// other listed files, or a history file. Segments are then searched in the
+/// provided order.
+pub fn detect_wal_source(
+    wal_dirs: Option<&str>,
+) -> Result<(WalSource, u32), Vec<RejectedCandidate>> {
+    let Some(wal_dirs) = wal_dirs else {
+        return detect_wal_dir(None)
+            .map(|(d, segsz)| (WalSource::new(vec![WalLocation::Dir(d)]), segsz));
+    };
+    let mut detected = Vec::new();
+    let mut rejections = Vec::new();
+    let mut segsz = None;
+    for d in wal_dirs.split(WAL_DIR_SEPARATOR).filter(|d| !d.is_empty()) {
+        let path = Path::new(d).to_path_buf();
+        if !path.is_file() {
+            match detect_wal_dir(Some(d)) {
+                Ok((d, s)) => {
+                    segsz.get_or_insert(s);
+                    detected.push(WalLocation::Dir(d));
+                }
+                Err(mut r) => rejections.append(&mut r),
+            }
+            continue;
+        }
+        if d.ends_with(".history") {
+            detected.push(WalLocation::File(path));
+            continue;
+        }
+        match (validate_wal_file(&path), segsz) {
+            (Ok(s), Some(expected)) if s != expected => rejections.push(RejectedCandidate {
+                dir: path,
+                reasons: vec![InvalidWalFile::MismatchedWalSegSz(
+                    d.to_string(),
+                    s,
+                    expected,
+                )],
+            }),
+            (Ok(s), _) => {
+                segsz = Some(s);
+                detected.push(WalLocation::File(path));
+            }
+            (Err(e), _) => rejections.push(RejectedCandidate {
+                dir: path,
+                reasons: vec![e],
+            }),
         }
     }
-    None
+    match segsz {
+        Some(segsz) => Ok((WalSource::new(detected), segsz)),
+        None => Err(rejections),
+    }
+}
+
+/// Returns the running cluster's WAL directory and its segment size.
+///
+/// The segment size comes from the `wal_segment_size` GUC instead of being
+/// detected from a segment that may be recycled while we read it.
+pub fn live_wal_dir() -> (PathBuf, u32) {
+    let data_dir = unsafe { CStr::from_ptr(pg_sys::DataDir) }
+        .to_string_lossy()
+        .to_string();
+    let xlog_dir = XLOGDIR.to_string_lossy().to_string();
+    let segsz = unsafe { pg_sys::wal_segment_size }.cast_unsigned();
+    (Path::new(&data_dir).join(xlog_dir), segsz)
 }
 
 /// Extract wal segsz from wal file
@@ -121,18 +337,65 @@ pub fn get_wal_segsz(wal_path: &PathBuf) -> Result<u32, InvalidWalFile> {
         Err(e) => return Err(InvalidWalFile::ReadError(wal_str, e.to_string())),
     }
 
-    let s = unsafe { std::ptr::read(buffer.as_ptr().cast::<XLogLongPageHeaderData>()) };
-    if !is_wal_segsz_valid(s.xlp_seg_size) {
-        return Err(InvalidWalFile::InvalidWalSegSz(s.xlp_seg_size));
+    // The server can only read WAL written in its own byte order, the
+    // records would be misread otherwise
+    match LongPageHeader::parse(&buffer).and_then(|header| {
+        header.std.validate_byte_order(ByteOrder::NATIVE)?;
+        Ok(header)
+    }) {
+        Ok(header) => Ok(header.seg_size),
+        Err(InvalidPage::InvalidWalSegSz(segsz)) => Err(InvalidWalFile::InvalidWalSegSz(segsz)),
+        Err(e) => Err(InvalidWalFile::ReadError(wal_str, e.to_string())),
     }
-    Ok(s.xlp_seg_size)
+}
+
+/// Returns the first segment after `after_segno` found in the WAL source on one
+/// of the provided timelines
+pub fn next_available_segment(
+    source: &WalSource,
+    segsz: u32,
+    timelines: &[pg_sys::TimeLineID],
+    after_segno: pg_sys::XLogSegNo,
+) -> Option<pg_sys::XLogSegNo> {
+    source
+        .files()
+        .into_iter()
+        .filter_map(|(file_name, _)| {
+            if !is_xlog_file_name(&file_name) {
+                return None;
+            }
+            let (tli, segno) = filename_to_startptr(&file_name, u64::from(segsz)).ok()?;
+            let tli = pg_sys::TimeLineID::try_from(tli).ok()?;
+            (timelines.contains(&tli) && segno > after_segno).then_some(segno)
+        })
+        .min()
+}
+
+/// Check that the segment size of a WAL file matches the expected one
+pub fn validate_segment_size(
+    wal_path: &PathBuf,
+    expected_segsz: u32,
+) -> Result<(), InvalidWalFile> {
+    let segsz = get_wal_segsz(wal_path)?;
+    if segsz != expected_segsz {
+        return Err(InvalidWalFile::MismatchedWalSegSz(
+            wal_path.to_string_lossy().to_string(),
+            segsz,
+            expected_segsz,
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(any(test, feature = "pg_test"))]
 mod tests {
     use std::path::Path;
 
-    use crate::wal::{search_directory, validate_wal_file};
+    use crate::wal::{
+        detect_wal_dir, detect_wal_source, find_segment_file, format_rejections, is_xlog_file_name,
+        next_available_segment, search_directory, validate_segment_size, validate_wal_file,
+        InvalidWalFile, WalLocation, WalSource,
+    };
 
     macro_rules! test_path {
         ($dirname:expr) => {
@@ -155,6 +418,47 @@ mod tests {
         assert_eq!(seg_size, 1024 * 1024, "Invalid segment size");
     }
 
+    #[test]
+    fn test_next_available_segment() {
+        let wal_dirs = WalSource::from_dirs(vec![test_path!("18_single_upgrade")]);
+        assert_eq!(
+            next_available_segment(&wal_dirs, 1024 * 1024, &[1], 0x10),
+            Some(0x18)
+        );
+        assert_eq!(
+            next_available_segment(&wal_dirs, 1024 * 1024, &[1], 0x18),
+            None
+        );
+        assert_eq!(
+            next_available_segment(&wal_dirs, 1024 * 1024, &[2], 0x10),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate_segment_size() {
+        let wal_path = test_path!("18_single_upgrade/000000010000000000000018");
+        assert!(validate_segment_size(&wal_path, 1024 * 1024).is_ok());
+        let err = validate_segment_size(&wal_path, 16 * 1024 * 1024).unwrap_err();
+        assert_eq!(
+            err,
+            InvalidWalFile::MismatchedWalSegSz(
+                wal_path.to_string_lossy().to_string(),
+                1024 * 1024,
+                16 * 1024 * 1024
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_xlog_file_name() {
+        assert!(is_xlog_file_name("000000010000000000000018"));
+        assert!(is_xlog_file_name("000000010000000000000018.partial"));
+        assert!(!is_xlog_file_name("000000010000000000000018.backup"));
+        assert!(!is_xlog_file_name("00000001000000000000001"));
+        assert!(!is_xlog_file_name("00000002.history"));
+    }
+
     #[test]
     fn test_search_directory() {
         let wal_dir = test_path!("18_single_upgrade");
@@ -165,4 +469,50 @@ mod tests {
         let expected_path = test_path!("18_single_upgrade/000000010000000000000018");
         assert_eq!(f, (expected_path, 1024 * 1024));
     }
+
+    #[test]
+    fn test_detect_wal_source() {
+        let wal_dir = test_path!("18_single_upgrade");
+        let missing_dir = test_path!("missing");
+        let wal_dirs = format!("{}:{}", missing_dir.display(), wal_dir.display());
+
+        let (source, segsz) = detect_wal_source(Some(&wal_dirs)).unwrap();
+        assert_eq!(source, WalSource::from_dirs(vec![wal_dir]));
+        assert_eq!(segsz, 1024 * 1024);
+        assert!(detect_wal_source(Some(&missing_dir.display().to_string())).is_err());
+    }
+
+    #[test]
+    fn test_detect_wal_source_files() {
+        let wal_path = test_path!("18_single_upgrade/000000010000000000000018");
+        let (source, segsz) = detect_wal_source(Some(&wal_path.display().to_string())).unwrap();
+        assert_eq!(
+            source.locations(),
+            [WalLocation::File(wal_path.clone())].as_slice()
+        );
+        assert_eq!(segsz, 1024 * 1024);
+        assert_eq!(source.default_dir(), test_path!("18_single_upgrade"));
+        assert_eq!(
+            find_segment_file(&source, "000000010000000000000018"),
+            Some(wal_path)
+        );
+        assert_eq!(find_segment_file(&source, "000000010000000000000019"), None);
+        assert_eq!(source.files().len(), 1);
+    }
+
+    #[test]
+    fn test_detect_wal_dir_rejections() {
+        let resources_dir = test_path!("");
+        let Err(rejections) = detect_wal_dir(Some(&resources_dir.display().to_string())) else {
+            panic!("resources dir shouldn't contain WAL files");
+        };
+        // Both the directory and its pg_wal subdirectory were tried
+        assert_eq!(rejections.len(), 2);
+        assert_eq!(rejections[0].dir, resources_dir);
+        assert!(!rejections[0].reasons.is_empty());
+
+        let detail = format_rejections(&rejections);
+        assert!(detail.contains("Invalid WAL file name 18_single_upgrade"));
+        assert!(detail.contains("No such file or directory"));
+    }
 }
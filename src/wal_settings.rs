@@ -0,0 +1,128 @@
+use pgrx::{pg_sys, prelude::*, PgBox};
+
+use crate::pg_lsn::PgLSN;
+
+/// Name of a `wal_level` setting
+fn wal_level_name(wal_level: u32) -> &'static str {
+    match wal_level {
+        pg_sys::WalLevel::WAL_LEVEL_MINIMAL => "minimal",
+        pg_sys::WalLevel::WAL_LEVEL_REPLICA => "replica",
+        pg_sys::WalLevel::WAL_LEVEL_LOGICAL => "logical",
+        _ => "unknown",
+    }
+}
+
+/// Settings read from a record, the `wal_level` and `full_page_writes` in
+/// effect after it when it logs them
+fn record_settings(record: &PgBox<pg_sys::DecodedXLogRecord>) -> (Option<u32>, Option<bool>) {
+    let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+    if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_XLOG_ID || record.main_data.is_null()
+    {
+        return (None, None);
+    }
+    let main_data_len = record.main_data_len as usize;
+    match info {
+        pg_sys::XLOG_CHECKPOINT_SHUTDOWN | pg_sys::XLOG_CHECKPOINT_ONLINE
+            if main_data_len >= size_of::<pg_sys::CheckPoint>() =>
+        {
+            let checkpoint =
+                unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::CheckPoint>()) };
+            (
+                Some(checkpoint.wal_level.cast_unsigned()),
+                Some(checkpoint.fullPageWrites),
+            )
+        }
+        pg_sys::XLOG_PARAMETER_CHANGE
+            if main_data_len >= size_of::<pg_sys::xl_parameter_change>() =>
+        {
+            let xlrec = unsafe {
+                std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_parameter_change>())
+            };
+            (Some(xlrec.wal_level.cast_unsigned()), None)
+        }
+        pg_sys::XLOG_FPW_CHANGE if main_data_len >= size_of::<bool>() => {
+            let fpw = unsafe { std::ptr::read_unaligned(record.main_data.cast::<u8>()) };
+            (None, Some(fpw != 0))
+        }
+        _ => (None, None),
+    }
+}
+
+/// Limit of the decoding caused by the settings of the server writing the
+/// WAL, None when they don't limit it
+fn wal_level_limit(wal_level: u32) -> Option<String> {
+    if wal_level >= pg_sys::WalLevel::WAL_LEVEL_LOGICAL {
+        return None;
+    }
+    Some(format!(
+        "WAL was written with wal_level={}, the old keys and rows of updates and deletes \
+         are only known from the cached pages",
+        wal_level_name(wal_level)
+    ))
+}
+
+fn full_page_writes_limit(full_page_writes: bool) -> Option<String> {
+    if full_page_writes {
+        return None;
+    }
+    Some(
+        "WAL was written with full_page_writes=off, few pages are logged whole and the \
+         changes to pages never seen can't be decoded"
+            .to_string(),
+    )
+}
+
+/// `wal_level` and `full_page_writes` of the server writing the decoded WAL,
+/// from its checkpoint and parameter change records. A warning is raised
+/// once each time a setting starts limiting the decoding.
+#[derive(Default)]
+pub struct WalSettings {
+    wal_level: Option<u32>,
+    full_page_writes: Option<bool>,
+}
+
+impl WalSettings {
+    pub fn observe(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>) {
+        let (wal_level, full_page_writes) = record_settings(record);
+        let lsn = PgLSN::from(record.lsn);
+        if let Some(wal_level) = wal_level {
+            if self.wal_level.replace(wal_level) != Some(wal_level) {
+                if let Some(limit) = wal_level_limit(wal_level) {
+                    warning!("{limit} (from {lsn})");
+                }
+            }
+        }
+        if let Some(full_page_writes) = full_page_writes {
+            if self.full_page_writes.replace(full_page_writes) != Some(full_page_writes) {
+                if let Some(limit) = full_page_writes_limit(full_page_writes) {
+                    warning!(
+                        "{limit} (from {lsn}), read_current_pages reads the missing pages from \
+                         the relations"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::wal_settings::{full_page_writes_limit, wal_level_limit};
+
+    #[test]
+    fn test_decoding_limits() {
+        assert_eq!(wal_level_limit(pg_sys::WalLevel::WAL_LEVEL_LOGICAL), None);
+        assert_eq!(
+            wal_level_limit(pg_sys::WalLevel::WAL_LEVEL_REPLICA).as_deref(),
+            Some(
+                "WAL was written with wal_level=replica, the old keys and rows of updates and \
+                 deletes are only known from the cached pages"
+            )
+        );
+        assert_eq!(full_page_writes_limit(true), None);
+        assert!(full_page_writes_limit(false).is_some());
+    }
+}
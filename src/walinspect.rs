@@ -0,0 +1,623 @@
+use std::{collections::BTreeMap, ffi::CStr};
+
+use pgrx::{pg_sys, prelude::*, PgBox};
+
+use crate::{
+    decoder::{DecoderOptions, WalDecoder},
+    pg_lsn::PgLSN,
+    xlog_reader::{get_block, get_block_data, get_block_tag_extended, rmgr_data},
+};
+
+/// Row of `pg_get_wal_record_info` and `pg_get_wal_records_info`
+type RecordInfoRow = (
+    name!(start_lsn, PgLSN),
+    name!(end_lsn, PgLSN),
+    name!(prev_lsn, PgLSN),
+    name!(xid, pg_sys::TransactionId),
+    name!(resource_manager, String),
+    name!(record_type, String),
+    name!(record_length, i32),
+    name!(main_data_length, i32),
+    name!(fpi_length, i32),
+    name!(description, Option<String>),
+    name!(block_ref, Option<String>),
+);
+
+/// Take the content of a StringInfo, None if it's empty
+fn string_info_text(buf: pg_sys::StringInfo) -> Option<String> {
+    let buf = unsafe { &*buf };
+    (buf.len > 0).then(|| {
+        unsafe { CStr::from_ptr(buf.data) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+pub(crate) fn rmgr_display_name(rmid: u8) -> String {
+    let name = rmgr_data(rmid).rm_name;
+    if name.is_null() {
+        return format!("custom{rmid:03}");
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Name of a record type like `rm_identify` gives it
+pub(crate) fn record_type_name(rmid: u8, info: u8) -> String {
+    let identified = rmgr_data(rmid)
+        .rm_identify
+        .map_or(std::ptr::null(), |rm_identify| unsafe { rm_identify(info) });
+    if identified.is_null() {
+        return format!("UNKNOWN ({:x})", u32::from(info) & !pg_sys::XLR_INFO_MASK);
+    }
+    unsafe { CStr::from_ptr(identified) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Description of the latest record read, as printed by pg_waldump
+pub(crate) fn record_description(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    rmid: u8,
+) -> Option<String> {
+    let rm_desc = rmgr_data(rmid).rm_desc?;
+    let buf = unsafe { pg_sys::makeStringInfo() };
+    unsafe { rm_desc(buf, xlog_reader.as_ptr()) };
+    string_info_text(buf)
+}
+
+/// Summary of the block references of the latest record read and the total
+/// length of its full page images
+pub(crate) fn block_ref_info(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+) -> (Option<String>, u32) {
+    let buf = unsafe { pg_sys::makeStringInfo() };
+    let mut fpi_len: u32 = 0;
+    unsafe {
+        pg_sys::XLogRecGetBlockRefInfo(xlog_reader.as_ptr(), false, true, buf, &raw mut fpi_len);
+    }
+    (string_info_text(buf), fpi_len)
+}
+
+/// Check the bounds of a range of LSNs, returning the end as the decoder
+/// takes it
+pub(crate) fn lsn_bounds(start_lsn: PgLSN, end_lsn: PgLSN) -> String {
+    if start_lsn > end_lsn {
+        error!("WAL start LSN must be less than end LSN");
+    }
+    end_lsn.to_string()
+}
+
+fn record_info_row(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> RecordInfoRow {
+    let rmid = record.header.xl_rmid;
+    let (block_ref, fpi_len) = block_ref_info(xlog_reader);
+    (
+        PgLSN::from(record.lsn),
+        PgLSN::from(xlog_reader.EndRecPtr),
+        PgLSN::from(record.header.xl_prev),
+        record.header.xl_xid,
+        rmgr_display_name(rmid),
+        record_type_name(rmid, record.header.xl_info),
+        i32::try_from(record.header.xl_tot_len).unwrap_or(i32::MAX),
+        i32::try_from(record.main_data_len).unwrap_or(i32::MAX),
+        i32::try_from(fpi_len).unwrap_or(i32::MAX),
+        record_description(xlog_reader, rmid),
+        block_ref,
+    )
+}
+
+pub(crate) fn wal_decoder(
+    start_lsn: PgLSN,
+    end_lsn: Option<&str>,
+    timeline: Option<i32>,
+    wal_dir: Option<&str>,
+) -> WalDecoder {
+    let options = DecoderOptions {
+        end_lsn,
+        timeline,
+        wal_dir,
+        ..Default::default()
+    };
+    WalDecoder::new(start_lsn, &options)
+}
+
+/// Information about the first record at or after `in_lsn`, like
+/// pg_walinspect's function but read from the WAL files of `wal_dir`
+#[pg_extern]
+fn pg_get_wal_record_info(
+    in_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<'static, RecordInfoRow> {
+    let mut wal_decoder = wal_decoder(in_lsn, None, timeline, wal_dir);
+    let Some(record) = wal_decoder.read_record() else {
+        error!("could not read WAL at {in_lsn}");
+    };
+    TableIterator::once(record_info_row(wal_decoder.xlog_reader(), &record))
+}
+
+/// Bounds of the record containing `lsn`. The records are read from the
+/// start of its page, then of its segment for a record crossing the page
+/// boundary.
+fn containing_record(
+    lsn: PgLSN,
+    timeline: Option<i32>,
+    wal_dir: Option<&str>,
+) -> Option<(PgLSN, PgLSN)> {
+    let target = u64::from(lsn);
+    let page_start = target - target % u64::from(pg_sys::XLOG_BLCKSZ);
+    let mut decoder = wal_decoder(PgLSN::from(page_start), None, timeline, wal_dir);
+    let segsz = u64::from(decoder.xlog_reader().segcxt.ws_segsize.cast_unsigned());
+    let segment_start = target - target % segsz;
+    for start in [page_start, segment_start] {
+        if start != page_start {
+            decoder = wal_decoder(PgLSN::from(start), None, timeline, wal_dir);
+        }
+        while let Some(record) = decoder.read_record() {
+            if record.lsn > target {
+                break;
+            }
+            let end = decoder.xlog_reader().EndRecPtr;
+            if end > target {
+                return Some((PgLSN::from(record.lsn), PgLSN::from(end)));
+            }
+        }
+        if page_start == segment_start {
+            break;
+        }
+    }
+    None
+}
+
+/// The record containing `lsn`, or starting at it, with its header, block
+/// references and description like `pg_get_wal_record_info`, and the change
+/// it makes for heap records. The change needs the record's page, from an
+/// image of the record or read from the relation with `read_current_pages`,
+/// it's NULL otherwise.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_waldecoder_record_at(
+    lsn: PgLSN,
+    read_current_pages: default!(bool, false),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(start_lsn, PgLSN),
+        name!(end_lsn, PgLSN),
+        name!(prev_lsn, PgLSN),
+        name!(xid, pg_sys::TransactionId),
+        name!(resource_manager, String),
+        name!(record_type, String),
+        name!(record_length, i32),
+        name!(main_data_length, i32),
+        name!(fpi_length, i32),
+        name!(description, Option<String>),
+        name!(block_ref, Option<String>),
+        name!(op, Option<String>),
+        name!(relid, Option<pg_sys::Oid>),
+        name!(row_before, Option<String>),
+        name!(row_after, Option<String>),
+        name!(redo_query, Option<String>),
+        name!(revert_query, Option<String>),
+    ),
+> {
+    let Some((start, end)) = containing_record(lsn, timeline, wal_dir) else {
+        error!("No record contains {lsn}");
+    };
+    let mut decoder = wal_decoder(start, None, timeline, wal_dir);
+    let Some(record) = decoder.read_record() else {
+        error!("could not read WAL at {start}");
+    };
+    let (
+        start_lsn,
+        end_lsn,
+        prev_lsn,
+        xid,
+        rmgr,
+        record_type,
+        len,
+        main_len,
+        fpi_len,
+        desc,
+        block_ref,
+    ) = record_info_row(decoder.xlog_reader(), &record);
+
+    let rmid = u32::from(record.header.xl_rmid);
+    let change = (rmid == pg_sys::RmgrIds::RM_HEAP_ID || rmid == pg_sys::RmgrIds::RM_HEAP2_ID)
+        .then(|| {
+            let end = end.to_string();
+            let options = DecoderOptions {
+                end_lsn: Some(&end),
+                timeline,
+                wal_dir,
+                read_current_pages,
+                ..Default::default()
+            };
+            WalDecoder::new(start, &options).next()
+        })
+        .flatten()
+        .filter(|change| change.lsn == start);
+    TableIterator::once((
+        start_lsn,
+        end_lsn,
+        prev_lsn,
+        xid,
+        rmgr,
+        record_type,
+        len,
+        main_len,
+        fpi_len,
+        desc,
+        block_ref,
+        change.as_ref().map(|change| change.op.clone()),
+        change.as_ref().and_then(|change| change.relid),
+        change.as_ref().and_then(|change| change.row_before.clone()),
+        change.as_ref().and_then(|change| change.row_after.clone()),
+        change.as_ref().and_then(|change| change.redo_query.clone()),
+        change.and_then(|change| change.revert_query),
+    ))
+}
+
+/// Information about the records between `start_lsn` and `end_lsn`
+#[pg_extern]
+fn pg_get_wal_records_info(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<'static, RecordInfoRow> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    TableIterator::new(std::iter::from_fn(move || {
+        let record = wal_decoder.read_record()?;
+        Some(record_info_row(wal_decoder.xlog_reader(), &record))
+    }))
+}
+
+/// Counts and sizes of a resource manager or record type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct WalStats {
+    count: u64,
+    record_size: u64,
+    fpi_size: u64,
+}
+
+impl WalStats {
+    fn add(&mut self, record_size: u64, fpi_size: u64) {
+        self.count += 1;
+        self.record_size += record_size;
+        self.fpi_size += fpi_size;
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn percentage(value: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * value as f64 / total as f64
+    }
+}
+
+/// Record type id as grouped by pg_waldump's statistics, xact records keep
+/// an optional flag in the first of the 4 bits
+fn record_type_id(rmid: u8, info: u8) -> u8 {
+    let recid = info >> 4;
+    if u32::from(rmid) == pg_sys::RmgrIds::RM_XACT_ID {
+        recid & 0x07
+    } else {
+        recid
+    }
+}
+
+/// Statistics of the records between `start_lsn` and `end_lsn`, by resource
+/// manager or by record type with `per_record`
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn pg_get_wal_stats(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    per_record: default!(bool, false),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(resource_manager_or_record_type, String),
+        name!(count, i64),
+        name!(count_percentage, f64),
+        name!(record_size, i64),
+        name!(record_size_percentage, f64),
+        name!(fpi_size, i64),
+        name!(fpi_size_percentage, f64),
+        name!(combined_size, i64),
+        name!(combined_size_percentage, f64),
+    ),
+> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut stats: BTreeMap<(u8, u8), WalStats> = BTreeMap::new();
+    while let Some(record) = wal_decoder.read_record() {
+        let (_, fpi_len) = block_ref_info(wal_decoder.xlog_reader());
+        let rmid = record.header.xl_rmid;
+        let recid = record_type_id(rmid, record.header.xl_info);
+        stats.entry((rmid, recid)).or_default().add(
+            u64::from(record.header.xl_tot_len - fpi_len),
+            u64::from(fpi_len),
+        );
+    }
+
+    let rows: Vec<(String, WalStats)> = if per_record {
+        stats
+            .into_iter()
+            .map(|((rmid, recid), stats)| {
+                let name = format!(
+                    "{}/{}",
+                    rmgr_display_name(rmid),
+                    record_type_name(rmid, recid << 4)
+                );
+                (name, stats)
+            })
+            .collect()
+    } else {
+        // Like pg_walinspect, every existing resource manager is listed
+        let mut by_rmgr: BTreeMap<u8, WalStats> = (0..=u8::MAX)
+            .filter(|rmid| !rmgr_data(*rmid).rm_name.is_null())
+            .map(|rmid| (rmid, WalStats::default()))
+            .collect();
+        for ((rmid, _), stats) in stats {
+            let total = by_rmgr.entry(rmid).or_default();
+            total.count += stats.count;
+            total.record_size += stats.record_size;
+            total.fpi_size += stats.fpi_size;
+        }
+        by_rmgr
+            .into_iter()
+            .map(|(rmid, stats)| (rmgr_display_name(rmid), stats))
+            .collect()
+    };
+
+    let total_count = rows.iter().map(|(_, s)| s.count).sum();
+    let total_record_size = rows.iter().map(|(_, s)| s.record_size).sum();
+    let total_fpi_size = rows.iter().map(|(_, s)| s.fpi_size).sum();
+    let total_size = total_record_size + total_fpi_size;
+    let as_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+    TableIterator::new(rows.into_iter().map(move |(name, stats)| {
+        let combined_size = stats.record_size + stats.fpi_size;
+        (
+            name,
+            as_i64(stats.count),
+            percentage(stats.count, total_count),
+            as_i64(stats.record_size),
+            percentage(stats.record_size, total_record_size),
+            as_i64(stats.fpi_size),
+            percentage(stats.fpi_size, total_fpi_size),
+            as_i64(combined_size),
+            percentage(combined_size, total_size),
+        )
+    }))
+}
+
+/// Flags of a full page image as named by pg_walinspect
+fn block_fpi_info(bimg_info: u8) -> Vec<String> {
+    let flags = [
+        (pg_sys::BKPIMAGE_HAS_HOLE, "HAS_HOLE"),
+        (pg_sys::BKPIMAGE_COMPRESS_PGLZ, "COMPRESS_PGLZ"),
+        (pg_sys::BKPIMAGE_COMPRESS_LZ4, "COMPRESS_LZ4"),
+        (pg_sys::BKPIMAGE_COMPRESS_ZSTD, "COMPRESS_ZSTD"),
+        (pg_sys::BKPIMAGE_APPLY, "APPLY"),
+    ];
+    flags
+        .into_iter()
+        .filter(|(flag, _)| u32::from(bimg_info) & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// Row of `pg_get_wal_block_info`
+type BlockInfoRow = (
+    name!(start_lsn, PgLSN),
+    name!(end_lsn, PgLSN),
+    name!(prev_lsn, PgLSN),
+    name!(block_id, i16),
+    name!(reltablespace, pg_sys::Oid),
+    name!(reldatabase, pg_sys::Oid),
+    name!(relfilenode, pg_sys::Oid),
+    name!(relforknumber, i16),
+    name!(relblocknumber, i64),
+    name!(xid, pg_sys::TransactionId),
+    name!(resource_manager, String),
+    name!(record_type, String),
+    name!(record_length, i32),
+    name!(main_data_length, i32),
+    name!(block_data_length, i32),
+    name!(block_fpi_length, i32),
+    name!(block_fpi_info, Option<Vec<String>>),
+    name!(description, Option<String>),
+    name!(block_data, Option<Vec<u8>>),
+    name!(block_fpi_data, Option<Vec<u8>>),
+);
+
+/// Page of the full page image of a block reference, raises an error if it
+/// can't be restored
+pub(crate) fn restore_block_image(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    block_id: u8,
+) -> Vec<u8> {
+    let mut page = vec![0u8; pg_sys::BLCKSZ as usize];
+    let restored = unsafe {
+        pg_sys::RestoreBlockImage(xlog_reader.as_ptr(), block_id, page.as_mut_ptr().cast())
+    };
+    if !restored {
+        error!(
+            "could not restore image of block {block_id} at {}",
+            PgLSN::from(record.lsn)
+        );
+    }
+    page
+}
+
+/// A row per block reference of the latest record read
+fn block_info_rows(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    show_data: bool,
+) -> Vec<BlockInfoRow> {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    let rmid = record.header.xl_rmid;
+    let record_type = record_type_name(rmid, record.header.xl_info);
+    let description = record_description(xlog_reader, rmid);
+    let mut rows = Vec::new();
+    for block_id in 0..=max_block_id {
+        let Some(block) = get_block(record, block_id) else {
+            continue;
+        };
+        let Some((rlocator, forknum, blknum)) = get_block_tag_extended(xlog_reader, block_id)
+        else {
+            continue;
+        };
+        let block_data = show_data
+            .then(|| get_block_data(xlog_reader, block_id).map(<[u8]>::to_vec))
+            .flatten();
+        let fpi_data = (show_data && block.has_image)
+            .then(|| restore_block_image(xlog_reader, record, block_id));
+        rows.push((
+            PgLSN::from(record.lsn),
+            PgLSN::from(xlog_reader.EndRecPtr),
+            PgLSN::from(record.header.xl_prev),
+            i16::from(block_id),
+            rlocator.spcOid,
+            rlocator.dbOid,
+            rlocator.relNumber,
+            i16::try_from(forknum).unwrap_or(-1),
+            i64::from(blknum),
+            record.header.xl_xid,
+            rmgr_display_name(rmid),
+            record_type.clone(),
+            i32::try_from(record.header.xl_tot_len).unwrap_or(i32::MAX),
+            i32::try_from(record.main_data_len).unwrap_or(i32::MAX),
+            i32::from(if block.has_data { block.data_len } else { 0 }),
+            i32::from(if block.has_image { block.bimg_len } else { 0 }),
+            block.has_image.then(|| block_fpi_info(block.bimg_info)),
+            description.clone(),
+            block_data,
+            fpi_data,
+        ));
+    }
+    rows
+}
+
+/// A row per block referenced by the records between `start_lsn` and
+/// `end_lsn`, with the block data and full page images unless `show_data`
+/// is false
+#[pg_extern]
+fn pg_get_wal_block_info(
+    start_lsn: PgLSN,
+    end_lsn: PgLSN,
+    show_data: default!(bool, true),
+    timeline: default!(Option<i32>, "NULL"),
+    wal_dir: default!(Option<&str>, "NULL"),
+) -> TableIterator<'static, BlockInfoRow> {
+    let end_lsn = lsn_bounds(start_lsn, end_lsn);
+    let mut wal_decoder = wal_decoder(start_lsn, Some(&end_lsn), timeline, wal_dir);
+    let mut rows = Vec::new().into_iter();
+    TableIterator::new(std::iter::from_fn(move || loop {
+        if let Some(row) = rows.next() {
+            return Some(row);
+        }
+        let record = wal_decoder.read_record()?;
+        rows = block_info_rows(wal_decoder.xlog_reader(), &record, show_data).into_iter();
+    }))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::{
+        pg_lsn::PgLSN,
+        walinspect::{block_fpi_info, record_type_id},
+    };
+
+    #[test]
+    fn test_record_type_id() {
+        // XLOG_XACT_COMMIT with XLOG_XACT_HAS_INFO is still a commit
+        assert_eq!(record_type_id(1, 0x80), 0);
+        assert_eq!(record_type_id(10, 0x80), 8);
+    }
+
+    #[test]
+    fn test_block_fpi_info() {
+        assert_eq!(block_fpi_info(0x03), vec!["HAS_HOLE", "APPLY"]);
+    }
+
+    #[pg_test]
+    fn test_pg_get_wal_records_info() {
+        unsafe {
+            Spi::run("CREATE TABLE test_walinspect (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_walinspect values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let inserts = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_get_wal_records_info('{startptr}', '{endptr}', timeline => 1)
+             WHERE resource_manager = 'Heap' AND record_type LIKE 'INSERT%'"
+        ));
+        assert_eq!(inserts, Ok(Some(1)));
+        let heap_count = Spi::get_one::<i64>(&format!(
+            "SELECT count FROM pg_get_wal_stats('{startptr}', '{endptr}', timeline => 1)
+             WHERE resource_manager_or_record_type = 'Heap'"
+        ));
+        assert_eq!(heap_count, Ok(Some(1)));
+        let blocks = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM pg_get_wal_block_info('{startptr}', '{endptr}', timeline => 1)
+             WHERE resource_manager = 'Heap'"
+        ));
+        assert_eq!(blocks, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_record_at() {
+        unsafe {
+            Spi::run("CREATE TABLE test_record_at (id int);");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            Spi::run("INSERT INTO test_record_at values (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        let insert_lsn = Spi::get_one::<PgLSN>(&format!(
+            "SELECT start_lsn FROM pg_get_wal_records_info('{startptr}', '{endptr}', timeline => 1)
+             WHERE resource_manager = 'Heap' AND record_type LIKE 'INSERT%'"
+        ))
+        .unwrap()
+        .unwrap();
+
+        // An LSN in the middle of the record
+        let inside = PgLSN::from(u64::from(insert_lsn) + 10);
+        let (start_lsn, op, row_after) = Spi::get_three::<PgLSN, String, String>(&format!(
+            "SELECT start_lsn, op, row_after FROM pg_waldecoder_record_at('{inside}', timeline => 1)"
+        ))
+        .unwrap();
+        assert_eq!(start_lsn, Some(insert_lsn));
+        assert!(op.unwrap().starts_with("INSERT"));
+        assert_eq!(row_after.as_deref(), Some("(1)"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+
+use pgrx::{pg_sys, IntoDatum, PgBox, TimestampWithTimeZone};
+
+/// Xids below this one are special and don't belong to an epoch
+pub(crate) const FIRST_NORMAL_TRANSACTION_ID: u32 = 3;
+
+/// 64-bit transaction id including its epoch, returned as `xid8`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FullXid(pub u64);
+
+impl IntoDatum for FullXid {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        Some(pg_sys::Datum::from(self.0))
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        pg_sys::XID8OID
+    }
+}
+
+/// Widen a 32-bit xid using a nearby full xid as reference. Like
+/// `FullTransactionIdFromAllowableAt`, the xid is assumed to be less than 2^31
+/// transactions away from the reference.
+fn widen_xid(reference: u64, xid: u32) -> Option<FullXid> {
+    if xid == 0 {
+        return None;
+    }
+    if xid < FIRST_NORMAL_TRANSACTION_ID {
+        return Some(FullXid(u64::from(xid)));
+    }
+    let reference_xid = u32::try_from(reference & 0xFFFF_FFFF).unwrap();
+    let distance = i64::from(xid.wrapping_sub(reference_xid).cast_signed());
+    reference.checked_add_signed(distance).map(FullXid)
+}
+
+/// Epoch of the decoded xids, taken from the next xid of the latest
+/// checkpoint record
+pub struct XidEpoch {
+    /// Next full xid at a point close to the decoded records
+    reference: Option<u64>,
+}
+
+impl XidEpoch {
+    pub fn new(reference: Option<u64>) -> XidEpoch {
+        XidEpoch { reference }
+    }
+
+    /// Use the next xid of a checkpoint record as the new reference
+    fn abort(&mut self, xid: pg_sys::TransactionId, subxacts: &[pg_sys::TransactionId]) {
+        self.aborted.insert(xid.into_inner());
+        self.aborted
+            .extend(subxacts.iter().map(|subxact| subxact.into_inner()));
+    }
+
+    pub fn observe(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>) {
+        let info = u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK;
+        if (info != pg_sys::XLOG_CHECKPOINT_SHUTDOWN && info != pg_sys::XLOG_CHECKPOINT_ONLINE)
+            || record.main_data.is_null()
+        {
+            return;
+        }
+        let checkpoint =
+            unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::CheckPoint>()) };
+        self.reference = Some(checkpoint.nextXid.value);
+    }
+
+    /// Full xid of a decoded xid, None until the epoch is known
+    pub fn full_xid(&self, xid: pg_sys::TransactionId) -> Option<FullXid> {
+        widen_xid(self.reference?, xid.into_inner())
+    }
+}
+
+/// Commit time of a transaction from the server's commit timestamps, None
+/// while it's in progress or once its timestamp was truncated
+pub fn commit_timestamp(xid: pg_sys::TransactionId) -> Option<TimestampWithTimeZone> {
+    if xid.into_inner() < FIRST_NORMAL_TRANSACTION_ID {
+        return None;
+    }
+    let mut timestamp: pg_sys::TimestampTz = 0;
+    let found = unsafe {
+        pg_sys::TransactionIdGetCommitTsData(xid, &raw mut timestamp, std::ptr::null_mut())
+    };
+    if !found {
+        return None;
+    }
+    TimestampWithTimeZone::try_from(timestamp).ok()
+}
+
+/// Commit time stored in a commit record
+pub fn xact_commit_time(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<pg_sys::TimestampTz> {
+    let info = u32::from(record.header.xl_info) & pg_sys::XLOG_XACT_OPMASK;
+    if (info != pg_sys::XLOG_XACT_COMMIT && info != pg_sys::XLOG_XACT_COMMIT_PREPARED)
+        || record.main_data.is_null()
+    {
+        return None;
+    }
+    let xlrec =
+        unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_xact_commit>()) };
+    Some(xlrec.xact_time)
+}
+
+/// End of a transaction read from its commit or abort record
+pub struct XactEnd {
+    /// Top level xid, the prepared transaction's xid for 2PC records
+    pub xid: pg_sys::TransactionId,
+    pub committed: bool,
+    pub time: pg_sys::TimestampTz,
+    pub subxacts: Vec<pg_sys::TransactionId>,
+}
+
+/// Parse a commit or abort record, prepared transactions included, None for
+/// the other xact records
+pub fn xact_end(record: &PgBox<pg_sys::DecodedXLogRecord>) -> Option<XactEnd> {
+    if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_XACT_ID || record.main_data.is_null()
+    {
+        return None;
+    }
+    let info = record.header.xl_info;
+    let (committed, time, nsubxacts, subxacts, twophase_xid) =
+        match u32::from(info) & pg_sys::XLOG_XACT_OPMASK {
+            pg_sys::XLOG_XACT_COMMIT | pg_sys::XLOG_XACT_COMMIT_PREPARED => {
+                let mut parsed = unsafe { std::mem::zeroed::<pg_sys::xl_xact_parsed_commit>() };
+                unsafe {
+                    pg_sys::ParseCommitRecord(info, record.main_data.cast(), &raw mut parsed);
+                }
+                (
+                    true,
+                    parsed.xact_time,
+                    parsed.nsubxacts,
+                    parsed.subxacts,
+                    parsed.twophase_xid,
+                )
+            }
+            pg_sys::XLOG_XACT_ABORT | pg_sys::XLOG_XACT_ABORT_PREPARED => {
+                let mut parsed = unsafe { std::mem::zeroed::<pg_sys::xl_xact_parsed_abort>() };
+                unsafe {
+                    pg_sys::ParseAbortRecord(info, record.main_data.cast(), &raw mut parsed);
+                }
+                (
+                    false,
+                    parsed.xact_time,
+                    parsed.nsubxacts,
+                    parsed.subxacts,
+                    parsed.twophase_xid,
+                )
+            }
+            _ => return None,
+        };
+    let subxacts = match usize::try_from(nsubxacts) {
+        Ok(nsubxacts) if nsubxacts > 0 && !subxacts.is_null() => {
+            unsafe { std::slice::from_raw_parts(subxacts, nsubxacts) }.to_vec()
+        }
+        _ => Vec::new(),
+    };
+    let xid = if twophase_xid.into_inner() == 0 {
+        record.header.xl_xid
+    } else {
+        twophase_xid
+    };
+    Some(XactEnd {
+        xid,
+        committed,
+        time,
+        subxacts,
+    })
+}
+
+/// Top level transaction of the subtransactions seen so far, learned from
+/// the top level xid logged with the first record of a subtransaction when
+/// wal_level is logical, from assignment records and from the subtransaction
+/// lists of commit and abort records. Below wal_level logical, the changes of
+/// a subtransaction read before its assignment or its commit keep their own
+/// xid. Abort records seen, including those of subtransactions rolled back to
+/// a savepoint, are kept to tell apart the changes that didn't survive.
+#[derive(Default)]
+pub struct SubxactTree {
+    parents: HashMap<u32, pg_sys::TransactionId>,
+    aborted: HashSet<u32>,
+}
+
+impl SubxactTree {
+    fn assign(&mut self, top: pg_sys::TransactionId, subxacts: &[pg_sys::TransactionId]) {
+        for subxact in subxacts {
+            if *subxact != top {
+                self.parents.insert(subxact.into_inner(), top);
+            }
+        }
+    }
+
+    pub fn observe(&mut self, record: &PgBox<pg_sys::DecodedXLogRecord>) {
+        let xid = record.header.xl_xid;
+        if record.toplevel_xid.into_inner() != 0 {
+            self.assign(record.toplevel_xid, &[xid]);
+        }
+        if u32::from(record.header.xl_rmid) != pg_sys::RmgrIds::RM_XACT_ID {
+            return;
+        }
+        let info = u32::from(record.header.xl_info) & pg_sys::XLOG_XACT_OPMASK;
+        if info == pg_sys::XLOG_XACT_ASSIGNMENT {
+            let xids_offset = std::mem::offset_of!(pg_sys::xl_xact_assignment, xsub);
+            if record.main_data.is_null() || (record.main_data_len as usize) < xids_offset {
+                return;
+            }
+            let xlrec = unsafe {
+                std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_xact_assignment>())
+            };
+            let nsubxacts = usize::try_from(xlrec.nsubxacts).unwrap_or(0).min(
+                (record.main_data_len as usize - xids_offset) / size_of::<pg_sys::TransactionId>(),
+            );
+            let subxacts: Vec<pg_sys::TransactionId> = (0..nsubxacts)
+                .map(|i| unsafe {
+                    std::ptr::read_unaligned(
+                        record
+                            .main_data
+                            .add(xids_offset + i * size_of::<pg_sys::TransactionId>())
+                            .cast::<pg_sys::TransactionId>(),
+                    )
+                })
+                .collect();
+            self.assign(xlrec.xtop, &subxacts);
+        } else if let Some(xact_end) = xact_end(record) {
+            self.assign(xact_end.xid, &xact_end.subxacts);
+            if !xact_end.committed {
+                self.abort(xact_end.xid, &xact_end.subxacts);
+            }
+        }
+    }
+
+    /// Top level xid of a transaction, the xid itself when it's not known as
+    /// a subtransaction
+    pub fn toplevel(&self, xid: pg_sys::TransactionId) -> pg_sys::TransactionId {
+        self.parents.get(&xid.into_inner()).copied().unwrap_or(xid)
+    }
+
+    /// Whether an abort record of the transaction, or of its top level
+    /// transaction, was seen
+    pub fn is_aborted(&self, xid: pg_sys::TransactionId) -> bool {
+        self.aborted.contains(&xid.into_inner())
+            || self.aborted.contains(&self.toplevel(xid).into_inner())
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::xid::{widen_xid, FullXid, SubxactTree};
+    use pgrx::prelude::*;
+
+    #[test]
+    fn test_widen_xid() {
+        let epoch = 5u64 << 32;
+        assert_eq!(widen_xid(epoch + 1000, 900), Some(FullXid(epoch + 900)));
+        assert_eq!(widen_xid(epoch + 1000, 1100), Some(FullXid(epoch + 1100)));
+        // Xids from before and after a wraparound of the reference
+        assert_eq!(
+            widen_xid(epoch + 10, u32::MAX - 5),
+            Some(FullXid(epoch - 6))
+        );
+        assert_eq!(widen_xid(epoch - 10, 20), Some(FullXid(epoch + 20)));
+        assert_eq!(widen_xid(epoch + 1000, 2), Some(FullXid(2)));
+        assert_eq!(widen_xid(epoch + 1000, 0), None);
+        assert_eq!(widen_xid(10, u32::MAX - 5), None);
+    }
+
+    #[test]
+    fn test_subxact_tree() {
+        let xid = |xid: u32| pg_sys::TransactionId::from(xid);
+        let mut tree = SubxactTree::default();
+        tree.assign(xid(750), &[xid(751), xid(752), xid(750)]);
+        assert_eq!(tree.toplevel(xid(752)), xid(750));
+        assert_eq!(tree.toplevel(xid(750)), xid(750));
+        assert_eq!(tree.toplevel(xid(800)), xid(800));
+
+        // A subtransaction rolled back to its savepoint, then the transaction
+        tree.abort(xid(751), &[]);
+        assert!(tree.is_aborted(xid(751)));
+        assert!(!tree.is_aborted(xid(752)));
+        tree.abort(xid(750), &[xid(752)]);
+        assert!(tree.is_aborted(xid(752)));
+    }
+}
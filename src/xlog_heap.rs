@@ -1,114 +1,1497 @@
-use std::{collections::HashMap, ffi::CStr};
+use std::{
+    ffi::CStr,
+    mem::{offset_of, size_of},
+};
 
 use pgrx::{
-    PgBox, pg_sys::{self, ItemPointerSetInvalid, RelFileLocator}
+    error,
+    pg_sys::{self, HeapTupleHeaderData, ItemPointerData, OffsetNumber, Page},
+    warning, PgBox, PgRelation,
 };
 
 use crate::{
-    decoder::{DecodedResult, PageId}, relation::get_relid_from_rlocator, xlog_reader::get_block_tag,
+    column_types::TupleDescCache,
+    conflict::{conflicting_revert, revert_conflict},
+    dblink::OtherDatabases,
+    ddl::catalog_ddl,
+    decoder::{DecodedResult, OnError, PageId},
+    guc::{verbose, Verbosity},
+    masking::MaskCache,
+    page::{
+        page_clear_all_visible, page_get_lsn, page_get_max_offset_number, page_get_normal_item_id,
+        page_set_lsn, page_set_prunable,
+    },
+    page_cache::PageCache,
+    pg_lsn::PgLSN,
+    relation::{classify_database, persistence_name, RecordDatabase, RelidCache},
+    rmgr::{DecodeContext, RmgrDecoder},
+    timing::{timed, Phase},
+    toast::ToastChunks,
+    tuple_str::{
+        changes_json, format_row, generate_key_query, generate_queries, query_fingerprint,
+        quote_identifier, relation_name, tuple_fits_desc, tuple_values_in, JsonbText,
+    },
+    xlog_reader::{
+        get_block_data, get_block_tag, get_block_tag_extended, has_block_image_to_apply,
+    },
 };
 
-fn item_pointer_set_invalid(mut item_pointer: pg_sys::ItemPointerData) {
-    assert!(item_pointer.ip_posid != 0);
-    item_pointer.ip_blkid.bi_hi = 0xFFFF;
-    item_pointer.ip_blkid.bi_lo = 0xFFFF;
-    item_pointer.ip_posid = pg_sys::InvalidOffsetNumber;
+/// Size of the fixed part of a heap tuple header, `SizeofHeapTupleHeader`
+const SIZEOF_HEAP_TUPLE_HEADER: usize = offset_of!(HeapTupleHeaderData, t_bits);
+/// Size of an `xl_heap_header` without padding, `SizeOfHeapHeader`
+const SIZE_OF_HEAP_HEADER: usize = offset_of!(pg_sys::xl_heap_header, t_hoff) + 1;
+/// Size of an `xl_heap_delete`, `SizeOfHeapDelete`
+const SIZE_OF_HEAP_DELETE: usize = offset_of!(pg_sys::xl_heap_delete, flags) + 1;
+/// Size of an `xl_heap_update`, `SizeOfHeapUpdate`
+const SIZE_OF_HEAP_UPDATE: usize =
+    offset_of!(pg_sys::xl_heap_update, new_offnum) + size_of::<OffsetNumber>();
+const FIRST_COMMAND_ID: pg_sys::CommandId = 0;
+
+/// Outcome of preparing a page for redo, mirrors `XLogRedoAction`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RedoAction {
+    /// Changes of the record need to be applied on the page
+    NeedsRedo,
+    /// The page was restored from an image or is already newer than the record
+    Done,
+}
+
+fn mask(bits: u32) -> u16 {
+    u16::try_from(bits).unwrap()
+}
+
+pub fn item_pointer(blknum: pg_sys::BlockNumber, offnum: OffsetNumber) -> ItemPointerData {
+    ItemPointerData {
+        ip_blkid: pg_sys::BlockIdData {
+            bi_hi: (blknum >> 16) as u16,
+            bi_lo: (blknum & 0xffff) as u16,
+        },
+        ip_posid: offnum,
+    }
+}
+
+/// Returns the page id of a block reference of the latest decoded record
+fn block_page_id(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    block_id: u8,
+) -> Option<(PageId, pg_sys::BlockNumber)> {
+    let (rlocator, forknum, blknum) = get_block_tag_extended(xlog_reader, block_id)?;
+    Some((PageId::new(&rlocator, forknum, blknum), blknum))
+}
+
+/// Restore the full page image of a block, overwriting the cached version of
+/// the page if there's one
+pub fn restore_fpw(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    block_id: u8,
+    page_id: &PageId,
+    page_cache: &mut PageCache,
+) -> Page {
+    let page = page_cache
+        .peek(page_id)
+        .unwrap_or_else(|| page_cache.alloc_page())
+        .cast::<i8>();
+    page_cache.stats.restored_images += 1;
+    let restored = timed(Phase::RestoreFpi, || unsafe {
+        pg_sys::RestoreBlockImage(xlog_reader.as_ptr(), block_id, page)
+    });
+    if !restored {
+        let errormsg = unsafe { CStr::from_ptr(xlog_reader.errormsg_buf) };
+        error!(
+            "Could not restore image of block {} at {}: {}",
+            block_id,
+            xlog_reader.ReadRecPtr,
+            errormsg.to_string_lossy()
+        );
+    }
+    page.cast()
 }
 
-fn get_heap_tuple(
+/// Get the cached page of a block reference ready for redo, mirrors
+/// `XLogReadBufferForRedoExtended`.
+///
+/// Returns None if the page was never seen in a full page image.
+fn read_page_for_redo(
     xlog_reader: &PgBox<pg_sys::XLogReaderState>,
     record: &PgBox<pg_sys::DecodedXLogRecord>,
-    page: pg_sys::Page,
-    relid: pg_sys::Oid,
-    old: bool,
-) -> Option<pg_sys::HeapTuple> {
-    let main_data = record.main_data;
-    let offnum = match u32::from(record.header.xl_info) & (pg_sys::XLOG_HEAP_OPMASK) {
-        pg_sys::XLOG_HEAP_INSERT => {
-            if old {
-                // No previous row version available
-                return None;
+    block_id: u8,
+    init_page: bool,
+    page_cache: &mut PageCache,
+) -> Option<(Page, RedoAction)> {
+    let (page_id, _) = block_page_id(xlog_reader, block_id)?;
+    if has_block_image_to_apply(record, block_id) {
+        let page = restore_fpw(xlog_reader, block_id, &page_id, page_cache);
+        page_cache.insert(page_id, page);
+        return Some((page, RedoAction::Done));
+    }
+    if init_page {
+        let page = page_cache
+            .peek(&page_id)
+            .unwrap_or_else(|| page_cache.alloc_page());
+        page_cache.insert(page_id, page);
+        unsafe { pg_sys::PageInit(page, pg_sys::BLCKSZ as usize, 0) };
+        return Some((page, RedoAction::NeedsRedo));
+    }
+    let page = page_cache.get(&page_id)?;
+    if unsafe { page_get_lsn(page) } >= xlog_reader.EndRecPtr {
+        return Some((page, RedoAction::Done));
+    }
+    Some((page, RedoAction::NeedsRedo))
+}
+
+/// Update the xmax flags of a tuple from the infobits stored in a record
+fn fix_infomask_from_infobits(infobits: u8, infomask: &mut u16, infomask2: &mut u16) {
+    let infobits = u32::from(infobits);
+    *infomask &= !mask(
+        pg_sys::HEAP_XMAX_IS_MULTI
+            | pg_sys::HEAP_XMAX_LOCK_ONLY
+            | pg_sys::HEAP_XMAX_KEYSHR_LOCK
+            | pg_sys::HEAP_XMAX_EXCL_LOCK,
+    );
+    *infomask2 &= !mask(pg_sys::HEAP_KEYS_UPDATED);
+
+    if infobits & pg_sys::XLHL_XMAX_IS_MULTI != 0 {
+        *infomask |= mask(pg_sys::HEAP_XMAX_IS_MULTI);
+    }
+    if infobits & pg_sys::XLHL_XMAX_LOCK_ONLY != 0 {
+        *infomask |= mask(pg_sys::HEAP_XMAX_LOCK_ONLY);
+    }
+    if infobits & pg_sys::XLHL_XMAX_EXCL_LOCK != 0 {
+        *infomask |= mask(pg_sys::HEAP_XMAX_EXCL_LOCK);
+    }
+    // HEAP_XMAX_SHR_LOCK isn't considered here
+    if infobits & pg_sys::XLHL_XMAX_KEYSHR_LOCK != 0 {
+        *infomask |= mask(pg_sys::HEAP_XMAX_KEYSHR_LOCK);
+    }
+    if infobits & pg_sys::XLHL_KEYS_UPDATED != 0 {
+        *infomask2 |= mask(pg_sys::HEAP_KEYS_UPDATED);
+    }
+}
+
+/// Get the header of the normal tuple at offnum
+fn page_get_tuple_header(page: Page, offnum: OffsetNumber) -> Option<*mut HeapTupleHeaderData> {
+    let item_id = unsafe { page_get_normal_item_id(page, offnum) }?;
+    Some(unsafe { pg_sys::PageGetItem(page, item_id) }.cast())
+}
+
+/// Set xmax and the lock/update flags of an existing tuple
+fn set_tuple_xmax(
+    htup: *mut HeapTupleHeaderData,
+    xmax: pg_sys::TransactionId,
+    infobits: u8,
+    hot_updated: bool,
+) {
+    unsafe {
+        let htup = &mut *htup;
+        htup.t_infomask &= !mask(pg_sys::HEAP_XMAX_BITS | pg_sys::HEAP_MOVED);
+        htup.t_infomask2 &= !mask(pg_sys::HEAP_KEYS_UPDATED);
+        if hot_updated {
+            htup.t_infomask2 |= mask(pg_sys::HEAP_HOT_UPDATED);
+        } else {
+            htup.t_infomask2 &= !mask(pg_sys::HEAP_HOT_UPDATED);
+        }
+        fix_infomask_from_infobits(infobits, &mut htup.t_infomask, &mut htup.t_infomask2);
+        htup.t_choice.t_heap.t_xmax = xmax;
+        // Set cmax, not a combo cid
+        htup.t_choice.t_heap.t_field3.t_cid = FIRST_COMMAND_ID;
+        htup.t_infomask &= !mask(pg_sys::HEAP_COMBOCID);
+    }
+}
+
+/// Build a tuple from its `xl_heap_header` and data as stored in a record
+fn form_heap_tuple(
+    xlhdr_and_data: &[u8],
+    xid: pg_sys::TransactionId,
+    xmax: pg_sys::TransactionId,
+    tid: ItemPointerData,
+) -> Option<Vec<u8>> {
+    if xlhdr_and_data.len() <= SIZE_OF_HEAP_HEADER {
+        return None;
+    }
+    let (xlhdr, data) = xlhdr_and_data.split_at(SIZE_OF_HEAP_HEADER);
+
+    let mut htup: HeapTupleHeaderData = unsafe { std::mem::zeroed() };
+    htup.t_infomask2 = u16::from_ne_bytes([xlhdr[0], xlhdr[1]]);
+    htup.t_infomask = u16::from_ne_bytes([xlhdr[2], xlhdr[3]]);
+    htup.t_hoff = xlhdr[4];
+    htup.t_choice.t_heap.t_xmin = xid;
+    htup.t_choice.t_heap.t_xmax = xmax;
+    htup.t_choice.t_heap.t_field3.t_cid = FIRST_COMMAND_ID;
+    htup.t_ctid = tid;
+
+    // PG73FORMAT: header, then bitmap [+ padding] [+ oid] + data
+    let header = unsafe {
+        std::slice::from_raw_parts((&raw const htup).cast::<u8>(), SIZEOF_HEAP_TUPLE_HEADER)
+    };
+    let mut tuple = Vec::with_capacity(SIZEOF_HEAP_TUPLE_HEADER + data.len());
+    tuple.extend_from_slice(header);
+    tuple.extend_from_slice(data);
+    Some(tuple)
+}
+
+/// Add a tuple at offnum, mirrors `PageAddItem(page, item, size, offnum, true, true)`.
+///
+/// Prune records aren't replayed so the line pointer may still hold a dead
+/// tuple. It is then discarded to make room for the new one.
+fn page_add_tuple(page: Page, tuple: &[u8], offnum: OffsetNumber) -> bool {
+    let flags = (pg_sys::PAI_OVERWRITE | pg_sys::PAI_IS_HEAP).cast_signed();
+    let add = || unsafe {
+        pg_sys::PageAddItemExtended(
+            page,
+            tuple.as_ptr().cast_mut().cast(),
+            tuple.len(),
+            offnum,
+            flags,
+        )
+    };
+    if add() != pg_sys::InvalidOffsetNumber {
+        return true;
+    }
+    if offnum > unsafe { page_get_max_offset_number(page) } {
+        return false;
+    }
+    unsafe {
+        let item_id = pg_sys::PageGetItemId(page, offnum);
+        (*item_id).set_lp_flags(pg_sys::LP_UNUSED);
+        (*item_id).set_lp_off(0);
+        (*item_id).set_lp_len(0);
+        pg_sys::PageRepairFragmentation(page);
+    }
+    add() != pg_sys::InvalidOffsetNumber
+}
+
+/// Drop a page whose content can't be kept in sync with the WAL anymore
+fn invalidate_page(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    block_id: u8,
+    page_cache: &mut PageCache,
+) {
+    if let Some((page_id, _)) = block_page_id(xlog_reader, block_id) {
+        if page_cache.remove(&page_id) {
+            warning!(
+                "Could not apply record at {} on block {}, dropping cached page",
+                xlog_reader.ReadRecPtr,
+                block_id
+            );
+        }
+    }
+}
+
+fn redo_heap_insert(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_insert>()) };
+    let init_page = u32::from(record.header.xl_info) & pg_sys::XLOG_HEAP_INIT_PAGE != 0;
+    let Some((page, RedoAction::NeedsRedo)) =
+        read_page_for_redo(xlog_reader, record, 0, init_page, page_cache)
+    else {
+        return;
+    };
+    let Some((_, blknum)) = block_page_id(xlog_reader, 0) else {
+        return;
+    };
+
+    let tuple = get_block_data(xlog_reader, 0).and_then(|data| {
+        form_heap_tuple(
+            data,
+            record.header.xl_xid,
+            pg_sys::InvalidTransactionId,
+            item_pointer(blknum, xlrec.offnum),
+        )
+    });
+    match tuple {
+        Some(tuple) if page_add_tuple(page, &tuple, xlrec.offnum) => unsafe {
+            if u32::from(xlrec.flags) & pg_sys::XLH_INSERT_ALL_VISIBLE_CLEARED != 0 {
+                page_clear_all_visible(page);
             }
-            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_insert>()) };
-            xlrec.offnum
+            page_set_lsn(page, xlog_reader.EndRecPtr);
+        },
+        _ => invalidate_page(xlog_reader, 0, page_cache),
+    }
+}
+
+fn redo_heap_delete(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_delete>()) };
+    let Some((page, RedoAction::NeedsRedo)) =
+        read_page_for_redo(xlog_reader, record, 0, false, page_cache)
+    else {
+        return;
+    };
+    let Some((_, blknum)) = block_page_id(xlog_reader, 0) else {
+        return;
+    };
+    let Some(htup) = page_get_tuple_header(page, xlrec.offnum) else {
+        invalidate_page(xlog_reader, 0, page_cache);
+        return;
+    };
+
+    let flags = u32::from(xlrec.flags);
+    set_tuple_xmax(htup, xlrec.xmax, xlrec.infobits_set, false);
+    unsafe {
+        if flags & pg_sys::XLH_DELETE_IS_SUPER != 0 {
+            // Super-deletion of a speculative insertion kills the tuple
+            (*htup).t_choice.t_heap.t_xmax = pg_sys::InvalidTransactionId;
+            (*htup).t_choice.t_heap.t_xmin = pg_sys::InvalidTransactionId;
         }
-        pg_sys::XLOG_HEAP_DELETE => {
-            if !old {
-                // No next row version available
-                return None;
+        (*htup).t_ctid = if flags & pg_sys::XLH_DELETE_IS_PARTITION_MOVE != 0 {
+            item_pointer(
+                pg_sys::InvalidBlockNumber,
+                OffsetNumber::try_from(pg_sys::MovedPartitionsOffsetNumber).unwrap(),
+            )
+        } else {
+            item_pointer(blknum, xlrec.offnum)
+        };
+        page_set_prunable(page, record.header.xl_xid);
+        if flags & pg_sys::XLH_DELETE_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(page);
+        }
+        page_set_lsn(page, xlog_reader.EndRecPtr);
+    }
+}
+
+/// Get the data of the normal tuple at offnum, past its header
+fn page_get_tuple_data(page: Page, offnum: OffsetNumber) -> Option<Vec<u8>> {
+    let item_id = unsafe { page_get_normal_item_id(page, offnum) }?;
+    unsafe {
+        let htup = pg_sys::PageGetItem(page, item_id).cast::<u8>();
+        let hoff = usize::from((*htup.cast::<HeapTupleHeaderData>()).t_hoff);
+        let len = usize::try_from((*item_id).lp_len()).unwrap();
+        let tuple = std::slice::from_raw_parts(htup, len);
+        tuple.get(hoff..).map(<[u8]>::to_vec)
+    }
+}
+
+/// Rebuild the `xl_heap_header` and data of an update's new tuple, mirrors
+/// `heap_xlog_update`.
+///
+/// When the old and new tuples are on the same page, the record may only
+/// contain the changed middle of the new tuple. The unchanged prefix and
+/// suffix are then copied from the old tuple.
+fn stitch_update_tuple(data: &[u8], flags: u32, old_tuple_data: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut rest = data;
+    let read_len = |rest: &mut &[u8]| -> Option<usize> {
+        let (len, tail) = rest.split_first_chunk::<2>()?;
+        *rest = tail;
+        Some(usize::from(u16::from_ne_bytes(*len)))
+    };
+    let prefixlen = if flags & pg_sys::XLH_UPDATE_PREFIX_FROM_OLD != 0 {
+        read_len(&mut rest)?
+    } else {
+        0
+    };
+    let suffixlen = if flags & pg_sys::XLH_UPDATE_SUFFIX_FROM_OLD != 0 {
+        read_len(&mut rest)?
+    } else {
+        0
+    };
+    if prefixlen == 0 && suffixlen == 0 {
+        return Some(rest.to_vec());
+    }
+    let old = old_tuple_data?;
+    if rest.len() < SIZE_OF_HEAP_HEADER || prefixlen + suffixlen > old.len() {
+        return None;
+    }
+
+    let (xlhdr, recdata) = rest.split_at(SIZE_OF_HEAP_HEADER);
+    let mut tuple = Vec::with_capacity(rest.len() + prefixlen + suffixlen);
+    tuple.extend_from_slice(xlhdr);
+    if prefixlen > 0 {
+        // Bitmap [+ padding] [+ oid] come from the record, before the prefix
+        let bitmap_len = usize::from(xlhdr[4]).checked_sub(SIZEOF_HEAP_TUPLE_HEADER)?;
+        let (bitmap, middle) = recdata.split_at_checked(bitmap_len)?;
+        tuple.extend_from_slice(bitmap);
+        tuple.extend_from_slice(&old[..prefixlen]);
+        tuple.extend_from_slice(middle);
+    } else {
+        tuple.extend_from_slice(recdata);
+    }
+    tuple.extend_from_slice(&old[old.len() - suffixlen..]);
+    Some(tuple)
+}
+
+fn redo_heap_update(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    hot_update: bool,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_update>()) };
+    let flags = u32::from(xlrec.flags);
+    let Some((_, newblk)) = block_page_id(xlog_reader, 0) else {
+        return;
+    };
+    // The old tuple is in block 1 when it's on a different page
+    let old_block_id = if block_page_id(xlog_reader, 1).is_some() {
+        1
+    } else {
+        0
+    };
+    let newtid = item_pointer(newblk, xlrec.new_offnum);
+
+    // Deal with old tuple version
+    let mut old = read_page_for_redo(xlog_reader, record, old_block_id, false, page_cache);
+    let mut old_tuple_data = None;
+    if let Some((page, RedoAction::NeedsRedo)) = old {
+        if let Some(htup) = page_get_tuple_header(page, xlrec.old_offnum) {
+            // Kept for the prefix and suffix of the new tuple
+            old_tuple_data = page_get_tuple_data(page, xlrec.old_offnum);
+            set_tuple_xmax(htup, xlrec.old_xmax, xlrec.old_infobits_set, hot_update);
+            unsafe {
+                // Set forward chain link in t_ctid
+                (*htup).t_ctid = newtid;
+                page_set_prunable(page, record.header.xl_xid);
+                if flags & pg_sys::XLH_UPDATE_OLD_ALL_VISIBLE_CLEARED != 0 {
+                    page_clear_all_visible(page);
+                }
+                page_set_lsn(page, xlog_reader.EndRecPtr);
             }
-            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_delete>()) };
-            xlrec.offnum
+        } else {
+            // The dropped page is released, the new tuple can't be added to it
+            invalidate_page(xlog_reader, old_block_id, page_cache);
+            old = None;
         }
-        pg_sys::XLOG_HEAP_HOT_UPDATE | pg_sys::XLOG_HEAP_UPDATE => {
-            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_update>()) };
-            if old {
-                xlrec.old_offnum
-            } else {
-                xlrec.new_offnum
+    }
+
+    // Deal with new tuple version
+    let new = if old_block_id == 0 {
+        old
+    } else {
+        let init_page = u32::from(record.header.xl_info) & pg_sys::XLOG_HEAP_INIT_PAGE != 0;
+        read_page_for_redo(xlog_reader, record, 0, init_page, page_cache)
+    };
+    let Some((page, RedoAction::NeedsRedo)) = new else {
+        return;
+    };
+
+    let tuple = get_block_data(xlog_reader, 0)
+        .and_then(|data| stitch_update_tuple(data, flags, old_tuple_data.as_deref()))
+        .and_then(|data| form_heap_tuple(&data, record.header.xl_xid, xlrec.new_xmax, newtid));
+    match tuple {
+        Some(tuple) if page_add_tuple(page, &tuple, xlrec.new_offnum) => unsafe {
+            if flags & pg_sys::XLH_UPDATE_NEW_ALL_VISIBLE_CLEARED != 0 {
+                page_clear_all_visible(page);
             }
+            page_set_lsn(page, xlog_reader.EndRecPtr);
+        },
+        _ => invalidate_page(xlog_reader, 0, page_cache),
+    }
+}
+
+fn redo_heap_lock(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_lock>()) };
+    let Some((page, RedoAction::NeedsRedo)) =
+        read_page_for_redo(xlog_reader, record, 0, false, page_cache)
+    else {
+        return;
+    };
+    let Some((_, blknum)) = block_page_id(xlog_reader, 0) else {
+        return;
+    };
+    let Some(htup) = page_get_tuple_header(page, xlrec.offnum) else {
+        invalidate_page(xlog_reader, 0, page_cache);
+        return;
+    };
+    unsafe {
+        let hot_updated = u32::from((*htup).t_infomask2) & pg_sys::HEAP_HOT_UPDATED != 0;
+        set_tuple_xmax(htup, xlrec.xmax, xlrec.infobits_set, hot_updated);
+        let infomask = u32::from((*htup).t_infomask);
+        let locked_only = infomask & pg_sys::HEAP_XMAX_LOCK_ONLY != 0
+            || infomask & (pg_sys::HEAP_XMAX_IS_MULTI | pg_sys::HEAP_LOCK_MASK)
+                == pg_sys::HEAP_XMAX_EXCL_LOCK;
+        if locked_only {
+            (*htup).t_infomask2 &= !mask(pg_sys::HEAP_HOT_UPDATED);
+            (*htup).t_ctid = item_pointer(blknum, xlrec.offnum);
         }
-        _ => return None,
+        page_set_lsn(page, xlog_reader.EndRecPtr);
+    }
+}
+
+fn redo_heap_confirm(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_confirm>()) };
+    let Some((page, RedoAction::NeedsRedo)) =
+        read_page_for_redo(xlog_reader, record, 0, false, page_cache)
+    else {
+        return;
     };
+    let Some((_, blknum)) = block_page_id(xlog_reader, 0) else {
+        return;
+    };
+    let Some(htup) = page_get_tuple_header(page, xlrec.offnum) else {
+        invalidate_page(xlog_reader, 0, page_cache);
+        return;
+    };
+    unsafe {
+        // Confirm the speculative insertion by replacing the token with the
+        // tuple's own TID
+        (*htup).t_ctid = item_pointer(blknum, xlrec.offnum);
+        page_set_lsn(page, xlog_reader.EndRecPtr);
+    }
+}
 
+fn redo_heap_inplace(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    let xlrec = unsafe { PgBox::from_pg(record.main_data.cast::<pg_sys::xl_heap_inplace>()) };
+    let Some((page, RedoAction::NeedsRedo)) =
+        read_page_for_redo(xlog_reader, record, 0, false, page_cache)
+    else {
+        return;
+    };
+    let Some(item_id) = (unsafe { page_get_normal_item_id(page, xlrec.offnum) }) else {
+        invalidate_page(xlog_reader, 0, page_cache);
+        return;
+    };
+    let Some(newtup) = get_block_data(xlog_reader, 0) else {
+        invalidate_page(xlog_reader, 0, page_cache);
+        return;
+    };
     unsafe {
-        let item_id = pg_sys::PageGetItemId(page, offnum);
-        let htup_len = PgBox::from_pg(item_id).lp_len();
+        let htup = pg_sys::PageGetItem(page, item_id).cast::<HeapTupleHeaderData>();
+        let hoff = usize::from((*htup).t_hoff);
+        let oldlen = usize::try_from((*item_id).lp_len()).unwrap() - hoff;
+        if oldlen != newtup.len() {
+            invalidate_page(xlog_reader, 0, page_cache);
+            return;
+        }
+        std::ptr::copy_nonoverlapping(newtup.as_ptr(), htup.cast::<u8>().add(hoff), oldlen);
+        page_set_lsn(page, xlog_reader.EndRecPtr);
+    }
+}
+
+/// Apply the changes of a heap record on the cached pages, mirrors `heap_redo`
+pub fn apply_heap_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+) {
+    match u32::from(record.header.xl_info) & pg_sys::XLOG_HEAP_OPMASK {
+        pg_sys::XLOG_HEAP_INSERT => redo_heap_insert(xlog_reader, record, page_cache),
+        pg_sys::XLOG_HEAP_DELETE => redo_heap_delete(xlog_reader, record, page_cache),
+        pg_sys::XLOG_HEAP_UPDATE => {
+            redo_heap_update(xlog_reader, record, false, page_cache);
+        }
+        pg_sys::XLOG_HEAP_HOT_UPDATE => {
+            redo_heap_update(xlog_reader, record, true, page_cache);
+        }
+        pg_sys::XLOG_HEAP_LOCK => redo_heap_lock(xlog_reader, record, page_cache),
+        pg_sys::XLOG_HEAP_CONFIRM => redo_heap_confirm(xlog_reader, record, page_cache),
+        pg_sys::XLOG_HEAP_INPLACE => redo_heap_inplace(xlog_reader, record, page_cache),
+        // Truncate has no block to apply
+        _ => {}
+    }
+}
+
+/// Cached pages of the blocks of a heap record whose LSN isn't the record's
+/// end once it was replayed, as real redo would set it. Blocks restored from
+/// their image and pages read from the current relation are left out.
+pub fn replayed_lsn_mismatches(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &PageCache,
+) -> Vec<(PageId, PgLSN)> {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    (0..=max_block_id)
+        .filter(|block_id| !has_block_image_to_apply(record, *block_id))
+        .filter_map(|block_id| block_page_id(xlog_reader, block_id))
+        .filter(|(page_id, _)| !page_cache.is_current(page_id))
+        .filter_map(|(page_id, _)| {
+            let page_lsn = unsafe { page_get_lsn(page_cache.peek(&page_id)?) };
+            (page_lsn != xlog_reader.EndRecPtr).then_some((page_id, PgLSN::from(page_lsn)))
+        })
+        .collect()
+}
+
+/// Pages of the block references of the latest decoded record
+pub fn record_page_ids(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Vec<PageId> {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    (0..=max_block_id)
+        .filter_map(|block_id| block_page_id(xlog_reader, block_id))
+        .map(|(page_id, _)| page_id)
+        .collect()
+}
+
+/// Build a heap tuple pointing to the tuple stored at offnum
+pub fn get_heap_tuple(
+    page: Page,
+    blknum: pg_sys::BlockNumber,
+    offnum: OffsetNumber,
+    relid: pg_sys::Oid,
+) -> Option<pg_sys::HeapTuple> {
+    let item_id = unsafe { page_get_normal_item_id(page, offnum) }?;
+    unsafe {
+        let htup_len = (*item_id).lp_len();
         let htuple = pg_sys::PageGetItem(page, item_id);
         // Create the fake tuple
         let mut tuple = PgBox::<pg_sys::HeapTupleData>::alloc0();
         tuple.t_data = htuple.cast();
         tuple.t_len = htup_len;
-        item_pointer_set_invalid(tuple.t_self);
+        tuple.t_self = item_pointer(blknum, offnum);
         tuple.t_tableOid = relid;
         Some(tuple.into_pg())
     }
 }
 
-fn apply_heap_record(
+/// How the tuple reported in a row was obtained
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TupleSource {
+    /// Tuple data of the record
+    Record,
+    /// Full page image of the record
+    Fpi,
+    /// Page rebuilt by replaying the earlier records on a full page image
+    Replay,
+    /// Page read from the current relation, the tuple may be a later version
+    Current,
+    /// Old tuple or key logged for logical decoding
+    Logged,
+}
+
+impl TupleSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TupleSource::Record => "record",
+            TupleSource::Fpi => "fpi",
+            TupleSource::Replay => "replay",
+            TupleSource::Current => "current",
+            TupleSource::Logged => "logged",
+        }
+    }
+}
+
+/// Where the cached page of a block reference comes from
+fn block_source(
     xlog_reader: &PgBox<pg_sys::XLogReaderState>,
-    page: pg_sys::Page,
-    block_id: i32,
-) {
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    block_id: u8,
+    page_cache: &PageCache,
+) -> TupleSource {
+    if has_block_image_to_apply(record, block_id) {
+        return TupleSource::Fpi;
+    }
+    match block_page_id(xlog_reader, block_id) {
+        Some((page_id, _)) if page_cache.is_current(&page_id) => TupleSource::Current,
+        _ => TupleSource::Replay,
+    }
+}
+
+/// Get the tuple at offnum in the cached page of a block reference
+fn get_block_tuple(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    block_id: u8,
+    offnum: OffsetNumber,
+    relid: pg_sys::Oid,
+    page_cache: &PageCache,
+) -> Option<pg_sys::HeapTuple> {
+    let (page_id, blknum) = block_page_id(xlog_reader, block_id)?;
+    let page = page_cache.peek(&page_id)?;
+    get_heap_tuple(page, blknum, offnum, relid)
+}
+
+/// Build the tuple inserted by a record from its block data, without needing
+/// the page. The data is omitted when a full page image was logged, unless
+/// the relation is logically logged.
+fn get_inserted_tuple(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    offnum: OffsetNumber,
+    relid: pg_sys::Oid,
+) -> Option<pg_sys::HeapTuple> {
+    let (_, blknum) = block_page_id(xlog_reader, 0)?;
+    let tid = item_pointer(blknum, offnum);
+    let data = get_block_data(xlog_reader, 0)?;
+    let tuple = form_heap_tuple(
+        data,
+        record.header.xl_xid,
+        pg_sys::InvalidTransactionId,
+        tid,
+    )?;
+    palloc_heap_tuple(&tuple, tid, relid)
+}
+
+/// Old tuple logged in the main data of an update or a delete of a logically
+/// logged relation, with `wal_level=logical`. The flag is set when only the
+/// replica identity columns are logged, the other ones being NULL.
+fn get_logged_old_tuple(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    heap_op: u32,
+    tid: ItemPointerData,
+    relid: pg_sys::Oid,
+) -> Option<(pg_sys::HeapTuple, bool)> {
+    if record.main_data.is_null() {
+        return None;
+    }
+    let main_data = unsafe {
+        std::slice::from_raw_parts(
+            record.main_data.cast::<u8>(),
+            usize::try_from(record.main_data_len).ok()?,
+        )
+    };
+    let (offset, flags, old_tuple_flag, old_key_flag) = match heap_op {
+        pg_sys::XLOG_HEAP_DELETE => {
+            let xlrec = unsafe {
+                std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_delete>())
+            };
+            (
+                SIZE_OF_HEAP_DELETE,
+                u32::from(xlrec.flags),
+                pg_sys::XLH_DELETE_CONTAINS_OLD_TUPLE,
+                pg_sys::XLH_DELETE_CONTAINS_OLD_KEY,
+            )
+        }
+        pg_sys::XLOG_HEAP_UPDATE | pg_sys::XLOG_HEAP_HOT_UPDATE => {
+            let xlrec = unsafe {
+                std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_update>())
+            };
+            (
+                SIZE_OF_HEAP_UPDATE,
+                u32::from(xlrec.flags),
+                pg_sys::XLH_UPDATE_CONTAINS_OLD_TUPLE,
+                pg_sys::XLH_UPDATE_CONTAINS_OLD_KEY,
+            )
+        }
+        _ => return None,
+    };
+    if flags & (old_tuple_flag | old_key_flag) == 0 {
+        return None;
+    }
+    let tuple = form_heap_tuple(
+        main_data.get(offset..)?,
+        pg_sys::InvalidTransactionId,
+        pg_sys::InvalidTransactionId,
+        tid,
+    )?;
+    let key_only = flags & old_tuple_flag == 0;
+    Some((palloc_heap_tuple(&tuple, tid, relid)?, key_only))
+}
+
+/// Copy a tuple in the current memory context, the per record one, like the
+/// values rendered from it
+fn palloc_heap_tuple(
+    tuple: &[u8],
+    tid: ItemPointerData,
+    relid: pg_sys::Oid,
+) -> Option<pg_sys::HeapTuple> {
+    let t_len = u32::try_from(tuple.len()).ok()?;
+    unsafe {
+        let t_data = pg_sys::palloc(tuple.len()).cast::<u8>();
+        std::ptr::copy_nonoverlapping(tuple.as_ptr(), t_data, tuple.len());
+        let mut htuple = PgBox::<pg_sys::HeapTupleData>::alloc0();
+        htuple.t_data = t_data.cast();
+        htuple.t_len = t_len;
+        htuple.t_self = tid;
+        htuple.t_tableOid = relid;
+        Some(htuple.into_pg())
+    }
+}
+
+/// Operation of a heap or heap2 record
+pub fn heap_op(record: &PgBox<pg_sys::DecodedXLogRecord>) -> u32 {
+    u32::from(record.header.xl_info) & pg_sys::XLOG_HEAP_OPMASK
+}
+
+/// Report the heap2 records that don't change rows as metadata rows
+fn decode_heap2_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    relid_cache: &mut RelidCache,
+    include_other_databases: bool,
+) -> Result<DecodedResult, SkipReason> {
+    match heap_op(record) {
+        pg_sys::XLOG_HEAP2_NEW_CID => {
+            decode_new_cid_record(record, relid_cache, include_other_databases)
+        }
+        pg_sys::XLOG_HEAP2_VISIBLE => {
+            decode_visible_record(xlog_reader, record, relid_cache, include_other_databases)
+        }
+        _ => Err(SkipReason::UnsupportedOperation),
+    }
+}
+
+/// Relid of a heap2 record's relation, None when it's in another database or
+/// can't be found. Fails if other databases aren't reported.
+pub fn heap2_relid(
+    rlocator: &pg_sys::RelFileLocator,
+    relid_cache: &mut RelidCache,
+    include_other_databases: bool,
+) -> Result<(Option<pg_sys::Oid>, bool), SkipReason> {
+    match classify_database(rlocator) {
+        RecordDatabase::Other if !include_other_databases => Err(SkipReason::OtherDatabase),
+        RecordDatabase::Other => Ok((None, false)),
+        _ => {
+            let relid = relid_cache.get(rlocator);
+            Ok((relid, relid.is_none()))
+        }
+    }
+}
+
+/// Report a `VISIBLE` record setting bits of the visibility map for a heap
+/// block. `row_after` holds `(blknum,all_visible,all_frozen)`.
+fn decode_visible_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    relid_cache: &mut RelidCache,
+    include_other_databases: bool,
+) -> Result<DecodedResult, SkipReason> {
+    if record.main_data.is_null() {
+        return Err(SkipReason::NoBlock);
+    }
+    // Block 0 is the visibility map page, block 1 the heap page
+    let Some((rlocator, _, blknum)) = get_block_tag_extended(xlog_reader, 1) else {
+        return Err(SkipReason::NoBlock);
+    };
+    let (relid, relation_missing) = heap2_relid(&rlocator, relid_cache, include_other_databases)?;
+    let xlrec =
+        unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_visible>()) };
+    let flags = u32::from(xlrec.flags);
+    let bool_out = |bit: u32| if flags & bit != 0 { 't' } else { 'f' };
+    let mut result = metadata_only_result(record, &rlocator, "VISIBLE", relation_missing);
+    result.relid = relid;
+    result.forknum = Some(pg_sys::ForkNumber::MAIN_FORKNUM);
+    result.blkno = Some(i64::from(blknum));
+    result.row_after = Some(format!(
+        "({blknum},{},{})",
+        bool_out(pg_sys::VISIBILITYMAP_ALL_VISIBLE),
+        bool_out(pg_sys::VISIBILITYMAP_ALL_FROZEN)
+    ));
+    Ok(result)
+}
+
+/// Report a `NEW_CID` record, logged with `wal_level=logical` when a catalog
+/// tuple is modified. `row_after` holds `(cmin,cmax,combocid,ctid)` of the
+/// catalog tuple.
+fn decode_new_cid_record(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    relid_cache: &mut RelidCache,
+    include_other_databases: bool,
+) -> Result<DecodedResult, SkipReason> {
+    if record.main_data.is_null() {
+        return Err(SkipReason::NoBlock);
+    }
+    let xlrec =
+        unsafe { std::ptr::read_unaligned(record.main_data.cast::<pg_sys::xl_heap_new_cid>()) };
+    let rlocator = xlrec.target_locator;
+    let (relid, relation_missing) = heap2_relid(&rlocator, relid_cache, include_other_databases)?;
+    let tid = xlrec.target_tid;
+    let blknum = (u32::from(tid.ip_blkid.bi_hi) << 16) | u32::from(tid.ip_blkid.bi_lo);
+    let mut result = metadata_only_result(record, &rlocator, "NEW_CID", relation_missing);
+    result.relid = relid;
+    result.forknum = Some(pg_sys::ForkNumber::MAIN_FORKNUM);
+    result.blkno = Some(i64::from(blknum));
+    // The changes of a catalog tuple belong to the top level transaction
+    result.xid = xlrec.top_xid;
+    result.row_after = Some(format!(
+        "({},{},{},\"({blknum},{})\")",
+        xlrec.cmin, xlrec.cmax, xlrec.combocid, tid.ip_posid
+    ));
+    Ok(result)
+}
+
+/// Result of a record whose relation can't be resolved, only identifying the
+/// modified relation
+pub fn metadata_only_result(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    rlocator: &pg_sys::RelFileLocator,
+    op: &str,
+    relation_missing: bool,
+) -> DecodedResult {
+    DecodedResult {
+        lsn: PgLSN::from(record.lsn),
+        dboid: rlocator.dbOid,
+        spcoid: rlocator.spcOid,
+        relfilenumber: rlocator.relNumber,
+        relation_missing,
+        xid: record.header.xl_xid,
+        toplevel_xid: record.header.xl_xid,
+        op: op.to_string(),
+        ..DecodedResult::default()
+    }
+}
+
+/// Why a heap record wasn't reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The record has no block reference
+    NoBlock,
+    /// Heap operation that doesn't change a row
+    UnsupportedOperation,
+    /// The change is in another database
+    OtherDatabase,
+    /// The modified page was never seen in a full page image
+    NoPage,
+    /// The relation of the change couldn't be found, skipped with `on_error`
+    UnresolvedRelation,
+    /// The record's blocks aren't in the relations decoded
+    Filtered,
+    /// Chunk of a toasted value, only used for the row of its table
+    ToastChunk,
+}
 
+impl SkipReason {
+    /// Whether the record may have changed rows that aren't reported
+    pub fn loses_change(self) -> bool {
+        matches!(self, SkipReason::NoBlock | SkipReason::NoPage)
+    }
+}
+
+/// Flags of `t_infomask`, with the names of `pageinspect`
+const INFOMASK_FLAGS: [(u32, &str); 16] = [
+    (pg_sys::HEAP_HASNULL, "HEAP_HASNULL"),
+    (pg_sys::HEAP_HASVARWIDTH, "HEAP_HASVARWIDTH"),
+    (pg_sys::HEAP_HASEXTERNAL, "HEAP_HASEXTERNAL"),
+    (pg_sys::HEAP_HASOID_OLD, "HEAP_HASOID_OLD"),
+    (pg_sys::HEAP_XMAX_KEYSHR_LOCK, "HEAP_XMAX_KEYSHR_LOCK"),
+    (pg_sys::HEAP_COMBOCID, "HEAP_COMBOCID"),
+    (pg_sys::HEAP_XMAX_EXCL_LOCK, "HEAP_XMAX_EXCL_LOCK"),
+    (pg_sys::HEAP_XMAX_LOCK_ONLY, "HEAP_XMAX_LOCK_ONLY"),
+    (pg_sys::HEAP_XMIN_COMMITTED, "HEAP_XMIN_COMMITTED"),
+    (pg_sys::HEAP_XMIN_INVALID, "HEAP_XMIN_INVALID"),
+    (pg_sys::HEAP_XMAX_COMMITTED, "HEAP_XMAX_COMMITTED"),
+    (pg_sys::HEAP_XMAX_INVALID, "HEAP_XMAX_INVALID"),
+    (pg_sys::HEAP_XMAX_IS_MULTI, "HEAP_XMAX_IS_MULTI"),
+    (pg_sys::HEAP_UPDATED, "HEAP_UPDATED"),
+    (pg_sys::HEAP_MOVED_OFF, "HEAP_MOVED_OFF"),
+    (pg_sys::HEAP_MOVED_IN, "HEAP_MOVED_IN"),
+];
+
+/// Flags of `t_infomask2`, the rest of it is the number of attributes
+const INFOMASK2_FLAGS: [(u32, &str); 3] = [
+    (pg_sys::HEAP_KEYS_UPDATED, "HEAP_KEYS_UPDATED"),
+    (pg_sys::HEAP_HOT_UPDATED, "HEAP_HOT_UPDATED"),
+    (pg_sys::HEAP_ONLY_TUPLE, "HEAP_ONLY_TUPLE"),
+];
+
+/// Combinations of flags with their own meaning, like pageinspect reports
+/// them
+const COMBINED_INFOMASK_FLAGS: [(u32, &str); 2] = [
+    (pg_sys::HEAP_XMIN_FROZEN, "HEAP_XMIN_FROZEN"),
+    (pg_sys::HEAP_XMAX_SHR_LOCK, "HEAP_XMAX_SHR_LOCK"),
+];
+
+/// Names of the flags set in the infomask fields of a tuple header, followed
+/// by the combined flags fully set
+fn infomask_flags(infomask: u16, infomask2: u16) -> Vec<String> {
+    let set = |mask: u16, flags: &[(u32, &str)]| {
+        flags
+            .iter()
+            .filter(|(flag, _)| u32::from(mask) & flag != 0)
+            .map(|(_, name)| (*name).to_string())
+            .collect::<Vec<_>>()
+    };
+    let mut flags = set(infomask, &INFOMASK_FLAGS);
+    flags.extend(set(infomask2, &INFOMASK2_FLAGS));
+    flags.extend(
+        COMBINED_INFOMASK_FLAGS
+            .iter()
+            .filter(|(flag, _)| u32::from(infomask) & flag == *flag)
+            .map(|(_, name)| (*name).to_string()),
+    );
+    flags
+}
+
+/// Bytes of a tuple, header included, with its infomask flags, its raw xmin
+/// and xmax and its command id
+fn raw_tuple(
+    tuple: pg_sys::HeapTuple,
+) -> (
+    Vec<u8>,
+    Vec<String>,
+    pg_sys::TransactionId,
+    pg_sys::TransactionId,
+    pg_sys::CommandId,
+) {
+    unsafe {
+        let header = (*tuple).t_data;
+        let len = usize::try_from((*tuple).t_len).unwrap();
+        let data = std::slice::from_raw_parts(header.cast::<u8>(), len).to_vec();
+        let flags = infomask_flags((*header).t_infomask, (*header).t_infomask2);
+        let fields = (*header).t_choice.t_heap;
+        (
+            data,
+            flags,
+            fields.t_xmin,
+            fields.t_xmax,
+            fields.t_field3.t_cid,
+        )
+    }
 }
 
-pub fn decode_heap_record(
+#[allow(clippy::too_many_arguments)]
+fn decode_heap_record(
     xlog_reader: &PgBox<pg_sys::XLogReaderState>,
     record: &PgBox<pg_sys::DecodedXLogRecord>,
-    page_hash: &HashMap<PageId, pg_sys::Page>,
-) -> Option<DecodedResult> {
+    page_cache: &mut PageCache,
+    relid_cache: &mut RelidCache,
+    mask_cache: &mut MaskCache,
+    tuple_descs: &TupleDescCache,
+    include_other_databases: bool,
+    check_revert_conflicts: bool,
+    include_raw_tuple: bool,
+    unqualified_names: bool,
+    include_toast_chunks: bool,
+    toast_chunks: &mut ToastChunks,
+    other_databases: Option<&mut OtherDatabases>,
+    on_error: Option<OnError>,
+) -> Result<DecodedResult, SkipReason> {
     if record.max_block_id < 0 {
         // No need to process anything if there's no blocks
-        return None;
+        return Err(SkipReason::NoBlock);
     }
-    let heap_op = u32::from(record.header.xl_info) & pg_sys::XLOG_HEAP_OPMASK;
+    let heap_op = heap_op(record);
     let op_name = unsafe { pg_sys::heap_identify(heap_op.try_into().unwrap()) };
     let op_name_str = unsafe { CStr::from_ptr(op_name).to_str().unwrap() };
-    pg_sys::info!(
+    verbose!(
+        Verbosity::Debug,
         "Processing HEAP record {} at LSN {}",
         op_name_str,
         xlog_reader.ReadRecPtr
     );
 
-    let (rlocator, _, _) = get_block_tag(xlog_reader);
-    let Some(relid) = get_relid_from_rlocator(&rlocator) else {
-        pg_sys::warning!("Couldn't find oid for rlocator {:?}", rlocator);
-        return None;
+    // Keep the cached pages in sync even if the change isn't reported
+    apply_heap_record(xlog_reader, record, page_cache);
+
+    // Block and offset of the old and new tuple versions
+    let main_data = record.main_data;
+    let (old_tid, new_tid) = match heap_op {
+        pg_sys::XLOG_HEAP_INSERT => {
+            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_insert>()) };
+            (None, Some((0, xlrec.offnum)))
+        }
+        pg_sys::XLOG_HEAP_DELETE => {
+            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_delete>()) };
+            (Some((0, xlrec.offnum)), None)
+        }
+        pg_sys::XLOG_HEAP_HOT_UPDATE | pg_sys::XLOG_HEAP_UPDATE => {
+            let xlrec = unsafe { PgBox::from_pg(main_data.cast::<pg_sys::xl_heap_update>()) };
+            let old_block_id = if block_page_id(xlog_reader, 1).is_some() {
+                1
+            } else {
+                0
+            };
+            (
+                Some((old_block_id, xlrec.old_offnum)),
+                Some((0, xlrec.new_offnum)),
+            )
+        }
+        _ => return Err(SkipReason::UnsupportedOperation),
+    };
+    let ctid = |(block_id, offnum)| {
+        block_page_id(xlog_reader, block_id).map(|(_, blknum)| item_pointer(blknum, offnum))
+    };
+    let old_ctid = old_tid.and_then(ctid);
+    let new_ctid = new_tid.and_then(ctid);
+
+    let (rlocator, forknum, blknum) = get_block_tag(xlog_reader);
+    // Tuples of foreign relations are deformed with the columns given, those
+    // of other databases with the columns of their own catalog, when it can
+    // be queried
+    let mut other_relation = tuple_descs.foreign(rlocator.relNumber);
+    if other_relation.is_none() && classify_database(&rlocator) == RecordDatabase::Other {
+        if !include_other_databases {
+            return Err(SkipReason::OtherDatabase);
+        }
+        other_relation = other_databases.and_then(|other_databases| other_databases.get(&rlocator));
+        if other_relation.is_none() {
+            return Ok(DecodedResult {
+                old_ctid,
+                new_ctid,
+                forknum: Some(forknum),
+                blkno: Some(i64::from(blknum)),
+                ..metadata_only_result(record, &rlocator, op_name_str, false)
+            });
+        }
+    }
+    let relid = match other_relation {
+        Some(other_relation) => Some(other_relation.relid),
+        None => timed(Phase::ResolveRelid, || relid_cache.get(&rlocator)),
+    };
+    let Some(relid) = relid else {
+        let message = format!(
+            "Couldn't find oid for rlocator {:?} at {}",
+            rlocator,
+            PgLSN::from(record.lsn)
+        );
+        let mut error = None;
+        match on_error {
+            Some(OnError::Stop) => error!("{message}"),
+            Some(OnError::Skip) => return Err(SkipReason::UnresolvedRelation),
+            Some(OnError::Emit) => error = Some(message),
+            None => warning!("Couldn't find oid for rlocator {:?}", rlocator),
+        }
+        // Still report the change so it can be audited by relfilenode
+        return Ok(DecodedResult {
+            old_ctid,
+            new_ctid,
+            forknum: Some(forknum),
+            blkno: Some(i64::from(blknum)),
+            error,
+            ..metadata_only_result(record, &rlocator, op_name_str, true)
+        });
+    };
+
+    let mut old_key_only = false;
+    let mut old_source = TupleSource::Logged;
+    let old_tuple = old_tid.map(|(block_id, offnum)| {
+        if let Some(tuple) = get_block_tuple(xlog_reader, block_id, offnum, relid, page_cache) {
+            old_source = block_source(xlog_reader, record, block_id, page_cache);
+            return Some(tuple);
+        }
+        // The page is missing, fall back to the old tuple logged for logical
+        // decoding
+        let blknum = block_page_id(xlog_reader, block_id).map_or(0, |(_, blknum)| blknum);
+        let tid = item_pointer(blknum, offnum);
+        let (tuple, key_only) = get_logged_old_tuple(record, heap_op, tid, relid)?;
+        old_key_only = key_only;
+        Some(tuple)
+    });
+    let mut new_source = TupleSource::Record;
+    let new_tuple = new_tid.map(|(block_id, offnum)| {
+        // Inserted tuples are in the record, the page may never have been seen
+        let inserted = (heap_op == pg_sys::XLOG_HEAP_INSERT)
+            .then(|| get_inserted_tuple(xlog_reader, record, offnum, relid))
+            .flatten();
+        inserted.or_else(|| {
+            new_source = block_source(xlog_reader, record, block_id, page_cache);
+            get_block_tuple(xlog_reader, block_id, offnum, relid, page_cache)
+        })
+    });
+    // The row before is the one that can be wrong
+    let provenance = if old_tid.is_some() {
+        old_source
+    } else {
+        new_source
+    };
+    if matches!(old_tuple, Some(None)) || matches!(new_tuple, Some(None)) {
+        verbose!(
+            Verbosity::Debug,
+            "No page found, skipping record at {}",
+            xlog_reader.ReadRecPtr
+        );
+        return Err(SkipReason::NoPage);
+    }
+
+    let rel = other_relation
+        .is_none()
+        .then(|| unsafe { PgRelation::with_lock(relid, pg_sys::AccessShareLock.cast_signed()) });
+    let current_tupdesc = rel.as_ref().map(PgRelation::tuple_desc);
+    let (tupdesc, relname, name, relpersistence) = match (&rel, other_relation) {
+        (Some(rel), _) => (
+            // The current columns differ from the WAL's when the table was
+            // altered
+            tuple_descs
+                .get(relid)
+                .unwrap_or_else(|| current_tupdesc.as_ref().unwrap()),
+            relation_name(rel),
+            rel.name(),
+            unsafe { (*rel.rd_rel).relpersistence }.cast_unsigned(),
+        ),
+        (None, Some(other_relation)) => (
+            &other_relation.tuple_desc,
+            other_relation.qualified_name(),
+            other_relation.relation_name.as_str(),
+            other_relation.relpersistence,
+        ),
+        (None, None) => unreachable!(),
+    };
+    // Live rows are always looked up with the qualified name
+    let query_relname = if unqualified_names {
+        quote_identifier(name)
+    } else {
+        relname.clone()
+    };
+    // Only unlogged relations have an init fork, whatever the catalog says now
+    let persistence = if forknum == pg_sys::ForkNumber::INIT_FORKNUM {
+        pg_sys::RELPERSISTENCE_UNLOGGED
+    } else {
+        relpersistence
+    };
+    // Chunks inserted in toast tables are kept to rebuild the toasted values
+    // of the rows of their table, decoded after them
+    let is_toast = rel.as_ref().is_some_and(|rel| {
+        unsafe { (*rel.rd_rel).relkind }.cast_unsigned() == pg_sys::RELKIND_TOASTVALUE
+    });
+    if is_toast {
+        if let Some(tuple) = new_tuple
+            .flatten()
+            .filter(|_| heap_op == pg_sys::XLOG_HEAP_INSERT)
+        {
+            toast_chunks.insert(relid, tupdesc, tuple);
+        }
+        if !include_toast_chunks {
+            return Err(SkipReason::ToastChunk);
+        }
+    }
+    let encoding = other_relation.and_then(|other_relation| other_relation.encoding);
+    let chunks = &*toast_chunks;
+    let build = |t| {
+        timed(Phase::BuildTuple, || {
+            tuple_values_in(tupdesc, t, encoding, Some(chunks))
+        })
+    };
+    // Page tuples may predate the columns added since, the tuples of the
+    // record have all the columns of the relation at the time
+    let old_tuple = old_tuple.flatten();
+    let new_tuple = new_tuple.flatten();
+    let schema_mismatch = old_tuple.is_some_and(|t| !tuple_fits_desc(tupdesc, t, false))
+        || new_tuple.is_some_and(|t| !tuple_fits_desc(tupdesc, t, true));
+    if schema_mismatch {
+        verbose!(
+            Verbosity::Normal,
+            "Tuples at {} don't match the columns of {relname}",
+            PgLSN::from(record.lsn)
+        );
+    }
+    let (raw_tuple, infomask, xmin, xmax, cid) = match new_tuple.or(old_tuple) {
+        Some(tuple) if include_raw_tuple => {
+            let (data, infomask, xmin, xmax, cid) = raw_tuple(tuple);
+            (
+                Some(data),
+                Some(infomask),
+                Some(xmin),
+                Some(xmax),
+                Some(i64::from(cid)),
+            )
+        }
+        _ => (None, None, None, None, None),
     };
+    let mut old_values = old_tuple.map(build);
+    let mut new_values = new_tuple.map(build);
+    toast_chunks.evict_fetched();
+    // Live rows are compared with the values before masking, catalog changes
+    // and the rows of other databases have no revert to check
+    let conflict = (check_revert_conflicts
+        && rel.is_some()
+        && !old_key_only
+        && relid.to_u32() >= pg_sys::FirstNormalObjectId)
+        .then(|| {
+            revert_conflict(
+                &relname,
+                relid,
+                old_values.as_deref(),
+                new_values.as_deref(),
+            )
+            .unwrap_or_else(|e| error!("Couldn't check the revert conflicts of {relname}: {e}"))
+        })
+        .flatten();
+    for values in old_values.iter_mut().chain(new_values.iter_mut()) {
+        match (&rel, other_relation) {
+            (Some(rel), _) => mask_cache.apply(rel, values),
+            // Masked in their database
+            (None, Some(other_relation)) => mask_cache.apply_masks(values, &other_relation.masks),
+            (None, None) => {}
+        }
+    }
+
+    // Catalog changes are reported as the DDL causing them, which has no
+    // generic revert
+    let (redo_query, revert_query) =
+        match catalog_ddl(relid, old_values.as_deref(), new_values.as_deref()) {
+            Some(ddl) => (ddl, None),
+            None if old_key_only => {
+                // Replica identity columns are never NULL, they're the only
+                // ones known. The change can be redone but not reverted.
+                let key = old_values
+                    .iter()
+                    .flatten()
+                    .filter(|c| c.value.is_some())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (
+                    generate_key_query(&query_relname, &key, new_values.as_deref()),
+                    None,
+                )
+            }
+            None => {
+                let (redo_query, revert_query) =
+                    generate_queries(&query_relname, old_values.as_deref(), new_values.as_deref())
+                        .ok_or(SkipReason::NoPage)?;
+                let revert_query = match conflict {
+                    Some(reason) => conflicting_revert(&revert_query, reason),
+                    None => revert_query,
+                };
+                (redo_query, Some(revert_query))
+            }
+        };
 
-    match heap_op {
-        XLOG_HEAP_INSERT => todo!(),
+    // The excluded columns are only hidden from the rows
+    let names = match (&rel, other_relation) {
+        (Some(rel), _) => Some((rel.namespace(), rel.name())),
+        (None, Some(other_relation)) => Some((
+            other_relation.schema_name.as_str(),
+            other_relation.relation_name.as_str(),
+        )),
+        (None, None) => None,
+    };
+    if let Some((schema, table)) = names {
+        for values in old_values.iter_mut().chain(new_values.iter_mut()) {
+            mask_cache.exclude(values, schema, table);
+        }
     }
 
-    Some(DecodedResult {
-        lsn: record.lsn.cast_signed(),
+    // Only the replica identity of the old row may be known
+    let changes = (!old_key_only)
+        .then(|| old_values.as_deref().zip(new_values.as_deref()))
+        .flatten()
+        .map(|(old, new)| JsonbText(changes_json(old, new)));
+
+    Ok(DecodedResult {
+        lsn: PgLSN::from(record.lsn),
         dboid: rlocator.dbOid,
-        relid,
+        relid: Some(relid).filter(|relid| *relid != pg_sys::InvalidOid),
+        spcoid: rlocator.spcOid,
+        relfilenumber: rlocator.relNumber,
+        forknum: Some(forknum),
+        blkno: Some(i64::from(blknum)),
+        schema_mismatch: Some(schema_mismatch),
+        provenance: Some(provenance.name().to_string()),
         xid: record.header.xl_xid,
-        redo_query: None,
-        revert_query: None,
-        row_before: None,
-        row_after: None,
+        schema_name: other_relation.map(|other_relation| other_relation.schema_name.clone()),
+        relation_name: other_relation.map(|other_relation| other_relation.relation_name.clone()),
+        toplevel_xid: record.header.xl_xid,
+        changes,
+        old_ctid,
+        new_ctid,
+        op: op_name_str.to_string(),
+        query_fingerprint: Some(query_fingerprint(&redo_query)),
+        redo_query: Some(redo_query),
+        revert_query,
+        row_before: old_values.as_deref().map(format_row),
+        row_after: new_values.as_deref().map(format_row),
+        raw_tuple,
+        infomask,
+        xmin,
+        xmax,
+        persistence: Some(persistence_name(persistence).to_string()),
+        cid,
+        ..DecodedResult::default()
     })
 }
+
+/// Decoder of the heap records changing rows
+pub struct HeapDecoder;
+
+impl RmgrDecoder for HeapDecoder {
+    fn decodes(&self, _ctx: &DecodeContext, _record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        true
+    }
+
+    fn decode(
+        &self,
+        ctx: &mut DecodeContext,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> Result<DecodedResult, SkipReason> {
+        decode_heap_record(
+            ctx.xlog_reader,
+            record,
+            ctx.page_cache,
+            ctx.relid_cache,
+            ctx.mask_cache,
+            ctx.tuple_descs,
+            ctx.include_other_databases,
+            ctx.check_revert_conflicts,
+            ctx.include_raw_tuple,
+            ctx.unqualified_names,
+            ctx.include_toast_chunks,
+            ctx.toast_chunks,
+            ctx.other_databases.as_deref_mut(),
+            ctx.on_error,
+        )
+    }
+}
+
+/// Decoder of the heap2 records reported as metadata rows
+pub struct Heap2Decoder;
+
+impl RmgrDecoder for Heap2Decoder {
+    fn decodes(&self, ctx: &DecodeContext, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        match heap_op(record) {
+            pg_sys::XLOG_HEAP2_NEW_CID => ctx.include_new_cid,
+            pg_sys::XLOG_HEAP2_VISIBLE => ctx.include_visible,
+            _ => false,
+        }
+    }
+
+    fn decode(
+        &self,
+        ctx: &mut DecodeContext,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> Result<DecodedResult, SkipReason> {
+        decode_heap2_record(
+            ctx.xlog_reader,
+            record,
+            ctx.relid_cache,
+            ctx.include_other_databases,
+        )
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use crate::xlog_heap::{infomask_flags, stitch_update_tuple, SIZEOF_HEAP_TUPLE_HEADER};
+    use pgrx::pg_sys;
+
+    #[test]
+    fn test_infomask_flags() {
+        let infomask = u16::try_from(pg_sys::HEAP_HASNULL | pg_sys::HEAP_XMAX_INVALID).unwrap();
+        let infomask2 = u16::try_from(pg_sys::HEAP_ONLY_TUPLE | 3).unwrap();
+        assert_eq!(
+            infomask_flags(infomask, infomask2),
+            ["HEAP_HASNULL", "HEAP_XMAX_INVALID", "HEAP_ONLY_TUPLE"]
+        );
+        assert!(infomask_flags(0, 3).is_empty());
+        let frozen = u16::try_from(pg_sys::HEAP_XMIN_FROZEN).unwrap();
+        assert_eq!(
+            infomask_flags(frozen, 0),
+            [
+                "HEAP_XMIN_COMMITTED",
+                "HEAP_XMIN_INVALID",
+                "HEAP_XMIN_FROZEN"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stitch_update_tuple() {
+        let t_hoff = u8::try_from(SIZEOF_HEAP_TUPLE_HEADER + 1).unwrap();
+        let xlhdr = [1, 0, 2, 0, t_hoff];
+        let old = b"prefix-old-suffix";
+
+        // Prefix and suffix lengths, header, null bitmap and the changed middle
+        let mut data = vec![7, 0, 7, 0];
+        data.extend_from_slice(&xlhdr);
+        data.push(0xff);
+        data.extend_from_slice(b"new");
+        let flags = pg_sys::XLH_UPDATE_PREFIX_FROM_OLD | pg_sys::XLH_UPDATE_SUFFIX_FROM_OLD;
+        let tuple = stitch_update_tuple(&data, flags, Some(old)).unwrap();
+
+        let mut expected = xlhdr.to_vec();
+        expected.push(0xff);
+        expected.extend_from_slice(b"prefix-new-suffix");
+        assert_eq!(tuple, expected);
+
+        // Nothing to stitch without the old tuple
+        assert_eq!(stitch_update_tuple(&data, flags, None), None);
+    }
+}
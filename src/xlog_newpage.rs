@@ -0,0 +1,206 @@
+use pgrx::{pg_sys, PgBox};
+
+use crate::{
+    decoder::{DecodedResult, PageId},
+    page_cache::PageCache,
+    relation::{classify_database, persistence_name, RecordDatabase, RelidCache},
+    rmgr::{DecodeContext, RmgrDecoder},
+    summary::ScanSummary,
+    xlog_heap::{heap2_relid, metadata_only_result, restore_fpw, SkipReason},
+    xlog_reader::{get_block_tag_extended, has_block_image_to_apply},
+};
+
+fn xlog_info(record: &PgBox<pg_sys::DecodedXLogRecord>) -> u32 {
+    u32::from(record.header.xl_info) & !pg_sys::XLR_INFO_MASK
+}
+
+/// Blocks of the latest decoded record, with their fork
+fn record_blocks(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+) -> Vec<(u8, pg_sys::RelFileLocator, i32, pg_sys::BlockNumber)> {
+    let Ok(max_block_id) = u8::try_from(record.max_block_id) else {
+        return Vec::new();
+    };
+    (0..=max_block_id)
+        .filter_map(|block_id| {
+            let (rlocator, forknum, blknum) = get_block_tag_extended(xlog_reader, block_id)?;
+            Some((block_id, rlocator, forknum, blknum))
+        })
+        .collect()
+}
+
+/// Heap relations of the local catalog, the pages of indexes and sequences
+/// aren't decoded and would only evict the cached heap pages
+fn is_heap_relation(rlocator: &pg_sys::RelFileLocator, relid_cache: &mut RelidCache) -> bool {
+    relid_cache.get(rlocator).is_some_and(|relid| {
+        matches!(
+            unsafe { pg_sys::get_rel_relkind(relid) }.cast_unsigned(),
+            pg_sys::RELKIND_RELATION | pg_sys::RELKIND_MATVIEW | pg_sys::RELKIND_TOASTVALUE
+        )
+    })
+}
+
+/// Report an `FPI` record logging whole new pages, written by a table
+/// rewrite, an index build or the creation of an init fork, or an
+/// `FPI_FOR_HINT` record logging a page whose hint bits were set. The images
+/// of the main fork pages of heap relations replace their cached version,
+/// the changes made after the rewrite can then be decoded. `row_after` holds the logged
+/// blocks, `{blknum,...}`, the images are returned by `pg_waldecoder_fpi`.
+fn decode_newpage_record(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    page_cache: &mut PageCache,
+    relid_cache: &mut RelidCache,
+    include_other_databases: bool,
+    op: &str,
+    reported: bool,
+) -> Result<DecodedResult, SkipReason> {
+    let blocks = record_blocks(xlog_reader, record);
+    let Some(&(_, rlocator, forknum, _)) = blocks.first() else {
+        return Err(SkipReason::NoBlock);
+    };
+    for &(block_id, rlocator, forknum, blknum) in &blocks {
+        if forknum == pg_sys::ForkNumber::MAIN_FORKNUM
+            && classify_database(&rlocator) != RecordDatabase::Other
+            && has_block_image_to_apply(record, block_id)
+            && is_heap_relation(&rlocator, relid_cache)
+        {
+            let page_id = PageId::new(&rlocator, forknum, blknum);
+            let page = restore_fpw(xlog_reader, block_id, &page_id, page_cache);
+            page_cache.insert(page_id, page);
+        }
+    }
+    if !reported {
+        return Err(SkipReason::UnsupportedOperation);
+    }
+    let (relid, relation_missing) = heap2_relid(&rlocator, relid_cache, include_other_databases)?;
+    let blknums = blocks
+        .iter()
+        .map(|(_, _, _, blknum)| blknum.to_string())
+        .collect::<Vec<_>>();
+    let mut result = metadata_only_result(record, &rlocator, op, relation_missing);
+    result.relid = relid;
+    result.forknum = Some(forknum);
+    result.blkno = blocks.first().map(|(_, _, _, blknum)| i64::from(*blknum));
+    // Only unlogged relations have an init fork
+    if forknum == pg_sys::ForkNumber::INIT_FORKNUM {
+        result.persistence = Some(persistence_name(pg_sys::RELPERSISTENCE_UNLOGGED).to_string());
+    }
+    result.row_after = Some(format!("{{{}}}", blknums.join(",")));
+    Ok(result)
+}
+
+/// Decoder of the xlog records logging whole pages
+pub struct NewPageDecoder;
+
+impl RmgrDecoder for NewPageDecoder {
+    /// `FPI` and `FPI_FOR_HINT` records are always decoded to keep the
+    /// cached pages in sync, they're only reported with `include_newpages`
+    /// and `include_hint_fpis`
+    fn decodes(&self, _ctx: &DecodeContext, record: &PgBox<pg_sys::DecodedXLogRecord>) -> bool {
+        matches!(
+            xlog_info(record),
+            pg_sys::XLOG_FPI | pg_sys::XLOG_FPI_FOR_HINT
+        )
+    }
+
+    fn decode(
+        &self,
+        ctx: &mut DecodeContext,
+        record: &PgBox<pg_sys::DecodedXLogRecord>,
+    ) -> Result<DecodedResult, SkipReason> {
+        let (op, reported) = if xlog_info(record) == pg_sys::XLOG_FPI_FOR_HINT {
+            ("FPI", ctx.include_hint_fpis)
+        } else {
+            ("NEWPAGE", ctx.include_newpages)
+        };
+        decode_newpage_record(
+            ctx.xlog_reader,
+            record,
+            ctx.page_cache,
+            ctx.relid_cache,
+            ctx.include_other_databases,
+            op,
+            reported,
+        )
+    }
+
+    /// Records that aren't reported are counted with the other resource
+    /// managers'
+    fn stats(&self, summary: &mut ScanSummary, reason: SkipReason) {
+        if reason == SkipReason::UnsupportedOperation {
+            summary.skipped_non_heap += 1;
+        } else {
+            summary.record_skipped(reason);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    use crate::pg_lsn::PgLSN;
+
+    #[pg_test]
+    fn test_pg_waldecoder_newpages() {
+        unsafe {
+            Spi::run("CREATE TABLE test_newpages (id int);");
+            Spi::run("INSERT INTO test_newpages VALUES (1)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // The pages of the index build are logged whole
+            Spi::run("CREATE INDEX test_newpages_id ON test_newpages (id)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let query = |include_newpages: bool| {
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                     include_newpages => {include_newpages})
+                 WHERE op = 'NEWPAGE' AND relid = 'test_newpages_id'::regclass AND forknum = 0"
+            ))
+        };
+        assert!(query(true).unwrap().unwrap() > 0);
+        assert_eq!(query(false), Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_pg_waldecoder_hint_fpis() {
+        let startptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+        unsafe {
+            // Hint bits set on the catalog pages read after the checkpoint
+            // log their pages with checksums or wal_log_hints
+            Spi::run("CHECKPOINT");
+            Spi::run("SELECT count(*) FROM pg_class, pg_attribute");
+            Spi::run("CREATE TABLE test_hint_fpis (id int)");
+            pg_sys::XLogFlush(pg_sys::XactLastRecEnd);
+        }
+        let endptr = unsafe { PgLSN::from(pg_sys::GetXLogWriteRecPtr()) };
+
+        let query = |include_hint_fpis: bool| {
+            Spi::get_two::<i64, i64>(&format!(
+                "SELECT count(*), count(*) FILTER (WHERE row_after LIKE '{{%}}' AND blkno IS NOT NULL)
+                 FROM pg_waldecoder('{startptr}', '{endptr}', 1,
+                     include_hint_fpis => {include_hint_fpis})
+                 WHERE op = 'FPI'"
+            ))
+            .unwrap()
+        };
+        let (fpis, with_blocks) = query(true);
+        assert_eq!(fpis, with_blocks);
+        let hints_logged = Spi::get_one::<bool>(
+            "SELECT current_setting('wal_log_hints')::bool
+                 OR current_setting('data_checksums')::bool",
+        );
+        if hints_logged == Ok(Some(false)) {
+            assert_eq!(fpis, Some(0));
+        }
+        assert_eq!(query(false), (Some(0), Some(0)));
+    }
+}
@@ -1,3 +1,5 @@
+use std::ptr;
+
 use pgrx::{PgBox, pg_sys::{self, RelFileLocator, XLogRecGetBlockTag}};
 
 /// Get block tag info from latest decoded record
@@ -20,3 +22,65 @@ pub fn get_block_tag(xlog_reader: &PgBox<pg_sys::XLogReaderState>) -> (RelFileLo
     };
     (rlocator, forknum, blknum)
 }
+
+/// Get block tag info of a block reference, None if the block isn't used by
+/// the latest decoded record
+pub fn get_block_tag_extended(
+    xlog_reader: &PgBox<pg_sys::XLogReaderState>,
+    block_id: u8,
+) -> Option<(RelFileLocator, i32, u32)> {
+    let mut rlocator: RelFileLocator = RelFileLocator {
+        spcOid: 0.into(),
+        dbOid: 0.into(),
+        relNumber: 0.into(),
+    };
+    let mut forknum: i32 = 0;
+    let mut blknum: u32 = 0;
+    let found = unsafe {
+        pg_sys::XLogRecGetBlockTagExtended(
+            xlog_reader.as_ptr(),
+            block_id,
+            &raw mut rlocator,
+            &raw mut forknum,
+            &raw mut blknum,
+            ptr::null_mut(),
+        )
+    };
+    found.then_some((rlocator, forknum, blknum))
+}
+
+/// Get the decoded block reference of the latest decoded record
+pub fn get_block(
+    record: &PgBox<pg_sys::DecodedXLogRecord>,
+    block_id: u8,
+) -> Option<&pg_sys::DecodedBkpBlock> {
+    if i32::from(block_id) > record.max_block_id {
+        return None;
+    }
+    let block = unsafe { &*record.blocks.as_ptr().add(usize::from(block_id)) };
+    block.in_use.then_some(block)
+}
+
+/// Returns true if the block reference has a full page image to apply
+pub fn has_block_image_to_apply(record: &PgBox<pg_sys::DecodedXLogRecord>, block_id: u8) -> bool {
+    get_block(record, block_id).is_some_and(|b| b.has_image && b.apply_image)
+}
+
+/// Get the data attached to a block reference
+pub fn get_block_data(xlog_reader: &PgBox<pg_sys::XLogReaderState>, block_id: u8) -> Option<&[u8]> {
+    let mut len: usize = 0;
+    let data = unsafe { pg_sys::XLogRecGetBlockData(xlog_reader.as_ptr(), block_id, &raw mut len) };
+    if data.is_null() {
+        return None;
+    }
+    Some(unsafe { std::slice::from_raw_parts(data.cast::<u8>(), len) })
+}
+
+/// Callbacks of a resource manager, builtin or custom
+pub fn rmgr_data(rmid: u8) -> &'static pg_sys::RmgrData {
+    unsafe {
+        &*std::ptr::addr_of!(pg_sys::RmgrTable)
+            .cast::<pg_sys::RmgrData>()
+            .add(usize::from(rmid))
+    }
+}